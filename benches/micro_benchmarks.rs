@@ -1,16 +1,20 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use knyst::envelope::{Curve, Envelope};
+use knyst::graph::Gen;
+use knyst::graph::{Mult, PanMonoToStereo};
 use knyst::prelude::*;
-use knyst::wavetable::{Phase, PhaseF32, FRACTIONAL_PART};
+use knyst::wavetable::{Phase, PhaseF32, WavetableOscillatorOwned, FRACTIONAL_PART};
 
 // Test if integer phase is in fact faster than floating point phase
 pub fn phase_float_or_uint(c: &mut Criterion) {
-    let sample_rate = 44100.0;
+    let sample_rate: f64 = 44100.0;
 
     let freq_to_phase_inc =
-        (TABLE_SIZE as f64 * FRACTIONAL_PART as f64 * (1.0 / sample_rate as f64)) as Sample;
+        (TABLE_SIZE as f64 * FRACTIONAL_PART as f64 * (1.0 / sample_rate)) as Sample;
     let freq_to_f32_phase_inc = 1.0 / sample_rate as f32;
-    let freq = 824.3;
+    let freq: Sample = 824.3;
+    // PhaseF32 is always f32, whichever type Sample is
+    let freq_f32: f32 = 824.3;
     let table: Vec<f32> = (0..TABLE_SIZE)
         .map(|i| ((i as f32) / TABLE_SIZE as f32).sin())
         .collect();
@@ -38,7 +42,7 @@ pub fn phase_float_or_uint(c: &mut Criterion) {
                 let (index, mix) = phase.index_mix();
                 let value = table[index] + differences[index] * mix;
                 black_box(value);
-                phase.increase(freq * freq_to_f32_phase_inc);
+                phase.increase(freq_f32 * freq_to_f32_phase_inc);
             }
         })
     });
@@ -87,7 +91,61 @@ pub fn envelope_segments(c: &mut Criterion) {
     });
 }
 
+// Run with and without the `simd` feature to compare
+pub fn block_processing(c: &mut Criterion) {
+    const BLOCK_SIZE: usize = 128;
+    let a: Vec<Sample> = (0..BLOCK_SIZE).map(|i| i as Sample * 0.01).collect();
+    let b: Vec<Sample> = (0..BLOCK_SIZE).map(|i| 1.0 - i as Sample * 0.005).collect();
+    let inputs = vec![a.into_boxed_slice(), b.into_boxed_slice()];
+    let mut resources = Resources::new(ResourcesSettings::default());
+    let mut mono = vec![vec![0.0; BLOCK_SIZE].into_boxed_slice()];
+    let mut stereo = vec![vec![0.0; BLOCK_SIZE].into_boxed_slice(); 2];
+    let mut mult = Mult;
+    c.bench_function("Mult block", |bencher| {
+        bencher.iter(|| {
+            mult.process(black_box(&inputs), &mut mono, &mut resources);
+            black_box(&mono);
+        })
+    });
+    let mut pan = PanMonoToStereo;
+    c.bench_function("PanMonoToStereo block", |bencher| {
+        bencher.iter(|| {
+            pan.process(black_box(&inputs), &mut stereo, &mut resources);
+            black_box(&stereo);
+        })
+    });
+    let freq = vec![vec![440.0; BLOCK_SIZE].into_boxed_slice()];
+    let mut osc = WavetableOscillatorOwned::new(Wavetable::sine());
+    osc.set_amp(0.5);
+    c.bench_function("WavetableOscillatorOwned block", |bencher| {
+        bencher.iter(|| {
+            osc.process(black_box(&freq), &mut mono, &mut resources);
+            black_box(&mono);
+        })
+    });
+    // Summing the outputs of many nodes into the outputs of a Graph
+    let mut graph: Graph = Graph::new(GraphSettings {
+        block_size: BLOCK_SIZE,
+        ..Default::default()
+    });
+    for i in 0..16 {
+        let osc = graph.push_gen(WavetableOscillatorOwned::new(Wavetable::sine()));
+        graph
+            .connect(constant(110.0 * (i + 1) as Sample).to(osc))
+            .unwrap();
+        graph.connect(osc.to_graph_out().channels(2)).unwrap();
+    }
+    let mut graph_node = graph.to_node().unwrap();
+    let no_inputs: Vec<Box<[Sample]>> = vec![];
+    c.bench_function("Graph with 16 oscillators block", |bencher| {
+        bencher.iter(|| {
+            graph_node.process(&no_inputs, &mut resources);
+            black_box(graph_node.output_buffers());
+        })
+    });
+}
+
 // criterion_group!(benches, phase_float_or_uint);
-criterion_group!(benches, envelope_segments);
+criterion_group!(benches, envelope_segments, block_processing);
 
 criterion_main!(benches);
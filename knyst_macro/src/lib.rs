@@ -0,0 +1,217 @@
+//! Derive macro for the `Gen` trait of knyst. Use it through the `derive`
+//! feature of knyst, which re-exports it as `knyst::graph::Gen`.
+//!
+//! Fields marked `#[input]` are set to the value of their input before every
+//! sample, then the processing method is called, after which the fields
+//! marked `#[output]` are written to their outputs. Inputs and outputs are
+//! numbered in the order of the fields and named after them.
+//!
+//! ```ignore
+//! #[derive(Gen)]
+//! #[gen(name = "Gain")]
+//! struct Gain {
+//!     #[input]
+//!     signal: Sample,
+//!     #[input(default = 0.5, name = "gain")]
+//!     amount: Sample,
+//!     #[output]
+//!     out: Sample,
+//! }
+//! impl Gain {
+//!     fn process_sample(&mut self, _resources: &mut Resources) {
+//!         self.out = self.signal * self.amount;
+//!     }
+//! }
+//! ```
+//!
+//! Attributes on the struct, all optional:
+//! - `name = "..."`: the name of the Gen, by default the name of the struct
+//! - `process = "method"`: the method called for every sample, by default
+//!   `process_sample`
+//! - `init = "method"`: a method taking the sample rate, called from `Gen::init`
+//!
+//! Attributes on fields, all optional:
+//! - `name = "..."`: the name of the input or output, by default the name of the field
+//! - `default = value`: the default value of an input
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, Ident, LitStr};
+
+#[proc_macro_derive(Gen, attributes(gen, input, output))]
+pub fn derive_gen(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct Port {
+    field: Ident,
+    name: String,
+    default: Option<Expr>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let mut name = ident.to_string();
+    let mut process = Ident::new("process_sample", ident.span());
+    let mut init = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("gen"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+            } else if meta.path.is_ident("process") {
+                process = meta.value()?.parse::<LitStr>()?.parse()?;
+            } else if meta.path.is_ident("init") {
+                init = Some(meta.value()?.parse::<LitStr>()?.parse::<Ident>()?);
+            } else {
+                return Err(meta.error("expected `name`, `process` or `init`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "Gen can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "Gen can only be derived for structs",
+            ))
+        }
+    };
+    let mut inputs = vec![];
+    let mut outputs = vec![];
+    for field in fields {
+        for attr in &field.attrs {
+            let ports = if attr.path().is_ident("input") {
+                &mut inputs
+            } else if attr.path().is_ident("output") {
+                &mut outputs
+            } else {
+                continue;
+            };
+            let field_ident = field.ident.clone().unwrap();
+            let mut port = Port {
+                name: field_ident.to_string(),
+                field: field_ident,
+                default: None,
+            };
+            if matches!(attr.meta, syn::Meta::List(_)) {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("name") {
+                        port.name = meta.value()?.parse::<LitStr>()?.value();
+                    } else if meta.path.is_ident("default") {
+                        port.default = Some(meta.value()?.parse()?);
+                    } else {
+                        return Err(meta.error("expected `name` or `default`"));
+                    }
+                    Ok(())
+                })?;
+            }
+            if port.default.is_some() && attr.path().is_ident("output") {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "only inputs can have a default value",
+                ));
+            }
+            ports.push(port);
+        }
+    }
+
+    let num_inputs = inputs.len();
+    let num_outputs = outputs.len();
+    let input_fields = inputs.iter().map(|port| &port.field);
+    let input_indices = 0..num_inputs;
+    let output_fields = outputs.iter().map(|port| &port.field);
+    let output_indices = 0..num_outputs;
+    let input_desc = port_names(&inputs);
+    let output_desc = port_names(&outputs);
+    let defaults = inputs.iter().enumerate().filter_map(|(index, port)| {
+        port.default
+            .as_ref()
+            .map(|default| quote! { #index => #default as ::knyst::Sample, })
+    });
+    let init = init.map(|init| {
+        quote! {
+            fn init(&mut self, sample_rate: ::knyst::Sample) {
+                self.#init(sample_rate)
+            }
+        }
+    });
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::knyst::graph::Gen for #ident #ty_generics #where_clause {
+            fn process(
+                &mut self,
+                inputs: &[Box<[::knyst::Sample]>],
+                outputs: &mut [Box<[::knyst::Sample]>],
+                resources: &mut ::knyst::Resources,
+            ) -> ::knyst::graph::GenState {
+                let block_size = outputs
+                    .first()
+                    .or(inputs.first())
+                    .map_or(0, |buffer| buffer.len());
+                for i in 0..block_size {
+                    #( self.#input_fields = inputs[#input_indices][i]; )*
+                    self.#process(resources);
+                    #( outputs[#output_indices][i] = self.#output_fields; )*
+                }
+                ::knyst::graph::GenState::Continue
+            }
+            fn num_inputs(&self) -> usize {
+                #num_inputs
+            }
+            fn num_outputs(&self) -> usize {
+                #num_outputs
+            }
+            fn input_desc(&self, input: usize) -> &'static str {
+                #input_desc
+            }
+            fn output_desc(&self, output: usize) -> &'static str {
+                let input = output;
+                #output_desc
+            }
+            fn default_input(&self, input: usize) -> ::knyst::Sample {
+                match input {
+                    #( #defaults )*
+                    _ => 0.0,
+                }
+            }
+            fn name(&self) -> &'static str {
+                #name
+            }
+            #init
+        }
+    })
+}
+
+/// A match on `input` returning the name of every port
+fn port_names(ports: &[Port]) -> TokenStream2 {
+    let arms = ports.iter().enumerate().map(|(index, port)| {
+        let name = &port.name;
+        quote! { #index => #name, }
+    });
+    quote! {
+        match input {
+            #( #arms )*
+            _ => "",
+        }
+    }
+}
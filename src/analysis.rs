@@ -0,0 +1,1318 @@
+//! Analysis of signals, e.g. for sidechaining, following an instrument or
+//! for visuals.
+//!
+//! Analysis Gens output their results as signals that can be connected to
+//! other nodes. The latest value can also be read from any thread through a
+//! [`SharedSample`] handle. A [`Meter`], a [`Probe`] and a
+//! [`SpectrumAnalyzer`] only have handles, for drawing levels, waveforms and
+//! spectra in a UI. A [`LoudnessMeter`] measures loudness according to EBU
+//! R128 for checking rendered material against loudness targets.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::dynamics::time_coefficient;
+use crate::filter::{Biquad, BiquadKind};
+use crate::graph::{Gen, GenState};
+use crate::spectral::hann_window;
+use crate::{sample_to_f64, Resources, Sample};
+
+#[cfg(not(feature = "f64"))]
+type AtomicSampleBits = std::sync::atomic::AtomicU32;
+#[cfg(feature = "f64")]
+type AtomicSampleBits = std::sync::atomic::AtomicU64;
+
+/// A [`Sample`] that can be written on the audio thread and read from any
+/// other thread without locking. Clones share the same value.
+#[derive(Debug, Clone)]
+pub struct SharedSample {
+    bits: Arc<AtomicSampleBits>,
+}
+
+impl SharedSample {
+    pub fn new(value: Sample) -> Self {
+        Self {
+            bits: Arc::new(AtomicSampleBits::new(value.to_bits())),
+        }
+    }
+    pub fn get(&self) -> Sample {
+        Sample::from_bits(self.bits.load(std::sync::atomic::Ordering::Relaxed))
+    }
+    pub fn set(&self, value: Sample) {
+        self.bits
+            .store(value.to_bits(), std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+impl Default for SharedSample {
+    fn default() -> Self {
+        Self::new(0.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeMode {
+    /// The absolute value of the signal
+    Peak,
+    /// The root mean square of the signal over a window in seconds
+    Rms(Sample),
+}
+
+/// Follows the amplitude of a signal.
+///
+/// Inputs are "signal", "attack" and "release" where the attack and release
+/// times are in seconds. The level is rectified, or averaged in
+/// [`EnvelopeMode::Rms`], and then smoothed with the attack time when rising
+/// and the release time when falling. The output is "envelope".
+///
+/// The last value of every block can be read from the control thread through
+/// [`EnvelopeFollower::handle`].
+#[derive(Debug, Clone)]
+pub struct EnvelopeFollower {
+    mode: EnvelopeMode,
+    envelope: Sample,
+    /// Squared samples in the RMS window, used as a ring buffer
+    window: Vec<Sample>,
+    window_pos: usize,
+    window_sum: f64,
+    handle: SharedSample,
+    sample_rate: Sample,
+}
+
+impl EnvelopeFollower {
+    pub fn new(mode: EnvelopeMode) -> Self {
+        Self {
+            mode,
+            envelope: 0.0,
+            window: vec![],
+            window_pos: 0,
+            window_sum: 0.0,
+            handle: SharedSample::default(),
+            sample_rate: 44100.0,
+        }
+    }
+    pub fn peak() -> Self {
+        Self::new(EnvelopeMode::Peak)
+    }
+    /// Follow the RMS level over a window of `window_time` seconds
+    pub fn rms(window_time: Sample) -> Self {
+        Self::new(EnvelopeMode::Rms(window_time))
+    }
+    /// A handle for reading the current envelope from another thread
+    pub fn handle(&self) -> SharedSample {
+        self.handle.clone()
+    }
+    #[inline]
+    fn level(&mut self, input: Sample) -> Sample {
+        if self.window.is_empty() {
+            return input.abs();
+        }
+        let squared = input * input;
+        self.window_sum += sample_to_f64(squared - self.window[self.window_pos]);
+        self.window[self.window_pos] = squared;
+        self.window_pos = (self.window_pos + 1) % self.window.len();
+        // Rounding errors can make the sum slightly negative
+        (self.window_sum.max(0.0) / self.window.len() as f64).sqrt() as Sample
+    }
+}
+
+impl Gen for EnvelopeFollower {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let level = self.level(inputs[0][i]);
+            let coefficient = if level > self.envelope {
+                time_coefficient(inputs[1][i], self.sample_rate)
+            } else {
+                time_coefficient(inputs[2][i], self.sample_rate)
+            };
+            self.envelope = level + coefficient * (self.envelope - level);
+            *out = self.envelope;
+        }
+        self.handle.set(self.envelope);
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        if let EnvelopeMode::Rms(window_time) = self.mode {
+            let window_size = ((window_time * sample_rate) as usize).max(1);
+            self.window = vec![0.0; window_size];
+            self.window_pos = 0;
+            self.window_sum = 0.0;
+        }
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "attack",
+            2 => "release",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "envelope",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "EnvelopeFollower"
+    }
+}
+
+/// Estimates the fundamental frequency of a monophonic signal using the YIN
+/// algorithm.
+///
+/// The input is "signal" and the outputs are "freq" in Hz and "confidence"
+/// from 0 to 1. The outputs are updated a few times per analysis window and
+/// held in between. When no pitch is found the last frequency is kept and the
+/// confidence is low. Both values can be read from the control thread through
+/// [`PitchTracker::freq_handle`] and [`PitchTracker::confidence_handle`].
+#[derive(Debug, Clone)]
+pub struct PitchTracker {
+    min_freq: Sample,
+    max_freq: Sample,
+    threshold: Sample,
+    /// The latest input used as a ring buffer
+    input: Vec<Sample>,
+    input_pos: usize,
+    /// The input in order, oldest first
+    frame: Vec<Sample>,
+    difference: Vec<Sample>,
+    hop_size: usize,
+    hop_counter: usize,
+    freq: Sample,
+    confidence: Sample,
+    freq_handle: SharedSample,
+    confidence_handle: SharedSample,
+    sample_rate: Sample,
+}
+
+impl PitchTracker {
+    /// `min_freq` is the lowest frequency that can be detected. Lower values
+    /// need a longer analysis window, which uses more CPU and reacts slower.
+    pub fn new(min_freq: Sample) -> Self {
+        Self {
+            min_freq: min_freq.max(1.0),
+            max_freq: 2000.0,
+            threshold: 0.15,
+            input: vec![],
+            input_pos: 0,
+            frame: vec![],
+            difference: vec![],
+            hop_size: 1,
+            hop_counter: 0,
+            freq: 0.0,
+            confidence: 0.0,
+            freq_handle: SharedSample::default(),
+            confidence_handle: SharedSample::default(),
+            sample_rate: 44100.0,
+        }
+    }
+    /// Set the highest frequency that can be detected. Defaults to 2000 Hz.
+    pub fn max_freq(mut self, max_freq: Sample) -> Self {
+        self.max_freq = max_freq;
+        self
+    }
+    /// Set the threshold of the normalized difference below which a period
+    /// is accepted. Lower values give fewer octave errors but miss noisy
+    /// pitches. Defaults to 0.15.
+    pub fn threshold(mut self, threshold: Sample) -> Self {
+        self.threshold = threshold;
+        self
+    }
+    pub fn freq_handle(&self) -> SharedSample {
+        self.freq_handle.clone()
+    }
+    pub fn confidence_handle(&self) -> SharedSample {
+        self.confidence_handle.clone()
+    }
+    /// Run YIN on the latest window of input
+    fn analyze(&mut self) {
+        let len = self.input.len();
+        for (i, sample) in self.frame.iter_mut().enumerate() {
+            *sample = self.input[(self.input_pos + i) % len];
+        }
+        let max_lag = self.difference.len() - 1;
+        let window = len - max_lag;
+        let min_lag = ((self.sample_rate / self.max_freq) as usize).clamp(2, max_lag);
+        // Cumulative mean normalized difference
+        self.difference[0] = 1.0;
+        let mut running_sum = 0.0;
+        for lag in 1..=max_lag {
+            let mut sum = 0.0;
+            for j in 0..window {
+                let diff = self.frame[j] - self.frame[j + lag];
+                sum += diff * diff;
+            }
+            running_sum += sum;
+            self.difference[lag] = if running_sum > 0.0 {
+                sum * lag as Sample / running_sum
+            } else {
+                1.0
+            };
+        }
+        // The first dip below the threshold, or the lowest value if there is none
+        let mut best = None;
+        let mut lag = min_lag;
+        while lag <= max_lag {
+            if self.difference[lag] < self.threshold {
+                while lag < max_lag && self.difference[lag + 1] < self.difference[lag] {
+                    lag += 1;
+                }
+                best = Some(lag);
+                break;
+            }
+            lag += 1;
+        }
+        let best = best.unwrap_or_else(|| {
+            (min_lag..=max_lag)
+                .min_by(|a, b| self.difference[*a].total_cmp(&self.difference[*b]))
+                .unwrap_or(min_lag)
+        });
+        self.confidence = (1.0 - self.difference[best]).clamp(0.0, 1.0);
+        if self.difference[best] >= self.threshold {
+            // Keep the last frequency when there is no clear pitch
+            return;
+        }
+        // Parabolic interpolation around the minimum
+        let mut period = best as Sample;
+        if best > 1 && best < max_lag {
+            let (a, b, c) = (
+                self.difference[best - 1],
+                self.difference[best],
+                self.difference[best + 1],
+            );
+            let denominator = a - 2.0 * b + c;
+            if denominator.abs() > Sample::EPSILON {
+                period += 0.5 * (a - c) / denominator;
+            }
+        }
+        self.freq = self.sample_rate / period;
+    }
+}
+
+impl Gen for PitchTracker {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        if self.input.is_empty() {
+            // Not initialized yet
+            for output in outputs.iter_mut() {
+                output.fill(0.0);
+            }
+            return GenState::Continue;
+        }
+        let len = self.input.len();
+        for i in 0..outputs[0].len() {
+            self.input[self.input_pos] = inputs[0][i];
+            self.input_pos = (self.input_pos + 1) % len;
+            self.hop_counter += 1;
+            if self.hop_counter >= self.hop_size {
+                self.hop_counter = 0;
+                self.analyze();
+            }
+            outputs[0][i] = self.freq;
+            outputs[1][i] = self.confidence;
+        }
+        self.freq_handle.set(self.freq);
+        self.confidence_handle.set(self.confidence);
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        // The window needs to fit two periods of the lowest frequency
+        let max_lag = ((sample_rate / self.min_freq) as usize).max(4);
+        self.input = vec![0.0; max_lag * 2];
+        self.input_pos = 0;
+        self.frame = vec![0.0; max_lag * 2];
+        self.difference = vec![1.0; max_lag + 1];
+        self.hop_size = (max_lag / 2).max(1);
+        self.hop_counter = 0;
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        2
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "freq",
+            1 => "confidence",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "PitchTracker"
+    }
+}
+
+const METER_INPUT_NAMES: [&str; 8] = ["in0", "in1", "in2", "in3", "in4", "in5", "in6", "in7"];
+
+/// The latest levels of one channel of a [`Meter`]
+#[derive(Debug, Clone, Default)]
+struct MeterLevels {
+    peak: SharedSample,
+    rms: SharedSample,
+}
+
+/// Reads the levels of a [`Meter`] from any thread. Clones share the same
+/// levels.
+#[derive(Debug, Clone)]
+pub struct MeterHandle {
+    levels: Arc<[MeterLevels]>,
+}
+
+impl MeterHandle {
+    pub fn num_channels(&self) -> usize {
+        self.levels.len()
+    }
+    /// The highest absolute value of `channel` in the last window, or 0 if
+    /// the channel doesn't exist
+    pub fn peak(&self, channel: usize) -> Sample {
+        self.levels
+            .get(channel)
+            .map_or(0.0, |levels| levels.peak.get())
+    }
+    /// The RMS level of `channel` in the last window, or 0 if the channel
+    /// doesn't exist
+    pub fn rms(&self, channel: usize) -> Sample {
+        self.levels
+            .get(channel)
+            .map_or(0.0, |levels| levels.rms.get())
+    }
+}
+
+/// Measures the peak and RMS level of every input channel for drawing level
+/// meters.
+///
+/// The levels are measured over consecutive windows and the levels of the
+/// last complete window can be read from the control thread through a
+/// [`MeterHandle`]. The inputs are "in0", "in1" etc. and there are no outputs,
+/// so the node can be connected to anything without changing the sound.
+#[derive(Debug, Clone)]
+pub struct Meter {
+    window_time: Sample,
+    window_size: usize,
+    counter: usize,
+    peaks: Vec<Sample>,
+    squared_sums: Vec<f64>,
+    handle: MeterHandle,
+}
+
+impl Meter {
+    /// A Meter with `num_channels` inputs and a window of 50 ms
+    pub fn new(num_channels: usize) -> Self {
+        Self {
+            window_time: 0.05,
+            window_size: 1,
+            counter: 0,
+            peaks: vec![0.0; num_channels],
+            squared_sums: vec![0.0; num_channels],
+            handle: MeterHandle {
+                levels: (0..num_channels).map(|_| MeterLevels::default()).collect(),
+            },
+        }
+    }
+    /// Set the length of the window in seconds
+    pub fn window(mut self, window_time: Sample) -> Self {
+        self.window_time = window_time;
+        self
+    }
+    pub fn handle(&self) -> MeterHandle {
+        self.handle.clone()
+    }
+}
+
+impl Gen for Meter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        _outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let block_size = inputs.first().map_or(0, |input| input.len());
+        let num_channels = self.peaks.len();
+        let mut start = 0;
+        while start < block_size {
+            let end = block_size.min(start + self.window_size - self.counter);
+            for (channel, input) in inputs[..num_channels].iter().enumerate() {
+                for &sample in &input[start..end] {
+                    self.peaks[channel] = self.peaks[channel].max(sample.abs());
+                    self.squared_sums[channel] += sample_to_f64(sample * sample);
+                }
+            }
+            self.counter += end - start;
+            start = end;
+            if self.counter == self.window_size {
+                for ((peak, squared_sum), levels) in self
+                    .peaks
+                    .iter_mut()
+                    .zip(self.squared_sums.iter_mut())
+                    .zip(self.handle.levels.iter())
+                {
+                    levels.peak.set(*peak);
+                    levels
+                        .rms
+                        .set((*squared_sum / self.window_size as f64).sqrt() as Sample);
+                    *peak = 0.0;
+                    *squared_sum = 0.0;
+                }
+                self.counter = 0;
+            }
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.window_size = ((self.window_time * sample_rate) as usize).max(1);
+        self.counter = 0;
+        self.peaks.fill(0.0);
+        self.squared_sums.fill(0.0);
+    }
+    fn num_inputs(&self) -> usize {
+        self.peaks.len()
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        METER_INPUT_NAMES.get(input).copied().unwrap_or("")
+    }
+    fn name(&self) -> &'static str {
+        "Meter"
+    }
+}
+
+/// Gating blocks quieter than this in LUFS aren't part of the integrated
+/// loudness
+const LOUDNESS_ABSOLUTE_GATE: f64 = -70.0;
+/// Gating blocks this many LU below the absolute gated loudness aren't part
+/// of the integrated loudness
+const LOUDNESS_RELATIVE_GATE: f64 = -10.0;
+/// The resolution of the relative gate
+const LOUDNESS_HISTOGRAM_BINS_PER_LU: f64 = 10.0;
+/// Covers -70 to +10 LUFS
+const LOUDNESS_HISTOGRAM_BINS: usize = 800;
+/// The number of 100 ms sub-blocks in the momentary and short-term windows
+const MOMENTARY_SUB_BLOCKS: usize = 4;
+const SHORT_TERM_SUB_BLOCKS: usize = 30;
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+/// The length of each phase of the true peak interpolation filter
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// The loudness in LUFS of a mean square weighted by channel
+fn energy_to_loudness(energy: f64) -> f64 {
+    if energy > 0.0 {
+        -0.691 + 10.0 * energy.log10()
+    } else {
+        f64::NEG_INFINITY
+    }
+}
+
+fn loudness_histogram_bin(loudness: f64) -> usize {
+    (((loudness - LOUDNESS_ABSOLUTE_GATE) * LOUDNESS_HISTOGRAM_BINS_PER_LU).max(0.0) as usize)
+        .min(LOUDNESS_HISTOGRAM_BINS - 1)
+}
+
+/// The latest measurements of a [`LoudnessMeter`]
+#[derive(Debug, Default)]
+struct LoudnessLevels {
+    momentary: SharedSample,
+    short_term: SharedSample,
+    integrated: SharedSample,
+    true_peak: SharedSample,
+    reset: AtomicBool,
+}
+
+/// Reads the measurements of a [`LoudnessMeter`] from any thread. Clones
+/// share the same measurements.
+#[derive(Debug, Clone)]
+pub struct LoudnessHandle {
+    levels: Arc<LoudnessLevels>,
+}
+
+impl LoudnessHandle {
+    /// The loudness of the last 400 ms in LUFS
+    pub fn momentary(&self) -> Sample {
+        self.levels.momentary.get()
+    }
+    /// The loudness of the last 3 s in LUFS
+    pub fn short_term(&self) -> Sample {
+        self.levels.short_term.get()
+    }
+    /// The gated loudness since the start or the last reset in LUFS
+    pub fn integrated(&self) -> Sample {
+        self.levels.integrated.get()
+    }
+    /// The highest true peak since the start or the last reset in dBTP
+    pub fn true_peak(&self) -> Sample {
+        self.levels.true_peak.get()
+    }
+    /// Restart the integrated loudness and the true peak, e.g. before
+    /// measuring a new piece. Takes effect on the next block.
+    pub fn reset(&self) {
+        self.levels.reset.store(true, Ordering::Release);
+    }
+}
+
+/// Measures loudness according to ITU-R BS.1770 and EBU R128.
+///
+/// The inputs "in0", "in1" etc. are K-weighted and measured as one program.
+/// The momentary (400 ms), short-term (3 s) and integrated (gated)
+/// loudness in LUFS and the true peak in dBTP can be read from the control
+/// thread through a [`LoudnessHandle`]. Loudness is updated every 100 ms and
+/// is negative infinity for silence. The true peak is detected by
+/// oversampling the inputs 4 times.
+///
+/// There are no outputs, so the node can be connected to anything without
+/// changing the sound.
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    weights: Vec<Sample>,
+    filters: Vec<[Biquad; 2]>,
+    /// The latest inputs for true peak interpolation, oldest first
+    histories: Vec<[Sample; TRUE_PEAK_TAPS]>,
+    true_peak_kernel: [[Sample; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING],
+    true_peak: Sample,
+    sub_block_size: usize,
+    counter: usize,
+    energy_sum: f64,
+    /// The mean square of the latest 100 ms sub-blocks
+    sub_blocks: [f64; SHORT_TERM_SUB_BLOCKS],
+    sub_block_pos: usize,
+    num_sub_blocks: usize,
+    histogram_counts: Vec<u64>,
+    histogram_energies: Vec<f64>,
+    handle: LoudnessHandle,
+}
+
+impl LoudnessMeter {
+    /// A LoudnessMeter with `num_channels` inputs, up to 8, all with a weight
+    /// of 1
+    pub fn new(num_channels: usize) -> Self {
+        // A Hann windowed sinc interpolating between the samples in the middle
+        // of the history
+        let mut true_peak_kernel = [[0.0; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING];
+        let half_width = (TRUE_PEAK_TAPS / 2) as f64;
+        for (phase, kernel) in true_peak_kernel.iter_mut().enumerate() {
+            for (tap, coefficient) in kernel.iter_mut().enumerate() {
+                let t =
+                    half_width - tap as f64 - 1.0 + phase as f64 / TRUE_PEAK_OVERSAMPLING as f64;
+                let sinc = if t == 0.0 {
+                    1.0
+                } else {
+                    (std::f64::consts::PI * t).sin() / (std::f64::consts::PI * t)
+                };
+                let window = 0.5 * (1.0 + (std::f64::consts::PI * t / half_width).cos());
+                *coefficient = (sinc * window) as Sample;
+            }
+        }
+        let num_channels = num_channels.min(METER_INPUT_NAMES.len());
+        let levels = LoudnessLevels::default();
+        for level in [&levels.momentary, &levels.short_term, &levels.integrated] {
+            level.set(Sample::NEG_INFINITY);
+        }
+        levels.true_peak.set(Sample::NEG_INFINITY);
+        Self {
+            weights: vec![1.0; num_channels],
+            filters: vec![[Biquad::new(); 2]; num_channels],
+            histories: vec![[0.0; TRUE_PEAK_TAPS]; num_channels],
+            true_peak_kernel,
+            true_peak: 0.0,
+            sub_block_size: 1,
+            counter: 0,
+            energy_sum: 0.0,
+            sub_blocks: [0.0; SHORT_TERM_SUB_BLOCKS],
+            sub_block_pos: 0,
+            num_sub_blocks: 0,
+            histogram_counts: vec![0; LOUDNESS_HISTOGRAM_BINS],
+            histogram_energies: vec![0.0; LOUDNESS_HISTOGRAM_BINS],
+            handle: LoudnessHandle {
+                levels: Arc::new(levels),
+            },
+        }
+    }
+    /// Set the weights of the channels, starting from the first. BS.1770
+    /// uses 1.0 for the left, right and center channels, 1.41 for the
+    /// surround channels and 0.0 for the LFE channel.
+    pub fn channel_weights(mut self, weights: &[Sample]) -> Self {
+        for (weight, new_weight) in self.weights.iter_mut().zip(weights) {
+            *weight = *new_weight;
+        }
+        self
+    }
+    pub fn handle(&self) -> LoudnessHandle {
+        self.handle.clone()
+    }
+    fn reset(&mut self) {
+        for [high_shelf, high_pass] in &mut self.filters {
+            high_shelf.reset();
+            high_pass.reset();
+        }
+        for history in &mut self.histories {
+            history.fill(0.0);
+        }
+        self.true_peak = 0.0;
+        self.counter = 0;
+        self.energy_sum = 0.0;
+        self.sub_blocks.fill(0.0);
+        self.sub_block_pos = 0;
+        self.num_sub_blocks = 0;
+        self.histogram_counts.fill(0);
+        self.histogram_energies.fill(0.0);
+        let levels = &self.handle.levels;
+        for level in [
+            &levels.momentary,
+            &levels.short_term,
+            &levels.integrated,
+            &levels.true_peak,
+        ] {
+            level.set(Sample::NEG_INFINITY);
+        }
+    }
+    /// The mean square of the latest `num` sub-blocks
+    fn window_energy(&self, num: usize) -> f64 {
+        (1..=num)
+            .map(|i| {
+                self.sub_blocks
+                    [(self.sub_block_pos + SHORT_TERM_SUB_BLOCKS - i) % SHORT_TERM_SUB_BLOCKS]
+            })
+            .sum::<f64>()
+            / num as f64
+    }
+    fn integrated_loudness(&self) -> f64 {
+        let (count, energy) = self
+            .histogram_counts
+            .iter()
+            .zip(&self.histogram_energies)
+            .fold((0, 0.0), |(count, energy), (c, e)| (count + c, energy + e));
+        if count == 0 {
+            return f64::NEG_INFINITY;
+        }
+        let gate = energy_to_loudness(energy / count as f64) + LOUDNESS_RELATIVE_GATE;
+        let first_bin = loudness_histogram_bin(gate);
+        let (count, energy) = self.histogram_counts[first_bin..]
+            .iter()
+            .zip(&self.histogram_energies[first_bin..])
+            .fold((0, 0.0), |(count, energy), (c, e)| (count + c, energy + e));
+        if count == 0 {
+            return f64::NEG_INFINITY;
+        }
+        energy_to_loudness(energy / count as f64)
+    }
+    /// Update the measurements at the end of a sub-block
+    fn end_sub_block(&mut self) {
+        self.sub_blocks[self.sub_block_pos] = self.energy_sum / self.sub_block_size as f64;
+        self.sub_block_pos = (self.sub_block_pos + 1) % SHORT_TERM_SUB_BLOCKS;
+        self.num_sub_blocks = (self.num_sub_blocks + 1).min(SHORT_TERM_SUB_BLOCKS);
+        self.energy_sum = 0.0;
+        self.counter = 0;
+        let momentary = self.window_energy(MOMENTARY_SUB_BLOCKS);
+        let short_term = self.window_energy(SHORT_TERM_SUB_BLOCKS);
+        // Gating blocks are 400 ms long and overlap by 75%
+        if self.num_sub_blocks >= MOMENTARY_SUB_BLOCKS {
+            let loudness = energy_to_loudness(momentary);
+            if loudness >= LOUDNESS_ABSOLUTE_GATE {
+                let bin = loudness_histogram_bin(loudness);
+                self.histogram_counts[bin] += 1;
+                self.histogram_energies[bin] += momentary;
+            }
+        }
+        let levels = &self.handle.levels;
+        levels
+            .momentary
+            .set(energy_to_loudness(momentary) as Sample);
+        levels
+            .short_term
+            .set(energy_to_loudness(short_term) as Sample);
+        levels.integrated.set(self.integrated_loudness() as Sample);
+    }
+}
+
+impl Gen for LoudnessMeter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        _outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        if self.handle.levels.reset.swap(false, Ordering::Acquire) {
+            self.reset();
+        }
+        let block_size = inputs.first().map_or(0, |input| input.len());
+        let num_channels = self.filters.len();
+        let mut start = 0;
+        while start < block_size {
+            let end = block_size.min(start + self.sub_block_size - self.counter);
+            for (channel, input) in inputs[..num_channels].iter().enumerate() {
+                let [high_shelf, high_pass] = &mut self.filters[channel];
+                let history = &mut self.histories[channel];
+                let mut squared_sum = 0.0;
+                for &sample in &input[start..end] {
+                    let weighted = high_pass.process(high_shelf.process(sample));
+                    squared_sum += sample_to_f64(weighted * weighted);
+                    history.copy_within(1.., 0);
+                    history[TRUE_PEAK_TAPS - 1] = sample;
+                    for kernel in &self.true_peak_kernel {
+                        let value: Sample =
+                            kernel.iter().zip(history.iter()).map(|(k, x)| k * x).sum();
+                        self.true_peak = self.true_peak.max(value.abs());
+                    }
+                }
+                self.energy_sum += sample_to_f64(self.weights[channel]) * squared_sum;
+            }
+            self.counter += end - start;
+            start = end;
+            if self.counter == self.sub_block_size {
+                self.end_sub_block();
+            }
+        }
+        self.handle
+            .levels
+            .true_peak
+            .set(20.0 * self.true_peak.log10());
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sub_block_size = ((0.1 * sample_rate) as usize).max(1);
+        // The K-weighting filter of BS.1770: a high shelf modelling the
+        // head followed by a high pass
+        for [high_shelf, high_pass] in &mut self.filters {
+            high_shelf.set(
+                BiquadKind::HighShelf,
+                1500.0,
+                std::f64::consts::FRAC_1_SQRT_2 as Sample,
+                4.0,
+                sample_rate,
+            );
+            high_pass.set(BiquadKind::HighPass, 38.0, 0.5, 0.0, sample_rate);
+        }
+        self.reset();
+    }
+    fn num_inputs(&self) -> usize {
+        self.filters.len()
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        METER_INPUT_NAMES.get(input).copied().unwrap_or("")
+    }
+    fn name(&self) -> &'static str {
+        "LoudnessMeter"
+    }
+}
+
+/// Set in the index of the back buffer when it has been written since the
+/// reader last took it
+const TRIPLE_BUFFER_NEW: usize = 4;
+
+/// Three buffers of which the writer and the reader each own one. The third,
+/// the back buffer, is swapped with the writer's buffer when it publishes and
+/// with the reader's buffer when there is something new to read, so neither
+/// side ever waits.
+struct TripleBuffer<T> {
+    buffers: [UnsafeCell<Box<[T]>>; 3],
+    back: AtomicUsize,
+}
+
+// The writer and the reader only access the buffers they own at the moment
+unsafe impl<T: Send> Sync for TripleBuffer<T> {}
+
+fn triple_buffer<T: Clone + Default>(len: usize) -> (TripleWriter<T>, TripleReader<T>) {
+    let buffer = || UnsafeCell::new(vec![T::default(); len].into_boxed_slice());
+    let shared = Arc::new(TripleBuffer {
+        buffers: [buffer(), buffer(), buffer()],
+        back: AtomicUsize::new(1),
+    });
+    (
+        TripleWriter {
+            shared: shared.clone(),
+            index: 0,
+        },
+        TripleReader { shared, index: 2 },
+    )
+}
+
+struct TripleWriter<T> {
+    shared: Arc<TripleBuffer<T>>,
+    index: usize,
+}
+
+impl<T> TripleWriter<T> {
+    fn buffer_mut(&mut self) -> &mut [T] {
+        unsafe { &mut *self.shared.buffers[self.index].get() }
+    }
+    /// Make the written buffer the latest one for the reader
+    fn publish(&mut self) {
+        let back = self
+            .shared
+            .back
+            .swap(self.index | TRIPLE_BUFFER_NEW, Ordering::AcqRel);
+        self.index = back & !TRIPLE_BUFFER_NEW;
+    }
+}
+
+struct TripleReader<T> {
+    shared: Arc<TripleBuffer<T>>,
+    index: usize,
+}
+
+impl<T> TripleReader<T> {
+    fn has_new(&self) -> bool {
+        self.shared.back.load(Ordering::Acquire) & TRIPLE_BUFFER_NEW != 0
+    }
+    /// The latest published buffer
+    fn read(&mut self) -> &[T] {
+        if self.has_new() {
+            let back = self.shared.back.swap(self.index, Ordering::AcqRel);
+            self.index = back & !TRIPLE_BUFFER_NEW;
+        }
+        unsafe { &*self.shared.buffers[self.index].get() }
+    }
+}
+
+/// Captures the waveform of its input for drawing an oscilloscope.
+///
+/// The input is "signal" and there are no outputs. Every time a capture of
+/// the configured length is complete it can be read from another thread
+/// through the [`ProbeHandle`] returned with the Probe. With triggering
+/// turned on, captures start at a rising zero crossing so that periodic
+/// signals stand still. If no zero crossing comes within one capture length
+/// the capture starts anyway.
+pub struct Probe {
+    writer: TripleWriter<Sample>,
+    capture_length: usize,
+    position: usize,
+    trigger: bool,
+    /// The number of samples waited for a zero crossing
+    waited: usize,
+    previous: Sample,
+}
+
+impl Probe {
+    /// *Allocates memory*
+    /// A Probe capturing `capture_length` samples at a time
+    pub fn new(capture_length: usize) -> (Self, ProbeHandle) {
+        let capture_length = capture_length.max(1);
+        let (writer, reader) = triple_buffer(capture_length);
+        (
+            Self {
+                writer,
+                capture_length,
+                position: 0,
+                trigger: false,
+                waited: 0,
+                previous: 0.0,
+            },
+            ProbeHandle { reader },
+        )
+    }
+    /// Start captures at rising zero crossings. Default: false
+    pub fn trigger(mut self, trigger: bool) -> Self {
+        self.trigger = trigger;
+        self
+    }
+}
+
+impl Gen for Probe {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        _outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for &sample in inputs[0].iter() {
+            let previous = self.previous;
+            self.previous = sample;
+            let waiting = self.position == 0 && self.trigger && self.waited < self.capture_length;
+            if waiting && !(previous <= 0.0 && sample > 0.0) {
+                self.waited += 1;
+                continue;
+            }
+            self.writer.buffer_mut()[self.position] = sample;
+            self.position += 1;
+            if self.position == self.capture_length {
+                self.writer.publish();
+                self.position = 0;
+                self.waited = 0;
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Probe"
+    }
+}
+
+/// Reads the captures of a [`Probe`] from another thread
+pub struct ProbeHandle {
+    reader: TripleReader<Sample>,
+}
+
+impl ProbeHandle {
+    /// If a capture has completed since the last call to
+    /// [`ProbeHandle::snapshot`]
+    pub fn has_new_snapshot(&self) -> bool {
+        self.reader.has_new()
+    }
+    /// The latest complete capture, zeros before the first one
+    pub fn snapshot(&mut self) -> &[Sample] {
+        self.reader.read()
+    }
+}
+
+/// Computes the magnitude spectrum of its input for drawing spectra and
+/// spectrograms.
+///
+/// The input is "signal" and there are no outputs. Every `fft_size / overlap`
+/// samples the latest `fft_size` samples are windowed with a Hann window and
+/// transformed on the audio thread. The magnitudes of the bins from 0 Hz to
+/// the Nyquist frequency can then be read from another thread through the
+/// [`SpectrumHandle`] returned with the analyzer. Bin `n` is centered on
+/// `n * sample_rate / fft_size` Hz and a sine with an amplitude of 1 at the
+/// center of a bin has a magnitude of 1.
+pub struct SpectrumAnalyzer {
+    fft: Arc<dyn Fft<Sample>>,
+    window: Vec<Sample>,
+    /// The latest input used as a ring buffer
+    input: Vec<Sample>,
+    input_pos: usize,
+    hop_size: usize,
+    hop_counter: usize,
+    spectrum: Vec<Complex<Sample>>,
+    scratch: Vec<Complex<Sample>>,
+    gain: Sample,
+    writer: TripleWriter<Sample>,
+}
+
+impl SpectrumAnalyzer {
+    /// *Allocates memory*
+    /// `fft_size` is rounded up to a power of two. `overlap` is the number of
+    /// frames overlapping every sample.
+    pub fn new(fft_size: usize, overlap: usize) -> (Self, SpectrumHandle) {
+        let fft_size = fft_size.max(4).next_power_of_two();
+        let hop_size = (fft_size / overlap.max(1)).max(1);
+        let fft = FftPlanner::new().plan_fft_forward(fft_size);
+        let window = hann_window(fft_size);
+        let gain = 2.0 / window.iter().sum::<Sample>();
+        let (writer, reader) = triple_buffer(fft_size / 2 + 1);
+        (
+            Self {
+                scratch: vec![Complex::default(); fft.get_inplace_scratch_len()],
+                fft,
+                window,
+                input: vec![0.0; fft_size],
+                input_pos: 0,
+                hop_size,
+                hop_counter: 0,
+                spectrum: vec![Complex::default(); fft_size],
+                gain,
+                writer,
+            },
+            SpectrumHandle { reader, fft_size },
+        )
+    }
+    fn analyze(&mut self) {
+        let len = self.input.len();
+        for (i, (bin, w)) in self.spectrum.iter_mut().zip(&self.window).enumerate() {
+            *bin = Complex::new(self.input[(self.input_pos + i) % len] * w, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        let gain = self.gain;
+        for (magnitude, bin) in self.writer.buffer_mut().iter_mut().zip(&self.spectrum) {
+            *magnitude = bin.norm() * gain;
+        }
+        // The 0 Hz and Nyquist bins have no mirrored negative frequency
+        let magnitudes = self.writer.buffer_mut();
+        let last = magnitudes.len() - 1;
+        magnitudes[0] *= 0.5;
+        magnitudes[last] *= 0.5;
+        self.writer.publish();
+    }
+}
+
+impl Gen for SpectrumAnalyzer {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        _outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let len = self.input.len();
+        for &sample in inputs[0].iter() {
+            self.input[self.input_pos] = sample;
+            self.input_pos = (self.input_pos + 1) % len;
+            self.hop_counter += 1;
+            if self.hop_counter >= self.hop_size {
+                self.hop_counter = 0;
+                self.analyze();
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "SpectrumAnalyzer"
+    }
+}
+
+/// Reads the spectra of a [`SpectrumAnalyzer`] from another thread
+pub struct SpectrumHandle {
+    reader: TripleReader<Sample>,
+    fft_size: usize,
+}
+
+impl SpectrumHandle {
+    pub fn fft_size(&self) -> usize {
+        self.fft_size
+    }
+    /// The center frequency of `bin` in Hz
+    pub fn bin_freq(&self, bin: usize, sample_rate: Sample) -> Sample {
+        bin as Sample * sample_rate / self.fft_size as Sample
+    }
+    /// If a spectrum has been computed since the last call to
+    /// [`SpectrumHandle::magnitudes`]
+    pub fn has_new_spectrum(&self) -> bool {
+        self.reader.has_new()
+    }
+    /// The magnitudes of the latest spectrum, `fft_size / 2 + 1` bins from
+    /// 0 Hz to the Nyquist frequency
+    pub fn magnitudes(&mut self) -> &[Sample] {
+        self.reader.read()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn follows_the_level() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let len = 4800;
+        let sine: Vec<Sample> = (0..len)
+            .map(|i| 0.5 * (std::f64::consts::TAU * 480.0 * i as f64 / 48000.0).sin() as Sample)
+            .collect();
+        let inputs = [
+            sine.into_boxed_slice(),
+            vec![0.001; len].into_boxed_slice(),
+            vec![0.1; len].into_boxed_slice(),
+        ];
+        let mut outputs = [vec![0.0; len].into_boxed_slice()];
+        let mut rms = EnvelopeFollower::rms(0.01);
+        rms.init(48000.);
+        let handle = rms.handle();
+        rms.process(&inputs, &mut outputs, &mut resources);
+        // The RMS of a sine is its amplitude / sqrt(2)
+        assert!((handle.get() - 0.5 / (2.0 as Sample).sqrt()).abs() < 0.01);
+        assert_eq!(handle.get(), outputs[0][len - 1]);
+        let mut peak = EnvelopeFollower::peak();
+        peak.init(48000.);
+        peak.process(&inputs, &mut outputs, &mut resources);
+        // A slow release holds the envelope close to the peak
+        assert!((peak.handle().get() - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn meters_peak_and_rms() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut meter = Meter::new(2).window(0.001);
+        // A window of 48 samples
+        meter.init(48000.);
+        let handle = meter.handle();
+        let square: Vec<Sample> = (0..32)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect();
+        let inputs = [square.into_boxed_slice(), vec![0.0; 32].into_boxed_slice()];
+        meter.process(&inputs, &mut [], &mut resources);
+        // The window isn't complete yet
+        assert_eq!(handle.peak(0), 0.0);
+        meter.process(&inputs, &mut [], &mut resources);
+        assert_eq!(handle.peak(0), 0.5);
+        assert!((handle.rms(0) - 0.5).abs() < 1e-6);
+        assert_eq!(handle.peak(1), 0.0);
+        assert_eq!(handle.rms(2), 0.0);
+    }
+
+    #[test]
+    fn measures_loudness() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut meter = LoudnessMeter::new(2);
+        meter.init(48000.);
+        let handle = meter.handle();
+        assert_eq!(handle.integrated(), Sample::NEG_INFINITY);
+        let sine = |amplitude: f64, freq: f64, phase: f64| {
+            let samples: Vec<Sample> = (0..4800)
+                .map(|i| {
+                    (amplitude * (std::f64::consts::TAU * freq * i as f64 / 48000.0 + phase).sin())
+                        as Sample
+                })
+                .collect();
+            [
+                samples.into_boxed_slice(),
+                vec![0.0; 4800].into_boxed_slice(),
+            ]
+        };
+        // A full scale 1 kHz sine in one channel is -3.01 LUFS
+        let loud = sine(1.0, 1000.0, 0.0);
+        for _ in 0..40 {
+            meter.process(&loud, &mut [], &mut resources);
+        }
+        assert!(
+            (handle.momentary() + 3.01).abs() < 0.05,
+            "{}",
+            handle.momentary()
+        );
+        assert!((handle.short_term() + 3.01).abs() < 0.05);
+        assert!((handle.integrated() + 3.01).abs() < 0.05);
+        // Quiet parts are gated out of the integrated loudness
+        let quiet = sine(0.01, 1000.0, 0.0);
+        for _ in 0..40 {
+            meter.process(&quiet, &mut [], &mut resources);
+        }
+        assert!((handle.momentary() + 43.01).abs() < 0.05);
+        // Only the blocks overlapping the loud part are above the relative
+        // gate
+        assert!((handle.integrated() + 3.01).abs() < 0.3);
+        handle.reset();
+        for _ in 0..4 {
+            meter.process(&quiet, &mut [], &mut resources);
+        }
+        assert!((handle.integrated() + 43.01).abs() < 0.05);
+    }
+
+    #[test]
+    fn measures_true_peak() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut meter = LoudnessMeter::new(1);
+        meter.init(48000.);
+        let handle = meter.handle();
+        // The samples of this sine never get above 0.71, but the signal
+        // reaches 1 between them
+        let sine: Vec<Sample> = (0..4800)
+            .map(|i| {
+                (std::f64::consts::FRAC_PI_2 * i as f64 + std::f64::consts::FRAC_PI_4).sin()
+                    as Sample
+            })
+            .collect();
+        meter.process(&[sine.into_boxed_slice()], &mut [], &mut resources);
+        assert!(handle.true_peak().abs() < 0.2, "{}", handle.true_peak());
+    }
+
+    #[test]
+    fn probe_captures() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let (probe, mut handle) = Probe::new(4);
+        let mut probe = probe.trigger(true);
+        let ramp: Vec<Sample> = (0..8).map(|i| i as Sample - 2.5).collect();
+        probe.process(&[ramp.into_boxed_slice()], &mut [], &mut resources);
+        assert!(handle.has_new_snapshot());
+        // Starts at the zero crossing from -0.5 to 0.5
+        assert_eq!(handle.snapshot(), &[0.5, 1.5, 2.5, 3.5]);
+        assert!(!handle.has_new_snapshot());
+        // Without a zero crossing the capture starts after one capture length
+        probe.process(&[vec![1.0; 3].into_boxed_slice()], &mut [], &mut resources);
+        assert!(!handle.has_new_snapshot());
+        probe.process(&[vec![1.0; 4].into_boxed_slice()], &mut [], &mut resources);
+        assert_eq!(handle.snapshot(), &[1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn analyzes_spectrum() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let (mut analyzer, mut handle) = SpectrumAnalyzer::new(256, 2);
+        // A sine at the center of bin 16
+        let freq = handle.bin_freq(16, 48000.);
+        let sine: Vec<Sample> = (0..256)
+            .map(|i| {
+                0.5 * (std::f64::consts::TAU * sample_to_f64(freq) * i as f64 / 48000.0).sin()
+                    as Sample
+            })
+            .collect();
+        analyzer.process(&[sine.into_boxed_slice()], &mut [], &mut resources);
+        assert!(handle.has_new_spectrum());
+        let magnitudes = handle.magnitudes();
+        assert_eq!(magnitudes.len(), 129);
+        assert!((magnitudes[16] - 0.5).abs() < 0.01);
+        assert!(magnitudes[40] < 0.001);
+    }
+
+    #[test]
+    fn tracks_pitch() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let len = 4800;
+        // A tone with a few harmonics
+        let tone: Vec<Sample> = (0..len)
+            .map(|i| {
+                let phase = std::f64::consts::TAU * 220.0 * i as f64 / 48000.0;
+                (phase.sin() + 0.5 * (phase * 2.0).sin() + 0.3 * (phase * 3.0).sin()) as Sample
+            })
+            .collect();
+        let mut tracker = PitchTracker::new(80.0);
+        tracker.init(48000.);
+        let freq = tracker.freq_handle();
+        let confidence = tracker.confidence_handle();
+        let mut outputs = [
+            vec![0.0; len].into_boxed_slice(),
+            vec![0.0; len].into_boxed_slice(),
+        ];
+        tracker.process(&[tone.into_boxed_slice()], &mut outputs, &mut resources);
+        assert!((freq.get() - 220.0).abs() < 1.0);
+        assert!(confidence.get() > 0.9);
+        // Noise has no clear pitch
+        let rng = fastrand::Rng::with_seed(1);
+        let noise: Vec<Sample> = (0..len).map(|_| rng.f32() as Sample - 0.5).collect();
+        tracker.process(&[noise.into_boxed_slice()], &mut outputs, &mut resources);
+        assert!(confidence.get() < 0.8);
+    }
+}
@@ -2,7 +2,8 @@
 //! To use the backends in this module you need to enable either the jack or the cpal feature.
 //!
 //! [`JackBackend`] currently has better support including a duplex client with
-//! the same number of inputs and outputs as the [`Graph`].
+//! the same number of inputs and outputs as the [`Graph`] and MIDI ports, see
+//! [`JackBackend::enable_midi`].
 //!
 //! To use an [`AudioBackend`], first create it to get the parameters of the
 //! system. When you have created your main graph, call
@@ -11,11 +12,61 @@
 //! output of the [`Graph`]. From this point, the [`Graph`] is considered to be
 //! running, meaning changes to the [`Graph`] may take longer to perform since
 //! they involve the audio thread.
+//!
+//! The [`Resources`] are moved to the audio thread when processing starts. To
+//! add or remove buffers, wavetables and user data after that, create a
+//! [`ResourcesCommandSender`] with [`Resources::command_channel`] first.
+//!
+//! Errors and other events on the audio thread, e.g. xruns and panics while
+//! processing the Graph, are reported through the [`BackendEventReceiver`]
+//! returned by [`AudioBackend::error_receiver`].
+//!
+//! The block size of the [`Graph`] doesn't have to match the number of frames
+//! the device asks for in each callback. Choose a small block size for a finer
+//! resolution of scheduled changes or a large one for throughput. As long as
+//! every callback is a multiple of the block size the Graph is processed
+//! without added latency. Otherwise the backend buffers one block of inputs
+//! and outputs, adding one block of latency.
+//!
+//! If the device runs at a different sample rate than the [`Graph`], e.g.
+//! because the device doesn't support the rate the [`Graph`] and
+//! [`Resources`] were built for, everything plays at the wrong pitch. Enable
+//! resampling with [`JackBackend::enable_resampling`] or
+//! [`CpalBackendOptions::resample`] to convert the inputs and outputs at the
+//! boundary of the backend instead, at the cost of some latency and CPU.
+//!
+//! # Low latency on Windows
+//!
+//! The default WASAPI host on Windows runs in shared mode which has a large
+//! latency. For live performance, enable the `asio` feature and choose the
+//! "ASIO" host with a fixed buffer size:
+//!
+//! ```no_run
+//! # #[cfg(feature = "cpal")]
+//! # {
+//! # use knyst::audio_backend::{CpalBackend, CpalBackendOptions};
+//! let backend = CpalBackend::new(CpalBackendOptions::default().host("ASIO").buffer_size(64));
+//! # }
+//! ```
+//!
+//! WASAPI exclusive mode is not supported since cpal always opens WASAPI
+//! streams in shared mode. A fixed buffer size can be requested from WASAPI
+//! as well, but the latency of the Windows mixer remains.
+
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 
-use crate::{graph::Graph, Resources};
+use crate::denormal::DenormalGuard;
+use crate::graph::{Graph, Node, ToNodeError};
+use crate::resample::{ResampleQuality, Resampler};
+use crate::{Resources, Sample};
+// Import for docs
+#[allow(unused_imports)]
+use crate::ResourcesCommandSender;
 
 #[cfg(feature = "cpal")]
-pub use cpal_backend::{CpalBackend, CpalBackendOptions};
+pub use cpal_backend::{CpalBackend, CpalBackendOptions, CpalConfigRange, CpalDeviceInfo};
 #[cfg(feature = "jack")]
 pub use jack_backend::JackBackend;
 
@@ -28,6 +79,437 @@ pub trait AudioBackend {
     fn stop(&mut self) -> Result<(), AudioBackendError>;
     fn sample_rate(&self) -> usize;
     fn block_size(&self) -> Option<usize>;
+    /// Take the receiver of errors and other events from the audio thread.
+    /// Returns None if it has already been taken.
+    fn error_receiver(&mut self) -> Option<BackendEventReceiver>;
+}
+
+/// The number of events that can wait in a [`BackendEventReceiver`] before
+/// new events are dropped
+const EVENT_CAPACITY: usize = 64;
+
+/// Something that happened in an audio backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendEvent {
+    /// The stream or audio server reported an error
+    StreamError(String),
+    /// The audio wasn't delivered in time
+    Xrun,
+    /// Processing the Graph panicked. The backend outputs silence from then on.
+    GraphPanic(String),
+    /// The audio server shut the backend down
+    Shutdown(String),
+    /// The audio server changed the sample rate. The Graph, its Gens and the
+    /// Resources have been updated to the new sample rate, unless resampling
+    /// is enabled in the backend in which case the Graph keeps its rate.
+    SampleRateChanged(usize),
+    /// The audio server changed the number of frames per callback. The Graph
+    /// keeps its block size, see the [module documentation](self).
+    BlockSizeChanged(usize),
+}
+
+/// Receives [`BackendEvent`]s from the audio thread without blocking it
+pub struct BackendEventReceiver {
+    consumers: Vec<rtrb::Consumer<BackendEvent>>,
+}
+
+impl BackendEventReceiver {
+    /// The next event, or None if there are no events waiting
+    pub fn try_recv(&mut self) -> Option<BackendEvent> {
+        self.consumers
+            .iter_mut()
+            .find_map(|consumer| consumer.pop().ok())
+    }
+}
+
+/// Create a [`BackendEventReceiver`] and a sender for each thread that
+/// reports events
+pub(crate) fn event_channels<const N: usize>(
+) -> ([rtrb::Producer<BackendEvent>; N], BackendEventReceiver) {
+    let mut consumers = Vec::with_capacity(N);
+    let producers = std::array::from_fn(|_| {
+        let (producer, consumer) = rtrb::RingBuffer::new(EVENT_CAPACITY);
+        consumers.push(consumer);
+        producer
+    });
+    (producers, BackendEventReceiver { consumers })
+}
+
+/// Processes the main node, catching panics so that they can be reported
+/// instead of taking down the audio thread.
+pub(crate) struct ProcessGuard {
+    panicked: bool,
+    events: rtrb::Producer<BackendEvent>,
+}
+
+#[cfg_attr(not(any(feature = "jack", feature = "cpal")), allow(dead_code))]
+impl ProcessGuard {
+    pub(crate) fn new(events: rtrb::Producer<BackendEvent>) -> Self {
+        Self {
+            panicked: false,
+            events,
+        }
+    }
+    /// Returns false if the node has panicked, now or before, in which case
+    /// its outputs should not be used.
+    pub(crate) fn process(
+        &mut self,
+        node: &mut Node,
+        input_buffers: &[Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> bool {
+        if self.panicked {
+            return false;
+        }
+        let _denormals = DenormalGuard::new();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            node.process(input_buffers, resources);
+        }));
+        if let Err(payload) = result {
+            self.panicked = true;
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                message.to_string()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            self.events.push(BackendEvent::GraphPanic(message)).ok();
+        }
+        !self.panicked
+    }
+    /// Report an event that isn't a panic
+    pub(crate) fn report(&mut self, event: BackendEvent) {
+        self.events.push(event).ok();
+    }
+}
+
+/// Processes a node with a fixed block size from callbacks with any number of
+/// frames.
+///
+/// Blocks are processed directly while every callback is a multiple of the
+/// block size. After the first callback that isn't, inputs and outputs are
+/// buffered for the rest of the stream, delaying them by one block.
+pub(crate) struct BlockAdapter {
+    node: Node,
+    block_size: usize,
+    input_buffers: Box<[Box<[Sample]>]>,
+    silence: Box<[Sample]>,
+    /// The current frame in the block when buffering
+    position: usize,
+    buffering: bool,
+    /// If the outputs of the node are from a successfully processed block
+    processed: bool,
+}
+
+impl BlockAdapter {
+    /// *Allocates memory*
+    pub(crate) fn new(node: Node, block_size: usize) -> Self {
+        let input_buffers =
+            vec![vec![0.0; block_size].into_boxed_slice(); node.num_inputs()].into_boxed_slice();
+        Self {
+            node,
+            block_size,
+            input_buffers,
+            silence: vec![0.0; block_size].into_boxed_slice(),
+            position: 0,
+            buffering: false,
+            processed: false,
+        }
+    }
+    /// *Allocates memory*
+    /// Always buffer, for a constant latency of one block
+    pub(crate) fn buffered(node: Node, block_size: usize) -> Self {
+        Self {
+            buffering: true,
+            ..Self::new(node, block_size)
+        }
+    }
+    /// The current latency in frames
+    pub(crate) fn latency(&self) -> usize {
+        if self.buffering {
+            self.block_size
+        } else {
+            0
+        }
+    }
+    pub(crate) fn node_mut(&mut self) -> &mut Node {
+        &mut self.node
+    }
+    /// Process `frames` frames of the device. `read_input(channel, frames,
+    /// buffer)` should fill `buffer` with the given frames of an input
+    /// channel and `write_output(channel, frames, samples)` should write
+    /// `samples` to the given frames of an output channel.
+    pub(crate) fn process(
+        &mut self,
+        frames: usize,
+        guard: &mut ProcessGuard,
+        resources: &mut Resources,
+        mut read_input: impl FnMut(usize, Range<usize>, &mut [Sample]),
+        mut write_output: impl FnMut(usize, Range<usize>, &[Sample]),
+    ) {
+        let partial_block = frames % self.block_size;
+        if !self.buffering && partial_block > 0 {
+            // The outputs of the last block have already been written
+            self.buffering = true;
+            self.processed = false;
+        }
+        let mut offset = 0;
+        while offset < frames {
+            let len = (frames - offset).min(self.block_size - self.position);
+            let device_frames = offset..offset + len;
+            let block_frames = self.position..self.position + len;
+            for (channel, buffer) in self.input_buffers.iter_mut().enumerate() {
+                read_input(
+                    channel,
+                    device_frames.clone(),
+                    &mut buffer[block_frames.clone()],
+                );
+            }
+            if self.buffering {
+                self.write_outputs(&mut write_output, device_frames.clone(), block_frames);
+            }
+            self.position += len;
+            if self.position == self.block_size {
+                self.processed = guard.process(&mut self.node, &self.input_buffers, resources);
+                self.position = 0;
+                if !self.buffering {
+                    self.write_outputs(&mut write_output, device_frames, 0..self.block_size);
+                }
+            }
+            offset += len;
+        }
+    }
+    fn write_outputs(
+        &self,
+        write_output: &mut impl FnMut(usize, Range<usize>, &[Sample]),
+        device_frames: Range<usize>,
+        block_frames: Range<usize>,
+    ) {
+        for channel in 0..self.node.num_outputs() {
+            let samples = if self.processed {
+                &self.node.output_buffers()[channel][block_frames.clone()]
+            } else {
+                &self.silence[block_frames.clone()]
+            };
+            write_output(channel, device_frames.clone(), samples);
+        }
+    }
+}
+
+/// Processes a node running at a different sample rate than the device,
+/// converting the inputs and outputs with a [`Resampler`] each.
+///
+/// Inputs are converted to the rate of the Graph and queued until the next
+/// block is processed, starting with one block of silence. Outputs are
+/// converted frame by frame as the device asks for them.
+#[cfg_attr(not(any(feature = "jack", feature = "cpal")), allow(dead_code))]
+pub(crate) struct ResamplingAdapter {
+    node: Node,
+    block_size: usize,
+    graph_rate: f64,
+    input_buffers: Box<[Box<[Sample]>]>,
+    /// Interleaved inputs at the rate of the Graph waiting to be processed
+    input_queue: VecDeque<Sample>,
+    input_resampler: Resampler,
+    output_resampler: Resampler,
+    /// The next frame of the outputs of the node to resample
+    block_position: usize,
+    /// If the outputs of the node are from a successfully processed block
+    processed: bool,
+    frame: Vec<Sample>,
+}
+
+#[cfg_attr(not(any(feature = "jack", feature = "cpal")), allow(dead_code))]
+impl ResamplingAdapter {
+    /// *Allocates memory*
+    pub(crate) fn new(node: Node, block_size: usize, graph_rate: f64, device_rate: f64) -> Self {
+        let num_inputs = node.num_inputs();
+        let num_outputs = node.num_outputs();
+        let input_buffers =
+            vec![vec![0.0; block_size].into_boxed_slice(); num_inputs].into_boxed_slice();
+        let mut input_queue = VecDeque::with_capacity(num_inputs * block_size * 4);
+        input_queue.resize(num_inputs * block_size, 0.0);
+        Self {
+            input_resampler: Resampler::new(
+                num_inputs,
+                device_rate,
+                graph_rate,
+                ResampleQuality::Medium,
+            ),
+            output_resampler: Resampler::new(
+                num_outputs,
+                graph_rate,
+                device_rate,
+                ResampleQuality::Medium,
+            ),
+            node,
+            block_size,
+            graph_rate,
+            input_buffers,
+            input_queue,
+            block_position: block_size,
+            processed: false,
+            frame: vec![0.0; num_inputs.max(num_outputs)],
+        }
+    }
+    /// *Allocates memory*
+    pub(crate) fn set_device_rate(&mut self, device_rate: f64) {
+        self.input_resampler = Resampler::new(
+            self.node.num_inputs(),
+            device_rate,
+            self.graph_rate,
+            ResampleQuality::Medium,
+        );
+        self.output_resampler = Resampler::new(
+            self.node.num_outputs(),
+            self.graph_rate,
+            device_rate,
+            ResampleQuality::Medium,
+        );
+    }
+    /// Process `frames` frames of the device, see [`BlockAdapter::process`]
+    pub(crate) fn process(
+        &mut self,
+        frames: usize,
+        guard: &mut ProcessGuard,
+        resources: &mut Resources,
+        mut read_input: impl FnMut(usize, Range<usize>, &mut [Sample]),
+        mut write_output: impl FnMut(usize, Range<usize>, &[Sample]),
+    ) {
+        let num_inputs = self.node.num_inputs();
+        let num_outputs = self.node.num_outputs();
+        for device_frame in 0..frames {
+            let device_frames = device_frame..device_frame + 1;
+            if num_inputs > 0 {
+                for channel in 0..num_inputs {
+                    read_input(
+                        channel,
+                        device_frames.clone(),
+                        &mut self.frame[channel..channel + 1],
+                    );
+                }
+                self.input_resampler.push_frame(&self.frame[..num_inputs]);
+                while !self.input_resampler.needs_input() {
+                    self.input_resampler
+                        .next_frame(&mut self.frame[..num_inputs]);
+                    // Drop frames rather than allocate if the queue is full
+                    if self.input_queue.len() + num_inputs <= self.input_queue.capacity() {
+                        self.input_queue.extend(&self.frame[..num_inputs]);
+                    }
+                }
+            }
+            while self.output_resampler.needs_input() {
+                if self.block_position == self.block_size {
+                    self.process_block(guard, resources);
+                }
+                for channel in 0..num_outputs {
+                    self.frame[channel] = if self.processed {
+                        self.node.output_buffers()[channel][self.block_position]
+                    } else {
+                        0.0
+                    };
+                }
+                self.output_resampler.push_frame(&self.frame[..num_outputs]);
+                self.block_position += 1;
+            }
+            self.output_resampler
+                .next_frame(&mut self.frame[..num_outputs]);
+            for channel in 0..num_outputs {
+                write_output(
+                    channel,
+                    device_frames.clone(),
+                    &self.frame[channel..channel + 1],
+                );
+            }
+        }
+    }
+    fn process_block(&mut self, guard: &mut ProcessGuard, resources: &mut Resources) {
+        let num_inputs = self.input_buffers.len();
+        for i in 0..self.block_size {
+            let available = self.input_queue.len() >= num_inputs;
+            for buffer in self.input_buffers.iter_mut() {
+                buffer[i] = if available {
+                    self.input_queue.pop_front().unwrap_or(0.0)
+                } else {
+                    0.0
+                };
+            }
+        }
+        self.processed = guard.process(&mut self.node, &self.input_buffers, resources);
+        self.block_position = 0;
+    }
+}
+
+/// Connects a node to the callbacks of a device, resampling if the node runs
+/// at a different sample rate and resampling was enabled in the backend.
+#[cfg_attr(not(any(feature = "jack", feature = "cpal")), allow(dead_code))]
+pub(crate) enum DeviceAdapter {
+    Block(Box<BlockAdapter>),
+    Resampling(Box<ResamplingAdapter>),
+}
+
+#[cfg_attr(not(any(feature = "jack", feature = "cpal")), allow(dead_code))]
+impl DeviceAdapter {
+    /// *Allocates memory*
+    pub(crate) fn new(
+        node: Node,
+        block_size: usize,
+        graph_rate: f64,
+        device_rate: f64,
+        resample: bool,
+    ) -> Self {
+        if graph_rate == device_rate {
+            DeviceAdapter::Block(Box::new(BlockAdapter::new(node, block_size)))
+        } else if resample {
+            DeviceAdapter::Resampling(Box::new(ResamplingAdapter::new(
+                node,
+                block_size,
+                graph_rate,
+                device_rate,
+            )))
+        } else {
+            eprintln!("Warning: The Graph runs at a sample rate of {graph_rate} but the device at {device_rate}. Everything will play at the wrong pitch unless resampling is enabled in the backend.");
+            DeviceAdapter::Block(Box::new(BlockAdapter::new(node, block_size)))
+        }
+    }
+    /// *Allocates memory*
+    /// Returns true if the node and the [`Resources`] should be changed to
+    /// the new sample rate of the device, false if the node keeps its rate
+    /// and is resampled instead.
+    #[cfg_attr(not(feature = "jack"), allow(dead_code))]
+    pub(crate) fn device_rate_changed(&mut self, device_rate: f64) -> bool {
+        match self {
+            DeviceAdapter::Block(adapter) => {
+                adapter
+                    .node_mut()
+                    .sample_rate_changed(device_rate as Sample);
+                true
+            }
+            DeviceAdapter::Resampling(adapter) => {
+                adapter.set_device_rate(device_rate);
+                false
+            }
+        }
+    }
+    /// Process `frames` frames of the device, see [`BlockAdapter::process`]
+    pub(crate) fn process(
+        &mut self,
+        frames: usize,
+        guard: &mut ProcessGuard,
+        resources: &mut Resources,
+        read_input: impl FnMut(usize, Range<usize>, &mut [Sample]),
+        write_output: impl FnMut(usize, Range<usize>, &[Sample]),
+    ) {
+        match self {
+            DeviceAdapter::Block(adapter) => {
+                adapter.process(frames, guard, resources, read_input, write_output)
+            }
+            DeviceAdapter::Resampling(adapter) => {
+                adapter.process(frames, guard, resources, read_input, write_output)
+            }
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -37,15 +519,30 @@ pub enum AudioBackendError {
     #[error("You tried to stop a backend that was already stopped.")]
     BackendNotRunning,
     #[error("Unable to create a node from the Graph: {0}")]
-    CouldNotCreateNode(String),
+    CouldNotCreateNode(ToNodeError),
     #[cfg(feature = "jack")]
     #[error(transparent)]
     JackError(#[from] jack::Error),
+    #[error("No host named \"{0}\" is available.")]
+    HostNotFound(String),
+    #[error("No output device named \"{0}\" was found.")]
+    DeviceNotFound(String),
+    #[error("The device doesn't support {0}.")]
+    UnsupportedConfig(String),
+    #[cfg(feature = "cpal")]
+    #[error(transparent)]
+    CpalHostUnavailable(#[from] cpal::HostUnavailable),
     #[cfg(feature = "cpal")]
     #[error(transparent)]
     CpalDevicesError(#[from] cpal::DevicesError),
     #[cfg(feature = "cpal")]
     #[error(transparent)]
+    CpalSupportedStreamConfigsError(#[from] cpal::SupportedStreamConfigsError),
+    #[cfg(feature = "cpal")]
+    #[error(transparent)]
+    CpalDefaultStreamConfigError(#[from] cpal::DefaultStreamConfigError),
+    #[cfg(feature = "cpal")]
+    #[error(transparent)]
     CpalDeviceNameError(#[from] cpal::DeviceNameError),
     #[cfg(feature = "cpal")]
     #[error(transparent)]
@@ -60,8 +557,12 @@ pub enum AudioBackendError {
 
 #[cfg(feature = "jack")]
 mod jack_backend {
-    use crate::audio_backend::{AudioBackend, AudioBackendError};
-    use crate::{graph::Graph, graph::Node, Resources, Sample};
+    use crate::audio_backend::{
+        event_channels, AudioBackend, AudioBackendError, BackendEvent, BackendEventReceiver,
+        DeviceAdapter, ProcessGuard,
+    };
+    use crate::midi::{MidiEvent, MidiMessage};
+    use crate::{graph::Graph, Resources, Sample};
     enum JackClient {
         Passive(jack::Client),
         Active(jack::AsyncClient<JackNotifications, JackProcess>),
@@ -71,6 +572,19 @@ mod jack_backend {
         client: Option<JackClient>,
         sample_rate: usize,
         block_size: usize,
+        midi: Option<JackMidi>,
+        resample: bool,
+        /// Senders of events from the process callback and the notification
+        /// handler, moved to them when processing starts
+        event_senders: Option<[rtrb::Producer<BackendEvent>; 2]>,
+        event_receiver: Option<BackendEventReceiver>,
+    }
+
+    /// The audio thread ends of the MIDI channels
+    struct JackMidi {
+        incoming: Option<rtrb::Producer<MidiEvent>>,
+        /// One receiver for every sender of outgoing events
+        outgoing: Vec<rtrb::Consumer<MidiEvent>>,
     }
 
     impl JackBackend {
@@ -80,12 +594,60 @@ mod jack_backend {
                 jack::Client::new(name.as_ref(), jack::ClientOptions::NO_START_SERVER).unwrap();
             let sample_rate = client.sample_rate();
             let block_size = client.buffer_size() as usize;
+            let (event_senders, event_receiver) = event_channels();
             Ok(Self {
                 client: Some(JackClient::Passive(client)),
                 sample_rate,
                 block_size,
+                midi: None,
+                resample: false,
+                event_senders: Some(event_senders),
+                event_receiver: Some(event_receiver),
             })
         }
+        /// Resample the inputs and outputs of the Graph if it runs at a
+        /// different sample rate than the JACK server, also after the server
+        /// changes its sample rate. Adds the latency of the resampler.
+        pub fn enable_resampling(&mut self) {
+            self.resample = true;
+        }
+        /// Add a "midi_in" and a "midi_out" port when processing starts.
+        /// Returns a receiver of the [`MidiEvent`]s arriving at "midi_in" and
+        /// a sender of events to send from "midi_out". `capacity` is the
+        /// number of events each channel can hold.
+        ///
+        /// Outgoing events must be sent in the order of their timestamps.
+        /// Events with a timestamp that has already passed are sent at the
+        /// start of the next block. Events sent from the Graph are sent in
+        /// the same block if the block size of the Graph matches JACK. Incoming events are dropped if the
+        /// receiver is full.
+        pub fn enable_midi(
+            &mut self,
+            capacity: usize,
+        ) -> (rtrb::Consumer<MidiEvent>, rtrb::Producer<MidiEvent>) {
+            let (incoming, receiver) = rtrb::RingBuffer::new(capacity);
+            let sender = self.add_midi_output(capacity);
+            if let Some(midi) = &mut self.midi {
+                midi.incoming = Some(incoming);
+            }
+            (receiver, sender)
+        }
+        /// Returns another sender of events to send from "midi_out", e.g. for
+        /// a [`MidiNoteOut`](crate::midi::MidiNoteOut) in the Graph. Events
+        /// from all senders are merged in the order of their timestamps.
+        /// Adds the MIDI ports if [`JackBackend::enable_midi`] hasn't been
+        /// called.
+        pub fn add_midi_output(&mut self, capacity: usize) -> rtrb::Producer<MidiEvent> {
+            let (sender, outgoing) = rtrb::RingBuffer::new(capacity);
+            self.midi
+                .get_or_insert_with(|| JackMidi {
+                    incoming: None,
+                    outgoing: vec![],
+                })
+                .outgoing
+                .push(outgoing);
+            sender
+        }
     }
 
     impl AudioBackend for JackBackend {
@@ -109,22 +671,43 @@ mod jack_backend {
                         client.register_port(&format!("out_{i}"), jack::AudioOut::default())?,
                     );
                 }
-                let mut input_buffers = vec![];
-                for _ in 0..num_inputs {
-                    input_buffers.push(vec![0.0; graph.block_size()].into_boxed_slice());
-                }
-                let input_buffers = input_buffers.into_boxed_slice();
+                let midi = match self.midi.take() {
+                    Some(midi) => Some(JackMidiPorts {
+                        in_port: client.register_port("midi_in", jack::MidiIn::default())?,
+                        out_port: client.register_port("midi_out", jack::MidiOut::default())?,
+                        channels: midi,
+                    }),
+                    None => None,
+                };
+                let [process_events, notification_events] = self
+                    .event_senders
+                    .take()
+                    .expect("the event senders are only taken when processing starts");
+                let (sample_rate_sender, sample_rate_receiver) = rtrb::RingBuffer::new(16);
                 let jack_process = JackProcess {
-                    main_node: node,
-                    input_buffers,
+                    adapter: DeviceAdapter::new(
+                        node,
+                        graph.block_size(),
+                        graph.sample_rate() as f64,
+                        self.sample_rate as f64,
+                        self.resample,
+                    ),
                     resources,
                     in_ports,
                     out_ports,
+                    midi,
+                    sample_counter: 0,
+                    guard: ProcessGuard::new(process_events),
+                    sample_rate: self.sample_rate,
+                    sample_rate_changes: sample_rate_receiver,
+                    block_size: self.block_size,
+                };
+                let notifications = JackNotifications {
+                    events: notification_events,
+                    sample_rate_changes: sample_rate_sender,
                 };
                 // Activate the client, which starts the processing.
-                let active_client = client
-                    .activate_async(JackNotifications, jack_process)
-                    .unwrap();
+                let active_client = client.activate_async(notifications, jack_process).unwrap();
                 self.client = Some(JackClient::Active(active_client));
             } else {
                 return Err(AudioBackendError::BackendAlreadyRunning);
@@ -148,38 +731,136 @@ mod jack_backend {
         fn block_size(&self) -> Option<usize> {
             Some(self.block_size)
         }
+
+        fn error_receiver(&mut self) -> Option<BackendEventReceiver> {
+            self.event_receiver.take()
+        }
+    }
+
+    struct JackMidiPorts {
+        in_port: jack::Port<jack::MidiIn>,
+        out_port: jack::Port<jack::MidiOut>,
+        channels: JackMidi,
+    }
+
+    impl JackMidiPorts {
+        fn process(&mut self, ps: &jack::ProcessScope, block_start: u64) {
+            for raw in self.in_port.iter(ps) {
+                if let (Some(incoming), Some(message)) = (
+                    &mut self.channels.incoming,
+                    MidiMessage::from_bytes(raw.bytes),
+                ) {
+                    // Drop the event if the receiver is full
+                    incoming
+                        .push(MidiEvent {
+                            timestamp: block_start + raw.time as u64,
+                            message,
+                        })
+                        .ok();
+                }
+            }
+            let block_end = block_start + ps.n_frames() as u64;
+            let mut writer = self.out_port.writer(ps);
+            // JACK needs the events in order so the senders are merged
+            loop {
+                let next = self
+                    .channels
+                    .outgoing
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, outgoing)| outgoing.peek().ok().map(|e| (i, e.timestamp)))
+                    .min_by_key(|&(_, timestamp)| timestamp);
+                let Some((i, timestamp)) = next else {
+                    break;
+                };
+                if timestamp >= block_end {
+                    break;
+                }
+                let Ok(event) = self.channels.outgoing[i].pop() else {
+                    break;
+                };
+                let time = event.timestamp.saturating_sub(block_start) as u32;
+                let bytes = event.message.to_bytes();
+                writer
+                    .write(&jack::RawMidi {
+                        time,
+                        bytes: bytes.as_slice(),
+                    })
+                    .ok();
+            }
+        }
     }
 
     struct JackProcess {
-        main_node: Node,
+        adapter: DeviceAdapter,
         in_ports: Vec<jack::Port<jack::AudioIn>>,
-        input_buffers: Box<[Box<[Sample]>]>,
         resources: Resources,
         out_ports: Vec<jack::Port<jack::AudioOut>>,
+        midi: Option<JackMidiPorts>,
+        /// The number of samples processed since processing started
+        sample_counter: u64,
+        guard: ProcessGuard,
+        sample_rate: usize,
+        /// New sample rates from the notification handler
+        sample_rate_changes: rtrb::Consumer<usize>,
+        /// The current number of frames per callback
+        block_size: usize,
+    }
+
+    impl JackProcess {
+        fn apply_sample_rate_changes(&mut self) {
+            while let Ok(sample_rate) = self.sample_rate_changes.pop() {
+                if sample_rate != self.sample_rate {
+                    self.sample_rate = sample_rate;
+                    if self.adapter.device_rate_changed(sample_rate as f64) {
+                        self.resources.set_sample_rate(sample_rate as Sample);
+                    }
+                    self.guard
+                        .report(BackendEvent::SampleRateChanged(sample_rate));
+                }
+            }
+        }
     }
 
     impl jack::ProcessHandler for JackProcess {
         fn process(&mut self, _: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
-            for (in_port, in_buffer) in self.in_ports.iter().zip(self.input_buffers.iter_mut()) {
-                let in_port_slice = in_port.as_slice(ps);
-                in_buffer.clone_from_slice(in_port_slice);
+            self.apply_sample_rate_changes();
+            let in_ports = &self.in_ports;
+            let out_ports = &mut self.out_ports;
+            self.adapter.process(
+                ps.n_frames() as usize,
+                &mut self.guard,
+                &mut self.resources,
+                |channel, frames, buffer| {
+                    buffer.copy_from_slice(&in_ports[channel].as_slice(ps)[frames]);
+                },
+                |channel, frames, samples| {
+                    out_ports[channel].as_mut_slice(ps)[frames].copy_from_slice(samples);
+                },
+            );
+            // After the Graph so that events it sends for this block are included
+            if let Some(midi) = &mut self.midi {
+                midi.process(ps, self.sample_counter);
             }
-            self.main_node
-                .process(&self.input_buffers, &mut self.resources);
+            self.sample_counter += ps.n_frames() as u64;
+            jack::Control::Continue
+        }
 
-            for (out_port, out_buffer) in self
-                .out_ports
-                .iter_mut()
-                .zip(self.main_node.output_buffers().iter())
-            {
-                let out_port_slice = out_port.as_mut_slice(ps);
-                out_port_slice.clone_from_slice(out_buffer);
+        fn buffer_size(&mut self, _: &jack::Client, size: jack::Frames) -> jack::Control {
+            let size = size as usize;
+            if size != self.block_size {
+                self.block_size = size;
+                self.guard.report(BackendEvent::BlockSizeChanged(size));
             }
             jack::Control::Continue
         }
     }
 
-    struct JackNotifications;
+    struct JackNotifications {
+        events: rtrb::Producer<BackendEvent>,
+        /// Sends new sample rates to the process callback
+        sample_rate_changes: rtrb::Producer<usize>,
+    }
 
     impl jack::NotificationHandler for JackNotifications {
         fn thread_init(&self, _: &jack::Client) {
@@ -187,10 +868,11 @@ mod jack_backend {
         }
 
         fn shutdown(&mut self, status: jack::ClientStatus, reason: &str) {
-            println!(
-                "JACK: shutdown with status {:?} because \"{}\"",
-                status, reason
-            );
+            self.events
+                .push(BackendEvent::Shutdown(format!(
+                    "JACK shutdown with status {status:?} because \"{reason}\""
+                )))
+                .ok();
         }
 
         fn freewheel(&mut self, _: &jack::Client, is_enabled: bool) {
@@ -201,7 +883,13 @@ mod jack_backend {
         }
 
         fn sample_rate(&mut self, _: &jack::Client, srate: jack::Frames) -> jack::Control {
-            println!("JACK: sample rate changed to {}", srate);
+            if self.sample_rate_changes.push(srate as usize).is_err() {
+                self.events
+                    .push(BackendEvent::StreamError(format!(
+                        "JACK: unable to apply the sample rate change to {srate}"
+                    )))
+                    .ok();
+            }
             jack::Control::Continue
         }
 
@@ -260,7 +948,7 @@ mod jack_backend {
         }
 
         fn xrun(&mut self, _: &jack::Client) -> jack::Control {
-            println!("JACK: xrun occurred");
+            self.events.push(BackendEvent::Xrun).ok();
             jack::Control::Continue
         }
     }
@@ -268,56 +956,269 @@ mod jack_backend {
 
 #[cfg(feature = "cpal")]
 pub mod cpal_backend {
-    use crate::audio_backend::{AudioBackend, AudioBackendError};
-    use crate::{graph::Graph, graph::Node, Resources};
+    use crate::audio_backend::{
+        event_channels, AudioBackend, AudioBackendError, BackendEvent, BackendEventReceiver,
+        DeviceAdapter, ProcessGuard,
+    };
+    use crate::{graph::Graph, Resources};
     use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
+    /// Options for choosing the host, device and stream config of a
+    /// [`CpalBackend`]. Anything that isn't set uses the default of the host or
+    /// device. Use [`CpalBackend::hosts`] and [`CpalBackend::output_devices`]
+    /// to find out what is available.
+    ///
+    /// ```no_run
+    /// # use knyst::audio_backend::{CpalBackend, CpalBackendOptions};
+    /// let backend = CpalBackend::new(
+    ///     CpalBackendOptions::default()
+    ///         .sample_rate(48000)
+    ///         .buffer_size(128)
+    ///         .num_outputs(2),
+    /// )
+    /// .unwrap();
+    /// ```
+    #[derive(Debug, Clone)]
     pub struct CpalBackendOptions {
+        host: String,
         device: String,
+        sample_rate: Option<usize>,
+        buffer_size: Option<usize>,
+        num_outputs: Option<usize>,
+        resample: bool,
         verbose: bool,
     }
     impl Default for CpalBackendOptions {
         fn default() -> Self {
             Self {
+                host: "default".into(),
                 device: "default".into(),
+                sample_rate: None,
+                buffer_size: None,
+                num_outputs: None,
+                resample: false,
                 verbose: false,
             }
         }
     }
+    impl CpalBackendOptions {
+        /// The name of the host, see [`CpalBackend::hosts`]. "ASIO" is only
+        /// available on Windows with the `asio` feature. Default: "default"
+        pub fn host(mut self, host: impl Into<String>) -> Self {
+            self.host = host.into();
+            self
+        }
+        /// The name of the output device. Default: "default"
+        pub fn device(mut self, device: impl Into<String>) -> Self {
+            self.device = device.into();
+            self
+        }
+        pub fn sample_rate(mut self, sample_rate: usize) -> Self {
+            self.sample_rate = Some(sample_rate);
+            self
+        }
+        /// The number of frames per callback, requested from the device as a
+        /// fixed buffer size. [`CpalBackend::new`] fails if the device
+        /// reports that it doesn't support the buffer size.
+        pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+            self.buffer_size = Some(buffer_size);
+            self
+        }
+        pub fn num_outputs(mut self, num_outputs: usize) -> Self {
+            self.num_outputs = Some(num_outputs);
+            self
+        }
+        /// Resample the output of the Graph if it runs at a different sample
+        /// rate than the device, e.g. when the device doesn't support the
+        /// rate the Graph was built for. Adds the latency of the resampler.
+        /// Default: false
+        pub fn resample(mut self, resample: bool) -> Self {
+            self.resample = resample;
+            self
+        }
+        /// Print the chosen device and config
+        pub fn verbose(mut self, verbose: bool) -> Self {
+            self.verbose = verbose;
+            self
+        }
+        /// If `range` supports the requested channels and sample rate,
+        /// returns the config to use
+        fn choose_config(
+            &self,
+            range: cpal::SupportedStreamConfigRange,
+            default_sample_rate: usize,
+        ) -> Option<cpal::SupportedStreamConfig> {
+            if self
+                .num_outputs
+                .is_some_and(|num_outputs| num_outputs != range.channels() as usize)
+            {
+                return None;
+            }
+            let supports = |sample_rate: usize| {
+                (range.min_sample_rate().0 as usize..=range.max_sample_rate().0 as usize)
+                    .contains(&sample_rate)
+            };
+            let sample_rate = match self.sample_rate {
+                Some(sample_rate) if supports(sample_rate) => sample_rate,
+                Some(_) => return None,
+                None if supports(default_sample_rate) => default_sample_rate,
+                None => range.max_sample_rate().0 as usize,
+            };
+            Some(range.with_sample_rate(cpal::SampleRate(sample_rate as u32)))
+        }
+    }
+
+    /// A range of stream configs supported by a device
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct CpalConfigRange {
+        pub channels: usize,
+        pub min_sample_rate: usize,
+        pub max_sample_rate: usize,
+        /// The smallest and largest number of frames per callback, if known
+        pub buffer_size: Option<(usize, usize)>,
+        pub sample_format: cpal::SampleFormat,
+    }
+
+    impl From<cpal::SupportedStreamConfigRange> for CpalConfigRange {
+        fn from(range: cpal::SupportedStreamConfigRange) -> Self {
+            Self {
+                channels: range.channels() as usize,
+                min_sample_rate: range.min_sample_rate().0 as usize,
+                max_sample_rate: range.max_sample_rate().0 as usize,
+                buffer_size: buffer_size_range(range.buffer_size()),
+                sample_format: range.sample_format(),
+            }
+        }
+    }
+
+    fn buffer_size_range(buffer_size: &cpal::SupportedBufferSize) -> Option<(usize, usize)> {
+        match buffer_size {
+            cpal::SupportedBufferSize::Range { min, max } => Some((*min as usize, *max as usize)),
+            cpal::SupportedBufferSize::Unknown => None,
+        }
+    }
+
+    /// An output device and the configs it supports
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct CpalDeviceInfo {
+        pub name: String,
+        pub configs: Vec<CpalConfigRange>,
+    }
+
     /// CPAL backend for convenience. The CPAL backend currently does not support passing on audio inputs from outside the program.
     pub struct CpalBackend {
         stream: Option<cpal::Stream>,
         sample_rate: usize,
         config: cpal::SupportedStreamConfig,
+        buffer_size: Option<usize>,
+        resample: bool,
         device: cpal::Device,
+        /// Senders of events from the data and error callbacks, moved to them
+        /// when processing starts
+        event_senders: Option<[rtrb::Producer<BackendEvent>; 2]>,
+        event_receiver: Option<BackendEventReceiver>,
     }
 
     impl CpalBackend {
         pub fn new(options: CpalBackendOptions) -> Result<Self, AudioBackendError> {
-            let host = cpal::default_host();
-
+            let host = Self::host(&options.host)?;
             let device = if options.device == "default" {
                 host.default_output_device()
             } else {
                 host.output_devices()?
                     .find(|x| x.name().map(|y| y == options.device).unwrap_or(false))
             }
-            .expect("failed to find output device");
+            .ok_or_else(|| AudioBackendError::DeviceNotFound(options.device.clone()))?;
             if options.verbose {
                 println!("Output device: {}", device.name()?);
             }
 
-            let config = device.default_output_config().unwrap();
+            let default_config = device.default_output_config()?;
+            let config = if options.sample_rate.is_none() && options.num_outputs.is_none() {
+                default_config
+            } else {
+                let default_sample_rate = default_config.sample_rate().0 as usize;
+                let mut configs: Vec<_> = device
+                    .supported_output_configs()?
+                    .filter_map(|range| options.choose_config(range, default_sample_rate))
+                    .collect();
+                // Prefer the sample format of the default config, then floats
+                configs.sort_by_key(|config| {
+                    (
+                        config.sample_format() != default_config.sample_format(),
+                        config.sample_format() != cpal::SampleFormat::F32,
+                    )
+                });
+                configs.into_iter().next().ok_or_else(|| {
+                    AudioBackendError::UnsupportedConfig(format!(
+                        "no config with {} outputs at a sample rate of {}",
+                        options
+                            .num_outputs
+                            .map_or("any number of".to_string(), |n| n.to_string()),
+                        options
+                            .sample_rate
+                            .map_or("any".to_string(), |sr| sr.to_string()),
+                    ))
+                })?
+            };
+            if let (Some(buffer_size), Some((min, max))) =
+                (options.buffer_size, buffer_size_range(config.buffer_size()))
+            {
+                if !(min..=max).contains(&buffer_size) {
+                    return Err(AudioBackendError::UnsupportedConfig(format!(
+                        "a buffer size of {buffer_size}, the device supports {min} to {max}"
+                    )));
+                }
+            }
             if options.verbose {
-                println!("Default output config: {:?}", config);
+                println!("Output config: {:?}", config);
             }
+            let (event_senders, event_receiver) = event_channels();
             Ok(Self {
                 stream: None,
                 sample_rate: config.sample_rate().0 as usize,
                 config,
+                buffer_size: options.buffer_size,
+                resample: options.resample,
                 device,
+                event_senders: Some(event_senders),
+                event_receiver: Some(event_receiver),
             })
         }
+        /// The names of the hosts available on this platform, e.g. "ALSA" and
+        /// "JACK" on Linux, for [`CpalBackendOptions::host`]
+        pub fn hosts() -> Vec<String> {
+            cpal::available_hosts()
+                .into_iter()
+                .map(|id| id.name().to_string())
+                .collect()
+        }
+        /// The output devices of `host` and the configs they support. Use
+        /// "default" for the default host.
+        pub fn output_devices(host: &str) -> Result<Vec<CpalDeviceInfo>, AudioBackendError> {
+            let host = Self::host(host)?;
+            let mut devices = vec![];
+            for device in host.output_devices()? {
+                devices.push(CpalDeviceInfo {
+                    name: device.name()?,
+                    configs: device
+                        .supported_output_configs()?
+                        .map(CpalConfigRange::from)
+                        .collect(),
+                });
+            }
+            Ok(devices)
+        }
+        fn host(name: &str) -> Result<cpal::Host, AudioBackendError> {
+            if name == "default" {
+                return Ok(cpal::default_host());
+            }
+            let id = cpal::available_hosts()
+                .into_iter()
+                .find(|id| id.name() == name)
+                .ok_or_else(|| AudioBackendError::HostNotFound(name.to_string()))?;
+            Ok(cpal::host_from_id(id)?)
+        }
         pub fn num_outputs(&self) -> usize {
             self.config.channels() as usize
         }
@@ -342,16 +1243,30 @@ pub mod cpal_backend {
             if node.num_inputs() > 0 {
                 eprintln!("Warning: CpalBackend currently does not support inputs into Graphs.")
             }
-            let config = self.config.clone();
+            let mut config: cpal::StreamConfig = self.config.clone().into();
+            if let Some(buffer_size) = self.buffer_size {
+                config.buffer_size = cpal::BufferSize::Fixed(buffer_size as u32);
+            }
+            let adapter = DeviceAdapter::new(
+                node,
+                graph.block_size(),
+                graph.sample_rate() as f64,
+                self.sample_rate as f64,
+                self.resample,
+            );
+            let events = self
+                .event_senders
+                .take()
+                .expect("the event senders are only taken when processing starts");
             let stream = match self.config.sample_format() {
                 cpal::SampleFormat::F32 => {
-                    run::<f32>(&self.device, &config.into(), node, resources)
+                    run::<f32>(&self.device, &config, adapter, resources, events)
                 }
                 cpal::SampleFormat::I16 => {
-                    run::<i16>(&self.device, &config.into(), node, resources)
+                    run::<i16>(&self.device, &config, adapter, resources, events)
                 }
                 cpal::SampleFormat::U16 => {
-                    run::<u16>(&self.device, &config.into(), node, resources)
+                    run::<u16>(&self.device, &config, adapter, resources, events)
                 }
             }?;
             self.stream = Some(stream);
@@ -367,45 +1282,50 @@ pub mod cpal_backend {
         }
 
         fn block_size(&self) -> Option<usize> {
-            None
+            self.buffer_size
+        }
+
+        fn error_receiver(&mut self) -> Option<BackendEventReceiver> {
+            self.event_receiver.take()
         }
     }
 
     fn run<T>(
         device: &cpal::Device,
         config: &cpal::StreamConfig,
-        mut node: Node,
+        mut adapter: DeviceAdapter,
         mut resources: Resources,
+        events: [rtrb::Producer<BackendEvent>; 2],
     ) -> Result<cpal::Stream, AudioBackendError>
     where
         T: cpal::Sample,
     {
         let channels = config.channels as usize;
 
-        // TODO: Send error back from the audio thread in a unified way.
-        let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+        let [process_events, mut error_events] = events;
+        let mut guard = ProcessGuard::new(process_events);
+        let err_fn = move |err: cpal::StreamError| {
+            error_events
+                .push(BackendEvent::StreamError(err.to_string()))
+                .ok();
+        };
 
-        let input_buffers = vec![vec![0.0; 0].into_boxed_slice(); 0].into_boxed_slice();
-        let mut sample_counter = 0;
-        let graph_block_size = node.output_buffers()[0].len();
-        node.process(&input_buffers, &mut resources);
         let stream = device.build_output_stream(
             config,
             move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
                 // TODO: When CPAL support duplex streams, copy inputs to graph inputs here.
-                for frame in output.chunks_mut(channels) {
-                    if sample_counter >= graph_block_size {
-                        node.process(&input_buffers, &mut resources);
-                        sample_counter = 0;
-                    }
-                    let buffer = node.output_buffers();
-                    for (channel_i, out) in frame.iter_mut().enumerate() {
-                        let value: T =
-                            cpal::Sample::from::<f32>(&buffer[channel_i][sample_counter]);
-                        *out = value;
-                    }
-                    sample_counter += 1;
-                }
+                adapter.process(
+                    output.len() / channels,
+                    &mut guard,
+                    &mut resources,
+                    |_, _, _| {},
+                    |channel, frames, samples| {
+                        for (frame, &sample) in frames.zip(samples.iter()) {
+                            output[frame * channels + channel] =
+                                cpal::Sample::from::<f32>(&(sample as f32));
+                        }
+                    },
+                );
             },
             err_fn,
         )?;
@@ -414,3 +1334,107 @@ pub mod cpal_backend {
         Ok(stream)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{gen, Connection, GenState, GraphSettings};
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn graph_panics_are_reported() {
+        let mut graph = Graph::new(GraphSettings::default());
+        let panicking =
+            graph.push_gen(gen(|_, _, _| -> GenState { panic!("broken Gen") }).output("out"));
+        graph.connect(Connection::graph_output(panicking)).unwrap();
+        let mut node = graph.to_node().unwrap();
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let ([events], mut receiver) = event_channels();
+        let mut guard = ProcessGuard::new(events);
+        assert!(!guard.process(&mut node, &[], &mut resources));
+        assert!(!guard.process(&mut node, &[], &mut resources));
+        assert_eq!(
+            receiver.try_recv(),
+            Some(BackendEvent::GraphPanic("broken Gen".to_string()))
+        );
+        assert_eq!(receiver.try_recv(), None);
+    }
+
+    #[test]
+    fn block_adapter() {
+        let mut node = Node::new(
+            "Passthrough",
+            Box::new(
+                gen(|inputs, outputs, _| {
+                    outputs[0].copy_from_slice(&inputs[0]);
+                    GenState::Continue
+                })
+                .input("in")
+                .output("out"),
+            ),
+        );
+        node.init(4, 44100.);
+        let mut adapter = BlockAdapter::new(node, 4);
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let ([events], _receiver) = event_channels();
+        let mut guard = ProcessGuard::new(events);
+        let mut frame_counter = 0;
+        let mut run = |frames: usize| {
+            let input: Vec<Sample> = (0..frames)
+                .map(|i| (frame_counter + i + 1) as Sample)
+                .collect();
+            frame_counter += frames;
+            let mut output = vec![-1.0; frames];
+            adapter.process(
+                frames,
+                &mut guard,
+                &mut resources,
+                |_, frames, buffer| buffer.copy_from_slice(&input[frames]),
+                |_, frames, samples| output[frames].copy_from_slice(samples),
+            );
+            output
+        };
+        // Multiples of the block size are processed without latency
+        assert_eq!(run(4), vec![1., 2., 3., 4.]);
+        assert_eq!(run(8), vec![5., 6., 7., 8., 9., 10., 11., 12.]);
+        // Other sizes are delayed by one block
+        assert_eq!(run(3), vec![0., 0., 0.]);
+        assert_eq!(run(5), vec![0., 13., 14., 15., 16.]);
+        assert_eq!(run(4), vec![17., 18., 19., 20.]);
+    }
+
+    #[test]
+    fn resampling_adapter() {
+        let mut node = Node::new(
+            "Passthrough",
+            Box::new(
+                gen(|inputs, outputs, _| {
+                    outputs[0].copy_from_slice(&inputs[0]);
+                    GenState::Continue
+                })
+                .input("in")
+                .output("out"),
+            ),
+        );
+        node.init(16, 44100.);
+        let mut adapter = ResamplingAdapter::new(node, 16, 44100., 48000.);
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let ([events], _receiver) = event_channels();
+        let mut guard = ProcessGuard::new(events);
+        let mut output = vec![0.0; 512];
+        adapter.process(
+            512,
+            &mut guard,
+            &mut resources,
+            |_, _, buffer| buffer.fill(1.0),
+            |_, frames, samples| output[frames].copy_from_slice(samples),
+        );
+        // A constant passes through unchanged after the latency of the
+        // resamplers and the queued block of inputs
+        for sample in &output[128..] {
+            assert!((sample - 1.0).abs() < 1e-3, "{sample}");
+        }
+    }
+}
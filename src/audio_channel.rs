@@ -0,0 +1,291 @@
+//! Streaming audio between a Graph and other threads.
+//!
+//! [`audio_channel`] creates an [`AudioSender`] and an [`AudioReceiver`]
+//! connected by a lock free single producer single consumer ring buffer.
+//! Either end can be pushed to a Graph as a node or be used directly from any
+//! other thread, e.g. to stream audio from a network receiver into the Graph,
+//! or to send the output of a node to a GUI for drawing.
+//!
+//! - As a node, an [`AudioSender`] has one input per channel and no outputs.
+//! - As a node, an [`AudioReceiver`] has no inputs and one output per channel.
+//!
+//! Neither end ever blocks. Frames that don't fit in the ring buffer are
+//! dropped and counted as overflows. Frames that an [`AudioReceiver`] node
+//! needs but which haven't arrived yet are output as silence and counted as
+//! underruns. The counts are available from both ends through
+//! [`AudioSender::stats`] and [`AudioReceiver::stats`].
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::audio_channel::audio_channel;
+//! # let mut graph = Graph::new(GraphSettings::default());
+//! let (mut sender, receiver) = audio_channel(1, 4096);
+//! let receiver = graph.push_gen(receiver);
+//! graph.connect(Connection::graph_output(receiver)).unwrap();
+//! std::thread::spawn(move || {
+//!     // e.g. samples from the network
+//!     sender.send(&[0.0; 512]);
+//! });
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::graph::{Gen, GenState};
+use crate::{Resources, Sample};
+
+const INPUT_NAMES: [&str; 8] = ["in0", "in1", "in2", "in3", "in4", "in5", "in6", "in7"];
+const OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
+
+/// *Allocates memory*
+/// Create a channel of `num_channels` interleaved channels with room for
+/// `capacity` frames, see the [module documentation](self).
+pub fn audio_channel(num_channels: usize, capacity: usize) -> (AudioSender, AudioReceiver) {
+    let num_channels = num_channels.max(1);
+    let (producer, consumer) = rtrb::RingBuffer::new(capacity * num_channels);
+    let counters = Arc::new(Counters::default());
+    (
+        AudioSender {
+            num_channels,
+            samples: producer,
+            counters: counters.clone(),
+        },
+        AudioReceiver {
+            num_channels,
+            samples: consumer,
+            counters,
+        },
+    )
+}
+
+/// Counts of dropped and missing frames in an audio channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChannelStats {
+    /// Frames dropped because the ring buffer was full
+    pub overflows: u64,
+    /// Frames an [`AudioReceiver`] node output as silence because they hadn't
+    /// been sent yet
+    pub underruns: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    overflows: AtomicU64,
+    underruns: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            overflows: self.overflows.load(Ordering::Relaxed),
+            underruns: self.underruns.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The sending end of an [`audio_channel`]
+pub struct AudioSender {
+    num_channels: usize,
+    samples: rtrb::Producer<Sample>,
+    counters: Arc<Counters>,
+}
+
+impl AudioSender {
+    /// Send interleaved frames. Returns the number of frames that were sent,
+    /// the rest are dropped. An incomplete frame at the end is ignored.
+    pub fn send(&mut self, interleaved: &[Sample]) -> usize {
+        let num_channels = self.num_channels;
+        self.write_frames(interleaved.len() / num_channels, |frame, channel| {
+            interleaved[frame * num_channels + channel]
+        })
+    }
+    /// The number of frames that can be sent without dropping any
+    pub fn free_frames(&self) -> usize {
+        self.samples.slots() / self.num_channels
+    }
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+    pub fn stats(&self) -> ChannelStats {
+        self.counters.stats()
+    }
+    #[cfg(feature = "network")]
+    pub(crate) fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+    fn write_frames(&mut self, frames: usize, sample: impl Fn(usize, usize) -> Sample) -> usize {
+        let num_channels = self.num_channels;
+        let sent = self.free_frames().min(frames);
+        if sent > 0 {
+            let sample = &sample;
+            let chunk = self
+                .samples
+                .write_chunk_uninit(sent * num_channels)
+                .unwrap();
+            chunk.fill_from_iter(
+                (0..sent).flat_map(|frame| (0..num_channels).map(move |c| sample(frame, c))),
+            );
+        }
+        if sent < frames {
+            self.counters
+                .overflows
+                .fetch_add((frames - sent) as u64, Ordering::Relaxed);
+        }
+        sent
+    }
+}
+
+impl Gen for AudioSender {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        _outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let frames = inputs.first().map_or(0, |input| input.len());
+        self.write_frames(frames, |frame, channel| inputs[channel][frame]);
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        self.num_channels
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        INPUT_NAMES.get(input).copied().unwrap_or("")
+    }
+    fn name(&self) -> &'static str {
+        "AudioSender"
+    }
+}
+
+/// The receiving end of an [`audio_channel`]
+pub struct AudioReceiver {
+    num_channels: usize,
+    samples: rtrb::Consumer<Sample>,
+    counters: Arc<Counters>,
+}
+
+impl AudioReceiver {
+    /// Receive as many whole frames as are available and fit in
+    /// `interleaved`. Returns the number of frames received.
+    pub fn receive(&mut self, interleaved: &mut [Sample]) -> usize {
+        let num_channels = self.num_channels;
+        self.read_frames(
+            interleaved.len() / num_channels,
+            |frame, channel, sample| {
+                interleaved[frame * num_channels + channel] = sample;
+            },
+        )
+    }
+    /// The number of frames that have been sent and not yet received
+    pub fn available_frames(&self) -> usize {
+        self.samples.slots() / self.num_channels
+    }
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+    pub fn stats(&self) -> ChannelStats {
+        self.counters.stats()
+    }
+    #[cfg(feature = "network")]
+    pub(crate) fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+    pub(crate) fn count_underruns(&self, frames: usize) {
+        self.counters
+            .underruns
+            .fetch_add(frames as u64, Ordering::Relaxed);
+    }
+    fn read_frames(&mut self, frames: usize, mut write: impl FnMut(usize, usize, Sample)) -> usize {
+        let num_channels = self.num_channels;
+        let received = self.available_frames().min(frames);
+        let chunk = self.samples.read_chunk(received * num_channels).unwrap();
+        for (i, sample) in chunk.into_iter().enumerate() {
+            write(i / num_channels, i % num_channels, sample);
+        }
+        received
+    }
+}
+
+impl Gen for AudioReceiver {
+    fn process(
+        &mut self,
+        _inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let frames = outputs.first().map_or(0, |output| output.len());
+        let received = self.read_frames(frames, |frame, channel, sample| {
+            outputs[channel][frame] = sample;
+        });
+        if received < frames {
+            for output in outputs.iter_mut() {
+                output[received..].fill(0.0);
+            }
+            self.count_underruns(frames - received);
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        0
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_channels
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        OUTPUT_NAMES.get(output).copied().unwrap_or("")
+    }
+    fn name(&self) -> &'static str {
+        "AudioReceiver"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{constant, Connection, Graph, GraphSettings};
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn stream_into_and_out_of_graph() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 2,
+            ..Default::default()
+        });
+        let (mut to_graph, receiver) = audio_channel(2, 5);
+        let (sender, mut from_graph) = audio_channel(1, 6);
+        let receiver = graph.push_gen(receiver);
+        let sender = graph.push_gen(sender);
+        graph
+            .connect(Connection::graph_output(receiver).channels(2))
+            .unwrap();
+        graph.connect(constant(0.5).to(sender)).unwrap();
+        let mut node = graph.to_node().unwrap();
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(ResourcesSettings::default());
+
+        // 6 frames of 2 channels, the last doesn't fit
+        let frames: Vec<Sample> = (0..12).map(|i| i as Sample).collect();
+        assert_eq!(to_graph.send(&frames), 5);
+        assert_eq!(to_graph.stats().overflows, 1);
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [0.0, 2.0, 4.0, 6.0]);
+        assert_eq!(node.output_buffers()[1][..], [1.0, 3.0, 5.0, 7.0]);
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [8.0, 0.0, 0.0, 0.0]);
+        assert_eq!(to_graph.stats().underruns, 3);
+
+        // 8 frames were sent from the graph, only 6 fit
+        let mut received = [0.0; 8];
+        assert_eq!(from_graph.available_frames(), 6);
+        assert_eq!(from_graph.receive(&mut received), 6);
+        assert_eq!(received, [0.5, 0.5, 0.5, 0.5, 0.5, 0.5, 0.0, 0.0]);
+        assert_eq!(from_graph.stats().overflows, 2);
+    }
+}
@@ -20,10 +20,12 @@ use symphonia::core::{
 #[allow(unused)]
 use crate::{
     graph::{Gen, GenState, Graph},
+    patch::ResourceRef,
+    resample::{resample, ResampleQuality},
     StopAction,
 };
 
-use super::Sample;
+use super::{sample_to_f64, Sample};
 
 new_key_type! {
     pub struct BufferKey;
@@ -80,7 +82,7 @@ impl Buffer {
         let path = path.into();
         let mut buffer = Vec::new();
         let mut codec_params = None;
-        let inp_file = File::open(&path).expect("Buffer: failed to open file!");
+        let inp_file = File::open(&path).map_err(SymphoniaError::IoError)?;
         // hint to the format registry of the decoder what file format it might be
         let mut hint = Hint::new();
         // Provide the file extension as a hint.
@@ -179,7 +181,7 @@ impl Buffer {
 
                                 // TODO: Get only one channel
                                 for sample in buf.samples() {
-                                    buffer.push(*sample);
+                                    buffer.push(*sample as Sample);
                                 }
                             }
                         }
@@ -235,9 +237,132 @@ impl Buffer {
             sampling_rate,
         ))
     }
+    /// *Allocates memory*
+    /// Returns a copy of the buffer converted to `sample_rate`, e.g. the
+    /// sample rate of the Graph, so that it can be played back without
+    /// interpolation. See [`resample`] for details.
+    pub fn resampled(&self, sample_rate: f64, quality: ResampleQuality) -> Buffer {
+        let buffer = resample(
+            &self.buffer,
+            self.num_channels,
+            self.sample_rate,
+            sample_rate,
+            quality,
+        );
+        Self::from_vec_interleaved(buffer, self.num_channels, sample_rate)
+    }
+    /// The largest absolute sample value in any channel
+    pub fn peak(&self) -> Sample {
+        self.buffer.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+    /// Scale all channels by the same amount so that the largest absolute
+    /// sample value becomes `peak`. A silent buffer is left as it is.
+    pub fn normalize(&mut self, peak: Sample) {
+        let current = self.peak();
+        if current > 0.0 {
+            let gain = peak / current;
+            for sample in self.buffer.iter_mut() {
+                *sample *= gain;
+            }
+        }
+    }
+    /// Reverse the order of the frames
+    pub fn reverse(&mut self) {
+        let num_channels = self.num_channels.max(1);
+        let num_frames = self.num_frames();
+        for frame in 0..num_frames / 2 {
+            let other = num_frames - 1 - frame;
+            for channel in 0..num_channels {
+                self.buffer.swap(
+                    frame * num_channels + channel,
+                    other * num_channels + channel,
+                );
+            }
+        }
+    }
+    /// Fade in linearly from silence over the first `seconds`
+    pub fn fade_in(&mut self, seconds: f64) {
+        let fade_frames = self.seconds_to_frames(seconds);
+        let num_channels = self.num_channels.max(1);
+        for (i, frame) in self
+            .buffer
+            .chunks_exact_mut(num_channels)
+            .take(fade_frames)
+            .enumerate()
+        {
+            let gain = i as Sample / fade_frames as Sample;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+    /// Fade out linearly to silence over the last `seconds`
+    pub fn fade_out(&mut self, seconds: f64) {
+        let fade_frames = self.seconds_to_frames(seconds);
+        let num_channels = self.num_channels.max(1);
+        for (i, frame) in self
+            .buffer
+            .chunks_exact_mut(num_channels)
+            .rev()
+            .take(fade_frames)
+            .enumerate()
+        {
+            let gain = i as Sample / fade_frames as Sample;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+    /// *Allocates memory*
+    /// Returns a copy of the frames between `start` and `end` seconds. The
+    /// range is clamped to the length of the buffer.
+    pub fn slice(&self, start: f64, end: f64) -> Buffer {
+        let num_channels = self.num_channels.max(1);
+        let start = self.seconds_to_frames(start);
+        let end = self.seconds_to_frames(end).max(start);
+        Self::from_vec_interleaved(
+            self.buffer[start * num_channels..end * num_channels].to_vec(),
+            self.num_channels,
+            self.sample_rate,
+        )
+    }
+    /// *Allocates memory*
+    /// Returns a copy with all channels mixed down to `num_channels`. Channel
+    /// `i` is added to output channel `i % num_channels` and the result is
+    /// divided by the number of channels added to it, e.g. stereo to mono
+    /// gives the average of left and right.
+    pub fn mixed_down(&self, num_channels: usize) -> Buffer {
+        let num_channels = num_channels.max(1);
+        let old_channels = self.num_channels.max(1);
+        let mut buffer = vec![0.0; self.num_frames() * num_channels];
+        for (old_frame, new_frame) in self
+            .buffer
+            .chunks_exact(old_channels)
+            .zip(buffer.chunks_exact_mut(num_channels))
+        {
+            for (channel, &sample) in old_frame.iter().enumerate() {
+                new_frame[channel % num_channels] += sample;
+            }
+        }
+        for (channel, sample) in buffer.iter_mut().enumerate() {
+            let channel = channel % num_channels;
+            // The number of old channels mixed into this channel
+            let mixed = (old_channels - channel).div_ceil(num_channels).max(1);
+            *sample /= mixed as Sample;
+        }
+        Self::from_vec_interleaved(buffer, num_channels, self.sample_rate)
+    }
+    /// The number of frames, i.e. the number of samples in each channel
+    pub fn num_frames(&self) -> usize {
+        self.buffer.len() / self.num_channels.max(1)
+    }
+    /// The number of whole frames in `seconds`, clamped to the length
+    fn seconds_to_frames(&self, seconds: f64) -> usize {
+        ((seconds.max(0.0) * self.sample_rate).round() as usize).min(self.num_frames())
+    }
     /// Returns the step size in samples for playing this buffer with the correct speed
-    pub fn buf_rate_scale(&self, server_sample_rate: f32) -> f64 {
-        self.sample_rate / server_sample_rate as f64
+    pub fn buf_rate_scale(&self, server_sample_rate: Sample) -> f64 {
+        self.sample_rate / sample_to_f64(server_sample_rate)
     }
     /// Linearly interpolate between the value in between to samples
     #[inline]
@@ -259,6 +384,16 @@ impl Buffer {
     pub fn size(&self) -> f64 {
         self.size
     }
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+    /// The memory used by the samples of the buffer
+    pub fn size_in_bytes(&self) -> usize {
+        self.buffer.len() * std::mem::size_of::<Sample>()
+    }
 }
 
 /// Reads a sample from a buffer and outputs it. In a multi channel [`Buffer`] only the first channel will be read.
@@ -357,6 +492,9 @@ impl Gen for BufferReader {
         "out"
     }
 
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::Buffer(self.buffer_key)]
+    }
     fn name(&self) -> &'static str {
         "BufferReader"
     }
@@ -472,8 +610,11 @@ impl Gen for BufferReaderMulti {
         }
     }
 
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::Buffer(self.buffer_key)]
+    }
     fn name(&self) -> &'static str {
-        "BufferReader"
+        "BufferReaderMulti"
     }
 }
 
@@ -493,3 +634,33 @@ fn output_str(num: usize) -> &'static str {
         _ => "",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_prep() {
+        // Stereo, 4 frames at 4 Hz
+        let mut buffer =
+            Buffer::from_vec_interleaved(vec![1.0, -1.0, 2.0, -2.0, 3.0, -3.0, 4.0, -4.0], 2, 4.0);
+        buffer.normalize(0.5);
+        assert_eq!(buffer.peak(), 0.5);
+        buffer.normalize(4.0);
+        buffer.reverse();
+        assert_eq!(buffer.get_interleaved(0), [4.0, -4.0]);
+        assert_eq!(buffer.get_interleaved(3), [1.0, -1.0]);
+
+        let slice = buffer.slice(0.25, 0.75);
+        assert_eq!(slice.num_frames(), 2);
+        assert_eq!(slice.get_interleaved(0), [3.0, -3.0]);
+
+        let mono = buffer.mixed_down(1);
+        assert_eq!(mono.num_channels(), 1);
+        assert!(mono.buffer.iter().all(|&s| s == 0.0));
+
+        buffer.fade_in(0.5);
+        buffer.fade_out(0.5);
+        assert_eq!(buffer.buffer, [0.0, 0.0, 1.5, -1.5, 1.0, -1.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,281 @@
+//! Loading sound files in the background while the audio is running.
+//!
+//! A [`BufferLoader`] decodes sound files on a thread of its own so that
+//! loading a large sample library doesn't block the control thread. The
+//! loaded [`Buffer`]s are inserted through a [`ResourcesCommandSender`] by
+//! [`BufferLoader::update`], which has to be called regularly on the control
+//! thread. A load is complete when its buffer has reached the [`Resources`]
+//! on the audio thread and can be played.
+//!
+//! Every load can either be awaited as a [`BufferLoad`] future, polled using
+//! [`BufferLoad::try_take`], or completed with a callback.
+//!
+//! ```no_run
+//! # use knyst::prelude::*;
+//! # use knyst::buffer_loader::BufferLoader;
+//! # let mut resources = Resources::new(ResourcesSettings::default());
+//! let mut sender = resources.command_channel(64).unwrap();
+//! // Move the resources to the audio thread, e.g. by starting a backend
+//! let mut loader = BufferLoader::new();
+//! let mut load = loader.load("kick.wav");
+//! loader.load_with("snare.wav", |result| match result {
+//!     Ok(key) => println!("The snare is ready: {key:?}"),
+//!     Err(e) => eprintln!("Unable to load the snare: {e}"),
+//! });
+//! loop {
+//!     loader.update(&mut sender);
+//!     if let Some(result) = load.try_take() {
+//!         println!("The kick is ready: {:?}", result.unwrap());
+//!         break;
+//!     }
+//!     std::thread::sleep(std::time::Duration::from_millis(10));
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use symphonia::core::errors::Error as SymphoniaError;
+
+use crate::buffer::{Buffer, BufferKey};
+#[allow(unused)]
+use crate::Resources;
+use crate::{ResourcesCommandSender, ResourcesError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum BufferLoadError {
+    #[error("Unable to load the sound file: {0}")]
+    SoundFile(#[from] SymphoniaError),
+    #[error(transparent)]
+    Resources(#[from] ResourcesError),
+    #[error("The loader thread has stopped.")]
+    LoaderStopped,
+}
+
+type LoadResult = Result<BufferKey, BufferLoadError>;
+
+/// The shared state of a [`BufferLoad`]
+#[derive(Default)]
+struct LoadState {
+    result: Option<LoadResult>,
+    waker: Option<Waker>,
+}
+
+/// A load started with [`BufferLoader::load`]. Resolves to the key of the
+/// buffer when it is in the [`Resources`].
+pub struct BufferLoad {
+    state: Arc<Mutex<LoadState>>,
+}
+
+impl BufferLoad {
+    /// Take the result if the load is complete. Returns None if it isn't or
+    /// if the result has already been taken.
+    pub fn try_take(&mut self) -> Option<LoadResult> {
+        self.state.lock().unwrap().result.take()
+    }
+}
+
+impl Future for BufferLoad {
+    type Output = LoadResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// What to do when a load is complete
+enum Completion {
+    Future(Arc<Mutex<LoadState>>),
+    Callback(Box<dyn FnOnce(LoadResult) + Send>),
+}
+
+impl Completion {
+    fn complete(self, result: LoadResult) {
+        match self {
+            Completion::Future(state) => {
+                let mut state = state.lock().unwrap();
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+            Completion::Callback(callback) => callback(result),
+        }
+    }
+}
+
+/// Loads sound files on a background thread, see the
+/// [module documentation](self).
+pub struct BufferLoader {
+    request_sender: Sender<(u64, PathBuf)>,
+    result_receiver: Receiver<(u64, Result<Buffer, SymphoniaError>)>,
+    next_id: u64,
+    /// Loads that haven't been inserted yet
+    loading: HashMap<u64, Completion>,
+    /// Inserted buffers waiting for the audio thread, with the number of
+    /// commands that have to be applied before they are in the Resources
+    inserted: Vec<(BufferKey, u64, Completion)>,
+}
+
+impl BufferLoader {
+    /// *Allocates memory*
+    /// Start the loader thread. It stops when the loader is dropped.
+    pub fn new() -> Self {
+        let (request_sender, request_receiver) = mpsc::channel::<(u64, PathBuf)>();
+        let (result_sender, result_receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("knyst_buffer_loader".to_string())
+            .spawn(move || {
+                for (id, path) in request_receiver {
+                    let result = Buffer::from_sound_file(path);
+                    if result_sender.send((id, result)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Unable to spawn the buffer loader thread");
+        Self {
+            request_sender,
+            result_receiver,
+            next_id: 0,
+            loading: HashMap::new(),
+            inserted: vec![],
+        }
+    }
+    /// Load the sound file at `path` and return a future resolving to the
+    /// key of the buffer.
+    pub fn load(&mut self, path: impl Into<PathBuf>) -> BufferLoad {
+        let state = Arc::new(Mutex::new(LoadState::default()));
+        self.request(path.into(), Completion::Future(state.clone()));
+        BufferLoad { state }
+    }
+    /// Load the sound file at `path` and call `callback` from
+    /// [`BufferLoader::update`] when it is complete.
+    pub fn load_with(
+        &mut self,
+        path: impl Into<PathBuf>,
+        callback: impl FnOnce(LoadResult) + Send + 'static,
+    ) {
+        self.request(path.into(), Completion::Callback(Box::new(callback)));
+    }
+    fn request(&mut self, path: PathBuf, completion: Completion) {
+        let id = self.next_id;
+        self.next_id += 1;
+        match self.request_sender.send((id, path)) {
+            Ok(_) => {
+                self.loading.insert(id, completion);
+            }
+            Err(_) => completion.complete(Err(BufferLoadError::LoaderStopped)),
+        }
+    }
+    /// The number of loads that aren't complete
+    pub fn num_pending(&self) -> usize {
+        self.loading.len() + self.inserted.len()
+    }
+    /// Insert the buffers that have been loaded and complete the loads whose
+    /// buffers have reached the audio thread. Call this regularly on the
+    /// control thread.
+    pub fn update(&mut self, sender: &mut ResourcesCommandSender) {
+        loop {
+            let (id, result) = match self.result_receiver.try_recv() {
+                Ok(loaded) => loaded,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    for (_, completion) in self.loading.drain() {
+                        completion.complete(Err(BufferLoadError::LoaderStopped));
+                    }
+                    break;
+                }
+            };
+            let Some(completion) = self.loading.remove(&id) else {
+                continue;
+            };
+            match result
+                .map_err(BufferLoadError::from)
+                .and_then(|buffer| sender.insert_buffer(buffer).map_err(BufferLoadError::from))
+            {
+                Ok(key) => self.inserted.push((key, sender.num_sent(), completion)),
+                Err(e) => completion.complete(Err(e)),
+            }
+        }
+        let num_applied = sender.num_applied();
+        let mut i = 0;
+        while i < self.inserted.len() {
+            if self.inserted[i].1 <= num_applied {
+                let (key, _, completion) = self.inserted.swap_remove(i);
+                completion.complete(Ok(key));
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl Default for BufferLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::WavWriter;
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn load_in_the_background() {
+        let path =
+            std::env::temp_dir().join(format!("knyst_buffer_loader_{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, 1, 48000).unwrap();
+        writer.write_samples(&[0.5; 16]).unwrap();
+        writer.finalize().unwrap();
+
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut sender = resources.command_channel(8).unwrap();
+        let mut loader = BufferLoader::new();
+        let mut load = loader.load(&path);
+        let missing = Arc::new(Mutex::new(None));
+        let missing_result = missing.clone();
+        loader.load_with("knyst_missing_file.wav", move |result| {
+            *missing_result.lock().unwrap() = Some(result);
+        });
+        let mut result = None;
+        for _ in 0..1000 {
+            loader.update(&mut sender);
+            // Nothing is complete until the audio thread has applied the
+            // commands
+            if result.is_none() {
+                assert!(load.try_take().is_none());
+            }
+            resources.apply_commands();
+            loader.update(&mut sender);
+            if result.is_none() {
+                result = load.try_take();
+            }
+            if result.is_some() && loader.num_pending() == 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        std::fs::remove_file(&path).unwrap();
+        let key = result.unwrap().unwrap();
+        assert_eq!(resources.buffers.get(key).unwrap().size(), 16.0);
+        assert!(matches!(
+            missing.lock().unwrap().take(),
+            Some(Err(BufferLoadError::SoundFile(_)))
+        ));
+    }
+}
@@ -0,0 +1,395 @@
+//! Named buses with sends and returns for sharing effects between many
+//! sources.
+//!
+//! [`Buses`] manages a mixing layer inside a [`Graph`]:
+//!
+//! - A bus has an input, where everything sent to it is summed, and a return
+//!   with a level, where the bus is mixed into the master bus. An effect
+//!   inserted with [`Buses::insert_effect`] processes the bus between the two.
+//! - The master bus is created together with the [`Buses`] and its return is
+//!   connected to the outputs of the Graph.
+//! - A [`Channel`] puts a fader after a node and sends the result to a bus,
+//!   usually the master bus. Any number of sends to other buses can be added
+//!   to a channel with [`Buses::send`], either before or after the fader.
+//!
+//! Faders, sends and returns are all [`Fader`] nodes, so their level is
+//! changed the same way, e.g. with [`set_level`].
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::graph::GenState;
+//! # use knyst::bus::{Buses, SendPosition, MASTER};
+//! # let mut graph = Graph::new(GraphSettings { num_outputs: 2, ..Default::default() });
+//! let mut buses = Buses::new(&mut graph, 2).unwrap();
+//! buses.add_bus(&mut graph, "reverb", 2).unwrap();
+//! // A reverb would go here
+//! let reverb = graph.push_closure(2, 2, |inputs, outputs, _| {
+//!     outputs[0].copy_from_slice(&inputs[0]);
+//!     outputs[1].copy_from_slice(&inputs[1]);
+//!     GenState::Continue
+//! });
+//! buses.insert_effect(&mut graph, "reverb", reverb).unwrap();
+//! let voice = graph.push_gen(knyst::noise::WhiteNoise::new());
+//! let channel = buses.add_channel(&mut graph, voice, MASTER).unwrap();
+//! let send = buses
+//!     .send(&mut graph, &channel, "reverb", SendPosition::PostFader, 0.3)
+//!     .unwrap();
+//! ```
+
+use std::collections::HashMap;
+
+use crate::graph::{constant, ConnectionError, Gen, GenState, Graph, NodeAddress, Rate};
+use crate::{Resources, Sample};
+
+/// The name of the master bus
+pub const MASTER: &str = "master";
+
+const INPUT_NAMES: [&str; 8] = ["in0", "in1", "in2", "in3", "in4", "in5", "in6", "in7"];
+const OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum BusError {
+    #[error("There is no bus named `{0}`.")]
+    BusNotFound(String),
+    #[error("There is already a bus named `{0}`.")]
+    BusExists(String),
+    #[error(transparent)]
+    ConnectionError(#[from] ConnectionError),
+}
+
+/// Where on a [`Channel`] a send is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendPosition {
+    /// Before the fader, so the send level is independent of the fader
+    PreFader,
+    /// After the fader
+    PostFader,
+}
+
+/// A node with a fader after it, see [`Buses::add_channel`]
+#[derive(Debug, Clone, Copy)]
+pub struct Channel {
+    source: NodeAddress,
+    fader: NodeAddress,
+    num_channels: usize,
+}
+
+impl Channel {
+    pub fn source(&self) -> NodeAddress {
+        self.source
+    }
+    pub fn fader(&self) -> NodeAddress {
+        self.fader
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bus {
+    input: NodeAddress,
+    ret: NodeAddress,
+    num_channels: usize,
+    effect: Option<NodeAddress>,
+}
+
+/// The buses of a Graph, see the [module documentation](self)
+pub struct Buses {
+    buses: HashMap<String, Bus>,
+}
+
+impl Buses {
+    /// *Allocates memory*
+    /// Create the master bus with `num_channels` channels and connect its
+    /// return to the outputs of `graph`.
+    pub fn new(graph: &mut Graph, num_channels: usize) -> Result<Self, BusError> {
+        let mut buses = Self {
+            buses: HashMap::new(),
+        };
+        let master = buses.push_bus(graph, num_channels)?;
+        let channels = num_channels.min(graph.num_outputs());
+        if channels > 0 {
+            graph.connect(master.ret.to_graph_out().channels(channels))?;
+        }
+        buses.buses.insert(MASTER.to_string(), master);
+        Ok(buses)
+    }
+    /// *Allocates memory*
+    /// Add a bus returning into the master bus. Until an effect is inserted
+    /// the input is passed straight through to the return.
+    pub fn add_bus(
+        &mut self,
+        graph: &mut Graph,
+        name: impl Into<String>,
+        num_channels: usize,
+    ) -> Result<(), BusError> {
+        let name = name.into();
+        if self.buses.contains_key(&name) {
+            return Err(BusError::BusExists(name));
+        }
+        let master = self.bus(MASTER)?;
+        let bus = self.push_bus(graph, num_channels)?;
+        graph.connect(
+            bus.ret
+                .to(master.input)
+                .channels(num_channels.min(master.num_channels)),
+        )?;
+        self.buses.insert(name, bus);
+        Ok(())
+    }
+    fn push_bus(&mut self, graph: &mut Graph, num_channels: usize) -> Result<Bus, BusError> {
+        let input = graph.push_gen(Fader::new(num_channels));
+        let ret = graph.push_gen(Fader::new(num_channels));
+        graph.connect(input.to(ret).channels(num_channels))?;
+        Ok(Bus {
+            input,
+            ret,
+            num_channels,
+            effect: None,
+        })
+    }
+    fn bus(&self, name: &str) -> Result<Bus, BusError> {
+        self.buses
+            .get(name)
+            .copied()
+            .ok_or_else(|| BusError::BusNotFound(name.to_string()))
+    }
+    /// The node where everything sent to the bus is summed
+    pub fn bus_input(&self, name: &str) -> Option<NodeAddress> {
+        self.buses.get(name).map(|bus| bus.input)
+    }
+    /// The node mixing the bus into the master bus, or into the outputs of
+    /// the Graph for the master bus
+    pub fn bus_return(&self, name: &str) -> Option<NodeAddress> {
+        self.buses.get(name).map(|bus| bus.ret)
+    }
+    /// Process a bus with `effect`, replacing the effect inserted before if
+    /// there was one. For a chain of effects, insert a Graph containing the
+    /// chain.
+    pub fn insert_effect(
+        &mut self,
+        graph: &mut Graph,
+        name: &str,
+        effect: NodeAddress,
+    ) -> Result<(), BusError> {
+        let bus = self.bus(name)?;
+        let n = bus.num_channels;
+        match bus.effect {
+            Some(old) => {
+                let inputs = n.min(graph.node_num_inputs(old).unwrap_or(0));
+                let outputs = n.min(graph.node_num_outputs(old).unwrap_or(0));
+                graph.disconnect(bus.input.to(old).channels(inputs))?;
+                graph.disconnect(old.to(bus.ret).channels(outputs))?;
+            }
+            None => graph.disconnect(bus.input.to(bus.ret).channels(n))?,
+        }
+        let inputs = n.min(graph.node_num_inputs(effect).unwrap_or(0));
+        let outputs = n.min(graph.node_num_outputs(effect).unwrap_or(0));
+        if inputs > 0 {
+            graph.connect(bus.input.to(effect).channels(inputs))?;
+        }
+        if outputs > 0 {
+            graph.connect(effect.to(bus.ret).channels(outputs))?;
+        }
+        if let Some(bus) = self.buses.get_mut(name) {
+            bus.effect = Some(effect);
+        }
+        Ok(())
+    }
+    /// Set the level of the return of a bus
+    pub fn set_return_level(
+        &self,
+        graph: &mut Graph,
+        name: &str,
+        level: Sample,
+    ) -> Result<(), BusError> {
+        set_level(graph, self.bus(name)?.ret, level)?;
+        Ok(())
+    }
+    /// Put a fader after `source` and send it to the bus named `output`,
+    /// e.g. [`MASTER`].
+    pub fn add_channel(
+        &mut self,
+        graph: &mut Graph,
+        source: NodeAddress,
+        output: &str,
+    ) -> Result<Channel, BusError> {
+        let bus = self.bus(output)?;
+        let num_channels = bus.num_channels;
+        let fader = graph.push_gen(Fader::new(num_channels));
+        let channels = num_channels.min(graph.node_num_outputs(source).unwrap_or(0));
+        if channels > 0 {
+            graph.connect(source.to(fader).channels(channels))?;
+        }
+        graph.connect(fader.to(bus.input).channels(num_channels))?;
+        Ok(Channel {
+            source,
+            fader,
+            num_channels,
+        })
+    }
+    /// Send a channel to a bus at `level`. Returns the send, whose level can
+    /// be changed with [`set_level`] and which can be freed to remove the
+    /// send.
+    pub fn send(
+        &mut self,
+        graph: &mut Graph,
+        channel: &Channel,
+        bus: &str,
+        position: SendPosition,
+        level: Sample,
+    ) -> Result<NodeAddress, BusError> {
+        let bus = self.bus(bus)?;
+        let num_channels = channel.num_channels.min(bus.num_channels);
+        let send = graph.push_gen(Fader::new(num_channels));
+        let (from, channels) = match position {
+            SendPosition::PreFader => (
+                channel.source,
+                num_channels.min(graph.node_num_outputs(channel.source).unwrap_or(0)),
+            ),
+            SendPosition::PostFader => (channel.fader, num_channels),
+        };
+        if channels > 0 {
+            graph.connect(from.to(send).channels(channels))?;
+        }
+        graph.connect(send.to(bus.input).channels(num_channels))?;
+        set_level(graph, send, level)?;
+        Ok(send)
+    }
+}
+
+/// Set the "level" input of a [`Fader`], e.g. the fader of a [`Channel`] or
+/// a send
+pub fn set_level(
+    graph: &mut Graph,
+    fader: NodeAddress,
+    level: Sample,
+) -> Result<(), ConnectionError> {
+    graph.connect(constant(level).to(fader).to_label("level"))
+}
+
+/// Multiplies every channel by the "level" input, ramping linearly over a
+/// block when the level changes.
+///
+/// The inputs are the channels followed by "level", which defaults to 1.
+pub struct Fader {
+    num_channels: usize,
+    /// The level at the end of the last block
+    level: Option<Sample>,
+}
+
+impl Fader {
+    pub fn new(num_channels: usize) -> Self {
+        Self {
+            num_channels,
+            level: None,
+        }
+    }
+}
+
+impl Gen for Fader {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let target = inputs[self.num_channels][0];
+        let start = self.level.unwrap_or(target);
+        for (output, input) in outputs.iter_mut().zip(inputs) {
+            let step = (target - start) / output.len() as Sample;
+            for (i, (out, sample)) in output.iter_mut().zip(input.iter()).enumerate() {
+                *out = *sample * (start + step * (i + 1) as Sample);
+            }
+        }
+        self.level = Some(target);
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        self.num_channels + 1
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_channels
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        if input == self.num_channels {
+            "level"
+        } else {
+            INPUT_NAMES.get(input).copied().unwrap_or("")
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        OUTPUT_NAMES.get(output).copied().unwrap_or("")
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        if input == self.num_channels {
+            1.0
+        } else {
+            0.0
+        }
+    }
+    fn input_rate(&self, input: usize) -> Rate {
+        if input == self.num_channels {
+            Rate::Control
+        } else {
+            Rate::Audio
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Fader"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{GraphSettings, Node};
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn sends_and_returns() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 1,
+            num_outputs: 1,
+            ..Default::default()
+        });
+        let mut buses = Buses::new(&mut graph, 1).unwrap();
+        buses.add_bus(&mut graph, "double", 1).unwrap();
+        let double = graph.push_closure(1, 1, |inputs, outputs, _| {
+            outputs[0][0] = inputs[0][0] * 2.0;
+            GenState::Continue
+        });
+        buses.insert_effect(&mut graph, "double", double).unwrap();
+        let source = graph.push_closure(0, 1, |_, outputs, _| {
+            outputs[0][0] = 1.0;
+            GenState::Continue
+        });
+        let channel = buses.add_channel(&mut graph, source, MASTER).unwrap();
+        set_level(&mut graph, channel.fader(), 0.5).unwrap();
+        let send = buses
+            .send(&mut graph, &channel, "double", SendPosition::PostFader, 0.5)
+            .unwrap();
+        let mut node: Node = graph.to_node().unwrap();
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        node.process(&[], &mut resources);
+        // 0.5 dry and 0.5 * 0.5 doubled
+        assert_eq!(node.output_buffers()[0][0], 1.0);
+
+        graph.free_node(send).unwrap();
+        buses
+            .send(&mut graph, &channel, "double", SendPosition::PreFader, 0.5)
+            .unwrap();
+        buses.set_return_level(&mut graph, "double", 0.5).unwrap();
+        graph.commit_changes();
+        graph.update();
+        node.process(&[], &mut resources);
+        // 0.5 dry and 1.0 * 0.5 doubled at half the level
+        assert_eq!(node.output_buffers()[0][0], 1.0);
+        assert!(matches!(
+            buses.add_bus(&mut graph, "double", 1),
+            Err(BusError::BusExists(_))
+        ));
+    }
+}
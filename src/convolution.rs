@@ -0,0 +1,260 @@
+//! Convolution with long impulse responses, e.g. for convolution reverb.
+//!
+//! [`Convolution`] uses uniformly partitioned overlap-save convolution in the
+//! frequency domain. The impulse response is split into partitions of
+//! `partition_size` samples and every partition of input costs one FFT, one
+//! inverse FFT and one complex multiply-add per impulse response partition,
+//! so the cost is the same every block and nothing is allocated on the audio
+//! thread.
+
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::buffer::Buffer;
+use crate::graph::{Gen, GenState};
+use crate::{Resources, Sample};
+
+/// Convolves the input with an impulse response.
+///
+/// The output is delayed by `partition_size` samples, which is reported as
+/// the latency of the Gen, see [`Graph::compensate_latency`](crate::graph::Graph::compensate_latency).
+/// Created with [`Convolution::with_block_size`] there is no added latency
+/// instead. Smaller partitions use more CPU.
+pub struct Convolution {
+    partition_size: usize,
+    /// True if every block is one partition, which is output directly
+    zero_latency: bool,
+    fft: Arc<dyn Fft<Sample>>,
+    ifft: Arc<dyn Fft<Sample>>,
+    scratch: Vec<Complex<Sample>>,
+    /// The spectrum of every partition of the impulse response
+    ir_spectra: Vec<Box<[Complex<Sample>]>>,
+    /// The spectra of the latest input partitions, used as a ring buffer
+    input_spectra: Vec<Box<[Complex<Sample>]>>,
+    input_spectra_pos: usize,
+    /// The previous and the current input partition
+    input_buffer: Vec<Sample>,
+    /// The number of samples in the current input partition
+    fill: usize,
+    spectrum: Vec<Complex<Sample>>,
+    accumulator: Vec<Complex<Sample>>,
+    /// The output of the latest partition
+    output_buffer: Vec<Sample>,
+}
+
+impl Convolution {
+    /// `partition_size` is rounded up to a power of two.
+    pub fn new(impulse_response: &[Sample], partition_size: usize) -> Self {
+        Self::with_partition_size(
+            impulse_response,
+            partition_size.max(1).next_power_of_two(),
+            false,
+        )
+    }
+    /// Without added latency, using the block size of the Graph as the
+    /// partition size. The Graph has to process blocks of exactly
+    /// `block_size` samples; other blocks are delayed by `block_size` samples
+    /// without it being reported as latency.
+    pub fn with_block_size(impulse_response: &[Sample], block_size: usize) -> Self {
+        Self::with_partition_size(impulse_response, block_size.max(1), true)
+    }
+    fn with_partition_size(
+        impulse_response: &[Sample],
+        partition_size: usize,
+        zero_latency: bool,
+    ) -> Self {
+        let fft_size = partition_size * 2;
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(fft_size);
+        let ifft = planner.plan_fft_inverse(fft_size);
+        let scratch_len = fft
+            .get_inplace_scratch_len()
+            .max(ifft.get_inplace_scratch_len());
+        let mut scratch = vec![Complex::default(); scratch_len];
+        let ir_spectra: Vec<Box<[Complex<Sample>]>> = impulse_response
+            .chunks(partition_size)
+            .map(|partition| {
+                let mut spectrum = vec![Complex::default(); fft_size];
+                for (c, &s) in spectrum.iter_mut().zip(partition) {
+                    c.re = s;
+                }
+                fft.process_with_scratch(&mut spectrum, &mut scratch);
+                spectrum.into_boxed_slice()
+            })
+            .collect();
+        let input_spectra = (0..ir_spectra.len())
+            .map(|_| vec![Complex::default(); fft_size].into_boxed_slice())
+            .collect();
+        Self {
+            partition_size,
+            zero_latency,
+            fft,
+            ifft,
+            scratch,
+            ir_spectra,
+            input_spectra,
+            input_spectra_pos: 0,
+            input_buffer: vec![0.0; fft_size],
+            fill: 0,
+            spectrum: vec![Complex::default(); fft_size],
+            accumulator: vec![Complex::default(); fft_size],
+            output_buffer: vec![0.0; partition_size],
+        }
+    }
+    /// Use the first channel of a [`Buffer`] as the impulse response.
+    pub fn from_buffer(buffer: &Buffer, partition_size: usize) -> Self {
+        let num_channels = buffer.num_channels().max(1);
+        let impulse_response: Vec<Sample> = (0..buffer.size() as usize / num_channels)
+            .map(|i| buffer.get_interleaved(i)[0])
+            .collect();
+        Self::new(&impulse_response, partition_size)
+    }
+    pub fn partition_size(&self) -> usize {
+        self.partition_size
+    }
+    /// Convolve the full partition in the second half of `input_buffer`
+    fn process_partition(&mut self) {
+        let p = self.partition_size;
+        let num_partitions = self.ir_spectra.len();
+        if num_partitions == 0 {
+            self.output_buffer.fill(0.0);
+            return;
+        }
+        for (c, &s) in self.spectrum.iter_mut().zip(self.input_buffer.iter()) {
+            *c = Complex::new(s, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        self.input_spectra[self.input_spectra_pos].copy_from_slice(&self.spectrum);
+        self.accumulator.fill(Complex::default());
+        for (k, ir) in self.ir_spectra.iter().enumerate() {
+            let input =
+                &self.input_spectra[(self.input_spectra_pos + num_partitions - k) % num_partitions];
+            for ((acc, &x), &h) in self.accumulator.iter_mut().zip(input.iter()).zip(ir.iter()) {
+                *acc += x * h;
+            }
+        }
+        self.ifft
+            .process_with_scratch(&mut self.accumulator, &mut self.scratch);
+        // The first half is the circular convolution wrapping around, discard it
+        let scale = 1.0 / (2 * p) as Sample;
+        for (out, c) in self.output_buffer.iter_mut().zip(&self.accumulator[p..]) {
+            *out = c.re * scale;
+        }
+        self.input_buffer.copy_within(p.., 0);
+        self.input_spectra_pos = (self.input_spectra_pos + 1) % num_partitions;
+    }
+}
+
+impl Gen for Convolution {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let input = &inputs[0];
+        let output = &mut outputs[0];
+        let p = self.partition_size;
+        if self.zero_latency && self.fill == 0 && input.len() == p {
+            // The block is exactly one partition so it can be output directly
+            self.input_buffer[p..].copy_from_slice(input);
+            self.process_partition();
+            output.copy_from_slice(&self.output_buffer);
+            return GenState::Continue;
+        }
+        for (&x, out) in input.iter().zip(output.iter_mut()) {
+            *out = self.output_buffer[self.fill];
+            self.input_buffer[p + self.fill] = x;
+            self.fill += 1;
+            if self.fill == p {
+                self.process_partition();
+                self.fill = 0;
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn latency(&self) -> usize {
+        if self.zero_latency {
+            0
+        } else {
+            self.partition_size
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Convolution"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn direct_convolution(signal: &[Sample], ir: &[Sample]) -> Vec<Sample> {
+        (0..signal.len())
+            .map(|n| {
+                (0..ir.len().min(n + 1))
+                    .map(|k| ir[k] * signal[n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn run(conv: &mut Convolution, signal: &[Sample], block_size: usize) -> Vec<Sample> {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut result = vec![];
+        for block in signal.chunks(block_size) {
+            let inputs = vec![block.to_vec().into_boxed_slice()];
+            let mut outputs = vec![vec![0.0; block.len()].into_boxed_slice()];
+            conv.process(&inputs, &mut outputs, &mut resources);
+            result.extend_from_slice(&outputs[0]);
+        }
+        result
+    }
+
+    #[test]
+    fn matches_direct_convolution() {
+        let rng = fastrand::Rng::with_seed(2);
+        let ir: Vec<Sample> = (0..300).map(|_| rng.f32() as Sample - 0.5).collect();
+        let signal: Vec<Sample> = (0..960).map(|_| rng.f32() as Sample - 0.5).collect();
+        let expected = direct_convolution(&signal, &ir);
+        // Partitions of one block have no latency
+        let mut conv = Convolution::with_block_size(&ir, 48);
+        assert_eq!(conv.latency(), 0);
+        let result = run(&mut conv, &signal, 48);
+        for (a, b) in result.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-3, "{a} {b}");
+        }
+        // Otherwise the output is delayed by one partition, whatever the block size
+        for block_size in [48, 64] {
+            let mut conv = Convolution::new(&ir, 64);
+            assert_eq!(conv.latency(), 64);
+            let result = run(&mut conv, &signal, block_size);
+            assert!(result[..64].iter().all(|&s| s == 0.0));
+            for (a, b) in result[64..].iter().zip(expected.iter()) {
+                assert!((a - b).abs() < 1e-3, "{a} {b}");
+            }
+        }
+    }
+}
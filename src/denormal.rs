@@ -0,0 +1,131 @@
+//! Protection against denormal (subnormal) numbers.
+//!
+//! Filters and reverbs with feedback decay towards zero exponentially and end
+//! up with tiny subnormal values, which are many times slower to compute with
+//! on most CPUs. The audio thread and the worker threads of a Graph run with
+//! flush-to-zero enabled through a [`DenormalGuard`] so that these become
+//! zero. On targets where flush-to-zero isn't available, Gens with feedback
+//! paths can use [`flush_denormal`] on their state.
+
+use crate::Sample;
+
+/// Values smaller than this are flushed by [`flush_denormal`]. Far below
+/// anything audible, but well above the subnormal range of f32.
+pub const DENORMAL_THRESHOLD: Sample = 1e-20;
+
+/// Returns zero for values so small that they risk becoming subnormal.
+#[inline(always)]
+pub fn flush_denormal(value: Sample) -> Sample {
+    if value.abs() < DENORMAL_THRESHOLD {
+        0.0
+    } else {
+        value
+    }
+}
+
+/// Enables flush-to-zero and denormals-are-zero for the current thread while
+/// it is alive, restoring the previous floating point mode when dropped.
+///
+/// Supported on x86_64 (SSE) and aarch64, a no-op on other targets.
+pub struct DenormalGuard {
+    previous: u64,
+}
+
+impl DenormalGuard {
+    pub fn new() -> Self {
+        let previous = arch::get();
+        arch::set(previous | arch::FLUSH_TO_ZERO);
+        Self { previous }
+    }
+}
+
+impl Default for DenormalGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for DenormalGuard {
+    fn drop(&mut self) {
+        arch::set(self.previous);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    /// The FTZ (bit 15) and DAZ (bit 6) flags of MXCSR
+    pub const FLUSH_TO_ZERO: u64 = (1 << 15) | (1 << 6);
+    #[inline]
+    pub fn get() -> u64 {
+        let mut csr: u32 = 0;
+        // Safety: Only stores MXCSR to the given location
+        unsafe {
+            std::arch::asm!("stmxcsr [{}]", in(reg) &mut csr as *mut u32, options(nostack, preserves_flags));
+        }
+        csr as u64
+    }
+    #[inline]
+    pub fn set(value: u64) {
+        let csr = value as u32;
+        // Safety: Only changes how floating point numbers are rounded and flushed
+        unsafe {
+            std::arch::asm!("ldmxcsr [{}]", in(reg) &csr as *const u32, options(nostack, readonly, preserves_flags));
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    /// The FZ flag (bit 24) of FPCR
+    pub const FLUSH_TO_ZERO: u64 = 1 << 24;
+    #[inline]
+    pub fn get() -> u64 {
+        let fpcr: u64;
+        // Safety: Only reads FPCR
+        unsafe {
+            std::arch::asm!("mrs {}, fpcr", out(reg) fpcr, options(nomem, nostack, preserves_flags));
+        }
+        fpcr
+    }
+    #[inline]
+    pub fn set(value: u64) {
+        // Safety: Only changes how floating point numbers are rounded and flushed
+        unsafe {
+            std::arch::asm!("msr fpcr, {}", in(reg) value, options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod arch {
+    pub const FLUSH_TO_ZERO: u64 = 0;
+    #[inline]
+    pub fn get() -> u64 {
+        0
+    }
+    #[inline]
+    pub fn set(_value: u64) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_small_values() {
+        assert_eq!(flush_denormal(1e-30), 0.0);
+        assert_eq!(flush_denormal(-1e-30), 0.0);
+        assert_eq!(flush_denormal(0.5), 0.5);
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn guard_flushes_subnormals_until_dropped() {
+        let tiny = std::hint::black_box(f32::MIN_POSITIVE);
+        {
+            let _guard = DenormalGuard::new();
+            assert_eq!(std::hint::black_box(tiny) * std::hint::black_box(0.5), 0.0);
+        }
+        assert!(std::hint::black_box(tiny) * std::hint::black_box(0.5) > 0.0);
+    }
+}
@@ -0,0 +1,395 @@
+//! Patching using operators.
+//!
+//! A [`Signal`] describes nodes and how they are connected without adding
+//! anything to a Graph. Arithmetic operators insert nodes doing the
+//! arithmetic (see [`math`](crate::math)) and `>>` connects the outputs of the left side to the inputs of
+//! the right side, starting at the first input. [`Graph::add`] adds all the
+//! new nodes in a Signal to a Graph and connects them.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::dsl::*;
+//! let mut graph = Graph::new(GraphSettings {
+//!     num_outputs: 2,
+//!     ..Default::default()
+//! });
+//! let vibrato = sine(5.0) * 3.0 + 440.0;
+//! let panner = graph
+//!     .add(sine(0.0).input("freq", vibrato) * 0.25 >> pan(0.0) >> graph_out())
+//!     .unwrap();
+//! // Nodes that have already been added can be used in new Signals
+//! graph.add(white_noise() * 0.1 >> Signal::from(panner)).unwrap();
+//! ```
+
+use std::ops::{Add, Div, Mul, Shr, Sub};
+
+use crate::fm::PmOperator;
+use crate::graph::{
+    constant, Connection, ConnectionError, Gen, Graph, InputKind, Mult, Node, NodeAddress,
+};
+use crate::math;
+use crate::noise::WhiteNoise;
+use crate::spatial::Pan2;
+use crate::Sample;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SignalError {
+    #[error("Only nodes can be connected to the graph output or have inputs.")]
+    NotANode,
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+}
+
+/// Which input of a node a Signal is connected to
+#[derive(Debug, Clone, Copy)]
+enum Input {
+    Index(usize),
+    Label(&'static str),
+    /// All outputs of the Signal to the inputs of the node, starting at the
+    /// first input
+    Chain,
+}
+
+enum NodeTarget {
+    New(Box<dyn Gen + Send>),
+    Existing(NodeAddress),
+}
+
+enum SignalKind {
+    Constant(Sample),
+    GraphInput(usize),
+    Node {
+        target: NodeTarget,
+        inputs: Vec<(Input, Signal)>,
+        /// The output to connect from, or all outputs if None
+        output: Option<usize>,
+    },
+    /// Something was connected to a Signal that isn't a node
+    Invalid,
+}
+
+/// A description of nodes and their connections, see the [module
+/// documentation](self).
+pub struct Signal {
+    kind: SignalKind,
+    /// The first graph output channel of every connection to the graph output
+    graph_outputs: Vec<usize>,
+}
+
+impl Signal {
+    fn from_kind(kind: SignalKind) -> Self {
+        Self {
+            kind,
+            graph_outputs: vec![],
+        }
+    }
+    /// A new node running `gen`
+    pub fn gen(gen: impl Gen + Send + 'static) -> Self {
+        Self::from_kind(SignalKind::Node {
+            target: NodeTarget::New(Box::new(gen)),
+            inputs: vec![],
+            output: None,
+        })
+    }
+    /// Connect `signal` to the input with the given label
+    pub fn input(self, label: &'static str, signal: impl Into<Signal>) -> Self {
+        self.with_input(Input::Label(label), signal.into())
+    }
+    /// Connect `signal` to the input with the given index
+    pub fn input_index(self, index: usize, signal: impl Into<Signal>) -> Self {
+        self.with_input(Input::Index(index), signal.into())
+    }
+    /// Only use one output of the node when connecting it
+    pub fn output(mut self, index: usize) -> Self {
+        if let SignalKind::Node { output, .. } = &mut self.kind {
+            *output = Some(index);
+        }
+        self
+    }
+    fn with_input(mut self, input: Input, signal: Signal) -> Self {
+        match &mut self.kind {
+            SignalKind::Node { inputs, .. } => inputs.push((input, signal)),
+            _ => self.kind = SignalKind::Invalid,
+        }
+        self
+    }
+    fn binary(gen: impl Gen + Send + 'static, a: Signal, b: Signal) -> Self {
+        Signal::gen(gen).input_index(0, a).input_index(1, b)
+    }
+}
+
+impl From<Sample> for Signal {
+    fn from(value: Sample) -> Self {
+        Signal::from_kind(SignalKind::Constant(value))
+    }
+}
+
+impl From<NodeAddress> for Signal {
+    fn from(node: NodeAddress) -> Self {
+        Signal::from_kind(SignalKind::Node {
+            target: NodeTarget::Existing(node),
+            inputs: vec![],
+            output: None,
+        })
+    }
+}
+
+/// A connection to the graph output, see [`graph_out`]
+#[derive(Debug, Clone, Copy)]
+pub struct GraphOut {
+    index: usize,
+}
+
+/// Connect all outputs of a Signal to the graph outputs, starting at the
+/// first graph output.
+pub fn graph_out() -> GraphOut {
+    GraphOut { index: 0 }
+}
+/// Connect all outputs of a Signal to the graph outputs, starting at `index`
+pub fn graph_out_at(index: usize) -> GraphOut {
+    GraphOut { index }
+}
+/// The graph input with the given index
+pub fn graph_in(index: usize) -> Signal {
+    Signal::from_kind(SignalKind::GraphInput(index))
+}
+/// A sine wave using a [`PmOperator`]
+pub fn sine(freq: impl Into<Signal>) -> Signal {
+    Signal::gen(PmOperator::new())
+        .input("freq", freq)
+        .input("ratio", 1.0 as Sample)
+}
+/// White noise with an amplitude of 1
+pub fn white_noise() -> Signal {
+    Signal::gen(WhiteNoise::new()).input("amp", 1.0 as Sample)
+}
+/// A [`Pan2`] with the signal to pan connected using `>>`
+pub fn pan(pan: impl Into<Signal>) -> Signal {
+    Signal::gen(Pan2).input("pan", pan)
+}
+
+impl<S: Into<Signal>> Mul<S> for Signal {
+    type Output = Signal;
+    fn mul(self, rhs: S) -> Signal {
+        Signal::binary(Mult, self, rhs.into())
+    }
+}
+impl<S: Into<Signal>> Add<S> for Signal {
+    type Output = Signal;
+    fn add(self, rhs: S) -> Signal {
+        Signal::binary(crate::graph::Add, self, rhs.into())
+    }
+}
+impl Mul<Signal> for Sample {
+    type Output = Signal;
+    fn mul(self, rhs: Signal) -> Signal {
+        Signal::binary(Mult, self.into(), rhs)
+    }
+}
+impl Add<Signal> for Sample {
+    type Output = Signal;
+    fn add(self, rhs: Signal) -> Signal {
+        Signal::binary(crate::graph::Add, self.into(), rhs)
+    }
+}
+impl<S: Into<Signal>> Sub<S> for Signal {
+    type Output = Signal;
+    fn sub(self, rhs: S) -> Signal {
+        Signal::binary(math::Sub, self, rhs.into())
+    }
+}
+impl<S: Into<Signal>> Div<S> for Signal {
+    type Output = Signal;
+    fn div(self, rhs: S) -> Signal {
+        Signal::binary(math::Div, self, rhs.into())
+    }
+}
+impl Sub<Signal> for Sample {
+    type Output = Signal;
+    fn sub(self, rhs: Signal) -> Signal {
+        Signal::binary(math::Sub, self.into(), rhs)
+    }
+}
+impl Div<Signal> for Sample {
+    type Output = Signal;
+    fn div(self, rhs: Signal) -> Signal {
+        Signal::binary(math::Div, self.into(), rhs)
+    }
+}
+impl Shr<Signal> for Signal {
+    type Output = Signal;
+    fn shr(self, rhs: Signal) -> Signal {
+        rhs.with_input(Input::Chain, self)
+    }
+}
+impl Shr<GraphOut> for Signal {
+    type Output = Signal;
+    fn shr(mut self, rhs: GraphOut) -> Signal {
+        self.graph_outputs.push(rhs.index);
+        self
+    }
+}
+
+/// Something that can be connected to an input once it has been added
+enum Source {
+    Constant(Sample),
+    GraphInput(usize),
+    Node {
+        node: NodeAddress,
+        output: Option<usize>,
+    },
+}
+
+impl Graph {
+    /// Add the new nodes in a [`Signal`] to this Graph and connect them.
+    /// Returns the node the Signal ends with.
+    pub fn add(&mut self, signal: Signal) -> Result<NodeAddress, SignalError> {
+        match self.add_signal(signal)? {
+            Source::Node { node, .. } => Ok(node),
+            _ => Err(SignalError::NotANode),
+        }
+    }
+    fn add_signal(&mut self, signal: Signal) -> Result<Source, SignalError> {
+        let Signal {
+            kind,
+            graph_outputs,
+        } = signal;
+        let source = match kind {
+            SignalKind::Constant(value) => Source::Constant(value),
+            SignalKind::GraphInput(index) => Source::GraphInput(index),
+            SignalKind::Invalid => return Err(SignalError::NotANode),
+            SignalKind::Node {
+                target,
+                inputs,
+                output,
+            } => {
+                let node = match target {
+                    NodeTarget::New(gen) => self.push_node(Node::new(gen.name(), gen)),
+                    NodeTarget::Existing(node) => node,
+                };
+                for (input, signal) in inputs {
+                    let source = self.add_signal(signal)?;
+                    self.connect_source(source, node, input)?;
+                }
+                Source::Node { node, output }
+            }
+        };
+        for index in graph_outputs {
+            match source {
+                Source::Node { node, output } => {
+                    let channels = match output {
+                        Some(_) => 1,
+                        None => self.node_num_outputs(node).unwrap_or(1),
+                    };
+                    self.connect(
+                        Connection::graph_output(node)
+                            .from_index(output.unwrap_or(0))
+                            .to_index(index)
+                            .channels(channels),
+                    )?;
+                }
+                _ => return Err(SignalError::NotANode),
+            }
+        }
+        Ok(source)
+    }
+    fn connect_source(
+        &mut self,
+        source: Source,
+        sink: NodeAddress,
+        input: Input,
+    ) -> Result<(), SignalError> {
+        let connection = |channel: usize| match source {
+            Source::Constant(value) => constant(value).to(sink),
+            Source::GraphInput(index) => Connection::graph_input(sink).from_index(index),
+            Source::Node { node, output } => {
+                node.to(sink).from_index(output.unwrap_or(0) + channel)
+            }
+        };
+        match input {
+            Input::Index(index) => self.connect(connection(0).to_index(index))?,
+            Input::Label(label) => self.connect(connection(0).to_label(label))?,
+            Input::Chain => {
+                // Chains only feed the main inputs, in order
+                let main_inputs: Vec<usize> = (0..self.node_num_inputs(sink).unwrap_or(1))
+                    .filter(|&input| {
+                        self.node_input_kind(sink, input) != Some(InputKind::Sidechain)
+                    })
+                    .collect();
+                let channels = match source {
+                    Source::Node { node, output: None } => self.node_num_outputs(node).unwrap_or(1),
+                    _ => 1,
+                };
+                for (channel, &index) in main_inputs.iter().take(channels).enumerate() {
+                    self.connect(connection(channel).to_index(index))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::GraphSettings;
+    use crate::{Resources, ResourcesSettings};
+
+    #[test]
+    fn arithmetic_and_chains() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 1,
+            num_inputs: 1,
+            num_outputs: 2,
+            ..Default::default()
+        });
+        let mut graph_node = graph.to_node().unwrap();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let scaled = graph.add((graph_in(0) * 4.0 - 1.0) / 2.0 + 1.5).unwrap();
+        graph
+            .add(Signal::from(scaled) >> pan(-1.0) >> graph_out())
+            .unwrap();
+        assert_eq!(
+            graph.add(Signal::from(1.0) >> graph_in(0)),
+            Err(SignalError::NotANode)
+        );
+        graph.commit_changes();
+        graph.update();
+        let input = vec![vec![3.0].into_boxed_slice()];
+        graph_node.process(&input, &mut resources);
+        assert!((graph_node.output_buffers()[0][0] - 7.0).abs() < 1e-5);
+        assert!(graph_node.output_buffers()[1][0].abs() < 1e-5);
+    }
+
+    #[test]
+    fn chains_skip_sidechain_inputs() {
+        let mut graph = Graph::new(GraphSettings::default());
+        let vocoder = graph
+            .add(white_noise() >> Signal::gen(crate::vocoder::Vocoder::new(4)))
+            .unwrap();
+        assert_eq!(
+            graph.node_input_kind(vocoder, 0),
+            Some(InputKind::Sidechain)
+        );
+        assert_eq!(graph.node_input_kind(vocoder, 1), Some(InputKind::Main));
+        assert_eq!(graph.node_input_kind(vocoder, 4), None);
+        let patch = graph.to_patch();
+        let vocoder_node = patch
+            .nodes
+            .iter()
+            .position(|node| node.gen == "Vocoder")
+            .unwrap();
+        assert_eq!(patch.nodes[vocoder_node].sidechain_inputs, [0]);
+        // The noise goes to the carrier, not the modulator
+        assert!(patch.edges.iter().any(|edge| edge.sink
+            == crate::patch::PatchSink::Node {
+                node: vocoder_node,
+                input: 1
+            }));
+        assert!(!patch.edges.iter().any(|edge| edge.sink
+            == crate::patch::PatchSink::Node {
+                node: vocoder_node,
+                input: 0
+            }));
+    }
+}
@@ -0,0 +1,387 @@
+//! Dynamics processing: compression, expansion, gating and limiting.
+//!
+//! [`Dynamics`] is a feed forward compressor, expander or gate with a soft
+//! knee and an optional external sidechain. [`Limiter`] is a lookahead
+//! brickwall limiter that guarantees that the output never goes above the
+//! ceiling, e.g. for protecting speakers at the output of a Graph.
+//!
+//! Levels and thresholds are in dB, times are in seconds.
+
+use crate::graph::{Gen, GenState, InputKind};
+use crate::{amplitude_to_db, db_to_amplitude, sample_to_f64, Resources, Sample};
+
+/// The lowest gain in dB applied by a gate
+const GATE_FLOOR_DB: Sample = -90.0;
+
+/// Coefficient for a one pole smoother reaching ~63% in `time` seconds
+pub(crate) fn time_coefficient(time: Sample, sample_rate: Sample) -> Sample {
+    if time <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (time * sample_rate)).exp()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynamicsKind {
+    /// Reduce the level above the threshold by the ratio
+    Compressor,
+    /// Reduce the level below the threshold by the ratio
+    Expander,
+    /// Silence the signal below the threshold. The ratio is ignored.
+    Gate,
+}
+
+/// A compressor, expander or gate.
+///
+/// Inputs are "signal", "threshold", "ratio", "attack", "release", "knee",
+/// "makeup" and, if enabled, "sidechain". The level is detected from the
+/// sidechain instead of the signal when it is enabled.
+#[derive(Debug, Clone)]
+pub struct Dynamics {
+    kind: DynamicsKind,
+    sidechain: bool,
+    envelope: Sample,
+    sample_rate: Sample,
+}
+
+impl Dynamics {
+    pub fn new(kind: DynamicsKind) -> Self {
+        Self {
+            kind,
+            sidechain: false,
+            envelope: 0.0,
+            sample_rate: 44100.0,
+        }
+    }
+    pub fn compressor() -> Self {
+        Self::new(DynamicsKind::Compressor)
+    }
+    pub fn expander() -> Self {
+        Self::new(DynamicsKind::Expander)
+    }
+    pub fn gate() -> Self {
+        Self::new(DynamicsKind::Gate)
+    }
+    /// Add a "sidechain" input that is used for detecting the level
+    pub fn sidechain(mut self) -> Self {
+        self.sidechain = true;
+        self
+    }
+    /// The gain in dB for a level in dB
+    pub fn gain_db(&self, level: Sample, threshold: Sample, ratio: Sample, knee: Sample) -> Sample {
+        let ratio = ratio.max(1.0);
+        let knee = knee.max(0.0);
+        let over = level - threshold;
+        match self.kind {
+            DynamicsKind::Compressor => {
+                if 2.0 * over < -knee {
+                    0.0
+                } else if 2.0 * over.abs() <= knee {
+                    (1.0 / ratio - 1.0) * (over + knee / 2.0).powi(2) / (2.0 * knee)
+                } else {
+                    (1.0 / ratio - 1.0) * over
+                }
+            }
+            DynamicsKind::Expander => {
+                if 2.0 * over > knee {
+                    0.0
+                } else if 2.0 * over.abs() <= knee {
+                    -(ratio - 1.0) * (over - knee / 2.0).powi(2) / (2.0 * knee)
+                } else {
+                    ((ratio - 1.0) * over).max(GATE_FLOOR_DB)
+                }
+            }
+            DynamicsKind::Gate => {
+                if over >= 0.0 {
+                    0.0
+                } else {
+                    GATE_FLOOR_DB
+                }
+            }
+        }
+    }
+}
+
+impl Gen for Dynamics {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let detector = if self.sidechain {
+            &inputs[7]
+        } else {
+            &inputs[0]
+        };
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let level = detector[i].abs();
+            let coefficient = if level > self.envelope {
+                time_coefficient(inputs[3][i], self.sample_rate)
+            } else {
+                time_coefficient(inputs[4][i], self.sample_rate)
+            };
+            self.envelope = level + coefficient * (self.envelope - level);
+            let level_db = amplitude_to_db(self.envelope.max(1e-9));
+            let gain_db =
+                self.gain_db(level_db, inputs[1][i], inputs[2][i], inputs[5][i]) + inputs[6][i];
+            *out = inputs[0][i] * db_to_amplitude(gain_db);
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+    }
+    fn num_inputs(&self) -> usize {
+        if self.sidechain {
+            8
+        } else {
+            7
+        }
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "threshold",
+            2 => "ratio",
+            3 => "attack",
+            4 => "release",
+            5 => "knee",
+            6 => "makeup",
+            7 => "sidechain",
+            _ => "",
+        }
+    }
+    fn input_required(&self, input: usize) -> bool {
+        input == 0
+    }
+    fn input_kind(&self, input: usize) -> InputKind {
+        match input {
+            7 => InputKind::Sidechain,
+            _ => InputKind::Main,
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        match self.kind {
+            DynamicsKind::Compressor => "Compressor",
+            DynamicsKind::Expander => "Expander",
+            DynamicsKind::Gate => "Gate",
+        }
+    }
+}
+
+/// A lookahead brickwall limiter for any number of linked channels.
+///
+/// The output is delayed by the lookahead time and the gain starts going down
+/// before a peak arrives so that the output never goes above the "ceiling"
+/// (in dB). After the peak the gain recovers with the "release" time. The
+/// inputs are one per channel followed by "ceiling" and "release".
+pub struct Limiter {
+    num_channels: usize,
+    lookahead_time: Sample,
+    lookahead: usize,
+    /// Delay line for every channel
+    delay: Vec<Vec<Sample>>,
+    delay_pos: usize,
+    /// Sliding window minimum of the required gain, stored as (sample index,
+    /// gain) pairs in a ring buffer
+    min_queue: Vec<(usize, Sample)>,
+    min_queue_start: usize,
+    min_queue_len: usize,
+    sample_counter: usize,
+    released_gain: Sample,
+    /// Moving average of the gain
+    average: Vec<Sample>,
+    average_sum: f64,
+    sample_rate: Sample,
+}
+
+impl Limiter {
+    /// `lookahead` is in seconds. A few milliseconds is usually enough.
+    pub fn new(num_channels: usize, lookahead: Sample) -> Self {
+        Self {
+            num_channels,
+            lookahead_time: lookahead,
+            lookahead: 0,
+            delay: vec![],
+            delay_pos: 0,
+            min_queue: vec![],
+            min_queue_start: 0,
+            min_queue_len: 0,
+            sample_counter: 0,
+            released_gain: 1.0,
+            average: vec![],
+            average_sum: 0.0,
+            sample_rate: 0.0,
+        }
+    }
+    /// The latency of the limiter in samples
+    pub fn latency(&self) -> usize {
+        self.lookahead
+    }
+    /// The minimum gain of the last `lookahead` + 1 samples including this one
+    /// so that every gain averaged for a delayed peak includes that peak
+    fn window_min(&mut self, gain: Sample) -> Sample {
+        let capacity = self.min_queue.len();
+        // Remove values that are no better than the new one from the back
+        while self.min_queue_len > 0 {
+            let back = (self.min_queue_start + self.min_queue_len - 1) % capacity;
+            if self.min_queue[back].1 >= gain {
+                self.min_queue_len -= 1;
+            } else {
+                break;
+            }
+        }
+        let back = (self.min_queue_start + self.min_queue_len) % capacity;
+        self.min_queue[back] = (self.sample_counter, gain);
+        self.min_queue_len += 1;
+        // Remove values that have left the window from the front
+        let front = self.min_queue[self.min_queue_start];
+        if self.sample_counter - front.0 > self.lookahead {
+            self.min_queue_start = (self.min_queue_start + 1) % capacity;
+            self.min_queue_len -= 1;
+        }
+        self.min_queue[self.min_queue_start].1
+    }
+}
+
+impl Gen for Limiter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let ceilings = &inputs[self.num_channels];
+        let releases = &inputs[self.num_channels + 1];
+        let block_size = outputs.first().map(|o| o.len()).unwrap_or(0);
+        for i in 0..block_size {
+            let ceiling = db_to_amplitude(ceilings[i]);
+            let peak = inputs[..self.num_channels]
+                .iter()
+                .fold(0.0 as Sample, |peak, channel| peak.max(channel[i].abs()));
+            let required_gain = if peak > ceiling { ceiling / peak } else { 1.0 };
+            let target = self.window_min(required_gain);
+            // Only smooth when the gain goes up so that peaks are never missed
+            if target < self.released_gain {
+                self.released_gain = target;
+            } else {
+                let coefficient = time_coefficient(releases[i], self.sample_rate);
+                self.released_gain = target + coefficient * (self.released_gain - target);
+            }
+            // Smooth the gain going down over the lookahead time
+            let average_pos = self.sample_counter % self.lookahead;
+            self.average_sum += sample_to_f64(self.released_gain - self.average[average_pos]);
+            self.average[average_pos] = self.released_gain;
+            let gain = (self.average_sum / self.lookahead as f64) as Sample;
+            for channel in 0..self.num_channels {
+                let delayed = self.delay[channel][self.delay_pos];
+                self.delay[channel][self.delay_pos] = inputs[channel][i];
+                outputs[channel][i] = delayed * gain;
+            }
+            self.delay_pos = (self.delay_pos + 1) % self.lookahead;
+            self.sample_counter += 1;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.lookahead = ((self.lookahead_time * sample_rate) as usize).max(1);
+        self.delay = vec![vec![0.0; self.lookahead]; self.num_channels];
+        self.delay_pos = 0;
+        self.min_queue = vec![(0, 1.0); self.lookahead + 2];
+        self.min_queue_start = 0;
+        self.min_queue_len = 0;
+        self.average = vec![1.0; self.lookahead];
+        self.average_sum = self.lookahead as f64;
+        self.released_gain = 1.0;
+    }
+    fn num_inputs(&self) -> usize {
+        self.num_channels + 2
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_channels
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        if input == self.num_channels {
+            "ceiling"
+        } else if input == self.num_channels + 1 {
+            "release"
+        } else {
+            ""
+        }
+    }
+    fn input_required(&self, input: usize) -> bool {
+        input < self.num_channels
+    }
+    fn latency(&self) -> usize {
+        self.lookahead
+    }
+    fn name(&self) -> &'static str {
+        "Limiter"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn static_curves() {
+        let compressor = Dynamics::compressor();
+        assert_eq!(compressor.gain_db(-30.0, -20.0, 4.0, 0.0), 0.0);
+        assert_eq!(compressor.gain_db(-10.0, -20.0, 4.0, 0.0), -7.5);
+        // The knee is continuous at its edges
+        let edge = compressor.gain_db(-17.0, -20.0, 4.0, 6.0);
+        assert!((edge - compressor.gain_db(-17.0, -20.0, 4.0, 0.0)).abs() < 1e-4);
+        let expander = Dynamics::expander();
+        assert_eq!(expander.gain_db(-30.0, -20.0, 2.0, 0.0), -10.0);
+        assert_eq!(expander.gain_db(-10.0, -20.0, 2.0, 0.0), 0.0);
+        assert_eq!(
+            Dynamics::gate().gain_db(-30.0, -20.0, 1.0, 0.0),
+            GATE_FLOOR_DB
+        );
+    }
+
+    #[test]
+    fn limiter_never_exceeds_the_ceiling() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut limiter = Limiter::new(2, 0.002);
+        limiter.init(48000.);
+        assert_eq!(limiter.latency(), 96);
+        let rng = fastrand::Rng::with_seed(3);
+        let mut max_out: Sample = 0.0;
+        for block in 0..20 {
+            let left: Vec<Sample> = (0..64).map(|_| (rng.f32() as Sample - 0.5) * 4.0).collect();
+            let right: Vec<Sample> = if block == 10 {
+                vec![8.0; 64]
+            } else {
+                vec![0.1; 64]
+            };
+            let inputs = vec![
+                left.into_boxed_slice(),
+                right.into_boxed_slice(),
+                vec![-6.0; 64].into_boxed_slice(),
+                vec![0.05; 64].into_boxed_slice(),
+            ];
+            let mut outputs = vec![vec![0.0; 64].into_boxed_slice(); 2];
+            limiter.process(&inputs, &mut outputs, &mut resources);
+            for out in outputs.iter().flat_map(|o| o.iter()) {
+                max_out = max_out.max(out.abs());
+            }
+        }
+        assert!(max_out <= db_to_amplitude(-6.0) + 1e-4, "{max_out}");
+        assert!(max_out > 0.4);
+    }
+}
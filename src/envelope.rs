@@ -20,7 +20,7 @@
 //! ```
 
 // level, duration
-type Point = (f32, f32);
+type Point = (Sample, Sample);
 // Storing time as samples in an f64 is fine, there's adequate range and avoids type casting.
 
 // Benefits compared to Spline implementation:
@@ -31,7 +31,8 @@ type Point = (f32, f32);
 
 use crate::{
     graph::{Gen, GenState, Sample},
-    StopAction,
+    patch::{PatchError, SavedState},
+    sample_to_f64, StopAction,
 };
 
 // TODO:
@@ -87,16 +88,20 @@ pub enum SustainMode {
 #[derive(Debug, Clone, Copy)]
 pub enum Curve {
     Linear,
-    Exponential(f32),
+    Exponential(Sample),
 }
 
 impl Curve {
     #[inline]
-    pub fn transform(&self, a: f32) -> f32 {
+    pub fn transform(&self, a: Sample) -> Sample {
         match self {
             Curve::Linear => a,
             // Using the fastapprox::faster variant is significantly faster, but too inaccurate
+            #[cfg(not(feature = "f64"))]
             Curve::Exponential(exponent) => fastapprox::fast::pow(a, *exponent),
+            // fastapprox only has f32 functions which would lose the precision of f64
+            #[cfg(feature = "f64")]
+            Curve::Exponential(exponent) => a.powf(*exponent),
         }
     }
 }
@@ -106,25 +111,25 @@ impl Curve {
 /// it offline, it can be turned into an iterator by calling [`EnvelopeGen::iter_mut`].
 #[derive(Debug, Clone)]
 pub struct EnvelopeGen {
-    pub start_value: f32,
+    pub start_value: Sample,
 
     // Points with their time value as seconds. This enables setting the sample rate when the Gen is initiated.
     points_secs: Vec<Point>,
     points: Vec<Point>,
     curves: Vec<Curve>,
-    source_value: f32,
-    target_value: f32,
+    source_value: Sample,
+    target_value: Sample,
     /// The difference between source_value and target_value
-    source_target_diff: f32,
-    fade_out_duration: f32,
-    segment_duration: f32,
+    source_target_diff: Sample,
+    fade_out_duration: Sample,
+    segment_duration: Sample,
     current_curve: Curve,
     current_timestep: f64,
     /// Goes from 0 to 1 over a segment
     duration_passed: f64,
     next_index: usize,
     pub playing: bool,
-    sample_rate: f32,
+    sample_rate: Sample,
     sustain: SustainMode,
     stop_action: StopAction,
     // pub sustaining: bool, // if the envelope should stop at a certain point before release
@@ -136,7 +141,7 @@ pub struct EnvelopeGen {
 
 impl EnvelopeGen {
     /// Create a new Envelope. points are in the format (level, duration) where the duration is given in seconds, and later converted to samples internally.
-    pub fn new(start_value: f32, mut points: Vec<Point>, sample_rate: f32) -> Self {
+    pub fn new(start_value: Sample, mut points: Vec<Point>, sample_rate: Sample) -> Self {
         let points_secs = points.clone();
         // Convert durations from seconds to samples
         for point in &mut points {
@@ -170,7 +175,13 @@ impl EnvelopeGen {
             waiting_for_release: false,
         }
     }
-    pub fn adsr(attack: f32, decay: f32, sustain: f32, release: f32, sample_rate: f32) -> Self {
+    pub fn adsr(
+        attack: Sample,
+        decay: Sample,
+        sustain: Sample,
+        release: Sample,
+        sample_rate: Sample,
+    ) -> Self {
         let points = vec![(1.0, attack), (sustain, decay), (0.0, release)];
         Self::new(0.0, points, sample_rate).sustain(SustainMode::SustainAtPoint(1))
     }
@@ -248,7 +259,7 @@ impl EnvelopeGen {
         self.source_target_diff = self.target_value - self.source_value;
         self.segment_duration = self.points[0].1;
         self.current_curve = self.curves[0];
-        self.current_timestep = (self.segment_duration as f64).recip();
+        self.current_timestep = sample_to_f64(self.segment_duration).recip();
         self.duration_passed = 0.;
         self.next_index = 1;
     }
@@ -261,7 +272,7 @@ impl EnvelopeGen {
         self.source_target_diff = self.target_value - self.source_value;
         self.segment_duration = self.points[0].1;
         self.current_curve = self.curves[0];
-        self.current_timestep = (self.segment_duration as f64).recip();
+        self.current_timestep = sample_to_f64(self.segment_duration).recip();
         self.duration_passed = 0.;
         self.next_index = 1;
     }
@@ -293,11 +304,11 @@ impl EnvelopeGen {
         self.source_target_diff = self.target_value - self.source_value;
         self.duration_passed = 0.0;
         self.segment_duration = self.fade_out_duration * self.sample_rate;
-        self.current_timestep = (self.segment_duration as f64).recip();
+        self.current_timestep = sample_to_f64(self.segment_duration).recip();
         self.current_curve = Curve::Linear;
     }
     /// Change the value of a specific point.
-    pub fn set_value(&mut self, value: f32, index: usize) {
+    pub fn set_value(&mut self, value: Sample, index: usize) {
         self.points[index].0 = value;
         // Also update the value if it's currently playing
         if index == self.next_index - 1 {
@@ -312,7 +323,7 @@ impl EnvelopeGen {
         }
     }
     /// Set the duration of a segment in seconds (the duration to reach the value with the same index). Will not affect the currently playing segment.
-    pub fn set_duration(&mut self, duration: f32, index: usize) {
+    pub fn set_duration(&mut self, duration: Sample, index: usize) {
         // Convert seconds to samples
         self.points[index].1 = duration * self.sample_rate;
     }
@@ -346,7 +357,7 @@ impl EnvelopeGen {
                     self.target_value = self.points[self.next_index].0;
                     self.source_target_diff = self.target_value - self.source_value;
                     self.segment_duration = self.points[self.next_index].1;
-                    self.current_timestep = (self.segment_duration as f64).recip();
+                    self.current_timestep = sample_to_f64(self.segment_duration).recip();
                     self.current_curve = self.curves[self.next_index];
                     self.duration_passed = 0.0;
                     self.next_index += 1;
@@ -366,7 +377,7 @@ impl EnvelopeGen {
         if destination_index < self.points.len() {
             self.target_value = self.points[destination_index].0;
             self.segment_duration = self.points[destination_index].1;
-            self.current_timestep = (self.segment_duration as f64).recip();
+            self.current_timestep = sample_to_f64(self.segment_duration).recip();
             self.current_curve = self.curves[destination_index];
             self.duration_passed = 0.;
             self.next_index = destination_index + 1;
@@ -378,7 +389,7 @@ impl EnvelopeGen {
     #[inline(always)]
     fn current_value(&mut self) -> Sample {
         // note: t goes from 1 to just above 0 over the duration of a segment
-        let t = self.current_curve.transform(self.duration_passed as f32);
+        let t = self.current_curve.transform(self.duration_passed as Sample);
         // linear interpolation
         self.source_value + (t * self.source_target_diff)
     }
@@ -465,6 +476,62 @@ impl Gen for EnvelopeGen {
         }
     }
 
+    /// The start value, points and curves
+    fn save_state(&self) -> Option<SavedState> {
+        let points = self
+            .points_secs
+            .iter()
+            .flat_map(|&(level, duration)| [level, duration])
+            .collect();
+        let curves = self
+            .curves
+            .iter()
+            .map(|curve| match curve {
+                Curve::Linear => SavedState::Text("linear".to_string()),
+                Curve::Exponential(exponent) => SavedState::Number(sample_to_f64(*exponent)),
+            })
+            .collect();
+        Some(SavedState::Map(vec![
+            (
+                "start_value".to_string(),
+                SavedState::Number(sample_to_f64(self.start_value)),
+            ),
+            ("points".to_string(), SavedState::Samples(points)),
+            ("curves".to_string(), SavedState::List(curves)),
+        ]))
+    }
+
+    fn load_state(&mut self, state: &SavedState) -> Result<(), PatchError> {
+        let invalid = PatchError::InvalidState(self.name());
+        let start_value = state.get("start_value").and_then(SavedState::as_number);
+        let points = state.get("points").and_then(SavedState::as_samples);
+        let curves = state.get("curves").and_then(SavedState::as_list);
+        let (Some(start_value), Some(points), Some(curves)) = (start_value, points, curves) else {
+            return Err(invalid);
+        };
+        if points.is_empty() || points.len() % 2 == 1 {
+            return Err(invalid);
+        }
+        let curves = curves
+            .iter()
+            .map(|curve| match curve {
+                SavedState::Number(exponent) => Some(Curve::Exponential(*exponent as Sample)),
+                SavedState::Text(text) if text == "linear" => Some(Curve::Linear),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(invalid)?;
+        self.start_value = start_value as Sample;
+        self.set_points(points.chunks(2).map(|p| (p[0], p[1])).collect());
+        for (slot, curve) in self.curves.iter_mut().zip(curves) {
+            *slot = curve;
+        }
+        if self.playing {
+            self.start();
+        }
+        Ok(())
+    }
+
     fn name(&self) -> &'static str {
         "Envelope"
     }
@@ -500,10 +567,11 @@ mod tests {
         for _i in 0..(sample_rate * 0.5 - 2.0) as i32 {
             env.next_sample();
         }
-        assert_eq!(
-            env.next_sample(),
-            0.5,
-            "Envelope value was expected to be 0.5 halfway between 0.0 and 1.0. {:?}",
+        // The position in the segment is accumulated so it may be slightly off
+        let value = env.next_sample();
+        assert!(
+            (value - 0.5).abs() < 1e-6,
+            "Envelope value was expected to be 0.5 halfway between 0.0 and 1.0, was {value}. {:?}",
             env
         );
         // fast forward another 0.5 seconds minus the samples we've already extracted
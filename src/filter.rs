@@ -0,0 +1,831 @@
+//! Filters.
+//!
+//! [`Biquad`] is a second order filter with coefficients from the
+//! RBJ Audio EQ Cookbook. [`Eq`] cascades biquads into a parametric
+//! equalizer with any number of bands, up to [`MAX_EQ_BANDS`].
+//! [`DcBlocker`] removes DC offset. [`LadderFilter`] is a nonlinear model of
+//! the four pole transistor ladder lowpass filter of analog synthesizers.
+//! [`Crossover`] splits a signal into frequency bands for multiband
+//! processing.
+//!
+//! Frequencies are in Hz and gains in dB.
+
+use crate::denormal::flush_denormal;
+use crate::dynamics::time_coefficient;
+use crate::graph::{Gen, GenState};
+use crate::{Resources, Sample};
+
+/// The largest number of bands in an [`Eq`]
+pub const MAX_EQ_BANDS: usize = 8;
+const BAND_INPUT_NAMES: [[&str; 3]; MAX_EQ_BANDS] = [
+    ["freq0", "gain0", "q0"],
+    ["freq1", "gain1", "q1"],
+    ["freq2", "gain2", "q2"],
+    ["freq3", "gain3", "q3"],
+    ["freq4", "gain4", "q4"],
+    ["freq5", "gain5", "q5"],
+    ["freq6", "gain6", "q6"],
+    ["freq7", "gain7", "q7"],
+];
+
+/// The response of a [`Biquad`]. The gain is only used by the peak and
+/// shelving filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BiquadKind {
+    LowPass,
+    HighPass,
+    /// Band pass with 0 dB gain at the center frequency
+    BandPass,
+    Notch,
+    /// Boost or cut around the frequency
+    Peak,
+    /// Boost or cut below the frequency
+    LowShelf,
+    /// Boost or cut above the frequency
+    HighShelf,
+    /// Flat magnitude with a phase shift of 180 degrees at the frequency
+    AllPass,
+}
+
+/// A second order filter in transposed direct form II.
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    b0: Sample,
+    b1: Sample,
+    b2: Sample,
+    a1: Sample,
+    a2: Sample,
+    z1: Sample,
+    z2: Sample,
+}
+
+impl Biquad {
+    /// A filter letting everything through until its coefficients are set
+    pub fn new() -> Self {
+        Self {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+    /// Set the coefficients without resetting the state of the filter
+    pub fn set(
+        &mut self,
+        kind: BiquadKind,
+        freq: Sample,
+        q: Sample,
+        gain_db: Sample,
+        sample_rate: Sample,
+    ) {
+        let freq = freq.clamp(1.0, sample_rate * 0.49);
+        let q = q.max(0.01);
+        let w0 = std::f64::consts::TAU as Sample * freq / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let a = (10.0 as Sample).powf(gain_db / 40.0);
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            BiquadKind::LowPass => {
+                let b = (1.0 - cos) / 2.0;
+                (b, 1.0 - cos, b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+            }
+            BiquadKind::HighPass => {
+                let b = (1.0 + cos) / 2.0;
+                (b, -(1.0 + cos), b, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+            }
+            BiquadKind::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            BiquadKind::Notch => (1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            BiquadKind::AllPass => (
+                1.0 - alpha,
+                -2.0 * cos,
+                1.0 + alpha,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            BiquadKind::Peak => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            BiquadKind::LowShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos + s),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - s),
+                    (a + 1.0) + (a - 1.0) * cos + s,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - s,
+                )
+            }
+            BiquadKind::HighShelf => {
+                let s = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos + s),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - s),
+                    (a + 1.0) - (a - 1.0) * cos + s,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - s,
+                )
+            }
+        };
+        self.b0 = b0 / a0;
+        self.b1 = b1 / a0;
+        self.b2 = b2 / a0;
+        self.a1 = a1 / a0;
+        self.a2 = a2 / a0;
+    }
+    #[inline]
+    pub fn process(&mut self, input: Sample) -> Sample {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+    /// Clear the state of the filter
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
+
+impl Default for Biquad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One band of an [`Eq`]. The values are the defaults of the inputs of the
+/// band.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    pub kind: BiquadKind,
+    pub freq: Sample,
+    pub gain_db: Sample,
+    pub q: Sample,
+}
+
+/// The smoothed parameters of a band the coefficients were last set from
+#[derive(Debug, Clone, Copy)]
+struct BandState {
+    filter: Biquad,
+    freq: Sample,
+    gain_db: Sample,
+    q: Sample,
+    dirty: bool,
+}
+
+/// A parametric equalizer made from cascaded biquads.
+///
+/// The first input is "signal", followed by "freq", "gain" and "q" for every
+/// band, numbered from 0, e.g. "freq0", "gain0", "q0", "freq1" etc. Changes
+/// to the band inputs are smoothed so that adjusting them live doesn't
+/// produce zipper noise.
+#[derive(Debug, Clone)]
+pub struct Eq {
+    bands: Vec<EqBand>,
+    states: Vec<BandState>,
+    smoothing_time: Sample,
+    sample_rate: Sample,
+}
+
+impl Eq {
+    /// An Eq without any bands
+    pub fn new() -> Self {
+        Self {
+            bands: Vec::with_capacity(MAX_EQ_BANDS),
+            states: vec![],
+            smoothing_time: 0.02,
+            sample_rate: 44100.0,
+        }
+    }
+    /// A three band Eq: a low shelf at 250 Hz, a peak at 1 kHz and a high
+    /// shelf at 4 kHz, all at 0 dB.
+    pub fn three_band() -> Self {
+        Self::new()
+            .band(BiquadKind::LowShelf, 250.0, 0.0, 0.707)
+            .band(BiquadKind::Peak, 1000.0, 0.0, 0.707)
+            .band(BiquadKind::HighShelf, 4000.0, 0.0, 0.707)
+    }
+    /// Add a band. Bands after the first [`MAX_EQ_BANDS`] are ignored.
+    pub fn band(mut self, kind: BiquadKind, freq: Sample, gain_db: Sample, q: Sample) -> Self {
+        if self.bands.len() < MAX_EQ_BANDS {
+            self.bands.push(EqBand {
+                kind,
+                freq,
+                gain_db,
+                q,
+            });
+        }
+        self
+    }
+    /// Set the time in seconds for smoothing changes to the band inputs.
+    /// Default: 0.02
+    pub fn smoothing_time(mut self, seconds: Sample) -> Self {
+        self.smoothing_time = seconds.max(0.0);
+        self
+    }
+    pub fn bands(&self) -> &[EqBand] {
+        &self.bands
+    }
+}
+
+impl Default for Eq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Move `value` towards `target`, snapping to it when close enough to stop
+/// recalculating coefficients. Returns true if the value changed.
+#[inline]
+fn smooth_towards(value: &mut Sample, target: Sample, coefficient: Sample) -> bool {
+    if *value == target {
+        return false;
+    }
+    let next = target + coefficient * (*value - target);
+    *value = if (next - target).abs() <= target.abs() * 1e-4 + 1e-6 {
+        target
+    } else {
+        next
+    };
+    true
+}
+
+impl Gen for Eq {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let coefficient = time_coefficient(self.smoothing_time, self.sample_rate);
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let mut value = inputs[0][i];
+            for (b, (band, state)) in self.bands.iter().zip(self.states.iter_mut()).enumerate() {
+                let band_inputs = &inputs[1 + b * 3..];
+                let mut changed = state.dirty;
+                changed |= smooth_towards(&mut state.freq, band_inputs[0][i], coefficient);
+                changed |= smooth_towards(&mut state.gain_db, band_inputs[1][i], coefficient);
+                changed |= smooth_towards(&mut state.q, band_inputs[2][i], coefficient);
+                if changed {
+                    state.filter.set(
+                        band.kind,
+                        state.freq,
+                        state.q,
+                        state.gain_db,
+                        self.sample_rate,
+                    );
+                    state.dirty = false;
+                }
+                value = state.filter.process(value);
+            }
+            *out = value;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.states = self
+            .bands
+            .iter()
+            .map(|band| BandState {
+                filter: Biquad::new(),
+                freq: band.freq,
+                gain_db: band.gain_db,
+                q: band.q,
+                dirty: true,
+            })
+            .collect();
+    }
+    fn num_inputs(&self) -> usize {
+        1 + self.bands.len() * 3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        if input == 0 {
+            "signal"
+        } else {
+            BAND_INPUT_NAMES
+                .get((input - 1) / 3)
+                .map(|names| names[(input - 1) % 3])
+                .unwrap_or("")
+        }
+    }
+    fn input_required(&self, input: usize) -> bool {
+        input == 0
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        if input == 0 {
+            return 0.0;
+        }
+        match self.bands.get((input - 1) / 3) {
+            Some(band) => match (input - 1) % 3 {
+                0 => band.freq,
+                1 => band.gain_db,
+                _ => band.q,
+            },
+            None => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Eq"
+    }
+}
+
+/// A one pole DC blocking filter.
+///
+/// Removes any DC offset from the "signal" input, e.g. after asymmetric
+/// waveshaping or in the feedback path of a delay or reverb.
+#[derive(Debug, Clone)]
+pub struct DcBlocker {
+    cutoff: Sample,
+    coefficient: Sample,
+    last_input: Sample,
+    last_output: Sample,
+}
+
+impl DcBlocker {
+    pub fn new() -> Self {
+        Self {
+            cutoff: 10.0,
+            coefficient: 0.999,
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+    /// Set the cutoff frequency of the filter. Default: 10 Hz
+    pub fn cutoff(mut self, freq: Sample) -> Self {
+        self.cutoff = freq.max(0.0);
+        self
+    }
+    #[inline]
+    pub fn process_sample(&mut self, input: Sample) -> Sample {
+        let output = input - self.last_input + self.coefficient * self.last_output;
+        self.last_input = input;
+        self.last_output = flush_denormal(output);
+        output
+    }
+}
+
+impl Default for DcBlocker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for DcBlocker {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (input, out) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            *out = self.process_sample(*input);
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.coefficient = (-std::f64::consts::TAU as Sample * self.cutoff / sample_rate).exp();
+        self.last_input = 0.0;
+        self.last_output = 0.0;
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            _ => "",
+        }
+    }
+    fn input_required(&self, input: usize) -> bool {
+        input == 0
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "DcBlocker"
+    }
+}
+
+/// A Moog style four pole ladder lowpass filter.
+///
+/// A zero delay feedback model with a saturating input stage. Inputs are
+/// "signal", "cutoff" (Hz), "resonance" and "drive". The filter starts
+/// self-oscillating at a resonance of 1, with the amplitude kept in check by
+/// the saturation. The drive is the gain into the saturation; higher drive
+/// gives more distortion. The cutoff can be modulated at audio rate.
+#[derive(Debug, Clone)]
+pub struct LadderFilter {
+    stages: [Sample; 4],
+    sample_rate: Sample,
+}
+
+impl LadderFilter {
+    pub fn new() -> Self {
+        Self {
+            stages: [0.0; 4],
+            sample_rate: 44100.0,
+        }
+    }
+    #[inline]
+    pub fn process_sample(
+        &mut self,
+        input: Sample,
+        cutoff: Sample,
+        resonance: Sample,
+        drive: Sample,
+    ) -> Sample {
+        let cutoff = cutoff.clamp(1.0, self.sample_rate * 0.49);
+        let g = (std::f64::consts::PI as Sample * cutoff / self.sample_rate).tan();
+        let gain = g / (1.0 + g);
+        let feedback = 4.0 * resonance.clamp(0.0, 1.5);
+        // Every stage is y = gain * x + s * (1 - gain), so the output of the
+        // ladder is linear in its input and can be solved for without delay
+        let mut sum = 0.0;
+        for s in &self.stages {
+            sum = sum * gain + s * (1.0 - gain);
+        }
+        let gain4 = gain * gain * gain * gain;
+        let estimate = (gain4 * input * drive + sum) / (1.0 + feedback * gain4);
+        let mut value = (input * drive - feedback * estimate).tanh();
+        for s in &mut self.stages {
+            let v = (value - *s) * gain;
+            value = v + *s;
+            *s = flush_denormal(value + v);
+        }
+        value
+    }
+}
+
+impl Default for LadderFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for LadderFilter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            *out = self.process_sample(inputs[0][i], inputs[1][i], inputs[2][i], inputs[3][i]);
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.stages = [0.0; 4];
+    }
+    fn num_inputs(&self) -> usize {
+        4
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "cutoff",
+            2 => "resonance",
+            3 => "drive",
+            _ => "",
+        }
+    }
+    fn input_required(&self, input: usize) -> bool {
+        input == 0
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            1 => 1000.0,
+            3 => 1.0,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "LadderFilter"
+    }
+}
+
+/// The largest number of bands in a [`Crossover`]
+pub const MAX_CROSSOVER_BANDS: usize = 8;
+const CROSSOVER_INPUT_NAMES: [&str; MAX_CROSSOVER_BANDS - 1] = [
+    "freq0", "freq1", "freq2", "freq3", "freq4", "freq5", "freq6",
+];
+const CROSSOVER_OUTPUT_NAMES: [&str; MAX_CROSSOVER_BANDS] = [
+    "band0", "band1", "band2", "band3", "band4", "band5", "band6", "band7",
+];
+
+/// A fourth order Linkwitz-Riley split into a low and a high band
+#[derive(Debug, Clone)]
+struct CrossoverSplit {
+    freq: Sample,
+    low: [Biquad; 2],
+    high: [Biquad; 2],
+    /// Allpass filters matching the phase of the splits above this one,
+    /// for the low band
+    allpasses: Vec<Biquad>,
+}
+
+/// Splits the input into frequency bands using fourth order Linkwitz-Riley
+/// filters.
+///
+/// Inputs are "signal" followed by one crossover frequency for every split,
+/// "freq0", "freq1" etc., which should be in ascending order. Outputs are
+/// "band0", "band1" etc. from the lowest band to the highest. Every band is
+/// phase compensated for the splits it didn't go through, so the bands sum
+/// back to an allpass filtered version of the input with a flat magnitude
+/// response. This makes it suitable for multiband compression or
+/// distortion where the bands are processed separately and then mixed.
+#[derive(Debug, Clone)]
+pub struct Crossover {
+    freqs: Vec<Sample>,
+    splits: Vec<CrossoverSplit>,
+    sample_rate: Sample,
+}
+
+impl Crossover {
+    /// A crossover with a band below the first frequency, between each pair
+    /// of frequencies and above the last frequency. Frequencies after the
+    /// first [`MAX_CROSSOVER_BANDS`] - 1 are ignored.
+    pub fn new(freqs: &[Sample]) -> Self {
+        let mut freqs: Vec<Sample> = freqs
+            .iter()
+            .copied()
+            .take(MAX_CROSSOVER_BANDS - 1)
+            .collect();
+        freqs.sort_by(|a, b| a.total_cmp(b));
+        Self {
+            freqs,
+            splits: vec![],
+            sample_rate: 44100.0,
+        }
+    }
+    /// The number of bands, one more than the number of crossover frequencies
+    pub fn num_bands(&self) -> usize {
+        self.freqs.len() + 1
+    }
+    /// Recalculate the coefficients of split `i` and of the allpass filters
+    /// compensating for it
+    fn set_split_freq(&mut self, i: usize, freq: Sample) {
+        let sample_rate = self.sample_rate;
+        let split = &mut self.splits[i];
+        split.freq = freq;
+        for filter in &mut split.low {
+            filter.set(BiquadKind::LowPass, freq, FRAC_1_SQRT_2, 0.0, sample_rate);
+        }
+        for filter in &mut split.high {
+            filter.set(BiquadKind::HighPass, freq, FRAC_1_SQRT_2, 0.0, sample_rate);
+        }
+        for (j, lower) in self.splits[..i].iter_mut().enumerate() {
+            lower.allpasses[i - j - 1].set(
+                BiquadKind::AllPass,
+                freq,
+                FRAC_1_SQRT_2,
+                0.0,
+                sample_rate,
+            );
+        }
+    }
+}
+
+const FRAC_1_SQRT_2: Sample = std::f64::consts::FRAC_1_SQRT_2 as Sample;
+
+impl Gen for Crossover {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for i in 0..self.splits.len() {
+            let freq = inputs[1 + i][0];
+            if freq != self.splits[i].freq {
+                self.set_split_freq(i, freq);
+            }
+        }
+        let num_splits = self.splits.len();
+        for (frame, &input) in inputs[0].iter().enumerate() {
+            let mut rest = input;
+            for (split, output) in self.splits.iter_mut().zip(outputs.iter_mut()) {
+                let [low0, low1] = &mut split.low;
+                let [high0, high1] = &mut split.high;
+                let mut low = low1.process(low0.process(rest));
+                rest = high1.process(high0.process(rest));
+                for allpass in &mut split.allpasses {
+                    low = allpass.process(low);
+                }
+                output[frame] = low;
+            }
+            outputs[num_splits][frame] = rest;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        let num_splits = self.freqs.len();
+        self.splits = (0..num_splits)
+            .map(|i| CrossoverSplit {
+                freq: 0.0,
+                low: [Biquad::new(); 2],
+                high: [Biquad::new(); 2],
+                allpasses: vec![Biquad::new(); num_splits - i - 1],
+            })
+            .collect();
+        for i in 0..num_splits {
+            self.set_split_freq(i, self.freqs[i]);
+        }
+    }
+    fn num_inputs(&self) -> usize {
+        1 + self.freqs.len()
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_bands()
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        if input == 0 {
+            "signal"
+        } else {
+            CROSSOVER_INPUT_NAMES.get(input - 1).copied().unwrap_or("")
+        }
+    }
+    fn input_required(&self, input: usize) -> bool {
+        input == 0
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        CROSSOVER_OUTPUT_NAMES.get(output).copied().unwrap_or("")
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            0 => 0.0,
+            _ => self.freqs.get(input - 1).copied().unwrap_or(0.0),
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Crossover"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db_to_amplitude, ResourcesSettings};
+
+    /// The output after feeding the filter a constant for a while
+    fn dc_gain(filter: &mut Biquad) -> Sample {
+        let mut out = 0.0;
+        for _ in 0..20000 {
+            out = filter.process(1.0);
+        }
+        out
+    }
+
+    #[test]
+    fn biquad_dc_gains() {
+        let mut filter = Biquad::new();
+        filter.set(BiquadKind::LowShelf, 500.0, 0.707, 6.0, 48000.0);
+        assert!((dc_gain(&mut filter) - db_to_amplitude(6.0)).abs() < 1e-3);
+        let mut filter = Biquad::new();
+        filter.set(BiquadKind::HighShelf, 500.0, 0.707, 6.0, 48000.0);
+        assert!((dc_gain(&mut filter) - 1.0).abs() < 1e-3);
+        let mut filter = Biquad::new();
+        filter.set(BiquadKind::HighPass, 500.0, 0.707, 0.0, 48000.0);
+        assert!(dc_gain(&mut filter).abs() < 1e-3);
+        let mut filter = Biquad::new();
+        filter.set(BiquadKind::LowPass, 500.0, 0.707, 0.0, 48000.0);
+        assert!((dc_gain(&mut filter) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn dc_blocker_removes_offset() {
+        let mut blocker = DcBlocker::new();
+        blocker.init(48000.0);
+        let mut out = 0.0;
+        for i in 0..48000 {
+            out = blocker.process_sample(0.5 + if i % 2 == 0 { 0.25 } else { -0.25 });
+        }
+        assert!((out.abs() - 0.25).abs() < 1e-2, "{out}");
+    }
+
+    #[test]
+    fn ladder_filter_passes_dc_and_self_oscillates() {
+        let mut ladder = LadderFilter::new();
+        ladder.init(48000.0);
+        let mut out = 0.0;
+        for _ in 0..4800 {
+            out = ladder.process_sample(0.1, 1000.0, 0.0, 1.0);
+        }
+        assert!((out - (0.1 as Sample).tanh()).abs() < 1e-4, "{out}");
+        ladder.init(48000.0);
+        ladder.process_sample(1.0, 1000.0, 1.1, 1.0);
+        let mut peak: Sample = 0.0;
+        for i in 0..48000 {
+            let out = ladder.process_sample(0.0, 1000.0, 1.1, 1.0);
+            if i > 24000 {
+                peak = peak.max(out.abs());
+            }
+        }
+        assert!(peak > 0.1 && peak < 2.0, "{peak}");
+    }
+
+    #[test]
+    fn crossover_bands_sum_to_flat_magnitude() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut crossover = Crossover::new(&[2000.0, 200.0]);
+        crossover.init(48000.0);
+        assert_eq!(crossover.num_outputs(), 3);
+        assert_eq!(crossover.default_input(1), 200.0);
+        let block = 48000;
+        let mut inputs = vec![vec![0.0; block].into_boxed_slice()];
+        for input in 1..crossover.num_inputs() {
+            inputs.push(vec![crossover.default_input(input); block].into_boxed_slice());
+        }
+        // Sines in the middle of every band and at the crossover frequencies
+        let freqs = [
+            (50.0, Some(0)),
+            (200.0, None),
+            (600.0, Some(1)),
+            (2000.0, None),
+            (8000.0, Some(2)),
+        ];
+        for (freq, band) in freqs {
+            crossover.init(48000.0);
+            for (i, sample) in inputs[0].iter_mut().enumerate() {
+                *sample = (std::f64::consts::TAU as Sample * freq * i as Sample / 48000.0).sin();
+            }
+            let mut outputs = vec![vec![0.0; block].into_boxed_slice(); 3];
+            crossover.process(&inputs, &mut outputs, &mut resources);
+            let rms = |output: &[Sample]| {
+                let tail = &output[block / 2..];
+                (tail.iter().map(|s| s * s).sum::<Sample>() / tail.len() as Sample).sqrt()
+            };
+            let input_rms = rms(&inputs[0]);
+            let sum: Vec<Sample> = (0..block)
+                .map(|i| outputs.iter().map(|output| output[i]).sum())
+                .collect();
+            assert!(
+                (rms(&sum) - input_rms).abs() < 1e-3,
+                "{freq}: {}",
+                rms(&sum)
+            );
+            if let Some(band) = band {
+                assert!(rms(&outputs[band]) > input_rms * 0.9, "{freq}");
+            }
+        }
+    }
+
+    #[test]
+    fn eq_bands_follow_their_inputs() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut eq = Eq::three_band().smoothing_time(0.001);
+        eq.init(48000.0);
+        assert_eq!(eq.num_inputs(), 10);
+        assert_eq!(eq.input_desc(4), "freq1");
+        assert_eq!(eq.default_input(7), 4000.0);
+        let block = 4800;
+        let mut inputs = vec![vec![1.0; block].into_boxed_slice()];
+        for input in 1..eq.num_inputs() {
+            inputs.push(vec![eq.default_input(input); block].into_boxed_slice());
+        }
+        // Cut the low shelf
+        inputs[2].fill(-12.0);
+        let mut outputs = vec![vec![0.0; block].into_boxed_slice()];
+        eq.process(&inputs, &mut outputs, &mut resources);
+        let out = outputs[0][block - 1];
+        assert!((out - db_to_amplitude(-12.0)).abs() < 1e-2, "{out}");
+    }
+}
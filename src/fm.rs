@@ -0,0 +1,350 @@
+//! Phase modulation synthesis in the style of classic FM synthesizers.
+//!
+//! [`PmOperator`] is a single sine operator with a phase modulation input.
+//! Operators can be connected to each other in a [`Graph`] to build any
+//! algorithm. [`FourOpFm`] runs four operators in one of the common
+//! [`FmAlgorithm`]s inside a single Gen, which is cheaper and doesn't add the
+//! block delay of feedback connections.
+
+#[allow(unused)]
+use crate::graph::Graph;
+use crate::graph::{Gen, GenState};
+use crate::wavetable::{shared_sine, Phase, Wavetable, FRACTIONAL_PART, TABLE_SIZE};
+use crate::{sample_to_f64, Resources, Sample};
+
+/// The length of one cycle in [`Phase`] units
+const PHASE_CYCLE: f64 = TABLE_SIZE as f64 * FRACTIONAL_PART as f64;
+const TAU: Sample = std::f64::consts::TAU as Sample;
+
+/// The shared state of a sine operator
+#[derive(Debug, Clone, Copy)]
+struct Operator {
+    phase: Phase,
+    last_output: Sample,
+}
+
+impl Operator {
+    fn new() -> Self {
+        Self {
+            phase: Phase(0),
+            last_output: 0.0,
+        }
+    }
+    /// `phase_mod` is in radians
+    #[inline]
+    fn next(&mut self, sine: &Wavetable, step: u32, phase_mod: Sample) -> Sample {
+        let offset = (sample_to_f64(phase_mod / TAU) * PHASE_CYCLE) as i64 as u32;
+        let mut phase = self.phase;
+        phase.increase(offset);
+        let value = sine.get_linear_interp(phase);
+        self.phase.increase(step);
+        self.last_output = value;
+        value
+    }
+}
+
+/// A sine operator with phase modulation.
+///
+/// The frequency of the operator is "freq" * "ratio". "phase_mod" is
+/// multiplied by "index" and added to the phase in radians, so connecting the
+/// output of another operator gives classic FM with "index" as the modulation
+/// index. Remember to set the ratio, an unconnected input is 0.
+#[derive(Debug, Clone)]
+pub struct PmOperator {
+    operator: Operator,
+    sine: &'static Wavetable,
+}
+
+impl PmOperator {
+    pub fn new() -> Self {
+        Self {
+            operator: Operator::new(),
+            sine: shared_sine(),
+        }
+    }
+    pub fn reset_phase(&mut self) {
+        self.operator.phase.0 = 0;
+    }
+}
+
+impl Default for PmOperator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for PmOperator {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let freq_to_phase_inc = resources.freq_to_phase_inc;
+        for ((((&freq, &ratio), &index), &phase_mod), out) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(inputs[2].iter())
+            .zip(inputs[3].iter())
+            .zip(outputs[0].iter_mut())
+        {
+            let step = (sample_to_f64(freq * ratio) * freq_to_phase_inc) as u32;
+            *out = self.operator.next(self.sine, step, phase_mod * index);
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        4
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "freq",
+            1 => "ratio",
+            2 => "index",
+            3 => "phase_mod",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "PmOperator"
+    }
+}
+
+/// How the four operators of a [`FourOpFm`] are connected. Operators are
+/// numbered 1 to 4 and modulators always have a higher number than the
+/// operator they modulate. Operator 4 can modulate itself through the
+/// "feedback" input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FmAlgorithm {
+    /// 4 -> 3 -> 2 -> 1
+    Stack,
+    /// 2 -> 1 and 4 -> 3, with 1 and 3 as carriers
+    TwoStacks,
+    /// 4 -> 3 -> 1 and 2 -> 1
+    Branch,
+    /// 2, 3 and 4 all modulate 1
+    ThreeToOne,
+    /// 4 modulates 1, 2 and 3 which are all carriers
+    OneToThree,
+    /// 4 -> 3 with 1, 2 and 3 as carriers
+    StackAndTwoCarriers,
+    /// All operators are carriers, i.e. additive synthesis
+    Parallel,
+}
+
+impl FmAlgorithm {
+    /// `modulators()[i][j]` is true if operator `j` modulates operator `i`
+    /// (zero indexed)
+    fn modulators(&self) -> [[bool; 4]; 4] {
+        let mut m = [[false; 4]; 4];
+        match self {
+            FmAlgorithm::Stack => {
+                m[0][1] = true;
+                m[1][2] = true;
+                m[2][3] = true;
+            }
+            FmAlgorithm::TwoStacks => {
+                m[0][1] = true;
+                m[2][3] = true;
+            }
+            FmAlgorithm::Branch => {
+                m[0][1] = true;
+                m[0][2] = true;
+                m[2][3] = true;
+            }
+            FmAlgorithm::ThreeToOne => {
+                m[0][1] = true;
+                m[0][2] = true;
+                m[0][3] = true;
+            }
+            FmAlgorithm::OneToThree => {
+                m[0][3] = true;
+                m[1][3] = true;
+                m[2][3] = true;
+            }
+            FmAlgorithm::StackAndTwoCarriers => {
+                m[2][3] = true;
+            }
+            FmAlgorithm::Parallel => (),
+        }
+        m
+    }
+    /// Operators that don't modulate any other operator are carriers
+    fn carriers(&self) -> [bool; 4] {
+        let m = self.modulators();
+        let mut carriers = [true; 4];
+        for row in m.iter() {
+            for (j, &modulates) in row.iter().enumerate() {
+                if modulates {
+                    carriers[j] = false;
+                }
+            }
+        }
+        carriers
+    }
+}
+
+/// Four [`PmOperator`]s connected according to an [`FmAlgorithm`].
+///
+/// The inputs are "freq", the ratio of every operator, the level of every
+/// operator and "feedback" for operator 4. For modulators the level is the
+/// modulation index, for carriers it is the amplitude. The output is the sum
+/// of all carriers.
+#[derive(Debug, Clone)]
+pub struct FourOpFm {
+    operators: [Operator; 4],
+    modulators: [[bool; 4]; 4],
+    carriers: [bool; 4],
+    sine: &'static Wavetable,
+}
+
+impl FourOpFm {
+    pub fn new(algorithm: FmAlgorithm) -> Self {
+        Self {
+            operators: [Operator::new(); 4],
+            modulators: algorithm.modulators(),
+            carriers: algorithm.carriers(),
+            sine: shared_sine(),
+        }
+    }
+    pub fn reset_phase(&mut self) {
+        for op in &mut self.operators {
+            op.phase.0 = 0;
+        }
+    }
+}
+
+impl Gen for FourOpFm {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let freq_to_phase_inc = resources.freq_to_phase_inc;
+        let ratios = &inputs[1..5];
+        let levels = &inputs[5..9];
+        let feedback = &inputs[9];
+        for (i, (&freq, out)) in inputs[0].iter().zip(outputs[0].iter_mut()).enumerate() {
+            let mut values = [0.0; 4];
+            // Modulators always have a higher number so run them first
+            for op in (0..4).rev() {
+                let mut phase_mod = 0.0;
+                for modulator in (op + 1)..4 {
+                    if self.modulators[op][modulator] {
+                        phase_mod += values[modulator] * levels[modulator][i];
+                    }
+                }
+                if op == 3 {
+                    phase_mod += self.operators[3].last_output * feedback[i];
+                }
+                let step = (sample_to_f64(freq * ratios[op][i]) * freq_to_phase_inc) as u32;
+                values[op] = self.operators[op].next(self.sine, step, phase_mod);
+            }
+            let mut sum = 0.0;
+            for op in 0..4 {
+                if self.carriers[op] {
+                    sum += values[op] * levels[op][i];
+                }
+            }
+            *out = sum;
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        10
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "freq",
+            1 => "ratio1",
+            2 => "ratio2",
+            3 => "ratio3",
+            4 => "ratio4",
+            5 => "level1",
+            6 => "level2",
+            7 => "level3",
+            8 => "level4",
+            9 => "feedback",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "FourOpFm"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn inputs(values: &[Sample], block_size: usize) -> Vec<Box<[Sample]>> {
+        values
+            .iter()
+            .map(|&v| vec![v; block_size].into_boxed_slice())
+            .collect()
+    }
+
+    #[test]
+    fn algorithms_without_modulation_are_sines() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut op = PmOperator::new();
+        let mut fm = FourOpFm::new(FmAlgorithm::Stack);
+        let mut op_out = vec![vec![0.0; 64].into_boxed_slice()];
+        let mut fm_out = vec![vec![0.0; 64].into_boxed_slice()];
+        op.process(
+            &inputs(&[440.0, 1.0, 0.0, 0.0], 64),
+            &mut op_out,
+            &mut resources,
+        );
+        // Only operator 1 is a carrier and all modulation indices are 0
+        fm.process(
+            &inputs(&[440.0, 1.0, 2.0, 3.0, 4.0, 1.0, 0.0, 0.0, 0.0, 0.0], 64),
+            &mut fm_out,
+            &mut resources,
+        );
+        assert_eq!(op_out, fm_out);
+        assert!(op_out[0].iter().any(|&s| s > 0.5));
+        // Modulation changes the output
+        fm.reset_phase();
+        fm.process(
+            &inputs(&[440.0, 1.0, 2.0, 3.0, 4.0, 1.0, 1.0, 0.0, 0.0, 0.0], 64),
+            &mut fm_out,
+            &mut resources,
+        );
+        assert_ne!(op_out, fm_out);
+    }
+
+    #[test]
+    fn carriers() {
+        assert_eq!(FmAlgorithm::Stack.carriers(), [true, false, false, false]);
+        assert_eq!(
+            FmAlgorithm::TwoStacks.carriers(),
+            [true, false, true, false]
+        );
+        assert_eq!(
+            FmAlgorithm::OneToThree.carriers(),
+            [true, true, true, false]
+        );
+        assert_eq!(FmAlgorithm::Parallel.carriers(), [true; 4]);
+    }
+}
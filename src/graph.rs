@@ -21,18 +21,49 @@
 //! graph.commit_changes();
 //! # Ok::<(), Box<dyn std::error::Error>>(())
 //! ```
+//!
+//! A Gen can have any number of outputs. Use [`NodeAddress::out`] or
+//! [`NodeAddress::out_label`] to connect from an output other than the first.
+//! An output can be connected to any number of inputs without copying it
+//! through extra nodes, and all connections to the same input are summed.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # let mut graph = Graph::new(GraphSettings { num_outputs: 2, ..Default::default() });
+//! let pan = graph.push_gen(PanMonoToStereo);
+//! let mult = graph.push_gen(Mult);
+//! graph.connect(pan.out_label("left").to_graph_out())?;
+//! // The right channel goes both to the graph output and to both inputs of `mult`
+//! graph.connect(pan.out(1).to_graph_out().to_index(1))?;
+//! graph.connect(pan.out_label("right").to(mult))?;
+//! graph.connect(pan.out_label("right").to(mult).to_index(1))?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
 //! To produce an output from the [`Graph`] we need to turn it into a node. If
 //! you want to listen to the graph output the easiest way is to use and audio
 //! backend. Have a look at [`Graph::to_node`] if you want to do non-realtime
 //! synthesis or implement your own backend.
 
 use std::cell::UnsafeCell;
-use std::collections::{HashMap, HashSet};
-use std::sync::atomic::{AtomicU16, AtomicU64};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU32, AtomicU64, AtomicUsize};
 use std::sync::{atomic::Ordering, Arc};
 use std::time::{Duration, Instant};
 
-use super::Resources;
+use crate::scheduling::{
+    Beats, MusicalTimeMap, MusicalTimeMapError, TempoChange, TransportCommand,
+    TransportCommandKind, TransportState, TransportTimeline,
+};
+
+use super::{sample_to_f32, sample_to_f64, Resources};
+use crate::buffer::BufferKey;
+use crate::patch::{
+    Patch, PatchEdge, PatchError, PatchNode, PatchSink, PatchSource, ResourceRef, SavedState,
+};
+#[cfg(feature = "derive")]
+pub use knyst_macro::Gen;
 /// The graph consists of (simplified)
 /// 1. a list of nodes
 /// 2. lists of edges that are inputs per node, outputs of the graph and inputs from the graph input to a node
@@ -45,7 +76,7 @@ use super::Resources;
 /// Each node contains a trait object on the heap with the sound generating object, a Box<dyn Gen> Each
 /// edge/connection specifies between which output/input of the nodes data is mapped.
 
-pub type Sample = f32;
+pub type Sample = crate::Sample;
 pub type GraphId = u64;
 
 /// Get a unique id for a Graph from this by using `fetch_add`
@@ -69,6 +100,7 @@ impl NodeAddress {
             to_index: None,
             to_label: None,
             channels: 1,
+            gain: 1.0,
             feedback: false,
         }
     }
@@ -79,6 +111,7 @@ impl NodeAddress {
             from_label: None,
             to_index: 0,
             channels: 1,
+            gain: 1.0,
         }
     }
     pub fn feedback_to(&self, sink_node: NodeAddress) -> Connection {
@@ -90,9 +123,55 @@ impl NodeAddress {
             to_index: None,
             to_label: None,
             channels: 1,
+            gain: 1.0,
             feedback: true,
         }
     }
+    /// Connect from output `index` of this node
+    pub fn out(&self, index: usize) -> NodeOutput {
+        NodeOutput {
+            node: *self,
+            output: Output::Index(index),
+        }
+    }
+    /// Connect from the output of this node named `label`
+    pub fn out_label(&self, label: &'static str) -> NodeOutput {
+        NodeOutput {
+            node: *self,
+            output: Output::Label(label),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Output {
+    Index(usize),
+    Label(&'static str),
+}
+
+/// A specific output of a node, see [`NodeAddress::out`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NodeOutput {
+    node: NodeAddress,
+    output: Output,
+}
+
+impl NodeOutput {
+    fn set_source_output(&self, connection: Connection) -> Connection {
+        match self.output {
+            Output::Index(index) => connection.from_index(index),
+            Output::Label(label) => connection.from_label(label),
+        }
+    }
+    pub fn to(&self, sink_node: NodeAddress) -> Connection {
+        self.set_source_output(self.node.to(sink_node))
+    }
+    pub fn to_graph_out(&self) -> Connection {
+        self.set_source_output(self.node.to_graph_out())
+    }
+    pub fn feedback_to(&self, sink_node: NodeAddress) -> Connection {
+        self.set_source_output(self.node.feedback_to(sink_node))
+    }
 }
 
 pub struct GraphInput;
@@ -104,6 +183,7 @@ impl GraphInput {
             to_index: None,
             to_label: None,
             channels: 1,
+            gain: 1.0,
         }
     }
 }
@@ -142,6 +222,17 @@ impl ParameterChange {
             input_label: None,
         }
     }
+    /// Schedule a change at a point in musical time. The change will be
+    /// held until the transport of the top level Graph is playing.
+    pub fn beats(node: NodeAddress, value: Sample, beats: Beats) -> Self {
+        Self {
+            node,
+            value,
+            time: TimeKind::Beats(beats),
+            input_index: None,
+            input_label: None,
+        }
+    }
     pub fn now(node: NodeAddress, value: Sample) -> Self {
         Self {
             node,
@@ -173,6 +264,8 @@ impl ParameterChange {
 pub enum TimeKind {
     DurationFromNow(Duration),
     AbsoluteSample(u64),
+    /// A position in musical time according to the transport of the top level Graph
+    Beats(Beats),
 }
 
 /// Connection provides a convenient API for creating connections between nodes in a
@@ -195,6 +288,8 @@ pub enum Connection {
         to_label: Option<&'static str>,
         /// default: 1
         channels: usize,
+        /// default: 1.0
+        gain: Sample,
         feedback: bool,
     },
     /// constant to node
@@ -203,6 +298,9 @@ pub enum Connection {
         sink: Option<NodeAddress>,
         to_index: Option<usize>,
         to_label: Option<&'static str>,
+        /// If set, changes to the constant of this input are smoothed from
+        /// now on. If None, the smoothing of the input is left as it is.
+        smoothing: Option<Smoothing>,
     },
     /// node to graph output
     GraphOutput {
@@ -211,6 +309,7 @@ pub enum Connection {
         from_label: Option<&'static str>,
         to_index: usize,
         channels: usize,
+        gain: Sample,
     },
     /// graph input to node
     GraphInput {
@@ -220,6 +319,7 @@ pub enum Connection {
         to_index: Option<usize>,
         to_label: Option<&'static str>,
         channels: usize,
+        gain: Sample,
     },
     Clear {
         node: NodeAddress,
@@ -242,6 +342,129 @@ pub fn constant(value: Sample) -> Connection {
         sink: None,
         to_index: None,
         to_label: None,
+        smoothing: None,
+    }
+}
+
+/// How changes to the constant value of a node input are smoothed to avoid
+/// clicks. Set using [`Connection::smoothing`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Smoothing {
+    /// Jump directly to the new value
+    None,
+    /// Ramp linearly to the new value over the given time
+    Linear(Duration),
+    /// Approach the new value exponentially, reaching ~63% of the way in the
+    /// given time
+    Exponential(Duration),
+}
+
+/// How the connections to a node input are combined when there is more than
+/// one. Set using [`Graph::set_input_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InputMode {
+    /// Sum the constant value of the input and all connections
+    #[default]
+    Add,
+    /// Use the last connection. Feedback connections come after normal
+    /// connections and graph inputs come last.
+    Replace,
+    /// Use the largest of the connections
+    Max,
+    /// Multiply the connections, e.g. an envelope with an oscillator for VCA
+    /// style gain control
+    Multiply,
+}
+
+/// How a single connection is written to an input buffer, depending on the
+/// [`InputMode`] of the input and the connections before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CombineOp {
+    Add,
+    Set,
+    Max,
+    Mul,
+}
+
+impl CombineOp {
+    /// The op for the next connection to an input, `connected` is true if
+    /// a connection to that input has already been added
+    fn next(mode: InputMode, connected: &mut bool) -> Self {
+        let first = !std::mem::replace(connected, true);
+        match mode {
+            InputMode::Add => CombineOp::Add,
+            InputMode::Replace => CombineOp::Set,
+            _ if first => CombineOp::Set,
+            InputMode::Max => CombineOp::Max,
+            InputMode::Multiply => CombineOp::Mul,
+        }
+    }
+    #[inline]
+    fn apply(self, to: &mut Sample, value: Sample) {
+        match self {
+            CombineOp::Add => *to += value,
+            CombineOp::Set => *to = value,
+            CombineOp::Max => *to = to.max(value),
+            CombineOp::Mul => *to *= value,
+        }
+    }
+}
+
+/// The smoothing state of one node input, running on the audio thread.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct InputSmoother {
+    /// The length of a linear ramp in samples
+    ramp_samples: Sample,
+    /// The one pole coefficient for exponential smoothing, 0.0 for linear
+    coefficient: Sample,
+    linear: bool,
+    value: Sample,
+    target: Sample,
+    step: Sample,
+}
+
+impl InputSmoother {
+    /// Returns None if the Smoothing doesn't smooth at all
+    pub(crate) fn new(smoothing: Smoothing, sample_rate: Sample) -> Option<Self> {
+        let (time, linear) = match smoothing {
+            Smoothing::None => return None,
+            Smoothing::Linear(time) => (time, true),
+            Smoothing::Exponential(time) => (time, false),
+        };
+        let time = time.as_secs_f64() as Sample;
+        Some(Self {
+            ramp_samples: (time * sample_rate).max(1.0),
+            coefficient: crate::dynamics::time_coefficient(time, sample_rate),
+            linear,
+            value: 0.0,
+            target: 0.0,
+            step: 0.0,
+        })
+    }
+    /// Start from `value` without smoothing towards anything
+    pub(crate) fn reset(&mut self, value: Sample) {
+        self.value = value;
+        self.target = value;
+        self.step = 0.0;
+    }
+    #[inline]
+    pub(crate) fn next(&mut self, target: Sample) -> Sample {
+        if target != self.target {
+            self.target = target;
+            self.step = (target - self.value) / self.ramp_samples;
+        }
+        if self.linear {
+            if (self.target - self.value).abs() <= self.step.abs() {
+                self.value = self.target;
+            } else {
+                self.value += self.step;
+            }
+        } else {
+            self.value = self.target + self.coefficient * (self.value - self.target);
+        }
+        self.value
     }
 }
 impl Connection {
@@ -252,6 +475,7 @@ impl Connection {
             from_label: None,
             to_index: 0,
             channels: 1,
+            gain: 1.0,
         }
     }
     pub fn graph_input(sink_node: NodeAddress) -> Self {
@@ -261,6 +485,7 @@ impl Connection {
             to_index: None,
             to_label: None,
             channels: 1,
+            gain: 1.0,
         }
     }
     pub fn clear_constants(node: NodeAddress) -> Self {
@@ -494,6 +719,42 @@ impl Connection {
         }
         self
     }
+    /// Smooth changes to the constant value of the input. Only valid for
+    /// Connection::Constant, on other variants it does nothing. The smoothing
+    /// stays active for the input until it is changed by another connection.
+    pub fn smoothing(mut self, new_smoothing: Smoothing) -> Self {
+        if let Connection::Constant { smoothing, .. } = &mut self {
+            *smoothing = Some(new_smoothing);
+        }
+        self
+    }
+    /// Multiply the signal of the connection by `gain` before it is added to
+    /// the input or graph output. Does nothing on a Connection::Constant or
+    /// Connection::Clear.
+    pub fn gain(mut self, new_gain: Sample) -> Self {
+        match &mut self {
+            Connection::Node { gain, .. }
+            | Connection::GraphOutput { gain, .. }
+            | Connection::GraphInput { gain, .. } => {
+                *gain = new_gain;
+            }
+            Connection::Constant { .. } | Connection::Clear { .. } => {}
+        }
+        self
+    }
+    /// Invert the phase of the signal of the connection
+    pub fn invert(self) -> Self {
+        let gain = self.get_gain();
+        self.gain(-gain)
+    }
+    pub fn get_gain(&self) -> Sample {
+        match self {
+            Connection::Node { gain, .. }
+            | Connection::GraphOutput { gain, .. }
+            | Connection::GraphInput { gain, .. } => *gain,
+            Connection::Constant { .. } | Connection::Clear { .. } => 1.0,
+        }
+    }
     pub fn feedback(mut self, activate: bool) -> Self {
         match &mut self {
             Connection::Node { feedback, .. } => {
@@ -517,6 +778,56 @@ impl Connection {
     }
 }
 
+/// Routes output channels of a node to the monitor outputs of the Graph
+/// containing it, see [`Graph::add_monitor_tap`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MonitorTap {
+    node: NodeAddress,
+    from_index: usize,
+    to_index: usize,
+    channels: usize,
+    gain: Sample,
+}
+
+impl MonitorTap {
+    /// Tap the first output of `node` into the first monitor output
+    pub fn new(node: NodeAddress) -> Self {
+        Self {
+            node,
+            from_index: 0,
+            to_index: 0,
+            channels: 1,
+            gain: 1.0,
+        }
+    }
+    /// The first output of the node to tap
+    pub fn from_index(mut self, index: usize) -> Self {
+        self.from_index = index;
+        self
+    }
+    /// The first monitor output to send to, counting from the first monitor
+    /// output of the Graph
+    pub fn to_index(mut self, index: usize) -> Self {
+        self.to_index = index;
+        self
+    }
+    pub fn channels(mut self, num_channels: usize) -> Self {
+        self.channels = num_channels;
+        self
+    }
+    pub fn gain(mut self, gain: Sample) -> Self {
+        self.gain = gain;
+        self
+    }
+    fn connection(&self, first_monitor_output: usize) -> Connection {
+        Connection::graph_output(self.node)
+            .from_index(self.from_index)
+            .to_index(first_monitor_output + self.to_index)
+            .channels(self.channels)
+            .gain(self.gain)
+    }
+}
+
 /// One task to complete, for the node graph Safety: Uses raw pointers to nodes
 /// and buffers. A node and its buffers may not be touched from the Graph while
 /// a Task containing pointers to it is running. This is guaranteed by an atomic
@@ -528,28 +839,46 @@ struct Task {
     /// The node key may be used to send a message to the Graph to free the node in this Task
     node_key: NodeKey,
     node_ptr: *mut Node,
-    /// inputs to copy from the graph inputs (whole buffers) in the form `(node_input_buffer_ptr, graph_input_index)`
-    graph_inputs_to_copy: Vec<(*mut Box<[Sample]>, usize)>,
-    /// list of tuples of single floats in the form `(from, to)` where the `from` points to an output of a different node and the `to` points to the input buffer.
-    inputs_to_copy: Vec<(*const Sample, *mut Sample)>,
+    /// inputs to copy from the graph inputs (whole buffers) in the form `(node_input_buffer_ptr, graph_input_index, gain, op)`
+    graph_inputs_to_copy: Vec<(*mut Box<[Sample]>, usize, Sample, CombineOp)>,
+    /// list of tuples of single floats in the form `(from, to, gain, op)` where the `from` points to an output of a different node and the `to` points to the input buffer.
+    inputs_to_copy: Vec<(*const Sample, *mut Sample, Sample, CombineOp)>,
+    /// Whether anything is connected to each input. Default input constants
+    /// are only used for unconnected inputs.
+    connected_inputs: Vec<bool>,
     input_buffers_ptr: *mut Box<[Sample]>,
     num_inputs: usize,
+    /// The state returned from the node the last time it was run
+    state: GenState,
 }
 impl Task {
     fn init_constants(&mut self) {
         let node = unsafe { &mut *self.node_ptr };
         // Copy all constants
-        let node_constants = &node.input_constants;
         let inputs_buffers: &mut [Box<[Sample]>] =
             unsafe { std::slice::from_raw_parts_mut(self.input_buffers_ptr, self.num_inputs) };
-        for (input, &constant) in inputs_buffers.iter_mut().zip(node_constants.iter()) {
-            input.fill(constant);
+        for (index, (input, &connected)) in inputs_buffers
+            .iter_mut()
+            .zip(self.connected_inputs.iter())
+            .enumerate()
+        {
+            input.fill(node.constant(index, connected));
         }
     }
     fn apply_constant_change(&mut self, change: &ScheduledChange, start_sample_in_block: usize) {
         let node = unsafe { &mut *self.node_ptr };
         match change.kind {
-            ScheduledChangeKind::Constant { index, value } => {
+            ScheduledChangeKind::Constant {
+                index,
+                value,
+                smoother,
+            } => {
+                if let Some(smoother) = smoother {
+                    // Smooth from the value the input actually had
+                    let current = node.constant(index, self.connected_inputs[index]);
+                    node.set_constant(current, index);
+                    node.set_input_smoother(index, smoother);
+                }
                 node.set_constant(value, index);
                 let inputs_buffers: &mut [Box<[Sample]>] = unsafe {
                     std::slice::from_raw_parts_mut(self.input_buffers_ptr, self.num_inputs)
@@ -558,26 +887,35 @@ impl Task {
                     *constant = value;
                 }
             }
+            ScheduledChangeKind::Bypass(bypassed) => node.set_bypassed(bypassed, false),
+            ScheduledChangeKind::Mute(muted) => node.set_muted(muted, false),
         }
     }
+    /// See [`Gen::uses_shared_resources`]
+    fn uses_shared_resources(&self) -> bool {
+        let node = unsafe { &*self.node_ptr };
+        node.uses_shared_resources()
+    }
     fn run(&mut self, graph_inputs: &[Box<[Sample]>], resources: &mut Resources) -> GenState {
         let node = unsafe { &mut *self.node_ptr };
         let inputs_buffers: &mut [Box<[Sample]>] =
             unsafe { std::slice::from_raw_parts_mut(self.input_buffers_ptr, self.num_inputs) };
+        // Smooth the constants before any other inputs are added
+        node.smooth_input_constants(inputs_buffers);
         // Copy all inputs
-        for (from, to) in &self.inputs_to_copy {
+        for &(from, to, gain, op) in &self.inputs_to_copy {
             unsafe {
-                **to += **from;
+                op.apply(&mut *to, *from * gain);
             }
         }
         // Copy all graph inputs
-        for (node_input_buffer_ptr, graph_input_index) in &self.graph_inputs_to_copy {
+        for &(node_input_buffer_ptr, graph_input_index, gain, op) in &self.graph_inputs_to_copy {
             unsafe {
-                for (to_sample, from_sample) in (**node_input_buffer_ptr)
+                for (to_sample, from_sample) in (*node_input_buffer_ptr)
                     .iter_mut()
-                    .zip(graph_inputs[*graph_input_index].iter())
+                    .zip(graph_inputs[graph_input_index].iter())
                 {
-                    *to_sample += *from_sample;
+                    op.apply(to_sample, *from_sample * gain);
                 }
             }
         }
@@ -593,6 +931,7 @@ unsafe impl Send for Task {}
 struct OutputTask {
     input_buffer_ptr: *const Box<[Sample]>,
     graph_output_index: usize,
+    gain: Sample,
 }
 unsafe impl Send for OutputTask {}
 
@@ -616,6 +955,12 @@ pub enum ConnectionError {
     ChannelOutOfBounds,
     #[error("The connection change required freeing a node, but the node could not be freed.")]
     NodeFree(#[from] FreeError),
+    #[error("No node is named `{0}`.")]
+    NodeNameNotFound(String),
+    #[error("The name `{0}` is already used by another node in the same Graph.")]
+    NodeNameTaken(String),
+    #[error("The node has no input or output named `{0}`.")]
+    PortNameNotFound(String),
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
@@ -628,6 +973,84 @@ pub enum FreeError {
     ConnectionError(#[from] Box<ConnectionError>),
 }
 #[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ReplaceGenError {
+    #[error("The graph containing the NodeAdress provided was not found. The node itself may or may not exist.")]
+    GraphNotFound,
+    #[error("The NodeAddress does not exist. The Node may have been freed already.")]
+    NodeNotFound,
+    #[error("The new Gen has {gen_inputs} inputs and {gen_outputs} outputs, but the node has {node_inputs} inputs and {node_outputs} outputs.")]
+    ChannelMismatch {
+        node_inputs: usize,
+        node_outputs: usize,
+        gen_inputs: usize,
+        gen_outputs: usize,
+    },
+    #[error(
+        "The new Gen could not be sent to the GraphGen. Please increase the ring buffer size."
+    )]
+    QueueFull,
+    #[error("The node is not running a Graph added using `push_graph`.")]
+    NotAGraph,
+    #[error(
+        "The block size of the new Graph doesn't evenly divide the block size of the parent Graph."
+    )]
+    InvalidBlockSize,
+    #[error("A Node has already been created from the new Graph.")]
+    GraphAlreadyRunning,
+}
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum GroupError {
+    #[error("The graph containing the group or node was not found.")]
+    GraphNotFound,
+    #[error("The group does not exist. It may have been freed already.")]
+    GroupNotFound,
+    #[error("The NodeAddress does not exist. The Node may have been freed already.")]
+    NodeNotFound,
+    #[error("The node and the group are in different graphs. Nodes can only be added to groups in the same graph.")]
+    DifferentGraphs,
+    #[error(transparent)]
+    Free(#[from] FreeError),
+    #[error(transparent)]
+    Schedule(#[from] ScheduleError),
+}
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ToNodeError {
+    #[error("A Node has already been created from this Graph. Only one can exist per Graph.")]
+    GraphAlreadyRunning,
+}
+/// A problem found by [`Graph::validate`]
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    #[error("Input {input} (`{label}`) of {node:?} is required, but nothing is connected to it.")]
+    UnconnectedInput {
+        node: NodeAddress,
+        input: usize,
+        label: &'static str,
+    },
+    #[error("Only {connected} of the {outputs} outputs of {from:?} are connected to {sink:?}, which could take {expected}.")]
+    ChannelMismatch {
+        from: NodeAddress,
+        sink: NodeAddress,
+        outputs: usize,
+        connected: usize,
+        expected: usize,
+    },
+    #[error("Only {connected} of the {outputs} outputs of {from:?} are connected to the Graph outputs, which could take {expected}.")]
+    OutputChannelMismatch {
+        from: NodeAddress,
+        outputs: usize,
+        connected: usize,
+        expected: usize,
+    },
+    #[error("The nodes {nodes:?} are connected in a cycle without a feedback connection.")]
+    Cycle { nodes: Vec<NodeAddress> },
+    #[error("{node:?} uses {resource:?}, which is not in the Resources.")]
+    MissingResource {
+        node: NodeAddress,
+        resource: ResourceRef,
+    },
+}
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
 pub enum ScheduleError {
     #[error("The graph containing the NodeAdress provided was not found. The node itself may or may not exist.")]
     GraphNotFound,
@@ -637,8 +1060,50 @@ pub enum ScheduleError {
     InputLabelNotFound(&'static str),
     #[error("No scheduler was created for the Graph so the change cannot be scheduled. This is likely because this Graph was not yet added to another Graph or split into a Node.")]
     SchedulerNotCreated,
+    #[error("Only the top level Graph, the one turned into a Node using `to_node`, has a transport and can schedule changes in musical time.")]
+    TransportNotAvailable,
+    #[error(transparent)]
+    MusicalTimeMap(#[from] MusicalTimeMapError),
+    #[error("The transport command could not be sent to the GraphGen. Please increase the ring buffer size.")]
+    TransportCommandQueueFull,
+    #[error(
+        "The transport is not playing, so the sample of a position in musical time is not known."
+    )]
+    TransportNotPlaying,
+}
+
+/// The rate at which an input or output of a [`Gen`] changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rate {
+    /// A new value every sample
+    #[default]
+    Audio,
+    /// One value per block. A control rate output only needs to write the
+    /// first sample of the block and the Graph fills in the rest by holding
+    /// the value. A control rate input only reads the first sample of the
+    /// block, so only that sample is copied from the nodes connected to it.
+    Control,
+    /// Like [`Rate::Control`], but the output is ramped linearly from the
+    /// value of the previous block to the new value over the block to avoid
+    /// steps. The same as [`Rate::Control`] for an input.
+    SmoothControl,
+}
+
+/// What an input of a [`Gen`] is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputKind {
+    /// Part of the signal being processed, or a parameter
+    #[default]
+    Main,
+    /// An auxiliary signal controlling the processing of the main signal,
+    /// e.g. the key of a compressor or the modulator of a vocoder. Sidechain
+    /// inputs are skipped when chaining nodes in the [`dsl`](crate::dsl), so
+    /// that a signal is never summed into them by accident.
+    Sidechain,
 }
 
+/// With the `derive` feature, simple per sample Gens can be implemented with
+/// `#[derive(Gen)]`, see the documentation of the `knyst_macro` crate.
 pub trait Gen {
     /// The input and output buffers are both indexed using \[in/out_index\]\[sample_index\].
     ///
@@ -659,18 +1124,82 @@ pub trait Gen {
     /// Initialize buffers etc.
     /// Default: nop
     fn init(&mut self, _sample_rate: Sample) {}
+    /// Called on the audio thread when the sample rate changes while the Gen
+    /// is running, e.g. because the audio server changed it.
+    /// Default: calls [`Gen::init`]
+    fn sample_rate_changed(&mut self, sample_rate: Sample) {
+        self.init(sample_rate);
+    }
     fn input_desc(&self, _input: usize) -> &'static str {
         ""
     }
     fn output_desc(&self, _output: usize) -> &'static str {
         ""
     }
+    /// The constant value of an input when the node is added to a Graph. The
+    /// default is only used while nothing is connected to the input and no
+    /// other constant has been set. Default: 0
+    fn default_input(&self, _input: usize) -> Sample {
+        0.0
+    }
+    /// The resources used by the Gen, saved in a [`Patch`] so that the Gen
+    /// can be created again with the same resources. Default: none
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        vec![]
+    }
+    /// True if the Gen uses the buffers, wavetables, lookup tables or user
+    /// data in the [`Resources`]. When a Graph processes in parallel, such
+    /// Gens always run on the audio thread. Other Gens may run on a worker
+    /// thread with Resources of its own, see [`ParallelSettings`].
+    /// Default: true if [`Gen::resource_refs`] isn't empty
+    fn uses_shared_resources(&self) -> bool {
+        !self.resource_refs().is_empty()
+    }
+    /// State that isn't described by the inputs or resources of the Gen,
+    /// e.g. envelope settings or the contents of a delay line, saved in a
+    /// [`Patch`] so that it can be restored. Default: none
+    fn save_state(&self) -> Option<SavedState> {
+        None
+    }
+    /// Restore the state from [`Gen::save_state`]. Called after
+    /// [`Gen::init`] when the Gen is loaded from a [`Patch`]. Default: nop
+    fn load_state(&mut self, _state: &SavedState) -> Result<(), PatchError> {
+        Ok(())
+    }
+    /// The number of samples the output is delayed relative to the input,
+    /// e.g. from lookahead or FFT processing. Used for latency compensation,
+    /// see [`Graph::compensate_latency`]. Default: 0
+    fn latency(&self) -> usize {
+        0
+    }
+    /// What an input is used for. Default: [`InputKind::Main`]
+    fn input_kind(&self, _input: usize) -> InputKind {
+        InputKind::Main
+    }
+    /// True if the Gen is not useful unless something is connected to the
+    /// input, e.g. the signal input of a filter. Checked by
+    /// [`Graph::validate`]. Default: false
+    fn input_required(&self, _input: usize) -> bool {
+        false
+    }
+    /// The rate of an input. Default: [`Rate::Audio`]
+    fn input_rate(&self, _input: usize) -> Rate {
+        Rate::Audio
+    }
+    /// The rate of an output. Default: [`Rate::Audio`]
+    fn output_rate(&self, _output: usize) -> Rate {
+        Rate::Audio
+    }
     fn name(&self) -> &'static str {
         "no_name"
     }
 }
 
 type SamplesSlice = [Box<[Sample]>];
+const CLOSURE_INPUT_NAMES: [&str; 8] = ["in0", "in1", "in2", "in3", "in4", "in5", "in6", "in7"];
+const CLOSURE_OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
 type ProcessFn =
     Box<dyn FnMut(&SamplesSlice, &mut SamplesSlice, &mut Resources) -> GenState + Send>;
 
@@ -701,6 +1230,16 @@ impl ClosureGen {
         self.inputs.push(input_name);
         self
     }
+    /// Adds `num_inputs` inputs named "in0", "in1" etc. and `num_outputs`
+    /// outputs named "out0", "out1" etc. Channels after the first 8 have no
+    /// name and can only be connected by index.
+    pub fn channels(mut self, num_inputs: usize, num_outputs: usize) -> Self {
+        self.inputs
+            .extend((0..num_inputs).map(|i| CLOSURE_INPUT_NAMES.get(i).copied().unwrap_or("")));
+        self.outputs
+            .extend((0..num_outputs).map(|i| CLOSURE_OUTPUT_NAMES.get(i).copied().unwrap_or("")));
+        self
+    }
     /// Set the name of the ClosureGen.
     pub fn name(mut self, name: &'static str) -> Self {
         self.name = name;
@@ -746,6 +1285,11 @@ impl Gen for ClosureGen {
         self.outputs.get(output).unwrap_or(&"")
     }
 
+    /// The closure may use any part of the Resources
+    fn uses_shared_resources(&self) -> bool {
+        true
+    }
+
     fn name(&self) -> &'static str {
         self.name
     }
@@ -774,6 +1318,50 @@ new_key_type! {
     /// Node identifier in a specific Graph. For referring to a Node outside of the context of a Graph, use NodeAddress instead.
     struct NodeKey;
 }
+new_key_type! {
+    /// Group identifier in a specific Graph, see [`GroupAddress`]
+    struct GroupKey;
+}
+
+/// An address to a group of nodes, see [`Graph::add_group`]
+#[derive(Copy, Clone, Debug, PartialEq, Hash, Eq)]
+pub struct GroupAddress {
+    graph_id: GraphId,
+    key: GroupKey,
+}
+
+/// Where in a group a node is added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupPosition {
+    /// Run before the other nodes in the group
+    Head,
+    /// Run after the other nodes in the group
+    Tail,
+}
+
+/// A group of nodes in a Graph
+struct Group {
+    /// From head to tail
+    nodes: Vec<NodeKey>,
+    /// Scales the connections leaving the group
+    gain: Sample,
+}
+
+/// The gain of a connection from `source` to `sink` from the group of
+/// `source`, None for the Graph outputs
+fn group_gain(
+    groups: &SlotMap<GroupKey, Group>,
+    node_groups: &SecondaryMap<NodeKey, GroupKey>,
+    source: NodeKey,
+    sink: Option<NodeKey>,
+) -> Sample {
+    match node_groups.get(source) {
+        Some(group) if sink.and_then(|sink| node_groups.get(sink)) != Some(group) => {
+            groups.get(*group).map_or(1.0, |group| group.gain)
+        }
+        _ => 1.0,
+    }
+}
 
 /// Pass to Graph::new to set the options the Graph is created with in an ergonomic and clear way.
 #[derive(Clone, Copy, Debug)]
@@ -784,6 +1372,10 @@ pub struct GraphSettings {
     pub max_node_inputs: usize,
     /// The number of outputs from the Graph
     pub num_outputs: usize,
+    /// The number of monitor outputs, added after the `num_outputs` main
+    /// outputs. They are meant for [`MonitorTap`]s, e.g. to listen to a
+    /// node on headphones without changing the main outputs.
+    pub num_monitor_outputs: usize,
     /// The block size this Graph uses for processing.
     pub block_size: usize,
     /// The maximum number of nodes that can be added to the graph.
@@ -796,6 +1388,47 @@ pub struct GraphSettings {
     pub ring_buffer_size: usize,
     /// How much time is added to every *relative* scheduling event to ensure the Change has time to travel to the GraphGen.
     pub latency: Duration,
+    /// Process independent nodes in parallel on a pool of worker threads.
+    /// Only the top level Graph uses worker threads.
+    pub parallel: Option<ParallelSettings>,
+}
+
+/// Settings for processing a Graph on multiple threads.
+///
+/// The nodes are sorted into stages where no node depends on another node in
+/// the same stage. The nodes in a stage are then shared between the audio
+/// thread and the worker threads. Synchronising threads has an overhead, so
+/// parallel processing only pays off for stages with enough work in them.
+///
+/// Nodes whose Gen uses the buffers, wavetables, lookup tables or user data
+/// of the [`Resources`] always run on the audio thread, see
+/// [`Gen::uses_shared_resources`]. Other nodes may run on a worker thread
+/// with Resources of its own, which have the sample rate and transport state
+/// of the audio thread and a [`Resources::rng`] seeded from it.
+///
+/// On unix platforms the worker threads get the scheduling policy and
+/// priority of the audio thread, e.g. real time priority, the first time the
+/// Graph is processed. Elsewhere they keep normal priority. They spin for a
+/// short while after each stage before going to sleep.
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelSettings {
+    /// The number of worker threads in addition to the audio thread.
+    pub num_threads: usize,
+    /// Stages with fewer nodes than this are processed on the audio thread only.
+    pub min_tasks_per_stage: usize,
+}
+
+impl Default for ParallelSettings {
+    fn default() -> Self {
+        let num_threads = std::thread::available_parallelism()
+            .map(|n| n.get().saturating_sub(1))
+            .unwrap_or(1)
+            .max(1);
+        Self {
+            num_threads,
+            min_tasks_per_stage: 4,
+        }
+    }
 }
 
 impl Default for GraphSettings {
@@ -803,12 +1436,14 @@ impl Default for GraphSettings {
         GraphSettings {
             num_inputs: 0,
             num_outputs: 2,
+            num_monitor_outputs: 0,
             max_node_inputs: 8,
             block_size: 64,
             num_nodes: 1024,
             sample_rate: 48000.,
             ring_buffer_size: 100,
             latency: Duration::from_millis(4),
+            parallel: None,
         }
     }
 }
@@ -824,6 +1459,26 @@ pub struct Graph {
     node_input_name_to_index: SecondaryMap<NodeKey, HashMap<&'static str, usize>>,
     node_output_index_to_name: SecondaryMap<NodeKey, Vec<&'static str>>,
     node_output_name_to_index: SecondaryMap<NodeKey, HashMap<&'static str, usize>>,
+    /// The latest constant value set for every input of every node. The
+    /// constants in the nodes may only be read by the GraphGen once it exists.
+    node_constants: SecondaryMap<NodeKey, Vec<Sample>>,
+    /// The latest smoothing set for every input of every node
+    node_smoothing: SecondaryMap<NodeKey, Vec<Smoothing>>,
+    /// How the connections to every input of every node are combined
+    node_input_modes: SecondaryMap<NodeKey, Vec<InputMode>>,
+    /// Names given to nodes by the user, unique within this Graph
+    node_names: HashMap<String, NodeKey>,
+    groups: SlotMap<GroupKey, Group>,
+    /// The order the groups run in
+    group_order: Vec<GroupKey>,
+    /// The group every node in a group is in
+    node_groups: SecondaryMap<NodeKey, GroupKey>,
+    /// Pairs of nodes where the first should run before the second, see
+    /// [`Graph::run_before`]
+    run_order: Vec<(NodeKey, NodeKey)>,
+    /// The nodes added in the current [`Graph::bundle`], None outside of a
+    /// bundle
+    bundle_new_nodes: Option<Vec<NodeKey>>,
     /// List of feedback input edges for every node. The NodeKey in the tuple is the index of the FeedbackNode doing the buffering
     node_feedback_edges: SecondaryMap<NodeKey, Vec<FeedbackEdge>>,
     node_feedback_node_key: SecondaryMap<NodeKey, NodeKey>,
@@ -837,7 +1492,9 @@ pub struct Graph {
     /// The edges from the graph inputs to nodes, one Vec per node. `source` in the edge is really the sink here.
     graph_input_edges: SecondaryMap<NodeKey, Vec<Edge>>,
     num_inputs: usize,
+    /// The number of outputs including the monitor outputs
     num_outputs: usize,
+    num_monitor_outputs: usize,
     block_size: usize,
     sample_rate: Sample,
     ring_buffer_size: usize,
@@ -847,6 +1504,17 @@ pub struct Graph {
     graph_gen_communicator: Option<GraphGenCommunicator>,
     /// The duration added to all changes scheduled to a relative time so that they have time to travel to the GraphGen.
     latency: Duration,
+    /// Changes scheduled in musical time waiting for the transport to start playing.
+    pending_beat_changes: Vec<ParameterChange>,
+    parallel: Option<ParallelSettings>,
+    /// The number of nodes in each stage of the node order if processing in parallel
+    stage_lengths: Vec<usize>,
+    /// The name and process times of every node
+    #[cfg(feature = "profiling")]
+    node_times: SecondaryMap<NodeKey, (&'static str, Arc<crate::profiling::ProcessTimes>)>,
+    /// The process times of the node running this Graph, if it is the top level Graph
+    #[cfg(feature = "profiling")]
+    graph_times: Option<Arc<crate::profiling::ProcessTimes>>,
 }
 
 impl Default for Graph {
@@ -860,12 +1528,14 @@ impl Graph {
         let GraphSettings {
             num_inputs,
             num_outputs,
+            num_monitor_outputs,
             max_node_inputs,
             block_size,
             num_nodes,
             sample_rate,
             ring_buffer_size,
             latency,
+            parallel,
         } = options;
         let inputs_buffers = vec![vec![0.0; block_size].into_boxed_slice(); max_node_inputs];
         let id = NEXT_GRAPH_ID.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
@@ -881,6 +1551,15 @@ impl Graph {
             node_input_name_to_index: SecondaryMap::with_capacity(num_nodes),
             node_output_index_to_name: SecondaryMap::with_capacity(num_nodes),
             node_output_name_to_index: SecondaryMap::with_capacity(num_nodes),
+            node_constants: SecondaryMap::with_capacity(num_nodes),
+            node_smoothing: SecondaryMap::with_capacity(num_nodes),
+            node_input_modes: SecondaryMap::with_capacity(num_nodes),
+            node_names: HashMap::new(),
+            groups: SlotMap::with_key(),
+            group_order: vec![],
+            node_groups: SecondaryMap::new(),
+            run_order: vec![],
+            bundle_new_nodes: None,
             node_feedback_node_key: SecondaryMap::with_capacity(num_nodes),
             node_feedback_edges,
             node_order: Vec::with_capacity(num_nodes),
@@ -892,7 +1571,8 @@ impl Graph {
             output_edges: vec![],
             graph_input_edges,
             num_inputs,
-            num_outputs,
+            num_outputs: num_outputs + num_monitor_outputs,
+            num_monitor_outputs,
             block_size,
             sample_rate,
             latency,
@@ -900,34 +1580,155 @@ impl Graph {
             inputs_buffers,
             ring_buffer_size,
             graph_gen_communicator: None,
+            pending_beat_changes: vec![],
+            parallel,
+            stage_lengths: vec![],
+            #[cfg(feature = "profiling")]
+            node_times: SecondaryMap::with_capacity(num_nodes),
+            #[cfg(feature = "profiling")]
+            graph_times: None,
         }
     }
     /// Create a node that will run this graph. This will fail if a Node or Gen has already been created from the Graph since only one Gen is allowed to exist per Graph.
     ///
     /// Only use this for manually running the main Graph (the Graph containing all other Graphs). For adding a Graph to another Graph, use the push_graph() method.
-    pub fn to_node(&mut self) -> Result<Node, String> {
+    pub fn to_node(&mut self) -> Result<Node, ToNodeError> {
         let block_size = self.block_size();
-        let graph_gen = self.create_graph_gen()?;
+        let mut graph_gen = self.create_graph_gen(true)?;
+        if let Some(settings) = self.parallel {
+            graph_gen.pool = Some(WorkerPool::new(settings));
+        }
         let mut node = Node::new("graph", Box::new(graph_gen));
         node.init(block_size, self.sample_rate);
+        #[cfg(feature = "profiling")]
+        {
+            self.graph_times = Some(node.times.clone());
+        }
         Ok(node)
     }
     /// Add a graph as a node in this graph. This will allow you to change the Graph you added later on as needed.
+    ///
+    /// The inner graph may have a smaller block size than this graph for
+    /// lower latency parameter changes inside it, as long as it divides the
+    /// block size of this graph evenly. It is then run several times per block.
     pub fn push_graph(&mut self, mut graph: Graph) -> NodeAddress {
-        if graph.block_size() != self.block_size() {
-            panic!("Warning: You are pushing a graph with a different block size. The library is not currently equipped to handle this. In a future version this will work seamlesly.")
-        }
-        if graph.sample_rate != self.sample_rate {
-            eprintln!("Warning: You are pushing a graph with a different sample rate. This is currently allowed, but expect bugs unless you deal with resampling manually.")
+        if !self.can_contain_block_size(graph.block_size()) {
+            panic!("Warning: You are pushing a graph with a block size that doesn't evenly divide the block size of the parent graph. The library is not currently equipped to handle this.")
         }
-        // Create the GraphGen from the new Graph
-        let gen = graph.create_graph_gen().unwrap();
-        // Add the GraphGen to this Graph as a Node
-        let address = self.push_gen(gen);
+        // Create the GraphGen from the new Graph and add it to this Graph as a Node
+        let gen = self.inner_graph_gen(&mut graph).unwrap();
+        let address = self.push_node(Node::new(gen.name(), gen));
         // Add the Graph to this Graph's graph list
         self.graphs_per_node.insert(address.key, graph);
         address
     }
+    /// Replace a Graph that was added using [`Graph::push_graph`] with a new
+    /// one, fading from the old to the new one over `crossfade`. The new
+    /// Graph can be built on any thread and needs the same number of inputs
+    /// and outputs. The node keeps its connections in this Graph.
+    ///
+    /// Returns the old Graph. It keeps running until the crossfade is over.
+    ///
+    /// The top level Graph can't be replaced this way. For live coding, run
+    /// the Graph you want to replace inside an otherwise empty top level Graph.
+    pub fn replace_graph(
+        &mut self,
+        node: impl Into<NodeAddress>,
+        mut graph: Graph,
+        crossfade: Duration,
+    ) -> Result<Graph, ReplaceGenError> {
+        let node = node.into();
+        let parent = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ReplaceGenError::GraphNotFound)?;
+        if !parent.graphs_per_node.contains_key(node.key) {
+            return Err(ReplaceGenError::NotAGraph);
+        }
+        if !parent.can_contain_block_size(graph.block_size()) {
+            return Err(ReplaceGenError::InvalidBlockSize);
+        }
+        let node_inputs = parent.node_input_index_to_name[node.key].len();
+        let node_outputs = parent.node_output_index_to_name[node.key].len();
+        if graph.num_inputs != node_inputs || graph.num_outputs != node_outputs {
+            return Err(ReplaceGenError::ChannelMismatch {
+                node_inputs,
+                node_outputs,
+                gen_inputs: graph.num_inputs,
+                gen_outputs: graph.num_outputs,
+            });
+        }
+        let gen = parent
+            .inner_graph_gen(&mut graph)
+            .map_err(|_| ReplaceGenError::GraphAlreadyRunning)?;
+        let old_graph = parent.graphs_per_node.remove(node.key).unwrap();
+        if let Err(e) = parent.replace_node_gen(node.key, gen, crossfade) {
+            parent.graphs_per_node.insert(node.key, old_graph);
+            return Err(e);
+        }
+        parent.graphs_per_node.insert(node.key, graph);
+        Ok(old_graph)
+    }
+    /// Inner Graphs need a block size that evenly divides the block size of this Graph
+    fn can_contain_block_size(&self, inner_block_size: usize) -> bool {
+        inner_block_size <= self.block_size() && self.block_size().is_multiple_of(inner_block_size)
+    }
+    /// Create the Gen running `graph` inside this Graph
+    fn inner_graph_gen(&self, graph: &mut Graph) -> Result<Box<dyn Gen + Send>, ToNodeError> {
+        if graph.sample_rate != self.sample_rate {
+            eprintln!("Warning: You are pushing a graph with a different sample rate. This is currently allowed, but expect bugs unless you deal with resampling manually.")
+        }
+        let inner_block_size = graph.block_size();
+        let gen = graph.create_graph_gen(false)?;
+        if inner_block_size == self.block_size() {
+            Ok(Box::new(gen))
+        } else {
+            Ok(Box::new(SubBlockGen::new(gen, inner_block_size)))
+        }
+    }
+    /// Add a node that is freed automatically `duration` after it starts
+    /// processing. Its outputs are silent from that sample on.
+    pub fn push_with_duration<G: Gen + Send + 'static>(
+        &mut self,
+        gen: G,
+        duration: Duration,
+    ) -> NodeAddress {
+        self.push_with_duration_and_fade_out(gen, duration, Duration::ZERO)
+    }
+    /// Same as [`Graph::push_with_duration`], but the outputs are faded out
+    /// linearly over the last `fade_out` of the duration.
+    pub fn push_with_duration_and_fade_out<G: Gen + Send + 'static>(
+        &mut self,
+        gen: G,
+        duration: Duration,
+        fade_out: Duration,
+    ) -> NodeAddress {
+        self.push_gen(FreeAfter::new(gen, duration, fade_out))
+    }
+    /// Add a node processed by a closure with `num_inputs` inputs and
+    /// `num_outputs` outputs, see [`ClosureGen::channels`]. Use [`gen`] to
+    /// also name the node and its channels.
+    ///
+    /// ```
+    /// # use knyst::prelude::*;
+    /// # use knyst::graph::GenState;
+    /// # let mut graph = Graph::new(GraphSettings::default());
+    /// let double = graph.push_closure(1, 1, |inputs, outputs, _resources| {
+    ///     for (out, input) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+    ///         *out = input * 2.0;
+    ///     }
+    ///     GenState::Continue
+    /// });
+    /// ```
+    pub fn push_closure(
+        &mut self,
+        num_inputs: usize,
+        num_outputs: usize,
+        process: impl FnMut(&[Box<[Sample]>], &mut [Box<[Sample]>], &mut Resources) -> GenState
+            + 'static
+            + Send,
+    ) -> NodeAddress {
+        self.push_gen(gen(process).channels(num_inputs, num_outputs))
+    }
     /// Add anything that implements Gen to this Graph as a node.
     pub fn push_gen<G: Gen + Send + 'static>(&mut self, gen: G) -> NodeAddress {
         self.push_node(Node::new(gen.name(), Box::new(gen)))
@@ -935,7 +1736,7 @@ impl Graph {
     /// Add a node to this Graph. The Node will be (re)initialised with the correct block size for this Graph.
     ///
     /// Making it not public means Graphs cannot be accidentally added, but a Node<Graph> can still be created for the top level one if preferred.
-    fn push_node(&mut self, mut node: Node) -> NodeAddress {
+    pub(crate) fn push_node(&mut self, mut node: Node) -> NodeAddress {
         if node.num_inputs() > self.inputs_buffers.len() {
             eprintln!("Warning: You are trying to add a node with more inputs than the maximum for this Graph. Try increasing the maximum number of node inputs in the GraphSettings.");
         }
@@ -956,7 +1757,24 @@ impl Graph {
             .map(|(i, &name)| (name, i))
             .collect();
         node.init(self.block_size, self.sample_rate);
+        if self.parallel.is_some() {
+            node.init_input_buffers(self.block_size);
+        }
+        let num_inputs = node.num_inputs();
+        let constants = node.input_constants.clone();
+        #[cfg(feature = "profiling")]
+        let times = (node.name, node.times.clone());
         let key = self.get_nodes_mut().insert(node);
+        if let Some(bundle_new_nodes) = &mut self.bundle_new_nodes {
+            bundle_new_nodes.push(key);
+        }
+        #[cfg(feature = "profiling")]
+        self.node_times.insert(key, times);
+        self.node_constants.insert(key, constants);
+        self.node_smoothing
+            .insert(key, vec![Smoothing::None; num_inputs]);
+        self.node_input_modes
+            .insert(key, vec![InputMode::Add; num_inputs]);
         self.node_input_edges.insert(key, vec![]);
         self.node_feedback_edges.insert(key, vec![]);
         self.graph_input_edges.insert(key, vec![]);
@@ -973,6 +1791,105 @@ impl Graph {
             key,
         }
     }
+    /// Replace the Gen of a node at the start of the next block, keeping all
+    /// of its connections and input constants. The new Gen needs to have the
+    /// same number of inputs and outputs as the node.
+    pub fn replace_gen<G: Gen + Send + 'static>(
+        &mut self,
+        node: impl Into<NodeAddress>,
+        gen: G,
+    ) -> Result<(), ReplaceGenError> {
+        self.replace_gen_with_crossfade(node, gen, Duration::ZERO)
+    }
+    /// Same as [`Graph::replace_gen`], but the old Gen keeps running and its
+    /// output is faded out while the new one is faded in over `crossfade`.
+    pub fn replace_gen_with_crossfade<G: Gen + Send + 'static>(
+        &mut self,
+        node: impl Into<NodeAddress>,
+        gen: G,
+        crossfade: Duration,
+    ) -> Result<(), ReplaceGenError> {
+        let node = node.into();
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ReplaceGenError::GraphNotFound)?;
+        graph.replace_node_gen(node.key, Box::new(gen), crossfade)
+    }
+    fn replace_node_gen(
+        &mut self,
+        key: NodeKey,
+        mut gen: Box<dyn Gen + Send>,
+        crossfade: Duration,
+    ) -> Result<(), ReplaceGenError> {
+        if !self.get_nodes().contains_key(key) || self.node_keys_pending_removal.contains(&key) {
+            return Err(ReplaceGenError::NodeNotFound);
+        }
+        let node_inputs = self.node_input_index_to_name[key].len();
+        let node_outputs = self.node_output_index_to_name[key].len();
+        if gen.num_inputs() != node_inputs || gen.num_outputs() != node_outputs {
+            return Err(ReplaceGenError::ChannelMismatch {
+                node_inputs,
+                node_outputs,
+                gen_inputs: gen.num_inputs(),
+                gen_outputs: gen.num_outputs(),
+            });
+        }
+        gen.init(self.sample_rate);
+        let input_index_to_name: Vec<_> = (0..node_inputs).map(|i| gen.input_desc(i)).collect();
+        let output_index_to_name: Vec<_> = (0..node_outputs).map(|i| gen.output_desc(i)).collect();
+        self.node_input_name_to_index.insert(
+            key,
+            input_index_to_name
+                .iter()
+                .enumerate()
+                .map(|(i, &name)| (name, i))
+                .collect(),
+        );
+        self.node_output_name_to_index.insert(
+            key,
+            output_index_to_name
+                .iter()
+                .enumerate()
+                .map(|(i, &name)| (name, i))
+                .collect(),
+        );
+        self.node_input_index_to_name
+            .insert(key, input_index_to_name);
+        self.node_output_index_to_name
+            .insert(key, output_index_to_name);
+        // If the node was running a Graph, that Graph is no longer reachable
+        self.graphs_per_node.remove(key);
+        #[cfg(feature = "profiling")]
+        if let Some((name, _)) = self.node_times.get_mut(key) {
+            *name = gen.name();
+        }
+        let replacement = Box::new(GenReplacement {
+            key,
+            name: gen.name(),
+            control_outputs: control_outputs(&*gen),
+            buffers: buffer_keys(&*gen),
+            shared_resources: gen.uses_shared_resources(),
+            gen,
+            outputs: vec![vec![0.0; self.block_size].into_boxed_slice(); node_outputs]
+                .into_boxed_slice(),
+            crossfade_samples: (crossfade.as_secs_f64() * sample_to_f64(self.sample_rate)) as usize,
+            position: 0,
+            applied: false,
+        });
+        match &mut self.graph_gen_communicator {
+            Some(ggc) => ggc
+                .gen_replacements
+                .push(replacement)
+                .map_err(|_| ReplaceGenError::QueueFull),
+            None => {
+                // The node isn't being processed so the Gen can be swapped right away
+                let node = &mut self.get_nodes_mut()[key];
+                node.replace_gen(replacement);
+                node.replacement = None;
+                Ok(())
+            }
+        }
+    }
     /// Remove all nodes in this graph and all its subgraphs that are not connected to anything.
     pub fn free_disconnected_nodes(&mut self) -> Result<(), FreeError> {
         // The easiest way to do it would be to store disconnected nodes after
@@ -1031,6 +1948,7 @@ impl Graph {
                             to_index: Some(edge.to_input_index),
                             to_label: None,
                             channels: 1,
+                            gain: edge.gain,
                             feedback: false,
                         });
                     }
@@ -1049,6 +1967,7 @@ impl Graph {
                         from_label: None,
                         to_index: graph_output.to_input_index,
                         channels: 1,
+                        gain: graph_output.gain,
                     });
                 }
             }
@@ -1081,6 +2000,7 @@ impl Graph {
                         to_index: Some(graph_input.to_input_index),
                         to_label: None,
                         channels: 1,
+                        gain: graph_input.gain,
                     });
                 }
             }
@@ -1089,10 +2009,14 @@ impl Graph {
                 for input in inputs {
                     for output in outputs {
                         let connection = output;
-                        self.connect(connection.from(NodeAddress {
-                            key: input.source,
-                            graph_id: self.id,
-                        }))
+                        self.connect(
+                            connection
+                                .from(NodeAddress {
+                                    key: input.source,
+                                    graph_id: self.id,
+                                })
+                                .gain(connection.get_gain() * input.gain),
+                        )
                         .expect("Mended connections should be guaranteed to succeed");
                     }
                 }
@@ -1104,7 +2028,8 @@ impl Graph {
                         match self.connect(
                             connection
                                 .to(output.get_source_node().unwrap())
-                                .to_index(output.get_to_index().unwrap()),
+                                .to_index(output.get_to_index().unwrap())
+                                .gain(connection.get_gain() * output.get_gain()),
                         ) {
                             Ok(_) => (),
                             Err(e) => return Err(FreeError::ConnectionError(Box::new(e))),
@@ -1139,6 +2064,15 @@ impl Graph {
             // Remove all edges leading to the node
             self.node_input_edges.remove(node.key);
             self.graph_input_edges.remove(node.key);
+            self.node_constants.remove(node.key);
+            self.node_smoothing.remove(node.key);
+            self.node_input_modes.remove(node.key);
+            self.remove_node_from_group(node.key);
+            self.run_order
+                .retain(|&(first, then)| first != node.key && then != node.key);
+            #[cfg(feature = "profiling")]
+            self.node_times.remove(node.key);
+            self.node_names.retain(|_, key| *key != node.key);
             // feedback from the freed node requires removing the feedback node and all edges from the feedback node
             self.node_feedback_edges.remove(node.key);
             // Remove all edges leading from the node to other nodes
@@ -1239,7 +2173,110 @@ impl Graph {
         Ok(())
     }
 
+    /// Make the edits in `edit` take effect together, at the start of the
+    /// block containing `time`: added nodes, connections and constant
+    /// changes made using [`Graph::connect`], e.g. so that a voice is never
+    /// heard half built. The changes are committed when `edit` returns and
+    /// [`Graph::commit_changes`] does nothing inside the bundle. Changes
+    /// committed after the bundle take effect no earlier than the bundle.
+    ///
+    /// Changes scheduled using [`Graph::schedule_change`] keep their own
+    /// time. Edits to Graphs inside this Graph are not part of the bundle.
+    ///
+    /// ```
+    /// # use knyst::prelude::*;
+    /// # use knyst::graph::{ConnectionError, TimeKind};
+    /// # use std::time::Duration;
+    /// # let mut graph = Graph::new(GraphSettings::default());
+    /// # let _node = graph.to_node().unwrap();
+    /// let time = TimeKind::DurationFromNow(Duration::from_millis(50));
+    /// let voice = graph
+    ///     .bundle(time, |graph| -> Result<_, ConnectionError> {
+    ///         let voice = graph.push_gen(knyst::fm::PmOperator::new());
+    ///         graph.connect(constant(2.0).to(voice).to_label("ratio"))?;
+    ///         graph.connect(Connection::graph_output(voice))?;
+    ///         Ok(voice)
+    ///     })
+    ///     .unwrap()
+    ///     .unwrap();
+    /// ```
+    pub fn bundle<R>(
+        &mut self,
+        time: TimeKind,
+        edit: impl FnOnce(&mut Graph) -> R,
+    ) -> Result<R, ScheduleError> {
+        let ggc = match &self.graph_gen_communicator {
+            // Inside another bundle the edits are part of that bundle, and
+            // without a GraphGen nothing runs until all edits are done
+            Some(ggc) if self.bundle_new_nodes.is_none() => ggc,
+            _ => return Ok(edit(self)),
+        };
+        let sample = match time {
+            TimeKind::AbsoluteSample(sample) => sample,
+            TimeKind::DurationFromNow(duration) => ggc.scheduler.local_time_to_sample(duration),
+            TimeKind::Beats(beats) => ggc
+                .transport
+                .as_ref()
+                .ok_or(ScheduleError::TransportNotAvailable)?
+                .timeline
+                .sample_of(beats)
+                .ok_or(ScheduleError::TransportNotPlaying)?,
+        };
+        let sample = sample - sample % self.block_size as u64;
+        self.bundle_new_nodes = Some(vec![]);
+        if let Some(ggc) = &mut self.graph_gen_communicator {
+            ggc.scheduler.bundle_time = Some(sample);
+        }
+        let result = edit(self);
+        self.bundle_new_nodes = None;
+        if let Some(ggc) = &mut self.graph_gen_communicator {
+            ggc.scheduler.bundle_time = None;
+        }
+        self.commit_changes_at(sample);
+        if let Some(ggc) = &mut self.graph_gen_communicator {
+            ggc.update();
+        }
+        Ok(result)
+    }
     pub fn schedule_change(&mut self, change: ParameterChange) -> Result<(), ScheduleError> {
+        let change = if let TimeKind::Beats(beats) = change.time {
+            // Musical time is converted to sample time by the top level Graph
+            // before the change is passed on to the Graph containing the node.
+            let transport = match &self.graph_gen_communicator {
+                Some(GraphGenCommunicator {
+                    transport: Some(transport),
+                    ..
+                }) => transport,
+                Some(_) => return Err(ScheduleError::TransportNotAvailable),
+                None => return Err(ScheduleError::SchedulerNotCreated),
+            };
+            match transport.timeline.sample_of(beats) {
+                Some(sample) => {
+                    let mut change = change;
+                    if change.node.graph_id == self.id {
+                        change.time = TimeKind::AbsoluteSample(sample);
+                    } else {
+                        // Sub graphs have their own sample counters so we can
+                        // only give them a time relative to now.
+                        let ggc = self.graph_gen_communicator.as_ref().unwrap();
+                        let now = ggc.timestamp.load(Ordering::SeqCst);
+                        let samples_from_now = sample.saturating_sub(now);
+                        change.time = TimeKind::DurationFromNow(Duration::from_secs_f64(
+                            samples_from_now as f64 / sample_to_f64(self.sample_rate),
+                        ));
+                    }
+                    change
+                }
+                None => {
+                    // The transport isn't playing so we can't know when the
+                    // change should happen yet.
+                    self.pending_beat_changes.push(change);
+                    return Ok(());
+                }
+            }
+        } else {
+            change
+        };
         if change.node.graph_id == self.id {
             // Does the Node exist?
             if !self.get_nodes_mut().contains_key(change.node.key) {
@@ -1258,10 +2295,14 @@ impl Graph {
                 0
             };
             if let Some(ggc) = &mut self.graph_gen_communicator {
+                if let Some(constant) = self.node_constants[change.node.key].get_mut(index) {
+                    *constant = change.value;
+                }
                 // The GraphGen has been created so we have to be more careful
                 let change_kind = ScheduledChangeKind::Constant {
                     index,
                     value: change.value,
+                    smoother: None,
                 };
                 match change.time {
                     TimeKind::DurationFromNow(d) => {
@@ -1271,6 +2312,7 @@ impl Graph {
                     TimeKind::AbsoluteSample(absolute_timestamp) => ggc
                         .scheduler
                         .schedule_absolute_sample(change.node.key, change_kind, absolute_timestamp),
+                    TimeKind::Beats(_) => unreachable!("Beats are converted to samples above"),
                 }
             } else {
                 return Err(ScheduleError::SchedulerNotCreated);
@@ -1321,6 +2363,7 @@ impl Graph {
                 to_index: input_index,
                 to_label: input_label,
                 channels,
+                gain: _,
                 feedback,
             } => {
                 if source.graph_id != sink.graph_id {
@@ -1361,7 +2404,7 @@ impl Graph {
                 } else if from_label.is_some() {
                     if let Some(label) = from_label {
                         // unwrap() is okay because the hashmap is generated for every node when it is inserted
-                        if let Some(index) = self.output_index_from_label(sink.key, label) {
+                        if let Some(index) = self.output_index_from_label(source.key, label) {
                             index
                         } else {
                             return Err(ConnectionError::InvalidOutputLabel(label));
@@ -1440,6 +2483,7 @@ impl Graph {
                 sink,
                 to_index: input_index,
                 to_label: input_label,
+                smoothing: _,
             } => {
                 if let Some(sink) = sink {
                     if sink.graph_id != self.id {
@@ -1465,12 +2509,20 @@ impl Graph {
                     } else {
                         0
                     };
-                    if let Some(ggc) = &mut self.graph_gen_communicator {
+                    if let Some(constant) = self
+                        .node_constants
+                        .get_mut(sink.key)
+                        .and_then(|constants| constants.get_mut(input))
+                    {
+                        *constant = 0.0;
+                    }
+                    if let Some(ggc) = &mut self.graph_gen_communicator {
                         ggc.scheduler.schedule_asap(
                             sink.key,
                             ScheduledChangeKind::Constant {
                                 index: input,
                                 value: 0.0,
+                                smoother: None,
                             },
                         );
                     } else {
@@ -1487,6 +2539,7 @@ impl Graph {
                 from_label,
                 to_index,
                 channels,
+                gain: _,
             } => {
                 if source.graph_id != self.id {
                     return try_disconnect_in_child_graphs(connection);
@@ -1536,6 +2589,7 @@ impl Graph {
                 to_index,
                 to_label,
                 channels,
+                gain: _,
             } => {
                 if sink.graph_id != self.id {
                     return try_disconnect_in_child_graphs(connection);
@@ -1559,7 +2613,8 @@ impl Graph {
                 } else {
                     0
                 };
-                if channels + to_index > self.num_outputs {
+                if channels + to_index > self.node_input_index_to_name.get(sink.key).unwrap().len()
+                {
                     return Err(ConnectionError::ChannelOutOfBounds);
                 }
                 let edge_list = &mut self.graph_input_edges[sink.key];
@@ -1613,6 +2668,7 @@ impl Graph {
                 to_index: input_index,
                 to_label: input_label,
                 channels,
+                gain,
                 feedback,
             } => {
                 if source.graph_id != sink.graph_id {
@@ -1653,7 +2709,7 @@ impl Graph {
                 } else if from_label.is_some() {
                     if let Some(label) = from_label {
                         // unwrap() is okay because the hashmap is generated for every node when it is inserted
-                        if let Some(index) = self.output_index_from_label(sink.key, label) {
+                        if let Some(index) = self.output_index_from_label(source.key, label) {
                             index
                         } else {
                             return Err(ConnectionError::InvalidOutputLabel(label));
@@ -1669,6 +2725,9 @@ impl Graph {
                 {
                     return Err(ConnectionError::ChannelOutOfBounds);
                 }
+                if channels + from_index > self.node_num_outputs_by_key(source.key)? {
+                    return Err(ConnectionError::ChannelOutOfBounds);
+                }
                 if !feedback {
                     let edge_list = &mut self.node_input_edges[sink.key];
                     for i in 0..channels {
@@ -1676,6 +2735,7 @@ impl Graph {
                             from_output_index: from_index + i,
                             source: source.key,
                             to_input_index: to_index + i,
+                            gain,
                         });
                     }
                 } else {
@@ -1700,6 +2760,7 @@ impl Graph {
                             from_output_index: from_index + i,
                             source: feedback_node_index,
                             to_input_index: to_index + i,
+                            gain,
                         });
                     }
                     let edge_list = &mut self.node_feedback_edges[feedback_node_index];
@@ -1718,6 +2779,7 @@ impl Graph {
                 sink,
                 to_index: input_index,
                 to_label: input_label,
+                smoothing,
             } => {
                 if let Some(sink) = sink {
                     if sink.graph_id != self.id {
@@ -1743,17 +2805,49 @@ impl Graph {
                     } else {
                         0
                     };
-                    if let Some(ggc) = &mut self.graph_gen_communicator {
+                    if let Some(constant) = self
+                        .node_constants
+                        .get_mut(sink.key)
+                        .and_then(|constants| constants.get_mut(input))
+                    {
+                        *constant = value;
+                    }
+                    if let (Some(smoothing), Some(input_smoothing)) = (
+                        smoothing,
+                        self.node_smoothing
+                            .get_mut(sink.key)
+                            .and_then(|smoothing| smoothing.get_mut(input)),
+                    ) {
+                        *input_smoothing = smoothing;
+                    }
+                    let smoother =
+                        smoothing.map(|smoothing| InputSmoother::new(smoothing, self.sample_rate));
+                    let new_in_bundle = self
+                        .bundle_new_nodes
+                        .as_ref()
+                        .is_some_and(|nodes| nodes.contains(&sink.key));
+                    if let Some(ggc) = self
+                        .graph_gen_communicator
+                        .as_mut()
+                        .filter(|_| !new_in_bundle)
+                    {
                         ggc.scheduler.schedule_asap(
                             sink.key,
                             ScheduledChangeKind::Constant {
                                 index: input,
                                 value,
+                                smoother,
                             },
                         );
                     } else {
-                        // No GraphGen exists so we can set the constant directly.
-                        self.get_nodes_mut()[sink.key].set_constant(value, input);
+                        // No GraphGen exists, or the node is new in a bundle
+                        // and hasn't been sent to it, so we can set the
+                        // constant directly.
+                        let node = &mut self.get_nodes_mut()[sink.key];
+                        if let Some(smoother) = smoother {
+                            node.set_input_smoother(input, smoother);
+                        }
+                        node.set_constant(value, input);
                     }
                 } else {
                     return Err(ConnectionError::SinkNotSet);
@@ -1765,6 +2859,7 @@ impl Graph {
                 from_label,
                 to_index,
                 channels,
+                gain,
             } => {
                 if source.graph_id != self.id {
                     return try_connect_to_graphs(connection);
@@ -1792,11 +2887,15 @@ impl Graph {
                 } else {
                     0
                 };
+                if channels + from_index > self.node_num_outputs_by_key(source.key)? {
+                    return Err(ConnectionError::ChannelOutOfBounds);
+                }
                 for i in 0..channels {
                     self.output_edges.push(Edge {
                         source: source.key,
                         from_output_index: from_index + i,
                         to_input_index: to_index + i,
+                        gain,
                     })
                 }
             }
@@ -1806,6 +2905,7 @@ impl Graph {
                 to_index,
                 to_label,
                 channels,
+                gain,
             } => {
                 if sink.graph_id != self.id {
                     return try_connect_to_graphs(connection);
@@ -1829,7 +2929,8 @@ impl Graph {
                 } else {
                     0
                 };
-                if channels + to_index > self.num_outputs {
+                if channels + to_index > self.node_input_index_to_name.get(sink.key).unwrap().len()
+                {
                     return Err(ConnectionError::ChannelOutOfBounds);
                 }
                 for i in 0..channels {
@@ -1837,6 +2938,7 @@ impl Graph {
                         source: sink.key,
                         from_output_index: from_index + i,
                         to_input_index: to_index + i,
+                        gain,
                     })
                 }
             }
@@ -1887,6 +2989,9 @@ impl Graph {
                 }
                 if input_constants {
                     self.get_nodes_mut()[node.key].input_constants.fill(0.);
+                    if let Some(constants) = self.node_constants.get_mut(node.key) {
+                        constants.fill(0.);
+                    }
                 }
                 if output_nodes {
                     for (_key, edges) in &mut self.node_input_edges {
@@ -1916,7 +3021,761 @@ impl Graph {
         }
         Ok(())
     }
-    fn input_index_from_label(&self, node: NodeKey, label: &'static str) -> Option<usize> {
+    /// Give a node a name which can be used to find it using
+    /// [`Graph::node_by_name`] and to connect it using
+    /// [`Graph::connect_by_name`]. Names are unique within the Graph the node
+    /// is in. A node can only have one name, setting a new name replaces the
+    /// old one.
+    pub fn set_node_name(
+        &mut self,
+        node: NodeAddress,
+        name: impl Into<String>,
+    ) -> Result<(), ConnectionError> {
+        let name = name.into();
+        if node.graph_id != self.id {
+            for (_key, graph) in &mut self.graphs_per_node {
+                match graph.set_node_name(node, name.clone()) {
+                    Err(ConnectionError::GraphNotFound) => (),
+                    result => return result,
+                }
+            }
+            return Err(ConnectionError::GraphNotFound);
+        }
+        if !self.get_nodes().contains_key(node.key) {
+            return Err(ConnectionError::NodeNotFound);
+        }
+        match self.node_names.get(&name) {
+            Some(&key) if key != node.key => return Err(ConnectionError::NodeNameTaken(name)),
+            _ => (),
+        }
+        self.node_names.retain(|_, key| *key != node.key);
+        self.node_names.insert(name, node.key);
+        Ok(())
+    }
+    /// Bypass a node so that input `n` is passed straight through to output
+    /// `n` instead of the processed signal. Outputs without a matching input
+    /// are silent. The change is ramped over a few milliseconds to avoid
+    /// clicks and the Gen keeps running while bypassed.
+    pub fn set_node_bypassed(
+        &mut self,
+        node: NodeAddress,
+        bypassed: bool,
+    ) -> Result<(), ScheduleError> {
+        self.set_node_ramp(node, ScheduledChangeKind::Bypass(bypassed))
+    }
+    /// Mute a node, fading its outputs out over a few milliseconds. The Gen
+    /// keeps running while muted.
+    pub fn set_node_mute(&mut self, node: NodeAddress, muted: bool) -> Result<(), ScheduleError> {
+        self.set_node_ramp(node, ScheduledChangeKind::Mute(muted))
+    }
+    fn set_node_ramp(
+        &mut self,
+        node: NodeAddress,
+        change: ScheduledChangeKind,
+    ) -> Result<(), ScheduleError> {
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ScheduleError::GraphNotFound)?;
+        if !graph.get_nodes().contains_key(node.key)
+            || graph.node_keys_pending_removal.contains(&node.key)
+        {
+            return Err(ScheduleError::NodeNotFound);
+        }
+        match &mut graph.graph_gen_communicator {
+            Some(ggc) => ggc.scheduler.schedule_asap(node.key, change),
+            None => {
+                // The node isn't being processed so there is no need to ramp
+                let node = &mut graph.get_nodes_mut()[node.key];
+                match change {
+                    ScheduledChangeKind::Bypass(bypassed) => node.set_bypassed(bypassed, true),
+                    ScheduledChangeKind::Mute(muted) => node.set_muted(muted, true),
+                    ScheduledChangeKind::Constant { .. } => unreachable!(),
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Add a node running `gen` that runs before `node`, as far as the
+    /// connections allow, see [`Graph::run_before`]
+    pub fn push_before<G: Gen + Send + 'static>(
+        &mut self,
+        gen: G,
+        node: NodeAddress,
+    ) -> Result<NodeAddress, ConnectionError> {
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ConnectionError::GraphNotFound)?;
+        if !graph.node_input_edges.contains_key(node.key) {
+            return Err(ConnectionError::NodeNotFound);
+        }
+        let new_node = graph.push_gen(gen);
+        graph.run_before(new_node, node)?;
+        Ok(new_node)
+    }
+    /// Add a node running `gen` that runs after `node`, as far as the
+    /// connections allow, see [`Graph::run_before`]
+    pub fn push_after<G: Gen + Send + 'static>(
+        &mut self,
+        gen: G,
+        node: NodeAddress,
+    ) -> Result<NodeAddress, ConnectionError> {
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ConnectionError::GraphNotFound)?;
+        if !graph.node_input_edges.contains_key(node.key) {
+            return Err(ConnectionError::NodeNotFound);
+        }
+        let new_node = graph.push_gen(gen);
+        graph.run_before(node, new_node)?;
+        Ok(new_node)
+    }
+    /// Make `node` run before `other`, e.g. to place the nodes of a
+    /// feedback loop relative to the rest of the Graph. The connections
+    /// always decide the order first: if `other` is connected to `node`,
+    /// directly or through other nodes, `node` still runs after it. Commit
+    /// the changes for the new order to take effect.
+    pub fn run_before(
+        &mut self,
+        node: NodeAddress,
+        other: NodeAddress,
+    ) -> Result<(), ConnectionError> {
+        if node.graph_id != other.graph_id {
+            return Err(ConnectionError::DifferentGraphs);
+        }
+        if node == other {
+            return Err(ConnectionError::SameNode);
+        }
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ConnectionError::GraphNotFound)?;
+        if !graph.node_input_edges.contains_key(node.key)
+            || !graph.node_input_edges.contains_key(other.key)
+        {
+            return Err(ConnectionError::NodeNotFound);
+        }
+        graph
+            .run_order
+            .retain(|&pair| pair != (node.key, other.key) && pair != (other.key, node.key));
+        graph.run_order.push((node.key, other.key));
+        Ok(())
+    }
+    /// Make `node` run after `other`, see [`Graph::run_before`]
+    pub fn run_after(
+        &mut self,
+        node: NodeAddress,
+        other: NodeAddress,
+    ) -> Result<(), ConnectionError> {
+        self.run_before(other, node)
+    }
+    /// Remove the run order set for a node using [`Graph::run_before`] or
+    /// [`Graph::run_after`]
+    pub fn clear_run_order(&mut self, node: NodeAddress) -> Result<(), ConnectionError> {
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ConnectionError::GraphNotFound)?;
+        graph
+            .run_order
+            .retain(|&(first, then)| first != node.key && then != node.key);
+        Ok(())
+    }
+    /// Add an empty group of nodes to this Graph. The nodes in groups run in
+    /// the order the groups were added in and from head to tail within a
+    /// group, unless their connections require another order. Nodes that
+    /// aren't in a group may run at any point. When processing in parallel
+    /// the nodes in groups still run one after the other, while other nodes
+    /// may run at the same time as them.
+    pub fn add_group(&mut self) -> GroupAddress {
+        let key = self.groups.insert(Group {
+            nodes: vec![],
+            gain: 1.0,
+        });
+        self.group_order.push(key);
+        GroupAddress {
+            graph_id: self.id,
+            key,
+        }
+    }
+    /// Add a node running `gen` at `position` in `group`
+    pub fn push_to_group<G: Gen + Send + 'static>(
+        &mut self,
+        gen: G,
+        group: GroupAddress,
+        position: GroupPosition,
+    ) -> Result<NodeAddress, GroupError> {
+        let graph = self
+            .graph_by_id_mut(group.graph_id)
+            .ok_or(GroupError::GraphNotFound)?;
+        if !graph.groups.contains_key(group.key) {
+            return Err(GroupError::GroupNotFound);
+        }
+        let node = graph.push_gen(gen);
+        graph.move_node(node, group, position)?;
+        Ok(node)
+    }
+    /// Move a node to `position` in `group`, removing it from the group it
+    /// was in. Commit the changes for the new order to take effect.
+    pub fn move_node(
+        &mut self,
+        node: NodeAddress,
+        group: GroupAddress,
+        position: GroupPosition,
+    ) -> Result<(), GroupError> {
+        if node.graph_id != group.graph_id {
+            return Err(GroupError::DifferentGraphs);
+        }
+        let graph = self
+            .graph_by_id_mut(group.graph_id)
+            .ok_or(GroupError::GraphNotFound)?;
+        if !graph.groups.contains_key(group.key) {
+            return Err(GroupError::GroupNotFound);
+        }
+        if !graph.node_input_edges.contains_key(node.key) {
+            return Err(GroupError::NodeNotFound);
+        }
+        graph.remove_node_from_group(node.key);
+        let nodes = &mut graph.groups[group.key].nodes;
+        match position {
+            GroupPosition::Head => nodes.insert(0, node.key),
+            GroupPosition::Tail => nodes.push(node.key),
+        }
+        graph.node_groups.insert(node.key, group.key);
+        Ok(())
+    }
+    /// Remove a node from the group it is in, if any
+    pub fn remove_from_group(&mut self, node: NodeAddress) -> Result<(), GroupError> {
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(GroupError::GraphNotFound)?;
+        if !graph.node_input_edges.contains_key(node.key) {
+            return Err(GroupError::NodeNotFound);
+        }
+        graph.remove_node_from_group(node.key);
+        Ok(())
+    }
+    fn remove_node_from_group(&mut self, key: NodeKey) {
+        if let Some(group) = self.node_groups.remove(key) {
+            if let Some(group) = self.groups.get_mut(group) {
+                group.nodes.retain(|&node| node != key);
+            }
+        }
+    }
+    /// The nodes in a group from head to tail
+    pub fn group_nodes(&self, group: GroupAddress) -> Option<Vec<NodeAddress>> {
+        let graph_id = group.graph_id;
+        let group = self.graph_by_id(graph_id)?.groups.get(group.key)?;
+        Some(
+            group
+                .nodes
+                .iter()
+                .map(|&key| NodeAddress { graph_id, key })
+                .collect(),
+        )
+    }
+    /// Free all nodes in a group and remove the group
+    pub fn free_group(&mut self, group: GroupAddress) -> Result<(), GroupError> {
+        let graph = self
+            .graph_by_id_mut(group.graph_id)
+            .ok_or(GroupError::GraphNotFound)?;
+        let removed = graph
+            .groups
+            .remove(group.key)
+            .ok_or(GroupError::GroupNotFound)?;
+        graph.group_order.retain(|&key| key != group.key);
+        for key in removed.nodes {
+            graph.node_groups.remove(key);
+            graph.free_node(NodeAddress {
+                graph_id: group.graph_id,
+                key,
+            })?;
+        }
+        Ok(())
+    }
+    /// Mute or unmute all nodes in a group, see [`Graph::set_node_mute`].
+    /// Nodes added to the group later are not affected.
+    pub fn set_group_mute(&mut self, group: GroupAddress, muted: bool) -> Result<(), GroupError> {
+        let nodes = self.group_nodes(group).ok_or(GroupError::GroupNotFound)?;
+        for node in nodes {
+            self.set_node_mute(node, muted)?;
+        }
+        Ok(())
+    }
+    /// Scale the connections from the nodes in a group to nodes outside of
+    /// it and to the Graph outputs by `gain`. Commit the changes for the gain
+    /// to take effect.
+    pub fn set_group_gain(&mut self, group: GroupAddress, gain: Sample) -> Result<(), GroupError> {
+        self.graph_by_id_mut(group.graph_id)
+            .ok_or(GroupError::GraphNotFound)?
+            .groups
+            .get_mut(group.key)
+            .ok_or(GroupError::GroupNotFound)?
+            .gain = gain;
+        Ok(())
+    }
+    /// Route output channels of a node in this Graph to the monitor outputs,
+    /// see [`GraphSettings::num_monitor_outputs`]. The main outputs aren't
+    /// changed. Adding a tap of the same channels again replaces it, e.g. to
+    /// change its gain. Commit the changes for the tap to take effect.
+    pub fn add_monitor_tap(&mut self, tap: MonitorTap) -> Result<(), ConnectionError> {
+        self.check_monitor_tap(&tap)?;
+        let connection = tap.connection(self.monitor_outputs().start);
+        self.disconnect(connection)?;
+        self.connect(connection)
+    }
+    /// Stop routing the channels of `tap` to the monitor outputs
+    pub fn remove_monitor_tap(&mut self, tap: MonitorTap) -> Result<(), ConnectionError> {
+        self.check_monitor_tap(&tap)?;
+        self.disconnect(tap.connection(self.monitor_outputs().start))
+    }
+    fn check_monitor_tap(&self, tap: &MonitorTap) -> Result<(), ConnectionError> {
+        if tap.node.graph_id != self.id {
+            return Err(ConnectionError::DifferentGraphs);
+        }
+        if tap.to_index + tap.channels > self.num_monitor_outputs {
+            return Err(ConnectionError::ChannelOutOfBounds);
+        }
+        Ok(())
+    }
+    /// The latency in samples at the output of a node in this Graph or a
+    /// Graph inside it: the latency of the node plus the largest latency of
+    /// the nodes it reads from. Feedback connections aren't counted.
+    pub fn node_latency(&self, node: NodeAddress) -> Option<usize> {
+        let graph = self.graph_by_id(node.graph_id)?;
+        if !graph.node_input_edges.contains_key(node.key) {
+            return None;
+        }
+        Some(graph.output_latency(node.key, &mut SecondaryMap::new()))
+    }
+    /// The largest latency at the outputs of the Graph, see
+    /// [`Graph::node_latency`]
+    pub fn latency(&self) -> usize {
+        let mut latencies = SecondaryMap::new();
+        self.output_edges
+            .iter()
+            .map(|edge| self.output_latency(edge.source, &mut latencies))
+            .max()
+            .unwrap_or(0)
+    }
+    /// The latency at the output of a node, memoized in `latencies`
+    fn output_latency(&self, key: NodeKey, latencies: &mut SecondaryMap<NodeKey, usize>) -> usize {
+        if let Some(&latency) = latencies.get(key) {
+            return latency;
+        }
+        let input_latency = self.node_input_edges.get(key).map_or(0, |edges| {
+            edges
+                .iter()
+                .map(|edge| self.output_latency(edge.source, latencies))
+                .max()
+                .unwrap_or(0)
+        });
+        let own_latency = match self.graphs_per_node.get(key) {
+            Some(graph) => graph.latency(),
+            None => self.get_nodes().get(key).map_or(0, |node| node.latency()),
+        };
+        let latency = input_latency + own_latency;
+        latencies.insert(key, latency);
+        latency
+    }
+    /// Line up the latencies of parallel paths, e.g. a dry signal mixed with
+    /// a compressed copy of itself, by inserting [`LatencyCompensation`]
+    /// delays on the connections with less latency. All connections to a
+    /// node get the largest latency of the connections to that node, and all
+    /// graph outputs the largest latency of the graph outputs. Returns the
+    /// number of delays added. Commit the changes for them to take effect.
+    ///
+    /// The added delays are normal nodes, so calling this again after
+    /// latencies have changed can only compensate for latencies that have
+    /// grown.
+    pub fn compensate_latency(&mut self) -> Result<usize, ConnectionError> {
+        let mut latencies = SecondaryMap::new();
+        let mut node_delays = vec![];
+        let mut graph_input_delays = vec![];
+        for (sink, edges) in self.node_input_edges.iter() {
+            if self.node_keys_pending_removal.contains(&sink) {
+                continue;
+            }
+            let edge_latencies: Vec<usize> = edges
+                .iter()
+                .map(|edge| self.output_latency(edge.source, &mut latencies))
+                .collect();
+            let target = edge_latencies.iter().copied().max().unwrap_or(0);
+            for (edge, latency) in edges.iter().zip(edge_latencies) {
+                if latency < target {
+                    node_delays.push((sink, *edge, target - latency));
+                }
+            }
+            if target > 0 {
+                for edge in self.graph_input_edges.get(sink).into_iter().flatten() {
+                    graph_input_delays.push((sink, *edge, target));
+                }
+            }
+        }
+        let output_latencies: Vec<usize> = self
+            .output_edges
+            .iter()
+            .map(|edge| self.output_latency(edge.source, &mut latencies))
+            .collect();
+        let target = output_latencies.iter().copied().max().unwrap_or(0);
+        let output_delays: Vec<_> = self
+            .output_edges
+            .iter()
+            .zip(output_latencies)
+            .filter(|(_, latency)| *latency < target)
+            .map(|(edge, latency)| (*edge, target - latency))
+            .collect();
+
+        let num_delays = node_delays.len() + graph_input_delays.len() + output_delays.len();
+        let graph_id = self.id;
+        let address = |key| NodeAddress { graph_id, key };
+        for (sink, edge, delay) in node_delays {
+            let (source, sink) = (address(edge.source), address(sink));
+            let delay_node = self.push_gen(LatencyCompensation::new(delay));
+            self.disconnect(
+                source
+                    .to(sink)
+                    .from_index(edge.from_output_index)
+                    .to_index(edge.to_input_index),
+            )?;
+            self.connect(
+                source
+                    .to(delay_node)
+                    .from_index(edge.from_output_index)
+                    .gain(edge.gain),
+            )?;
+            self.connect(delay_node.to(sink).to_index(edge.to_input_index))?;
+        }
+        for (sink, edge, delay) in graph_input_delays {
+            let sink = address(sink);
+            let delay_node = self.push_gen(LatencyCompensation::new(delay));
+            self.disconnect(
+                Connection::graph_input(sink)
+                    .from_index(edge.from_output_index)
+                    .to_index(edge.to_input_index),
+            )?;
+            self.connect(
+                Connection::graph_input(delay_node)
+                    .from_index(edge.from_output_index)
+                    .gain(edge.gain),
+            )?;
+            self.connect(delay_node.to(sink).to_index(edge.to_input_index))?;
+        }
+        for (edge, delay) in output_delays {
+            let source = address(edge.source);
+            let delay_node = self.push_gen(LatencyCompensation::new(delay));
+            self.disconnect(
+                Connection::graph_output(source)
+                    .from_index(edge.from_output_index)
+                    .to_index(edge.to_input_index),
+            )?;
+            self.connect(
+                source
+                    .to(delay_node)
+                    .from_index(edge.from_output_index)
+                    .gain(edge.gain),
+            )?;
+            self.connect(Connection::graph_output(delay_node).to_index(edge.to_input_index))?;
+        }
+        Ok(num_delays)
+    }
+    /// The name of a node set using [`Graph::set_node_name`]
+    pub fn node_name(&self, node: NodeAddress) -> Option<&str> {
+        if node.graph_id != self.id {
+            return self
+                .graphs_per_node
+                .values()
+                .find_map(|graph| graph.node_name(node));
+        }
+        self.node_names
+            .iter()
+            .find(|(_, &key)| key == node.key)
+            .map(|(name, _)| name.as_str())
+    }
+    /// Find a node by its name, first in this Graph and then in the Graphs
+    /// inside it.
+    pub fn node_by_name(&self, name: &str) -> Option<NodeAddress> {
+        if let Some(&key) = self.node_names.get(name) {
+            return Some(NodeAddress {
+                graph_id: self.id,
+                key,
+            });
+        }
+        self.graphs_per_node
+            .values()
+            .find_map(|graph| graph.node_by_name(name))
+    }
+    /// Connect an output to an input using the names of the nodes and the
+    /// names of the output and input, e.g.
+    /// `graph.connect_by_name(("osc1", "sig"), ("filter", "in"))`. A number
+    /// can be used instead of the name of an output or input.
+    pub fn connect_by_name(
+        &mut self,
+        from: (&str, &str),
+        to: (&str, &str),
+    ) -> Result<(), ConnectionError> {
+        let connection = self.named_connection(from, to)?;
+        self.connect(connection)
+    }
+    /// Disconnect a connection made using [`Graph::connect_by_name`]
+    pub fn disconnect_by_name(
+        &mut self,
+        from: (&str, &str),
+        to: (&str, &str),
+    ) -> Result<(), ConnectionError> {
+        let connection = self.named_connection(from, to)?;
+        self.disconnect(connection)
+    }
+    /// Create a Connection between named nodes and ports
+    fn named_connection(
+        &self,
+        from: (&str, &str),
+        to: (&str, &str),
+    ) -> Result<Connection, ConnectionError> {
+        let source = self
+            .node_by_name(from.0)
+            .ok_or_else(|| ConnectionError::NodeNameNotFound(from.0.to_string()))?;
+        let sink = self
+            .node_by_name(to.0)
+            .ok_or_else(|| ConnectionError::NodeNameNotFound(to.0.to_string()))?;
+        if source.graph_id != sink.graph_id {
+            return Err(ConnectionError::DifferentGraphs);
+        }
+        let graph = self
+            .graph_by_id(source.graph_id)
+            .ok_or(ConnectionError::GraphNotFound)?;
+        let from_index = from
+            .1
+            .parse::<usize>()
+            .ok()
+            .or_else(|| graph.output_index_from_label(source.key, from.1))
+            .ok_or_else(|| ConnectionError::PortNameNotFound(from.1.to_string()))?;
+        let to_index =
+            to.1.parse::<usize>()
+                .ok()
+                .or_else(|| graph.input_index_from_label(sink.key, to.1))
+                .ok_or_else(|| ConnectionError::PortNameNotFound(to.1.to_string()))?;
+        Ok(source.to(sink).from_index(from_index).to_index(to_index))
+    }
+    /// The number of inputs of a node in this Graph or a Graph inside it
+    pub fn node_num_inputs(&self, node: NodeAddress) -> Option<usize> {
+        self.graph_by_id(node.graph_id)?
+            .node_input_index_to_name
+            .get(node.key)
+            .map(|names| names.len())
+    }
+    /// What an input of a node in this Graph or a Graph inside it is used
+    /// for, see [`Gen::input_kind`]
+    pub fn node_input_kind(&self, node: NodeAddress, input: usize) -> Option<InputKind> {
+        let graph = self.graph_by_id(node.graph_id)?;
+        let num_inputs = graph.node_input_index_to_name.get(node.key)?.len();
+        if input >= num_inputs {
+            return None;
+        }
+        graph
+            .get_nodes()
+            .get(node.key)
+            .map(|node| node.input_kind(input))
+    }
+    /// Set how the connections to an input of a node in this Graph or a Graph
+    /// inside it are combined. With any mode other than [`InputMode::Add`]
+    /// the constant value of the input is only used while nothing is
+    /// connected to it. Commit the changes for the mode to take effect.
+    pub fn set_input_mode(
+        &mut self,
+        node: NodeAddress,
+        input: usize,
+        mode: InputMode,
+    ) -> Result<(), ConnectionError> {
+        let graph = self
+            .graph_by_id_mut(node.graph_id)
+            .ok_or(ConnectionError::GraphNotFound)?;
+        let modes = graph
+            .node_input_modes
+            .get_mut(node.key)
+            .ok_or(ConnectionError::NodeNotFound)?;
+        *modes
+            .get_mut(input)
+            .ok_or(ConnectionError::ChannelOutOfBounds)? = mode;
+        Ok(())
+    }
+    /// How the connections to an input of a node are combined, see
+    /// [`Graph::set_input_mode`]
+    pub fn input_mode(&self, node: NodeAddress, input: usize) -> Option<InputMode> {
+        self.graph_by_id(node.graph_id)?
+            .node_input_modes
+            .get(node.key)?
+            .get(input)
+            .copied()
+    }
+    /// *Allocates memory*
+    /// Check this Graph and the Graphs inside it for likely mistakes before
+    /// running it. Returns every problem found:
+    ///
+    /// - inputs that are required, see [`Gen::input_required`], but have
+    ///   nothing connected to them
+    /// - nodes with several outputs connected only to one node, or only to
+    ///   the Graph outputs, using fewer outputs than there are outputs and
+    ///   [`InputKind::Main`] inputs to connect them to
+    /// - nodes connected in a cycle without a feedback connection
+    /// - resources used by a Gen, see [`Gen::resource_refs`], that aren't in
+    ///   `resources`
+    pub fn validate(&self, resources: &Resources) -> Result<(), Vec<ValidationError>> {
+        let mut errors = vec![];
+        self.validate_into(resources, &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    fn validate_into(&self, resources: &Resources, errors: &mut Vec<ValidationError>) {
+        let nodes = self.get_nodes();
+        let graph_id = self.id;
+        let address = |key| NodeAddress { graph_id, key };
+        let keys: Vec<NodeKey> = nodes
+            .keys()
+            .filter(|key| {
+                self.node_input_edges.contains_key(*key)
+                    && !self.node_keys_pending_removal.contains(key)
+                    && !self.feedback_node_indices.contains(key)
+            })
+            .collect();
+        // Where the outputs of every node go, None for the Graph outputs
+        let mut sinks: SecondaryMap<NodeKey, Vec<(Option<NodeKey>, usize)>> = SecondaryMap::new();
+        for &sink in &keys {
+            for edge in &self.node_input_edges[sink] {
+                if let Some(list) = sinks.entry(edge.source) {
+                    list.or_default().push((Some(sink), edge.from_output_index));
+                }
+            }
+        }
+        let num_main_outputs = self.monitor_outputs().start;
+        for edge in &self.output_edges {
+            if edge.to_input_index < num_main_outputs {
+                if let Some(list) = sinks.entry(edge.source) {
+                    list.or_default().push((None, edge.from_output_index));
+                }
+            }
+        }
+        for &key in &keys {
+            let node = &nodes[key];
+            for input in (0..node.num_inputs()).filter(|&input| node.input_required(input)) {
+                let connected = self.node_input_edges[key]
+                    .iter()
+                    .chain(self.graph_input_edges.get(key).into_iter().flatten())
+                    .any(|edge| edge.to_input_index == input)
+                    || self
+                        .node_feedback_edges
+                        .get(key)
+                        .into_iter()
+                        .flatten()
+                        .any(|edge| edge.to_input_index == input);
+                if !connected {
+                    errors.push(ValidationError::UnconnectedInput {
+                        node: address(key),
+                        input,
+                        label: self.node_input_index_to_name[key][input],
+                    });
+                }
+            }
+            let outputs = node.num_outputs();
+            if let Some(node_sinks) = sinks.get(key).filter(|_| outputs > 1) {
+                let sink = node_sinks[0].0;
+                if node_sinks.iter().all(|(other, _)| *other == sink) {
+                    let connected = node_sinks
+                        .iter()
+                        .map(|(_, output)| *output)
+                        .collect::<HashSet<_>>()
+                        .len();
+                    match sink {
+                        Some(sink) => {
+                            let sink_node = &nodes[sink];
+                            let expected = (0..sink_node.num_inputs())
+                                .filter(|&input| sink_node.input_kind(input) == InputKind::Main)
+                                .count()
+                                .min(outputs);
+                            if connected < expected {
+                                errors.push(ValidationError::ChannelMismatch {
+                                    from: address(key),
+                                    sink: address(sink),
+                                    outputs,
+                                    connected,
+                                    expected,
+                                });
+                            }
+                        }
+                        None => {
+                            let expected = num_main_outputs.min(outputs);
+                            if connected < expected {
+                                errors.push(ValidationError::OutputChannelMismatch {
+                                    from: address(key),
+                                    outputs,
+                                    connected,
+                                    expected,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+            for resource in node.gen.resource_refs() {
+                let exists = match resource {
+                    ResourceRef::Buffer(key) => resources.buffers.contains_key(key),
+                    ResourceRef::Wavetable(key) => resources.wavetables.contains_key(key),
+                    ResourceRef::LookupTable(key) => resources.lookup_tables.contains_key(key),
+                };
+                if !exists {
+                    errors.push(ValidationError::MissingResource {
+                        node: address(key),
+                        resource,
+                    });
+                }
+            }
+        }
+        for cycle in strongly_connected_nodes(&keys, &self.node_input_edges) {
+            errors.push(ValidationError::Cycle {
+                nodes: cycle.into_iter().map(address).collect(),
+            });
+        }
+        for (_key, graph) in &self.graphs_per_node {
+            graph.validate_into(resources, errors);
+        }
+    }
+    /// True if the node exists in this Graph or a Graph inside it and hasn't
+    /// been freed
+    pub fn contains_node(&self, node: NodeAddress) -> bool {
+        self.graph_by_id(node.graph_id)
+            .is_some_and(|graph| graph.node_input_edges.contains_key(node.key))
+    }
+    /// The number of outputs of a node in this Graph or a Graph inside it
+    pub fn node_num_outputs(&self, node: NodeAddress) -> Option<usize> {
+        self.graph_by_id(node.graph_id)?
+            .node_output_index_to_name
+            .get(node.key)
+            .map(|names| names.len())
+    }
+    fn node_num_outputs_by_key(&self, key: NodeKey) -> Result<usize, ConnectionError> {
+        self.node_output_index_to_name
+            .get(key)
+            .map(|names| names.len())
+            .ok_or(ConnectionError::NodeNotFound)
+    }
+    /// Find this Graph or a Graph inside it
+    fn graph_by_id(&self, id: GraphId) -> Option<&Graph> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.graphs_per_node
+            .values()
+            .find_map(|graph| graph.graph_by_id(id))
+    }
+    fn graph_by_id_mut(&mut self, id: GraphId) -> Option<&mut Graph> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.graphs_per_node
+            .values_mut()
+            .find_map(|graph| graph.graph_by_id_mut(id))
+    }
+    fn input_index_from_label(&self, node: NodeKey, label: &str) -> Option<usize> {
         if let Some(&index) = self
             .node_input_name_to_index
             .get(node)
@@ -1928,7 +3787,7 @@ impl Graph {
             None
         }
     }
-    fn output_index_from_label(&self, node: NodeKey, label: &'static str) -> Option<usize> {
+    fn output_index_from_label(&self, node: NodeKey, label: &str) -> Option<usize> {
         if let Some(&index) = self
             .node_output_name_to_index
             .get(node)
@@ -1945,20 +3804,37 @@ impl Graph {
         visited: &mut HashSet<NodeKey>,
         nodes_to_process: &mut Vec<NodeKey>,
     ) -> Vec<NodeKey> {
-        let mut stack = Vec::with_capacity(self.get_nodes().capacity());
-        while !nodes_to_process.is_empty() {
-            let node_index = nodes_to_process.pop().unwrap();
-            stack.push(node_index);
-            // cloning the input edges here to avoid unsafe
-            let input_edges = &self.node_input_edges[node_index];
-            for edge in input_edges {
+        // A node is only finished after all its inputs are, so that a node
+        // reached through several paths, e.g. a source with both a direct
+        // connection and a send to an effect, still comes before everything
+        // depending on it. The starting nodes have been marked as visited by
+        // the caller to avoid duplicates, but still need to be expanded.
+        for node in nodes_to_process.iter() {
+            visited.remove(node);
+        }
+        let mut finished = Vec::with_capacity(self.get_nodes().capacity());
+        let mut to_visit: Vec<(NodeKey, bool)> = nodes_to_process
+            .drain(..)
+            .map(|node| (node, false))
+            .collect();
+        while let Some((node_index, expanded)) = to_visit.pop() {
+            if expanded {
+                finished.push(node_index);
+                continue;
+            }
+            if !visited.insert(node_index) {
+                continue;
+            }
+            to_visit.push((node_index, true));
+            for edge in &self.node_input_edges[node_index] {
                 if !visited.contains(&edge.source) {
-                    nodes_to_process.push(edge.source);
-                    visited.insert(edge.source);
+                    to_visit.push((edge.source, false));
                 }
             }
         }
-        stack
+        // Reversed to match the order in which the callers expect the stack
+        finished.reverse();
+        finished
     }
     fn get_deepest_output_node(&self, start_node: NodeKey, visited: &HashSet<NodeKey>) -> NodeKey {
         let mut last_connected_node_index = start_node;
@@ -2075,71 +3951,575 @@ impl Graph {
         }
         self.node_order.extend(remaining_nodes.iter());
         self.disconnected_nodes = remaining_nodes;
-    }
-    pub fn block_size(&self) -> usize {
-        self.block_size
-    }
-    pub fn num_nodes(&self) -> usize {
-        self.get_nodes().len()
-    }
+        self.sort_node_order_by_constraints();
 
+        if self.parallel.is_some() {
+            self.sort_node_order_into_stages();
+        }
+    }
+    /// Reorder the nodes so that the nodes in groups run in the order of the
+    /// groups and from head to tail within a group, and so that the pairs in
+    /// `run_order` run in order, as far as the connections allow. Otherwise
+    /// the order is kept.
     /// NB: Not real time safe
-    fn generate_tasks(&mut self) -> Vec<Task> {
-        let mut tasks = vec![];
-        // Safety: No other thread will access the SlotMap. All we're doing with the buffers is taking pointers; there's no manipulation.
-        let nodes = unsafe { &mut *self.nodes.get() };
-        let inputs_buffers = self.inputs_buffers.as_mut_slice();
-        for &node_key in &self.node_order {
-            // Collect inputs into the node's input buffer
-            let input_edges = &self.node_input_edges[node_key];
-            let graph_input_edges = &self.graph_input_edges[node_key];
-            let feedback_input_edges = &self.node_feedback_edges[node_key];
-
-            let mut inputs_to_copy = vec![];
-            let mut graph_inputs_to_copy = vec![];
-
-            for input_edge in input_edges {
-                let source = &nodes[input_edge.source];
-                let output_values = &source.output_buffers[input_edge.from_output_index];
-                let input_buffer = &mut inputs_buffers[input_edge.to_input_index];
-                for i in 0..self.block_size {
-                    inputs_to_copy.push((
-                        &output_values[i] as *const Sample,
-                        &mut input_buffer[i] as *mut Sample,
-                    ));
-                }
+    fn sort_node_order_by_constraints(&mut self) {
+        let group_nodes = self.group_node_order();
+        if group_nodes.len() < 2 && self.run_order.is_empty() {
+            return;
+        }
+        let num_nodes = self.node_order.len();
+        let mut positions = SecondaryMap::with_capacity(num_nodes);
+        let mut num_dependencies = SecondaryMap::with_capacity(num_nodes);
+        let mut dependents: SecondaryMap<NodeKey, Vec<NodeKey>> =
+            SecondaryMap::with_capacity(num_nodes);
+        for (position, &key) in self.node_order.iter().enumerate() {
+            positions.insert(key, position);
+            num_dependencies.insert(key, 0);
+            dependents.insert(key, vec![]);
+        }
+        let mut add_dependency = |source: NodeKey, sink: NodeKey| {
+            if positions.contains_key(source) && positions.contains_key(sink) {
+                dependents[source].push(sink);
+                num_dependencies[sink] += 1;
             }
-            for input_edge in graph_input_edges {
-                let input_buffer = &mut inputs_buffers[input_edge.to_input_index];
-                graph_inputs_to_copy.push((
-                    input_buffer as *mut Box<[Sample]>,
-                    input_edge.from_output_index,
-                ));
+        };
+        for &key in &self.node_order {
+            // Feedback nodes read the previous block and always run first
+            if self.feedback_node_indices.contains(&key) {
+                continue;
             }
-            // Add feedback input edges. This will read the previous value from the Node, provided this Node is before that Node.
-            for feedback_edge in feedback_input_edges {
-                let source = &nodes[feedback_edge.source];
-                let output_values = &source.output_buffers[feedback_edge.from_output_index];
-                let input_buffer = &mut inputs_buffers[feedback_edge.to_input_index];
-                for i in 0..self.block_size {
-                    inputs_to_copy.push((
-                        &output_values[i] as *const Sample,
-                        &mut input_buffer[i] as *mut Sample,
-                    ));
+            for edge in self.node_input_edges.get(key).into_iter().flatten() {
+                add_dependency(edge.source, key);
+            }
+        }
+        for pair in group_nodes.windows(2) {
+            add_dependency(pair[0], pair[1]);
+        }
+        for &(first, then) in &self.run_order {
+            add_dependency(first, then);
+        }
+        let mut ready: BinaryHeap<Reverse<usize>> = self
+            .node_order
+            .iter()
+            .filter(|&&key| num_dependencies[key] == 0)
+            .map(|&key| Reverse(positions[key]))
+            .collect();
+        let mut added = vec![false; num_nodes];
+        let mut order = Vec::with_capacity(num_nodes);
+        while order.len() < num_nodes {
+            let position = match ready.pop() {
+                Some(Reverse(position)) => position,
+                // The groups or the run order contradict the connections,
+                // or there is a cycle. Continue from the earliest node left.
+                None => added.iter().position(|added| !added).unwrap(),
+            };
+            if added[position] {
+                continue;
+            }
+            added[position] = true;
+            let key = self.node_order[position];
+            order.push(key);
+            for &dependent in &dependents[key] {
+                let remaining = &mut num_dependencies[dependent];
+                *remaining -= 1;
+                if *remaining == 0 {
+                    ready.push(Reverse(positions[dependent]));
                 }
             }
-            tasks.push(Task {
-                node_ptr: &mut nodes[node_key] as *mut Node,
-                node_key,
-                inputs_to_copy,
-                graph_inputs_to_copy,
-                input_buffers_ptr: inputs_buffers.as_mut_ptr(),
-                num_inputs: inputs_buffers.len(),
-            });
         }
-        tasks
+        self.node_order = order;
     }
-    fn generate_output_tasks(&mut self) -> Vec<OutputTask> {
+    /// The nodes in all groups in the order they should run in
+    fn group_node_order(&self) -> Vec<NodeKey> {
+        self.group_order
+            .iter()
+            .flat_map(|&group| self.groups[group].nodes.iter().copied())
+            .collect()
+    }
+    /// Sort the node order so that nodes that can run in parallel are next to
+    /// each other and store the length of every stage. A node always ends up
+    /// in a later stage than the nodes it reads from so that the result is
+    /// the same as when processing in the original order. Nodes in groups
+    /// also end up in a later stage than the node before them in the groups,
+    /// and the second node of a pair in `run_order` in a later stage than
+    /// the first.
+    /// NB: Not real time safe
+    fn sort_node_order_into_stages(&mut self) {
+        let mut stages: SecondaryMap<NodeKey, usize> =
+            SecondaryMap::with_capacity(self.node_order.len());
+        let mut run_after: SecondaryMap<NodeKey, Vec<NodeKey>> = SecondaryMap::new();
+        for (first, then) in self
+            .group_node_order()
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .chain(self.run_order.iter().copied())
+        {
+            run_after.entry(then).unwrap().or_default().push(first);
+        }
+        let mut min_stages: SecondaryMap<NodeKey, usize> = SecondaryMap::new();
+        let mut later_sources = vec![];
+        for &key in &self.node_order {
+            let mut stage = min_stages.get(key).copied().unwrap_or(0);
+            later_sources.clear();
+            let sources = self.node_input_edges[key]
+                .iter()
+                .map(|edge| edge.source)
+                .chain(self.node_feedback_edges[key].iter().map(|edge| edge.source))
+                .chain(run_after.get(key).into_iter().flatten().copied());
+            for source in sources {
+                match stages.get(source) {
+                    Some(&source_stage) => stage = stage.max(source_stage + 1),
+                    // The source runs after this node in the node order,
+                    // e.g. for feedback. It must not run in the same stage.
+                    None => later_sources.push(source),
+                }
+            }
+            for &source in &later_sources {
+                let min_stage = min_stages.entry(source).unwrap().or_insert(0);
+                *min_stage = (*min_stage).max(stage + 1);
+            }
+            stages.insert(key, stage);
+        }
+        // The sort is stable so the order within a stage is kept
+        self.node_order.sort_by_key(|&key| stages[key]);
+        self.stage_lengths.clear();
+        let mut last_stage = None;
+        for &key in &self.node_order {
+            if last_stage == Some(stages[key]) {
+                *self.stage_lengths.last_mut().unwrap() += 1;
+            } else {
+                self.stage_lengths.push(1);
+                last_stage = Some(stages[key]);
+            }
+        }
+    }
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+    pub fn num_inputs(&self) -> usize {
+        self.num_inputs
+    }
+    /// The number of outputs, including the monitor outputs
+    pub fn num_outputs(&self) -> usize {
+        self.num_outputs
+    }
+    /// The indices of the monitor outputs among the outputs of the Graph
+    pub fn monitor_outputs(&self) -> std::ops::Range<usize> {
+        self.num_outputs - self.num_monitor_outputs..self.num_outputs
+    }
+    /// The sample rate of the Graph. When the Graph is running this is
+    /// updated by [`Graph::update`] after the sample rate has changed on the
+    /// audio thread.
+    pub fn sample_rate(&self) -> Sample {
+        self.sample_rate
+    }
+    pub fn num_nodes(&self) -> usize {
+        self.get_nodes().len()
+    }
+    /// *Allocates memory*
+    /// The process times of the nodes in this Graph and all its inner Graphs,
+    /// and the DSP load if this is the top level Graph. See the
+    /// [`profiling`](crate::profiling) module.
+    #[cfg(feature = "profiling")]
+    pub fn profiling_report(&self) -> crate::profiling::ProfilingReport {
+        let mut nodes = vec![];
+        self.collect_node_profiles(&mut nodes);
+        nodes.sort_by_key(|n| std::cmp::Reverse(n.average));
+        let block_duration = self.block_size as f64 / sample_to_f64(self.sample_rate);
+        let (average_load, max_load) = match &self.graph_times {
+            Some(times) => (
+                times.average().as_secs_f64() / block_duration,
+                times.max().as_secs_f64() / block_duration,
+            ),
+            None => (0.0, 0.0),
+        };
+        crate::profiling::ProfilingReport {
+            nodes,
+            average_load,
+            max_load,
+        }
+    }
+    #[cfg(feature = "profiling")]
+    fn collect_node_profiles(&self, nodes: &mut Vec<crate::profiling::NodeProfile>) {
+        for (key, (name, times)) in &self.node_times {
+            nodes.push(crate::profiling::NodeProfile {
+                address: NodeAddress {
+                    graph_id: self.id,
+                    key,
+                },
+                name,
+                calls: times.calls(),
+                average: times.average(),
+                max: times.max(),
+            });
+        }
+        for (_key, graph) in &self.graphs_per_node {
+            graph.collect_node_profiles(nodes);
+        }
+    }
+    /// Start measuring process times and the DSP load from scratch, in this
+    /// Graph and all its inner Graphs
+    #[cfg(feature = "profiling")]
+    pub fn reset_profiling(&self) {
+        for (_key, (_name, times)) in &self.node_times {
+            times.reset();
+        }
+        if let Some(times) = &self.graph_times {
+            times.reset();
+        }
+        for (_key, graph) in &self.graphs_per_node {
+            graph.reset_profiling();
+        }
+    }
+    /// The keys of the nodes in the order they appear in a [`Patch`], leaving
+    /// out feedback nodes and nodes that are being freed.
+    fn patch_node_keys(&self) -> Vec<NodeKey> {
+        self.get_nodes()
+            .keys()
+            .filter(|key| {
+                !self.feedback_node_indices.contains(key)
+                    && !self.node_keys_pending_removal.contains(key)
+            })
+            .collect()
+    }
+    /// Describe the nodes and connections of this Graph in the GraphViz DOT
+    /// format, e.g. to render it with `dot -Tsvg`. Graphs inside this Graph
+    /// are drawn as clusters, feedback connections as dashed lines and input
+    /// constants that aren't 0 next to the input label.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph {\n    rankdir=LR;\n    node [shape=record];\n");
+        self.write_dot(&mut dot, 1);
+        dot.push_str("}\n");
+        dot
+    }
+    fn write_dot(&self, dot: &mut String, depth: usize) {
+        use std::fmt::Write;
+        // Characters with a special meaning in record labels
+        fn escape(label: &str) -> String {
+            let mut escaped = String::with_capacity(label.len());
+            for c in label.chars() {
+                if matches!(c, '|' | '{' | '}' | '<' | '>' | '"' | '\\') {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            escaped
+        }
+        fn ports(prefix: &str, labels: impl Iterator<Item = String>) -> String {
+            labels
+                .enumerate()
+                .map(|(i, label)| format!("<{prefix}{i}> {label}"))
+                .collect::<Vec<_>>()
+                .join("|")
+        }
+        let indent = "    ".repeat(depth);
+        let id = self.id;
+        let keys = self.patch_node_keys();
+        let patch = self.to_patch();
+        if self.num_inputs > 0 {
+            let inputs = ports("p", (0..self.num_inputs).map(|i| i.to_string()));
+            writeln!(dot, "{indent}g{id}_in [label=\"{{in|{{{inputs}}}}}\"];").unwrap();
+        }
+        if self.num_outputs > 0 {
+            let outputs = ports("p", (0..self.num_outputs).map(|i| i.to_string()));
+            writeln!(dot, "{indent}g{id}_out [label=\"{{{{{outputs}}}|out}}\"];").unwrap();
+        }
+        for (index, (&key, node)) in keys.iter().zip(&patch.nodes).enumerate() {
+            let title = match &node.name {
+                Some(name) => format!("{name}: {}", node.gen),
+                None => node.gen.clone(),
+            };
+            if let Some(graph) = self.graphs_per_node.get(key) {
+                writeln!(dot, "{indent}subgraph cluster_g{} {{", graph.id).unwrap();
+                writeln!(dot, "{indent}    label=\"{}\";", title.replace('"', "\\\"")).unwrap();
+                graph.write_dot(dot, depth + 1);
+                writeln!(dot, "{indent}}}").unwrap();
+                continue;
+            }
+            let inputs = ports(
+                "i",
+                self.node_input_index_to_name[key]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &name)| {
+                        let name = if name.is_empty() {
+                            i.to_string()
+                        } else {
+                            escape(name)
+                        };
+                        match node.constants.get(i) {
+                            Some(&value) if value != 0.0 => format!("{name} = {value}"),
+                            _ => name,
+                        }
+                    }),
+            );
+            let outputs = ports(
+                "o",
+                self.node_output_index_to_name[key]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &name)| {
+                        if name.is_empty() {
+                            i.to_string()
+                        } else {
+                            escape(name)
+                        }
+                    }),
+            );
+            writeln!(
+                dot,
+                "{indent}g{id}_n{index} [label=\"{{{{{inputs}}}|{}|{{{outputs}}}}}\"];",
+                escape(&title)
+            )
+            .unwrap();
+        }
+        let inner_graph_id = |node: usize| self.graphs_per_node.get(keys[node]).map(|g| g.id);
+        for edge in &patch.edges {
+            let source = match edge.source {
+                PatchSource::Node { node, output } => match inner_graph_id(node) {
+                    Some(inner) => format!("g{inner}_out:p{output}"),
+                    None => format!("g{id}_n{node}:o{output}"),
+                },
+                PatchSource::GraphInput(input) => format!("g{id}_in:p{input}"),
+            };
+            let sink = match edge.sink {
+                PatchSink::Node { node, input } => match inner_graph_id(node) {
+                    Some(inner) => format!("g{inner}_in:p{input}"),
+                    None => format!("g{id}_n{node}:i{input}"),
+                },
+                PatchSink::GraphOutput(output) => format!("g{id}_out:p{output}"),
+            };
+            let mut attributes = vec![];
+            if edge.feedback {
+                attributes.push("style=dashed".to_string());
+            }
+            if edge.gain != 1.0 {
+                attributes.push(format!("label=\"×{}\"", edge.gain));
+            }
+            let attributes = if attributes.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", attributes.join(", "))
+            };
+            writeln!(dot, "{indent}{source} -> {sink}{attributes};").unwrap();
+        }
+    }
+    /// Describe the structure of this Graph and all Graphs inside it as a
+    /// [`Patch`] that can be saved and turned back into a Graph using
+    /// [`Patch::to_graph`].
+    ///
+    /// Nodes are stored by their name, which is the name of the Gen for nodes
+    /// added using [`Graph::push_gen`]. Constants are the latest values set
+    /// through this Graph, including scheduled changes that may not have
+    /// been applied yet.
+    pub fn to_patch(&self) -> Patch {
+        let nodes = self.get_nodes();
+        let mut patch_indices = SecondaryMap::with_capacity(nodes.len());
+        let mut patch_nodes = vec![];
+        for key in self.patch_node_keys() {
+            let node = &nodes[key];
+            patch_indices.insert(key, patch_nodes.len());
+            patch_nodes.push(PatchNode {
+                gen: node.name().to_string(),
+                name: self
+                    .node_names
+                    .iter()
+                    .find(|(_, &named)| named == key)
+                    .map(|(name, _)| name.clone()),
+                constants: self.node_constants.get(key).cloned().unwrap_or_default(),
+                smoothing: self.node_smoothing.get(key).cloned().unwrap_or_default(),
+                input_modes: self.node_input_modes.get(key).cloned().unwrap_or_default(),
+                resources: node.gen.resource_refs(),
+                state: node.gen.save_state(),
+                sidechain_inputs: (0..node.num_inputs())
+                    .filter(|&input| node.input_kind(input) == InputKind::Sidechain)
+                    .collect(),
+                graph: self
+                    .graphs_per_node
+                    .get(key)
+                    .map(|graph| Box::new(graph.to_patch())),
+            });
+        }
+        let mut edges = vec![];
+        for (key, &sink) in &patch_indices {
+            for edge in self.node_input_edges.get(key).into_iter().flatten() {
+                // Edges from a feedback node are stored as feedback edges from
+                // the node the feedback node is buffering.
+                let (source, feedback) = if self.feedback_node_indices.contains(&edge.source) {
+                    match self
+                        .node_feedback_node_key
+                        .iter()
+                        .find(|(_, &feedback_node)| feedback_node == edge.source)
+                    {
+                        Some((source, _)) => (source, true),
+                        None => continue,
+                    }
+                } else {
+                    (edge.source, false)
+                };
+                if let Some(&source) = patch_indices.get(source) {
+                    edges.push(PatchEdge {
+                        source: PatchSource::Node {
+                            node: source,
+                            output: edge.from_output_index,
+                        },
+                        sink: PatchSink::Node {
+                            node: sink,
+                            input: edge.to_input_index,
+                        },
+                        feedback,
+                        gain: edge.gain,
+                    });
+                }
+            }
+            for edge in self.graph_input_edges.get(key).into_iter().flatten() {
+                edges.push(PatchEdge {
+                    source: PatchSource::GraphInput(edge.from_output_index),
+                    sink: PatchSink::Node {
+                        node: sink,
+                        input: edge.to_input_index,
+                    },
+                    feedback: false,
+                    gain: edge.gain,
+                });
+            }
+        }
+        for edge in &self.output_edges {
+            if let Some(&source) = patch_indices.get(edge.source) {
+                edges.push(PatchEdge {
+                    source: PatchSource::Node {
+                        node: source,
+                        output: edge.from_output_index,
+                    },
+                    sink: PatchSink::GraphOutput(edge.to_input_index),
+                    feedback: false,
+                    gain: edge.gain,
+                });
+            }
+        }
+        Patch {
+            num_inputs: self.num_inputs,
+            num_outputs: self.num_outputs,
+            block_size: self.block_size,
+            nodes: patch_nodes,
+            edges,
+        }
+    }
+
+    /// NB: Not real time safe
+    fn generate_tasks(&mut self) -> Vec<Task> {
+        let mut tasks = vec![];
+        // Safety: No other thread will access the SlotMap. All we're doing with the buffers is taking pointers; there's no manipulation.
+        let nodes = unsafe { &mut *self.nodes.get() };
+        let shared_inputs_buffers = self.inputs_buffers.as_mut_slice();
+        for &node_key in &self.node_order {
+            // Only the first sample of a control rate input is used
+            let input_samples: Vec<usize> = (0..nodes[node_key].num_inputs())
+                .map(
+                    |input_index| match nodes[node_key].input_rate(input_index) {
+                        Rate::Audio => self.block_size,
+                        Rate::Control | Rate::SmoothControl => 1,
+                    },
+                )
+                .collect();
+            let inputs_buffers: &mut [Box<[Sample]>] = if self.parallel.is_some() {
+                // Nodes that may run at the same time need their own input buffers.
+                // Safety: The node doesn't read from its own outputs so the
+                // input buffers don't alias the output buffers we take pointers to below.
+                unsafe { &mut (*(&mut nodes[node_key] as *mut Node)).input_buffers }
+            } else {
+                &mut *shared_inputs_buffers
+            };
+            // Collect inputs into the node's input buffer
+            let input_edges = &self.node_input_edges[node_key];
+            let graph_input_edges = &self.graph_input_edges[node_key];
+            let feedback_input_edges = &self.node_feedback_edges[node_key];
+            let input_modes = &self.node_input_modes[node_key];
+            // The ops depend on the order the connections are applied in
+            // Task::run: node edges, feedback edges and then graph inputs.
+            let mut connected = vec![false; input_modes.len()];
+
+            let mut inputs_to_copy = vec![];
+            let mut graph_inputs_to_copy = vec![];
+            let mut connected_inputs = vec![false; nodes[node_key].num_inputs()];
+            for input in input_edges
+                .iter()
+                .chain(graph_input_edges)
+                .map(|edge| edge.to_input_index)
+                .chain(feedback_input_edges.iter().map(|edge| edge.to_input_index))
+            {
+                connected_inputs[input] = true;
+            }
+
+            for input_edge in input_edges {
+                let source = &nodes[input_edge.source];
+                let output_values = &source.output_buffers[input_edge.from_output_index];
+                let input_buffer = &mut inputs_buffers[input_edge.to_input_index];
+                let op = CombineOp::next(
+                    input_modes[input_edge.to_input_index],
+                    &mut connected[input_edge.to_input_index],
+                );
+                let gain = input_edge.gain
+                    * group_gain(
+                        &self.groups,
+                        &self.node_groups,
+                        input_edge.source,
+                        Some(node_key),
+                    );
+                for i in 0..input_samples[input_edge.to_input_index] {
+                    inputs_to_copy.push((
+                        &output_values[i] as *const Sample,
+                        &mut input_buffer[i] as *mut Sample,
+                        gain,
+                        op,
+                    ));
+                }
+            }
+            // Add feedback input edges. This will read the previous value from the Node, provided this Node is before that Node.
+            for feedback_edge in feedback_input_edges {
+                let source = &nodes[feedback_edge.source];
+                let output_values = &source.output_buffers[feedback_edge.from_output_index];
+                let input_buffer = &mut inputs_buffers[feedback_edge.to_input_index];
+                let op = CombineOp::next(
+                    input_modes[feedback_edge.to_input_index],
+                    &mut connected[feedback_edge.to_input_index],
+                );
+                for i in 0..input_samples[feedback_edge.to_input_index] {
+                    inputs_to_copy.push((
+                        &output_values[i] as *const Sample,
+                        &mut input_buffer[i] as *mut Sample,
+                        1.0,
+                        op,
+                    ));
+                }
+            }
+            for input_edge in graph_input_edges {
+                let input_buffer = &mut inputs_buffers[input_edge.to_input_index];
+                let op = CombineOp::next(
+                    input_modes[input_edge.to_input_index],
+                    &mut connected[input_edge.to_input_index],
+                );
+                graph_inputs_to_copy.push((
+                    input_buffer as *mut Box<[Sample]>,
+                    input_edge.from_output_index,
+                    input_edge.gain,
+                    op,
+                ));
+            }
+            tasks.push(Task {
+                node_ptr: &mut nodes[node_key] as *mut Node,
+                node_key,
+                inputs_to_copy,
+                graph_inputs_to_copy,
+                connected_inputs,
+                input_buffers_ptr: inputs_buffers.as_mut_ptr(),
+                num_inputs: inputs_buffers.len(),
+                state: GenState::Continue,
+            });
+        }
+        tasks
+    }
+    fn generate_output_tasks(&mut self) -> Vec<OutputTask> {
         let mut output_tasks = vec![];
         for output_edge in &self.output_edges {
             let source = &self.get_nodes()[output_edge.source];
@@ -2148,17 +4528,20 @@ impl Graph {
             output_tasks.push(OutputTask {
                 input_buffer_ptr: output_values as *const Box<[Sample]>,
                 graph_output_index,
+                gain: output_edge.gain
+                    * group_gain(&self.groups, &self.node_groups, output_edge.source, None),
             });
         }
         output_tasks
     }
     /// Only one GraphGen can be created from a Graph, since otherwise nodes in
     /// the graph could be run multiple times.
-    fn create_graph_gen(&mut self) -> Result<GraphGen, String> {
+    ///
+    /// Only the top level GraphGen gets a transport and updates the
+    /// [`crate::scheduling::TransportSnapshot`] in [`Resources`].
+    fn create_graph_gen(&mut self, top_level: bool) -> Result<GraphGen, ToNodeError> {
         if self.graph_gen_communicator.is_some() {
-            return Err(
-                "create_graph_gen: GraphGenCommunicator already existed for this graph".to_owned(),
-            );
+            return Err(ToNodeError::GraphAlreadyRunning);
         }
         self.init();
         let tasks = self.generate_tasks().into_boxed_slice();
@@ -2166,6 +4549,8 @@ impl Graph {
         let task_data = TaskData {
             tasks,
             output_tasks,
+            stages: self.stage_lengths.clone().into_boxed_slice(),
+            apply_at: 0,
         };
         // let task_data = Box::into_raw(Box::new(task_data));
         // let task_data_ptr = Arc::new(AtomicPtr::new(task_data));
@@ -2175,7 +4560,32 @@ impl Graph {
             RingBuffer::<TaskData>::new(self.ring_buffer_size);
         let (task_data_to_be_dropped_producer, task_data_to_be_dropped_consumer) =
             RingBuffer::<TaskData>::new(self.ring_buffer_size);
+        let (sample_rate_producer, sample_rate_consumer) = RingBuffer::new(self.ring_buffer_size);
+        let (gen_replacements_producer, gen_replacements_consumer) =
+            RingBuffer::new(self.ring_buffer_size);
+        let (replaced_gens_producer, replaced_gens_consumer) =
+            RingBuffer::new(self.ring_buffer_size);
         let (scheduler, schedule_receiver) = Scheduler::new(self.sample_rate, 300, self.latency);
+        let (transport_control, graph_gen_transport) = if top_level {
+            let timeline =
+                TransportTimeline::new(MusicalTimeMap::new(), sample_to_f64(self.sample_rate));
+            let (producer, consumer) = RingBuffer::new(self.ring_buffer_size);
+            let (applied_at_producer, applied_at_consumer) = RingBuffer::new(self.ring_buffer_size);
+            (
+                Some(TransportControl::new(
+                    timeline.clone(),
+                    producer,
+                    applied_at_consumer,
+                )),
+                Some(GraphGenTransport {
+                    timeline,
+                    consumer,
+                    applied_at: applied_at_producer,
+                }),
+            )
+        } else {
+            (None, None)
+        };
 
         let graph_gen_communicator = GraphGenCommunicator {
             generation: Arc::new(AtomicU16::new(0)),
@@ -2184,6 +4594,10 @@ impl Graph {
             task_data_to_be_dropped_consumer,
             new_task_data_producer,
             timestamp: Arc::new(AtomicU64::new(0)),
+            transport: transport_control,
+            sample_rate_changes: sample_rate_consumer,
+            gen_replacements: gen_replacements_producer,
+            replaced_gens: replaced_gens_consumer,
         };
 
         let graph_gen = GraphGen {
@@ -2200,6 +4614,12 @@ impl Graph {
             _arc_nodes: self.nodes.clone(),
             task_data_to_be_dropped_producer,
             new_task_data_consumer,
+            transport: graph_gen_transport,
+            pool: None,
+            sample_rate_changes: sample_rate_producer,
+            gen_replacements: gen_replacements_consumer,
+            replaced_gens: replaced_gens_producer,
+            num_gen_replacements: 0,
         };
         self.graph_gen_communicator = Some(graph_gen_communicator);
         Ok(graph_gen)
@@ -2210,8 +4630,12 @@ impl Graph {
         self.calculate_node_order();
         let block_size = self.block_size;
         let sample_rate = self.sample_rate;
+        let parallel = self.parallel.is_some();
         for (_key, n) in self.get_nodes_mut() {
             n.init(block_size, sample_rate);
+            if parallel {
+                n.init_input_buffers(block_size);
+            }
         }
         // self.tasks = self.generate_tasks();
         // self.output_tasks = self.generate_output_tasks();
@@ -2219,14 +4643,24 @@ impl Graph {
     }
 
     /// Applies the latest changes to connections and added nodes in the graph on the audio thread and updates the scheduler.
+    ///
+    /// Inside a [`Graph::bundle`] the changes are committed when the bundle
+    /// is done instead.
     pub fn commit_changes(&mut self) {
+        if self.bundle_new_nodes.is_some() {
+            return;
+        }
+        self.commit_changes_at(0);
+    }
+    fn commit_changes_at(&mut self, apply_at: u64) {
         if self.graph_gen_communicator.is_some() {
             self.free_old();
             self.calculate_node_order();
             let output_tasks = self.generate_output_tasks().into_boxed_slice();
             let tasks = self.generate_tasks().into_boxed_slice();
+            let stages = self.stage_lengths.clone().into_boxed_slice();
             if let Some(ggc) = &mut self.graph_gen_communicator {
-                ggc.send_updated_tasks(tasks, output_tasks);
+                ggc.send_updated_tasks(tasks, output_tasks, stages, apply_at);
             }
         }
         for (_key, graph) in &mut self.graphs_per_node {
@@ -2236,13 +4670,157 @@ impl Graph {
 
     /// This function needs to be run regularly to be sure that scheduled changes are carried out.
     pub fn update(&mut self) {
+        if !self.pending_beat_changes.is_empty()
+            && self.transport_state() == Some(TransportState::Playing)
+        {
+            for change in std::mem::take(&mut self.pending_beat_changes) {
+                if let Err(e) = self.schedule_change(change) {
+                    eprintln!("Failed to schedule a change in musical time: {e}");
+                }
+            }
+        }
         if let Some(ggc) = &mut self.graph_gen_communicator {
+            while let Ok((sample_rate, at_sample)) = ggc.sample_rate_changes.pop() {
+                self.sample_rate = sample_rate;
+                ggc.scheduler.set_sample_rate(sample_rate, at_sample);
+                if let Some(transport) = &mut ggc.transport {
+                    transport.set_sample_rate(sample_to_f64(sample_rate), at_sample);
+                }
+            }
+            if let Some(transport) = &mut ggc.transport {
+                transport.update();
+            }
             ggc.update();
         }
+        self.resend_unapplied_gen_replacements();
         for (_key, graph) in &mut self.graphs_per_node {
             graph.update();
         }
     }
+    /// Replaced Gens come back from the GraphGen to be dropped here. A
+    /// replacement for a node that wasn't processed yet because the changes
+    /// to the Graph weren't committed is sent again.
+    fn resend_unapplied_gen_replacements(&mut self) {
+        let Some(ggc) = &mut self.graph_gen_communicator else {
+            return;
+        };
+        let mut unapplied = vec![];
+        while let Ok(replacement) = ggc.replaced_gens.pop() {
+            if !replacement.applied {
+                unapplied.push(replacement);
+            }
+        }
+        for replacement in unapplied {
+            if self.get_nodes().contains_key(replacement.key)
+                && !self.node_keys_pending_removal.contains(&replacement.key)
+            {
+                if let Some(ggc) = &mut self.graph_gen_communicator {
+                    ggc.gen_replacements.push(replacement).ok();
+                }
+            }
+        }
+    }
+
+    /// Start the transport. Beat 0 is reached at the time of the call plus
+    /// the latency of the Graph, unless the transport was paused in which case
+    /// it continues from where it was.
+    ///
+    /// Only available on the top level Graph when it is running.
+    pub fn transport_play(&mut self) -> Result<(), ScheduleError> {
+        self.send_transport_command(TransportCommandKind::Play)
+    }
+    /// Pause the transport, keeping the current position in beats.
+    pub fn transport_pause(&mut self) -> Result<(), ScheduleError> {
+        self.send_transport_command(TransportCommandKind::Pause)
+    }
+    /// Stop the transport and return to beat 0.
+    pub fn transport_stop(&mut self) -> Result<(), ScheduleError> {
+        self.send_transport_command(TransportCommandKind::Stop)
+    }
+    /// Move the transport to `beats`, keeping its state. Changes that have
+    /// already been scheduled in beats are not moved.
+    pub fn transport_seek(&mut self, beats: Beats) -> Result<(), ScheduleError> {
+        self.send_transport_command(TransportCommandKind::Seek(beats))
+    }
+    /// Insert a tempo change in the [`MusicalTimeMap`] of the transport.
+    pub fn insert_tempo_change(
+        &mut self,
+        tempo_change: TempoChange,
+        time_stamp: Beats,
+    ) -> Result<(), ScheduleError> {
+        self.send_transport_command(TransportCommandKind::InsertTempoChange(
+            tempo_change,
+            time_stamp,
+        ))
+    }
+    /// Replace the tempo change at `index` in the [`MusicalTimeMap`] of the transport.
+    pub fn replace_tempo_change(
+        &mut self,
+        index: usize,
+        tempo_change: TempoChange,
+    ) -> Result<(), ScheduleError> {
+        self.send_transport_command(TransportCommandKind::ReplaceTempoChange(
+            index,
+            tempo_change,
+        ))
+    }
+    /// Remove the tempo change at `index` in the [`MusicalTimeMap`] of the transport.
+    pub fn remove_tempo_change(&mut self, index: usize) -> Result<(), ScheduleError> {
+        self.send_transport_command(TransportCommandKind::RemoveTempoChange(index))
+    }
+    /// The [`MusicalTimeMap`] of the transport, if this is a running top level Graph.
+    pub fn musical_time_map(&self) -> Option<&MusicalTimeMap> {
+        self.graph_gen_communicator
+            .as_ref()
+            .and_then(|ggc| ggc.transport.as_ref())
+            .map(|t| t.timeline.map())
+    }
+    /// The state of the transport, if this is a running top level Graph.
+    pub fn transport_state(&self) -> Option<TransportState> {
+        self.graph_gen_communicator
+            .as_ref()
+            .and_then(|ggc| ggc.transport.as_ref())
+            .map(|t| t.timeline.state())
+    }
+    /// The absolute sample at which the transport reaches `beats`, e.g. for
+    /// the timestamp of a [`crate::midi::MidiEvent`]. None if the transport
+    /// isn't playing or this isn't a running top level Graph.
+    pub fn sample_at_beats(&self, beats: Beats) -> Option<u64> {
+        self.graph_gen_communicator
+            .as_ref()
+            .and_then(|ggc| ggc.transport.as_ref())
+            .and_then(|t| t.timeline.sample_of(beats))
+    }
+    /// The position of the transport at the last completed block, if this is
+    /// a running top level Graph.
+    pub fn current_beats(&self) -> Option<Beats> {
+        self.graph_gen_communicator.as_ref().and_then(|ggc| {
+            ggc.transport
+                .as_ref()
+                .map(|t| t.timeline.beats_at(ggc.timestamp.load(Ordering::SeqCst)))
+        })
+    }
+    /// The position of the transport at the sample where a transport command
+    /// sent now takes effect, and the time until that sample.
+    #[cfg(feature = "link")]
+    pub(crate) fn transport_position_at_latency(&self) -> Option<(Beats, Duration)> {
+        let ggc = self.graph_gen_communicator.as_ref()?;
+        let transport = ggc.transport.as_ref()?;
+        let at_sample = ggc.timestamp.load(Ordering::SeqCst) + ggc.scheduler.latency;
+        Some((transport.timeline.beats_at(at_sample), self.latency))
+    }
+    fn send_transport_command(&mut self, kind: TransportCommandKind) -> Result<(), ScheduleError> {
+        match &mut self.graph_gen_communicator {
+            Some(ggc) => {
+                let at_sample = ggc.timestamp.load(Ordering::SeqCst) + ggc.scheduler.latency;
+                match &mut ggc.transport {
+                    Some(transport) => transport.send(TransportCommand { at_sample, kind }),
+                    None => Err(ScheduleError::TransportNotAvailable),
+                }
+            }
+            None => Err(ScheduleError::SchedulerNotCreated),
+        }
+    }
 
     /// Check if there are any old nodes or other resources that have been
     /// removed from the graph and can now be freed since they are no longer
@@ -2293,6 +4871,18 @@ impl Graph {
             }
         }
     }
+    /// Load state from [`Gen::save_state`] into a node. Only used while
+    /// building a Graph from a [`Patch`], before the Graph is running.
+    pub(crate) fn load_node_state(
+        &mut self,
+        node: NodeAddress,
+        state: &SavedState,
+    ) -> Result<(), PatchError> {
+        match self.get_nodes_mut().get_mut(node.key) {
+            Some(node) => node.gen.load_state(state),
+            None => Err(ConnectionError::NodeNotFound.into()),
+        }
+    }
     fn get_nodes_mut(&mut self) -> &mut SlotMap<NodeKey, Node> {
         unsafe { &mut *self.nodes.get() }
     }
@@ -2310,6 +4900,27 @@ impl Gen for GraphGen {
     fn name(&self) -> &'static str {
         "GraphGen"
     }
+    /// The nodes of the Graph may use any part of the Resources
+    fn uses_shared_resources(&self) -> bool {
+        true
+    }
+    /// Notifies all nodes that are being processed. Nodes that aren't
+    /// connected to anything yet are initialised with the new sample rate
+    /// when they are, since the Graph is updated as well.
+    fn sample_rate_changed(&mut self, sample_rate: Sample) {
+        for task in self.current_task_data.tasks.iter_mut() {
+            let node = unsafe { &mut *task.node_ptr };
+            node.sample_rate_changed(sample_rate);
+        }
+        if let Some(transport) = &mut self.transport {
+            transport
+                .timeline
+                .set_sample_rate(sample_to_f64(sample_rate), self.sample_counter);
+        }
+        self.sample_rate_changes
+            .push((sample_rate, self.sample_counter))
+            .ok();
+    }
     fn process(
         &mut self,
         inputs: &[Box<[Sample]>],
@@ -2323,65 +4934,103 @@ impl Gen for GraphGen {
                 //
                 let mut do_empty_buffer = None;
                 let mut do_mend_connections = None;
-                let num_new_task_data = self.new_task_data_consumer.slots();
-                if num_new_task_data > 0 {
-                    if let Ok(td_chunk) = self.new_task_data_consumer.read_chunk(num_new_task_data)
-                    {
-                        self.generation.fetch_add(1, Ordering::SeqCst);
-                        for td in td_chunk {
-                            let old_td = std::mem::replace(&mut self.current_task_data, td);
-                            match self.task_data_to_be_dropped_producer.push(old_td) {
-                            Ok(_) => (),
-                            Err(e) => eprintln!("RingBuffer for TaskData to be dropped was full. Please increase the size of the RingBuffer. The GraphGen will drop the TaskData here instead. e: {e}"),
-                        }
-                        }
+                // TaskData from a bundle waits for the block it is scheduled for
+                let mut new_generation = false;
+                while let Ok(td) = self.new_task_data_consumer.peek() {
+                    if td.apply_at > self.sample_counter {
+                        break;
+                    }
+                    let Ok(td) = self.new_task_data_consumer.pop() else {
+                        break;
+                    };
+                    new_generation = true;
+                    let old_td = std::mem::replace(&mut self.current_task_data, td);
+                    match self.task_data_to_be_dropped_producer.push(old_td) {
+                        Ok(_) => (),
+                        Err(e) => eprintln!("RingBuffer for TaskData to be dropped was full. Please increase the size of the RingBuffer. The GraphGen will drop the TaskData here instead. e: {e}"),
                     }
                 }
+                if new_generation {
+                    self.generation.fetch_add(1, Ordering::SeqCst);
+                }
+
+                self.apply_gen_replacements();
 
                 // let task_data = unsafe { &mut *self.task_data_ptr.load(Ordering::Relaxed) };
                 let task_data = &mut self.current_task_data;
                 let TaskData {
                     tasks,
                     output_tasks,
+                    stages,
+                    ..
                 } = task_data;
 
+                if let Some(transport) = &mut self.transport {
+                    // Only the top level Graph applies changes to the Resources
+                    resources.apply_commands();
+                    transport.update(self.sample_counter, self.block_size, resources);
+                }
+
                 let changes = self.schedule_receiver.changes();
 
                 // Run the tasks
-                for task in tasks.iter_mut() {
-                    task.init_constants();
-                    // If there are any changes to the constants of the node, apply them here
-                    let mut i = 0;
-                    while i < changes.len() {
-                        let change = &changes[i];
-                        if change.key == task.node_key {
-                            let sample_to_apply = if change.timestamp < self.sample_counter {
-                                if change.timestamp != 0 {
-                                    // timestamps of 0 simply means as fast as possible. It is not an error or issue.
-                                    eprintln!("Warning: Scheduled change was applied late. Consider increasing latency.");
-                                }
-                                0
-                            } else {
-                                change.timestamp - self.sample_counter
-                            } as usize;
-                            if sample_to_apply < self.block_size {
-                                task.apply_constant_change(change, sample_to_apply);
-                                // TODO: This is inefficient since the the first
-                                // changes are the most likely to be removed,
-                                // and are the most expensive to remove. Either
-                                // the changes can be in reverse order, but then
-                                // pushing into the list always puts new changes
-                                // in the wrong place, or many changes can be
-                                // removed all at once after applying them.
-                                changes.remove(i);
+                match &mut self.pool {
+                    Some(pool) if !stages.is_empty() => {
+                        pool.sync_resources(resources);
+                        let mut stage_start = 0;
+                        for &stage_len in stages.iter() {
+                            let stage = &mut tasks[stage_start..stage_start + stage_len];
+                            stage_start += stage_len;
+                            for task in stage.iter_mut() {
+                                task.init_constants();
+                                apply_scheduled_changes(
+                                    task,
+                                    changes,
+                                    self.sample_counter,
+                                    self.block_size,
+                                );
+                            }
+                            if stage.len() >= pool.min_tasks_per_stage {
+                                pool.run_stage(stage, inputs, resources);
                             } else {
-                                i += 1;
+                                for task in stage.iter_mut() {
+                                    task.state = task.run(inputs, resources);
+                                }
                             }
-                        } else {
-                            i += 1;
                         }
                     }
-                    match task.run(inputs, resources) {
+                    _ => {
+                        for task in tasks.iter_mut() {
+                            task.init_constants();
+                            apply_scheduled_changes(
+                                task,
+                                changes,
+                                self.sample_counter,
+                                self.block_size,
+                            );
+                            task.state = task.run(inputs, resources);
+                        }
+                    }
+                }
+                if self.num_gen_replacements > 0 {
+                    self.num_gen_replacements = 0;
+                    for task in tasks.iter() {
+                        let node = unsafe { &mut *task.node_ptr };
+                        if let Some(replacement) = node.take_finished_replacement() {
+                            if let Err(rtrb::PushError::Full(replacement)) =
+                                self.replaced_gens.push(replacement)
+                            {
+                                // Try again next block rather than deallocating here
+                                node.replacement = Some(replacement);
+                            }
+                        }
+                        if node.replacement.is_some() {
+                            self.num_gen_replacements += 1;
+                        }
+                    }
+                }
+                for task in tasks.iter() {
+                    match task.state {
                         GenState::Continue => (),
                         GenState::FreeSelf => {
                             // We don't care if it fails since if it does the
@@ -2425,9 +5074,12 @@ impl Gen for GraphGen {
                 for output_task in output_tasks.iter() {
                     let input_values = unsafe { &*output_task.input_buffer_ptr };
                     let output = &mut outputs[output_task.graph_output_index];
-                    for i in 0..self.block_size {
-                        let value = input_values[i];
-                        output[i] += value;
+                    if output_task.gain == 1.0 {
+                        crate::simd::add_assign(output, input_values);
+                    } else {
+                        for (o, i) in output.iter_mut().zip(input_values.iter()) {
+                            *o += *i * output_task.gain;
+                        }
                     }
                 }
                 if let Some(from_relative_sample_nr) = do_empty_buffer {
@@ -2479,6 +5131,33 @@ impl Gen for GraphGen {
     }
 }
 
+impl GraphGen {
+    /// Swap in new Gens sent from the Graph. A replacement for a node that
+    /// isn't in the current Tasks is sent back to be sent again later.
+    fn apply_gen_replacements(&mut self) {
+        while let Ok(replacement) = self.gen_replacements.pop() {
+            let task = self
+                .current_task_data
+                .tasks
+                .iter()
+                .find(|task| task.node_key == replacement.key);
+            let returned = match task {
+                Some(task) => {
+                    let node = unsafe { &mut *task.node_ptr };
+                    self.num_gen_replacements += 1;
+                    node.replace_gen(replacement)
+                }
+                None => Some(replacement),
+            };
+            if let Some(returned) = returned {
+                if self.replaced_gens.push(returned).is_err() {
+                    eprintln!("RingBuffer for replaced Gens was full. Please increase the size of the RingBuffer. The GraphGen will drop the Gen here instead.");
+                }
+            }
+        }
+    }
+}
+
 /// This gets placed as a dyn Gen in a Node in a Graph. It's how the Graph gets
 /// run. The Graph communicates with the GraphGen in a thread safe way.
 ///
@@ -2507,6 +5186,16 @@ struct GraphGen {
     free_node_queue_producer: rtrb::Producer<(NodeKey, GenState)>,
     task_data_to_be_dropped_producer: rtrb::Producer<TaskData>,
     new_task_data_consumer: rtrb::Consumer<TaskData>,
+    /// Only the top level GraphGen has a transport
+    transport: Option<GraphGenTransport>,
+    /// Worker threads for parallel processing
+    pool: Option<WorkerPool>,
+    /// Sends new sample rates and the sample they changed at to the Graph
+    sample_rate_changes: rtrb::Producer<(Sample, u64)>,
+    gen_replacements: rtrb::Consumer<Box<GenReplacement>>,
+    replaced_gens: rtrb::Producer<Box<GenReplacement>>,
+    /// The number of nodes that may still hold a replaced Gen
+    num_gen_replacements: usize,
 }
 
 /// Safety: This impl of Send is required because of the Arc<UnsafeCell<...>> in
@@ -2515,24 +5204,358 @@ struct GraphGen {
 /// to access the data from within GraphGen.
 unsafe impl Send for GraphGen {}
 
-struct ScheduledChange {
-    timestamp: u64,
-    key: NodeKey,
-    kind: ScheduledChangeKind,
+/// Apply the changes to the constants of the node in the task that are
+/// scheduled for this block.
+fn apply_scheduled_changes(
+    task: &mut Task,
+    changes: &mut Vec<ScheduledChange>,
+    sample_counter: u64,
+    block_size: usize,
+) {
+    let mut i = 0;
+    while i < changes.len() {
+        let change = &changes[i];
+        if change.key == task.node_key {
+            let sample_to_apply = if change.timestamp < sample_counter {
+                if change.timestamp != 0 {
+                    // timestamps of 0 simply means as fast as possible. It is not an error or issue.
+                    eprintln!(
+                        "Warning: Scheduled change was applied late. Consider increasing latency."
+                    );
+                }
+                0
+            } else {
+                change.timestamp - sample_counter
+            } as usize;
+            if sample_to_apply < block_size {
+                task.apply_constant_change(change, sample_to_apply);
+                // TODO: This is inefficient since the the first
+                // changes are the most likely to be removed,
+                // and are the most expensive to remove. Either
+                // the changes can be in reverse order, but then
+                // pushing into the list always puts new changes
+                // in the wrong place, or many changes can be
+                // removed all at once after applying them.
+                changes.remove(i);
+            } else {
+                i += 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
 }
-enum ScheduledChangeKind {
-    Constant { index: usize, value: Sample },
+
+/// The number of times a worker checks for a new stage before going to sleep.
+const WORKER_SPINS_BEFORE_PARKING: usize = 10_000;
+
+/// A pool of worker threads that process the Tasks of a stage together with
+/// the audio thread.
+struct WorkerPool {
+    shared: Arc<WorkerPoolShared>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+    min_tasks_per_stage: usize,
+    /// The generation of the stage that was last started
+    generation: u32,
+    /// True once the workers have been given the priority and random seeds
+    /// of the audio thread
+    started: bool,
 }
 
-struct Scheduler {
-    start_ts: Instant,
-    sample_rate: u64,
-    /// if the ts of the change is less than this number of samples in the future, send it to the GraphGen
-    max_duration_to_send: u64,
+impl WorkerPool {
+    fn new(settings: ParallelSettings) -> Self {
+        let shared = Arc::new(WorkerPoolShared {
+            next_task: AtomicU64::new(0),
+            tasks_done: AtomicUsize::new(0),
+            stages: [StageSlot::new(), StageSlot::new()],
+            stop: AtomicBool::new(false),
+            acknowledged: (0..settings.num_threads)
+                .map(|_| AtomicU32::new(0))
+                .collect(),
+            resources: (0..settings.num_threads)
+                .map(|_| UnsafeCell::new(Resources::new_worker()))
+                .collect(),
+        });
+        let threads = (0..settings.num_threads)
+            .map(|worker_index| {
+                let shared = shared.clone();
+                std::thread::Builder::new()
+                    .name(format!("knyst-worker-{worker_index}"))
+                    .spawn(move || shared.worker_loop(worker_index))
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+        Self {
+            shared,
+            threads,
+            min_tasks_per_stage: settings.min_tasks_per_stage,
+            generation: 0,
+            started: false,
+        }
+    }
+    /// Update the Resources of the workers from the Resources of the audio
+    /// thread for the coming block.
+    fn sync_resources(&mut self, resources: &mut Resources) {
+        // The workers may still be looking at their Resources until they
+        // have acknowledged the last stage
+        self.shared.wait_until_acknowledged(self.generation);
+        for worker_resources in self.shared.resources.iter() {
+            // Safety: Every worker has acknowledged the last stage and
+            // doesn't access its Resources until the next one is published.
+            let worker_resources = unsafe { &mut *worker_resources.get() };
+            if !self.started {
+                worker_resources.rng = fastrand::Rng::with_seed(resources.rng.u64(..));
+            }
+            resources.sync_worker(worker_resources);
+        }
+        if !self.started {
+            self.match_audio_thread_priority();
+            self.started = true;
+        }
+    }
+    /// Give the workers the scheduling policy and priority of the current
+    /// thread, which is the audio thread. Failing, e.g. because of missing
+    /// permissions, leaves them at their current priority.
+    #[cfg(unix)]
+    fn match_audio_thread_priority(&self) {
+        use std::os::unix::thread::JoinHandleExt;
+        let mut policy = 0;
+        // Safety: sched_param is a plain C struct
+        let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+        // Safety: The pointers are valid and the threads are running until the pool is dropped
+        unsafe {
+            if libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param) != 0 {
+                return;
+            }
+            for thread in &self.threads {
+                libc::pthread_setschedparam(thread.as_pthread_t(), policy, &param);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    fn match_audio_thread_priority(&self) {}
+    /// Run all the tasks in a stage and return when they are all done.
+    /// Tasks with nodes using the shared parts of the Resources are run on
+    /// the audio thread, the rest are shared with the workers.
+    fn run_stage(
+        &mut self,
+        tasks: &mut [Task],
+        graph_inputs: &[Box<[Sample]>],
+        resources: &mut Resources,
+    ) {
+        // Move the tasks for the audio thread to the front. The order of the
+        // tasks in a stage doesn't matter and it rarely changes.
+        let mut num_local = 0;
+        for i in 0..tasks.len() {
+            if tasks[i].uses_shared_resources() {
+                tasks.swap(num_local, i);
+                num_local += 1;
+            }
+        }
+        if num_local == tasks.len() {
+            for task in tasks.iter_mut() {
+                task.state = task.run(graph_inputs, resources);
+            }
+            return;
+        }
+        // The slot was last used two stages ago
+        self.shared
+            .wait_until_acknowledged(self.generation.wrapping_sub(1));
+        self.generation = self.generation.wrapping_add(1);
+        let slot = self.shared.slot(self.generation);
+        slot.tasks.store(tasks.as_mut_ptr(), Ordering::Relaxed);
+        slot.num_tasks.store(tasks.len(), Ordering::Relaxed);
+        slot.graph_inputs.store(
+            graph_inputs.as_ptr() as *mut Box<[Sample]>,
+            Ordering::Relaxed,
+        );
+        slot.num_graph_inputs
+            .store(graph_inputs.len(), Ordering::Relaxed);
+        self.shared.tasks_done.store(0, Ordering::Relaxed);
+        // Publish the new stage, starting after the tasks for the audio thread
+        self.shared.next_task.store(
+            ((self.generation as u64) << 32) | num_local as u64,
+            Ordering::Release,
+        );
+        for thread in &self.threads {
+            thread.thread().unpark();
+        }
+        // Process tasks on this thread as well
+        for task in tasks[..num_local].iter_mut() {
+            task.state = task.run(graph_inputs, resources);
+        }
+        while let Some(index) = self.shared.claim_task(self.generation) {
+            // Safety: The task was claimed for the current stage
+            unsafe { self.shared.run_task(self.generation, index, resources) };
+        }
+        while self.shared.tasks_done.load(Ordering::Acquire) < tasks.len() - num_local {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            thread.thread().unpark();
+            thread.join().ok();
+        }
+    }
+}
+
+/// Describes the Tasks of a stage to the workers
+struct StageSlot {
+    tasks: AtomicPtr<Task>,
+    num_tasks: AtomicUsize,
+    graph_inputs: AtomicPtr<Box<[Sample]>>,
+    num_graph_inputs: AtomicUsize,
+}
+impl StageSlot {
+    fn new() -> Self {
+        Self {
+            tasks: AtomicPtr::new(std::ptr::null_mut()),
+            num_tasks: AtomicUsize::new(0),
+            graph_inputs: AtomicPtr::new(std::ptr::null_mut()),
+            num_graph_inputs: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Safety: The Tasks of a stage don't depend on each other and every Task is
+/// claimed by exactly one thread using `next_task`. The current stage is
+/// stored in one of two slots depending on the generation so that a worker
+/// that is late to notice a new stage can never claim a Task from the wrong
+/// stage: its claim fails because the generation in `next_task` has changed.
+struct WorkerPoolShared {
+    /// The generation of the current stage in the upper 32 bits and the index
+    /// of the next Task to claim in the lower 32 bits.
+    next_task: AtomicU64,
+    tasks_done: AtomicUsize,
+    stages: [StageSlot; 2],
+    stop: AtomicBool,
+    /// The generation of the last stage each worker is done with
+    acknowledged: Box<[AtomicU32]>,
+    /// The Resources of each worker thread, updated from the Resources of the
+    /// audio thread before the first stage of every block
+    resources: Box<[UnsafeCell<Resources>]>,
+}
+unsafe impl Sync for WorkerPoolShared {}
+unsafe impl Send for WorkerPoolShared {}
+
+impl WorkerPoolShared {
+    fn slot(&self, generation: u32) -> &StageSlot {
+        &self.stages[(generation % 2) as usize]
+    }
+    /// Wait until every worker is done with the stage of `generation` or a
+    /// later one.
+    fn wait_until_acknowledged(&self, generation: u32) {
+        for acknowledged in self.acknowledged.iter() {
+            while (acknowledged
+                .load(Ordering::Acquire)
+                .wrapping_sub(generation) as i32)
+                < 0
+            {
+                std::hint::spin_loop();
+            }
+        }
+    }
+    fn claim_task(&self, generation: u32) -> Option<usize> {
+        let slot = self.slot(generation);
+        let mut current = self.next_task.load(Ordering::Acquire);
+        loop {
+            if (current >> 32) as u32 != generation {
+                return None;
+            }
+            let index = (current & 0xFFFF_FFFF) as usize;
+            if index >= slot.num_tasks.load(Ordering::Relaxed) {
+                return None;
+            }
+            match self.next_task.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(index),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+    /// Safety: `index` has to have been claimed for `generation` using `claim_task`.
+    unsafe fn run_task(&self, generation: u32, index: usize, resources: &mut Resources) {
+        let slot = self.slot(generation);
+        let task = &mut *slot.tasks.load(Ordering::Relaxed).add(index);
+        let graph_inputs = std::slice::from_raw_parts(
+            slot.graph_inputs.load(Ordering::Relaxed),
+            slot.num_graph_inputs.load(Ordering::Relaxed),
+        );
+        task.state = task.run(graph_inputs, resources);
+        self.tasks_done.fetch_add(1, Ordering::Release);
+    }
+    fn worker_loop(&self, worker_index: usize) {
+        let _denormals = crate::denormal::DenormalGuard::new();
+        let mut last_generation = 0;
+        loop {
+            // Wait for a new stage
+            let mut spins = 0;
+            let generation = loop {
+                if self.stop.load(Ordering::Acquire) {
+                    return;
+                }
+                let generation = (self.next_task.load(Ordering::Acquire) >> 32) as u32;
+                if generation != last_generation {
+                    break generation;
+                }
+                if spins < WORKER_SPINS_BEFORE_PARKING {
+                    spins += 1;
+                    std::hint::spin_loop();
+                } else {
+                    std::thread::park();
+                }
+            };
+            last_generation = generation;
+            // Safety: Only this worker uses these Resources and the audio
+            // thread doesn't change them until this stage is acknowledged.
+            let resources = unsafe { &mut *self.resources[worker_index].get() };
+            while let Some(index) = self.claim_task(generation) {
+                unsafe { self.run_task(generation, index, resources) };
+            }
+            self.acknowledged[worker_index].store(generation, Ordering::Release);
+        }
+    }
+}
+
+struct ScheduledChange {
+    timestamp: u64,
+    key: NodeKey,
+    kind: ScheduledChangeKind,
+}
+enum ScheduledChangeKind {
+    /// `smoother` is Some if the smoothing of the input should be changed
+    Constant {
+        index: usize,
+        value: Sample,
+        smoother: Option<Option<InputSmoother>>,
+    },
+    Bypass(bool),
+    Mute(bool),
+}
+
+struct Scheduler {
+    start_ts: Instant,
+    /// The sample at `start_ts`
+    start_sample: u64,
+    sample_rate: u64,
+    /// if the ts of the change is less than this number of samples in the future, send it to the GraphGen
+    max_duration_to_send: u64,
     rb_producer: rtrb::Producer<ScheduledChange>,
     /// Changes waiting to be sent to the GraphGen because they are too far into the future
     scheduling_queue: Vec<ScheduledChange>,
     latency: u64,
+    /// The sample changes scheduled as soon as possible are scheduled for
+    /// inside a [`Graph::bundle`]
+    bundle_time: Option<u64>,
 }
 impl Scheduler {
     fn new(sample_rate: Sample, capacity: usize, latency: Duration) -> (Self, ScheduleReceiver) {
@@ -2540,15 +5563,26 @@ impl Scheduler {
         (
             Scheduler {
                 start_ts: Instant::now(),
+                start_sample: 0,
                 sample_rate: sample_rate as u64,
                 max_duration_to_send: (sample_rate * 0.5) as u64,
                 scheduling_queue: vec![],
                 rb_producer,
-                latency: (latency.as_secs_f32() * sample_rate) as u64,
+                latency: (latency.as_secs_f64() * sample_to_f64(sample_rate)) as u64,
+                bundle_time: None,
             },
             ScheduleReceiver::new(rb_consumer, capacity),
         )
     }
+    /// Continue counting time from `at_sample` at the new sample rate
+    fn set_sample_rate(&mut self, sample_rate: Sample, at_sample: u64) {
+        let latency_secs = self.latency as f64 / self.sample_rate as f64;
+        self.start_ts = Instant::now();
+        self.start_sample = at_sample;
+        self.sample_rate = sample_rate as u64;
+        self.max_duration_to_send = (sample_rate * 0.5) as u64;
+        self.latency = (latency_secs * sample_to_f64(sample_rate)) as u64;
+    }
     fn schedule_absolute_sample(
         &mut self,
         key: NodeKey,
@@ -2563,20 +5597,25 @@ impl Scheduler {
     }
     fn schedule_asap(&mut self, key: NodeKey, change: ScheduledChangeKind) {
         self.scheduling_queue.push(ScheduledChange {
-            timestamp: 0,
+            timestamp: self.bundle_time.unwrap_or(0),
             key,
             kind: change,
         });
     }
+    /// The sample `duration_from_now` from now, including the latency
+    fn local_time_to_sample(&self, duration_from_now: Duration) -> u64 {
+        ((self.start_ts.elapsed() + duration_from_now).as_secs_f64() * self.sample_rate as f64)
+            as u64
+            + self.start_sample
+            + self.latency
+    }
     fn schedule_local_time(
         &mut self,
         key: NodeKey,
         change: ScheduledChangeKind,
         duration_from_now: Duration,
     ) {
-        let timestamp = ((self.start_ts.elapsed() + duration_from_now).as_secs_f64()
-            * self.sample_rate as f64) as u64
-            + self.latency;
+        let timestamp = self.local_time_to_sample(duration_from_now);
         self.scheduling_queue.push(ScheduledChange {
             timestamp,
             key,
@@ -2638,6 +5677,101 @@ impl ScheduleReceiver {
     }
 }
 
+/// The Graph side of the transport. Commands are applied to the local
+/// timeline immediately so that changes in musical time can be converted to
+/// samples, and then sent to the GraphGen which applies them at the same
+/// sample. A command that arrives after its sample has passed is applied at
+/// the start of the next block instead; the GraphGen reports back the sample
+/// every command was applied at so that the local timeline can follow.
+struct TransportControl {
+    timeline: TransportTimeline,
+    /// The timeline with only the commands the GraphGen has applied
+    applied_timeline: TransportTimeline,
+    /// Commands sent to the GraphGen that it hasn't applied yet, in order
+    pending: VecDeque<TransportCommand>,
+    producer: rtrb::Producer<TransportCommand>,
+    applied_at: rtrb::Consumer<u64>,
+}
+impl TransportControl {
+    fn new(
+        timeline: TransportTimeline,
+        producer: rtrb::Producer<TransportCommand>,
+        applied_at: rtrb::Consumer<u64>,
+    ) -> Self {
+        Self {
+            applied_timeline: timeline.clone(),
+            timeline,
+            pending: VecDeque::new(),
+            producer,
+            applied_at,
+        }
+    }
+    fn send(&mut self, command: TransportCommand) -> Result<(), ScheduleError> {
+        // Apply to a copy first so that the timelines stay in sync if the command fails
+        let mut timeline = self.timeline.clone();
+        timeline.apply(command)?;
+        if self.producer.push(command).is_err() {
+            return Err(ScheduleError::TransportCommandQueueFull);
+        }
+        self.timeline = timeline;
+        self.pending.push_back(command);
+        Ok(())
+    }
+    /// Receive the samples at which the GraphGen applied the pending
+    /// commands and rebuild the local timeline if any of them were late.
+    fn update(&mut self) {
+        let mut moved = false;
+        while let Ok(at_sample) = self.applied_at.pop() {
+            let Some(mut command) = self.pending.pop_front() else {
+                break;
+            };
+            moved |= command.at_sample != at_sample;
+            command.at_sample = at_sample;
+            self.applied_timeline.apply(command).ok();
+        }
+        if moved {
+            let mut timeline = self.applied_timeline.clone();
+            for command in &self.pending {
+                timeline.apply(*command).ok();
+            }
+            self.timeline = timeline;
+        }
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64, at_sample: u64) {
+        self.timeline.set_sample_rate(sample_rate, at_sample);
+        self.applied_timeline
+            .set_sample_rate(sample_rate, at_sample);
+    }
+}
+
+/// The GraphGen side of the transport.
+struct GraphGenTransport {
+    timeline: TransportTimeline,
+    consumer: rtrb::Consumer<TransportCommand>,
+    applied_at: rtrb::Producer<u64>,
+}
+impl GraphGenTransport {
+    /// Apply all commands up to the end of this block and store the current
+    /// state of the transport in the [`Resources`].
+    fn update(&mut self, sample_counter: u64, block_size: usize, resources: &mut Resources) {
+        let block_end = sample_counter + block_size as u64;
+        while let Ok(command) = self.consumer.peek() {
+            // Wait with the command if it can't be reported back
+            if command.at_sample >= block_end || self.applied_at.is_full() {
+                break;
+            }
+            let mut command = *command;
+            self.consumer.pop().ok();
+            // Commands are applied at the block boundary
+            command.at_sample = command.at_sample.max(sample_counter);
+            // The command was already validated on the Graph side
+            self.timeline.apply(command).ok();
+            self.applied_at.push(command.at_sample).ok();
+        }
+        resources.transport = self.timeline.snapshot(sample_counter);
+    }
+}
+
 /// This data is sent via a boxed TaskData converted to a raw pointer.
 ///
 /// Safety: The tasks or output_tasks may not be moved while there is a raw
@@ -2646,6 +5780,11 @@ impl ScheduleReceiver {
 struct TaskData {
     tasks: Box<[Task]>,
     output_tasks: Box<[OutputTask]>,
+    /// The number of tasks in each stage if processing in parallel. Tasks in
+    /// the same stage don't depend on each other.
+    stages: Box<[usize]>,
+    /// The sample from which the tasks are used, 0 for as soon as possible
+    apply_at: u64,
 }
 
 struct GraphGenCommunicator {
@@ -2658,6 +5797,12 @@ struct GraphGenCommunicator {
     free_node_queue_consumer: rtrb::Consumer<(NodeKey, GenState)>,
     task_data_to_be_dropped_consumer: rtrb::Consumer<TaskData>,
     new_task_data_producer: rtrb::Producer<TaskData>,
+    /// Only the top level Graph has a transport
+    transport: Option<TransportControl>,
+    sample_rate_changes: rtrb::Consumer<(Sample, u64)>,
+    gen_replacements: rtrb::Producer<Box<GenReplacement>>,
+    /// Replaced Gens to be dropped, or replacements to send again
+    replaced_gens: rtrb::Consumer<Box<GenReplacement>>,
 }
 
 unsafe impl Send for GraphGenCommunicator {}
@@ -2680,12 +5825,20 @@ impl GraphGenCommunicator {
     /// Sends the updated tasks to the GraphGen. NB: Always check if any
     /// resoruces in the Graph can be freed before running this.
     /// GraphGenCommunicator will free its own resources.
-    fn send_updated_tasks(&mut self, tasks: Box<[Task]>, output_tasks: Box<[OutputTask]>) {
+    fn send_updated_tasks(
+        &mut self,
+        tasks: Box<[Task]>,
+        output_tasks: Box<[OutputTask]>,
+        stages: Box<[usize]>,
+        apply_at: u64,
+    ) {
         self.free_old();
 
         let td = TaskData {
             tasks,
             output_tasks,
+            stages,
+            apply_at,
         };
         if let Err(e) = self.new_task_data_producer.push(td) {
             eprintln!(
@@ -2754,32 +5907,214 @@ pub struct Node {
     name: &'static str,
     /// A constant value per input
     input_constants: Vec<Sample>,
+    /// Inputs whose constant is still the default of the Gen, which is only
+    /// used while nothing is connected to the input
+    default_inputs: Vec<bool>,
     /// input buffers are layed out [i0: [s0, s1, s2...], i1: [s0, s1, s2...]]
     // output_buffers: Vec<Vec<Sample>>,
     output_buffers: Box<[Box<[Sample]>]>,
+    /// Input buffers owned by the node, only used when processing in parallel.
+    /// Otherwise the input buffers are shared by all nodes in the Graph.
+    input_buffers: Box<[Box<[Sample]>]>,
+    #[cfg(feature = "profiling")]
+    times: Arc<crate::profiling::ProcessTimes>,
+    /// Control rate outputs that need to be filled in after processing
+    control_outputs: Vec<ControlOutput>,
+    /// Smoothing of the constant value per input
+    input_smoothers: Vec<Option<InputSmoother>>,
+    gen: Box<dyn Gen + Send>,
+    /// The buffers used by the Gen, marked as used every block so that they
+    /// aren't evicted from the [`Resources`] while the node is running
+    buffers: Vec<BufferKey>,
+    /// See [`Gen::uses_shared_resources`]
+    shared_resources: bool,
+    /// The Gen that was replaced, while it is being crossfaded
+    replacement: Option<Box<GenReplacement>>,
+    /// 1 when the output is processed, 0 when the node is bypassed
+    wet: GainRamp,
+    /// 1 unless the node is muted
+    level: GainRamp,
+}
+
+/// How long it takes to bypass or mute a node
+const NODE_RAMP_DURATION: Sample = 0.005;
+
+/// A gain moving linearly towards its target, used to bypass and mute nodes
+/// without clicks
+#[derive(Debug, Clone, Copy)]
+struct GainRamp {
+    value: Sample,
+    target: Sample,
+    step: Sample,
+}
+
+impl GainRamp {
+    fn new() -> Self {
+        Self {
+            value: 1.0,
+            target: 1.0,
+            step: 1.0,
+        }
+    }
+    fn set_sample_rate(&mut self, sample_rate: Sample) {
+        self.step = 1.0 / (NODE_RAMP_DURATION * sample_rate).max(1.0);
+    }
+    fn set_target(&mut self, target: Sample, immediately: bool) {
+        self.target = target;
+        if immediately {
+            self.value = target;
+        }
+    }
+    fn is_unity(&self) -> bool {
+        self.value == 1.0 && self.target == 1.0
+    }
+    #[inline]
+    fn next(&mut self) -> Sample {
+        if self.value < self.target {
+            self.value = (self.value + self.step).min(self.target);
+        } else if self.value > self.target {
+            self.value = (self.value - self.step).max(self.target);
+        }
+        self.value
+    }
+}
+
+/// A control rate output of a Node and the value it had in the last block
+#[derive(Debug, Clone, Copy)]
+struct ControlOutput {
+    index: usize,
+    smooth: bool,
+    last_value: Sample,
+}
+
+/// *Allocates memory*
+/// The keys of the buffers in the [`Gen::resource_refs`] of `gen`
+fn buffer_keys(gen: &dyn Gen) -> Vec<BufferKey> {
+    gen.resource_refs()
+        .into_iter()
+        .filter_map(|resource| match resource {
+            ResourceRef::Buffer(key) => Some(key),
+            _ => None,
+        })
+        .collect()
+}
+
+/// *Allocates memory*
+/// The control rate outputs of `gen`
+fn control_outputs(gen: &dyn Gen) -> Vec<ControlOutput> {
+    (0..gen.num_outputs())
+        .filter_map(|index| match gen.output_rate(index) {
+            Rate::Audio => None,
+            Rate::Control => Some(ControlOutput {
+                index,
+                smooth: false,
+                last_value: 0.0,
+            }),
+            Rate::SmoothControl => Some(ControlOutput {
+                index,
+                smooth: true,
+                last_value: 0.0,
+            }),
+        })
+        .collect()
+}
+
+/// A new Gen for a node, prepared on the control thread. After it has been
+/// swapped in it holds the old Gen instead.
+struct GenReplacement {
+    key: NodeKey,
+    name: &'static str,
     gen: Box<dyn Gen + Send>,
+    control_outputs: Vec<ControlOutput>,
+    buffers: Vec<BufferKey>,
+    shared_resources: bool,
+    /// Output buffers for the old Gen while crossfading
+    outputs: Box<[Box<[Sample]>]>,
+    crossfade_samples: usize,
+    /// The number of samples of the crossfade that have been processed
+    position: usize,
+    applied: bool,
+}
+
+impl GenReplacement {
+    fn is_finished(&self) -> bool {
+        self.position >= self.crossfade_samples
+    }
+    /// Run the old Gen and mix its output into `outputs`
+    fn crossfade(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) {
+        if self.is_finished() {
+            return;
+        }
+        self.gen.process(inputs, &mut self.outputs, resources);
+        let crossfade_samples = self.crossfade_samples as Sample;
+        for (output, old) in outputs.iter_mut().zip(self.outputs.iter()) {
+            for (i, (sample, old)) in output.iter_mut().zip(old.iter()).enumerate() {
+                let gain = ((self.position + i) as Sample / crossfade_samples).min(1.0);
+                *sample = *sample * gain + *old * (1.0 - gain);
+            }
+        }
+        self.position += self.outputs.first().map_or(0, |output| output.len());
+    }
 }
 
 impl Node {
     pub fn new(name: &'static str, gen: Box<dyn Gen + Send>) -> Self {
+        let control_outputs = control_outputs(&*gen);
+        let buffers = buffer_keys(&*gen);
+        let shared_resources = gen.uses_shared_resources();
         Node {
             name,
-            input_constants: vec![0.0 as Sample; gen.num_inputs()],
+            input_constants: (0..gen.num_inputs())
+                .map(|input| gen.default_input(input))
+                .collect(),
+            default_inputs: vec![true; gen.num_inputs()],
+            input_smoothers: vec![None; gen.num_inputs()],
             gen,
+            buffers,
+            shared_resources,
+            replacement: None,
+            wet: GainRamp::new(),
+            level: GainRamp::new(),
             output_buffers: vec![vec![0.0; 0].into_boxed_slice(); 0].into_boxed_slice(),
+            input_buffers: vec![].into_boxed_slice(),
+            control_outputs,
+            #[cfg(feature = "profiling")]
+            times: Arc::new(crate::profiling::ProcessTimes::default()),
         }
     }
     pub fn name(&self) -> &'static str {
         self.name
     }
+    /// Tell the Gen of the node that the sample rate has changed, see
+    /// [`Gen::sample_rate_changed`]
+    pub fn sample_rate_changed(&mut self, sample_rate: Sample) {
+        self.wet.set_sample_rate(sample_rate);
+        self.level.set_sample_rate(sample_rate);
+        self.gen.sample_rate_changed(sample_rate);
+    }
     /// *Allocates memory*
     /// Allocates enough memory for the given block size
     pub fn init(&mut self, block_size: usize, sample_rate: Sample) {
         self.output_buffers =
             vec![vec![0.0 as Sample; block_size].into_boxed_slice(); self.gen.num_outputs()]
                 .into_boxed_slice();
+        self.wet.set_sample_rate(sample_rate);
+        self.level.set_sample_rate(sample_rate);
         self.gen.init(sample_rate);
     }
+    /// *Allocates memory*
+    /// Give the node its own input buffers instead of using the ones shared by
+    /// all nodes in the Graph. Required for processing nodes in parallel.
+    fn init_input_buffers(&mut self, block_size: usize) {
+        self.input_buffers =
+            vec![vec![0.0 as Sample; block_size].into_boxed_slice(); self.num_inputs()]
+                .into_boxed_slice();
+    }
     /// Use the embedded Gen to generate values that are placed in the
     /// output_buffer. The Graph will have already filled the input buffer with
     /// the correct values.
@@ -2789,11 +6124,141 @@ impl Node {
         input_buffers: &[Box<[Sample]>],
         resources: &mut Resources,
     ) -> GenState {
-        self.gen
-            .process(input_buffers, &mut self.output_buffers[..], resources)
+        #[cfg(feature = "rt-audit")]
+        let _audio_thread_guard = crate::rt_audit::AudioThreadGuard::new();
+        #[cfg(feature = "profiling")]
+        let start = std::time::Instant::now();
+        for &buffer in &self.buffers {
+            resources.mark_buffer_used(buffer);
+        }
+        let state = self
+            .gen
+            .process(input_buffers, &mut self.output_buffers[..], resources);
+        if let Some(replacement) = &mut self.replacement {
+            replacement.crossfade(input_buffers, &mut self.output_buffers, resources);
+        }
+        self.upsample_control_outputs();
+        if !(self.wet.is_unity() && self.level.is_unity()) {
+            self.apply_bypass_and_mute(input_buffers);
+        }
+        #[cfg(feature = "profiling")]
+        self.times.record(start);
+        state
+    }
+    fn set_bypassed(&mut self, bypassed: bool, immediately: bool) {
+        self.wet
+            .set_target(if bypassed { 0.0 } else { 1.0 }, immediately);
+    }
+    fn set_muted(&mut self, muted: bool, immediately: bool) {
+        self.level
+            .set_target(if muted { 0.0 } else { 1.0 }, immediately);
+    }
+    /// Crossfade the outputs with the inputs while bypassed and fade them out
+    /// while muted
+    #[inline]
+    fn apply_bypass_and_mute(&mut self, input_buffers: &[Box<[Sample]>]) {
+        let block_size = self.output_buffers.first().map_or(0, |output| output.len());
+        for i in 0..block_size {
+            let wet = self.wet.next();
+            let level = self.level.next();
+            for (index, output) in self.output_buffers.iter_mut().enumerate() {
+                let dry = input_buffers.get(index).map_or(0.0, |input| input[i]);
+                output[i] = (output[i] * wet + dry * (1.0 - wet)) * level;
+            }
+        }
+    }
+    /// Swap in the Gen of `replacement`, which then holds the old Gen. Returns
+    /// the previous replacement if one was still being crossfaded.
+    fn replace_gen(&mut self, mut replacement: Box<GenReplacement>) -> Option<Box<GenReplacement>> {
+        std::mem::swap(&mut self.gen, &mut replacement.gen);
+        std::mem::swap(&mut self.name, &mut replacement.name);
+        std::mem::swap(&mut self.control_outputs, &mut replacement.control_outputs);
+        std::mem::swap(&mut self.buffers, &mut replacement.buffers);
+        std::mem::swap(
+            &mut self.shared_resources,
+            &mut replacement.shared_resources,
+        );
+        replacement.applied = true;
+        self.replacement.replace(replacement)
+    }
+    /// True if the Gen, or the replaced Gen while it is being crossfaded,
+    /// uses the shared parts of the [`Resources`]
+    fn uses_shared_resources(&self) -> bool {
+        self.shared_resources
+            || self
+                .replacement
+                .as_ref()
+                .is_some_and(|replacement| replacement.shared_resources)
+    }
+    /// Take the replaced Gen if it is done crossfading
+    fn take_finished_replacement(&mut self) -> Option<Box<GenReplacement>> {
+        if self.replacement.as_ref()?.is_finished() {
+            self.replacement.take()
+        } else {
+            None
+        }
+    }
+    /// Fill the whole block of control rate outputs from their first sample
+    #[inline]
+    fn upsample_control_outputs(&mut self) {
+        for control in &mut self.control_outputs {
+            let output = &mut self.output_buffers[control.index];
+            let value = match output.first() {
+                Some(value) => *value,
+                None => continue,
+            };
+            if control.smooth {
+                let step = (value - control.last_value) / output.len() as Sample;
+                for (i, sample) in output.iter_mut().enumerate() {
+                    *sample = control.last_value + step * (i + 1) as Sample;
+                }
+            } else {
+                output.fill(value);
+            }
+            control.last_value = value;
+        }
     }
     pub fn set_constant(&mut self, value: Sample, input_index: usize) {
         self.input_constants[input_index] = value;
+        self.default_inputs[input_index] = false;
+    }
+    /// The constant of an input. A default constant is ignored if something
+    /// is connected to the input.
+    #[inline]
+    fn constant(&self, input_index: usize, connected: bool) -> Sample {
+        if connected && self.default_inputs[input_index] {
+            0.0
+        } else {
+            self.input_constants[input_index]
+        }
+    }
+    /// Set or remove the smoothing of an input. A new smoother starts from
+    /// the current value of the input so that only later changes are smoothed.
+    fn set_input_smoother(&mut self, input_index: usize, smoother: Option<InputSmoother>) {
+        let current = match &self.input_smoothers[input_index] {
+            Some(old) => old.value,
+            None => self.input_constants[input_index],
+        };
+        self.input_smoothers[input_index] = smoother.map(|mut smoother| {
+            smoother.reset(current);
+            smoother
+        });
+    }
+    /// Smooth the constant values in the input buffers for inputs with
+    /// smoothing
+    #[inline]
+    fn smooth_input_constants(&mut self, input_buffers: &mut [Box<[Sample]>]) {
+        for (smoother, input) in self
+            .input_smoothers
+            .iter_mut()
+            .zip(input_buffers.iter_mut())
+        {
+            if let Some(smoother) = smoother {
+                for sample in input.iter_mut() {
+                    *sample = smoother.next(*sample);
+                }
+            }
+        }
     }
     pub fn output_buffers(&self) -> &[Box<[Sample]>] {
         &self.output_buffers
@@ -2806,6 +6271,22 @@ impl Node {
     pub fn num_outputs(&self) -> usize {
         self.gen.num_outputs()
     }
+    /// The latency of the Gen, see [`Gen::latency`]
+    pub fn latency(&self) -> usize {
+        self.gen.latency()
+    }
+    pub fn input_kind(&self, input: usize) -> InputKind {
+        self.gen.input_kind(input)
+    }
+    pub fn input_required(&self, input: usize) -> bool {
+        self.gen.input_required(input)
+    }
+    pub fn input_rate(&self, input: usize) -> Rate {
+        self.gen.input_rate(input)
+    }
+    pub fn output_rate(&self, output: usize) -> Rate {
+        self.gen.output_rate(output)
+    }
     pub fn input_indices_to_names(&self) -> Vec<&'static str> {
         let mut list = vec![];
         for i in 0..self.num_inputs() {
@@ -2859,70 +6340,440 @@ impl Gen for FeedbackGen {
         self.num_channels
     }
 }
-#[derive(Clone, Debug, Copy)]
-struct Edge {
-    source: NodeKey,
-    /// the output index on the destination node
-    from_output_index: usize,
-    /// the input index on the origin node where the input from the node is placed
-    to_input_index: usize,
-}
-impl Edge {}
-
-/// Edge containing all metadata for a feedback connection since a feedback
-/// connection includes several things that may need to be freed together:
-/// - a node
-/// - a feedback edge
-/// - a normal edge
-#[derive(Clone, Debug, Copy)]
-struct FeedbackEdge {
-    source: NodeKey,
-    /// the output index on the destination node
-    from_output_index: usize,
-    /// the input index on the origin node where the input from the node is placed
-    to_input_index: usize,
-    /// If the source node is freed we want to remove the normal edge to the destination node.
-    feedback_destination: NodeKey,
-}
-
-#[derive(Default)]
-pub struct Ramp {
-    // Compare with the current value. If there is change, recalculate the step.
-    last_value: Sample,
-    // Compare with the current value. If there is change, recalculate the step.
-    last_time: Sample,
-    current_value: Sample,
-    step: Sample,
-    sample_rate: Sample,
+/// Runs a Gen with a smaller block size than the Graph it is in by running it
+/// several times per block. Used for inner graphs with a smaller block size.
+struct SubBlockGen<G: Gen> {
+    gen: G,
+    block_size: usize,
+    input_buffers: Vec<Box<[Sample]>>,
+    output_buffers: Vec<Box<[Sample]>>,
 }
 
-impl Ramp {
-    pub fn new() -> Self {
-        Self::default()
+impl<G: Gen> SubBlockGen<G> {
+    fn new(gen: G, block_size: usize) -> Self {
+        let input_buffers = (0..gen.num_inputs())
+            .map(|_| vec![0.0; block_size].into_boxed_slice())
+            .collect();
+        let output_buffers = (0..gen.num_outputs())
+            .map(|_| vec![0.0; block_size].into_boxed_slice())
+            .collect();
+        Self {
+            gen,
+            block_size,
+            input_buffers,
+            output_buffers,
+        }
     }
 }
-impl Gen for Ramp {
+
+impl<G: Gen> Gen for SubBlockGen<G> {
     fn process(
         &mut self,
         inputs: &[Box<[Sample]>],
         outputs: &mut [Box<[Sample]>],
-        _resources: &mut Resources,
+        resources: &mut Resources,
     ) -> GenState {
-        let values = &inputs[0];
-        let times = &inputs[1];
-        for ((value, time), out) in values.iter().zip(times.iter()).zip(outputs[0].iter_mut()) {
-            let mut recalculate = false;
-            if *value != self.last_value {
-                self.last_value = *value;
-                recalculate = true;
+        let outer_block_size = inputs
+            .first()
+            .or(outputs.first())
+            .map(|buf| buf.len())
+            .unwrap_or(self.block_size);
+        let mut state = GenState::Continue;
+        for start in (0..outer_block_size).step_by(self.block_size) {
+            let end = start + self.block_size;
+            for (inner, outer) in self.input_buffers.iter_mut().zip(inputs) {
+                inner.copy_from_slice(&outer[start..end]);
             }
-            if *time != self.last_time {
-                self.last_time = *time;
-                recalculate = true;
+            let sub_block_state =
+                self.gen
+                    .process(&self.input_buffers, &mut self.output_buffers, resources);
+            for (inner, outer) in self.output_buffers.iter().zip(outputs.iter_mut()) {
+                outer[start..end].copy_from_slice(inner);
             }
-            if recalculate {
-                let num_samples = (time * self.sample_rate).floor();
-                self.step = (value - self.current_value) / num_samples;
+            // Translate sample numbers to the outer block
+            state = match sub_block_state {
+                GenState::Continue => continue,
+                GenState::FreeGraph(from_sample_nr) => GenState::FreeGraph(start + from_sample_nr),
+                GenState::FreeGraphMendConnections(from_sample_nr) => {
+                    GenState::FreeGraphMendConnections(start + from_sample_nr)
+                }
+                other => other,
+            };
+            // The Gen wants to be freed so the rest of the block is silent
+            for output in outputs.iter_mut() {
+                output[end..].fill(0.0);
+            }
+            break;
+        }
+        state
+    }
+    fn num_inputs(&self) -> usize {
+        self.gen.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        self.gen.num_outputs()
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.gen.init(sample_rate)
+    }
+    fn sample_rate_changed(&mut self, sample_rate: Sample) {
+        self.gen.sample_rate_changed(sample_rate)
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        self.gen.input_desc(input)
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        self.gen.output_desc(output)
+    }
+    fn uses_shared_resources(&self) -> bool {
+        self.gen.uses_shared_resources()
+    }
+    fn save_state(&self) -> Option<SavedState> {
+        self.gen.save_state()
+    }
+    fn load_state(&mut self, state: &SavedState) -> Result<(), PatchError> {
+        self.gen.load_state(state)
+    }
+    fn latency(&self) -> usize {
+        self.gen.latency()
+    }
+    fn input_kind(&self, input: usize) -> InputKind {
+        self.gen.input_kind(input)
+    }
+    fn input_required(&self, input: usize) -> bool {
+        self.gen.input_required(input)
+    }
+    fn input_rate(&self, input: usize) -> Rate {
+        self.gen.input_rate(input)
+    }
+    fn output_rate(&self, output: usize) -> Rate {
+        self.gen.output_rate(output)
+    }
+    fn name(&self) -> &'static str {
+        self.gen.name()
+    }
+}
+
+/// Runs a Gen for a limited duration and then frees it, optionally fading
+/// out its outputs first. Used by [`Graph::push_with_duration`].
+struct FreeAfter<G: Gen> {
+    gen: G,
+    duration: Duration,
+    fade_out: Duration,
+    sample_rate: Sample,
+    /// The number of samples processed so far
+    elapsed: usize,
+    /// The number of samples to process before freeing the node
+    end: usize,
+    fade_samples: usize,
+}
+
+impl<G: Gen> FreeAfter<G> {
+    fn new(gen: G, duration: Duration, fade_out: Duration) -> Self {
+        Self {
+            gen,
+            duration,
+            fade_out: fade_out.min(duration),
+            sample_rate: 0.0,
+            elapsed: 0,
+            end: 0,
+            fade_samples: 0,
+        }
+    }
+    fn set_sample_rate(&mut self, sample_rate: Sample) {
+        if self.sample_rate > 0.0 {
+            self.elapsed =
+                (self.elapsed as f64 * sample_to_f64(sample_rate / self.sample_rate)) as usize;
+        }
+        self.sample_rate = sample_rate;
+        self.end = (self.duration.as_secs_f64() * sample_to_f64(sample_rate)) as usize;
+        self.fade_samples = (self.fade_out.as_secs_f64() * sample_to_f64(sample_rate)) as usize;
+    }
+}
+
+impl<G: Gen> Gen for FreeAfter<G> {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let state = self.gen.process(inputs, outputs, resources);
+        let block_size = outputs
+            .first()
+            .or(inputs.first())
+            .map_or(0, |buf| buf.len());
+        let fade_start = self.end - self.fade_samples;
+        if self.elapsed + block_size > fade_start {
+            for output in outputs.iter_mut() {
+                for (i, sample) in output.iter_mut().enumerate() {
+                    let position = self.elapsed + i;
+                    if position >= self.end {
+                        *sample = 0.0;
+                    } else if position >= fade_start {
+                        *sample *= (self.end - position) as Sample / self.fade_samples as Sample;
+                    }
+                }
+            }
+        }
+        self.elapsed += block_size;
+        match state {
+            GenState::Continue if self.elapsed >= self.end => GenState::FreeSelf,
+            state => state,
+        }
+    }
+    fn num_inputs(&self) -> usize {
+        self.gen.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        self.gen.num_outputs()
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.gen.init(sample_rate);
+        self.set_sample_rate(sample_rate);
+    }
+    fn sample_rate_changed(&mut self, sample_rate: Sample) {
+        self.gen.sample_rate_changed(sample_rate);
+        self.set_sample_rate(sample_rate);
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        self.gen.input_desc(input)
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        self.gen.output_desc(output)
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        self.gen.default_input(input)
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        self.gen.resource_refs()
+    }
+    fn uses_shared_resources(&self) -> bool {
+        self.gen.uses_shared_resources()
+    }
+    fn save_state(&self) -> Option<SavedState> {
+        self.gen.save_state()
+    }
+    fn load_state(&mut self, state: &SavedState) -> Result<(), PatchError> {
+        self.gen.load_state(state)
+    }
+    fn latency(&self) -> usize {
+        self.gen.latency()
+    }
+    fn input_kind(&self, input: usize) -> InputKind {
+        self.gen.input_kind(input)
+    }
+    fn input_required(&self, input: usize) -> bool {
+        self.gen.input_required(input)
+    }
+    fn input_rate(&self, input: usize) -> Rate {
+        self.gen.input_rate(input)
+    }
+    fn output_rate(&self, output: usize) -> Rate {
+        self.gen.output_rate(output)
+    }
+    fn name(&self) -> &'static str {
+        self.gen.name()
+    }
+}
+
+/// Delays its input by a whole number of samples to line up paths with
+/// different latencies. Added by [`Graph::compensate_latency`].
+pub struct LatencyCompensation {
+    delay: usize,
+    buffer: Vec<Sample>,
+    position: usize,
+}
+
+impl LatencyCompensation {
+    pub fn new(delay: usize) -> Self {
+        Self {
+            delay,
+            buffer: vec![],
+            position: 0,
+        }
+    }
+    /// The delay in samples
+    pub fn delay(&self) -> usize {
+        self.delay
+    }
+}
+
+impl Gen for LatencyCompensation {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        if self.buffer.is_empty() {
+            outputs[0].copy_from_slice(&inputs[0]);
+            return GenState::Continue;
+        }
+        for (&input, output) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            *output = self.buffer[self.position];
+            self.buffer[self.position] = input;
+            self.position = (self.position + 1) % self.buffer.len();
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn init(&mut self, _sample_rate: Sample) {
+        self.buffer = vec![0.0; self.delay];
+        self.position = 0;
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "in",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn latency(&self) -> usize {
+        self.delay
+    }
+    fn name(&self) -> &'static str {
+        "LatencyCompensation"
+    }
+}
+
+/// The groups of more than one node that depend on each other through
+/// `input_edges`, found using Tarjan's algorithm
+fn strongly_connected_nodes(
+    keys: &[NodeKey],
+    input_edges: &SecondaryMap<NodeKey, Vec<Edge>>,
+) -> Vec<Vec<NodeKey>> {
+    struct State {
+        next_index: usize,
+        indices: SecondaryMap<NodeKey, (usize, usize)>,
+        stack: Vec<NodeKey>,
+        on_stack: HashSet<NodeKey>,
+        components: Vec<Vec<NodeKey>>,
+    }
+    fn visit(key: NodeKey, input_edges: &SecondaryMap<NodeKey, Vec<Edge>>, state: &mut State) {
+        let index = state.next_index;
+        state.next_index += 1;
+        state.indices.insert(key, (index, index));
+        state.stack.push(key);
+        state.on_stack.insert(key);
+        for edge in input_edges.get(key).into_iter().flatten() {
+            let low_link = match state.indices.get(edge.source) {
+                None => {
+                    visit(edge.source, input_edges, state);
+                    state.indices[edge.source].1
+                }
+                Some(&(source_index, _)) if state.on_stack.contains(&edge.source) => source_index,
+                Some(_) => continue,
+            };
+            let indices = &mut state.indices[key];
+            indices.1 = indices.1.min(low_link);
+        }
+        if state.indices[key] == (index, index) {
+            let mut component = vec![];
+            while let Some(node) = state.stack.pop() {
+                state.on_stack.remove(&node);
+                component.push(node);
+                if node == key {
+                    break;
+                }
+            }
+            if component.len() > 1 {
+                component.reverse();
+                state.components.push(component);
+            }
+        }
+    }
+    let mut state = State {
+        next_index: 0,
+        indices: SecondaryMap::new(),
+        stack: vec![],
+        on_stack: HashSet::new(),
+        components: vec![],
+    };
+    for &key in keys {
+        if !state.indices.contains_key(key) {
+            visit(key, input_edges, &mut state);
+        }
+    }
+    state.components
+}
+
+#[derive(Clone, Debug, Copy)]
+struct Edge {
+    source: NodeKey,
+    /// the output index on the destination node
+    from_output_index: usize,
+    /// the input index on the origin node where the input from the node is placed
+    to_input_index: usize,
+    /// multiplies the signal before it is added to the input
+    gain: Sample,
+}
+impl Edge {}
+
+/// Edge containing all metadata for a feedback connection since a feedback
+/// connection includes several things that may need to be freed together:
+/// - a node
+/// - a feedback edge
+/// - a normal edge
+#[derive(Clone, Debug, Copy)]
+struct FeedbackEdge {
+    source: NodeKey,
+    /// the output index on the destination node
+    from_output_index: usize,
+    /// the input index on the origin node where the input from the node is placed
+    to_input_index: usize,
+    /// If the source node is freed we want to remove the normal edge to the destination node.
+    feedback_destination: NodeKey,
+}
+
+#[derive(Default)]
+pub struct Ramp {
+    // Compare with the current value. If there is change, recalculate the step.
+    last_value: Sample,
+    // Compare with the current value. If there is change, recalculate the step.
+    last_time: Sample,
+    current_value: Sample,
+    step: Sample,
+    sample_rate: Sample,
+}
+
+impl Ramp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl Gen for Ramp {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let values = &inputs[0];
+        let times = &inputs[1];
+        for ((value, time), out) in values.iter().zip(times.iter()).zip(outputs[0].iter_mut()) {
+            let mut recalculate = false;
+            if *value != self.last_value {
+                self.last_value = *value;
+                recalculate = true;
+            }
+            if *time != self.last_time {
+                self.last_time = *time;
+                recalculate = true;
+            }
+            if recalculate {
+                let num_samples = (time * self.sample_rate).floor();
+                self.step = (value - self.current_value) / num_samples;
             }
             if (self.current_value - value).abs() < 0.0001 {
                 self.current_value = *value;
@@ -2973,11 +6824,7 @@ impl Gen for Mult {
         outputs: &mut [Box<[Sample]>],
         _resources: &mut Resources,
     ) -> GenState {
-        let i0 = &inputs[0];
-        let i1 = &inputs[1];
-        for (o, (in0, in1)) in outputs[0].iter_mut().zip(i0.iter().zip(i1.iter())) {
-            *o = in0 * in1;
-        }
+        crate::simd::mul(&mut outputs[0], &inputs[0], &inputs[1]);
         GenState::Continue
     }
 
@@ -3005,6 +6852,43 @@ impl Gen for Mult {
         "Mult"
     }
 }
+pub struct Add;
+impl Gen for Add {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        outputs[0].copy_from_slice(&inputs[0]);
+        crate::simd::add_assign(&mut outputs[0], &inputs[1]);
+        GenState::Continue
+    }
+
+    fn num_inputs(&self) -> usize {
+        2
+    }
+
+    fn num_outputs(&self) -> usize {
+        1
+    }
+
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "value0",
+            1 => "value1",
+            _ => "",
+        }
+    }
+
+    fn output_desc(&self, _output: usize) -> &'static str {
+        "sum"
+    }
+
+    fn name(&self) -> &'static str {
+        "Add"
+    }
+}
 /// Pan a mono signal to stereo using the cos/sine pan law. Pan value should be between 0 and 1, 0.5 being in the center
 /// TODO: Implement multiple different pan laws, maybe as a generic.
 pub struct PanMonoToStereo;
@@ -3020,18 +6904,14 @@ impl Gen for PanMonoToStereo {
         let (lefts, rest) = outputs.split_at_mut(1);
         let lefts = &mut lefts[0];
         let rights = &mut rest[0];
-        for (((left, right), signal), pan) in lefts
-            .iter_mut()
-            .zip(rights.iter_mut())
-            .zip(signals.iter())
-            .zip(pans.iter())
-        {
-            let pan_pos_radians = pan * std::f32::consts::FRAC_PI_2;
-            let left_gain = fastapprox::fast::cos(pan_pos_radians);
-            let right_gain = fastapprox::fast::sin(pan_pos_radians);
-            *left = signal * left_gain;
-            *right = signal * right_gain;
+        // Calculate the gains in place, then apply them to the signal
+        for ((left, right), pan) in lefts.iter_mut().zip(rights.iter_mut()).zip(pans.iter()) {
+            let pan_pos_radians = sample_to_f32(*pan) * std::f32::consts::FRAC_PI_2;
+            *left = fastapprox::fast::cos(pan_pos_radians) as Sample;
+            *right = fastapprox::fast::sin(pan_pos_radians) as Sample;
         }
+        crate::simd::mul_assign(lefts, signals);
+        crate::simd::mul_assign(rights, signals);
         GenState::Continue
     }
 
@@ -3116,7 +6996,9 @@ use slotmap::{new_key_type, SecondaryMap, SlotMap};
 #[cfg(test)]
 mod tests {
 
-    use crate::ResourcesSettings;
+    use crate::buffer::{Buffer, BufferReader};
+    use crate::convolution::Convolution;
+    use crate::{ResourcesSettings, StopAction};
 
     use super::*;
     fn null_input() -> Vec<Box<[Sample]>> {
@@ -3152,7 +7034,7 @@ mod tests {
         }
     }
     struct DummyGen {
-        counter: f32,
+        counter: Sample,
     }
     impl Gen for DummyGen {
         fn process(
@@ -3273,37 +7155,186 @@ mod tests {
         );
     }
     #[test]
-    fn feedback_in_graph() {
-        // v-------------<
-        // |   2 -> 3 -> 4
-        // |   |    |
-        // fbv fbv fbv
-        //   0     -> 1 -> out
-        const BLOCK: usize = 4;
+    fn graph_in_a_graph_with_smaller_block_size() {
+        const BLOCK: usize = 16;
+        const INNER_BLOCK: usize = 4;
+        let mut inner_graph: Graph = Graph::new(GraphSettings {
+            block_size: INNER_BLOCK,
+            latency: Duration::from_millis(0),
+            ..Default::default()
+        });
+        let node = inner_graph.push_gen(DummyGen { counter: 0.0 });
+        inner_graph.connect(Connection::graph_output(node)).unwrap();
         let mut graph: Graph = Graph::new(GraphSettings {
             block_size: BLOCK,
             ..Default::default()
         });
-        let n0 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 0.0 })));
-        let n1 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 0.0 })));
-        let n2 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 4.0 })));
-        let n3 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 5.0 })));
-        let n4 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 1.0 })));
-        graph.connect(Connection::graph_output(n1)).unwrap();
+        let inner_graph_node = graph.push_graph(inner_graph);
         graph
-            .connect(Connection::graph_output(n0).to_index(1))
+            .connect(Connection::graph_output(inner_graph_node))
             .unwrap();
-        graph.connect(n0.to(n1)).unwrap();
-        graph.connect(n2.to(n3)).unwrap();
-        graph.connect(n3.to(n4)).unwrap();
-        graph.connect(n2.to(n0).feedback(true)).unwrap();
-        graph.connect(n3.to(n1).feedback(true)).unwrap();
-        graph.connect(n4.to(n0).feedback(true)).unwrap();
+        // Changes inside the inner graph have the granularity of its block size
+        graph
+            .schedule_change(ParameterChange::absolute_samples(node, 100.0, 8).i(0))
+            .unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        for i in 0..8 {
+            assert_eq!(graph_node.output_buffers()[0][i], (i + 1) as Sample);
+        }
+        assert_eq!(graph_node.output_buffers()[0][8], 109.0);
+        assert_eq!(graph_node.output_buffers()[0][BLOCK - 1], 116.0);
+    }
+    #[test]
+    fn parallel_processing() {
+        const BLOCK: usize = 16;
+        fn build_graph(parallel: Option<ParallelSettings>) -> Graph {
+            let mut graph: Graph = Graph::new(GraphSettings {
+                block_size: BLOCK,
+                parallel,
+                ..Default::default()
+            });
+            // Two layers of independent nodes mixed together in the end
+            let mix = graph.push_gen(DummyGen { counter: 0.0 });
+            for i in 0..8 {
+                let first = graph.push_gen(DummyGen { counter: 0.0 });
+                let second = graph.push_gen(DummyGen { counter: 0.0 });
+                graph.connect(constant(i as Sample).to(first)).unwrap();
+                graph.connect(first.to(second)).unwrap();
+                graph.connect(second.to(mix)).unwrap();
+            }
+            graph.connect(Connection::graph_output(mix)).unwrap();
+            graph
+        }
+        let mut graph = build_graph(None);
+        let mut parallel_graph = build_graph(Some(ParallelSettings {
+            num_threads: 2,
+            min_tasks_per_stage: 1,
+        }));
+        let mut parallel_graph_node = graph_node(&mut parallel_graph);
+        assert_eq!(parallel_graph.stage_lengths, vec![8, 8, 1]);
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        for _ in 0..4 {
+            graph_node.process(&null_input(), &mut resources);
+            parallel_graph_node.process(&null_input(), &mut resources);
+            assert_eq!(
+                graph_node.output_buffers()[0],
+                parallel_graph_node.output_buffers()[0]
+            );
+        }
+        // Changing the graph while it is running
+        let node = parallel_graph.push_gen(DummyGen { counter: 0.0 });
+        parallel_graph
+            .connect(Connection::graph_output(node))
+            .unwrap();
+        parallel_graph.commit_changes();
+        parallel_graph_node.process(&null_input(), &mut resources);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(
+            graph_node.output_buffers()[0][0] + 1.0,
+            parallel_graph_node.output_buffers()[0][0]
+        );
+    }
+    #[test]
+    fn parallel_processing_runs_resource_users_on_the_audio_thread() {
+        const BLOCK: usize = 4;
+        /// Outputs 1 when run on the audio thread
+        struct AudioThreadCheck;
+        impl Gen for AudioThreadCheck {
+            fn process(
+                &mut self,
+                _inputs: &[Box<[Sample]>],
+                outputs: &mut [Box<[Sample]>],
+                _resources: &mut Resources,
+            ) -> GenState {
+                let thread = std::thread::current();
+                let on_worker = thread
+                    .name()
+                    .is_some_and(|name| name.starts_with("knyst-worker"));
+                outputs[0].fill(if on_worker { 0.0 } else { 1.0 });
+                GenState::Continue
+            }
+            fn num_inputs(&self) -> usize {
+                0
+            }
+            fn num_outputs(&self) -> usize {
+                1
+            }
+            fn uses_shared_resources(&self) -> bool {
+                true
+            }
+        }
+        let mut resources = Resources::new(test_resources_settings());
+        let buffer = resources
+            .insert_buffer(Buffer::from_vec(vec![1.0, 2.0, 3.0, 4.0], 48000.))
+            .unwrap();
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            num_outputs: 3,
+            parallel: Some(ParallelSettings {
+                num_threads: 2,
+                min_tasks_per_stage: 1,
+            }),
+            ..Default::default()
+        });
+        for _ in 0..8 {
+            let reader = graph.push_gen(BufferReader::new(buffer, 1.0, StopAction::Continue));
+            graph.connect(Connection::graph_output(reader)).unwrap();
+            let check = graph.push_gen(AudioThreadCheck);
+            graph
+                .connect(Connection::graph_output(check).to_index(1))
+                .unwrap();
+            let one = graph.push_gen(OneGen {});
+            graph
+                .connect(Connection::graph_output(one).to_index(2))
+                .unwrap();
+        }
+        let mut graph_node = graph_node(&mut graph);
+        assert_eq!(graph.stage_lengths, vec![24]);
+        for _ in 0..4 {
+            graph_node.process(&null_input(), &mut resources);
+            // Every reader found the buffer on the audio thread
+            assert_eq!(&*graph_node.output_buffers()[0], &[8.0, 16.0, 24.0, 32.0]);
+            assert_eq!(&*graph_node.output_buffers()[1], &[8.0; BLOCK]);
+            assert_eq!(&*graph_node.output_buffers()[2], &[8.0; BLOCK]);
+        }
+    }
+    #[test]
+    fn feedback_in_graph() {
+        // v-------------<
+        // |   2 -> 3 -> 4
+        // |   |    |
+        // fbv fbv fbv
+        //   0     -> 1 -> out
+        const BLOCK: usize = 4;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            ..Default::default()
+        });
+        let n0 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 0.0 })));
+        let n1 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 0.0 })));
+        let n2 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 4.0 })));
+        let n3 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 5.0 })));
+        let n4 = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 1.0 })));
+        graph.connect(Connection::graph_output(n1)).unwrap();
+        graph
+            .connect(Connection::graph_output(n0).to_index(1))
+            .unwrap();
+        graph.connect(n0.to(n1)).unwrap();
+        graph.connect(n2.to(n3)).unwrap();
+        graph.connect(n3.to(n4)).unwrap();
+        graph.connect(n2.to(n0).feedback(true)).unwrap();
+        graph.connect(n3.to(n1).feedback(true)).unwrap();
+        graph.connect(n4.to(n0).feedback(true)).unwrap();
         let mut graph_node = graph_node(&mut graph);
         let mut resources = Resources::new(test_resources_settings());
         graph_node.process(&null_input(), &mut resources);
-        let block1 = vec![2.0f32, 4., 6., 8.];
-        let block2 = vec![39f32, 47., 55., 63.];
+        let block1 = vec![2.0 as Sample, 4., 6., 8.];
+        let block2 = vec![39.0 as Sample, 47., 55., 63.];
         for (&output, expected) in graph_node.output_buffers()[0].iter().zip(block1) {
             assert_eq!(
                 output,
@@ -3349,11 +7380,11 @@ mod tests {
             graph_node.process(&null_input(), &mut resources);
             assert_eq!(
                 graph_node.output_buffers()[0][0],
-                (i + 1) as f32 * 0.5 + triangular_sequence(i + 1) as f32,
+                (i + 1) as Sample * 0.5 + triangular_sequence(i + 1) as Sample,
                 "i: {}, output: {}, expected: {}",
                 i,
                 graph_node.output_buffers()[0][0],
-                (i + 1) as f32 * 0.5 + triangular_sequence(i + 1) as f32,
+                (i + 1) as Sample * 0.5 + triangular_sequence(i + 1) as Sample,
             );
         }
     }
@@ -3667,4 +7698,1122 @@ mod tests {
         // increasing the number of iterations above.
         assert_eq!(graph_node.output_buffers()[0][0], 1002.0);
     }
+    #[test]
+    fn beat_scheduling() {
+        const BLOCK: usize = 4;
+        const SR: usize = 48000;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            sample_rate: SR as Sample,
+            latency: Duration::from_millis(0),
+            ..Default::default()
+        });
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        let node0 = graph.push_gen(OneGen {});
+        graph.connect(Connection::graph_output(node0)).unwrap();
+        graph.commit_changes();
+        // One beat every 8 samples
+        graph
+            .replace_tempo_change(
+                0,
+                TempoChange::NewTempo {
+                    bpm: 60.0 * SR as f64 / 8.0,
+                },
+            )
+            .unwrap();
+        // The transport is stopped so this change waits for it to start
+        graph
+            .schedule_change(ParameterChange::beats(node0, 5.0, Beats::from_beats(1)).i(0))
+            .unwrap();
+        graph.transport_play().unwrap();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(resources.transport.state, TransportState::Playing);
+        assert_eq!(graph_node.output_buffers()[0][3], 1.0);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(resources.transport.beats, Beats::from_fraction(1, 2));
+        assert_eq!(graph_node.output_buffers()[0][3], 1.0);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 6.0);
+        assert_eq!(graph.current_beats(), Some(Beats::from_fraction(3, 2)));
+        graph.transport_stop().unwrap();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(resources.transport.state, TransportState::Stopped);
+        assert_eq!(resources.transport.beats, Beats::ZERO);
+    }
+    #[test]
+    fn late_transport_commands_keep_timelines_in_sync() {
+        let timeline = TransportTimeline::new(MusicalTimeMap::new(), 48000.0);
+        let (producer, consumer) = RingBuffer::new(8);
+        let (applied_at_producer, applied_at_consumer) = RingBuffer::new(8);
+        let mut control = TransportControl::new(timeline.clone(), producer, applied_at_consumer);
+        let mut graph_gen_transport = GraphGenTransport {
+            timeline,
+            consumer,
+            applied_at: applied_at_producer,
+        };
+        let mut resources = Resources::new(test_resources_settings());
+        control
+            .send(TransportCommand {
+                at_sample: 10,
+                kind: TransportCommandKind::Play,
+            })
+            .unwrap();
+        control
+            .send(TransportCommand {
+                at_sample: 20,
+                kind: TransportCommandKind::Seek(Beats::from_beats(4)),
+            })
+            .unwrap();
+        // The GraphGen has already passed both samples when the commands arrive
+        graph_gen_transport.update(64, 16, &mut resources);
+        control.update();
+        assert!(control.pending.is_empty());
+        for sample in [64, 100, 48064] {
+            assert_eq!(
+                control.timeline.beats_at(sample),
+                graph_gen_transport.timeline.beats_at(sample)
+            );
+        }
+        assert_eq!(
+            control.timeline.sample_of(Beats::from_beats(5)),
+            Some(48064)
+        );
+    }
+
+    /// Counts blocks at control rate
+    struct ControlCounter {
+        rate: Rate,
+        counter: Sample,
+    }
+    impl Gen for ControlCounter {
+        fn process(
+            &mut self,
+            _inputs: &[Box<[Sample]>],
+            outputs: &mut [Box<[Sample]>],
+            _resources: &mut Resources,
+        ) -> GenState {
+            self.counter += 1.0;
+            outputs[0][0] = self.counter * 4.0;
+            GenState::Continue
+        }
+        fn num_inputs(&self) -> usize {
+            0
+        }
+        fn num_outputs(&self) -> usize {
+            1
+        }
+        fn output_rate(&self, _output: usize) -> Rate {
+            self.rate
+        }
+    }
+    /// Holds the first sample of its input for the whole block
+    struct ControlReader;
+    impl Gen for ControlReader {
+        fn process(
+            &mut self,
+            inputs: &[Box<[Sample]>],
+            outputs: &mut [Box<[Sample]>],
+            _resources: &mut Resources,
+        ) -> GenState {
+            outputs[0].fill(inputs[0][0]);
+            GenState::Continue
+        }
+        fn num_inputs(&self) -> usize {
+            1
+        }
+        fn num_outputs(&self) -> usize {
+            1
+        }
+        fn input_rate(&self, _input: usize) -> Rate {
+            Rate::Control
+        }
+    }
+    #[test]
+    fn control_rate_outputs() {
+        const BLOCK: usize = 4;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            ..Default::default()
+        });
+        let held = graph.push_gen(ControlCounter {
+            rate: Rate::Control,
+            counter: 0.0,
+        });
+        let smooth = graph.push_gen(ControlCounter {
+            rate: Rate::SmoothControl,
+            counter: 0.0,
+        });
+        let held_plus_one = graph.push_node(Node::new("One", Box::new(OneGen {})));
+        graph.connect(held.to(held_plus_one)).unwrap();
+        graph
+            .connect(Connection::graph_output(held_plus_one))
+            .unwrap();
+        graph
+            .connect(Connection::graph_output(smooth).to_index(1))
+            .unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[5.0; BLOCK]);
+        assert_eq!(&*graph_node.output_buffers()[1], &[1.0, 2.0, 3.0, 4.0]);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[9.0; BLOCK]);
+        assert_eq!(&*graph_node.output_buffers()[1], &[5.0, 6.0, 7.0, 8.0]);
+    }
+    #[test]
+    fn smoothed_constants() {
+        const BLOCK: usize = 8;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            sample_rate: 1000.,
+            ..Default::default()
+        });
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        let node = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        graph.connect(Connection::graph_output(node)).unwrap();
+        // 4 samples at 1000 Hz
+        graph
+            .connect(
+                constant(1.0)
+                    .to(node)
+                    .smoothing(Smoothing::Linear(Duration::from_millis(4))),
+            )
+            .unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(
+            &*graph_node.output_buffers()[0],
+            &[1.25, 1.5, 1.75, 2.0, 2.0, 2.0, 2.0, 2.0]
+        );
+        // The smoothing stays for later changes
+        graph.connect(constant(3.0).to(node)).unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(
+            &*graph_node.output_buffers()[0],
+            &[2.5, 3.0, 3.5, 4.0, 4.0, 4.0, 4.0, 4.0]
+        );
+        graph
+            .connect(constant(1.0).to(node).smoothing(Smoothing::None))
+            .unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[2.0; BLOCK]);
+    }
+
+    #[test]
+    fn input_modes() {
+        const BLOCK: usize = 4;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            ..Default::default()
+        });
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        let first = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let second = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let sink = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        graph.connect(constant(1.0).to(first)).unwrap();
+        graph.connect(constant(2.0).to(second)).unwrap();
+        graph.connect(constant(10.0).to(sink)).unwrap();
+        graph.connect(first.to(sink)).unwrap();
+        graph.connect(second.to(sink)).unwrap();
+        graph.connect(Connection::graph_output(sink)).unwrap();
+        assert_eq!(graph.input_mode(sink, 0), Some(InputMode::Add));
+        // The sink gets 2.0 and 3.0 from the other nodes
+        for (mode, expected) in [
+            (InputMode::Add, 16.0),
+            (InputMode::Multiply, 7.0),
+            (InputMode::Max, 4.0),
+            (InputMode::Replace, 4.0),
+        ] {
+            graph.set_input_mode(sink, 0, mode).unwrap();
+            graph.commit_changes();
+            graph.update();
+            graph_node.process(&null_input(), &mut resources);
+            assert_eq!(&*graph_node.output_buffers()[0], &[expected; BLOCK]);
+        }
+        // Without connections the constant is used
+        graph.disconnect(first.to(sink)).unwrap();
+        graph.disconnect(second.to(sink)).unwrap();
+        graph.set_input_mode(sink, 0, InputMode::Multiply).unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[11.0; BLOCK]);
+        assert_eq!(
+            graph.set_input_mode(sink, 1, InputMode::Max),
+            Err(ConnectionError::ChannelOutOfBounds)
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            ..Default::default()
+        });
+        let resources = Resources::new(test_resources_settings());
+        assert_eq!(graph.validate(&resources), Ok(()));
+        let filter = graph.push_gen(crate::filter::DcBlocker::new());
+        let pan = graph.push_gen(PanMonoToStereo);
+        let first = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let second = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        graph.connect(first.to(second)).unwrap();
+        graph.connect(second.to(first)).unwrap();
+        graph.connect(Connection::graph_output(pan)).unwrap();
+        let mut other_resources = Resources::new(test_resources_settings());
+        let buffer = other_resources
+            .insert_buffer(Buffer::from_vec(vec![0.0; 4], 44100.))
+            .unwrap();
+        let reader = graph.push_gen(BufferReader::new(buffer, 1.0, StopAction::Continue));
+        let errors = graph.validate(&resources).unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(errors.contains(&ValidationError::UnconnectedInput {
+            node: filter,
+            input: 0,
+            label: "signal",
+        }));
+        assert!(errors.contains(&ValidationError::OutputChannelMismatch {
+            from: pan,
+            outputs: 2,
+            connected: 1,
+            expected: 2,
+        }));
+        assert!(
+            errors.contains(&ValidationError::Cycle {
+                nodes: vec![first, second],
+            }) || errors.contains(&ValidationError::Cycle {
+                nodes: vec![second, first],
+            })
+        );
+        assert!(errors.contains(&ValidationError::MissingResource {
+            node: reader,
+            resource: ResourceRef::Buffer(buffer),
+        }));
+
+        graph.free_node(filter).unwrap();
+        graph.free_node(first).unwrap();
+        graph.free_node(reader).unwrap();
+        graph
+            .connect(Connection::graph_output(pan).channels(2))
+            .unwrap();
+        assert_eq!(graph.validate(&resources), Ok(()));
+    }
+
+    #[test]
+    fn groups() {
+        const BLOCK: usize = 4;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            ..Default::default()
+        });
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        let voices = graph.add_group();
+        let effects = graph.add_group();
+        let effect = graph
+            .push_to_group(OneGen {}, effects, GroupPosition::Tail)
+            .unwrap();
+        let second = graph
+            .push_to_group(OneGen {}, voices, GroupPosition::Tail)
+            .unwrap();
+        let first = graph
+            .push_to_group(OneGen {}, voices, GroupPosition::Head)
+            .unwrap();
+        let ungrouped = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        graph.connect(first.to(ungrouped)).unwrap();
+        graph.connect(Connection::graph_output(ungrouped)).unwrap();
+        graph.connect(Connection::graph_output(second)).unwrap();
+        assert_eq!(graph.group_nodes(voices), Some(vec![first, second]));
+        graph.commit_changes();
+        let position = |node: NodeAddress| graph.node_order.iter().position(|&key| key == node.key);
+        assert!(position(first) < position(second));
+        assert!(position(second) < position(effect));
+        assert!(position(first) < position(ungrouped));
+
+        // Only connections leaving the group are scaled
+        graph.set_group_gain(voices, 0.5).unwrap();
+        graph
+            .move_node(ungrouped, voices, GroupPosition::Tail)
+            .unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        // (2 + 1) * 0.5
+        assert_eq!(&*graph_node.output_buffers()[0], &[1.5; BLOCK]);
+
+        graph.free_group(voices).unwrap();
+        assert_eq!(graph.group_nodes(voices), None);
+        assert!(!graph.contains_node(first));
+        assert!(!graph.contains_node(ungrouped));
+        assert_eq!(
+            graph.move_node(effect, voices, GroupPosition::Head),
+            Err(GroupError::GroupNotFound)
+        );
+    }
+
+    #[test]
+    fn groups_in_parallel() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            parallel: Some(ParallelSettings {
+                num_threads: 2,
+                min_tasks_per_stage: 1,
+            }),
+            ..Default::default()
+        });
+        let group = graph.add_group();
+        let nodes: Vec<NodeAddress> = (0..3)
+            .map(|_| {
+                graph
+                    .push_to_group(OneGen {}, group, GroupPosition::Tail)
+                    .unwrap()
+            })
+            .collect();
+        let ungrouped = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        for &node in nodes.iter().chain([&ungrouped]) {
+            graph.connect(Connection::graph_output(node)).unwrap();
+        }
+        let mut graph_node = graph_node(&mut graph);
+        // The nodes in the group run in separate stages, the ungrouped node
+        // runs at the same time as the first one
+        assert_eq!(graph.stage_lengths, vec![2, 1, 1]);
+        let position = |node: NodeAddress| graph.node_order.iter().position(|&key| key == node.key);
+        assert!(position(nodes[0]) < Some(2));
+        assert_eq!(position(nodes[1]), Some(2));
+        assert_eq!(position(nodes[2]), Some(3));
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[4.0; 4]);
+    }
+
+    #[test]
+    fn run_order() {
+        let mut graph: Graph = Graph::new(GraphSettings::default());
+        let _graph_node = graph_node(&mut graph);
+        let source = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let effect = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        graph.connect(source.to(effect)).unwrap();
+        graph.connect(Connection::graph_output(effect)).unwrap();
+        let analysis = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let early = graph.push_before(OneGen {}, source).unwrap();
+        graph.run_after(analysis, effect).unwrap();
+        // Contradicts the connection and is ignored
+        graph.run_before(effect, source).unwrap();
+        graph.commit_changes();
+        let position = |node: NodeAddress| graph.node_order.iter().position(|&key| key == node.key);
+        assert!(position(early) < position(source));
+        assert!(position(source) < position(effect));
+        assert!(position(effect) < position(analysis));
+        assert_eq!(
+            graph.run_before(effect, effect),
+            Err(ConnectionError::SameNode)
+        );
+    }
+
+    #[test]
+    fn run_order_in_parallel() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            parallel: Some(ParallelSettings {
+                num_threads: 2,
+                min_tasks_per_stage: 1,
+            }),
+            ..Default::default()
+        });
+        let source = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let analysis = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let other = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        for node in [source, analysis, other] {
+            graph.connect(Connection::graph_output(node)).unwrap();
+        }
+        graph.run_after(analysis, source).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        // The analysis node runs in a later stage than the source, the other
+        // node at the same time as the source
+        assert_eq!(graph.stage_lengths, vec![2, 1]);
+        let position = |node: NodeAddress| graph.node_order.iter().position(|&key| key == node.key);
+        assert!(position(source) < Some(2));
+        assert!(position(other) < Some(2));
+        assert_eq!(position(analysis), Some(2));
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[3.0; 4]);
+    }
+
+    #[test]
+    fn bundles() {
+        const BLOCK: usize = 4;
+        // The TaskData of a bundle is applied at the same time whether the
+        // Graph processes in parallel or not
+        for parallel in [
+            None,
+            Some(ParallelSettings {
+                num_threads: 2,
+                min_tasks_per_stage: 1,
+            }),
+        ] {
+            let mut graph: Graph = Graph::new(GraphSettings {
+                block_size: BLOCK,
+                parallel,
+                ..Default::default()
+            });
+            let mut graph_node = graph_node(&mut graph);
+            let mut resources = Resources::new(test_resources_settings());
+            let existing = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+            graph.connect(Connection::graph_output(existing)).unwrap();
+            graph.commit_changes();
+            // Rounded down to the start of the third block
+            let time = TimeKind::AbsoluteSample(BLOCK as u64 * 2 + 1);
+            graph
+                .bundle(time, |graph| -> Result<(), ConnectionError> {
+                    let voice = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+                    graph.connect(constant(1.0).to(voice))?;
+                    graph.connect(Connection::graph_output(voice))?;
+                    graph.connect(constant(5.0).to(existing))?;
+                    // Waits for the end of the bundle
+                    graph.commit_changes();
+                    Ok(())
+                })
+                .unwrap()
+                .unwrap();
+            for expected in [1.0, 1.0, 8.0] {
+                graph_node.process(&null_input(), &mut resources);
+                assert_eq!(&*graph_node.output_buffers()[0], &[expected; BLOCK]);
+            }
+        }
+    }
+
+    #[test]
+    fn named_connections() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 1,
+            ..Default::default()
+        });
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        let first = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let second = graph.push_gen(Mult);
+        graph.set_node_name(first, "one").unwrap();
+        graph.set_node_name(second, "mult").unwrap();
+        assert_eq!(
+            graph.set_node_name(second, "one"),
+            Err(ConnectionError::NodeNameTaken("one".to_string()))
+        );
+        assert_eq!(graph.node_by_name("mult"), Some(second));
+        assert_eq!(graph.node_name(first), Some("one"));
+        graph
+            .connect_by_name(("one", "0"), ("mult", "value0"))
+            .unwrap();
+        assert_eq!(
+            graph.connect_by_name(("one", "0"), ("mult", "value2")),
+            Err(ConnectionError::PortNameNotFound("value2".to_string()))
+        );
+        assert_eq!(
+            graph.connect_by_name(("two", "0"), ("mult", "value0")),
+            Err(ConnectionError::NodeNameNotFound("two".to_string()))
+        );
+        graph
+            .connect(constant(3.0).to(second).to_label("value1"))
+            .unwrap();
+        graph.connect(Connection::graph_output(second)).unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 3.0);
+        graph.free_node(first).unwrap();
+        assert_eq!(graph.node_by_name("one"), None);
+    }
+
+    #[test]
+    fn dot_export() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            num_inputs: 1,
+            ..Default::default()
+        });
+        let one = graph.push_node(Node::new("OneGen", Box::new(OneGen {})));
+        let mult = graph.push_gen(Mult);
+        graph.connect(Connection::graph_input(one)).unwrap();
+        graph.connect(one.to(mult)).unwrap();
+        graph.connect(constant(0.5).to(mult).to_index(1)).unwrap();
+        graph.connect(mult.to(one).feedback(true)).unwrap();
+        let inner = Graph::new(GraphSettings {
+            num_inputs: 1,
+            ..Default::default()
+        });
+        let inner_id = inner.id;
+        let inner = graph.push_graph(inner);
+        graph.connect(mult.to(inner)).unwrap();
+        let dot = graph.to_dot();
+        let id = graph.id;
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains(&format!(
+            "g{id}_n0 [label=\"{{{{<i0> passthrough}}|OneGen|{{<o0> 0}}}}\"];"
+        )));
+        assert!(dot.contains("<i1> value1 = 0.5"));
+        assert!(dot.contains(&format!("g{id}_in:p0 -> g{id}_n0:i0;")));
+        assert!(dot.contains(&format!("g{id}_n1:o0 -> g{id}_n0:i0 [style=dashed];")));
+        assert!(dot.contains(&format!("subgraph cluster_g{inner_id} {{")));
+        assert!(dot.contains(&format!("g{id}_n1:o0 -> g{inner_id}_in:p0;")));
+    }
+
+    #[test]
+    fn control_rate_inputs() {
+        const BLOCK: usize = 4;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            ..Default::default()
+        });
+        let counter = graph.push_node(Node::new("Dummy", Box::new(DummyGen { counter: 0.0 })));
+        let reader = graph.push_gen(ControlReader);
+        graph.connect(counter.to(reader)).unwrap();
+        graph.connect(Connection::graph_output(reader)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[1.0; BLOCK]);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(&*graph_node.output_buffers()[0], &[5.0; BLOCK]);
+    }
+
+    #[test]
+    fn sample_rate_change() {
+        // Outputs the sample rate it was last initialised with
+        struct SampleRateGen {
+            sample_rate: Sample,
+        }
+        impl Gen for SampleRateGen {
+            fn process(
+                &mut self,
+                _inputs: &[Box<[Sample]>],
+                outputs: &mut [Box<[Sample]>],
+                _resources: &mut Resources,
+            ) -> GenState {
+                outputs[0].fill(self.sample_rate);
+                GenState::Continue
+            }
+            fn init(&mut self, sample_rate: Sample) {
+                self.sample_rate = sample_rate;
+            }
+            fn num_inputs(&self) -> usize {
+                0
+            }
+            fn num_outputs(&self) -> usize {
+                1
+            }
+        }
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            sample_rate: 48000.,
+            ..Default::default()
+        });
+        let probe = graph.push_gen(SampleRateGen { sample_rate: 0.0 });
+        graph.connect(Connection::graph_output(probe)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 48000.);
+        graph_node.sample_rate_changed(96000.);
+        resources.set_sample_rate(96000.);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 96000.);
+        graph.update();
+        assert_eq!(graph.sample_rate(), 96000.);
+        let expected = Resources::new(ResourcesSettings {
+            sample_rate: 96000.,
+            ..test_resources_settings()
+        });
+        assert_eq!(resources.freq_to_phase_inc, expected.freq_to_phase_inc);
+    }
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn profiling_report() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            ..Default::default()
+        });
+        let mut inner = Graph::new(GraphSettings {
+            block_size: 4,
+            ..Default::default()
+        });
+        let inner_node = inner.push_gen(Mult);
+        inner.connect(Connection::graph_output(inner_node)).unwrap();
+        let inner = graph.push_graph(inner);
+        graph.connect(Connection::graph_output(inner)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        for _ in 0..3 {
+            graph_node.process(&null_input(), &mut resources);
+        }
+        let report = graph.profiling_report();
+        assert_eq!(report.nodes.len(), 2);
+        assert!(report.nodes.iter().all(|node| node.calls == 3));
+        assert!(report.nodes.iter().any(|node| node.address == inner_node));
+        assert!(report.max_load > 0.0);
+        assert!(report.max_load >= report.average_load);
+        graph.reset_profiling();
+        let report = graph.profiling_report();
+        assert!(report.nodes.iter().all(|node| node.calls == 0));
+        assert_eq!(report.max_load, 0.0);
+    }
+    #[test]
+    fn replace_gen_with_crossfade() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            sample_rate: 8000.,
+            ..Default::default()
+        });
+        let node = graph.push_gen(OneGen {});
+        graph.connect(constant(1.0).to(node)).unwrap();
+        graph.connect(Connection::graph_output(node)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 2.0);
+        assert_eq!(
+            graph.replace_gen(node, Mult),
+            Err(ReplaceGenError::ChannelMismatch {
+                node_inputs: 1,
+                node_outputs: 1,
+                gen_inputs: 2,
+                gen_outputs: 1,
+            })
+        );
+        let times_four = gen(|inputs, outputs, _resources| {
+            for (i, o) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+                *o = i * 4.0;
+            }
+            GenState::Continue
+        })
+        .input("in")
+        .output("out");
+        // 8 samples at 8000 Hz
+        graph
+            .replace_gen_with_crossfade(node, times_four, Duration::from_millis(1))
+            .unwrap();
+        let mut output = vec![];
+        for _ in 0..3 {
+            graph_node.process(&null_input(), &mut resources);
+            output.extend_from_slice(&graph_node.output_buffers()[0]);
+        }
+        let expected: Vec<Sample> = (0..12)
+            .map(|i| 2.0 + (i as Sample / 8.0).min(1.0) * 2.0)
+            .collect();
+        assert_eq!(output, expected);
+        // The new input name is used for connections
+        graph
+            .connect(constant(2.0).to(node).to_label("in"))
+            .unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 8.0);
+    }
+    #[test]
+    fn replace_graph_with_crossfade() {
+        let settings = GraphSettings {
+            block_size: 4,
+            sample_rate: 8000.,
+            ..Default::default()
+        };
+        let inner_graph = |value: Sample| {
+            let mut inner = Graph::new(settings);
+            let node = inner.push_gen(OneGen {});
+            inner.connect(constant(value).to(node)).unwrap();
+            inner.connect(Connection::graph_output(node)).unwrap();
+            (inner, node)
+        };
+        let mut graph: Graph = Graph::new(settings);
+        let (first, _) = inner_graph(0.0);
+        let first_id = first.id;
+        let inner = graph.push_graph(first);
+        graph.connect(Connection::graph_output(inner)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 1.0);
+        // Built without access to the running Graph
+        let (mut second, second_node) = inner_graph(2.0);
+        second.commit_changes();
+        let old = graph
+            .replace_graph(inner, second, Duration::from_millis(1))
+            .unwrap();
+        assert_eq!(old.id, first_id);
+        let mut output = vec![];
+        for _ in 0..3 {
+            graph_node.process(&null_input(), &mut resources);
+            output.extend_from_slice(&graph_node.output_buffers()[0]);
+        }
+        let expected: Vec<Sample> = (0..12)
+            .map(|i| 1.0 + (i as Sample / 8.0).min(1.0) * 2.0)
+            .collect();
+        assert_eq!(output, expected);
+        // The new Graph can be changed through the parent
+        graph.connect(constant(4.0).to(second_node)).unwrap();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 5.0);
+    }
+    #[test]
+    fn bypass_and_mute_node() {
+        // Ramps of 4 samples
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            sample_rate: 800.,
+            ..Default::default()
+        });
+        let node = graph.push_gen(OneGen {});
+        graph.connect(constant(1.0).to(node)).unwrap();
+        graph.connect(Connection::graph_output(node)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [2.0; 4]);
+        graph.set_node_bypassed(node, true).unwrap();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [1.75, 1.5, 1.25, 1.0]);
+        graph.set_node_mute(node, true).unwrap();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [0.75, 0.5, 0.25, 0.0]);
+        graph.set_node_bypassed(node, false).unwrap();
+        graph.set_node_mute(node, false).unwrap();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [2.0; 4]);
+    }
+    #[test]
+    fn monitor_taps() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 1,
+            num_monitor_outputs: 2,
+            ..Default::default()
+        });
+        assert_eq!(graph.num_outputs(), 3);
+        assert_eq!(graph.monitor_outputs(), 1..3);
+        let main = graph.push_gen(OneGen {});
+        let tapped = graph.push_gen(OneGen {});
+        graph.connect(constant(1.0).to(main)).unwrap();
+        graph.connect(constant(3.0).to(tapped)).unwrap();
+        graph.connect(Connection::graph_output(main)).unwrap();
+        assert_eq!(
+            graph.add_monitor_tap(MonitorTap::new(tapped).to_index(1).channels(2)),
+            Err(ConnectionError::ChannelOutOfBounds)
+        );
+        let tap = MonitorTap::new(tapped).to_index(1);
+        graph.add_monitor_tap(tap.gain(0.5)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        let mut process = |graph_node: &mut Node| {
+            graph_node.process(&null_input(), &mut resources);
+            let outputs = graph_node.output_buffers();
+            [outputs[0][0], outputs[1][0], outputs[2][0]]
+        };
+        assert_eq!(process(&mut graph_node), [2.0, 0.0, 2.0]);
+        // Adding the tap again replaces it
+        graph.add_monitor_tap(tap).unwrap();
+        graph.commit_changes();
+        graph.update();
+        assert_eq!(process(&mut graph_node), [2.0, 0.0, 4.0]);
+        graph.remove_monitor_tap(tap).unwrap();
+        graph.commit_changes();
+        graph.update();
+        assert_eq!(process(&mut graph_node), [2.0, 0.0, 0.0]);
+    }
+    #[test]
+    fn latency_compensation() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 2,
+            ..Default::default()
+        });
+        let mut counter = 0.0;
+        let counter = graph.push_gen(
+            gen(move |_inputs, outputs, _resources| {
+                for out in outputs[0].iter_mut() {
+                    counter += 1.0;
+                    *out = counter;
+                }
+                GenState::Continue
+            })
+            .output("out"),
+        );
+        let delay = graph.push_gen(LatencyCompensation::new(3));
+        graph.connect(counter.to(delay)).unwrap();
+        graph.connect(Connection::graph_output(counter)).unwrap();
+        graph
+            .connect(Connection::graph_output(delay).to_index(1))
+            .unwrap();
+        assert_eq!(graph.node_latency(counter), Some(0));
+        assert_eq!(graph.node_latency(delay), Some(3));
+        assert_eq!(graph.latency(), 3);
+        assert_eq!(graph.compensate_latency(), Ok(1));
+        // The paths are already aligned
+        assert_eq!(graph.compensate_latency(), Ok(0));
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(graph_node.output_buffers()[1][..], [0.0, 0.0, 0.0, 1.0]);
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(graph_node.output_buffers()[1][..], [2.0, 3.0, 4.0, 5.0]);
+    }
+    #[test]
+    fn latency_compensation_with_convolution() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 2,
+            ..Default::default()
+        });
+        let mut counter = 0.0;
+        let counter = graph.push_gen(
+            gen(move |_inputs, outputs, _resources| {
+                for out in outputs[0].iter_mut() {
+                    counter += 1.0;
+                    *out = counter;
+                }
+                GenState::Continue
+            })
+            .output("out"),
+        );
+        // An impulse response of a single impulse passes the signal through
+        let convolution = graph.push_gen(Convolution::new(&[1.0], 8));
+        graph.connect(counter.to(convolution)).unwrap();
+        graph.connect(Connection::graph_output(counter)).unwrap();
+        graph
+            .connect(Connection::graph_output(convolution).to_index(1))
+            .unwrap();
+        assert_eq!(graph.node_latency(convolution), Some(8));
+        assert_eq!(graph.compensate_latency(), Ok(1));
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        let mut dry = vec![];
+        let mut wet = vec![];
+        for _ in 0..4 {
+            graph_node.process(&null_input(), &mut resources);
+            dry.extend_from_slice(&graph_node.output_buffers()[0]);
+            wet.extend_from_slice(&graph_node.output_buffers()[1]);
+        }
+        assert_eq!(dry[..8], [0.0; 8]);
+        assert_eq!(dry[8..], [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        for (dry, wet) in dry.iter().zip(&wet) {
+            assert!((dry - wet).abs() < 1e-4, "{dry} {wet}");
+        }
+    }
+    #[test]
+    fn push_with_duration() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            sample_rate: 8000.,
+            ..Default::default()
+        });
+        let ones = gen(|_inputs, outputs, _resources| {
+            outputs[0].fill(1.0);
+            GenState::Continue
+        })
+        .output("out");
+        // 6 samples, fading out over the last 4
+        let node = graph.push_with_duration_and_fade_out(
+            ones,
+            Duration::from_micros(750),
+            Duration::from_micros(500),
+        );
+        graph.connect(Connection::graph_output(node)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        let mut output = vec![];
+        for _ in 0..2 {
+            graph_node.process(&null_input(), &mut resources);
+            output.extend_from_slice(&graph_node.output_buffers()[0]);
+        }
+        assert_eq!(output, [1.0, 1.0, 1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+        assert_eq!(graph.num_nodes(), 1);
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&null_input(), &mut resources);
+        graph.commit_changes();
+        assert_eq!(graph.num_nodes(), 0);
+    }
+    #[test]
+    fn multiple_outputs_fan_out() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 3,
+            ..Default::default()
+        });
+        // Outputs its input multiplied by 1, 2 and 3
+        let multiples = gen(|inputs, outputs, _resources| {
+            for (factor, output) in outputs.iter_mut().enumerate() {
+                for (o, i) in output.iter_mut().zip(inputs[0].iter()) {
+                    *o = i * (factor + 1) as Sample;
+                }
+            }
+            GenState::Continue
+        })
+        .input("in")
+        .output("single")
+        .output("double")
+        .output("triple");
+        let multiples = graph.push_gen(multiples);
+        let mult = graph.push_gen(Mult);
+        graph.connect(constant(2.0).to(multiples)).unwrap();
+        // One output to several inputs, and several outputs to one input
+        graph.connect(multiples.out(1).to(mult)).unwrap();
+        graph
+            .connect(multiples.out(1).to(mult).to_index(1))
+            .unwrap();
+        graph
+            .connect(multiples.out_label("triple").to(mult).to_index(1))
+            .unwrap();
+        graph.connect(mult.to_graph_out()).unwrap();
+        graph
+            .connect(multiples.out_label("double").to_graph_out().to_index(1))
+            .unwrap();
+        graph
+            .connect(multiples.out(2).to_graph_out().to_index(2))
+            .unwrap();
+        assert_eq!(
+            graph.connect(multiples.out(3).to(mult)),
+            Err(ConnectionError::ChannelOutOfBounds)
+        );
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        let outputs = graph_node.output_buffers();
+        assert_eq!(outputs[0][0], 4.0 * (4.0 + 6.0));
+        assert_eq!(outputs[1][0], 4.0);
+        assert_eq!(outputs[2][0], 6.0);
+    }
+    #[test]
+    fn connection_gains() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 2,
+            ..Default::default()
+        });
+        let one = graph.push_gen(OneGen {});
+        let mult = graph.push_gen(Mult);
+        graph.connect(constant(1.0).to(one)).unwrap();
+        graph.connect(one.to(mult).gain(0.5)).unwrap();
+        graph.connect(one.to(mult).to_index(1).invert()).unwrap();
+        graph.connect(mult.to_graph_out()).unwrap();
+        graph
+            .connect(one.to_graph_out().to_index(1).gain(0.25))
+            .unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][3], 1.0 * -2.0);
+        assert_eq!(graph_node.output_buffers()[1][3], 0.5);
+        assert!(graph.to_dot().contains("label=\"×0.25\""));
+    }
+    #[test]
+    fn push_closure() {
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            ..Default::default()
+        });
+        let node = graph.push_closure(2, 1, |inputs, outputs, _resources| {
+            for ((out, a), b) in outputs[0].iter_mut().zip(&*inputs[0]).zip(&*inputs[1]) {
+                *out = a - b;
+            }
+            GenState::Continue
+        });
+        graph.connect(constant(3.0).to(node)).unwrap();
+        graph
+            .connect(constant(1.0).to(node).to_label("in1"))
+            .unwrap();
+        graph
+            .connect(Connection::graph_output(node).from_label("out0"))
+            .unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [2.0; 4]);
+    }
+    #[cfg(feature = "derive")]
+    #[test]
+    fn derive_gen() {
+        #[derive(Gen)]
+        #[gen(name = "Gain", init = "init")]
+        struct Gain {
+            #[input]
+            signal: Sample,
+            #[input(default = 0.5, name = "gain")]
+            amount: Sample,
+            #[output]
+            out: Sample,
+            sample_rate: Sample,
+        }
+        impl Gain {
+            fn init(&mut self, sample_rate: Sample) {
+                self.sample_rate = sample_rate;
+            }
+            fn process_sample(&mut self, _resources: &mut Resources) {
+                self.out = self.signal * self.amount;
+            }
+        }
+        let mut gain = Gain {
+            signal: 0.,
+            amount: 0.,
+            out: 0.,
+            sample_rate: 0.,
+        };
+        assert_eq!(gain.num_inputs(), 2);
+        assert_eq!(gain.num_outputs(), 1);
+        assert_eq!(gain.input_desc(1), "gain");
+        assert_eq!(gain.output_desc(0), "out");
+        assert_eq!(gain.default_input(1), 0.5);
+        assert_eq!(Gen::name(&gain), "Gain");
+        Gen::init(&mut gain, 8000.);
+        assert_eq!(gain.sample_rate, 8000.);
+
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: 4,
+            ..Default::default()
+        });
+        let node = graph.push_gen(gain);
+        graph.connect(constant(3.0).to(node)).unwrap();
+        graph.connect(Connection::graph_output(node)).unwrap();
+        let mut graph_node = graph_node(&mut graph);
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(test_resources_settings());
+        graph_node.process(&null_input(), &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][..], [1.5; 4]);
+    }
 }
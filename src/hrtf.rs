@@ -0,0 +1,366 @@
+//! Binaural rendering for headphones using head related impulse responses
+//! (HRIRs), enabled with the `hrtf` feature.
+//!
+//! An [`HrirSet`] holds one pair of impulse responses per measured direction.
+//! Sets can be loaded from stereo sound files, one per direction, or created
+//! from a simple spherical head model with [`HrirSet::spherical_head`]. A
+//! [`BinauralPanner`] convolves a mono signal with the impulse responses
+//! closest to the direction of the source.
+//!
+//! Directions use the same convention as the ambisonics Gens in
+//! [`spatial`](crate::spatial): degrees, azimuth counter clockwise from the
+//! front (positive is to the left) and elevation positive upwards.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use symphonia::core::errors::Error as SymphoniaError;
+
+use crate::buffer::Buffer;
+use crate::graph::{Gen, GenState};
+use crate::{sample_to_f64, Resources, Sample};
+
+#[derive(thiserror::Error, Debug)]
+pub enum HrtfError {
+    #[error("Unable to load the sound file: {0}")]
+    SoundFile(#[from] SymphoniaError),
+    #[error("The HRIR file {0:?} needs to have two channels")]
+    NotStereo(PathBuf),
+}
+
+/// The impulse responses for both ears for sound coming from one direction
+#[derive(Debug, Clone)]
+pub struct Hrir {
+    pub azimuth: Sample,
+    pub elevation: Sample,
+    pub left: Vec<Sample>,
+    pub right: Vec<Sample>,
+}
+
+/// A set of [`Hrir`]s measured at the same sample rate
+#[derive(Debug, Clone)]
+pub struct HrirSet {
+    hrirs: Vec<Hrir>,
+    sample_rate: Sample,
+    /// The length of the longest impulse response
+    max_len: usize,
+}
+
+impl HrirSet {
+    pub fn new(sample_rate: Sample) -> Self {
+        Self {
+            hrirs: vec![],
+            sample_rate,
+            max_len: 0,
+        }
+    }
+    pub fn add(&mut self, hrir: Hrir) {
+        self.max_len = self.max_len.max(hrir.left.len()).max(hrir.right.len());
+        self.hrirs.push(hrir);
+    }
+    /// Load HRIRs from stereo sound files, one file per (azimuth, elevation).
+    /// The left channel is the left ear. The sample rate is taken from the
+    /// first file.
+    pub fn from_sound_files(
+        files: impl IntoIterator<Item = (Sample, Sample, impl Into<PathBuf>)>,
+    ) -> Result<Self, HrtfError> {
+        let mut set = Self::new(0.0);
+        for (azimuth, elevation, path) in files {
+            let path = path.into();
+            let buffer = Buffer::from_sound_file(path.clone())?;
+            if buffer.num_channels() != 2 {
+                return Err(HrtfError::NotStereo(path));
+            }
+            if set.hrirs.is_empty() {
+                set.sample_rate = buffer.sample_rate() as Sample;
+            }
+            let num_frames = buffer.size() as usize / 2;
+            let (left, right) = (0..num_frames)
+                .map(|i| {
+                    let frame = buffer.get_interleaved(i);
+                    (frame[0], frame[1])
+                })
+                .unzip();
+            set.add(Hrir {
+                azimuth,
+                elevation,
+                left,
+                right,
+            });
+        }
+        Ok(set)
+    }
+    /// Create HRIRs in the horizontal plane every `azimuth_step` degrees from
+    /// a spherical head model: the interaural time difference follows
+    /// Woodworth's formula and head shadowing uses the filter from Brown and
+    /// Duda (1998). It has no elevation cues, but needs no data.
+    pub fn spherical_head(sample_rate: Sample, azimuth_step: Sample) -> Self {
+        const HEAD_RADIUS: f64 = 0.0875;
+        const SPEED_OF_SOUND: f64 = 343.0;
+        const LEN: usize = 128;
+        let sample_rate_f64 = sample_to_f64(sample_rate);
+        let mut set = Self::new(sample_rate);
+        let num_directions = (360.0 / sample_to_f64(azimuth_step.max(1.0))).round() as usize;
+        for i in 0..num_directions {
+            let azimuth = i as f64 * 360.0 / num_directions as f64;
+            // The ears are at +90 (left) and -90 (right) degrees
+            let ear = |ear_azimuth: f64| {
+                let mut incidence = (azimuth - ear_azimuth).rem_euclid(360.0);
+                if incidence > 180.0 {
+                    incidence = 360.0 - incidence;
+                }
+                let incidence = incidence.to_radians();
+                // Woodworth, offset so that the closest ear has no delay
+                let delay = if incidence < std::f64::consts::FRAC_PI_2 {
+                    -incidence.cos()
+                } else {
+                    incidence - std::f64::consts::FRAC_PI_2
+                };
+                let delay = (delay + 1.0) * HEAD_RADIUS / SPEED_OF_SOUND * sample_rate_f64;
+                // Head shadow: H(s) = (alpha s + beta) / (s + beta), bilinear transform
+                let alpha =
+                    1.05 + 0.95 * (incidence.to_degrees() / 150.0 * 180.0).to_radians().cos();
+                let beta = 2.0 * SPEED_OF_SOUND / HEAD_RADIUS;
+                let k = 2.0 * sample_rate_f64;
+                let b0 = (alpha * k + beta) / (k + beta);
+                let b1 = (beta - alpha * k) / (k + beta);
+                let a1 = (beta - k) / (k + beta);
+                let delay_index = delay.floor() as usize;
+                let frac = delay - delay.floor();
+                let mut x1 = 0.0;
+                let mut y1 = 0.0;
+                (0..LEN)
+                    .map(|n| {
+                        let x = if n == delay_index {
+                            1.0 - frac
+                        } else if n == delay_index + 1 {
+                            frac
+                        } else {
+                            0.0
+                        };
+                        let y = b0 * x + b1 * x1 - a1 * y1;
+                        x1 = x;
+                        y1 = y;
+                        y as Sample
+                    })
+                    .collect::<Vec<Sample>>()
+            };
+            set.add(Hrir {
+                azimuth: azimuth as Sample,
+                elevation: 0.0,
+                left: ear(90.0),
+                right: ear(-90.0),
+            });
+        }
+        set
+    }
+    pub fn hrirs(&self) -> &[Hrir] {
+        &self.hrirs
+    }
+    pub fn sample_rate(&self) -> Sample {
+        self.sample_rate
+    }
+    /// The index of the HRIR closest to the direction
+    fn nearest(&self, azimuth: Sample, elevation: Sample) -> usize {
+        let direction = unit_vector(azimuth, elevation);
+        let mut nearest = 0;
+        let mut best = f64::MIN;
+        for (i, hrir) in self.hrirs.iter().enumerate() {
+            let other = unit_vector(hrir.azimuth, hrir.elevation);
+            let similarity: f64 = direction.iter().zip(other.iter()).map(|(a, b)| a * b).sum();
+            if similarity > best {
+                best = similarity;
+                nearest = i;
+            }
+        }
+        nearest
+    }
+}
+
+fn unit_vector(azimuth: Sample, elevation: Sample) -> [f64; 3] {
+    let azimuth = sample_to_f64(azimuth).to_radians();
+    let elevation = sample_to_f64(elevation).to_radians();
+    [
+        azimuth.cos() * elevation.cos(),
+        azimuth.sin() * elevation.cos(),
+        elevation.sin(),
+    ]
+}
+
+/// Renders a mono source to binaural stereo. Inputs are "signal", "azimuth"
+/// and "elevation", outputs "left" and "right".
+///
+/// The direction is read once per block and the closest [`Hrir`] in the set is
+/// used. When it changes the output crossfades from the old impulse responses
+/// to the new ones over one block. The convolution is done directly in the
+/// time domain, which is efficient for the short impulse responses typical of
+/// HRIRs.
+pub struct BinauralPanner {
+    set: Arc<HrirSet>,
+    current: Option<usize>,
+    previous: Option<usize>,
+    /// The last `len` input samples stored twice in a row so that a
+    /// contiguous window is always available
+    history: Vec<Sample>,
+    len: usize,
+    write_pos: usize,
+}
+
+impl BinauralPanner {
+    /// The set is shared so that many panners can use the same HRIRs.
+    pub fn new(set: Arc<HrirSet>) -> Self {
+        let len = set.max_len.max(1);
+        Self {
+            set,
+            current: None,
+            previous: None,
+            history: vec![0.0; len * 2],
+            len,
+            write_pos: 0,
+        }
+    }
+}
+
+#[inline]
+fn convolve(ir: &[Sample], history: &[Sample], newest: usize) -> Sample {
+    ir.iter()
+        .enumerate()
+        .map(|(k, &h)| h * history[newest - k])
+        .sum()
+}
+
+impl Gen for BinauralPanner {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let (lefts, rights) = outputs.split_at_mut(1);
+        let (lefts, rights) = (&mut lefts[0], &mut rights[0]);
+        if self.set.hrirs.is_empty() {
+            lefts.fill(0.0);
+            rights.fill(0.0);
+            return GenState::Continue;
+        }
+        let target = self.set.nearest(inputs[1][0], inputs[2][0]);
+        if self.current != Some(target) {
+            self.previous = self.current;
+            self.current = Some(target);
+        }
+        let current = &self.set.hrirs[target];
+        let previous = self.previous.map(|i| &self.set.hrirs[i]);
+        let block_size = lefts.len();
+        for (i, ((&signal, left), right)) in inputs[0]
+            .iter()
+            .zip(lefts.iter_mut())
+            .zip(rights.iter_mut())
+            .enumerate()
+        {
+            self.history[self.write_pos] = signal;
+            self.history[self.write_pos + self.len] = signal;
+            let newest = self.write_pos + self.len;
+            *left = convolve(&current.left, &self.history, newest);
+            *right = convolve(&current.right, &self.history, newest);
+            if let Some(previous) = previous {
+                let fade_in = (i + 1) as Sample / block_size as Sample;
+                *left = *left * fade_in
+                    + convolve(&previous.left, &self.history, newest) * (1.0 - fade_in);
+                *right = *right * fade_in
+                    + convolve(&previous.right, &self.history, newest) * (1.0 - fade_in);
+            }
+            self.write_pos = (self.write_pos + 1) % self.len;
+        }
+        self.previous = None;
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        if self.set.sample_rate != sample_rate {
+            eprintln!(
+                "BinauralPanner: the HRIRs have a sample rate of {} but the Graph runs at {}",
+                self.set.sample_rate, sample_rate
+            );
+        }
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        2
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "azimuth",
+            2 => "elevation",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "left",
+            1 => "right",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "BinauralPanner"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn render(panner: &mut BinauralPanner, signal: &[Sample], azimuth: Sample) -> [Vec<Sample>; 2] {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let len = signal.len();
+        let inputs = vec![
+            signal.to_vec().into_boxed_slice(),
+            vec![azimuth; len].into_boxed_slice(),
+            vec![0.0; len].into_boxed_slice(),
+        ];
+        let mut outputs = vec![
+            vec![0.0; len].into_boxed_slice(),
+            vec![0.0; len].into_boxed_slice(),
+        ];
+        panner.process(&inputs, &mut outputs, &mut resources);
+        [outputs[0].to_vec(), outputs[1].to_vec()]
+    }
+
+    #[test]
+    fn convolves_with_nearest_hrir() {
+        let mut set = HrirSet::new(44100.);
+        set.add(Hrir {
+            azimuth: 90.0,
+            elevation: 0.0,
+            left: vec![1.0, 0.5],
+            right: vec![0.0, 0.0, 0.25],
+        });
+        set.add(Hrir {
+            azimuth: -90.0,
+            elevation: 0.0,
+            left: vec![0.0],
+            right: vec![1.0],
+        });
+        let mut panner = BinauralPanner::new(Arc::new(set));
+        let [left, right] = render(&mut panner, &[1.0, 0.0, 0.0, 2.0, 0.0, 0.0], 80.0);
+        assert_eq!(left, [1.0, 0.5, 0.0, 2.0, 1.0, 0.0]);
+        assert_eq!(right, [0.0, 0.0, 0.25, 0.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn spherical_head_is_louder_and_earlier_on_the_near_side() {
+        let set = HrirSet::spherical_head(48000., 15.0);
+        assert_eq!(set.hrirs().len(), 24);
+        let mut panner = BinauralPanner::new(Arc::new(set));
+        let mut impulse = vec![0.0; 256];
+        impulse[0] = 1.0;
+        let [left, right] = render(&mut panner, &impulse, 90.0);
+        let energy = |s: &[Sample]| s.iter().map(|x| x * x).sum::<Sample>();
+        let onset = |s: &[Sample]| s.iter().position(|x| x.abs() > 0.1).unwrap();
+        assert!(energy(&left) > energy(&right));
+        assert!(onset(&left) < onset(&right));
+    }
+}
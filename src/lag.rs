@@ -0,0 +1,219 @@
+//! Smoothing of control signals.
+//!
+//! [`Lag`] glides exponentially towards its input, e.g. for portamento
+//! between pitches. [`SlewLimiter`] limits how fast its output can change,
+//! moving linearly towards its input. Both have separate times for rising and
+//! falling signals and start at the first value of their input so that there
+//! is no glide from 0 when they start.
+
+use crate::dynamics::time_coefficient;
+use crate::graph::{Gen, GenState};
+use crate::{Resources, Sample};
+
+/// Exponential smoothing towards the "signal" input.
+///
+/// "up" and "down" are the times in seconds to move ~63% of the way towards
+/// a higher or lower input respectively.
+#[derive(Debug, Clone)]
+pub struct Lag {
+    value: Option<Sample>,
+    sample_rate: Sample,
+}
+
+impl Lag {
+    pub fn new() -> Self {
+        Self {
+            value: None,
+            sample_rate: 44100.0,
+        }
+    }
+}
+
+impl Default for Lag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for Lag {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let target = inputs[0][i];
+            let value = match self.value {
+                Some(value) => {
+                    let time = if target > value {
+                        inputs[1][i]
+                    } else {
+                        inputs[2][i]
+                    };
+                    target + time_coefficient(time, self.sample_rate) * (value - target)
+                }
+                None => target,
+            };
+            self.value = Some(value);
+            *out = value;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.value = None;
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "up",
+            2 => "down",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            1 | 2 => 0.1,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Lag"
+    }
+}
+
+/// Linear movement towards the "signal" input.
+///
+/// "rise" and "fall" are the largest change of the output per second when
+/// the input is higher or lower respectively. A rate of 0 or less means no
+/// limit.
+#[derive(Debug, Clone)]
+pub struct SlewLimiter {
+    value: Option<Sample>,
+    sample_rate: Sample,
+}
+
+impl SlewLimiter {
+    pub fn new() -> Self {
+        Self {
+            value: None,
+            sample_rate: 44100.0,
+        }
+    }
+}
+
+impl Default for SlewLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for SlewLimiter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let target = inputs[0][i];
+            let value = match self.value {
+                Some(value) => {
+                    let rise = inputs[1][i];
+                    let fall = inputs[2][i];
+                    let mut change = target - value;
+                    if rise > 0.0 {
+                        change = change.min(rise / self.sample_rate);
+                    }
+                    if fall > 0.0 {
+                        change = change.max(-fall / self.sample_rate);
+                    }
+                    value + change
+                }
+                None => target,
+            };
+            self.value = Some(value);
+            *out = value;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.value = None;
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "rise",
+            2 => "fall",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "SlewLimiter"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn run(gen: &mut dyn Gen, signal: [Sample; 6], up: Sample, down: Sample) -> Vec<Sample> {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let inputs = vec![
+            signal.to_vec().into_boxed_slice(),
+            vec![up; 6].into_boxed_slice(),
+            vec![down; 6].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; 6].into_boxed_slice()];
+        gen.process(&inputs, &mut outputs, &mut resources);
+        outputs[0].to_vec()
+    }
+
+    #[test]
+    fn lag_glides_with_separate_times() {
+        let mut lag = Lag::new();
+        lag.init(10.0);
+        let out = run(&mut lag, [1.0, 2.0, 2.0, 0.0, 0.0, 0.0], 0.1, 0.0);
+        assert_eq!(out[0], 1.0);
+        let c = (-1.0 as Sample).exp();
+        assert!((out[1] - (2.0 - c)).abs() < 1e-6);
+        assert!((out[2] - (2.0 - c * c)).abs() < 1e-6);
+        // A down time of 0 jumps
+        assert_eq!(&out[3..], [0.0; 3]);
+    }
+
+    #[test]
+    fn slew_limiter_moves_linearly() {
+        let mut slew = SlewLimiter::new();
+        slew.init(10.0);
+        let out = run(&mut slew, [0.0, 1.0, 1.0, 1.0, -1.0, -1.0], 5.0, 10.0);
+        assert_eq!(out, [0.0, 0.5, 1.0, 1.0, 0.0, -1.0]);
+    }
+}
@@ -0,0 +1,251 @@
+//! Low frequency oscillators for modulation.
+//!
+//! An [`Lfo`] has the inputs "rate", "phase" and "retrigger":
+//!
+//! - "rate" is the frequency in Hz, or with [`Lfo::sync`] the length of a
+//!   cycle in beats of the transport, e.g. 0.5 for an eighth note at 4/4.
+//! - "phase" (0 to 1) is added to the phase of the oscillator.
+//! - "retrigger" (trigger) restarts the cycle.
+//!
+//! A synced Lfo follows the position of the transport, so it stays in time
+//! with sequencers and tempo changes and stands still while the transport is
+//! stopped. Retriggering a synced Lfo makes the cycle start at the beat of the
+//! trigger.
+//!
+//! The output is bipolar, -1 to 1, or with [`Lfo::unipolar`] 0 to 1.
+
+use crate::graph::{Gen, GenState};
+use crate::trig::is_trigger;
+use crate::{sample_to_f64, Resources, Sample};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoShape {
+    Sine,
+    /// Starts at the lowest value, rises to the highest halfway through the
+    /// cycle and falls back down
+    Triangle,
+    /// Rises from the lowest to the highest value
+    Saw,
+    /// The highest value for the first half of the cycle, then the lowest
+    Square,
+    /// A new random value every cycle, held until the next (sample and hold)
+    Random,
+}
+
+/// A low frequency oscillator, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Lfo {
+    shape: LfoShape,
+    sync: bool,
+    unipolar: bool,
+    /// The position in the cycle from 0 to 1 without the phase offset
+    phase: f64,
+    /// The beat the cycle of a synced Lfo starts from
+    start_beat: f64,
+    /// The value of the Random shape, bipolar
+    random_value: Sample,
+    sample_rate: f64,
+}
+
+impl Lfo {
+    pub fn new(shape: LfoShape) -> Self {
+        Self {
+            shape,
+            sync: false,
+            unipolar: false,
+            phase: 0.0,
+            start_beat: 0.0,
+            random_value: 0.0,
+            sample_rate: 44100.0,
+        }
+    }
+    pub fn sine() -> Self {
+        Self::new(LfoShape::Sine)
+    }
+    pub fn triangle() -> Self {
+        Self::new(LfoShape::Triangle)
+    }
+    pub fn saw() -> Self {
+        Self::new(LfoShape::Saw)
+    }
+    pub fn square() -> Self {
+        Self::new(LfoShape::Square)
+    }
+    pub fn random() -> Self {
+        Self::new(LfoShape::Random)
+    }
+    /// Follow the transport, with "rate" as the length of a cycle in beats
+    pub fn sync(mut self) -> Self {
+        self.sync = true;
+        self
+    }
+    /// Output 0 to 1 instead of -1 to 1
+    pub fn unipolar(mut self) -> Self {
+        self.unipolar = true;
+        self
+    }
+    /// The bipolar value of the shape at `phase` from 0 to 1
+    fn shape_value(&self, phase: f64) -> Sample {
+        match self.shape {
+            LfoShape::Sine => (phase * std::f64::consts::TAU).sin() as Sample,
+            LfoShape::Triangle => (1.0 - 4.0 * (phase - 0.5).abs()) as Sample,
+            LfoShape::Saw => (2.0 * phase - 1.0) as Sample,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::Random => self.random_value,
+        }
+    }
+}
+
+impl Gen for Lfo {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let transport = resources.transport;
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let rate = sample_to_f64(inputs[0][i]);
+            let retrigger = is_trigger(inputs[2][i]);
+            let mut new_cycle = retrigger;
+            if self.sync {
+                let beat = transport.beats_at_sample(i).as_beats_f64();
+                if retrigger {
+                    self.start_beat = beat;
+                }
+                let phase = if rate > 0.0 {
+                    ((beat - self.start_beat) / rate).rem_euclid(1.0)
+                } else {
+                    0.0
+                };
+                // Also true if the transport jumped backwards
+                new_cycle |= phase < self.phase;
+                self.phase = phase;
+            } else if retrigger {
+                self.phase = 0.0;
+            }
+            if new_cycle && self.shape == LfoShape::Random {
+                self.random_value = resources.rng.f32() as Sample * 2.0 - 1.0;
+            }
+            let phase = (self.phase + sample_to_f64(inputs[1][i])).rem_euclid(1.0);
+            let value = self.shape_value(phase);
+            *out = if self.unipolar {
+                value * 0.5 + 0.5
+            } else {
+                value
+            };
+            if !self.sync {
+                self.phase += rate / self.sample_rate;
+                if self.phase >= 1.0 {
+                    self.phase -= self.phase.floor();
+                    if self.shape == LfoShape::Random {
+                        self.random_value = resources.rng.f32() as Sample * 2.0 - 1.0;
+                    }
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_to_f64(sample_rate);
+        self.phase = 0.0;
+        self.start_beat = 0.0;
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "rate",
+            1 => "phase",
+            2 => "retrigger",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            0 => 1.0,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Lfo"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduling::{Beats, TransportSnapshot, TransportState};
+    use crate::ResourcesSettings;
+
+    fn run(lfo: &mut Lfo, resources: &mut Resources, rate: Sample, phase: Sample) -> Vec<Sample> {
+        let inputs = vec![
+            vec![rate; 8].into_boxed_slice(),
+            vec![phase; 8].into_boxed_slice(),
+            vec![0.0; 8].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; 8].into_boxed_slice()];
+        lfo.process(&inputs, &mut outputs, resources);
+        outputs[0].to_vec()
+    }
+
+    #[test]
+    fn free_running_shapes() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut saw = Lfo::saw();
+        saw.init(8.0);
+        assert_eq!(
+            run(&mut saw, &mut resources, 2.0, 0.0),
+            [-1.0, -0.5, 0.0, 0.5, -1.0, -0.5, 0.0, 0.5]
+        );
+        let mut square = Lfo::square().unipolar();
+        square.init(8.0);
+        assert_eq!(
+            run(&mut square, &mut resources, 1.0, 0.5),
+            [0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0]
+        );
+        let mut triangle = Lfo::triangle();
+        triangle.init(8.0);
+        assert_eq!(
+            run(&mut triangle, &mut resources, 1.0, 0.0),
+            [-1.0, -0.5, 0.0, 0.5, 1.0, 0.5, 0.0, -0.5]
+        );
+    }
+
+    #[test]
+    fn synced_lfo_follows_the_transport() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        resources.transport = TransportSnapshot {
+            state: TransportState::Playing,
+            sample: 0,
+            beats: Beats::from_beats_f64(3.0),
+            bpm: 120.0,
+            beats_per_sample: 0.25,
+        };
+        // One cycle every two beats
+        let mut saw = Lfo::saw().sync().unipolar();
+        saw.init(8.0);
+        assert_eq!(
+            run(&mut saw, &mut resources, 2.0, 0.0),
+            [0.5, 0.625, 0.75, 0.875, 0.0, 0.125, 0.25, 0.375]
+        );
+        resources.transport.state = TransportState::Stopped;
+        assert_eq!(run(&mut saw, &mut resources, 2.0, 0.0), [0.5; 8]);
+    }
+}
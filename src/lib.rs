@@ -38,6 +38,9 @@
 //! Using the [`audio_backend`]s this process is automated for you.
 //!
 
+// Lets the Gen derive macro refer to ::knyst from inside this crate
+extern crate self as knyst;
+
 use buffer::{Buffer, BufferKey};
 use core::fmt::Debug;
 use downcast_rs::{impl_downcast, Downcast};
@@ -45,24 +48,140 @@ use graph::GenState;
 // Import these for docs
 #[allow(unused_imports)]
 use graph::{Connection, Gen, Graph, Node};
-use slotmap::SlotMap;
-use std::collections::HashMap;
+use slotmap::{new_key_type, SecondaryMap, SlotMap};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use waveshaper::{LookupTable, LookupTableKey};
 use wavetable::{Wavetable, WavetableKey};
 
 use crate::wavetable::{FRACTIONAL_PART, TABLE_SIZE};
 
+pub mod analysis;
 pub mod audio_backend;
+pub mod audio_channel;
 pub mod buffer;
+pub mod buffer_loader;
+pub mod bus;
+pub mod convolution;
+pub mod denormal;
+pub mod dsl;
+pub mod dynamics;
 pub mod envelope;
+pub mod filter;
+pub mod fm;
 pub mod graph;
+#[cfg(feature = "hrtf")]
+pub mod hrtf;
+pub mod lag;
+pub mod lfo;
+#[cfg(feature = "link")]
+pub mod link;
+pub mod looper;
+pub mod math;
+pub mod midi;
+pub mod mixer;
+pub mod modal;
+pub mod modulation;
+pub mod multi_graph;
+#[cfg(feature = "network")]
+pub mod network;
+pub mod noise;
+pub mod oversample;
+pub mod param;
+pub mod patch;
+pub mod patch_file;
+pub mod plugin;
 pub mod prelude;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+pub mod recorder;
+pub mod registry;
+pub mod render;
+pub mod resample;
+#[cfg(feature = "rt-audit")]
+pub mod rt_audit;
+pub mod scheduling;
+pub mod sequencer;
+pub mod simd;
+pub mod slicer;
+pub mod spatial;
+pub mod spectral;
+pub mod test;
+pub mod trig;
+pub mod tuning;
+pub mod unison;
+pub mod vocoder;
+pub mod voice;
+pub mod waveshaper;
 pub mod wavetable;
 pub mod xorrng;
 
+/// The sample type used for all processing. f32 by default, f64 with the
+/// `f64` feature for offline rendering where more precision is needed.
+#[cfg(not(feature = "f64"))]
 pub type Sample = f32;
+#[cfg(feature = "f64")]
+pub type Sample = f64;
+
+/// `sample as f64`, which is a cast to the same type with the `f64` feature
+#[allow(clippy::unnecessary_cast)]
+#[inline(always)]
+pub(crate) fn sample_to_f64(sample: Sample) -> f64 {
+    sample as f64
+}
+/// `sample as f32`, which is a cast to the same type without the `f64` feature
+#[allow(clippy::unnecessary_cast)]
+#[inline(always)]
+pub(crate) fn sample_to_f32(sample: Sample) -> f32 {
+    sample as f32
+}
+/// Data of any type that can be stored in the [`Resources`] and shared
+/// between nodes, see [`Resources::insert_user_data`].
 pub trait AnyData: Downcast + Send + Debug {}
 impl_downcast!(AnyData);
 
+new_key_type! {
+    /// The untyped key of some user data
+    struct RawUserDataKey;
+}
+
+/// A key to user data of type `T` in the [`Resources`]. Looking up the data
+/// is an index into a preallocated table, so it is cheap enough for the audio
+/// thread.
+pub struct UserDataKey<T> {
+    key: RawUserDataKey,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> UserDataKey<T> {
+    fn new(key: RawUserDataKey) -> Self {
+        Self {
+            key,
+            _type: PhantomData,
+        }
+    }
+}
+
+// Implemented by hand since deriving would require `T` to implement the traits
+impl<T> Clone for UserDataKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for UserDataKey<T> {}
+impl<T> PartialEq for UserDataKey<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+impl<T> Eq for UserDataKey<T> {}
+impl<T> Debug for UserDataKey<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("UserDataKey").field(&self.key).finish()
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum StopAction {
     Continue,
@@ -90,7 +209,15 @@ pub struct ResourcesSettings {
     pub max_wavetables: usize,
     /// The maximum number of buffers that can be added to the Resources
     pub max_buffers: usize,
+    /// The maximum number of lookup tables that can be added to the Resources
+    pub max_lookup_tables: usize,
+    /// The maximum number of [`AnyData`] that can be added to the Resources
     pub max_user_data: usize,
+    /// The maximum number of bytes used by the samples of all buffers. When
+    /// inserting a buffer would go over the limit, the least recently used
+    /// buffers that aren't pinned are removed to make room. A buffer is used
+    /// every block a node reading it is processed. None means no limit.
+    pub max_buffer_memory: Option<usize>,
 }
 impl Default for ResourcesSettings {
     fn default() -> Self {
@@ -98,7 +225,9 @@ impl Default for ResourcesSettings {
             sample_rate: 44100.0,
             max_wavetables: 10,
             max_buffers: 10,
-            max_user_data: 0,
+            max_lookup_tables: 10,
+            max_user_data: 10,
+            max_buffer_memory: None,
         }
     }
 }
@@ -109,94 +238,806 @@ pub enum ResourcesError {
     WavetablesFull(Wavetable),
     #[error("There is not enough space to insert the given Buffer. You can create a Resources with more space or remove old Buffers")]
     BuffersFull(Buffer),
+    #[error("The given Buffer doesn't fit in the buffer memory limit, even after removing all Buffers that aren't pinned.")]
+    BufferMemoryFull(Buffer),
+    #[error("Keys are allocated by a ResourcesCommandSender for these Resources. Insert the Wavetable through the ResourcesCommandSender instead.")]
+    InsertWavetableThroughSender(Wavetable),
+    #[error("Keys are allocated by a ResourcesCommandSender for these Resources. Insert the Buffer through the ResourcesCommandSender instead.")]
+    InsertBufferThroughSender(Buffer),
+    #[error("There is not enough space to insert the given LookupTable. You can create a Resources with more space or remove old LookupTables")]
+    LookupTablesFull(LookupTable),
+    #[error("Keys are allocated by a ResourcesCommandSender for these Resources. Insert the LookupTable through the ResourcesCommandSender instead.")]
+    InsertLookupTableThroughSender(LookupTable),
+    #[error("There is not enough space to insert the given user data. You can create a Resources with more space or remove old user data")]
+    UserDataFull(Box<dyn AnyData>),
+    #[error("Keys are allocated by a ResourcesCommandSender for these Resources. Insert the user data through the ResourcesCommandSender instead.")]
+    InsertUserDataThroughSender(Box<dyn AnyData>),
+    #[error("A ResourcesCommandSender has already been created for these Resources.")]
+    CommandChannelExists,
+    #[error("The command queue to the Resources is full. Try again after the next block has been processed or create the channel with a larger capacity.")]
+    CommandQueueFull,
+}
+
+/// Allocates the keys for buffers, wavetables, lookup tables and user data. Keys are kept separately
+/// from the data so that they can be handed out on the control thread before
+/// the data has reached the [`Resources`] on the audio thread.
+struct ResourceKeys {
+    buffers: SlotMap<BufferKey, ()>,
+    wavetables: SlotMap<WavetableKey, ()>,
+    lookup_tables: SlotMap<LookupTableKey, ()>,
+    user_data: SlotMap<RawUserDataKey, ()>,
+    max_buffers: usize,
+    max_wavetables: usize,
+    max_lookup_tables: usize,
+    max_user_data: usize,
+    /// The buffers that haven't been removed, for the memory limit
+    buffer_usage: SecondaryMap<BufferKey, BufferUsage>,
+    buffer_memory: usize,
+    max_buffer_memory: Option<usize>,
+    /// Incremented every time a buffer is used. Shared with the [`Resources`]
+    /// so that nodes reading a buffer on the audio thread count as uses.
+    use_clock: Arc<AtomicU64>,
+}
+
+/// How much memory a buffer uses and when it was last used
+struct BufferUsage {
+    bytes: usize,
+    /// Shared with the [`Resources`], see [`Resources::mark_buffer_used`]
+    last_used: Arc<AtomicU64>,
+    pinned: bool,
+}
+
+/// Set `last_used` to the next tick of `use_clock`
+fn mark_used(use_clock: &AtomicU64, last_used: &AtomicU64) {
+    last_used.store(
+        use_clock.fetch_add(1, Ordering::Relaxed) + 1,
+        Ordering::Relaxed,
+    );
+}
+
+impl ResourceKeys {
+    fn new(settings: &ResourcesSettings) -> Self {
+        Self {
+            buffers: SlotMap::with_capacity_and_key(settings.max_buffers),
+            wavetables: SlotMap::with_capacity_and_key(settings.max_wavetables),
+            lookup_tables: SlotMap::with_capacity_and_key(settings.max_lookup_tables),
+            user_data: SlotMap::with_capacity_and_key(settings.max_user_data),
+            max_buffers: settings.max_buffers,
+            max_wavetables: settings.max_wavetables,
+            max_lookup_tables: settings.max_lookup_tables,
+            max_user_data: settings.max_user_data,
+            buffer_usage: SecondaryMap::with_capacity(settings.max_buffers),
+            buffer_memory: 0,
+            max_buffer_memory: settings.max_buffer_memory,
+            use_clock: Arc::new(AtomicU64::new(0)),
+        }
+    }
+    /// True if a buffer key can be allocated once `freed` keys have been freed
+    fn has_room_for_buffer(&self, freed: usize) -> bool {
+        self.buffers.len() - freed < self.max_buffers
+    }
+    /// Returns the key and the time the buffer was last used, to be shared
+    /// with the [`Resources`]
+    fn new_buffer_key(&mut self, bytes: usize) -> Option<(BufferKey, Arc<AtomicU64>)> {
+        if self.has_room_for_buffer(0) {
+            let key = self.buffers.insert(());
+            let last_used = Arc::new(AtomicU64::new(0));
+            mark_used(&self.use_clock, &last_used);
+            self.buffer_usage.insert(
+                key,
+                BufferUsage {
+                    bytes,
+                    last_used: last_used.clone(),
+                    pinned: false,
+                },
+            );
+            self.buffer_memory += bytes;
+            Some((key, last_used))
+        } else {
+            None
+        }
+    }
+    /// The least recently used buffers that aren't pinned which have to be
+    /// evicted to make room for `bytes` more. None if there isn't enough room
+    /// even after evicting all of them.
+    fn buffers_to_evict(&self, bytes: usize) -> Option<Vec<BufferKey>> {
+        let Some(max_memory) = self.max_buffer_memory else {
+            return Some(vec![]);
+        };
+        let mut memory = self.buffer_memory;
+        let mut candidates: Vec<_> = self
+            .buffer_usage
+            .iter()
+            .filter(|(_, usage)| !usage.pinned)
+            .map(|(key, usage)| (usage.last_used.load(Ordering::Relaxed), key, usage.bytes))
+            .collect();
+        candidates.sort_unstable_by_key(|(last_used, _, _)| *last_used);
+        let mut evicted = vec![];
+        for (_, key, evicted_bytes) in candidates {
+            if memory + bytes <= max_memory {
+                break;
+            }
+            memory -= evicted_bytes;
+            evicted.push(key);
+        }
+        (memory + bytes <= max_memory).then_some(evicted)
+    }
+    /// Stop counting the memory of a buffer that is being removed
+    fn release_buffer(&mut self, key: BufferKey) {
+        if let Some(usage) = self.buffer_usage.remove(key) {
+            self.buffer_memory -= usage.bytes;
+        }
+    }
+    fn touch_buffer(&mut self, key: BufferKey) -> bool {
+        match self.buffer_usage.get(key) {
+            Some(usage) => {
+                mark_used(&self.use_clock, &usage.last_used);
+                true
+            }
+            None => false,
+        }
+    }
+    fn set_buffer_pinned(&mut self, key: BufferKey, pinned: bool) -> bool {
+        match self.buffer_usage.get_mut(key) {
+            Some(usage) => {
+                usage.pinned = pinned;
+                true
+            }
+            None => false,
+        }
+    }
+    fn new_wavetable_key(&mut self) -> Option<WavetableKey> {
+        if self.wavetables.len() < self.max_wavetables {
+            Some(self.wavetables.insert(()))
+        } else {
+            None
+        }
+    }
+    fn new_lookup_table_key(&mut self) -> Option<LookupTableKey> {
+        if self.lookup_tables.len() < self.max_lookup_tables {
+            Some(self.lookup_tables.insert(()))
+        } else {
+            None
+        }
+    }
+    fn new_user_data_key(&mut self) -> Option<RawUserDataKey> {
+        if self.user_data.len() < self.max_user_data {
+            Some(self.user_data.insert(()))
+        } else {
+            None
+        }
+    }
+    fn free(&mut self, key: FreedKey) {
+        match key {
+            FreedKey::Buffer(key) => {
+                self.release_buffer(key);
+                self.buffers.remove(key);
+            }
+            FreedKey::Wavetable(key) => {
+                self.wavetables.remove(key);
+            }
+            FreedKey::LookupTable(key) => {
+                self.lookup_tables.remove(key);
+            }
+            FreedKey::UserData(key) => {
+                self.user_data.remove(key);
+            }
+        }
+    }
+}
+
+enum ResourcesCommand {
+    InsertBuffer(BufferKey, Buffer, Arc<AtomicU64>),
+    InsertWavetable(WavetableKey, Wavetable),
+    RemoveBuffer(BufferKey),
+    RemoveWavetable(WavetableKey),
+    InsertLookupTable(LookupTableKey, LookupTable),
+    RemoveLookupTable(LookupTableKey),
+    InsertUserData(RawUserDataKey, Box<dyn AnyData>),
+    RemoveUserData(RawUserDataKey),
+}
+
+/// A key that was freed on the audio thread and can be reused
+enum FreedKey {
+    Buffer(BufferKey),
+    Wavetable(WavetableKey),
+    LookupTable(LookupTableKey),
+    UserData(RawUserDataKey),
+}
+
+/// Things sent back from the audio thread, either to be reused or to be
+/// deallocated on the control thread.
+// The values are only held to be dropped
+#[allow(dead_code)]
+enum ResourcesReturn {
+    Key(FreedKey),
+    Buffer(Buffer),
+    Wavetable(Wavetable),
+    LookupTable(LookupTable),
+    UserData(Box<dyn AnyData>),
+    BufferUse(Arc<AtomicU64>),
+}
+
+/// Inserts and removes buffers, wavetables, lookup tables and user data in [`Resources`]
+/// that are owned by the audio thread. Create it using
+/// [`Resources::command_channel`] before the [`Resources`] are moved to the
+/// audio thread.
+///
+/// Keys are returned immediately and can be used right away, e.g. to create a
+/// [`BufferReader`](buffer::BufferReader). The changes are applied at the start
+/// of the next block processed by the top level [`Graph`]. Anything removed is
+/// sent back to be deallocated here, which happens whenever a method on the
+/// sender is called. Call [`ResourcesCommandSender::update`] regularly if you
+/// remove things without inserting new ones.
+pub struct ResourcesCommandSender {
+    keys: ResourceKeys,
+    command_producer: rtrb::Producer<ResourcesCommand>,
+    return_consumer: rtrb::Consumer<ResourcesReturn>,
+    /// The number of commands sent
+    num_sent: u64,
+    /// The number of commands applied on the audio thread
+    num_applied: Arc<AtomicU64>,
+}
+
+impl ResourcesCommandSender {
+    /// Insert a buffer. If it doesn't fit in
+    /// [`ResourcesSettings::max_buffer_memory`], the least recently used
+    /// buffers that aren't pinned are removed first.
+    pub fn insert_buffer(&mut self, buffer: Buffer) -> Result<BufferKey, ResourcesError> {
+        self.update();
+        let Some(evicted) = self.keys.buffers_to_evict(buffer.size_in_bytes()) else {
+            return Err(ResourcesError::BufferMemoryFull(buffer));
+        };
+        // The keys of evicted buffers are only freed once they have been
+        // removed on the audio thread
+        if !self.keys.has_room_for_buffer(0) {
+            return Err(ResourcesError::BuffersFull(buffer));
+        }
+        // Every evicted buffer is removed with a command of its own
+        if self.command_producer.slots() <= evicted.len() {
+            return Err(ResourcesError::CommandQueueFull);
+        }
+        for evicted_key in evicted {
+            self.remove_buffer(evicted_key)?;
+        }
+        let (key, last_used) = match self.keys.new_buffer_key(buffer.size_in_bytes()) {
+            Some(key) => key,
+            None => return Err(ResourcesError::BuffersFull(buffer)),
+        };
+        match self
+            .command_producer
+            .push(ResourcesCommand::InsertBuffer(key, buffer, last_used))
+        {
+            Ok(_) => {
+                self.num_sent += 1;
+                Ok(key)
+            }
+            Err(_) => {
+                self.keys.free(FreedKey::Buffer(key));
+                Err(ResourcesError::CommandQueueFull)
+            }
+        }
+    }
+    pub fn insert_wavetable(
+        &mut self,
+        wavetable: Wavetable,
+    ) -> Result<WavetableKey, ResourcesError> {
+        self.update();
+        let key = match self.keys.new_wavetable_key() {
+            Some(key) => key,
+            None => return Err(ResourcesError::WavetablesFull(wavetable)),
+        };
+        match self
+            .command_producer
+            .push(ResourcesCommand::InsertWavetable(key, wavetable))
+        {
+            Ok(_) => {
+                self.num_sent += 1;
+                Ok(key)
+            }
+            Err(_) => {
+                self.keys.wavetables.remove(key);
+                Err(ResourcesError::CommandQueueFull)
+            }
+        }
+    }
+    pub fn insert_lookup_table(
+        &mut self,
+        lookup_table: LookupTable,
+    ) -> Result<LookupTableKey, ResourcesError> {
+        self.update();
+        let key = match self.keys.new_lookup_table_key() {
+            Some(key) => key,
+            None => return Err(ResourcesError::LookupTablesFull(lookup_table)),
+        };
+        match self
+            .command_producer
+            .push(ResourcesCommand::InsertLookupTable(key, lookup_table))
+        {
+            Ok(_) => {
+                self.num_sent += 1;
+                Ok(key)
+            }
+            Err(_) => {
+                self.keys.lookup_tables.remove(key);
+                Err(ResourcesError::CommandQueueFull)
+            }
+        }
+    }
+    /// Remove a buffer. Nodes reading from it will output silence. The key is
+    /// only reused after the buffer has been removed on the audio thread.
+    pub fn remove_buffer(&mut self, buffer_key: BufferKey) -> Result<(), ResourcesError> {
+        self.send(ResourcesCommand::RemoveBuffer(buffer_key))?;
+        self.keys.release_buffer(buffer_key);
+        Ok(())
+    }
+    /// Mark a buffer as used so that it is evicted after the buffers that
+    /// have been used less recently. Returns false if the buffer has been
+    /// removed.
+    pub fn touch_buffer(&mut self, buffer_key: BufferKey) -> bool {
+        self.keys.touch_buffer(buffer_key)
+    }
+    /// Keep a buffer from being evicted to stay within
+    /// [`ResourcesSettings::max_buffer_memory`]. Returns false if the buffer
+    /// has been removed.
+    pub fn pin_buffer(&mut self, buffer_key: BufferKey) -> bool {
+        self.keys.set_buffer_pinned(buffer_key, true)
+    }
+    /// Allow a pinned buffer to be evicted again. Returns false if the
+    /// buffer has been removed.
+    pub fn unpin_buffer(&mut self, buffer_key: BufferKey) -> bool {
+        self.keys.set_buffer_pinned(buffer_key, false)
+    }
+    /// True if the buffer has been inserted and hasn't been removed or
+    /// evicted
+    pub fn contains_buffer(&self, buffer_key: BufferKey) -> bool {
+        self.keys.buffer_usage.contains_key(buffer_key)
+    }
+    /// The number of bytes used by the samples of all buffers that haven't
+    /// been removed
+    pub fn buffer_memory(&self) -> usize {
+        self.keys.buffer_memory
+    }
+    /// Remove a wavetable. The key is only reused after the wavetable has been
+    /// removed on the audio thread.
+    pub fn remove_wavetable(&mut self, wavetable_key: WavetableKey) -> Result<(), ResourcesError> {
+        self.send(ResourcesCommand::RemoveWavetable(wavetable_key))
+    }
+    /// Remove a lookup table. The key is only reused after the lookup table
+    /// has been removed on the audio thread.
+    pub fn remove_lookup_table(
+        &mut self,
+        lookup_table_key: LookupTableKey,
+    ) -> Result<(), ResourcesError> {
+        self.send(ResourcesCommand::RemoveLookupTable(lookup_table_key))
+    }
+    /// Insert user data, see [`Resources::insert_user_data`]
+    pub fn insert_user_data<T: AnyData>(
+        &mut self,
+        data: T,
+    ) -> Result<UserDataKey<T>, ResourcesError> {
+        self.update();
+        let key = match self.keys.new_user_data_key() {
+            Some(key) => key,
+            None => return Err(ResourcesError::UserDataFull(Box::new(data))),
+        };
+        match self
+            .command_producer
+            .push(ResourcesCommand::InsertUserData(key, Box::new(data)))
+        {
+            Ok(_) => {
+                self.num_sent += 1;
+                Ok(UserDataKey::new(key))
+            }
+            Err(_) => {
+                self.keys.user_data.remove(key);
+                Err(ResourcesError::CommandQueueFull)
+            }
+        }
+    }
+    /// Replace the user data behind `key`. All nodes reading it see the new
+    /// data from the same block on. The old data is sent back to be dropped
+    /// here.
+    pub fn replace_user_data<T: AnyData>(
+        &mut self,
+        key: UserDataKey<T>,
+        data: T,
+    ) -> Result<(), ResourcesError> {
+        self.send(ResourcesCommand::InsertUserData(key.key, Box::new(data)))
+    }
+    /// Remove user data. The key is only reused after the data has been
+    /// removed on the audio thread.
+    pub fn remove_user_data<T: AnyData>(
+        &mut self,
+        key: UserDataKey<T>,
+    ) -> Result<(), ResourcesError> {
+        self.send(ResourcesCommand::RemoveUserData(key.key))
+    }
+    /// Reuse keys freed on the audio thread and deallocate anything that was
+    /// removed.
+    pub fn update(&mut self) {
+        while let Ok(returned) = self.return_consumer.pop() {
+            if let ResourcesReturn::Key(key) = returned {
+                self.keys.free(key);
+            }
+        }
+    }
+    /// The number of commands sent so far. Every successful insert or
+    /// remove is one command.
+    pub fn num_sent(&self) -> u64 {
+        self.num_sent
+    }
+    /// The number of commands applied to the [`Resources`] on the audio
+    /// thread so far. Something inserted is in the Resources once this has
+    /// reached the value [`ResourcesCommandSender::num_sent`] had right after
+    /// inserting it.
+    pub fn num_applied(&self) -> u64 {
+        self.num_applied.load(Ordering::Acquire)
+    }
+    fn send(&mut self, command: ResourcesCommand) -> Result<(), ResourcesError> {
+        self.update();
+        self.command_producer
+            .push(command)
+            .map_err(|_| ResourcesError::CommandQueueFull)?;
+        self.num_sent += 1;
+        Ok(())
+    }
+}
+
+/// The audio thread end of a [`ResourcesCommandSender`]
+struct ResourcesCommandReceiver {
+    command_consumer: rtrb::Consumer<ResourcesCommand>,
+    return_producer: rtrb::Producer<ResourcesReturn>,
+    num_applied: Arc<AtomicU64>,
+}
+
+impl ResourcesCommandReceiver {
+    fn send_back(&mut self, returned: ResourcesReturn) {
+        if self.return_producer.push(returned).is_err() {
+            eprintln!("Unable to send removed resources back to the ResourcesCommandSender. They will be dropped on the audio thread and freed keys will not be reused.");
+        }
+    }
 }
 
 /// Common resources for all Nodes in a Graph and all its sub Graphs:
 /// - [`Wavetable`]
 /// - [`Buffer`]
+/// - [`LookupTable`]
 /// - [`fastrand::Rng`]
 ///
-/// You can also add any resource you need to be shared between nodes using [`AnyData`].
+/// You can also add any resource you need to be shared between nodes using
+/// [`AnyData`]. User data is meant for data that needs to be read by many
+/// nodes and updated for all of them simultaneously, e.g. a tuning or a
+/// tempo.
+///
+/// Buffers and wavetables are accessed using generational keys so that a key
+/// to something that has been removed will never point to something else,
+/// even if its slot is reused.
 pub struct Resources {
-    pub buffers: SlotMap<BufferKey, Buffer>,
-    pub wavetables: SlotMap<WavetableKey, Wavetable>,
+    pub buffers: SecondaryMap<BufferKey, Buffer>,
+    pub wavetables: SecondaryMap<WavetableKey, Wavetable>,
+    /// Transfer curves for [`waveshaper::Waveshaper`]
+    pub lookup_tables: SecondaryMap<LookupTableKey, LookupTable>,
+    /// Any data shared between nodes, accessed with [`UserDataKey`]s
+    user_data: SecondaryMap<RawUserDataKey, Box<dyn AnyData>>,
+    /// When each buffer was last used, shared with the [`ResourceKeys`]
+    buffer_use: SecondaryMap<BufferKey, Arc<AtomicU64>>,
+    use_clock: Arc<AtomicU64>,
+    /// None if the keys are allocated by a [`ResourcesCommandSender`]
+    keys: Option<ResourceKeys>,
+    commands: Option<ResourcesCommandReceiver>,
     /// A precalculated value based on the sample rate and the table size. The
     /// frequency * this number is the amount that the phase should increase one
     /// sample. It is stored here so that it doesn't need to be stored in every
     /// wavetable oscillator.
     pub freq_to_phase_inc: f64,
-    /// UserData is meant for data that needs to be read by many nodes and
-    /// updated for all of them simultaneously. Strings are used as keys for
-    /// simplicity. A HopSlotMap could be used, but it would require sending and
-    /// matching keys back and forth.
-    ///
-    /// This is a temporary solution. If you have a suggestion for a better way
-    /// to make Resources user extendable, plese get in touch.
-    pub user_data: HashMap<String, Box<dyn AnyData>>,
 
     /// The sample rate of the audio process
     pub sample_rate: Sample,
+    /// Gens using random numbers draw their seeds from this. Create the
+    /// Resources with [`Resources::new_with_seed`] to make renders
+    /// reproducible.
     pub rng: fastrand::Rng,
+    /// The state of the transport at the start of the current block. Only
+    /// updated by the top level Graph.
+    pub transport: scheduling::TransportSnapshot,
 }
 
 impl Resources {
     pub fn new(settings: ResourcesSettings) -> Self {
-        // let user_data = HopSlotMap::with_capacity_and_key(1000);
-        let user_data = HashMap::with_capacity(1000);
-        let rng = fastrand::Rng::new();
-        // Add standard wavetables to the arena
-        let wavetables = SlotMap::with_capacity_and_key(settings.max_wavetables);
-        let buffers = SlotMap::with_capacity_and_key(settings.max_buffers);
+        Self::with_rng(settings, fastrand::Rng::new())
+    }
+    /// Create with a seeded [`Resources::rng`]. With the same seed, the same
+    /// Gens and the same inputs, the output is exactly the same every time,
+    /// which is useful for offline renders and tests.
+    pub fn new_with_seed(settings: ResourcesSettings, seed: u64) -> Self {
+        Self::with_rng(settings, fastrand::Rng::with_seed(seed))
+    }
+    fn with_rng(settings: ResourcesSettings, rng: fastrand::Rng) -> Self {
+        let user_data = SecondaryMap::with_capacity(settings.max_user_data);
+        // Allocate all the space up front so that inserting never allocates
+        let wavetables = SecondaryMap::with_capacity(settings.max_wavetables);
+        let buffers = SecondaryMap::with_capacity(settings.max_buffers);
+        let lookup_tables = SecondaryMap::with_capacity(settings.max_lookup_tables);
+        let buffer_use = SecondaryMap::with_capacity(settings.max_buffers);
+        let keys = ResourceKeys::new(&settings);
+        let use_clock = keys.use_clock.clone();
 
-        let freq_to_phase_inc =
-            TABLE_SIZE as f64 * FRACTIONAL_PART as f64 * (1.0 / settings.sample_rate as f64);
+        let freq_to_phase_inc = TABLE_SIZE as f64
+            * FRACTIONAL_PART as f64
+            * (1.0 / sample_to_f64(settings.sample_rate));
 
         Resources {
             buffers,
             wavetables,
+            lookup_tables,
+            buffer_use,
+            use_clock,
+            keys: Some(keys),
+            commands: None,
             freq_to_phase_inc,
             user_data,
             sample_rate: settings.sample_rate,
             rng,
+            transport: scheduling::TransportSnapshot::default(),
+        }
+    }
+    /// Update the sample rate and the values depending on it, e.g. when the
+    /// audio server changes the sample rate.
+    pub fn set_sample_rate(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.freq_to_phase_inc =
+            TABLE_SIZE as f64 * FRACTIONAL_PART as f64 * (1.0 / sample_to_f64(sample_rate));
+    }
+    /// *Allocates memory*
+    /// Resources for nodes running on a worker thread when a [`Graph`]
+    /// processes in parallel. They have no buffers, wavetables, lookup tables
+    /// or user data, since nodes using those always run on the audio thread,
+    /// see [`Gen::uses_shared_resources`].
+    pub(crate) fn new_worker() -> Self {
+        Resources {
+            buffers: SecondaryMap::new(),
+            wavetables: SecondaryMap::new(),
+            lookup_tables: SecondaryMap::new(),
+            user_data: SecondaryMap::new(),
+            buffer_use: SecondaryMap::new(),
+            use_clock: Arc::new(AtomicU64::new(0)),
+            keys: None,
+            commands: None,
+            freq_to_phase_inc: 0.0,
+            sample_rate: 0.0,
+            rng: fastrand::Rng::with_seed(0),
+            transport: scheduling::TransportSnapshot::default(),
         }
     }
-    /// Insert any kind of data using [`AnyData`]. Returns the `data` in an error if there is not enough space for the data in the HashTable.
-    pub fn insert_user_data(
+    /// Copy the sample rate and the state of the transport to the Resources
+    /// of a worker thread, see [`Resources::new_worker`].
+    pub(crate) fn sync_worker(&self, worker: &mut Resources) {
+        worker.freq_to_phase_inc = self.freq_to_phase_inc;
+        worker.sample_rate = self.sample_rate;
+        worker.transport = self.transport;
+    }
+    /// Create a channel for inserting buffers and wavetables from the control
+    /// thread after the Resources have been moved to the audio thread.
+    /// `capacity` is the maximum number of commands that can be waiting to be
+    /// applied.
+    ///
+    /// From now on the [`ResourcesCommandSender`] allocates all the keys, so
+    /// buffers and wavetables can no longer be inserted directly into the
+    /// Resources.
+    pub fn command_channel(
         &mut self,
-        key: String,
-        data: Box<dyn AnyData>,
-    ) -> Result<(), Box<dyn AnyData>> {
-        if self.user_data.len() < self.user_data.capacity() {
-            self.user_data.insert(key, data);
-            Ok(())
-        } else {
-            Err(data)
+        capacity: usize,
+    ) -> Result<ResourcesCommandSender, ResourcesError> {
+        let keys = match self.keys.take() {
+            Some(keys) => keys,
+            None => return Err(ResourcesError::CommandChannelExists),
+        };
+        let (command_producer, command_consumer) = rtrb::RingBuffer::new(capacity);
+        // Every command can send back at most three things
+        let (return_producer, return_consumer) = rtrb::RingBuffer::new(
+            keys.max_buffers
+                + keys.max_wavetables
+                + keys.max_lookup_tables
+                + keys.max_user_data
+                + capacity * 3,
+        );
+        let num_applied = Arc::new(AtomicU64::new(0));
+        self.commands = Some(ResourcesCommandReceiver {
+            command_consumer,
+            return_producer,
+            num_applied: num_applied.clone(),
+        });
+        Ok(ResourcesCommandSender {
+            keys,
+            command_producer,
+            return_consumer,
+            num_sent: 0,
+            num_applied,
+        })
+    }
+    /// Apply all commands sent from a [`ResourcesCommandSender`]. This is
+    /// called by the top level [`Graph`] at the start of every block so you
+    /// shouldn't normally have to call it. It doesn't allocate or deallocate.
+    pub fn apply_commands(&mut self) {
+        let mut commands = match self.commands.take() {
+            Some(commands) => commands,
+            None => return,
+        };
+        let mut num_applied = 0;
+        while let Ok(command) = commands.command_consumer.pop() {
+            num_applied += 1;
+            match command {
+                ResourcesCommand::InsertBuffer(key, buffer, last_used) => {
+                    self.buffers.insert(key, buffer);
+                    self.buffer_use.insert(key, last_used);
+                }
+                ResourcesCommand::InsertWavetable(key, wavetable) => {
+                    self.wavetables.insert(key, wavetable);
+                }
+                ResourcesCommand::RemoveBuffer(key) => {
+                    if let Some(buffer) = self.buffers.remove(key) {
+                        commands.send_back(ResourcesReturn::Buffer(buffer));
+                        commands.send_back(ResourcesReturn::Key(FreedKey::Buffer(key)));
+                    }
+                    if let Some(last_used) = self.buffer_use.remove(key) {
+                        commands.send_back(ResourcesReturn::BufferUse(last_used));
+                    }
+                }
+                ResourcesCommand::RemoveWavetable(key) => {
+                    if let Some(wavetable) = self.wavetables.remove(key) {
+                        commands.send_back(ResourcesReturn::Wavetable(wavetable));
+                        commands.send_back(ResourcesReturn::Key(FreedKey::Wavetable(key)));
+                    }
+                }
+                ResourcesCommand::InsertLookupTable(key, lookup_table) => {
+                    self.lookup_tables.insert(key, lookup_table);
+                }
+                ResourcesCommand::RemoveLookupTable(key) => {
+                    if let Some(lookup_table) = self.lookup_tables.remove(key) {
+                        commands.send_back(ResourcesReturn::LookupTable(lookup_table));
+                        commands.send_back(ResourcesReturn::Key(FreedKey::LookupTable(key)));
+                    }
+                }
+                ResourcesCommand::InsertUserData(key, data) => {
+                    // Replacing returns the old data, which is sent back to
+                    // avoid dropping it here
+                    if let Some(old_data) = self.user_data.insert(key, data) {
+                        commands.send_back(ResourcesReturn::UserData(old_data));
+                    }
+                }
+                ResourcesCommand::RemoveUserData(key) => {
+                    if let Some(data) = self.user_data.remove(key) {
+                        commands.send_back(ResourcesReturn::UserData(data));
+                        commands.send_back(ResourcesReturn::Key(FreedKey::UserData(key)));
+                    }
+                }
+            }
+        }
+        if num_applied > 0 {
+            commands
+                .num_applied
+                .fetch_add(num_applied, Ordering::Release);
         }
+        self.commands = Some(commands);
     }
-    pub fn get_user_data(&mut self, key: &String) -> Option<&mut Box<dyn AnyData>> {
-        self.user_data.get_mut(key)
+    /// Insert any kind of data implementing [`AnyData`]. The returned key is
+    /// used to read the data from nodes with [`Resources::user_data`].
+    pub fn insert_user_data<T: AnyData>(
+        &mut self,
+        data: T,
+    ) -> Result<UserDataKey<T>, ResourcesError> {
+        let key = match &mut self.keys {
+            Some(keys) => keys.new_user_data_key(),
+            None => return Err(ResourcesError::InsertUserDataThroughSender(Box::new(data))),
+        };
+        match key {
+            Some(key) => {
+                self.user_data.insert(key, Box::new(data));
+                Ok(UserDataKey::new(key))
+            }
+            None => Err(ResourcesError::UserDataFull(Box::new(data))),
+        }
+    }
+    /// None if the data has been removed or hasn't reached the audio thread
+    /// yet
+    pub fn user_data<T: AnyData>(&self, key: UserDataKey<T>) -> Option<&T> {
+        self.user_data.get(key.key)?.downcast_ref()
+    }
+    pub fn user_data_mut<T: AnyData>(&mut self, key: UserDataKey<T>) -> Option<&mut T> {
+        self.user_data.get_mut(key.key)?.downcast_mut()
+    }
+    /// Removes the data and returns it if the key is valid. Don't do this on
+    /// the audio thread unless you have a way of sending the data to a
+    /// different thread for deallocation.
+    pub fn remove_user_data<T: AnyData>(&mut self, key: UserDataKey<T>) -> Option<Box<T>> {
+        let data = self.user_data.remove(key.key)?;
+        self.free_key(FreedKey::UserData(key.key));
+        data.downcast().ok()
     }
 
     pub fn insert_wavetable(
         &mut self,
         wavetable: Wavetable,
     ) -> Result<WavetableKey, ResourcesError> {
-        if self.wavetables.len() < self.wavetables.capacity() {
-            Ok(self.wavetables.insert(wavetable))
-        } else {
-            Err(ResourcesError::WavetablesFull(wavetable))
+        let key = match &mut self.keys {
+            Some(keys) => keys.new_wavetable_key(),
+            None => return Err(ResourcesError::InsertWavetableThroughSender(wavetable)),
+        };
+        match key {
+            Some(key) => {
+                self.wavetables.insert(key, wavetable);
+                Ok(key)
+            }
+            None => Err(ResourcesError::WavetablesFull(wavetable)),
         }
     }
     pub fn remove_wavetable(&mut self, wavetable_key: WavetableKey) -> Option<Wavetable> {
-        self.wavetables.remove(wavetable_key)
+        let wavetable = self.wavetables.remove(wavetable_key)?;
+        self.free_key(FreedKey::Wavetable(wavetable_key));
+        Some(wavetable)
     }
+    /// Insert a buffer. If it doesn't fit in
+    /// [`ResourcesSettings::max_buffer_memory`], the least recently used
+    /// buffers that aren't pinned are removed first.
     pub fn insert_buffer(&mut self, buf: Buffer) -> Result<BufferKey, ResourcesError> {
-        if self.buffers.len() < self.buffers.capacity() {
-            Ok(self.buffers.insert(buf))
-        } else {
-            Err(ResourcesError::BuffersFull(buf))
+        let keys = match &mut self.keys {
+            Some(keys) => keys,
+            None => return Err(ResourcesError::InsertBufferThroughSender(buf)),
+        };
+        let Some(evicted) = keys.buffers_to_evict(buf.size_in_bytes()) else {
+            return Err(ResourcesError::BufferMemoryFull(buf));
+        };
+        if !keys.has_room_for_buffer(evicted.len()) {
+            return Err(ResourcesError::BuffersFull(buf));
+        }
+        for key in evicted {
+            keys.free(FreedKey::Buffer(key));
+            self.buffers.remove(key);
+            self.buffer_use.remove(key);
+        }
+        match keys.new_buffer_key(buf.size_in_bytes()) {
+            Some((key, last_used)) => {
+                self.buffers.insert(key, buf);
+                self.buffer_use.insert(key, last_used);
+                Ok(key)
+            }
+            None => Err(ResourcesError::BuffersFull(buf)),
         }
     }
+    /// Mark a buffer as used, see [`ResourcesCommandSender::touch_buffer`].
+    /// Returns false if the buffer doesn't exist or the keys are allocated by
+    /// a [`ResourcesCommandSender`].
+    pub fn touch_buffer(&mut self, buffer_key: BufferKey) -> bool {
+        self.keys
+            .as_mut()
+            .is_some_and(|keys| keys.touch_buffer(buffer_key))
+    }
+    /// Mark a buffer as used from the audio thread. Called by the [`Graph`]
+    /// for every buffer of a node it processes so that buffers that are
+    /// playing aren't evicted. It doesn't allocate.
+    pub(crate) fn mark_buffer_used(&self, buffer_key: BufferKey) {
+        if let Some(last_used) = self.buffer_use.get(buffer_key) {
+            mark_used(&self.use_clock, last_used);
+        }
+    }
+    /// Keep a buffer from being evicted, see
+    /// [`ResourcesCommandSender::pin_buffer`]. Returns false if the buffer
+    /// doesn't exist or the keys are allocated by a
+    /// [`ResourcesCommandSender`].
+    pub fn pin_buffer(&mut self, buffer_key: BufferKey) -> bool {
+        self.keys
+            .as_mut()
+            .is_some_and(|keys| keys.set_buffer_pinned(buffer_key, true))
+    }
+    /// Allow a pinned buffer to be evicted again. Returns false if the buffer
+    /// doesn't exist or the keys are allocated by a
+    /// [`ResourcesCommandSender`].
+    pub fn unpin_buffer(&mut self, buffer_key: BufferKey) -> bool {
+        self.keys
+            .as_mut()
+            .is_some_and(|keys| keys.set_buffer_pinned(buffer_key, false))
+    }
     /// Returns the rate with which a buffer needs to be played to sound at its original speed at the current sample rate.
     ///
     /// # Example:
@@ -222,13 +1063,214 @@ impl Resources {
     /// the audio thread unless you have a way of sending the buffer to a
     /// different thread for deallocation.
     pub fn remove_buffer(&mut self, buffer_key: BufferKey) -> Option<Buffer> {
-        self.buffers.remove(buffer_key)
+        self.buffer_use.remove(buffer_key);
+        let buffer = self.buffers.remove(buffer_key)?;
+        self.free_key(FreedKey::Buffer(buffer_key));
+        Some(buffer)
+    }
+    pub fn insert_lookup_table(
+        &mut self,
+        lookup_table: LookupTable,
+    ) -> Result<LookupTableKey, ResourcesError> {
+        let key = match &mut self.keys {
+            Some(keys) => keys.new_lookup_table_key(),
+            None => return Err(ResourcesError::InsertLookupTableThroughSender(lookup_table)),
+        };
+        match key {
+            Some(key) => {
+                self.lookup_tables.insert(key, lookup_table);
+                Ok(key)
+            }
+            None => Err(ResourcesError::LookupTablesFull(lookup_table)),
+        }
+    }
+    pub fn remove_lookup_table(&mut self, lookup_table_key: LookupTableKey) -> Option<LookupTable> {
+        let lookup_table = self.lookup_tables.remove(lookup_table_key)?;
+        self.free_key(FreedKey::LookupTable(lookup_table_key));
+        Some(lookup_table)
+    }
+    fn free_key(&mut self, key: FreedKey) {
+        if let Some(keys) = &mut self.keys {
+            keys.free(key);
+        } else if let Some(commands) = &mut self.commands {
+            commands.send_back(ResourcesReturn::Key(key));
+        }
     }
 }
 
-pub fn db_to_amplitude(db: f32) -> f32 {
-    10.0_f32.powf(db / 20.)
+pub fn db_to_amplitude(db: Sample) -> Sample {
+    (10.0 as Sample).powf(db / 20.)
 }
-pub fn amplitude_to_db(amplitude: f32) -> f32 {
+pub fn amplitude_to_db(amplitude: Sample) -> Sample {
     20.0 * amplitude.log10()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::GraphSettings;
+
+    #[test]
+    fn stale_buffer_keys_are_rejected() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let old_key = resources.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        resources.remove_buffer(old_key).unwrap();
+        // The slot is reused, but the old key doesn't point to the new buffer
+        let new_key = resources.insert_buffer(Buffer::new(32, 1, 44100.)).unwrap();
+        assert!(resources.buffers.get(old_key).is_none());
+        assert!(resources.buffers.get(new_key).is_some());
+        assert!(resources.remove_buffer(old_key).is_none());
+    }
+
+    #[test]
+    fn insert_through_command_channel() {
+        let mut resources = Resources::new(ResourcesSettings {
+            max_buffers: 1,
+            ..Default::default()
+        });
+        let mut sender = resources.command_channel(4).unwrap();
+        assert!(resources.command_channel(4).is_err());
+        assert!(resources.insert_buffer(Buffer::new(16, 1, 44100.)).is_err());
+        let mut graph = Graph::new(GraphSettings::default());
+        let mut graph_node = graph.to_node().unwrap();
+        let key = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        assert!(matches!(
+            sender.insert_buffer(Buffer::new(16, 1, 44100.)),
+            Err(ResourcesError::BuffersFull(_))
+        ));
+        assert!(resources.buffers.get(key).is_none());
+        graph_node.process(&[], &mut resources);
+        assert!(resources.buffers.get(key).is_some());
+        // Keys freed on the audio thread are sent back to the sender
+        resources.remove_buffer(key).unwrap();
+        let new_key = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        graph_node.process(&[], &mut resources);
+        assert!(resources.buffers.get(key).is_none());
+        assert!(resources.buffers.get(new_key).is_some());
+    }
+
+    #[derive(Debug)]
+    struct Tempo(f32);
+    impl AnyData for Tempo {}
+
+    #[test]
+    fn remove_and_user_data_through_command_channel() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut sender = resources.command_channel(8).unwrap();
+        let buffer_key = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        let tempo = sender.insert_user_data(Tempo(120.)).unwrap();
+        assert!(resources.user_data(tempo).is_none());
+        resources.apply_commands();
+        assert!(resources.buffers.get(buffer_key).is_some());
+        assert_eq!(resources.user_data(tempo).unwrap().0, 120.);
+        sender.replace_user_data(tempo, Tempo(90.)).unwrap();
+        sender.remove_buffer(buffer_key).unwrap();
+        resources.apply_commands();
+        assert!(resources.buffers.get(buffer_key).is_none());
+        assert_eq!(resources.user_data(tempo).unwrap().0, 90.);
+        sender.remove_user_data(tempo).unwrap();
+        resources.apply_commands();
+        assert!(resources.user_data(tempo).is_none());
+        // The removed buffer and the replaced data are dropped here
+        sender.update();
+    }
+
+    #[test]
+    fn typed_user_data() {
+        let mut resources = Resources::new(ResourcesSettings {
+            max_user_data: 1,
+            ..Default::default()
+        });
+        let tempo = resources.insert_user_data(Tempo(120.)).unwrap();
+        assert!(matches!(
+            resources.insert_user_data(Tempo(90.)),
+            Err(ResourcesError::UserDataFull(_))
+        ));
+        resources.user_data_mut(tempo).unwrap().0 = 100.;
+        assert_eq!(resources.remove_user_data(tempo).unwrap().0, 100.);
+        // The slot is reused, but the old key doesn't point to the new data
+        let new_tempo = resources.insert_user_data(Tempo(60.)).unwrap();
+        assert_ne!(tempo, new_tempo);
+        assert!(resources.user_data(tempo).is_none());
+        assert_eq!(resources.user_data(new_tempo).unwrap().0, 60.);
+    }
+
+    #[test]
+    fn buffer_memory_limit_evicts_least_recently_used() {
+        let bytes = Buffer::new(16, 1, 44100.).size_in_bytes();
+        let mut resources = Resources::new(ResourcesSettings {
+            max_buffer_memory: Some(bytes * 3),
+            ..Default::default()
+        });
+        let pinned = resources.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        let touched = resources.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        let oldest = resources.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        assert!(resources.pin_buffer(pinned));
+        assert!(resources.touch_buffer(touched));
+        let newest = resources.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        assert!(resources.buffers.get(oldest).is_none());
+        assert!(!resources.touch_buffer(oldest));
+        for key in [pinned, touched, newest] {
+            assert!(resources.buffers.get(key).is_some());
+        }
+        // Evicting everything that isn't pinned still isn't enough
+        assert!(matches!(
+            resources.insert_buffer(Buffer::new(48, 1, 44100.)),
+            Err(ResourcesError::BufferMemoryFull(_))
+        ));
+        assert!(resources.buffers.get(touched).is_some());
+        assert!(resources.unpin_buffer(pinned));
+        resources.insert_buffer(Buffer::new(48, 1, 44100.)).unwrap();
+        assert_eq!(resources.buffers.len(), 1);
+    }
+
+    #[test]
+    fn buffer_memory_limit_through_command_channel() {
+        let bytes = Buffer::new(16, 1, 44100.).size_in_bytes();
+        let mut resources = Resources::new(ResourcesSettings {
+            max_buffer_memory: Some(bytes * 2),
+            ..Default::default()
+        });
+        let mut sender = resources.command_channel(8).unwrap();
+        let first = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        let second = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        resources.apply_commands();
+        let third = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        assert!(!sender.contains_buffer(first));
+        assert_eq!(sender.buffer_memory(), bytes * 2);
+        resources.apply_commands();
+        assert!(resources.buffers.get(first).is_none());
+        assert!(resources.buffers.get(second).is_some());
+        assert!(resources.buffers.get(third).is_some());
+    }
+
+    #[test]
+    fn buffers_read_by_nodes_are_not_evicted() {
+        let bytes = Buffer::new(16, 1, 44100.).size_in_bytes();
+        let mut resources = Resources::new(ResourcesSettings {
+            max_buffer_memory: Some(bytes * 2),
+            ..Default::default()
+        });
+        let mut sender = resources.command_channel(8).unwrap();
+        let playing = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        let idle = sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 4,
+            ..Default::default()
+        });
+        let reader = graph.push_gen(buffer::BufferReader::new(
+            playing,
+            1.0,
+            StopAction::Continue,
+        ));
+        graph.connect(Connection::graph_output(reader)).unwrap();
+        let mut node = graph.to_node().unwrap();
+        graph.commit_changes();
+        graph.update();
+        // Reading the buffer makes it more recently used than the idle one
+        node.process(&[], &mut resources);
+        sender.insert_buffer(Buffer::new(16, 1, 44100.)).unwrap();
+        assert!(sender.contains_buffer(playing));
+        assert!(!sender.contains_buffer(idle));
+    }
+}
@@ -0,0 +1,166 @@
+//! Tempo and beat synchronization with [Ableton Link](https://www.ableton.com/link/),
+//! enabled with the `link` feature.
+//!
+//! A [`LinkSync`] joins a Link session and keeps the transport of the top
+//! level Graph in sync with it:
+//!
+//! - The tempo of the session replaces the [`MusicalTimeMap`](crate::scheduling::MusicalTimeMap)
+//!   of the transport, which is reduced to a single tempo.
+//! - While the transport is playing its phase within a quantum, e.g. a bar
+//!   of 4 beats, is kept aligned with the session by moving it when it drifts
+//!   by more than a tolerance.
+//! - With start/stop sync, the transport is started and stopped together with
+//!   the session.
+//!
+//! Changes to the session are picked up in [`LinkSync::update`], which should
+//! be called regularly from the control thread, e.g. together with
+//! [`Graph::update`]. Changes made by this program should go through
+//! [`LinkSync::set_tempo`], [`LinkSync::play`] and [`LinkSync::stop`] so that
+//! the other peers follow.
+//!
+//! ```no_run
+//! # use knyst::prelude::*;
+//! # use knyst::link::LinkSync;
+//! # use std::time::Duration;
+//! # let mut graph = Graph::new(GraphSettings::default());
+//! let mut link = LinkSync::new(120.0).quantum(4.0).output_latency(Duration::from_millis(10));
+//! loop {
+//!     link.update(&mut graph).unwrap();
+//!     graph.update();
+//!     std::thread::sleep(Duration::from_millis(5));
+//! }
+//! ```
+
+use std::time::Duration;
+
+use rusty_link::{AblLink, SessionState};
+
+use crate::graph::{Graph, ScheduleError};
+use crate::scheduling::{Beats, TempoChange, TransportState};
+
+/// Keeps the transport of a Graph in sync with an Ableton Link session, see
+/// the [module documentation](self).
+pub struct LinkSync {
+    link: AblLink,
+    state: SessionState,
+    quantum: f64,
+    tolerance: f64,
+    output_latency: Duration,
+}
+
+impl LinkSync {
+    /// Join a Link session, proposing `bpm` if there are no other peers.
+    /// Start/stop sync is enabled.
+    pub fn new(bpm: f64) -> Self {
+        let link = AblLink::new(bpm);
+        link.enable(true);
+        link.enable_start_stop_sync(true);
+        Self {
+            link,
+            state: SessionState::new(),
+            quantum: 4.0,
+            tolerance: 0.02,
+            output_latency: Duration::ZERO,
+        }
+    }
+    /// The number of beats within which the phase is aligned with the
+    /// session. Default: 4
+    pub fn quantum(mut self, quantum: f64) -> Self {
+        self.quantum = quantum;
+        self
+    }
+    /// How far in beats the transport may drift from the session before it
+    /// is moved. Default: 0.02
+    pub fn tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+    /// The time from the Graph producing a block to it being heard, i.e. the
+    /// latency of the audio backend. Default: 0
+    pub fn output_latency(mut self, output_latency: Duration) -> Self {
+        self.output_latency = output_latency;
+        self
+    }
+    /// Start and stop the transport together with the session
+    pub fn start_stop_sync(self, enabled: bool) -> Self {
+        self.link.enable_start_stop_sync(enabled);
+        self
+    }
+    /// The number of other peers in the session
+    pub fn num_peers(&self) -> u64 {
+        self.link.num_peers()
+    }
+    /// Change the tempo of the session
+    pub fn set_tempo(&mut self, bpm: f64) {
+        self.link.capture_app_session_state(&mut self.state);
+        self.state.set_tempo(bpm, self.link.clock_micros());
+        self.link.commit_app_session_state(&self.state);
+    }
+    /// Start the session, and the transport with it if start/stop sync is
+    /// enabled
+    pub fn play(&mut self) {
+        self.set_is_playing(true);
+    }
+    /// Stop the session, and the transport with it if start/stop sync is
+    /// enabled
+    pub fn stop(&mut self) {
+        self.set_is_playing(false);
+    }
+    fn set_is_playing(&mut self, playing: bool) {
+        self.link.capture_app_session_state(&mut self.state);
+        self.state
+            .set_is_playing(playing, self.link.clock_micros() as u64);
+        self.link.commit_app_session_state(&self.state);
+    }
+    /// Apply the tempo, phase and playing state of the session to the
+    /// transport of `graph`, which has to be a running top level Graph.
+    pub fn update(&mut self, graph: &mut Graph) -> Result<(), ScheduleError> {
+        self.link.capture_app_session_state(&mut self.state);
+        let (beats, _) = graph
+            .transport_position_at_latency()
+            .ok_or(ScheduleError::TransportNotAvailable)?;
+
+        let bpm = self.state.tempo();
+        let map = graph
+            .musical_time_map()
+            .ok_or(ScheduleError::TransportNotAvailable)?;
+        let num_tempo_changes = map.len();
+        if num_tempo_changes > 1 || (map.tempo_at(beats) - bpm).abs() > 1e-9 {
+            for index in (1..num_tempo_changes).rev() {
+                graph.remove_tempo_change(index)?;
+            }
+            graph.replace_tempo_change(0, TempoChange::NewTempo { bpm })?;
+        }
+
+        let playing = graph.transport_state() == Some(TransportState::Playing);
+        if self.link.is_start_stop_sync_enabled() && self.state.is_playing() != playing {
+            if playing {
+                graph.transport_stop()?;
+            } else {
+                graph.transport_play()?;
+            }
+        }
+
+        if graph.transport_state() == Some(TransportState::Playing) {
+            let (beats, latency) = graph
+                .transport_position_at_latency()
+                .ok_or(ScheduleError::TransportNotAvailable)?;
+            let time =
+                self.link.clock_micros() + (latency + self.output_latency).as_micros() as i64;
+            let link_phase = self.state.phase_at_time(time, self.quantum);
+            let beats = beats.as_beats_f64();
+            let phase = beats.rem_euclid(self.quantum);
+            // The shortest way to the phase of the session
+            let difference = (link_phase - phase + self.quantum / 2.0).rem_euclid(self.quantum)
+                - self.quantum / 2.0;
+            if difference.abs() > self.tolerance {
+                let mut target = beats + difference;
+                if target < 0.0 {
+                    target += self.quantum;
+                }
+                graph.transport_seek(Beats::from_beats_f64(target))?;
+            }
+        }
+        Ok(())
+    }
+}
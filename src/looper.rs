@@ -0,0 +1,317 @@
+//! A live looper.
+//!
+//! A [`Looper`] records its input into an internal buffer and plays it back
+//! in a loop. While looping, new layers can be overdubbed on top and the last
+//! layer can be undone. It is controlled through its inputs, so that the
+//! controls can come from MIDI, triggers or scheduled changes:
+//!
+//! - "rec" (trigger): start recording a new loop, or close the loop being
+//!   recorded and start playing it. The length of the loop is the time
+//!   between the two triggers, optionally rounded to a number of beats with
+//!   [`Looper::quantize`].
+//! - "overdub" (gate): add the input to the loop while open.
+//! - "feedback": how much of the loop is kept while overdubbing, 1 keeps all
+//!   of it and 0 replaces it with the input.
+//! - "undo" (trigger): undo the last overdubbed layer. Triggering it again
+//!   redoes the layer.
+//! - "clear" (trigger): stop and forget the loop.
+//!
+//! The outputs are the loop followed by "phase", the position in the loop
+//! from 0 to 1.
+
+use crate::graph::{Gen, GenState};
+use crate::trig::is_trigger;
+use crate::{sample_to_f64, Resources, Sample};
+
+const INPUT_NAMES: [&str; 8] = ["in0", "in1", "in2", "in3", "in4", "in5", "in6", "in7"];
+const OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
+const CONTROL_NAMES: [&str; 5] = ["rec", "overdub", "feedback", "undo", "clear"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LooperState {
+    Empty,
+    Recording,
+    Looping,
+}
+
+/// Records and loops its input with overdubbing, see the
+/// [module documentation](self).
+pub struct Looper {
+    num_channels: usize,
+    max_seconds: Sample,
+    quantize: Option<f64>,
+    state: LooperState,
+    /// Interleaved frames, allocated in `init`
+    buffer: Vec<Sample>,
+    /// The samples the current overdub layer replaced, at the same positions
+    /// as in `buffer`
+    undo: Vec<Sample>,
+    max_frames: usize,
+    /// The length of the loop in frames, or of the recording so far
+    length: usize,
+    position: usize,
+    overdubbing: bool,
+    /// Where the last overdub layer started and how many frames it covers
+    layer_start: usize,
+    layer_frames: usize,
+}
+
+impl Looper {
+    /// Loop `num_channels` channels with loops of at most `max_seconds`.
+    /// The memory is allocated when the Looper is added to a Graph.
+    pub fn new(num_channels: usize, max_seconds: Sample) -> Self {
+        Self {
+            num_channels: num_channels.max(1),
+            max_seconds,
+            quantize: None,
+            state: LooperState::Empty,
+            buffer: vec![],
+            undo: vec![],
+            max_frames: 0,
+            length: 0,
+            position: 0,
+            overdubbing: false,
+            layer_start: 0,
+            layer_frames: 0,
+        }
+    }
+    /// Round the length of a new loop to the nearest multiple of `beats`
+    /// beats at the tempo of the transport. Default: not quantized
+    pub fn quantize(mut self, beats: f64) -> Self {
+        self.quantize = Some(beats).filter(|beats| *beats > 0.0);
+        self
+    }
+    fn start_recording(&mut self) {
+        self.state = LooperState::Recording;
+        self.length = 0;
+        self.position = 0;
+        self.layer_frames = 0;
+    }
+    fn close_loop(&mut self, resources: &Resources) {
+        let mut length = self.length;
+        let bpm = resources.transport.bpm;
+        if let Some(beats) = self.quantize.filter(|_| bpm > 0.0) {
+            let frames = beats * sample_to_f64(resources.sample_rate) * 60.0 / bpm;
+            let multiple = (length as f64 / frames).round().max(1.0);
+            length = ((multiple * frames).round() as usize).min(self.max_frames);
+            // A loop made longer is filled with silence
+            if length > self.length {
+                let channels = self.num_channels;
+                self.buffer[self.length * channels..length * channels].fill(0.0);
+            }
+        }
+        if length == 0 {
+            self.state = LooperState::Empty;
+            return;
+        }
+        self.length = length;
+        self.position = 0;
+        self.state = LooperState::Looping;
+    }
+    fn swap_layer(&mut self) {
+        let channels = self.num_channels;
+        let frames = self.layer_frames.min(self.length);
+        for i in 0..frames {
+            let frame = (self.layer_start + i) % self.length;
+            let range = frame * channels..(frame + 1) * channels;
+            self.buffer[range.clone()].swap_with_slice(&mut self.undo[range]);
+        }
+    }
+}
+
+impl Gen for Looper {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let channels = self.num_channels;
+        let controls = &inputs[channels..];
+        for i in 0..outputs[0].len() {
+            if is_trigger(controls[4][i]) {
+                self.state = LooperState::Empty;
+                self.layer_frames = 0;
+            }
+            if is_trigger(controls[0][i]) && self.max_frames > 0 {
+                match self.state {
+                    LooperState::Recording => self.close_loop(resources),
+                    _ => self.start_recording(),
+                }
+            }
+            if is_trigger(controls[3][i]) && self.state == LooperState::Looping {
+                self.swap_layer();
+            }
+            let overdub = controls[1][i] > 0.0 && self.state == LooperState::Looping;
+            if overdub && !self.overdubbing {
+                // A new layer replaces the one that could be undone
+                self.layer_start = self.position;
+                self.layer_frames = 0;
+            }
+            self.overdubbing = overdub;
+
+            let frame = self.position * channels;
+            match self.state {
+                LooperState::Empty => {
+                    for output in outputs[..channels].iter_mut() {
+                        output[i] = 0.0;
+                    }
+                }
+                LooperState::Recording => {
+                    for channel in 0..channels {
+                        self.buffer[frame + channel] = inputs[channel][i];
+                        outputs[channel][i] = 0.0;
+                    }
+                    self.length += 1;
+                    self.position += 1;
+                    if self.length >= self.max_frames {
+                        self.close_loop(resources);
+                    }
+                }
+                LooperState::Looping => {
+                    let feedback = controls[2][i];
+                    let save_undo = overdub && self.layer_frames < self.length;
+                    for channel in 0..channels {
+                        let sample = self.buffer[frame + channel];
+                        outputs[channel][i] = sample;
+                        if overdub {
+                            if save_undo {
+                                self.undo[frame + channel] = sample;
+                            }
+                            self.buffer[frame + channel] = sample * feedback + inputs[channel][i];
+                        }
+                    }
+                    if overdub {
+                        self.layer_frames += 1;
+                    }
+                    self.position = (self.position + 1) % self.length;
+                }
+            }
+            outputs[channels][i] = match self.state {
+                LooperState::Looping => self.position as Sample / self.length as Sample,
+                _ => 0.0,
+            };
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        self.num_channels + CONTROL_NAMES.len()
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_channels + 1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        if input < self.num_channels {
+            INPUT_NAMES.get(input).copied().unwrap_or("")
+        } else {
+            CONTROL_NAMES
+                .get(input - self.num_channels)
+                .copied()
+                .unwrap_or("")
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        if output == self.num_channels {
+            "phase"
+        } else {
+            OUTPUT_NAMES.get(output).copied().unwrap_or("")
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        if input == self.num_channels + 2 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.max_frames = (self.max_seconds * sample_rate).max(0.0) as usize;
+        self.buffer = vec![0.0; self.max_frames * self.num_channels];
+        self.undo = vec![0.0; self.max_frames * self.num_channels];
+        self.state = LooperState::Empty;
+    }
+    fn name(&self) -> &'static str {
+        "Looper"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    /// Run one block with `signal` on the input and the control inputs set
+    /// to `controls` on the first sample only
+    fn run(
+        looper: &mut Looper,
+        signal: Sample,
+        controls: [Sample; 5],
+        resources: &mut Resources,
+    ) -> Vec<Sample> {
+        let mut inputs = vec![vec![signal; 4].into_boxed_slice()];
+        for (i, &value) in controls.iter().enumerate() {
+            let mut input = vec![0.0; 4];
+            input[0] = value;
+            if i == 1 || i == 2 {
+                // Gates and levels hold their value
+                input.fill(value);
+            }
+            inputs.push(input.into_boxed_slice());
+        }
+        let mut outputs = vec![vec![0.0; 4].into_boxed_slice(); 2];
+        looper.process(&inputs, &mut outputs, resources);
+        outputs[0].to_vec()
+    }
+
+    #[test]
+    fn record_overdub_and_undo() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut looper = Looper::new(1, 1.0);
+        looper.init(8.0);
+        run(&mut looper, 1.0, [1.0, 0.0, 1.0, 0.0, 0.0], &mut resources);
+        // Close the loop after 4 frames
+        let out = run(&mut looper, 0.0, [1.0, 0.0, 1.0, 0.0, 0.0], &mut resources);
+        assert_eq!(out, [1.0; 4]);
+        // Overdub at half feedback
+        let out = run(&mut looper, 2.0, [0.0, 1.0, 0.5, 0.0, 0.0], &mut resources);
+        assert_eq!(out, [1.0; 4]);
+        let out = run(&mut looper, 0.0, [0.0, 0.0, 1.0, 0.0, 0.0], &mut resources);
+        assert_eq!(out, [2.5; 4]);
+        let out = run(&mut looper, 0.0, [0.0, 0.0, 1.0, 1.0, 0.0], &mut resources);
+        assert_eq!(out, [1.0; 4]);
+        // Redo
+        let out = run(&mut looper, 0.0, [0.0, 0.0, 1.0, 1.0, 0.0], &mut resources);
+        assert_eq!(out, [2.5; 4]);
+        let out = run(&mut looper, 0.0, [0.0, 0.0, 1.0, 0.0, 1.0], &mut resources);
+        assert_eq!(out, [0.0; 4]);
+    }
+
+    #[test]
+    fn quantized_length() {
+        let mut resources = Resources::new(ResourcesSettings {
+            sample_rate: 8.0,
+            ..Default::default()
+        });
+        // 60 bpm, 8 frames per beat
+        let mut looper = Looper::new(1, 4.0).quantize(1.0);
+        looper.init(8.0);
+        run(&mut looper, 1.0, [1.0, 0.0, 1.0, 0.0, 0.0], &mut resources);
+        run(&mut looper, 1.0, [0.0, 0.0, 1.0, 0.0, 0.0], &mut resources);
+        run(&mut looper, 1.0, [0.0, 0.0, 1.0, 0.0, 0.0], &mut resources);
+        // 12 frames are rounded to 16
+        let mut out = run(&mut looper, 0.0, [1.0, 0.0, 1.0, 0.0, 0.0], &mut resources);
+        for _ in 0..3 {
+            out.extend(run(
+                &mut looper,
+                0.0,
+                [0.0, 0.0, 1.0, 0.0, 0.0],
+                &mut resources,
+            ));
+        }
+        let mut expected = vec![1.0; 12];
+        expected.extend([0.0; 4]);
+        assert_eq!(out, expected);
+    }
+}
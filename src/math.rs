@@ -0,0 +1,183 @@
+//! Per sample arithmetic and range mapping.
+//!
+//! [`Mult`](crate::graph::Mult) and [`Add`](crate::graph::Add) live in the
+//! graph module. This module has the rest:
+//!
+//! - [`Sub`], [`Div`] and [`Pow`] with two inputs
+//! - [`Abs`] with one input
+//! - [`Clip`] and [`Wrap`] limiting a signal to a range
+//! - [`LinLin`] and [`LinExp`] mapping a signal from one range to another
+//!
+//! Results that aren't finite, e.g. from dividing by 0, are output as 0 so
+//! that they don't spread through the rest of the Graph.
+
+use crate::graph::{Gen, GenState};
+use crate::{Resources, Sample};
+
+#[inline]
+fn finite_or_zero(value: Sample) -> Sample {
+    if value.is_finite() {
+        value
+    } else {
+        0.0
+    }
+}
+
+/// Implements Gen for a Gen without state that outputs `$f` applied to each
+/// frame of its inputs
+macro_rules! per_sample_gen {
+    ($gen:ident, $name:literal, [$($input:ident: $index:literal),+], $output:literal, $f:expr) => {
+        impl Gen for $gen {
+            fn process(
+                &mut self,
+                inputs: &[Box<[Sample]>],
+                outputs: &mut [Box<[Sample]>],
+                _resources: &mut Resources,
+            ) -> GenState {
+                let f = $f;
+                for (i, out) in outputs[0].iter_mut().enumerate() {
+                    $(let $input = inputs[$index][i];)+
+                    *out = finite_or_zero(f($($input),+));
+                }
+                GenState::Continue
+            }
+            fn num_inputs(&self) -> usize {
+                [$(stringify!($input)),+].len()
+            }
+            fn num_outputs(&self) -> usize {
+                1
+            }
+            fn input_desc(&self, input: usize) -> &'static str {
+                [$(stringify!($input)),+].get(input).copied().unwrap_or("")
+            }
+            fn output_desc(&self, output: usize) -> &'static str {
+                match output {
+                    0 => $output,
+                    _ => "",
+                }
+            }
+            fn name(&self) -> &'static str {
+                $name
+            }
+        }
+    };
+}
+
+/// "value0" minus "value1"
+pub struct Sub;
+per_sample_gen!(
+    Sub,
+    "Sub",
+    [value0: 0, value1: 1],
+    "difference",
+    |a: Sample, b: Sample| a - b
+);
+
+/// "value0" divided by "value1". Dividing by 0 outputs 0.
+pub struct Div;
+per_sample_gen!(
+    Div,
+    "Div",
+    [value0: 0, value1: 1],
+    "quotient",
+    |a: Sample, b: Sample| a / b
+);
+
+/// "base" to the power of "exponent"
+pub struct Pow;
+per_sample_gen!(
+    Pow,
+    "Pow",
+    [base: 0, exponent: 1],
+    "power",
+    |base: Sample, exponent: Sample| base.powf(exponent)
+);
+
+/// The absolute value of "signal"
+pub struct Abs;
+per_sample_gen!(Abs, "Abs", [signal: 0], "abs", |signal: Sample| signal.abs());
+
+/// Limits "signal" to between "min" and "max"
+pub struct Clip;
+per_sample_gen!(
+    Clip,
+    "Clip",
+    [signal: 0, min: 1, max: 2],
+    "sig",
+    |signal: Sample, min: Sample, max: Sample| signal.max(min).min(max)
+);
+
+/// Wraps "signal" around to stay between "min" and "max", e.g. a phase
+/// between 0 and 1. Outputs "min" if the range is empty.
+pub struct Wrap;
+per_sample_gen!(
+    Wrap,
+    "Wrap",
+    [signal: 0, min: 1, max: 2],
+    "sig",
+    |signal: Sample, min: Sample, max: Sample| {
+        let range = max - min;
+        if range <= 0.0 {
+            min
+        } else {
+            min + (signal - min).rem_euclid(range)
+        }
+    }
+);
+
+/// Maps "signal" linearly from the range "in_min" to "in_max" to the range
+/// "out_min" to "out_max" without clipping it.
+pub struct LinLin;
+per_sample_gen!(
+    LinLin,
+    "LinLin",
+    [signal: 0, in_min: 1, in_max: 2, out_min: 3, out_max: 4],
+    "sig",
+    |signal: Sample, in_min: Sample, in_max: Sample, out_min: Sample, out_max: Sample| {
+        out_min + (signal - in_min) / (in_max - in_min) * (out_max - out_min)
+    }
+);
+
+/// Maps "signal" from the linear range "in_min" to "in_max" to the
+/// exponential range "out_min" to "out_max", e.g. from an LFO to a frequency.
+/// "out_min" and "out_max" need to be larger than 0.
+pub struct LinExp;
+per_sample_gen!(
+    LinExp,
+    "LinExp",
+    [signal: 0, in_min: 1, in_max: 2, out_min: 3, out_max: 4],
+    "sig",
+    |signal: Sample, in_min: Sample, in_max: Sample, out_min: Sample, out_max: Sample| {
+        out_min * (out_max / out_min).powf((signal - in_min) / (in_max - in_min))
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn run(gen: &mut dyn Gen, inputs: &[Sample]) -> Sample {
+        let inputs: Vec<Box<[Sample]>> = inputs.iter().map(|&v| vec![v].into()).collect();
+        let mut outputs = vec![vec![0.0].into_boxed_slice()];
+        let mut resources = Resources::new(ResourcesSettings::default());
+        gen.process(&inputs, &mut outputs, &mut resources);
+        outputs[0][0]
+    }
+
+    #[test]
+    fn math_gens() {
+        assert_eq!(run(&mut Sub, &[3.0, 5.0]), -2.0);
+        assert_eq!(run(&mut Div, &[3.0, 2.0]), 1.5);
+        assert_eq!(run(&mut Div, &[3.0, 0.0]), 0.0);
+        assert_eq!(run(&mut Pow, &[2.0, 3.0]), 8.0);
+        assert_eq!(run(&mut Abs, &[-0.5]), 0.5);
+        assert_eq!(run(&mut Clip, &[2.0, -1.0, 1.0]), 1.0);
+        assert!((run(&mut Wrap, &[1.25, 0.0, 1.0]) - 0.25).abs() < 1e-6);
+        assert!((run(&mut Wrap, &[-0.25, 0.0, 1.0]) - 0.75).abs() < 1e-6);
+        assert_eq!(run(&mut LinLin, &[0.0, -1.0, 1.0, 100.0, 200.0]), 150.0);
+        assert!((run(&mut LinExp, &[0.5, 0.0, 1.0, 100.0, 400.0]) - 200.0).abs() < 1e-3);
+        assert_eq!(LinExp.num_inputs(), 5);
+        assert_eq!(Clip.input_desc(2), "max");
+    }
+}
@@ -0,0 +1,479 @@
+//! MIDI messages with sample accurate timestamps.
+//!
+//! Audio backends that support MIDI, currently [`JackBackend`], deliver
+//! incoming [`MidiEvent`]s to the control thread and send outgoing ones at
+//! the sample given by their timestamp. Timestamps count samples from when
+//! processing started, the same clock as
+//! [`ParameterChange::absolute_samples`], so an incoming event can be turned
+//! into a change at the time it arrived plus some latency.
+//!
+//! MIDI can be sent from inside the Graph with [`MidiNoteOut`], which plays a
+//! note for every trigger it receives, e.g. from an onset detector, and
+//! [`MidiControlOut`], which sends a control change when its input changes.
+//! They timestamp their events with the sample they were generated at. Give
+//! each of them its own sender, e.g. from [`JackBackend::add_midi_output`].
+//! Steps of a [`Sequencer`] can be sent with the timestamp from
+//! [`Graph::sample_at_beats`]:
+//!
+//! ```ignore
+//! sequencer.add(pattern, Beats::ZERO, move |graph, event| {
+//!     if let Some(timestamp) = graph.sample_at_beats(event.beats) {
+//!         let note = event.value as u8;
+//!         let on = MidiMessage::NoteOn { channel: 0, note, velocity: 100 };
+//!         midi_out.push(MidiEvent { timestamp, message: on }).ok();
+//!     }
+//! });
+//! ```
+//!
+//! ```
+//! # use knyst::midi::*;
+//! let message = MidiMessage::from_bytes(&[0x90, 60, 100]).unwrap();
+//! assert_eq!(
+//!     message,
+//!     MidiMessage::NoteOn {
+//!         channel: 0,
+//!         note: 60,
+//!         velocity: 100
+//!     }
+//! );
+//! assert_eq!(message.to_bytes().as_slice(), &[0x90, 60, 100]);
+//! ```
+
+// Imports for docs
+#[allow(unused_imports)]
+#[cfg(feature = "jack")]
+use crate::audio_backend::JackBackend;
+use crate::graph::{Gen, GenState, Rate};
+#[allow(unused_imports)]
+use crate::graph::{Graph, ParameterChange};
+#[allow(unused_imports)]
+use crate::sequencer::Sequencer;
+use crate::trig::is_trigger;
+use crate::{Resources, Sample};
+
+/// A MIDI channel voice message. Channels are 0 to 15.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    /// `value` is between -8192 and 8191, 0 being the center
+    PitchBend {
+        channel: u8,
+        value: i16,
+    },
+}
+
+impl MidiMessage {
+    /// Parse a channel voice message. Returns None for other messages, e.g.
+    /// system exclusive, or if there are too few bytes. A note on with a
+    /// velocity of 0 is returned as a note off.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let status = *bytes.first()?;
+        let channel = status & 0x0F;
+        let data = |i: usize| bytes.get(i).map(|b| b & 0x7F);
+        let message = match status & 0xF0 {
+            0x80 => MidiMessage::NoteOff {
+                channel,
+                note: data(1)?,
+                velocity: data(2)?,
+            },
+            0x90 => match (data(1)?, data(2)?) {
+                (note, 0) => MidiMessage::NoteOff {
+                    channel,
+                    note,
+                    velocity: 0,
+                },
+                (note, velocity) => MidiMessage::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                },
+            },
+            0xA0 => MidiMessage::PolyPressure {
+                channel,
+                note: data(1)?,
+                pressure: data(2)?,
+            },
+            0xB0 => MidiMessage::ControlChange {
+                channel,
+                controller: data(1)?,
+                value: data(2)?,
+            },
+            0xC0 => MidiMessage::ProgramChange {
+                channel,
+                program: data(1)?,
+            },
+            0xD0 => MidiMessage::ChannelPressure {
+                channel,
+                pressure: data(1)?,
+            },
+            0xE0 => MidiMessage::PitchBend {
+                channel,
+                value: ((data(1)? as i16) | ((data(2)? as i16) << 7)) - 8192,
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+    /// The bytes of the message
+    pub fn to_bytes(&self) -> MidiBytes {
+        let (status, channel, data, len) = match *self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (0x80, channel, [note, velocity], 3),
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (0x90, channel, [note, velocity], 3),
+            MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => (0xA0, channel, [note, pressure], 3),
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => (0xB0, channel, [controller, value], 3),
+            MidiMessage::ProgramChange { channel, program } => (0xC0, channel, [program, 0], 2),
+            MidiMessage::ChannelPressure { channel, pressure } => (0xD0, channel, [pressure, 0], 2),
+            MidiMessage::PitchBend { channel, value } => {
+                let value = (value.clamp(-8192, 8191) + 8192) as u16;
+                (0xE0, channel, [(value & 0x7F) as u8, (value >> 7) as u8], 3)
+            }
+        };
+        MidiBytes {
+            bytes: [status | (channel & 0x0F), data[0] & 0x7F, data[1] & 0x7F],
+            len,
+        }
+    }
+}
+
+/// The bytes of a [`MidiMessage`], without allocating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiBytes {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl MidiBytes {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// A [`MidiMessage`] at an absolute sample position
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MidiEvent {
+    /// The number of samples since processing started
+    pub timestamp: u64,
+    pub message: MidiMessage,
+}
+
+/// Sends a note on for every trigger on its "trig" input and a note off
+/// "duration" seconds later. The "note" and "velocity" inputs are read at
+/// the time of the trigger. A note that is still playing when it is
+/// triggered again is ended first.
+pub struct MidiNoteOut {
+    events: rtrb::Producer<MidiEvent>,
+    channel: u8,
+    /// Notes waiting for their note off and the timestamp to send it at
+    playing: Vec<(u8, u64)>,
+    max_playing: usize,
+    sample_rate: Sample,
+}
+
+impl MidiNoteOut {
+    /// *Allocates memory*
+    /// Send notes on `channel` to `events`. At most `max_playing` notes can
+    /// be playing at the same time, triggers beyond that are ignored.
+    pub fn new(events: rtrb::Producer<MidiEvent>, channel: u8, max_playing: usize) -> Self {
+        Self {
+            events,
+            channel,
+            playing: Vec::with_capacity(max_playing),
+            max_playing,
+            sample_rate: 0.0,
+        }
+    }
+    /// Send the note off for `note` if it is playing
+    fn note_off(&mut self, note: u8, timestamp: u64) {
+        if let Some(i) = self.playing.iter().position(|&(n, _)| n == note) {
+            self.playing.swap_remove(i);
+            self.send(
+                timestamp,
+                MidiMessage::NoteOff {
+                    channel: self.channel,
+                    note,
+                    velocity: 0,
+                },
+            );
+        }
+    }
+    fn send(&mut self, timestamp: u64, message: MidiMessage) {
+        // Events are dropped if the receiver is full
+        self.events.push(MidiEvent { timestamp, message }).ok();
+    }
+}
+
+impl Gen for MidiNoteOut {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        _outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let block_start = resources.transport.sample;
+        for (i, &trig) in inputs[0].iter().enumerate() {
+            let now = block_start + i as u64;
+            while let Some(&(note, _)) = self.playing.iter().find(|(_, off)| *off <= now) {
+                self.note_off(note, now);
+            }
+            if is_trigger(trig) {
+                let note = inputs[1][i].round().clamp(0.0, 127.0) as u8;
+                self.note_off(note, now);
+                if self.playing.len() < self.max_playing {
+                    let velocity = inputs[2][i].round().clamp(1.0, 127.0) as u8;
+                    let duration = (inputs[3][i] * self.sample_rate).max(1.0) as u64;
+                    self.playing.push((note, now + duration));
+                    self.send(
+                        now,
+                        MidiMessage::NoteOn {
+                            channel: self.channel,
+                            note,
+                            velocity,
+                        },
+                    );
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        4
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "trig",
+            1 => "note",
+            2 => "velocity",
+            3 => "duration",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            1 => 60.0,
+            2 => 100.0,
+            3 => 0.25,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "MidiNoteOut"
+    }
+}
+
+/// Sends a control change every block in which its "value" input, from 0 to
+/// 1, has changed by at least one MIDI step.
+pub struct MidiControlOut {
+    events: rtrb::Producer<MidiEvent>,
+    channel: u8,
+    controller: u8,
+    last_value: Option<u8>,
+}
+
+impl MidiControlOut {
+    pub fn new(events: rtrb::Producer<MidiEvent>, channel: u8, controller: u8) -> Self {
+        Self {
+            events,
+            channel,
+            controller,
+            last_value: None,
+        }
+    }
+}
+
+impl Gen for MidiControlOut {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        _outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let value = (inputs[0][0].clamp(0.0, 1.0) * 127.0).round() as u8;
+        if self.last_value != Some(value) {
+            self.last_value = Some(value);
+            let message = MidiMessage::ControlChange {
+                channel: self.channel,
+                controller: self.controller,
+                value,
+            };
+            self.events
+                .push(MidiEvent {
+                    timestamp: resources.transport.sample,
+                    message,
+                })
+                .ok();
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "value",
+            _ => "",
+        }
+    }
+    fn input_rate(&self, _input: usize) -> Rate {
+        Rate::Control
+    }
+    fn name(&self) -> &'static str {
+        "MidiControlOut"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_write_messages() {
+        let messages = [
+            MidiMessage::NoteOff {
+                channel: 3,
+                note: 64,
+                velocity: 12,
+            },
+            MidiMessage::ControlChange {
+                channel: 15,
+                controller: 1,
+                value: 127,
+            },
+            MidiMessage::ProgramChange {
+                channel: 0,
+                program: 5,
+            },
+            MidiMessage::PitchBend {
+                channel: 1,
+                value: -8192,
+            },
+            MidiMessage::PitchBend {
+                channel: 1,
+                value: 8191,
+            },
+        ];
+        for message in messages {
+            assert_eq!(
+                MidiMessage::from_bytes(message.to_bytes().as_slice()),
+                Some(message)
+            );
+        }
+        assert_eq!(
+            MidiMessage::from_bytes(&[0x91, 60, 0]),
+            Some(MidiMessage::NoteOff {
+                channel: 1,
+                note: 60,
+                velocity: 0
+            })
+        );
+        assert_eq!(MidiMessage::from_bytes(&[0xF0, 1, 2, 0xF7]), None);
+        assert_eq!(MidiMessage::from_bytes(&[0x90, 60]), None);
+    }
+
+    #[test]
+    fn notes_from_triggers() {
+        let (events, mut receiver) = rtrb::RingBuffer::new(16);
+        let mut note_out = MidiNoteOut::new(events, 2, 4);
+        note_out.init(1000.0);
+        let mut resources = Resources::new(crate::ResourcesSettings::default());
+        resources.transport.sample = 100;
+        let block = |values: [Sample; 4]| -> Box<[Sample]> { values.into() };
+        let inputs = [
+            block([1.0, 0.0, 1.0, 0.0]),
+            block([60.0, 60.0, 64.0, 64.0]),
+            block([100.0; 4]),
+            block([0.002; 4]),
+        ];
+        note_out.process(&inputs, &mut [], &mut resources);
+        let events: Vec<MidiEvent> = std::iter::from_fn(|| receiver.pop().ok()).collect();
+        let on = |note| MidiMessage::NoteOn {
+            channel: 2,
+            note,
+            velocity: 100,
+        };
+        let off = |note| MidiMessage::NoteOff {
+            channel: 2,
+            note,
+            velocity: 0,
+        };
+        assert_eq!(
+            events,
+            [
+                MidiEvent {
+                    timestamp: 100,
+                    message: on(60)
+                },
+                MidiEvent {
+                    timestamp: 102,
+                    message: off(60)
+                },
+                MidiEvent {
+                    timestamp: 102,
+                    message: on(64)
+                },
+            ]
+        );
+        resources.transport.sample = 104;
+        let mut inputs = inputs;
+        inputs[0] = block([0.0; 4]);
+        note_out.process(&inputs, &mut [], &mut resources);
+        assert_eq!(
+            receiver.pop().unwrap(),
+            MidiEvent {
+                timestamp: 104,
+                message: off(64)
+            }
+        );
+    }
+}
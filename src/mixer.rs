@@ -0,0 +1,269 @@
+//! Mixing many signals into one bus.
+//!
+//! A [`Mixer`] has a number of slots, each with a signal input, a gain and,
+//! for a stereo Mixer, a pan position. The gain of a slot is 1 until
+//! something is connected to it so connecting a signal to a free slot is
+//! enough to hear it, which makes a
+//! Mixer a natural target for nodes that are added and freed while the Graph
+//! is running, e.g. voices.
+//!
+//! The number of slots is fixed when the node is created since nodes can't
+//! change their number of inputs. Unconnected slots are silent. A Mixer
+//! often has more inputs than the default
+//! [`GraphSettings::max_node_inputs`](crate::graph::GraphSettings::max_node_inputs),
+//! so remember to increase it.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::mixer::Mixer;
+//! let mut graph = Graph::new(GraphSettings {
+//!     num_outputs: 2,
+//!     max_node_inputs: 24,
+//!     ..Default::default()
+//! });
+//! let mixer = graph.push_gen(Mixer::new(8));
+//! graph.connect(Connection::graph_output(mixer).channels(2)).unwrap();
+//! let voice = graph.push_gen(knyst::noise::WhiteNoise::new());
+//! graph.connect(constant(0.1).to(voice)).unwrap();
+//! graph.connect(voice.to(mixer).to_label("in3")).unwrap();
+//! graph.connect(constant(-0.5).to(mixer).to_label("pan3")).unwrap();
+//! ```
+
+use std::sync::OnceLock;
+
+use crate::graph::{Gen, GenState};
+use crate::spatial::equal_power_gains;
+use crate::{Resources, Sample};
+
+/// Slots above this number have inputs without names, connect to them by
+/// index.
+pub const MAX_NAMED_SLOTS: usize = 128;
+
+/// The names of the inputs of every slot: signal, gain and pan
+fn slot_names(slot: usize) -> [&'static str; 3] {
+    static NAMES: OnceLock<Vec<[&'static str; 3]>> = OnceLock::new();
+    let names = NAMES.get_or_init(|| {
+        (0..MAX_NAMED_SLOTS)
+            .map(|slot| {
+                [
+                    &*Box::leak(format!("in{slot}").into_boxed_str()),
+                    &*Box::leak(format!("gain{slot}").into_boxed_str()),
+                    &*Box::leak(format!("pan{slot}").into_boxed_str()),
+                ]
+            })
+            .collect()
+    });
+    names.get(slot).copied().unwrap_or(["", "", ""])
+}
+
+/// Sums a number of signals with a gain per signal and, if stereo, a pan
+/// position between -1 (left) and 1 (right) per signal.
+///
+/// The inputs of slot `n` are "in{n}", "gain{n}" and, if stereo, "pan{n}".
+pub struct Mixer {
+    num_slots: usize,
+    stereo: bool,
+}
+
+impl Mixer {
+    /// A stereo Mixer with `num_slots` slots
+    pub fn new(num_slots: usize) -> Self {
+        Self {
+            num_slots,
+            stereo: true,
+        }
+    }
+    /// A mono Mixer with `num_slots` slots and no pan inputs
+    pub fn mono(num_slots: usize) -> Self {
+        Self {
+            num_slots,
+            stereo: false,
+        }
+    }
+    pub fn num_slots(&self) -> usize {
+        self.num_slots
+    }
+    fn inputs_per_slot(stereo: bool) -> usize {
+        if stereo {
+            3
+        } else {
+            2
+        }
+    }
+    /// The index of the signal input of `slot`
+    pub fn signal_index(&self, slot: usize) -> usize {
+        slot * Self::inputs_per_slot(self.stereo)
+    }
+    /// The index of the gain input of `slot`
+    pub fn gain_index(&self, slot: usize) -> usize {
+        slot * Self::inputs_per_slot(self.stereo) + 1
+    }
+    /// The index of the pan input of `slot`, or None for a mono Mixer
+    pub fn pan_index(&self, slot: usize) -> Option<usize> {
+        self.stereo
+            .then(|| slot * Self::inputs_per_slot(self.stereo) + 2)
+    }
+}
+
+impl Gen for Mixer {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for out in outputs.iter_mut() {
+            out.fill(0.0);
+        }
+        let per_slot = Self::inputs_per_slot(self.stereo);
+        for slot in inputs[..self.num_slots * per_slot].chunks(per_slot) {
+            if self.stereo {
+                let (lefts, rights) = outputs.split_at_mut(1);
+                for ((((&signal, &gain), &pan), left), right) in slot[0]
+                    .iter()
+                    .zip(slot[1].iter())
+                    .zip(slot[2].iter())
+                    .zip(lefts[0].iter_mut())
+                    .zip(rights[0].iter_mut())
+                {
+                    let (left_gain, right_gain) = equal_power_gains((pan + 1.0) * 0.5);
+                    *left += signal * gain * left_gain;
+                    *right += signal * gain * right_gain;
+                }
+            } else {
+                for ((&signal, &gain), out) in slot[0]
+                    .iter()
+                    .zip(slot[1].iter())
+                    .zip(outputs[0].iter_mut())
+                {
+                    *out += signal * gain;
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        self.num_slots * Self::inputs_per_slot(self.stereo)
+    }
+    fn num_outputs(&self) -> usize {
+        if self.stereo {
+            2
+        } else {
+            1
+        }
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        let per_slot = Self::inputs_per_slot(self.stereo);
+        slot_names(input / per_slot)[input % per_slot]
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match (self.stereo, output) {
+            (true, 0) => "left",
+            (true, 1) => "right",
+            (false, 0) => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        if input % Self::inputs_per_slot(self.stereo) == 1 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Mixer"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{constant, gen, Connection, Graph, GraphSettings};
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn mix_slots() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 1,
+            num_outputs: 3,
+            max_node_inputs: 9,
+            ..Default::default()
+        });
+        let mut graph_node = graph.to_node().unwrap();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let stereo_mixer = Mixer::new(3);
+        let pan2 = stereo_mixer.pan_index(2).unwrap();
+        let stereo = graph.push_gen(stereo_mixer);
+        graph
+            .connect(Connection::graph_output(stereo).channels(2))
+            .unwrap();
+        graph
+            .connect(constant(1.0).to(stereo).to_label("in0"))
+            .unwrap();
+        graph
+            .connect(constant(-1.0).to(stereo).to_label("pan0"))
+            .unwrap();
+        graph
+            .connect(constant(0.5).to(stereo).to_label("in2"))
+            .unwrap();
+        graph
+            .connect(constant(2.0).to(stereo).to_label("gain2"))
+            .unwrap();
+        graph
+            .connect(constant(1.0).to(stereo).to_index(pan2))
+            .unwrap();
+        let mono_mixer = Mixer::mono(2);
+        assert_eq!(mono_mixer.pan_index(0), None);
+        let signal1 = mono_mixer.signal_index(1);
+        let mono = graph.push_gen(mono_mixer);
+        graph
+            .connect(Connection::graph_output(mono).to_index(2))
+            .unwrap();
+        graph
+            .connect(constant(0.25).to(mono).to_index(signal1))
+            .unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&[], &mut resources);
+        let outputs = graph_node.output_buffers();
+        assert!((outputs[0][0] - 1.0).abs() < 1e-3);
+        assert!((outputs[1][0] - 1.0).abs() < 1e-3);
+        assert_eq!(outputs[2][0], 0.25);
+    }
+    #[test]
+    fn connected_gain_replaces_the_default() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 1,
+            num_outputs: 1,
+            ..Default::default()
+        });
+        let mut graph_node = graph.to_node().unwrap();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mixer = graph.push_gen(Mixer::mono(1));
+        graph.connect(Connection::graph_output(mixer)).unwrap();
+        graph
+            .connect(constant(2.0).to(mixer).to_label("in0"))
+            .unwrap();
+        let control = graph.push_gen(
+            gen(|_inputs, outputs, _resources| {
+                outputs[0].fill(0.25);
+                GenState::Continue
+            })
+            .output("out"),
+        );
+        graph.connect(control.to(mixer).to_label("gain0")).unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&[], &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 0.5);
+        // The default is used again when the control is disconnected
+        graph
+            .disconnect(control.to(mixer).to_label("gain0"))
+            .unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&[], &mut resources);
+        assert_eq!(graph_node.output_buffers()[0][0], 2.0);
+    }
+}
@@ -0,0 +1,300 @@
+//! Modal synthesis with a bank of resonators.
+//!
+//! A [`ResonatorBank`] is a set of parallel two pole resonators, one per
+//! [`Partial`], all excited by the same input. An impulse gives a struck
+//! sound like a bell or a bar, noise gives a bowed or blown sound, and any
+//! other signal takes on the resonances of the body described by the
+//! partials.
+//!
+//! The partials are given as a [`ModalPreset`], either when the bank is
+//! created or through user data in the [`Resources`] so that they can be
+//! replaced while the bank is running.
+
+use crate::denormal::flush_denormal;
+use crate::graph::{Gen, GenState, Rate};
+use crate::{AnyData, Resources, Sample, UserDataKey};
+
+/// The largest number of partials in a [`ResonatorBank`]
+pub const MAX_PARTIALS: usize = 64;
+/// ln(1000), the decay to -60 dB
+const LN_1000: Sample = 6.907_755;
+
+/// One mode of vibration of a resonating body
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Partial {
+    /// The frequency relative to the "freq" input of the bank
+    pub ratio: Sample,
+    /// The amplitude of the response to an impulse of 1
+    pub amplitude: Sample,
+    /// The time in seconds for the partial to decay by 60 dB
+    pub decay: Sample,
+}
+
+impl Partial {
+    pub fn new(ratio: Sample, amplitude: Sample, decay: Sample) -> Self {
+        Self {
+            ratio,
+            amplitude,
+            decay,
+        }
+    }
+}
+
+/// The partials of a [`ResonatorBank`]. Can be stored as user data to
+/// change the partials of a running bank.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ModalPreset {
+    pub partials: Vec<Partial>,
+}
+
+impl AnyData for ModalPreset {}
+
+impl ModalPreset {
+    pub fn new(partials: Vec<Partial>) -> Self {
+        Self { partials }
+    }
+    /// A church bell, with the hum tone an octave below the fundamental
+    pub fn bell() -> Self {
+        Self::new(vec![
+            Partial::new(0.5, 0.4, 6.0),
+            Partial::new(1.0, 0.5, 4.0),
+            Partial::new(1.183, 0.35, 3.0),
+            Partial::new(1.506, 0.25, 2.5),
+            Partial::new(2.0, 0.3, 2.0),
+            Partial::new(2.514, 0.15, 1.5),
+            Partial::new(2.662, 0.12, 1.2),
+            Partial::new(3.011, 0.1, 1.0),
+        ])
+    }
+    /// A tuned bar like that of a marimba
+    pub fn marimba() -> Self {
+        Self::new(vec![
+            Partial::new(1.0, 0.6, 1.2),
+            Partial::new(3.99, 0.25, 0.4),
+            Partial::new(10.65, 0.1, 0.1),
+        ])
+    }
+    /// A free rectangular plate
+    pub fn plate() -> Self {
+        Self::new(vec![
+            Partial::new(1.0, 0.4, 3.0),
+            Partial::new(1.47, 0.3, 2.6),
+            Partial::new(1.84, 0.3, 2.3),
+            Partial::new(2.42, 0.25, 2.0),
+            Partial::new(2.92, 0.2, 1.7),
+            Partial::new(3.53, 0.18, 1.4),
+            Partial::new(4.21, 0.15, 1.2),
+            Partial::new(5.03, 0.1, 1.0),
+        ])
+    }
+}
+
+/// A two pole resonator
+#[derive(Debug, Clone, Copy, Default)]
+struct Resonator {
+    b0: Sample,
+    a1: Sample,
+    a2: Sample,
+    y1: Sample,
+    y2: Sample,
+}
+
+impl Resonator {
+    fn set(&mut self, freq: Sample, amplitude: Sample, decay: Sample, sample_rate: Sample) {
+        if freq <= 0.0 || freq >= sample_rate * 0.5 || decay <= 0.0 {
+            self.b0 = 0.0;
+            self.a1 = 0.0;
+            self.a2 = 0.0;
+            return;
+        }
+        let w = std::f64::consts::TAU as Sample * freq / sample_rate;
+        let r = (-LN_1000 / (decay * sample_rate)).exp();
+        // Scaled so that the response to an impulse of 1 starts at `amplitude`
+        self.b0 = amplitude * w.sin();
+        self.a1 = -2.0 * r * w.cos();
+        self.a2 = r * r;
+    }
+    #[inline]
+    fn process(&mut self, input: Sample) -> Sample {
+        let y = self.b0 * input - self.a1 * self.y1 - self.a2 * self.y2;
+        self.y2 = self.y1;
+        self.y1 = flush_denormal(y);
+        y
+    }
+}
+
+/// A bank of parallel resonators, see the [module documentation](self).
+///
+/// Inputs are "signal", the excitation, "freq", the frequency in Hz that
+/// the ratios of the partials are relative to, and "decay", a factor for the
+/// decay times of all partials. "freq" and "decay" are control rate inputs.
+#[derive(Debug, Clone)]
+pub struct ResonatorBank {
+    partials: Vec<Partial>,
+    preset_key: Option<UserDataKey<ModalPreset>>,
+    resonators: Vec<Resonator>,
+    sample_rate: Sample,
+}
+
+impl ResonatorBank {
+    /// A bank with the partials of `preset`. Partials after the first
+    /// [`MAX_PARTIALS`] are ignored.
+    pub fn new(preset: &ModalPreset) -> Self {
+        let mut partials = Vec::with_capacity(MAX_PARTIALS);
+        partials.extend(preset.partials.iter().take(MAX_PARTIALS));
+        Self {
+            partials,
+            preset_key: None,
+            resonators: vec![Resonator::default(); MAX_PARTIALS],
+            sample_rate: 44100.0,
+        }
+    }
+    /// A bank reading its partials from a [`ModalPreset`] in the user data
+    /// every block. The bank is silent while there is no data for the key.
+    pub fn from_user_data(key: UserDataKey<ModalPreset>) -> Self {
+        Self {
+            preset_key: Some(key),
+            ..Self::new(&ModalPreset::default())
+        }
+    }
+    pub fn partials(&self) -> &[Partial] {
+        &self.partials
+    }
+}
+
+impl Gen for ResonatorBank {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        if let Some(key) = self.preset_key {
+            self.partials.clear();
+            if let Some(preset) = resources.user_data(key) {
+                self.partials
+                    .extend(preset.partials.iter().take(MAX_PARTIALS));
+            }
+        }
+        let freq = inputs[1][0];
+        let decay = inputs[2][0];
+        let resonators = &mut self.resonators[..self.partials.len()];
+        for (resonator, partial) in resonators.iter_mut().zip(&self.partials) {
+            resonator.set(
+                freq * partial.ratio,
+                partial.amplitude,
+                partial.decay * decay,
+                self.sample_rate,
+            );
+        }
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let input = inputs[0][i];
+            *out = resonators
+                .iter_mut()
+                .map(|resonator| resonator.process(input))
+                .sum();
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.resonators.fill(Resonator::default());
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "freq",
+            2 => "decay",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            1 => 220.0,
+            2 => 1.0,
+            _ => 0.0,
+        }
+    }
+    fn input_rate(&self, input: usize) -> Rate {
+        match input {
+            1 | 2 => Rate::Control,
+            _ => Rate::Audio,
+        }
+    }
+    fn uses_shared_resources(&self) -> bool {
+        self.preset_key.is_some()
+    }
+    fn name(&self) -> &'static str {
+        "ResonatorBank"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn strike(bank: &mut ResonatorBank, resources: &mut Resources, frames: usize) -> Vec<Sample> {
+        let mut signal = vec![0.0; frames];
+        signal[0] = 1.0;
+        let inputs = vec![
+            signal.into_boxed_slice(),
+            vec![1000.0; frames].into_boxed_slice(),
+            vec![1.0; frames].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; frames].into_boxed_slice()];
+        bank.process(&inputs, &mut outputs, resources);
+        outputs[0].to_vec()
+    }
+
+    #[test]
+    fn partial_rings_and_decays() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let preset = ModalPreset::new(vec![Partial::new(2.0, 0.5, 0.5)]);
+        let mut bank = ResonatorBank::new(&preset);
+        bank.init(48000.0);
+        let out = strike(&mut bank, &mut resources, 48000);
+        let peak = |range: std::ops::Range<usize>| {
+            out[range]
+                .iter()
+                .fold(0.0 as Sample, |acc, v| acc.max(v.abs()))
+        };
+        assert!((peak(0..100) - 0.5).abs() < 0.01);
+        // -60 dB after the decay time
+        let decayed = peak(24000..24100) / 0.5;
+        assert!((decayed - 0.001).abs() < 0.0002, "{decayed}");
+        // 2000 Hz gives 48 zero crossings in 12 ms
+        let crossings = out[..576]
+            .windows(2)
+            .filter(|w| w[0].signum() != w[1].signum())
+            .count();
+        assert!((47..=49).contains(&crossings), "{crossings}");
+    }
+
+    #[test]
+    fn partials_from_user_data() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let key = resources.insert_user_data(ModalPreset::bell()).unwrap();
+        let mut bank = ResonatorBank::from_user_data(key);
+        bank.init(48000.0);
+        let out = strike(&mut bank, &mut resources, 64);
+        assert!(out.iter().any(|v| v.abs() > 0.1));
+        assert_eq!(bank.partials().len(), 8);
+        resources.remove_user_data(key);
+        bank.init(48000.0);
+        let out = strike(&mut bank, &mut resources, 64);
+        assert!(out.iter().all(|v| *v == 0.0));
+    }
+}
@@ -0,0 +1,655 @@
+//! Modulation effects: chorus, flanger, phaser, ring modulation and
+//! frequency shifting.
+//!
+//! [`ModulatedDelay`] reads an [`InterpolatedDelay`] at a delay time swept
+//! by a sine LFO. With a longer delay it is a chorus and with a shorter delay
+//! and some feedback it is a flanger. [`Phaser`] sweeps the frequency of a
+//! chain of all-pass filters instead. These have the inputs "signal", "rate"
+//! (the LFO frequency in Hz), "depth" (0 to 1), "feedback" (-1 to 1) and
+//! "mix" (0 is only the dry signal, 1 only the wet signal).
+//!
+//! [`RingMod`] multiplies the signal with a sine, giving the sum and
+//! difference of every frequency in the signal and the frequency of the sine.
+//! [`FrequencyShifter`] moves every frequency in the signal by the same
+//! amount, which makes harmonic sounds inharmonic.
+
+use crate::denormal::flush_denormal;
+use crate::graph::{Gen, GenState};
+use crate::patch::{PatchError, SavedState};
+use crate::wavetable::hermite_interpolate;
+use crate::{Resources, Sample};
+
+const TAU: Sample = std::f64::consts::TAU as Sample;
+const PI: Sample = std::f64::consts::PI as Sample;
+/// The feedback is clamped to this to keep the effects stable
+const MAX_FEEDBACK: Sample = 0.99;
+const INPUT_NAMES: [&str; 5] = ["signal", "rate", "depth", "feedback", "mix"];
+
+/// A delay line that can be read at fractional delay times using 4 point
+/// Hermite interpolation.
+#[derive(Debug, Clone)]
+pub struct InterpolatedDelay {
+    buffer: Vec<Sample>,
+    /// The position of the last written sample
+    write_pos: usize,
+}
+
+impl InterpolatedDelay {
+    /// A delay line for delay times up to `max_delay` samples.
+    pub fn new(max_delay: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max_delay + 4],
+            write_pos: 0,
+        }
+    }
+    /// The longest delay in samples that can be read
+    pub fn max_delay(&self) -> Sample {
+        (self.buffer.len() - 3) as Sample
+    }
+    /// Write the next sample into the delay line
+    #[inline]
+    pub fn write(&mut self, value: Sample) {
+        self.write_pos += 1;
+        if self.write_pos >= self.buffer.len() {
+            self.write_pos = 0;
+        }
+        self.buffer[self.write_pos] = value;
+    }
+    /// Read the value `delay` samples before the last written sample. The
+    /// delay is clamped to between 1 and [`InterpolatedDelay::max_delay`].
+    #[inline]
+    pub fn read(&self, delay: Sample) -> Sample {
+        let delay = delay.clamp(1.0, self.max_delay());
+        let whole = (delay as usize).max(1);
+        let fraction = delay - whole as Sample;
+        let len = self.buffer.len();
+        let at = |d: usize| self.buffer[(self.write_pos + len - d) % len];
+        hermite_interpolate(
+            at(whole - 1),
+            at(whole),
+            at(whole + 1),
+            at(whole + 2),
+            fraction,
+        )
+    }
+    /// Fill the delay line with silence
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+    /// *Allocates memory*
+    /// The contents of the delay line, oldest sample first
+    pub fn contents(&self) -> Vec<Sample> {
+        let oldest = self.write_pos + 1;
+        self.buffer[oldest..]
+            .iter()
+            .chain(&self.buffer[..oldest])
+            .copied()
+            .collect()
+    }
+    /// Replace the contents of the delay line, oldest sample first. If
+    /// `contents` is longer than the delay line the oldest samples are
+    /// dropped.
+    pub fn set_contents(&mut self, contents: &[Sample]) {
+        self.clear();
+        let skip = contents.len().saturating_sub(self.buffer.len());
+        for &value in &contents[skip..] {
+            self.write(value);
+        }
+    }
+}
+
+/// A sine LFO, 0 to 1
+#[derive(Debug, Clone, Copy, Default)]
+struct Lfo {
+    phase: Sample,
+}
+
+impl Lfo {
+    #[inline]
+    fn advance(&mut self, freq: Sample, sample_rate: Sample) -> Sample {
+        let value = 0.5 - 0.5 * (self.phase * TAU).cos();
+        self.phase += freq / sample_rate;
+        self.phase -= self.phase.floor();
+        value
+    }
+}
+
+/// A chorus or flanger, see the [module documentation](self).
+///
+/// The delay time is swept between the base delay and the base delay plus
+/// the sweep width times "depth".
+#[derive(Debug, Clone)]
+pub struct ModulatedDelay {
+    /// The shortest delay in seconds
+    base_delay: Sample,
+    /// The widest sweep in seconds
+    sweep: Sample,
+    name: &'static str,
+    /// Allocated in `init`
+    delay: InterpolatedDelay,
+    lfo: Lfo,
+    last_wet: Sample,
+    sample_rate: Sample,
+}
+
+impl ModulatedDelay {
+    /// A modulated delay swept from `base_delay` to `base_delay + sweep`
+    /// seconds at full depth.
+    pub fn new(base_delay: Sample, sweep: Sample) -> Self {
+        Self {
+            base_delay: base_delay.max(0.0),
+            sweep: sweep.max(0.0),
+            name: "ModulatedDelay",
+            delay: InterpolatedDelay::new(0),
+            lfo: Lfo::default(),
+            last_wet: 0.0,
+            sample_rate: 44100.0,
+        }
+    }
+    /// A chorus sweeping between 10 and 30 ms
+    pub fn chorus() -> Self {
+        Self {
+            name: "Chorus",
+            ..Self::new(0.01, 0.02)
+        }
+    }
+    /// A flanger sweeping between 0.5 and 5.5 ms
+    pub fn flanger() -> Self {
+        Self {
+            name: "Flanger",
+            ..Self::new(0.0005, 0.005)
+        }
+    }
+}
+
+impl Gen for ModulatedDelay {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let dry = inputs[0][i];
+            let depth = inputs[2][i].clamp(0.0, 1.0);
+            let feedback = inputs[3][i].clamp(-MAX_FEEDBACK, MAX_FEEDBACK);
+            let mix = inputs[4][i];
+            let lfo = self.lfo.advance(inputs[1][i], self.sample_rate);
+            let delay_time = (self.base_delay + self.sweep * depth * lfo) * self.sample_rate;
+            self.delay.write(dry + self.last_wet * feedback);
+            let wet = self.delay.read(delay_time);
+            self.last_wet = flush_denormal(wet);
+            *out = dry * (1.0 - mix) + wet * mix;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        let max_delay = ((self.base_delay + self.sweep) * sample_rate).ceil() as usize;
+        self.delay = InterpolatedDelay::new(max_delay + 1);
+        self.lfo = Lfo::default();
+        self.last_wet = 0.0;
+    }
+    fn num_inputs(&self) -> usize {
+        5
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        INPUT_NAMES.get(input).copied().unwrap_or("")
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            1 | 2 | 4 => 0.5,
+            _ => 0.0,
+        }
+    }
+    /// The contents of the delay line
+    fn save_state(&self) -> Option<SavedState> {
+        Some(SavedState::Samples(self.delay.contents()))
+    }
+    fn load_state(&mut self, state: &SavedState) -> Result<(), PatchError> {
+        let contents = state
+            .as_samples()
+            .ok_or(PatchError::InvalidState(self.name))?;
+        self.delay.set_contents(contents);
+        Ok(())
+    }
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// A first order all-pass filter
+#[derive(Debug, Clone, Copy, Default)]
+struct AllPass {
+    state: Sample,
+}
+
+impl AllPass {
+    #[inline]
+    fn process(&mut self, input: Sample, coefficient: Sample) -> Sample {
+        let output = coefficient * input + self.state;
+        self.state = input - coefficient * output;
+        output
+    }
+}
+
+/// A phaser made from a chain of first order all-pass filters, see the
+/// [module documentation](self).
+///
+/// The break frequency of the all-pass filters is swept exponentially
+/// upwards from the lowest frequency by up to 6 octaves times "depth".
+#[derive(Debug, Clone)]
+pub struct Phaser {
+    stages: Vec<AllPass>,
+    min_freq: Sample,
+    lfo: Lfo,
+    last_wet: Sample,
+    sample_rate: Sample,
+}
+
+impl Phaser {
+    /// A phaser with `stages` all-pass filters. Every two stages add a notch.
+    pub fn new(stages: usize) -> Self {
+        Self {
+            stages: vec![AllPass::default(); stages.max(1)],
+            min_freq: 200.0,
+            lfo: Lfo::default(),
+            last_wet: 0.0,
+            sample_rate: 44100.0,
+        }
+    }
+    /// Set the lowest frequency of the sweep. Default: 200 Hz
+    pub fn min_freq(mut self, freq: Sample) -> Self {
+        self.min_freq = freq.max(1.0);
+        self
+    }
+}
+
+impl Default for Phaser {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl Gen for Phaser {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let nyquist_limit = self.sample_rate * 0.49;
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let dry = inputs[0][i];
+            let depth = inputs[2][i].clamp(0.0, 1.0);
+            let feedback = inputs[3][i].clamp(-MAX_FEEDBACK, MAX_FEEDBACK);
+            let mix = inputs[4][i];
+            let lfo = self.lfo.advance(inputs[1][i], self.sample_rate);
+            let freq = (self.min_freq * (depth * lfo * 6.0).exp2()).min(nyquist_limit);
+            let t = (PI * freq / self.sample_rate).tan();
+            let coefficient = (t - 1.0) / (t + 1.0);
+            let mut wet = dry + self.last_wet * feedback;
+            for stage in &mut self.stages {
+                wet = stage.process(wet, coefficient);
+            }
+            self.last_wet = flush_denormal(wet);
+            *out = dry * (1.0 - mix) + wet * mix;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.stages.fill(AllPass::default());
+        self.lfo = Lfo::default();
+        self.last_wet = 0.0;
+    }
+    fn num_inputs(&self) -> usize {
+        5
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        INPUT_NAMES.get(input).copied().unwrap_or("")
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            1 | 2 | 4 => 0.5,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Phaser"
+    }
+}
+
+/// Multiplies the signal with a sine, see the [module documentation](self).
+///
+/// Inputs are "signal", "freq" of the sine in Hz and "mix".
+#[derive(Debug, Clone)]
+pub struct RingMod {
+    phase: Sample,
+    sample_rate: Sample,
+}
+
+impl RingMod {
+    pub fn new() -> Self {
+        Self {
+            phase: 0.0,
+            sample_rate: 44100.0,
+        }
+    }
+}
+
+impl Default for RingMod {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for RingMod {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let dry = inputs[0][i];
+            let mix = inputs[2][i];
+            let wet = dry * (self.phase * TAU).sin();
+            self.phase += inputs[1][i] / self.sample_rate;
+            self.phase -= self.phase.floor();
+            *out = dry * (1.0 - mix) + wet * mix;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.phase = 0.0;
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "freq",
+            2 => "mix",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            1 => 440.0,
+            2 => 1.0,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "RingMod"
+    }
+}
+
+/// Coefficients of the two all-pass chains of the Hilbert transformer by
+/// Olli Niemitalo, giving a 90 degree phase difference within a degree
+/// between 0.2% and 99.8% of the Nyquist frequency.
+const HILBERT_COEFFICIENTS: [[Sample; 4]; 2] = [
+    [0.692_387_9, 0.936_065_4, 0.988_229_5, 0.998_748_8],
+    [0.402_192_1, 0.856_171_1, 0.972_290_9, 0.995_288_5],
+];
+
+/// An all-pass section in z^-2: y\[n\] = a^2 (x\[n\] + y\[n-2\]) - x\[n-2\]
+#[derive(Debug, Clone, Copy, Default)]
+struct HilbertAllPass {
+    x: [Sample; 2],
+    y: [Sample; 2],
+}
+
+impl HilbertAllPass {
+    #[inline]
+    fn process(&mut self, input: Sample, a2: Sample) -> Sample {
+        let output = a2 * (input + self.y[1]) - self.x[1];
+        self.x = [input, self.x[0]];
+        self.y = [flush_denormal(output), self.y[0]];
+        output
+    }
+}
+
+/// A single sideband frequency shifter, see the
+/// [module documentation](self).
+///
+/// Inputs are "signal" and "shift" in Hz, which moves the frequencies up
+/// when positive and down when negative. The signal is split into two
+/// signals 90 degrees apart by a Hilbert transformer made from all-pass
+/// filters, which are then modulated by a quadrature oscillator.
+#[derive(Debug, Clone)]
+pub struct FrequencyShifter {
+    chains: [[HilbertAllPass; 4]; 2],
+    /// The extra sample of delay of the first chain
+    delayed: Sample,
+    squared_coefficients: [[Sample; 4]; 2],
+    phase: Sample,
+    sample_rate: Sample,
+}
+
+impl FrequencyShifter {
+    pub fn new() -> Self {
+        let mut squared_coefficients = HILBERT_COEFFICIENTS;
+        for a in squared_coefficients.iter_mut().flatten() {
+            *a *= *a;
+        }
+        Self {
+            chains: [[HilbertAllPass::default(); 4]; 2],
+            delayed: 0.0,
+            squared_coefficients,
+            phase: 0.0,
+            sample_rate: 44100.0,
+        }
+    }
+}
+
+impl Default for FrequencyShifter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for FrequencyShifter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let input = inputs[0][i];
+            let mut paths = [input; 2];
+            for ((path, chain), coefficients) in paths
+                .iter_mut()
+                .zip(self.chains.iter_mut())
+                .zip(self.squared_coefficients.iter())
+            {
+                for (section, a2) in chain.iter_mut().zip(coefficients) {
+                    *path = section.process(*path, *a2);
+                }
+            }
+            let in_phase = self.delayed;
+            self.delayed = paths[0];
+            let quadrature = paths[1];
+            let (sin, cos) = (self.phase * TAU).sin_cos();
+            *out = in_phase * cos + quadrature * sin;
+            self.phase += inputs[1][i] / self.sample_rate;
+            self.phase -= self.phase.floor();
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.chains = [[HilbertAllPass::default(); 4]; 2];
+        self.delayed = 0.0;
+        self.phase = 0.0;
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "shift",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "FrequencyShifter"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn interpolated_delay_reads_between_samples() {
+        let mut delay = InterpolatedDelay::new(16);
+        for i in 0..10 {
+            delay.write(i as Sample);
+        }
+        assert_eq!(delay.read(1.0), 8.0);
+        assert_eq!(delay.read(3.0), 6.0);
+        // A ramp is interpolated exactly
+        assert!((delay.read(2.5) - 6.5).abs() < 1e-5);
+        assert_eq!(delay.read(100.0), delay.read(delay.max_delay()));
+        let mut restored = InterpolatedDelay::new(16);
+        restored.set_contents(&delay.contents());
+        assert_eq!(restored.contents(), delay.contents());
+        assert_eq!(restored.read(3.0), 6.0);
+    }
+
+    #[test]
+    fn full_wet_flanger_delays_the_signal() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut flanger = ModulatedDelay::new(0.001, 0.0);
+        flanger.init(1000.0);
+        let mut signal = vec![0.0; 8];
+        signal[0] = 1.0;
+        let inputs = vec![
+            signal.into_boxed_slice(),
+            vec![1.0; 8].into_boxed_slice(),
+            vec![1.0; 8].into_boxed_slice(),
+            vec![0.0; 8].into_boxed_slice(),
+            vec![1.0; 8].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; 8].into_boxed_slice()];
+        flanger.process(&inputs, &mut outputs, &mut resources);
+        let expected = [0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        for (out, expected) in outputs[0].iter().zip(expected) {
+            assert!((out - expected).abs() < 1e-4, "{out}");
+        }
+    }
+
+    #[test]
+    fn phaser_is_all_pass_when_fully_wet() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut phaser = Phaser::default();
+        phaser.init(48000.0);
+        let block = 4800;
+        let sine: Vec<Sample> = (0..block)
+            .map(|i| (TAU * 1000.0 * i as Sample / 48000.0).sin())
+            .collect();
+        let inputs = vec![
+            sine.into_boxed_slice(),
+            vec![0.0; block].into_boxed_slice(),
+            vec![0.5; block].into_boxed_slice(),
+            vec![0.0; block].into_boxed_slice(),
+            vec![1.0; block].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; block].into_boxed_slice()];
+        phaser.process(&inputs, &mut outputs, &mut resources);
+        let peak = outputs[0][block / 2..]
+            .iter()
+            .fold(0.0 as Sample, |acc, v| acc.max(v.abs()));
+        assert!((peak - 1.0).abs() < 0.01, "{peak}");
+    }
+
+    /// The amplitude of the `freq` component of `signal`
+    fn magnitude_at(signal: &[Sample], freq: Sample, sample_rate: Sample) -> Sample {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, value) in signal.iter().enumerate() {
+            let phase = TAU * freq * i as Sample / sample_rate;
+            re += value * phase.cos();
+            im += value * phase.sin();
+        }
+        2.0 * (re * re + im * im).sqrt() / signal.len() as Sample
+    }
+
+    #[test]
+    fn ring_mod_and_frequency_shifter_spectra() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let block = 48000;
+        let sine: Vec<Sample> = (0..block)
+            .map(|i| (TAU * 1000.0 * i as Sample / 48000.0).sin())
+            .collect();
+        let mut ring_mod = RingMod::new();
+        ring_mod.init(48000.0);
+        let inputs = vec![
+            sine.clone().into_boxed_slice(),
+            vec![200.0; block].into_boxed_slice(),
+            vec![1.0; block].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; block].into_boxed_slice()];
+        ring_mod.process(&inputs, &mut outputs, &mut resources);
+        assert!((magnitude_at(&outputs[0], 1200.0, 48000.0) - 0.5).abs() < 0.01);
+        assert!((magnitude_at(&outputs[0], 800.0, 48000.0) - 0.5).abs() < 0.01);
+        assert!(magnitude_at(&outputs[0], 1000.0, 48000.0) < 0.01);
+
+        for (shift, kept, removed) in [(200.0, 1200.0, 800.0), (-200.0, 800.0, 1200.0)] {
+            let mut shifter = FrequencyShifter::new();
+            shifter.init(48000.0);
+            let inputs = vec![
+                sine.clone().into_boxed_slice(),
+                vec![shift; block].into_boxed_slice(),
+            ];
+            let mut outputs = vec![vec![0.0; block].into_boxed_slice()];
+            shifter.process(&inputs, &mut outputs, &mut resources);
+            let kept = magnitude_at(&outputs[0], kept, 48000.0);
+            let removed = magnitude_at(&outputs[0], removed, 48000.0);
+            assert!((kept - 1.0).abs() < 0.02, "{kept}");
+            assert!(removed < 0.02, "{removed}");
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Running several independent top level Graphs on one backend.
+//!
+//! An [`AudioBackend`](crate::audio_backend::AudioBackend) runs a single
+//! [`Graph`]. A [`MultiGraph`] lets several Graphs, e.g. one per scene of a
+//! performance, share one backend and one [`Resources`]:
+//!
+//! - Every Graph added with [`MultiGraph::add_graph`] runs as its own top
+//!   level Graph with its own scheduler and transport. It receives the inputs
+//!   of the main Graph and its outputs are mixed into the outputs of the main
+//!   Graph.
+//! - Graphs can be started and stopped, and their volume changed, with
+//!   [`MultiGraph::start`], [`MultiGraph::stop`] and
+//!   [`MultiGraph::set_volume`]. A stopped Graph isn't processed at all, so
+//!   its time stands still until it is started again.
+//!
+//! Pass [`MultiGraph::main_graph_mut`] to the backend to start processing.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::multi_graph::MultiGraph;
+//! let settings = GraphSettings {
+//!     num_outputs: 2,
+//!     ..Default::default()
+//! };
+//! let mut multi_graph = MultiGraph::new(settings);
+//! let intro = multi_graph.add_graph(Graph::new(settings)).unwrap();
+//! let verse = multi_graph.add_graph(Graph::new(settings)).unwrap();
+//! multi_graph.stop(verse).unwrap();
+//! // backend.start_processing(multi_graph.main_graph_mut(), resources)
+//! multi_graph.commit_changes();
+//! // Later
+//! multi_graph.stop(intro).unwrap();
+//! multi_graph.start(verse).unwrap();
+//! multi_graph.set_volume(verse, 0.5).unwrap();
+//! ```
+
+use slotmap::{new_key_type, SlotMap};
+
+use crate::graph::{
+    constant, Connection, ConnectionError, FreeError, Gen, GenState, Graph, GraphSettings, Node,
+    NodeAddress, Rate, ToNodeError,
+};
+use crate::{Resources, Sample};
+
+const INPUT_NAMES: [&str; 8] = ["in0", "in1", "in2", "in3", "in4", "in5", "in6", "in7"];
+const OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
+
+new_key_type! {
+    /// Identifies a Graph in a [`MultiGraph`]
+    pub struct GraphKey;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MultiGraphError {
+    #[error("The Graph was not found in the MultiGraph. It may have been removed already.")]
+    GraphNotFound,
+    #[error("The Graph has a block size of {graph}, the main Graph has a block size of {main}. They need to be the same.")]
+    BlockSizeMismatch { graph: usize, main: usize },
+    #[error("The Graph has a sample rate of {graph}, the main Graph has a sample rate of {main}. They need to be the same.")]
+    SampleRateMismatch { graph: Sample, main: Sample },
+    #[error("Unable to create a node from the Graph: {0}")]
+    CouldNotCreateNode(ToNodeError),
+    #[error(transparent)]
+    ConnectionError(#[from] ConnectionError),
+    #[error(transparent)]
+    FreeError(#[from] FreeError),
+}
+
+/// Several top level Graphs sharing one backend, see the
+/// [module documentation](self)
+pub struct MultiGraph {
+    main: Graph,
+    graphs: SlotMap<GraphKey, (NodeAddress, Graph)>,
+}
+
+impl MultiGraph {
+    /// *Allocates memory*
+    /// Create the main Graph, which should have the same number of inputs
+    /// and outputs as the backend.
+    pub fn new(settings: GraphSettings) -> Self {
+        Self {
+            main: Graph::new(settings),
+            graphs: SlotMap::with_key(),
+        }
+    }
+    /// The Graph to start processing in the backend
+    pub fn main_graph_mut(&mut self) -> &mut Graph {
+        &mut self.main
+    }
+    /// *Allocates memory*
+    /// Add a Graph, which starts playing at full volume. It needs the same
+    /// block size and sample rate as the main Graph.
+    pub fn add_graph(&mut self, mut graph: Graph) -> Result<GraphKey, MultiGraphError> {
+        if graph.block_size() != self.main.block_size() {
+            return Err(MultiGraphError::BlockSizeMismatch {
+                graph: graph.block_size(),
+                main: self.main.block_size(),
+            });
+        }
+        if graph.sample_rate() != self.main.sample_rate() {
+            return Err(MultiGraphError::SampleRateMismatch {
+                graph: graph.sample_rate(),
+                main: self.main.sample_rate(),
+            });
+        }
+        let node = graph
+            .to_node()
+            .map_err(MultiGraphError::CouldNotCreateNode)?;
+        let num_inputs = node.num_inputs();
+        let num_outputs = node.num_outputs();
+        let player = self.main.push_gen(GraphPlayer { node, gain: 0.0 });
+        let channels = num_inputs.min(self.main.num_inputs());
+        if channels > 0 {
+            self.main.connect(
+                Connection::graph_input(player)
+                    .to_index(PLAYER_CONTROLS)
+                    .channels(channels),
+            )?;
+        }
+        let channels = num_outputs.min(self.main.num_outputs());
+        if channels > 0 {
+            self.main
+                .connect(Connection::graph_output(player).channels(channels))?;
+        }
+        Ok(self.graphs.insert((player, graph)))
+    }
+    /// Remove a Graph, which stops immediately. Returns the Graph, which can
+    /// no longer be processed.
+    pub fn remove_graph(&mut self, key: GraphKey) -> Result<Graph, MultiGraphError> {
+        let (player, graph) = self
+            .graphs
+            .remove(key)
+            .ok_or(MultiGraphError::GraphNotFound)?;
+        self.main.free_node(player)?;
+        Ok(graph)
+    }
+    pub fn graph(&self, key: GraphKey) -> Option<&Graph> {
+        self.graphs.get(key).map(|(_, graph)| graph)
+    }
+    /// Change a Graph, e.g. to add nodes to it. Changes are applied by
+    /// [`MultiGraph::commit_changes`].
+    pub fn graph_mut(&mut self, key: GraphKey) -> Option<&mut Graph> {
+        self.graphs.get_mut(key).map(|(_, graph)| graph)
+    }
+    /// Start processing a stopped Graph, fading in over one block
+    pub fn start(&mut self, key: GraphKey) -> Result<(), MultiGraphError> {
+        self.set_control(key, "playing", 1.0)
+    }
+    /// Fade out a Graph over one block and stop processing it
+    pub fn stop(&mut self, key: GraphKey) -> Result<(), MultiGraphError> {
+        self.set_control(key, "playing", 0.0)
+    }
+    /// Change the volume of a Graph, ramped over one block. Default: 1
+    pub fn set_volume(&mut self, key: GraphKey, volume: Sample) -> Result<(), MultiGraphError> {
+        self.set_control(key, "volume", volume)
+    }
+    fn set_control(
+        &mut self,
+        key: GraphKey,
+        label: &'static str,
+        value: Sample,
+    ) -> Result<(), MultiGraphError> {
+        let (player, _) = self.graphs.get(key).ok_or(MultiGraphError::GraphNotFound)?;
+        self.main
+            .connect(constant(value).to(*player).to_label(label))?;
+        Ok(())
+    }
+    /// Apply the changes made to the main Graph and all other Graphs, see
+    /// [`Graph::commit_changes`]
+    pub fn commit_changes(&mut self) {
+        self.main.commit_changes();
+        for (_, graph) in self.graphs.values_mut() {
+            graph.commit_changes();
+        }
+    }
+    /// Update the main Graph and all other Graphs, see [`Graph::update`]
+    pub fn update(&mut self) {
+        self.main.update();
+        for (_, graph) in self.graphs.values_mut() {
+            graph.update();
+        }
+    }
+}
+
+/// The number of inputs of a [`GraphPlayer`] before the inputs of its Graph
+const PLAYER_CONTROLS: usize = 2;
+
+/// Processes the node of a top level Graph inside the main Graph
+struct GraphPlayer {
+    node: Node,
+    /// The gain applied at the end of the last block
+    gain: Sample,
+}
+
+impl Gen for GraphPlayer {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let playing = inputs[0][0] > 0.5;
+        let target = if playing { inputs[1][0] } else { 0.0 };
+        if !playing && self.gain == 0.0 {
+            for output in outputs.iter_mut() {
+                output.fill(0.0);
+            }
+            return GenState::Continue;
+        }
+        // The Graph can't free itself from here, a finished Graph stays
+        // silent until it is removed
+        self.node.process(&inputs[PLAYER_CONTROLS..], resources);
+        for (output, graph_output) in outputs.iter_mut().zip(self.node.output_buffers()) {
+            let step = (target - self.gain) / output.len() as Sample;
+            for (i, (out, sample)) in output.iter_mut().zip(graph_output.iter()).enumerate() {
+                *out = *sample * (self.gain + step * (i + 1) as Sample);
+            }
+        }
+        self.gain = target;
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        PLAYER_CONTROLS + self.node.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        self.node.num_outputs()
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "playing",
+            1 => "volume",
+            _ => INPUT_NAMES
+                .get(input - PLAYER_CONTROLS)
+                .copied()
+                .unwrap_or(""),
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        OUTPUT_NAMES.get(output).copied().unwrap_or("")
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            0 | 1 => 1.0,
+            _ => 0.0,
+        }
+    }
+    fn input_rate(&self, input: usize) -> Rate {
+        if input < PLAYER_CONTROLS {
+            Rate::Control
+        } else {
+            Rate::Audio
+        }
+    }
+    fn sample_rate_changed(&mut self, sample_rate: Sample) {
+        self.node.sample_rate_changed(sample_rate);
+    }
+    fn name(&self) -> &'static str {
+        "GraphPlayer"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn start_stop_and_volume() {
+        let settings = GraphSettings {
+            block_size: 4,
+            num_outputs: 1,
+            ..Default::default()
+        };
+        let mut multi_graph = MultiGraph::new(settings);
+        let mut scene = |value| {
+            let mut graph = Graph::new(settings);
+            let node = graph.push_closure(0, 1, move |_, outputs, _| {
+                outputs[0].fill(value);
+                GenState::Continue
+            });
+            graph.connect(Connection::graph_output(node)).unwrap();
+            multi_graph.add_graph(graph).unwrap()
+        };
+        let first = scene(1.0);
+        let second = scene(10.0);
+        multi_graph.stop(second).unwrap();
+        let mut node = multi_graph.main_graph_mut().to_node().unwrap();
+        multi_graph.commit_changes();
+        multi_graph.update();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        // Fading in from silence
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [0.25, 0.5, 0.75, 1.0]);
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [1.0; 4]);
+
+        multi_graph.stop(first).unwrap();
+        multi_graph.start(second).unwrap();
+        multi_graph.set_volume(second, 0.5).unwrap();
+        multi_graph.update();
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [2.0, 3.0, 4.0, 5.0]);
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [5.0; 4]);
+    }
+}
@@ -0,0 +1,538 @@
+//! Streaming audio between knyst instances over UDP, enabled with the
+//! `network` feature.
+//!
+//! A [`NetworkSink`] node sends its inputs to a [`NetworkSource`] node in
+//! another Graph, usually on a different machine, e.g. to spread a
+//! distributed installation over several computers. The audio is sent as
+//! uncompressed 32 bit float PCM in packets with a sequence number. The
+//! sockets are handled by background threads so the audio thread never
+//! touches the network; the nodes only read and write an
+//! [`audio_channel`](crate::audio_channel).
+//!
+//! UDP packets can be lost or arrive out of order, which the receiving side
+//! handles with a jitter buffer configured through [`JitterBufferSettings`]:
+//!
+//! - Packets arriving out of order are put back in order as long as no more
+//!   than `max_reorder` later packets have arrived. A packet still missing
+//!   after that is counted as lost and replaced by silence.
+//! - The [`NetworkSource`] waits until `latency` frames have arrived before
+//!   it starts outputting, and again after every underrun.
+//!
+//! There is no compensation for the clocks of the two machines drifting
+//! apart. Over time that leads to either dropped frames or underruns, both of
+//! which are counted in [`NetworkStats`].
+//!
+//! ```no_run
+//! # use knyst::prelude::*;
+//! # use knyst::network::{network_sink, network_source, NetworkSettings};
+//! # let mut graph = Graph::new(GraphSettings::default());
+//! // On the receiving machine
+//! let (source, handle) = network_source("0.0.0.0:9000", 2, NetworkSettings::default()).unwrap();
+//! let source = graph.push_gen(source);
+//! graph.connect(Connection::graph_output(source).channels(2)).unwrap();
+//! // On the sending machine
+//! let (sink, handle) = network_sink("192.168.0.2:9000", 2, NetworkSettings::default()).unwrap();
+//! let sink = graph.push_gen(sink);
+//! ```
+//!
+//! A packet consists of the bytes `knys`, the sequence number as a u64, the
+//! number of channels and frames as u16s, followed by the interleaved
+//! samples as f32s, all little endian.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::audio_channel::{audio_channel, AudioReceiver, AudioSender, ChannelStats, Counters};
+use crate::graph::{Gen, GenState};
+use crate::{sample_to_f32, Resources, Sample};
+
+const MAGIC: &[u8; 4] = b"knys";
+const HEADER_BYTES: usize = 16;
+/// The largest payload of a UDP packet over IPv4
+const MAX_PACKET_BYTES: usize = 65507;
+
+#[derive(thiserror::Error, Debug)]
+pub enum NetworkError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("The address could not be resolved.")]
+    NoAddress,
+    #[error("Packets of {frames} frames with {channels} channels don't fit in a UDP packet.")]
+    PacketTooLarge { frames: usize, channels: usize },
+}
+
+/// Settings for both ends of a network stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkSettings {
+    /// The number of frames sent in every packet. Keeping packets below the
+    /// MTU of the network, usually around 1400 bytes, avoids fragmentation.
+    /// Both ends don't need to agree.
+    pub frames_per_packet: usize,
+    /// The number of frames that can be buffered between the node and the
+    /// background thread
+    pub capacity: usize,
+    pub jitter_buffer: JitterBufferSettings,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            frames_per_packet: 128,
+            capacity: 8192,
+            jitter_buffer: JitterBufferSettings::default(),
+        }
+    }
+}
+
+/// How the receiving side deals with jitter, see the
+/// [module documentation](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitterBufferSettings {
+    /// The number of frames to buffer before starting to output
+    pub latency: usize,
+    /// The number of packets that can arrive before a missing packet is
+    /// considered lost
+    pub max_reorder: usize,
+}
+
+impl Default for JitterBufferSettings {
+    fn default() -> Self {
+        Self {
+            latency: 1024,
+            max_reorder: 4,
+        }
+    }
+}
+
+/// Counts for one end of a network stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NetworkStats {
+    /// Packets sent or received
+    pub packets: u64,
+    /// Packets that never arrived and were replaced by silence
+    pub lost_packets: u64,
+    /// Packets that arrived after they had been considered lost
+    pub late_packets: u64,
+    /// Received packets that were not in the format of a knyst stream or had
+    /// the wrong number of channels
+    pub invalid_packets: u64,
+    /// Errors sending or receiving packets
+    pub io_errors: u64,
+    /// Overflows and underruns between the node and the background thread
+    pub channel: ChannelStats,
+}
+
+#[derive(Debug, Default)]
+struct NetworkShared {
+    stop: AtomicBool,
+    packets: AtomicU64,
+    lost_packets: AtomicU64,
+    late_packets: AtomicU64,
+    invalid_packets: AtomicU64,
+    io_errors: AtomicU64,
+}
+
+impl NetworkShared {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Controls the background thread of a [`NetworkSink`] or a
+/// [`NetworkSource`]. Dropping the handle stops the thread without waiting
+/// for it.
+pub struct NetworkHandle {
+    shared: Arc<NetworkShared>,
+    channel: Arc<Counters>,
+    local_addr: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NetworkHandle {
+    /// The local address of the socket, e.g. to find the port that was
+    /// picked when binding to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+    pub fn stats(&self) -> NetworkStats {
+        NetworkStats {
+            packets: self.shared.packets.load(Ordering::Relaxed),
+            lost_packets: self.shared.lost_packets.load(Ordering::Relaxed),
+            late_packets: self.shared.late_packets.load(Ordering::Relaxed),
+            invalid_packets: self.shared.invalid_packets.load(Ordering::Relaxed),
+            io_errors: self.shared.io_errors.load(Ordering::Relaxed),
+            channel: self.channel.stats(),
+        }
+    }
+    /// Stop the background thread and wait for it to finish
+    pub fn stop(mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for NetworkHandle {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+    }
+}
+
+fn check_packet_size(num_channels: usize, settings: &NetworkSettings) -> Result<(), NetworkError> {
+    let bytes = HEADER_BYTES + settings.frames_per_packet * num_channels * 4;
+    if bytes > MAX_PACKET_BYTES || num_channels > u16::MAX as usize {
+        return Err(NetworkError::PacketTooLarge {
+            frames: settings.frames_per_packet,
+            channels: num_channels,
+        });
+    }
+    Ok(())
+}
+
+/// *Allocates memory*
+/// Create a node sending its `num_channels` inputs to `target` and start the
+/// background thread sending the packets.
+pub fn network_sink(
+    target: impl ToSocketAddrs,
+    num_channels: usize,
+    settings: NetworkSettings,
+) -> Result<(NetworkSink, NetworkHandle), NetworkError> {
+    let num_channels = num_channels.max(1);
+    let frames_per_packet = settings.frames_per_packet.max(1);
+    check_packet_size(num_channels, &settings)?;
+    let target = target
+        .to_socket_addrs()?
+        .next()
+        .ok_or(NetworkError::NoAddress)?;
+    let socket = if target.is_ipv4() {
+        UdpSocket::bind("0.0.0.0:0")?
+    } else {
+        UdpSocket::bind("[::]:0")?
+    };
+    socket.connect(target)?;
+    let local_addr = socket.local_addr()?;
+    let (sender, mut receiver) = audio_channel(num_channels, settings.capacity);
+    let shared = Arc::new(NetworkShared::default());
+    let channel = receiver.counters();
+    let thread_shared = shared.clone();
+    let thread = std::thread::spawn(move || {
+        let shared = thread_shared;
+        let mut samples = vec![0.0; frames_per_packet * num_channels];
+        let mut packet = Vec::with_capacity(HEADER_BYTES + samples.len() * 4);
+        let mut sequence = 0;
+        while !shared.stop.load(Ordering::Acquire) {
+            if receiver.available_frames() < frames_per_packet {
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            receiver.receive(&mut samples);
+            encode_packet(&mut packet, sequence, num_channels, &samples);
+            match socket.send(&packet) {
+                Ok(_) => NetworkShared::count(&shared.packets),
+                Err(_) => NetworkShared::count(&shared.io_errors),
+            }
+            sequence += 1;
+        }
+    });
+    Ok((
+        NetworkSink { sender },
+        NetworkHandle {
+            shared,
+            channel,
+            local_addr,
+            thread: Some(thread),
+        },
+    ))
+}
+
+/// *Allocates memory*
+/// Create a node outputting `num_channels` channels received on
+/// `local_addr` and start the background thread receiving the packets.
+pub fn network_source(
+    local_addr: impl ToSocketAddrs,
+    num_channels: usize,
+    settings: NetworkSettings,
+) -> Result<(NetworkSource, NetworkHandle), NetworkError> {
+    let num_channels = num_channels.max(1);
+    check_packet_size(num_channels, &settings)?;
+    let socket = UdpSocket::bind(local_addr)?;
+    // Wake up regularly to check if the thread should stop
+    socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+    let local_addr = socket.local_addr()?;
+    let latency = settings.jitter_buffer.latency;
+    let (mut sender, receiver) = audio_channel(num_channels, settings.capacity.max(latency * 2));
+    let shared = Arc::new(NetworkShared::default());
+    let channel = sender.counters();
+    let thread_shared = shared.clone();
+    let thread = std::thread::spawn(move || {
+        let shared = thread_shared;
+        let mut packet = vec![0; MAX_PACKET_BYTES];
+        let mut jitter_buffer = JitterBuffer::new(settings.jitter_buffer.max_reorder);
+        while !shared.stop.load(Ordering::Acquire) {
+            match socket.recv(&mut packet) {
+                Ok(len) => match decode_packet(&packet[..len], num_channels) {
+                    Some((sequence, samples)) => {
+                        NetworkShared::count(&shared.packets);
+                        jitter_buffer.insert(sequence, samples, &shared, |samples| {
+                            sender.send(samples);
+                        });
+                    }
+                    None => NetworkShared::count(&shared.invalid_packets),
+                },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(_) => NetworkShared::count(&shared.io_errors),
+            }
+        }
+    });
+    Ok((
+        NetworkSource {
+            receiver,
+            latency,
+            buffering: true,
+        },
+        NetworkHandle {
+            shared,
+            channel,
+            local_addr,
+            thread: Some(thread),
+        },
+    ))
+}
+
+fn encode_packet(packet: &mut Vec<u8>, sequence: u64, num_channels: usize, samples: &[Sample]) {
+    packet.clear();
+    packet.extend_from_slice(MAGIC);
+    packet.extend_from_slice(&sequence.to_le_bytes());
+    packet.extend_from_slice(&(num_channels as u16).to_le_bytes());
+    packet.extend_from_slice(&((samples.len() / num_channels) as u16).to_le_bytes());
+    for sample in samples {
+        packet.extend_from_slice(&sample_to_f32(*sample).to_le_bytes());
+    }
+}
+
+/// Returns the sequence number and the samples of a valid packet
+fn decode_packet(packet: &[u8], num_channels: usize) -> Option<(u64, Vec<Sample>)> {
+    if packet.len() < HEADER_BYTES || &packet[0..4] != MAGIC {
+        return None;
+    }
+    let sequence = u64::from_le_bytes(packet[4..12].try_into().unwrap());
+    let channels = u16::from_le_bytes(packet[12..14].try_into().unwrap()) as usize;
+    let frames = u16::from_le_bytes(packet[14..16].try_into().unwrap()) as usize;
+    let samples = &packet[HEADER_BYTES..];
+    if channels != num_channels || samples.len() != frames * channels * 4 {
+        return None;
+    }
+    let samples = samples
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()) as Sample)
+        .collect();
+    Some((sequence, samples))
+}
+
+/// Puts packets back in order and replaces lost packets by silence
+struct JitterBuffer {
+    /// The sequence number of the next packet to release
+    next: Option<u64>,
+    pending: BTreeMap<u64, Vec<Sample>>,
+    max_reorder: usize,
+    silence: Vec<Sample>,
+    /// Late packets in a row, a sign that the sender has restarted
+    late_in_a_row: usize,
+}
+
+impl JitterBuffer {
+    fn new(max_reorder: usize) -> Self {
+        Self {
+            next: None,
+            pending: BTreeMap::new(),
+            max_reorder,
+            silence: vec![],
+            late_in_a_row: 0,
+        }
+    }
+    fn insert(
+        &mut self,
+        sequence: u64,
+        samples: Vec<Sample>,
+        shared: &NetworkShared,
+        mut release: impl FnMut(&[Sample]),
+    ) {
+        let mut next = *self.next.get_or_insert(sequence);
+        if sequence < next {
+            NetworkShared::count(&shared.late_packets);
+            self.late_in_a_row += 1;
+            if self.late_in_a_row > self.max_reorder {
+                // Start over from this packet
+                self.pending.clear();
+                self.late_in_a_row = 0;
+                next = sequence;
+            } else {
+                return;
+            }
+        }
+        self.late_in_a_row = 0;
+        self.pending.insert(sequence, samples);
+        loop {
+            if let Some(samples) = self.pending.remove(&next) {
+                release(&samples);
+                next += 1;
+            } else if self.pending.len() > self.max_reorder {
+                let len = self.pending.values().next().map_or(0, |s| s.len());
+                self.silence.resize(len, 0.0);
+                release(&self.silence);
+                NetworkShared::count(&shared.lost_packets);
+                next += 1;
+            } else {
+                break;
+            }
+        }
+        self.next = Some(next);
+    }
+}
+
+/// Sends its inputs over the network, see the [module documentation](self)
+pub struct NetworkSink {
+    sender: AudioSender,
+}
+
+impl Gen for NetworkSink {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        self.sender.process(inputs, outputs, resources)
+    }
+    fn num_inputs(&self) -> usize {
+        self.sender.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        0
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        self.sender.input_desc(input)
+    }
+    fn name(&self) -> &'static str {
+        "NetworkSink"
+    }
+}
+
+/// Outputs audio received over the network, see the
+/// [module documentation](self)
+pub struct NetworkSource {
+    receiver: AudioReceiver,
+    latency: usize,
+    /// Waiting for `latency` frames to arrive
+    buffering: bool,
+}
+
+impl NetworkSource {
+    /// The number of frames that have arrived but not been output yet
+    pub fn buffered_frames(&self) -> usize {
+        self.receiver.available_frames()
+    }
+}
+
+impl Gen for NetworkSource {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        if self.buffering {
+            if self.receiver.available_frames() < self.latency {
+                for output in outputs.iter_mut() {
+                    output.fill(0.0);
+                }
+                return GenState::Continue;
+            }
+            self.buffering = false;
+        }
+        let frames = outputs.first().map_or(0, |output| output.len());
+        if self.receiver.available_frames() < frames {
+            self.buffering = true;
+        }
+        self.receiver.process(inputs, outputs, resources)
+    }
+    fn num_inputs(&self) -> usize {
+        0
+    }
+    fn num_outputs(&self) -> usize {
+        self.receiver.num_outputs()
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        self.receiver.output_desc(output)
+    }
+    fn name(&self) -> &'static str {
+        "NetworkSource"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+    use std::time::Instant;
+
+    #[test]
+    fn jitter_buffer_reorders() {
+        let shared = NetworkShared::default();
+        let mut jitter_buffer = JitterBuffer::new(1);
+        let mut released = vec![];
+        for sequence in [0, 2, 1, 4, 5, 3, 6] {
+            jitter_buffer.insert(sequence, vec![sequence as Sample], &shared, |samples| {
+                released.push(samples[0])
+            });
+        }
+        // 3 was given up on when 4 and 5 had arrived
+        assert_eq!(released, [0.0, 1.0, 2.0, 0.0, 4.0, 5.0, 6.0]);
+        assert_eq!(shared.lost_packets.load(Ordering::Relaxed), 1);
+        assert_eq!(shared.late_packets.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn stream_over_localhost() {
+        let settings = NetworkSettings {
+            frames_per_packet: 4,
+            capacity: 64,
+            jitter_buffer: JitterBufferSettings {
+                latency: 8,
+                max_reorder: 2,
+            },
+        };
+        let (mut source, source_handle) = network_source("127.0.0.1:0", 2, settings).unwrap();
+        let (mut sink, sink_handle) =
+            network_sink(source_handle.local_addr(), 2, settings).unwrap();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let inputs: Vec<Box<[Sample]>> = vec![vec![0.5; 4].into(), vec![-0.5; 4].into()];
+        let mut outputs: Vec<Box<[Sample]>> = vec![vec![0.0; 4].into(), vec![0.0; 4].into()];
+        for _ in 0..3 {
+            sink.process(&inputs, &mut [], &mut resources);
+        }
+        let start = Instant::now();
+        while source.buffered_frames() < 12 && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        for _ in 0..3 {
+            source.process(&[], &mut outputs, &mut resources);
+            assert_eq!(outputs[0][..], [0.5; 4]);
+            assert_eq!(outputs[1][..], [-0.5; 4]);
+        }
+        // Buffering again until 8 frames have arrived
+        source.process(&[], &mut outputs, &mut resources);
+        assert_eq!(outputs[0][..], [0.0; 4]);
+        sink_handle.stop();
+        let stats = source_handle.stats();
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.lost_packets, 0);
+        source_handle.stop();
+    }
+}
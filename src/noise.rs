@@ -0,0 +1,308 @@
+//! Noise generators. All of them use [`XOrShift32Rng`], have an "amp" input
+//! and output values roughly between -amp and amp.
+//!
+//! Unless created with a seed, a noise generator takes its seed from
+//! [`Resources::rng`] the first time it is processed, so the noise is the
+//! same every time when the Resources are created with
+//! [`Resources::new_with_seed`].
+//!
+//! - [`WhiteNoise`] has equal energy per frequency
+//! - [`PinkNoise`] has equal energy per octave (-3dB/octave)
+//! - [`BrownNoise`] falls at -6dB/octave
+//! - [`VioletNoise`] rises at +6dB/octave
+
+use crate::graph::{Gen, GenState};
+use crate::xorrng::XOrShift32Rng;
+use crate::{Resources, Sample};
+
+/// The rng of a noise generator, seeded from [`Resources::rng`] if it doesn't
+/// have one yet
+#[inline]
+fn rng<'a>(rng: &'a mut Option<XOrShift32Rng>, resources: &mut Resources) -> &'a mut XOrShift32Rng {
+    rng.get_or_insert_with(|| XOrShift32Rng::new(resources.rng.u32(..)))
+}
+
+#[inline]
+fn white(rng: &mut XOrShift32Rng) -> Sample {
+    rng.gen_f32() as Sample * 2.0 - 1.0
+}
+
+macro_rules! noise_gen_common {
+    ($name:literal) => {
+        fn num_inputs(&self) -> usize {
+            1
+        }
+        fn num_outputs(&self) -> usize {
+            1
+        }
+        fn input_desc(&self, input: usize) -> &'static str {
+            match input {
+                0 => "amp",
+                _ => "",
+            }
+        }
+        fn output_desc(&self, output: usize) -> &'static str {
+            match output {
+                0 => "sig",
+                _ => "",
+            }
+        }
+        fn name(&self) -> &'static str {
+            $name
+        }
+    };
+}
+
+/// White noise
+#[derive(Debug, Clone)]
+pub struct WhiteNoise {
+    rng: Option<XOrShift32Rng>,
+}
+
+impl WhiteNoise {
+    pub fn new() -> Self {
+        Self { rng: None }
+    }
+    /// Create with a seed to get the same noise every time
+    pub fn with_seed(seed: u32) -> Self {
+        Self {
+            rng: Some(XOrShift32Rng::new(seed)),
+        }
+    }
+}
+
+impl Default for WhiteNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for WhiteNoise {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let rng = rng(&mut self.rng, resources);
+        for (&amp, out) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            *out = white(rng) * amp;
+        }
+        GenState::Continue
+    }
+    noise_gen_common!("WhiteNoise");
+}
+
+/// Pink noise using Paul Kellet's refined filter on white noise, accurate to
+/// within ±0.05dB above 9.2Hz at 44.1kHz.
+#[derive(Debug, Clone)]
+pub struct PinkNoise {
+    rng: Option<XOrShift32Rng>,
+    b: [Sample; 7],
+}
+
+impl PinkNoise {
+    pub fn new() -> Self {
+        Self {
+            rng: None,
+            b: [0.0; 7],
+        }
+    }
+    /// Create with a seed to get the same noise every time
+    pub fn with_seed(seed: u32) -> Self {
+        Self {
+            rng: Some(XOrShift32Rng::new(seed)),
+            b: [0.0; 7],
+        }
+    }
+}
+
+impl Default for PinkNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for PinkNoise {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let rng = rng(&mut self.rng, resources);
+        let b = &mut self.b;
+        for (&amp, out) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            let white = white(rng);
+            b[0] = 0.99886 * b[0] + white * 0.0555179;
+            b[1] = 0.99332 * b[1] + white * 0.0750759;
+            b[2] = 0.96900 * b[2] + white * 0.153852;
+            b[3] = 0.86650 * b[3] + white * 0.3104856;
+            b[4] = 0.55000 * b[4] + white * 0.5329522;
+            b[5] = -0.7616 * b[5] - white * 0.0168980;
+            let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+            b[6] = white * 0.115926;
+            // Scale to roughly -1 to 1
+            *out = pink * 0.11 * amp;
+        }
+        GenState::Continue
+    }
+    noise_gen_common!("PinkNoise");
+}
+
+/// Brown (red) noise made by integrating white noise with a leak to keep it
+/// from drifting.
+#[derive(Debug, Clone)]
+pub struct BrownNoise {
+    rng: Option<XOrShift32Rng>,
+    last: Sample,
+}
+
+impl BrownNoise {
+    pub fn new() -> Self {
+        Self {
+            rng: None,
+            last: 0.0,
+        }
+    }
+    /// Create with a seed to get the same noise every time
+    pub fn with_seed(seed: u32) -> Self {
+        Self {
+            rng: Some(XOrShift32Rng::new(seed)),
+            last: 0.0,
+        }
+    }
+}
+
+impl Default for BrownNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for BrownNoise {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let rng = rng(&mut self.rng, resources);
+        for (&amp, out) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            self.last = (self.last + 0.02 * white(rng)) / 1.02;
+            // Scale to roughly -1 to 1
+            *out = self.last * 3.5 * amp;
+        }
+        GenState::Continue
+    }
+    noise_gen_common!("BrownNoise");
+}
+
+/// Violet (purple) noise made by differentiating white noise.
+#[derive(Debug, Clone)]
+pub struct VioletNoise {
+    rng: Option<XOrShift32Rng>,
+    last_white: Sample,
+}
+
+impl VioletNoise {
+    pub fn new() -> Self {
+        Self {
+            rng: None,
+            last_white: 0.0,
+        }
+    }
+    /// Create with a seed to get the same noise every time
+    pub fn with_seed(seed: u32) -> Self {
+        Self {
+            rng: Some(XOrShift32Rng::new(seed)),
+            last_white: 0.0,
+        }
+    }
+}
+
+impl Default for VioletNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for VioletNoise {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let rng = rng(&mut self.rng, resources);
+        for (&amp, out) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            let white = white(rng);
+            *out = (white - self.last_white) * 0.5 * amp;
+            self.last_white = white;
+        }
+        GenState::Continue
+    }
+    noise_gen_common!("VioletNoise");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn run(gen: &mut dyn Gen) -> Vec<Sample> {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let inputs = vec![vec![1.0; 4096].into_boxed_slice()];
+        let mut outputs = vec![vec![0.0; 4096].into_boxed_slice()];
+        gen.process(&inputs, &mut outputs, &mut resources);
+        outputs[0].to_vec()
+    }
+
+    /// The average absolute difference between consecutive samples relative
+    /// to the average level, which is larger the more high frequency content
+    /// there is
+    fn roughness(samples: &[Sample]) -> Sample {
+        let diff: Sample = samples.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+        let level: Sample = samples.iter().map(|s| s.abs()).sum();
+        diff / level
+    }
+
+    #[test]
+    fn noise_colours() {
+        let white = run(&mut WhiteNoise::with_seed(1));
+        let pink = run(&mut PinkNoise::with_seed(1));
+        let brown = run(&mut BrownNoise::with_seed(1));
+        let violet = run(&mut VioletNoise::with_seed(1));
+        for noise in [&white, &pink, &brown, &violet] {
+            assert!(noise.iter().all(|s| s.abs() <= 1.5));
+            assert!(noise.iter().any(|&s| s != 0.0));
+        }
+        // Same seed, same noise
+        assert_eq!(white, run(&mut WhiteNoise::with_seed(1)));
+        assert!(roughness(&violet) > roughness(&white));
+        assert!(roughness(&white) > roughness(&pink));
+        assert!(roughness(&pink) > roughness(&brown));
+    }
+
+    #[test]
+    fn seeded_from_resources() {
+        let render = |seed| {
+            let mut resources = Resources::new_with_seed(ResourcesSettings::default(), seed);
+            let inputs = vec![vec![1.0; 64].into_boxed_slice()];
+            let mut outputs = vec![vec![0.0; 64].into_boxed_slice()];
+            let mut noise = [WhiteNoise::new(), WhiteNoise::new()];
+            noise
+                .iter_mut()
+                .flat_map(|noise| {
+                    noise.process(&inputs, &mut outputs, &mut resources);
+                    outputs[0].to_vec()
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = render(7);
+        assert_eq!(first, render(7));
+        assert_ne!(first, render(8));
+        // Each Gen gets its own seed
+        assert_ne!(first[..64], first[64..]);
+    }
+}
@@ -0,0 +1,336 @@
+//! Running Gens at a higher sample rate.
+//!
+//! Nonlinear processing such as waveshaping, FM with a high index or analog
+//! style filters adds harmonics above the Nyquist frequency which alias back
+//! into the audible range. [`Oversample`] runs any Gen at 2x or 4x the sample
+//! rate of the Graph, with halfband filters for upsampling its inputs and
+//! downsampling its outputs, which suppresses most of the aliasing.
+
+use crate::graph::{Gen, GenState, InputKind, Rate};
+use crate::patch::{PatchError, ResourceRef, SavedState};
+use crate::{Resources, Sample};
+
+pub(crate) const HALFBAND_TAPS: usize = 31;
+
+/// The taps of a windowed sinc halfband lowpass filter at a quarter of the
+/// sample rate, normalized to unity gain at DC.
+pub(crate) fn halfband_taps() -> [Sample; HALFBAND_TAPS] {
+    let mut taps = [0.0; HALFBAND_TAPS];
+    let center = (HALFBAND_TAPS - 1) as f64 / 2.0;
+    for (n, tap) in taps.iter_mut().enumerate() {
+        let t = n as f64 - center;
+        let sinc = if t == 0.0 {
+            0.5
+        } else {
+            (std::f64::consts::PI * 0.5 * t).sin() / (std::f64::consts::PI * t)
+        };
+        // Blackman window
+        let phase = 2.0 * std::f64::consts::PI * n as f64 / (HALFBAND_TAPS - 1) as f64;
+        let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+        *tap = (sinc * window) as Sample;
+    }
+    let sum: Sample = taps.iter().sum();
+    for tap in &mut taps {
+        *tap /= sum;
+    }
+    taps
+}
+
+/// A halfband filter with its own history
+#[derive(Debug, Clone)]
+struct Halfband {
+    history: [Sample; HALFBAND_TAPS],
+    pos: usize,
+}
+
+impl Halfband {
+    fn new() -> Self {
+        Self {
+            history: [0.0; HALFBAND_TAPS],
+            pos: 0,
+        }
+    }
+    #[inline]
+    fn process(&mut self, taps: &[Sample; HALFBAND_TAPS], input: Sample) -> Sample {
+        self.pos = (self.pos + 1) % HALFBAND_TAPS;
+        self.history[self.pos] = input;
+        let mut sum = 0.0;
+        for (i, tap) in taps.iter().enumerate() {
+            sum += tap * self.history[(self.pos + HALFBAND_TAPS - i) % HALFBAND_TAPS];
+        }
+        sum
+    }
+    /// Double the sample rate of `input` into `output`, which needs to be
+    /// twice as long.
+    fn upsample(
+        &mut self,
+        taps: &[Sample; HALFBAND_TAPS],
+        input: &[Sample],
+        output: &mut [Sample],
+    ) {
+        for (value, out) in input.iter().zip(output.chunks_exact_mut(2)) {
+            // Zero stuffing halves the level, which is made up for by the 2.0
+            out[0] = self.process(taps, value * 2.0);
+            out[1] = self.process(taps, 0.0);
+        }
+    }
+    /// Halve the sample rate of `input` into `output`, which needs to be
+    /// half as long.
+    fn downsample(
+        &mut self,
+        taps: &[Sample; HALFBAND_TAPS],
+        input: &[Sample],
+        output: &mut [Sample],
+    ) {
+        for (pair, out) in input.chunks_exact(2).zip(output.iter_mut()) {
+            self.process(taps, pair[0]);
+            *out = self.process(taps, pair[1]);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversamplingFactor {
+    X2,
+    X4,
+}
+
+impl OversamplingFactor {
+    pub fn factor(self) -> usize {
+        match self {
+            OversamplingFactor::X2 => 2,
+            OversamplingFactor::X4 => 4,
+        }
+    }
+    /// The number of 2x stages
+    fn stages(self) -> usize {
+        match self {
+            OversamplingFactor::X2 => 1,
+            OversamplingFactor::X4 => 2,
+        }
+    }
+}
+
+/// Runs a Gen at a multiple of the sample rate, see the
+/// [module documentation](self).
+///
+/// The inner Gen is initialized with the higher sample rate and processes
+/// blocks that are `factor` times larger. Inputs and outputs have the same
+/// names and defaults as those of the inner Gen, but the outputs are
+/// delayed by [`Oversample::latency`] samples.
+pub struct Oversample<G: Gen> {
+    gen: G,
+    factor: OversamplingFactor,
+    block_size: usize,
+    taps: [Sample; HALFBAND_TAPS],
+    /// Inputs and outputs at the higher sample rate
+    inputs: Box<[Box<[Sample]>]>,
+    outputs: Box<[Box<[Sample]>]>,
+    /// Between the two stages when oversampling 4x
+    scratch: Box<[Sample]>,
+    /// One filter per stage for every input and output
+    up_filters: Vec<[Halfband; 2]>,
+    down_filters: Vec<[Halfband; 2]>,
+}
+
+impl<G: Gen> Oversample<G> {
+    /// *Allocates memory*
+    /// Oversample `gen` by `factor`. `block_size` has to be the block size of
+    /// the Graph the Oversample is added to.
+    pub fn new(gen: G, factor: OversamplingFactor, block_size: usize) -> Self {
+        let inner_block = block_size * factor.factor();
+        let inputs =
+            vec![vec![0.0; inner_block].into_boxed_slice(); gen.num_inputs()].into_boxed_slice();
+        let outputs =
+            vec![vec![0.0; inner_block].into_boxed_slice(); gen.num_outputs()].into_boxed_slice();
+        let up_filters = (0..gen.num_inputs())
+            .map(|_| [Halfband::new(), Halfband::new()])
+            .collect();
+        let down_filters = (0..gen.num_outputs())
+            .map(|_| [Halfband::new(), Halfband::new()])
+            .collect();
+        Self {
+            gen,
+            factor,
+            block_size,
+            taps: halfband_taps(),
+            inputs,
+            outputs,
+            scratch: vec![0.0; block_size * 2].into_boxed_slice(),
+            up_filters,
+            down_filters,
+        }
+    }
+    /// The delay of the outputs in samples at the sample rate of the Graph
+    pub fn latency(&self) -> Sample {
+        // Every stage delays by two halfband filters at its rate, less one
+        // sample at that rate from keeping the second sample of every pair
+        // when downsampling
+        let stage_delay = (HALFBAND_TAPS - 2) as Sample;
+        (1..=self.factor.stages())
+            .map(|stage| stage_delay / (1 << stage) as Sample)
+            .sum()
+    }
+    pub fn inner(&self) -> &G {
+        &self.gen
+    }
+    pub fn inner_mut(&mut self) -> &mut G {
+        &mut self.gen
+    }
+}
+
+impl<G: Gen> Gen for Oversample<G> {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let block_size = self.block_size;
+        if outputs.first().map(|o| o.len()).unwrap_or(block_size) != block_size {
+            // The buffers were made for a different block size
+            for output in outputs.iter_mut() {
+                output.fill(0.0);
+            }
+            return GenState::Continue;
+        }
+        let stages = self.factor.stages();
+        for (i, filters) in self.up_filters.iter_mut().enumerate() {
+            let input = &inputs[i][..block_size];
+            if stages == 1 {
+                filters[0].upsample(&self.taps, input, &mut self.inputs[i]);
+            } else {
+                filters[0].upsample(&self.taps, input, &mut self.scratch);
+                filters[1].upsample(&self.taps, &self.scratch, &mut self.inputs[i]);
+            }
+        }
+        let state = self.gen.process(&self.inputs, &mut self.outputs, resources);
+        for (i, filters) in self.down_filters.iter_mut().enumerate() {
+            let output = &mut outputs[i][..block_size];
+            if stages == 1 {
+                filters[0].downsample(&self.taps, &self.outputs[i], output);
+            } else {
+                filters[1].downsample(&self.taps, &self.outputs[i], &mut self.scratch);
+                filters[0].downsample(&self.taps, &self.scratch, output);
+            }
+        }
+        state
+    }
+    fn num_inputs(&self) -> usize {
+        self.gen.num_inputs()
+    }
+    fn num_outputs(&self) -> usize {
+        self.gen.num_outputs()
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.gen.init(sample_rate * self.factor.factor() as Sample);
+    }
+    fn sample_rate_changed(&mut self, sample_rate: Sample) {
+        self.gen
+            .sample_rate_changed(sample_rate * self.factor.factor() as Sample);
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        self.gen.input_desc(input)
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        self.gen.output_desc(output)
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        self.gen.default_input(input)
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        self.gen.resource_refs()
+    }
+    fn uses_shared_resources(&self) -> bool {
+        self.gen.uses_shared_resources()
+    }
+    fn save_state(&self) -> Option<SavedState> {
+        self.gen.save_state()
+    }
+    fn load_state(&mut self, state: &SavedState) -> Result<(), PatchError> {
+        self.gen.load_state(state)
+    }
+    fn latency(&self) -> usize {
+        Oversample::latency(self).round() as usize + self.gen.latency() / self.factor.factor()
+    }
+    fn input_kind(&self, input: usize) -> InputKind {
+        self.gen.input_kind(input)
+    }
+    fn input_required(&self, input: usize) -> bool {
+        self.gen.input_required(input)
+    }
+    fn input_rate(&self, input: usize) -> Rate {
+        self.gen.input_rate(input)
+    }
+    fn name(&self) -> &'static str {
+        self.gen.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sample_to_f64, ResourcesSettings};
+
+    /// Passes its input through and remembers its sample rate
+    struct Through {
+        sample_rate: Sample,
+    }
+
+    impl Gen for Through {
+        fn process(
+            &mut self,
+            inputs: &[Box<[Sample]>],
+            outputs: &mut [Box<[Sample]>],
+            _resources: &mut Resources,
+        ) -> GenState {
+            outputs[0].copy_from_slice(&inputs[0]);
+            GenState::Continue
+        }
+        fn init(&mut self, sample_rate: Sample) {
+            self.sample_rate = sample_rate;
+        }
+        fn num_inputs(&self) -> usize {
+            1
+        }
+        fn num_outputs(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn passes_low_frequencies_with_latency() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        for factor in [OversamplingFactor::X2, OversamplingFactor::X4] {
+            let block_size = 64;
+            let mut oversample = Oversample::new(Through { sample_rate: 0.0 }, factor, block_size);
+            oversample.init(48000.0);
+            assert_eq!(
+                oversample.inner().sample_rate,
+                48000.0 * factor.factor() as Sample
+            );
+            let freq = 200.0;
+            let sine =
+                |i: usize| (std::f64::consts::TAU * freq * i as f64 / 48000.0).sin() as Sample;
+            let latency = sample_to_f64(oversample.latency());
+            let mut max_error: Sample = 0.0;
+            for block in 0..20 {
+                let input: Vec<Sample> = (0..block_size)
+                    .map(|i| sine(block * block_size + i))
+                    .collect();
+                let inputs = vec![input.into_boxed_slice()];
+                let mut outputs = vec![vec![0.0; block_size].into_boxed_slice()];
+                oversample.process(&inputs, &mut outputs, &mut resources);
+                if block < 2 {
+                    continue;
+                }
+                for (i, out) in outputs[0].iter().enumerate() {
+                    let t = (block * block_size + i) as f64 - latency;
+                    let expected = (std::f64::consts::TAU * freq * t / 48000.0).sin() as Sample;
+                    max_error = max_error.max((out - expected).abs());
+                }
+            }
+            assert!(max_error < 1e-2, "{factor:?}: {max_error}");
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Parameters that can be set from any thread without changing the Graph.
+//!
+//! A [`SharedParam`] is a value shared between e.g. a GUI slider and the
+//! audio thread. Setting it is a single atomic store, so it can be done at
+//! any time and as often as needed without going through
+//! [`Graph::connect`] or scheduling a change. On the audio thread a
+//! [`ParamReader`] node outputs the value, optionally smoothed, and can be
+//! connected to any node input. [`SharedParam::bind`] does both in one go.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::param::SharedParam;
+//! # use knyst::wavetable::{Wavetable, WavetableOscillatorOwned};
+//! # use std::time::Duration;
+//! # let mut graph = Graph::new(GraphSettings::default());
+//! let osc = graph.push_gen(WavetableOscillatorOwned::new(Wavetable::sine()));
+//! let freq = SharedParam::new(440.);
+//! freq.bind(&mut graph, osc, "freq", Smoothing::Exponential(Duration::from_millis(10)))
+//!     .unwrap();
+//! // From the GUI thread
+//! freq.set(660.);
+//! ```
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::graph::{
+    constant, ConnectionError, Gen, GenState, Graph, InputSmoother, NodeAddress, Smoothing,
+};
+use crate::{sample_to_f32, Resources, Sample};
+
+/// An atomic f32 that can be shared between threads, see the
+/// [module documentation](self). Clones refer to the same value.
+#[derive(Debug, Clone)]
+pub struct SharedParam {
+    value: Arc<AtomicU32>,
+}
+
+impl SharedParam {
+    /// *Allocates memory*
+    pub fn new(value: Sample) -> Self {
+        Self {
+            value: Arc::new(AtomicU32::new(sample_to_f32(value).to_bits())),
+        }
+    }
+    /// Set the value. This never blocks and can be called from any thread.
+    pub fn set(&self, value: Sample) {
+        self.value
+            .store(sample_to_f32(value).to_bits(), Ordering::Relaxed);
+    }
+    pub fn get(&self) -> Sample {
+        f32::from_bits(self.value.load(Ordering::Relaxed)) as Sample
+    }
+    /// A node outputting the value of the parameter
+    pub fn reader(&self) -> ParamReader {
+        ParamReader {
+            param: self.clone(),
+            smoothing: Smoothing::None,
+            smoother: None,
+        }
+    }
+    /// Add a [`ParamReader`] with `smoothing` to `graph` and connect it to the
+    /// input `input` of `sink`, replacing the constant value of the input.
+    /// Returns the reader so that it can be freed when the binding is no
+    /// longer needed.
+    pub fn bind(
+        &self,
+        graph: &mut Graph,
+        sink: NodeAddress,
+        input: &'static str,
+        smoothing: Smoothing,
+    ) -> Result<NodeAddress, ConnectionError> {
+        let reader = graph.push_gen(self.reader().smoothing(smoothing));
+        // The output of the reader is added to the constant of the input
+        graph.connect(constant(0.0).to(sink).to_label(input))?;
+        graph.connect(reader.to(sink).to_label(input))?;
+        Ok(reader)
+    }
+}
+
+/// Outputs the value of a [`SharedParam`]. The parameter is read once per
+/// block.
+pub struct ParamReader {
+    param: SharedParam,
+    smoothing: Smoothing,
+    smoother: Option<InputSmoother>,
+}
+
+impl ParamReader {
+    /// How changes to the value are smoothed. Default: [`Smoothing::None`]
+    pub fn smoothing(mut self, smoothing: Smoothing) -> Self {
+        self.smoothing = smoothing;
+        self
+    }
+}
+
+impl Gen for ParamReader {
+    fn process(
+        &mut self,
+        _inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let value = self.param.get();
+        match &mut self.smoother {
+            Some(smoother) => {
+                for out in outputs[0].iter_mut() {
+                    *out = smoother.next(value);
+                }
+            }
+            None => outputs[0].fill(value),
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        0
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "value",
+            _ => "",
+        }
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        // Start at the current value so that only later changes are smoothed
+        self.smoother = InputSmoother::new(self.smoothing, sample_rate).map(|mut smoother| {
+            smoother.reset(self.param.get());
+            smoother
+        });
+    }
+    fn name(&self) -> &'static str {
+        "ParamReader"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{Connection, GraphSettings};
+    use crate::ResourcesSettings;
+    use std::time::Duration;
+
+    #[test]
+    fn bound_param() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 1,
+            sample_rate: 4.0,
+            ..Default::default()
+        });
+        let sink = graph.push_closure(1, 1, |inputs, outputs, _| {
+            outputs[0].copy_from_slice(&inputs[0]);
+            GenState::Continue
+        });
+        graph.connect(constant(100.0).to(sink)).unwrap();
+        graph.connect(Connection::graph_output(sink)).unwrap();
+        let param = SharedParam::new(1.0);
+        param
+            .bind(
+                &mut graph,
+                sink,
+                "in0",
+                Smoothing::Linear(Duration::from_secs(1)),
+            )
+            .unwrap();
+        let mut node = graph.to_node().unwrap();
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [1.0; 4]);
+        param.clone().set(5.0);
+        assert_eq!(param.get(), 5.0);
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[0][..], [2.0, 3.0, 4.0, 5.0]);
+    }
+}
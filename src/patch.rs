@@ -0,0 +1,455 @@
+//! Saving and loading the structure of a [`Graph`].
+//!
+//! [`Graph::to_patch`] describes the nodes, connections, constants and
+//! resource references of a Graph and all Graphs inside it as a [`Patch`].
+//! With the `serde` feature enabled a [`Patch`] can be serialized to any
+//! format supported by serde, e.g. JSON or RON, and loaded again later.
+//!
+//! A [`Patch`] doesn't contain the Gens themselves, only their names. Turning
+//! it back into a [`Graph`] using [`Patch::to_graph`] requires a function
+//! creating a Gen for every node.
+//!
+//! Gens can save state that isn't described by their inputs, e.g. the
+//! settings of an envelope or the contents of a delay line, as a
+//! [`SavedState`] using [`Gen::save_state`]. It is loaded into the new Gen
+//! using [`Gen::load_state`] when the Graph is created.
+//!
+//! The [`ResourceRef`]s of a node are keys to resources in the [`Resources`]
+//! the Graph was running with. They are only valid as long as those resources
+//! are still loaded with the same keys.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::BufferKey;
+use crate::graph::{
+    constant, Connection, ConnectionError, Gen, Graph, GraphSettings, InputMode, Node, Smoothing,
+};
+use crate::waveshaper::LookupTableKey;
+use crate::wavetable::WavetableKey;
+#[allow(unused)]
+use crate::Resources;
+use crate::Sample;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PatchError {
+    #[error("No Gen could be created for the node `{0}`.")]
+    UnknownGen(String),
+    #[error("An edge refers to node {0}, which is not in the patch.")]
+    NodeNotFound(usize),
+    #[error("Edges from a graph input directly to a graph output are not supported.")]
+    GraphInputToGraphOutput,
+    #[error("The saved state of `{0}` could not be loaded.")]
+    InvalidState(&'static str),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+}
+
+/// A reference to a resource used by a Gen, see [`Gen::resource_refs`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ResourceRef {
+    Buffer(BufferKey),
+    Wavetable(WavetableKey),
+    LookupTable(LookupTableKey),
+}
+
+/// State of a Gen saved in a [`Patch`], see [`Gen::save_state`]. Lists and
+/// maps can be nested to describe any state.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SavedState {
+    Number(f64),
+    Samples(Vec<Sample>),
+    Text(String),
+    List(Vec<SavedState>),
+    /// Values by name, in the order they were saved
+    Map(Vec<(String, SavedState)>),
+}
+
+impl SavedState {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            SavedState::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+    pub fn as_samples(&self) -> Option<&[Sample]> {
+        match self {
+            SavedState::Samples(samples) => Some(samples),
+            _ => None,
+        }
+    }
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            SavedState::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+    pub fn as_list(&self) -> Option<&[SavedState]> {
+        match self {
+            SavedState::List(list) => Some(list),
+            _ => None,
+        }
+    }
+    /// The value of `key` if this is a Map
+    pub fn get(&self, key: &str) -> Option<&SavedState> {
+        match self {
+            SavedState::Map(values) => values
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// The structure of a [`Graph`]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Patch {
+    pub num_inputs: usize,
+    pub num_outputs: usize,
+    pub block_size: usize,
+    pub nodes: Vec<PatchNode>,
+    pub edges: Vec<PatchEdge>,
+}
+
+/// A node in a [`Patch`]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchNode {
+    /// The name of the node, by default the name of its Gen
+    pub gen: String,
+    /// The name set using [`Graph::set_node_name`]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub name: Option<String>,
+    /// The constant value of every input
+    pub constants: Vec<Sample>,
+    /// The smoothing of every input
+    pub smoothing: Vec<Smoothing>,
+    /// How the connections to every input are combined
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub input_modes: Vec<InputMode>,
+    pub resources: Vec<ResourceRef>,
+    /// The state saved by the Gen, see [`Gen::save_state`]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub state: Option<SavedState>,
+    /// The sidechain inputs of the Gen, see [`Gen::input_kind`]. For tools
+    /// reading the Patch; not used when loading it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub sidechain_inputs: Vec<usize>,
+    /// If the node is a Graph, the structure of that Graph
+    pub graph: Option<Box<Patch>>,
+}
+
+/// Where a [`PatchEdge`] comes from. Nodes are indices into [`Patch::nodes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PatchSource {
+    Node { node: usize, output: usize },
+    GraphInput(usize),
+}
+
+/// Where a [`PatchEdge`] goes to. Nodes are indices into [`Patch::nodes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PatchSink {
+    Node { node: usize, input: usize },
+    GraphOutput(usize),
+}
+
+/// A connection between one output and one input in a [`Patch`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchEdge {
+    pub source: PatchSource,
+    pub sink: PatchSink,
+    pub feedback: bool,
+    /// Patches saved before edges had a gain load with a gain of 1.0
+    #[cfg_attr(feature = "serde", serde(default = "unity_gain"))]
+    pub gain: Sample,
+}
+
+#[cfg(feature = "serde")]
+fn unity_gain() -> Sample {
+    1.0
+}
+
+impl Patch {
+    /// Call `f` for every node in this Patch and the Patches of Graphs
+    /// inside it.
+    pub fn visit_nodes_mut(&mut self, f: &mut dyn FnMut(&mut PatchNode)) {
+        for node in &mut self.nodes {
+            f(node);
+            if let Some(graph) = &mut node.graph {
+                graph.visit_nodes_mut(f);
+            }
+        }
+    }
+    /// *Allocates memory*
+    /// The names of the Gens in this Patch and the Graphs inside it, sorted
+    /// and without duplicates. Graphs themselves are not included.
+    pub fn gen_names(&self) -> Vec<String> {
+        fn collect(patch: &Patch, names: &mut Vec<String>) {
+            for node in &patch.nodes {
+                match &node.graph {
+                    Some(graph) => collect(graph, names),
+                    None => names.push(node.gen.clone()),
+                }
+            }
+        }
+        let mut names = vec![];
+        collect(self, &mut names);
+        names.sort();
+        names.dedup();
+        names
+    }
+    /// Create a new [`Graph`] with the structure of this Patch. `make_gen` is
+    /// called for every node that isn't a Graph to create its Gen, returning
+    /// None results in [`PatchError::UnknownGen`]. The saved state of every
+    /// node is loaded into the new Gen after it has been initialised.
+    ///
+    /// The number of inputs and outputs of `settings` are replaced by the ones
+    /// in the Patch. Graphs inside the Graph are created with the same
+    /// settings, but with their own block size.
+    pub fn to_graph(
+        &self,
+        settings: GraphSettings,
+        mut make_gen: impl FnMut(&PatchNode) -> Option<Box<dyn Gen + Send>>,
+    ) -> Result<Graph, PatchError> {
+        self.build_graph(settings, &mut make_gen)
+    }
+    fn build_graph(
+        &self,
+        settings: GraphSettings,
+        make_gen: &mut dyn FnMut(&PatchNode) -> Option<Box<dyn Gen + Send>>,
+    ) -> Result<Graph, PatchError> {
+        let mut graph = Graph::new(GraphSettings {
+            num_inputs: self.num_inputs,
+            num_outputs: self.num_outputs,
+            ..settings
+        });
+        let mut addresses = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let (address, defaults) = match &node.graph {
+                Some(inner) => {
+                    let inner_settings = GraphSettings {
+                        block_size: inner.block_size,
+                        ..settings
+                    };
+                    let address = graph.push_graph(inner.build_graph(inner_settings, make_gen)?);
+                    (address, vec![])
+                }
+                None => {
+                    let gen =
+                        make_gen(node).ok_or_else(|| PatchError::UnknownGen(node.gen.clone()))?;
+                    let defaults: Vec<Sample> = (0..gen.num_inputs())
+                        .map(|input| gen.default_input(input))
+                        .collect();
+                    let address = graph.push_node(Node::new(gen.name(), gen));
+                    if let Some(state) = &node.state {
+                        graph.load_node_state(address, state)?;
+                    }
+                    (address, defaults)
+                }
+            };
+            if let Some(name) = &node.name {
+                graph.set_node_name(address, name.clone())?;
+            }
+            for (index, &value) in node.constants.iter().enumerate() {
+                let smoothing = node
+                    .smoothing
+                    .get(index)
+                    .copied()
+                    .unwrap_or(Smoothing::None);
+                // Constants left at their default are only used while the
+                // input is unconnected, so they shouldn't be set explicitly
+                let default = defaults.get(index).copied().unwrap_or(0.0);
+                if value == default && smoothing == Smoothing::None {
+                    continue;
+                }
+                let mut connection = constant(value).to(address).to_index(index);
+                if smoothing != Smoothing::None {
+                    connection = connection.smoothing(smoothing);
+                }
+                graph.connect(connection)?;
+            }
+            for (index, &mode) in node.input_modes.iter().enumerate() {
+                if mode != InputMode::Add {
+                    graph.set_input_mode(address, index, mode)?;
+                }
+            }
+            addresses.push(address);
+        }
+        let address = |node: usize| {
+            addresses
+                .get(node)
+                .copied()
+                .ok_or(PatchError::NodeNotFound(node))
+        };
+        for edge in &self.edges {
+            let connection = match (edge.source, edge.sink) {
+                (PatchSource::Node { node, output }, PatchSink::Node { node: sink, input }) => {
+                    address(node)?
+                        .to(address(sink)?)
+                        .from_index(output)
+                        .to_index(input)
+                        .feedback(edge.feedback)
+                }
+                (PatchSource::Node { node, output }, PatchSink::GraphOutput(index)) => {
+                    Connection::graph_output(address(node)?)
+                        .from_index(output)
+                        .to_index(index)
+                }
+                (PatchSource::GraphInput(index), PatchSink::Node { node, input }) => {
+                    Connection::graph_input(address(node)?)
+                        .from_index(index)
+                        .to_index(input)
+                }
+                (PatchSource::GraphInput(_), PatchSink::GraphOutput(_)) => {
+                    return Err(PatchError::GraphInputToGraphOutput)
+                }
+            };
+            graph.connect(connection.gain(edge.gain))?;
+        }
+        Ok(graph)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::{Curve, Envelope, EnvelopeGen};
+    use crate::graph::{Mult, Ramp};
+    use crate::wavetable::Oscillator;
+    use crate::{Resources, ResourcesSettings};
+    use std::time::Duration;
+
+    fn make_gen(node: &PatchNode) -> Option<Box<dyn Gen + Send>> {
+        match (node.gen.as_str(), node.resources.first()) {
+            ("Mult", _) => Some(Box::new(Mult)),
+            ("Ramp", _) => Some(Box::new(Ramp::new())),
+            ("Oscillator", Some(ResourceRef::Wavetable(key))) => {
+                Some(Box::new(Oscillator::new(*key)))
+            }
+            _ => None,
+        }
+    }
+
+    fn example_graph(resources: &mut Resources) -> Graph {
+        let mut graph = Graph::new(GraphSettings {
+            num_inputs: 1,
+            ..Default::default()
+        });
+        let wavetable = resources
+            .insert_wavetable(crate::wavetable::Wavetable::sine())
+            .unwrap();
+        let osc = graph.push_gen(Oscillator::new(wavetable));
+        let mult = graph.push_gen(Mult);
+        graph.set_node_name(mult, "amp").unwrap();
+        graph.connect(constant(220.0).to(osc)).unwrap();
+        graph.connect(osc.to(mult)).unwrap();
+        graph
+            .connect(mult.to(osc).feedback(true).gain(0.5))
+            .unwrap();
+        graph
+            .connect(Connection::graph_input(mult).to_index(1))
+            .unwrap();
+        let mut inner = Graph::new(GraphSettings {
+            num_inputs: 1,
+            ..Default::default()
+        });
+        let ramp = inner.push_gen(Ramp::new());
+        inner
+            .connect(
+                constant(0.5)
+                    .to(ramp)
+                    .to_index(1)
+                    .smoothing(Smoothing::Linear(Duration::from_millis(10))),
+            )
+            .unwrap();
+        inner.connect(Connection::graph_output(ramp)).unwrap();
+        let inner = graph.push_graph(inner);
+        graph.connect(mult.to(inner)).unwrap();
+        graph
+            .connect(Connection::graph_output(inner).to_index(1))
+            .unwrap();
+        graph
+    }
+
+    #[test]
+    fn patch_round_trip() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let graph = example_graph(&mut resources);
+        let patch = graph.to_patch();
+        assert_eq!(patch.nodes.len(), 3);
+        assert_eq!(patch.nodes[0].constants[0], 220.0);
+        assert!(matches!(
+            patch.nodes[0].resources[..],
+            [ResourceRef::Wavetable(_)]
+        ));
+        assert!(patch.nodes[1].resources.is_empty());
+        assert_eq!(patch.nodes[1].name.as_deref(), Some("amp"));
+        assert!(patch.edges.contains(&PatchEdge {
+            source: PatchSource::Node { node: 1, output: 0 },
+            sink: PatchSink::Node { node: 0, input: 0 },
+            feedback: true,
+            gain: 0.5,
+        }));
+        let inner = patch.nodes[2].graph.as_ref().unwrap();
+        assert_eq!(
+            inner.nodes[0].smoothing[1],
+            Smoothing::Linear(Duration::from_millis(10))
+        );
+        let loaded = patch.to_graph(GraphSettings::default(), make_gen).unwrap();
+        assert_eq!(loaded.to_patch(), patch);
+        let missing = patch.to_graph(GraphSettings::default(), |_| None);
+        assert_eq!(
+            missing.err(),
+            Some(PatchError::UnknownGen("Oscillator".to_string()))
+        );
+    }
+
+    #[test]
+    fn gen_state_round_trip() {
+        fn make_envelope(node: &PatchNode) -> Option<Box<dyn Gen + Send>> {
+            match node.gen.as_str() {
+                "Envelope" => Some(Box::new(Envelope::default().to_gen())),
+                _ => None,
+            }
+        }
+        let mut graph = Graph::new(GraphSettings::default());
+        let envelope = EnvelopeGen::new(0.5, vec![(1.0, 0.1), (0.0, 2.0)], 44100.0)
+            .curves(vec![Curve::Linear, Curve::Exponential(2.0)]);
+        graph.push_gen(envelope);
+        let patch = graph.to_patch();
+        let state = patch.nodes[0].state.as_ref().unwrap();
+        assert_eq!(
+            state.get("points"),
+            Some(&SavedState::Samples(vec![1.0, 0.1, 0.0, 2.0]))
+        );
+        let loaded = patch
+            .to_graph(GraphSettings::default(), make_envelope)
+            .unwrap();
+        assert_eq!(loaded.to_patch(), patch);
+        let mut invalid = patch.clone();
+        invalid.nodes[0].state = Some(SavedState::Number(1.0));
+        assert_eq!(
+            invalid
+                .to_graph(GraphSettings::default(), make_envelope)
+                .err(),
+            Some(PatchError::InvalidState("Envelope"))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn patch_to_json() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let patch = example_graph(&mut resources).to_patch();
+        let json = serde_json::to_string(&patch).unwrap();
+        let loaded: Patch = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, patch);
+    }
+}
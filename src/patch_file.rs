@@ -0,0 +1,499 @@
+//! A versioned file format for [`Patch`]es.
+//!
+//! A [`PatchFile`] is a [`Patch`] with a [`PatchHeader`] describing what is
+//! needed to load it: the version of the format and of knyst it was saved
+//! with, the names of the Gens that have to be registered in the
+//! [`GenRegistry`] and the files that the buffers and wavetables of the
+//! Patch are loaded from. With the `serde` feature enabled it can be
+//! serialized like a [`Patch`].
+//!
+//! When the Gens of knyst change in a way that makes old patches load
+//! incorrectly, e.g. a Gen is renamed or its inputs are reordered,
+//! [`FORMAT_VERSION`] is increased and a migration from the previous version
+//! is added to [`Migrations::new`]. Applications can register their own
+//! migrations for the same versions, e.g. for their own Gens.
+//!
+//! The resources are loaded by a [`ResourceLoader`], which looks for files
+//! with relative paths in a list of search paths and can report its progress.
+//! Loading them gives them new keys, so the Patch is updated to use the new
+//! keys before the Graph is created.
+//!
+//! Loading a saved patch:
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::patch_file::{Migrations, PatchFile, ResourceLoader};
+//! # use knyst::registry::GenRegistry;
+//! # let mut resources = Resources::new(ResourcesSettings::default());
+//! # let mut file = PatchFile::new(Graph::new(GraphSettings::default()).to_patch());
+//! // let mut file: PatchFile = serde_json::from_str(&json)?;
+//! Migrations::new().migrate(&mut file).unwrap();
+//! ResourceLoader::new()
+//!     .search_path("samples")
+//!     .on_progress(|progress| println!("{}/{}", progress.loaded, progress.total))
+//!     .load(&mut file, &mut resources)
+//!     .unwrap();
+//! let graph = file
+//!     .to_graph(GraphSettings::default(), &GenRegistry::with_defaults())
+//!     .unwrap();
+//! ```
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use symphonia::core::errors::Error as SymphoniaError;
+
+use crate::buffer::Buffer;
+use crate::graph::{Graph, GraphSettings};
+use crate::patch::{Patch, PatchError, ResourceRef};
+use crate::registry::GenRegistry;
+use crate::wavetable::{first_channel, Wavetable, WavetableBank, WavetableBankError};
+use crate::{Resources, ResourcesError};
+
+/// The current version of the patch file format
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(thiserror::Error, Debug)]
+pub enum PatchFileError {
+    #[error(
+        "The patch has format version {0}, which is newer than this version of knyst supports."
+    )]
+    UnsupportedVersion(u32),
+    #[error("There is no migration from format version {0}.")]
+    MissingMigration(u32),
+    #[error("No Gens are registered with the names {0:?}.")]
+    MissingGens(Vec<String>),
+    #[error("The resource {0:?} can't be loaded from {1:?}.")]
+    ResourceMismatch(ResourceRef, ResourceSource),
+    #[error("The file {0:?} was not found in any of the search paths.")]
+    FileNotFound(PathBuf),
+    #[error("The file {0:?} has no cycle {1}.")]
+    MissingCycle(PathBuf, usize),
+    #[error("Unable to load the sound file: {0}")]
+    SoundFile(#[from] SymphoniaError),
+    #[error(transparent)]
+    WavetableBank(#[from] WavetableBankError),
+    #[error(transparent)]
+    Resources(#[from] ResourcesError),
+    #[error(transparent)]
+    Patch(#[from] PatchError),
+}
+
+/// Where a resource of a Patch is loaded from
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ResourceSource {
+    /// A sound file loaded into a [`Buffer`]
+    SoundFile(PathBuf),
+    /// A sound file with a single cycle waveform, loaded into a
+    /// [`Wavetable`] using [`Wavetable::from_single_cycle`]
+    SingleCycleFile(PathBuf),
+    /// One wavetable of a [`WavetableBank`] loaded from a file with cycles of
+    /// `cycle_length` samples after each other. The file is only loaded once
+    /// for all the wavetables of the bank.
+    BankCycle {
+        path: PathBuf,
+        cycle_length: usize,
+        index: usize,
+    },
+}
+
+/// A resource referred to by the Patch and where to load it from
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ResourceEntry {
+    /// The resource as it is referred to in the Patch
+    pub resource: ResourceRef,
+    pub source: ResourceSource,
+}
+
+/// Everything needed to load a [`PatchFile`] except the Patch itself
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchHeader {
+    /// The version of the format, see [`FORMAT_VERSION`]
+    pub format_version: u32,
+    /// The version of knyst the patch was saved with
+    pub crate_version: String,
+    /// The names of the Gens in the Patch
+    pub gens: Vec<String>,
+    /// The resources to load before creating the Graph
+    pub resources: Vec<ResourceEntry>,
+}
+
+/// A [`Patch`] with a [`PatchHeader`], see the
+/// [module documentation](self).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PatchFile {
+    pub header: PatchHeader,
+    pub patch: Patch,
+}
+
+impl PatchFile {
+    /// A patch file with the current format and crate versions
+    pub fn new(patch: Patch) -> Self {
+        Self {
+            header: PatchHeader {
+                format_version: FORMAT_VERSION,
+                crate_version: env!("CARGO_PKG_VERSION").to_string(),
+                gens: patch.gen_names(),
+                resources: vec![],
+            },
+            patch,
+        }
+    }
+    /// Add a file to load `resource` from
+    pub fn resource(mut self, resource: ResourceRef, source: ResourceSource) -> Self {
+        self.header
+            .resources
+            .push(ResourceEntry { resource, source });
+        self
+    }
+    /// The Gens in the header that aren't registered in `registry`
+    pub fn missing_gens(&self, registry: &GenRegistry) -> Vec<String> {
+        self.header
+            .gens
+            .iter()
+            .filter(|name| !registry.contains(name))
+            .cloned()
+            .collect()
+    }
+    /// Load every resource in the header into `resources` using a
+    /// [`ResourceLoader`] without search paths.
+    pub fn load_resources(&mut self, resources: &mut Resources) -> Result<(), PatchFileError> {
+        ResourceLoader::new().load(self, resources)
+    }
+    /// Create the Graph using the Gens in `registry`. Returns
+    /// [`PatchFileError::MissingGens`] if any Gen in the header isn't
+    /// registered.
+    pub fn to_graph(
+        &self,
+        settings: GraphSettings,
+        registry: &GenRegistry,
+    ) -> Result<Graph, PatchFileError> {
+        let missing = self.missing_gens(registry);
+        if !missing.is_empty() {
+            return Err(PatchFileError::MissingGens(missing));
+        }
+        Ok(self
+            .patch
+            .to_graph(settings, |node| registry.create_for_patch(node))?)
+    }
+}
+
+/// The progress of a [`ResourceLoader`], reported after every resource
+#[derive(Debug, Clone, Copy)]
+pub struct LoadProgress<'a> {
+    /// The number of resources loaded so far
+    pub loaded: usize,
+    pub total: usize,
+    /// The file the last resource was loaded from
+    pub path: &'a Path,
+}
+
+/// Called with the progress of a [`ResourceLoader`]
+type ProgressCallback<'a> = Box<dyn FnMut(LoadProgress) + 'a>;
+
+/// Loads the resources of a [`PatchFile`], see the
+/// [module documentation](self).
+#[derive(Default)]
+pub struct ResourceLoader<'a> {
+    search_paths: Vec<PathBuf>,
+    progress: Option<ProgressCallback<'a>>,
+}
+
+impl<'a> ResourceLoader<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add a directory to look for files with relative paths in. Search
+    /// paths are searched in the order they were added, before the current
+    /// directory.
+    pub fn search_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.search_paths.push(path.into());
+        self
+    }
+    /// Call `progress` after every resource that has been loaded
+    pub fn on_progress(mut self, progress: impl FnMut(LoadProgress) + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+    /// The path of an existing file for `path`
+    pub fn resolve(&self, path: &Path) -> Result<PathBuf, PatchFileError> {
+        if path.is_relative() {
+            for search_path in &self.search_paths {
+                let candidate = search_path.join(path);
+                if candidate.is_file() {
+                    return Ok(candidate);
+                }
+            }
+        }
+        if path.is_file() {
+            Ok(path.to_path_buf())
+        } else {
+            Err(PatchFileError::FileNotFound(path.to_path_buf()))
+        }
+    }
+    /// Load every resource in the header of `file` into `resources` and
+    /// update the Patch and the header to refer to the newly loaded
+    /// resources.
+    pub fn load(
+        &mut self,
+        file: &mut PatchFile,
+        resources: &mut Resources,
+    ) -> Result<(), PatchFileError> {
+        let total = file.header.resources.len();
+        let mut loaded = HashMap::with_capacity(total);
+        let mut banks: HashMap<(PathBuf, usize), Vec<Wavetable>> = HashMap::new();
+        for (i, entry) in file.header.resources.iter_mut().enumerate() {
+            let (new_resource, path) = match (entry.resource, &entry.source) {
+                (ResourceRef::Buffer(_), ResourceSource::SoundFile(path)) => {
+                    let path = self.resolve(path)?;
+                    let buffer = Buffer::from_sound_file(path.clone())?;
+                    (ResourceRef::Buffer(resources.insert_buffer(buffer)?), path)
+                }
+                (ResourceRef::Wavetable(_), ResourceSource::SingleCycleFile(path)) => {
+                    let path = self.resolve(path)?;
+                    let buffer = Buffer::from_sound_file(path.clone())?;
+                    let wavetable = Wavetable::from_single_cycle(&first_channel(&buffer));
+                    (
+                        ResourceRef::Wavetable(resources.insert_wavetable(wavetable)?),
+                        path,
+                    )
+                }
+                (
+                    ResourceRef::Wavetable(_),
+                    ResourceSource::BankCycle {
+                        path,
+                        cycle_length,
+                        index,
+                    },
+                ) => {
+                    let path = self.resolve(path)?;
+                    let wavetables = match banks.entry((path.clone(), *cycle_length)) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => entry.insert(
+                            WavetableBank::from_concatenated_sound_file(&path, *cycle_length)?
+                                .into_wavetables(),
+                        ),
+                    };
+                    let wavetable = wavetables
+                        .get(*index)
+                        .cloned()
+                        .ok_or_else(|| PatchFileError::MissingCycle(path.clone(), *index))?;
+                    (
+                        ResourceRef::Wavetable(resources.insert_wavetable(wavetable)?),
+                        path,
+                    )
+                }
+                (resource, source) => {
+                    return Err(PatchFileError::ResourceMismatch(resource, source.clone()))
+                }
+            };
+            loaded.insert(entry.resource, new_resource);
+            entry.resource = new_resource;
+            if let Some(progress) = &mut self.progress {
+                progress(LoadProgress {
+                    loaded: i + 1,
+                    total,
+                    path: &path,
+                });
+            }
+        }
+        file.patch.visit_nodes_mut(&mut |node| {
+            for resource in &mut node.resources {
+                if let Some(&new_resource) = loaded.get(resource) {
+                    *resource = new_resource;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+type Migration = Box<dyn Fn(&mut PatchFile) -> Result<(), PatchFileError> + Send + Sync>;
+
+/// Migrations of [`PatchFile`]s from older format versions, see the
+/// [module documentation](self).
+pub struct Migrations {
+    /// The migrations from every version to the next, in the order they were
+    /// registered
+    migrations: HashMap<u32, Vec<Migration>>,
+}
+
+impl Migrations {
+    /// The migrations of the Gens in knyst
+    pub fn new() -> Self {
+        Self {
+            migrations: HashMap::new(),
+        }
+    }
+    /// Register a migration from format `version` to `version + 1`. It runs
+    /// after the migrations already registered for the same version.
+    pub fn register(
+        &mut self,
+        version: u32,
+        migration: impl Fn(&mut PatchFile) -> Result<(), PatchFileError> + Send + Sync + 'static,
+    ) {
+        self.migrations
+            .entry(version)
+            .or_default()
+            .push(Box::new(migration));
+    }
+    /// Migrate `file` to [`FORMAT_VERSION`] one version at a time.
+    pub fn migrate(&self, file: &mut PatchFile) -> Result<(), PatchFileError> {
+        if file.header.format_version > FORMAT_VERSION {
+            return Err(PatchFileError::UnsupportedVersion(
+                file.header.format_version,
+            ));
+        }
+        while file.header.format_version < FORMAT_VERSION {
+            let version = file.header.format_version;
+            let migrations = self
+                .migrations
+                .get(&version)
+                .ok_or(PatchFileError::MissingMigration(version))?;
+            for migration in migrations {
+                migration(file)?;
+            }
+            file.header.format_version = version + 1;
+        }
+        file.header.gens = file.patch.gen_names();
+        Ok(())
+    }
+}
+
+impl Default for Migrations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{Connection, Mult};
+    use crate::recorder::WavWriter;
+    use crate::wavetable::Oscillator;
+    use crate::ResourcesSettings;
+
+    fn example_file(resources: &mut Resources) -> PatchFile {
+        let wavetable = resources.insert_wavetable(Wavetable::sine()).unwrap();
+        let mut graph = Graph::new(GraphSettings::default());
+        let osc = graph.push_gen(Oscillator::new(wavetable));
+        let mult = graph.push_gen(Mult);
+        graph.connect(osc.to(mult)).unwrap();
+        graph.connect(Connection::graph_output(mult)).unwrap();
+        PatchFile::new(graph.to_patch()).resource(
+            ResourceRef::Wavetable(wavetable),
+            ResourceSource::SingleCycleFile("saw.wav".into()),
+        )
+    }
+
+    #[test]
+    fn header_describes_the_patch() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let file = example_file(&mut resources);
+        assert_eq!(file.header.format_version, FORMAT_VERSION);
+        assert_eq!(file.header.gens, ["Mult", "Oscillator"]);
+        let mut registry = GenRegistry::with_defaults();
+        assert!(file.missing_gens(&registry).is_empty());
+        assert!(file.to_graph(GraphSettings::default(), &registry).is_ok());
+        registry.unregister("Oscillator");
+        assert!(matches!(
+            file.to_graph(GraphSettings::default(), &registry),
+            Err(PatchFileError::MissingGens(missing)) if missing == ["Oscillator"]
+        ));
+        let mut mismatched = file.clone();
+        mismatched.header.resources[0].source = ResourceSource::SoundFile("saw.wav".into());
+        assert!(matches!(
+            mismatched.load_resources(&mut resources),
+            Err(PatchFileError::ResourceMismatch(..))
+        ));
+    }
+
+    #[test]
+    fn migrate_old_versions() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut file = example_file(&mut resources);
+        file.header.format_version = FORMAT_VERSION - 1;
+        let mut migrations = Migrations::new();
+        assert!(matches!(
+            migrations.migrate(&mut file.clone()),
+            Err(PatchFileError::MissingMigration(version)) if version == FORMAT_VERSION - 1
+        ));
+        // The Gen was renamed after the patch was saved
+        migrations.register(FORMAT_VERSION - 1, |file| {
+            file.patch.visit_nodes_mut(&mut |node| {
+                if node.gen == "Mult" {
+                    node.gen = "Multiply".to_string();
+                }
+            });
+            Ok(())
+        });
+        migrations.migrate(&mut file).unwrap();
+        assert_eq!(file.header.format_version, FORMAT_VERSION);
+        assert_eq!(file.header.gens, ["Multiply", "Oscillator"]);
+        file.header.format_version = FORMAT_VERSION + 1;
+        assert!(matches!(
+            migrations.migrate(&mut file),
+            Err(PatchFileError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn load_resources_from_search_paths() {
+        let dir = std::env::temp_dir().join(format!("knyst_patch_file_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = WavWriter::create(&dir.join("cycles.wav"), 1, 48000).unwrap();
+        writer
+            .write_samples(&[0.0, 1.0, 0.0, -1.0, 1.0, 1.0, -1.0, -1.0])
+            .unwrap();
+        writer.finalize().unwrap();
+
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut file = example_file(&mut resources);
+        let old = file.header.resources[0].resource;
+        file.header.resources[0].source = ResourceSource::BankCycle {
+            path: "cycles.wav".into(),
+            cycle_length: 4,
+            index: 1,
+        };
+        let mut missing_cycle = file.clone();
+        missing_cycle.header.resources[0].source = ResourceSource::BankCycle {
+            path: "cycles.wav".into(),
+            cycle_length: 4,
+            index: 2,
+        };
+        assert!(matches!(
+            file.clone().load_resources(&mut resources),
+            Err(PatchFileError::FileNotFound(_))
+        ));
+        let mut reports = vec![];
+        ResourceLoader::new()
+            .search_path(&dir)
+            .on_progress(|progress| reports.push((progress.loaded, progress.total)))
+            .load(&mut file, &mut resources)
+            .unwrap();
+        let missing_cycle = ResourceLoader::new()
+            .search_path(&dir)
+            .load(&mut missing_cycle, &mut resources);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(reports, [(1, 1)]);
+        let new = file.header.resources[0].resource;
+        assert_ne!(new, old);
+        assert_eq!(file.patch.nodes[0].resources, [new]);
+        assert!(
+            matches!(new, ResourceRef::Wavetable(key) if resources.wavetables.get(key).is_some())
+        );
+        assert!(matches!(
+            missing_cycle,
+            Err(PatchFileError::MissingCycle(_, 2))
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+//! Running a Graph as the DSP core of an audio plugin.
+//!
+//! knyst doesn't depend on a plugin framework and there is no nih-plug
+//! feature. A [`PluginProcessor`] is framework agnostic: it is driven by the
+//! process callback of a plugin built with a framework such as
+//! [nih-plug](https://github.com/robbert-vdh/nih-plug), which handles the
+//! CLAP/VST3 side, including the plugin parameters and the conversion of
+//! host events. The processor takes care of what differs from running the
+//! Graph through an [`AudioBackend`](crate::audio_backend::AudioBackend):
+//!
+//! - Plugin parameters are mapped to Graph inputs. The Graph has the audio
+//!   inputs of the plugin first, followed by one input per parameter, so
+//!   parameter `n` can be connected from `Connection::graph_input(node).from_index(num_audio_inputs + n)`.
+//! - The host can call the plugin with any number of frames. The Graph is
+//!   always processed with its own block size which adds a constant latency
+//!   of one block, see [`PluginProcessor::latency`], that should be reported
+//!   to the host.
+//! - MIDI from the host is not delivered inside the Graph. It is sent as
+//!   [`MidiEvent`]s to the control thread, e.g. to a
+//!   [`VoiceAllocator`](crate::voice::VoiceAllocator), in the same way as for
+//!   the JACK backend. The timestamps are sample accurate so the events can
+//!   be scheduled at the time they arrived plus some latency, see the
+//!   [`midi`](crate::midi) module.
+//!
+//! The Graph itself stays with the plugin, outside of the audio thread, where
+//! [`Graph::update`] should be called regularly, e.g. from the editor or a
+//! background task.
+//!
+//! With nih-plug, the process function of the plugin could look like this:
+//!
+//! ```ignore
+//! fn process(&mut self, buffer: &mut Buffer, _: &mut AuxiliaryBuffers, context: &mut impl ProcessContext<Self>) -> ProcessStatus {
+//!     while let Some(event) = context.next_event() {
+//!         if let NoteEvent::NoteOn { timing, channel, note, velocity, .. } = event {
+//!             let velocity = (velocity * 127.0) as u8;
+//!             self.processor.receive_midi(timing as usize, MidiMessage::NoteOn { channel, note, velocity });
+//!         }
+//!     }
+//!     self.processor.set_parameter(0, self.params.cutoff.smoothed.next());
+//!     self.processor.process(buffer.as_slice());
+//!     ProcessStatus::Normal
+//! }
+//! ```
+
+use std::cell::RefCell;
+
+use crate::audio_backend::{event_channels, BackendEventReceiver, BlockAdapter, ProcessGuard};
+use crate::graph::{Graph, ToNodeError};
+use crate::midi::{MidiEvent, MidiMessage};
+use crate::{Resources, Sample};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PluginError {
+    #[error("Unable to create a node from the Graph: {0}")]
+    CouldNotCreateNode(ToNodeError),
+    #[error(
+        "The Graph has {graph_inputs} inputs, which is not enough for {parameters} parameters."
+    )]
+    NotEnoughInputs {
+        graph_inputs: usize,
+        parameters: usize,
+    },
+}
+
+/// Processes a [`Graph`] inside a plugin, see the [module documentation](self).
+pub struct PluginProcessor {
+    adapter: BlockAdapter,
+    guard: ProcessGuard,
+    resources: Resources,
+    num_audio_inputs: usize,
+    parameters: Vec<Sample>,
+    midi: Option<rtrb::Producer<MidiEvent>>,
+    /// The number of frames processed since processing started
+    sample_counter: u64,
+}
+
+impl PluginProcessor {
+    /// *Allocates memory*
+    /// Create a processor for `graph` with `num_parameters` parameters. The
+    /// last `num_parameters` inputs of the Graph are used for the parameters.
+    /// Also returns a receiver of panics while processing the Graph.
+    pub fn new(
+        graph: &mut Graph,
+        resources: Resources,
+        num_parameters: usize,
+    ) -> Result<(Self, BackendEventReceiver), PluginError> {
+        let node = graph.to_node().map_err(PluginError::CouldNotCreateNode)?;
+        let graph_inputs = node.num_inputs();
+        if graph_inputs < num_parameters {
+            return Err(PluginError::NotEnoughInputs {
+                graph_inputs,
+                parameters: num_parameters,
+            });
+        }
+        let ([events], receiver) = event_channels();
+        Ok((
+            Self {
+                adapter: BlockAdapter::buffered(node, graph.block_size()),
+                guard: ProcessGuard::new(events),
+                resources,
+                num_audio_inputs: graph_inputs - num_parameters,
+                parameters: vec![0.0; num_parameters],
+                midi: None,
+                sample_counter: 0,
+            },
+            receiver,
+        ))
+    }
+    /// *Allocates memory*
+    /// Returns a receiver of the MIDI events passed to
+    /// [`PluginProcessor::receive_midi`]. `capacity` is the number of events
+    /// the channel can hold, events are dropped if it is full.
+    pub fn enable_midi(&mut self, capacity: usize) -> rtrb::Consumer<MidiEvent> {
+        let (producer, consumer) = rtrb::RingBuffer::new(capacity);
+        self.midi = Some(producer);
+        consumer
+    }
+    /// The latency of the processor in frames
+    pub fn latency(&self) -> usize {
+        self.adapter.latency()
+    }
+    /// Set the value of a parameter from the next call to
+    /// [`PluginProcessor::process`]. Parameters that don't exist are ignored.
+    pub fn set_parameter(&mut self, index: usize, value: Sample) {
+        if let Some(parameter) = self.parameters.get_mut(index) {
+            *parameter = value;
+        }
+    }
+    /// Pass on a MIDI message `offset` frames into the next call to
+    /// [`PluginProcessor::process`]
+    pub fn receive_midi(&mut self, offset: usize, message: MidiMessage) {
+        if let Some(midi) = &mut self.midi {
+            midi.push(MidiEvent {
+                timestamp: self.sample_counter + offset as u64,
+                message,
+            })
+            .ok();
+        }
+    }
+    /// Notify the Graph and the Resources of a new sample rate
+    pub fn set_sample_rate(&mut self, sample_rate: Sample) {
+        self.adapter.node_mut().sample_rate_changed(sample_rate);
+        self.resources.set_sample_rate(sample_rate);
+    }
+    /// Process the channels in `buffers` in place, using them as the audio
+    /// inputs of the Graph and replacing them with its outputs.
+    pub fn process(&mut self, buffers: &mut [&mut [Sample]]) {
+        let frames = buffers.first().map_or(0, |channel| channel.len());
+        let num_audio_inputs = self.num_audio_inputs;
+        let parameters = &self.parameters;
+        // Inputs are read before outputs are written to the same frames
+        let buffers = RefCell::new(buffers);
+        self.adapter.process(
+            frames,
+            &mut self.guard,
+            &mut self.resources,
+            |channel, frames, buffer| {
+                if channel >= num_audio_inputs {
+                    buffer.fill(parameters[channel - num_audio_inputs]);
+                } else if let Some(input) = buffers.borrow().get(channel) {
+                    buffer.copy_from_slice(&input[frames]);
+                } else {
+                    buffer.fill(0.0);
+                }
+            },
+            |channel, frames, samples| {
+                if let Some(output) = buffers.borrow_mut().get_mut(channel) {
+                    output[frames].copy_from_slice(samples);
+                }
+            },
+        );
+        self.sample_counter += frames as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{Connection, GraphSettings, Mult};
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn parameters_and_audio_inputs() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_inputs: 2,
+            num_outputs: 1,
+            ..Default::default()
+        });
+        let mult = graph.push_gen(Mult);
+        graph.connect(Connection::graph_input(mult)).unwrap();
+        graph
+            .connect(Connection::graph_input(mult).from_index(1).to_index(1))
+            .unwrap();
+        graph.connect(Connection::graph_output(mult)).unwrap();
+        let resources = Resources::new(ResourcesSettings::default());
+        let (mut processor, _events) = PluginProcessor::new(&mut graph, resources, 1).unwrap();
+        graph.commit_changes();
+        graph.update();
+        let mut midi = processor.enable_midi(4);
+        processor.receive_midi(
+            2,
+            MidiMessage::NoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 100,
+            },
+        );
+        assert_eq!(midi.pop().unwrap().timestamp, 2);
+        assert_eq!(processor.latency(), 4);
+        processor.set_parameter(0, 0.5);
+        let mut output = vec![];
+        for frames in [3, 5, 4] {
+            let mut channel = vec![2.0; frames];
+            processor.process(&mut [&mut channel]);
+            output.extend(channel);
+        }
+        // Delayed by one block of 4 frames
+        assert_eq!(output[..4], [0.0; 4]);
+        assert_eq!(output[4..], [1.0; 8]);
+    }
+}
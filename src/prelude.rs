@@ -1,8 +1,12 @@
 pub use crate::audio_backend::AudioBackend;
 pub use crate::buffer::{Buffer, BufferKey, BufferReader};
 pub use crate::graph::{
-    constant, gen, Connection, Graph, GraphInput, GraphSettings, Mult, PanMonoToStereo,
-    ParameterChange, Ramp,
+    constant, gen, Connection, Graph, GraphInput, GraphSettings, InputMode, Mult, PanMonoToStereo,
+    ParameterChange, Ramp, Rate, Smoothing,
 };
+pub use crate::scheduling::{Beats, TempoChange, TransportState};
+pub use crate::sequencer::{Pattern, PatternEvent, Sequencer};
 pub use crate::wavetable::{Wavetable, WavetableKey, TABLE_POWER, TABLE_SIZE};
-pub use crate::{AnyData, Resources, ResourcesSettings, Sample, StopAction};
+pub use crate::{
+    AnyData, Resources, ResourcesCommandSender, ResourcesSettings, Sample, StopAction, UserDataKey,
+};
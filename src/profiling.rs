@@ -0,0 +1,108 @@
+//! Per node timing for finding what is expensive in a Graph, enabled with
+//! the `profiling` feature.
+//!
+//! While the feature is enabled every call to [`Node::process`] is timed.
+//! [`Graph::profiling_report`] collects the times of all the nodes in a Graph
+//! and its inner Graphs, most expensive first, together with the DSP load:
+//! the time spent processing the Graph relative to the duration of the
+//! audio in a block. A load close to 1.0 means the audio thread is about to
+//! miss its deadline.
+//!
+//! ```ignore
+//! // ...
+//! println!("{}", graph.profiling_report());
+//! graph.reset_profiling();
+//! ```
+//!
+//! Timing a node is two calls to [`Instant::now`] and a few atomic
+//! operations, which is cheap but not free for very small nodes.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::graph::NodeAddress;
+#[allow(unused)]
+use crate::graph::{Graph, Node};
+
+/// The accumulated process times of one node, written from the audio thread
+#[derive(Debug, Default)]
+pub(crate) struct ProcessTimes {
+    calls: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl ProcessTimes {
+    #[inline]
+    pub(crate) fn record(&self, start: Instant) {
+        let nanos = start.elapsed().as_nanos() as u64;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+    pub(crate) fn reset(&self) {
+        self.calls.store(0, Ordering::Relaxed);
+        self.total_nanos.store(0, Ordering::Relaxed);
+        self.max_nanos.store(0, Ordering::Relaxed);
+    }
+    pub(crate) fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+    pub(crate) fn average(&self) -> Duration {
+        let calls = self.calls();
+        if calls == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed) / calls)
+    }
+    pub(crate) fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// The process times of a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeProfile {
+    pub address: NodeAddress,
+    /// The name of the Gen
+    pub name: &'static str,
+    /// The number of times the node has been processed
+    pub calls: u64,
+    pub average: Duration,
+    pub max: Duration,
+}
+
+/// Process times of all the nodes in a Graph, see the
+/// [module documentation](self).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfilingReport {
+    /// All nodes, including nodes in inner Graphs, with the highest average
+    /// process time first. An inner Graph node includes the time of its nodes.
+    pub nodes: Vec<NodeProfile>,
+    /// The average time processing the Graph took relative to the duration
+    /// of a block. Only measured for a Graph that has been turned into a node.
+    pub average_load: f64,
+    /// The highest time processing the Graph took relative to the duration
+    /// of a block
+    pub max_load: f64,
+}
+
+impl Display for ProfilingReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "DSP load: {:.1}% average, {:.1}% max",
+            self.average_load * 100.,
+            self.max_load * 100.
+        )?;
+        for node in &self.nodes {
+            writeln!(
+                f,
+                "{:>24}: {:>10.2?} average, {:>10.2?} max, {} calls",
+                node.name, node.average, node.max, node.calls
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+//! Recording signals to disk while the Graph is running.
+//!
+//! A [`DiskRecorder`] node passes its inputs through to its outputs and, while
+//! recording, sends them to a background thread that writes them to a 32 bit
+//! float WAV file. Connect it to the graph outputs to capture a performance,
+//! or to any node to record just that node. Files can be converted to a
+//! compressed format such as FLAC afterwards.
+//!
+//! The audio thread never waits for the disk. If the background thread falls
+//! behind and the ring buffer between them is full, whole blocks are dropped
+//! and counted in [`RecorderHandle::dropped_frames`].
+//!
+//! ```no_run
+//! # use knyst::prelude::*;
+//! # use knyst::recorder::DiskRecorder;
+//! # let mut graph = Graph::new(GraphSettings { num_outputs: 2, ..Default::default() });
+//! # let source = graph.push_gen(knyst::noise::WhiteNoise::new());
+//! let (recorder, mut handle) = DiskRecorder::new("performance.wav", 2, 44100.).unwrap();
+//! let recorder = graph.push_gen(recorder);
+//! graph.connect(source.to(recorder).channels(2)).unwrap();
+//! graph.connect(Connection::graph_output(recorder).channels(2)).unwrap();
+//! handle.start();
+//! // ...
+//! handle.stop();
+//! handle.finish().unwrap();
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::graph::{Gen, GenState};
+use crate::{sample_to_f32, Resources, Sample};
+
+/// The number of seconds of audio that fit in the ring buffer to the
+/// background thread
+const BUFFER_SECONDS: Sample = 2.0;
+
+const INPUT_NAMES: [&str; 8] = ["in0", "in1", "in2", "in3", "in4", "in5", "in6", "in7"];
+const OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum RecorderError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("The recording thread panicked.")]
+    ThreadPanicked,
+}
+
+/// Passes its inputs through and records them, see the
+/// [module documentation](self).
+pub struct DiskRecorder {
+    num_channels: usize,
+    samples: rtrb::Producer<f32>,
+    shared: Arc<RecorderShared>,
+}
+
+/// State shared between the node, the handle and the background thread
+struct RecorderShared {
+    recording: AtomicBool,
+    finished: AtomicBool,
+    dropped_frames: AtomicU64,
+}
+
+impl DiskRecorder {
+    /// *Allocates memory*
+    /// Create the file at `path` and start the background thread writing to
+    /// it. Nothing is recorded until [`RecorderHandle::start`] is called.
+    pub fn new(
+        path: impl AsRef<Path>,
+        num_channels: usize,
+        sample_rate: Sample,
+    ) -> Result<(Self, RecorderHandle), RecorderError> {
+        let mut writer = WavWriter::create(path.as_ref(), num_channels, sample_rate as u32)?;
+        let capacity = ((sample_rate * BUFFER_SECONDS) as usize * num_channels).max(1);
+        let (samples, mut consumer) = rtrb::RingBuffer::new(capacity);
+        let shared = Arc::new(RecorderShared {
+            recording: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            dropped_frames: AtomicU64::new(0),
+        });
+        let thread_shared = shared.clone();
+        let thread = std::thread::spawn(move || -> Result<(), RecorderError> {
+            loop {
+                // Read the flag before draining so that everything sent
+                // before finishing is written
+                let finished = thread_shared.finished.load(Ordering::Acquire);
+                let chunk = consumer.read_chunk(consumer.slots()).unwrap();
+                let (first, second) = chunk.as_slices();
+                writer.write_samples(first)?;
+                writer.write_samples(second)?;
+                let written = chunk.len();
+                chunk.commit_all();
+                if finished {
+                    return Ok(writer.finalize()?);
+                }
+                if written == 0 {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        });
+        Ok((
+            Self {
+                num_channels,
+                samples,
+                shared: shared.clone(),
+            },
+            RecorderHandle {
+                shared,
+                thread: Some(thread),
+            },
+        ))
+    }
+}
+
+impl Gen for DiskRecorder {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (output, input) in outputs.iter_mut().zip(inputs.iter()) {
+            output.copy_from_slice(input);
+        }
+        // The Graph may pass more input buffers than the node has inputs
+        let inputs = &inputs[..self.num_channels];
+        if self.shared.recording.load(Ordering::Relaxed) {
+            let frames = inputs.first().map_or(0, |input| input.len());
+            match self.samples.write_chunk_uninit(frames * self.num_channels) {
+                Ok(chunk) => {
+                    chunk.fill_from_iter(
+                        (0..frames)
+                            .flat_map(|i| inputs.iter().map(move |input| sample_to_f32(input[i]))),
+                    );
+                }
+                Err(_) => {
+                    self.shared
+                        .dropped_frames
+                        .fetch_add(frames as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        self.num_channels
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_channels
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        INPUT_NAMES.get(input).copied().unwrap_or("")
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        OUTPUT_NAMES.get(output).copied().unwrap_or("")
+    }
+    fn name(&self) -> &'static str {
+        "DiskRecorder"
+    }
+}
+
+/// Controls a [`DiskRecorder`] from the control thread. Dropping the handle
+/// finishes the file without waiting for it.
+pub struct RecorderHandle {
+    shared: Arc<RecorderShared>,
+    thread: Option<JoinHandle<Result<(), RecorderError>>>,
+}
+
+impl RecorderHandle {
+    /// Start or resume recording from the next block
+    pub fn start(&mut self) {
+        self.shared.recording.store(true, Ordering::Relaxed);
+    }
+    /// Pause recording from the next block
+    pub fn stop(&mut self) {
+        self.shared.recording.store(false, Ordering::Relaxed);
+    }
+    pub fn is_recording(&self) -> bool {
+        self.shared.recording.load(Ordering::Relaxed)
+    }
+    /// The number of frames that were dropped because the background thread
+    /// didn't keep up
+    pub fn dropped_frames(&self) -> u64 {
+        self.shared.dropped_frames.load(Ordering::Relaxed)
+    }
+    /// Stop recording, write what has been recorded and finish the file.
+    /// Blocks until the file is written.
+    pub fn finish(mut self) -> Result<(), RecorderError> {
+        self.stop();
+        self.shared.finished.store(true, Ordering::Release);
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| RecorderError::ThreadPanicked)?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RecorderHandle {
+    fn drop(&mut self) {
+        self.shared.recording.store(false, Ordering::Relaxed);
+        self.shared.finished.store(true, Ordering::Release);
+    }
+}
+
+/// Writes interleaved 32 bit float samples to a WAV file
+pub(crate) struct WavWriter {
+    file: BufWriter<File>,
+    num_channels: u16,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    pub(crate) fn create(
+        path: &Path,
+        num_channels: usize,
+        sample_rate: u32,
+    ) -> std::io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            num_channels: num_channels as u16,
+            data_bytes: 0,
+        };
+        // The sizes are filled in by `finalize`
+        writer.write_header(sample_rate)?;
+        Ok(writer)
+    }
+    fn write_header(&mut self, sample_rate: u32) -> std::io::Result<()> {
+        let block_align = self.num_channels * 4;
+        let file = &mut self.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + self.data_bytes).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16_u32.to_le_bytes())?;
+        // IEEE float
+        file.write_all(&3_u16.to_le_bytes())?;
+        file.write_all(&self.num_channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&32_u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&self.data_bytes.to_le_bytes())?;
+        Ok(())
+    }
+    pub(crate) fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_bytes = self.data_bytes.saturating_add((samples.len() * 4) as u32);
+        Ok(())
+    }
+    pub(crate) fn finalize(mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_bytes).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{constant, Connection, Graph, GraphSettings};
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn record_wav() {
+        let path = std::env::temp_dir().join(format!("knyst_recorder_{}.wav", std::process::id()));
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 4,
+            num_outputs: 2,
+            ..Default::default()
+        });
+        let (recorder, mut handle) = DiskRecorder::new(&path, 2, 48000.).unwrap();
+        let recorder = graph.push_gen(recorder);
+        graph.connect(constant(0.5).to(recorder)).unwrap();
+        graph
+            .connect(constant(-0.25).to(recorder).to_index(1))
+            .unwrap();
+        graph
+            .connect(Connection::graph_output(recorder).channels(2))
+            .unwrap();
+        let mut node = graph.to_node().unwrap();
+        graph.commit_changes();
+        graph.update();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        node.process(&[], &mut resources);
+        handle.start();
+        node.process(&[], &mut resources);
+        node.process(&[], &mut resources);
+        handle.stop();
+        node.process(&[], &mut resources);
+        assert_eq!(node.output_buffers()[1][0], -0.25);
+        assert_eq!(handle.dropped_frames(), 0);
+        handle.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[24..28].try_into().unwrap()), 48000);
+        // 2 blocks of 4 frames with 2 channels of 4 bytes
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 64);
+        assert_eq!(bytes.len(), 44 + 64);
+        let samples: Vec<f32> = bytes[44..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(samples[..4], [0.5, -0.25, 0.5, -0.25]);
+    }
+}
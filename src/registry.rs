@@ -0,0 +1,331 @@
+//! Creating Gens by name.
+//!
+//! A [`GenRegistry`] maps names to functions creating a Gen from a list of
+//! numeric arguments and resources. This is what makes it possible to load a
+//! [`Patch`], or to create nodes from text, e.g. from OSC messages or a
+//! scripting language.
+//!
+//! ```
+//! # use knyst::registry::*;
+//! let mut registry = GenRegistry::with_defaults();
+//! registry.register("noise", |_args| Ok(Box::new(knyst::noise::WhiteNoise::new())));
+//! let noise = registry.create_from_str("noise", &[]).unwrap();
+//! assert_eq!(noise.name(), "WhiteNoise");
+//! let limiter = registry.create_from_str("Limiter 2 0.01", &[]).unwrap();
+//! assert_eq!(limiter.num_outputs(), 2);
+//! ```
+
+use std::collections::HashMap;
+
+use crate::analysis::{EnvelopeFollower, PitchTracker};
+use crate::buffer::{BufferKey, BufferReader, BufferReaderMulti};
+use crate::dynamics::{Dynamics, Limiter};
+use crate::fm::PmOperator;
+use crate::graph::{Add, Gen, Mult, PanMonoToStereo, Ramp};
+use crate::math::{Abs, Clip, Div, LinExp, LinLin, Pow, Sub, Wrap};
+use crate::mixer::Mixer;
+use crate::noise::{BrownNoise, PinkNoise, VioletNoise, WhiteNoise};
+#[allow(unused)]
+use crate::patch::Patch;
+use crate::patch::{PatchNode, ResourceRef};
+use crate::spatial::{Pan2, XFade};
+use crate::trig::{Counter, Latch, Metro, TrigDelay, TrigDivider, TrigToGate};
+use crate::waveshaper::Waveshaper;
+use crate::wavetable::{
+    BankOscillator, Oscillator, PhaseCurve, PhaseDistortion, SyncOscillator, WavetableKey,
+};
+use crate::{sample_to_f64, Sample, StopAction};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum RegistryError {
+    #[error("No Gen is registered with the name `{0}`.")]
+    UnknownGen(String),
+    #[error("The Gen needs a {0} resource that was not given.")]
+    MissingResource(&'static str),
+    #[error("The argument `{0}` is not a number.")]
+    InvalidArgument(String),
+    #[error("The command is empty.")]
+    EmptyCommand,
+}
+
+/// The arguments given to a Gen constructor in a [`GenRegistry`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenArgs<'a> {
+    pub values: &'a [Sample],
+    pub resources: &'a [ResourceRef],
+}
+
+impl<'a> GenArgs<'a> {
+    pub fn new(values: &'a [Sample], resources: &'a [ResourceRef]) -> Self {
+        Self { values, resources }
+    }
+    /// The value at `index` or `default` if there aren't that many values
+    pub fn value_or(&self, index: usize, default: Sample) -> Sample {
+        self.values.get(index).copied().unwrap_or(default)
+    }
+    /// The first wavetable in the resources
+    pub fn wavetable(&self) -> Result<WavetableKey, RegistryError> {
+        self.wavetables()
+            .next()
+            .ok_or(RegistryError::MissingResource("wavetable"))
+    }
+    pub fn wavetables(&self) -> impl Iterator<Item = WavetableKey> + 'a {
+        self.resources.iter().filter_map(|resource| match resource {
+            ResourceRef::Wavetable(key) => Some(*key),
+            _ => None,
+        })
+    }
+    /// The first buffer in the resources
+    pub fn buffer(&self) -> Result<BufferKey, RegistryError> {
+        self.resources
+            .iter()
+            .find_map(|resource| match resource {
+                ResourceRef::Buffer(key) => Some(*key),
+                _ => None,
+            })
+            .ok_or(RegistryError::MissingResource("buffer"))
+    }
+}
+
+type GenConstructor =
+    Box<dyn Fn(&GenArgs) -> Result<Box<dyn Gen + Send>, RegistryError> + Send + Sync>;
+
+/// A collection of named Gen constructors
+#[derive(Default)]
+pub struct GenRegistry {
+    constructors: HashMap<String, GenConstructor>,
+}
+
+impl GenRegistry {
+    /// An empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// A registry with the Gens in this crate that can be created from
+    /// numbers and resources, registered with the names of the Gens.
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register("Mult", |_| Ok(Box::new(Mult)));
+        registry.register("Add", |_| Ok(Box::new(Add)));
+        registry.register("Sub", |_| Ok(Box::new(Sub)));
+        registry.register("Div", |_| Ok(Box::new(Div)));
+        registry.register("Pow", |_| Ok(Box::new(Pow)));
+        registry.register("Abs", |_| Ok(Box::new(Abs)));
+        registry.register("Clip", |_| Ok(Box::new(Clip)));
+        registry.register("Wrap", |_| Ok(Box::new(Wrap)));
+        registry.register("LinLin", |_| Ok(Box::new(LinLin)));
+        registry.register("LinExp", |_| Ok(Box::new(LinExp)));
+        registry.register("Ramp", |_| Ok(Box::new(Ramp::new())));
+        registry.register("PanMonoToStereo", |_| Ok(Box::new(PanMonoToStereo)));
+        // Mixer num_slots
+        registry.register("Mixer", |args| {
+            Ok(Box::new(
+                Mixer::new(args.value_or(0, 8.0).max(1.0) as usize),
+            ))
+        });
+        registry.register("Pan2", |_| Ok(Box::new(Pan2)));
+        registry.register("XFade", |_| Ok(Box::new(XFade)));
+        registry.register("WhiteNoise", |_| Ok(Box::new(WhiteNoise::new())));
+        registry.register("PinkNoise", |_| Ok(Box::new(PinkNoise::new())));
+        registry.register("BrownNoise", |_| Ok(Box::new(BrownNoise::new())));
+        registry.register("VioletNoise", |_| Ok(Box::new(VioletNoise::new())));
+        registry.register("PmOperator", |_| Ok(Box::new(PmOperator::new())));
+        registry.register("Compressor", |_| Ok(Box::new(Dynamics::compressor())));
+        registry.register("Expander", |_| Ok(Box::new(Dynamics::expander())));
+        registry.register("Gate", |_| Ok(Box::new(Dynamics::gate())));
+        // Limiter num_channels lookahead
+        registry.register("Limiter", |args| {
+            Ok(Box::new(Limiter::new(
+                args.value_or(0, 1.0).max(1.0) as usize,
+                args.value_or(1, 0.005),
+            )))
+        });
+        registry.register("EnvelopeFollower", |_| {
+            Ok(Box::new(EnvelopeFollower::peak()))
+        });
+        // PitchTracker min_freq
+        registry.register("PitchTracker", |args| {
+            Ok(Box::new(PitchTracker::new(args.value_or(0, 50.0))))
+        });
+        registry.register("Metro", |_| Ok(Box::new(Metro::new())));
+        // TrigDelay max_pending
+        registry.register("TrigDelay", |args| {
+            Ok(Box::new(TrigDelay::new(
+                args.value_or(0, 16.0).max(1.0) as usize
+            )))
+        });
+        registry.register("TrigDivider", |_| Ok(Box::new(TrigDivider::new())));
+        registry.register("TrigToGate", |_| Ok(Box::new(TrigToGate::new())));
+        registry.register("Latch", |_| Ok(Box::new(Latch::new())));
+        registry.register("Counter", |_| Ok(Box::new(Counter::new())));
+        registry.register("Waveshaper", |_| Ok(Box::new(Waveshaper::tanh())));
+        registry.register("Oscillator", |args| {
+            Ok(Box::new(Oscillator::new(args.wavetable()?)))
+        });
+        registry.register("BankOscillator", |args| {
+            let wavetables: Vec<_> = args.wavetables().collect();
+            if wavetables.is_empty() {
+                return Err(RegistryError::MissingResource("wavetable"));
+            }
+            Ok(Box::new(BankOscillator::new(wavetables)))
+        });
+        registry.register("SyncOscillator", |args| {
+            Ok(Box::new(SyncOscillator::new(args.wavetable()?)))
+        });
+        // The curve is the first lookup table in the resources, or Bend
+        registry.register("PhaseDistortion", |args| {
+            let curve = args
+                .resources
+                .iter()
+                .find_map(|resource| match resource {
+                    ResourceRef::LookupTable(key) => Some(PhaseCurve::Table(*key)),
+                    _ => None,
+                })
+                .unwrap_or(PhaseCurve::Bend);
+            Ok(Box::new(PhaseDistortion::new(args.wavetable()?, curve)))
+        });
+        // BufferReader rate
+        registry.register("BufferReader", |args| {
+            Ok(Box::new(BufferReader::new(
+                args.buffer()?,
+                sample_to_f64(args.value_or(0, 1.0)),
+                StopAction::Continue,
+            )))
+        });
+        // BufferReaderMulti num_channels rate
+        registry.register("BufferReaderMulti", |args| {
+            Ok(Box::new(
+                BufferReaderMulti::new(
+                    args.buffer()?,
+                    sample_to_f64(args.value_or(1, 1.0)),
+                    StopAction::Continue,
+                )
+                .channels(args.value_or(0, 2.0).max(1.0) as usize),
+            ))
+        });
+        registry
+    }
+    /// Register a constructor under `name`, replacing any constructor
+    /// already registered with the same name.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        constructor: impl Fn(&GenArgs) -> Result<Box<dyn Gen + Send>, RegistryError>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.constructors.insert(name.into(), Box::new(constructor));
+    }
+    pub fn unregister(&mut self, name: &str) {
+        self.constructors.remove(name);
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(name)
+    }
+    /// The registered names in no particular order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.constructors.keys().map(|name| name.as_str())
+    }
+    pub fn create(&self, name: &str, args: &GenArgs) -> Result<Box<dyn Gen + Send>, RegistryError> {
+        match self.constructors.get(name) {
+            Some(constructor) => constructor(args),
+            None => Err(RegistryError::UnknownGen(name.to_string())),
+        }
+    }
+    /// Create a Gen from a command with the name followed by numeric
+    /// arguments separated by whitespace, e.g. "Limiter 2 0.005".
+    pub fn create_from_str(
+        &self,
+        command: &str,
+        resources: &[ResourceRef],
+    ) -> Result<Box<dyn Gen + Send>, RegistryError> {
+        let mut parts = command.split_whitespace();
+        let name = parts.next().ok_or(RegistryError::EmptyCommand)?;
+        let values = parts
+            .map(|part| {
+                part.parse::<Sample>()
+                    .map_err(|_| RegistryError::InvalidArgument(part.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.create(name, &GenArgs::new(&values, resources))
+    }
+    /// Create the Gen for a node in a [`Patch`] from its name and resources.
+    /// Use it with [`Patch::to_graph`]:
+    ///
+    /// ```
+    /// # use knyst::prelude::*;
+    /// # use knyst::registry::GenRegistry;
+    /// # let patch = Graph::new(GraphSettings::default()).to_patch();
+    /// let registry = GenRegistry::with_defaults();
+    /// let graph = patch
+    ///     .to_graph(GraphSettings::default(), |node| registry.create_for_patch(node))
+    ///     .unwrap();
+    /// ```
+    pub fn create_for_patch(&self, node: &PatchNode) -> Option<Box<dyn Gen + Send>> {
+        match self.create(&node.gen, &GenArgs::new(&[], &node.resources)) {
+            Ok(gen) => Some(gen),
+            Err(e) => {
+                eprintln!("Unable to create Gen for node `{}`: {e}", node.gen);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{Connection, Graph, GraphSettings};
+    use crate::{Resources, ResourcesSettings};
+
+    #[test]
+    fn create_by_name() {
+        let mut registry = GenRegistry::with_defaults();
+        assert!(registry.contains("Mult"));
+        assert_eq!(
+            registry.create_from_str("Sine 440", &[]).err(),
+            Some(RegistryError::UnknownGen("Sine".to_string()))
+        );
+        assert_eq!(
+            registry.create_from_str("Limiter two", &[]).err(),
+            Some(RegistryError::InvalidArgument("two".to_string()))
+        );
+        assert_eq!(
+            registry.create_from_str("Oscillator", &[]).err(),
+            Some(RegistryError::MissingResource("wavetable"))
+        );
+        registry.register("sine_wt", |args| {
+            Ok(Box::new(Oscillator::new(args.wavetable()?)))
+        });
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let sine = resources
+            .insert_wavetable(crate::wavetable::Wavetable::sine())
+            .unwrap();
+        let gen = registry
+            .create_from_str("sine_wt", &[ResourceRef::Wavetable(sine)])
+            .unwrap();
+        assert_eq!(gen.name(), "Oscillator");
+    }
+
+    #[test]
+    fn load_patch() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let wavetable = resources
+            .insert_wavetable(crate::wavetable::Wavetable::sine())
+            .unwrap();
+        let mut graph = Graph::new(GraphSettings::default());
+        let osc = graph.push_gen(Oscillator::new(wavetable));
+        let mult = graph.push_gen(Mult);
+        graph.connect(osc.to(mult)).unwrap();
+        graph.connect(Connection::graph_output(mult)).unwrap();
+        let patch = graph.to_patch();
+        let registry = GenRegistry::with_defaults();
+        let loaded = patch
+            .to_graph(GraphSettings::default(), |node| {
+                registry.create_for_patch(node)
+            })
+            .unwrap();
+        assert_eq!(loaded.to_patch(), patch);
+    }
+}
@@ -0,0 +1,503 @@
+//! Rendering an arrangement offline, faster than real time.
+//!
+//! A [`Timeline`] holds parameter automation, notes and callbacks at times
+//! in seconds or [`Beats`]. [`Timeline::render`] turns a [`Graph`] that
+//! hasn't been started into a [`Node`] and processes it block by block,
+//! scheduling the events as it goes, so the result doesn't depend on the
+//! speed of the computer. Create the [`Resources`] with
+//! [`Resources::new_with_seed`] to make renders using random numbers
+//! reproducible.
+//!
+//! Automation and notes are sample accurate. Callbacks, e.g. for pushing new
+//! nodes or playing notes through a [`VoiceAllocator`](crate::voice::VoiceAllocator),
+//! run before the block containing their time.
+//!
+//! ```no_run
+//! # use knyst::prelude::*;
+//! # use knyst::render::*;
+//! # use knyst::wavetable::WavetableOscillatorOwned;
+//! let mut graph = Graph::new(GraphSettings::default());
+//! let osc = graph.push_gen(WavetableOscillatorOwned::new(Wavetable::sine()));
+//! graph.connect(Connection::graph_output(osc)).unwrap();
+//! let mut resources = Resources::new_with_seed(ResourcesSettings::default(), 1);
+//! let mut timeline = Timeline::new();
+//! timeline.automate(
+//!     osc,
+//!     "freq",
+//!     Automation::new()
+//!         .set(0.0, 220.0)
+//!         .exponential(Beats::from_beats(4), 880.0),
+//! );
+//! timeline
+//!     .render_to_file(&mut graph, &mut resources, Beats::from_beats(8), "sweep.wav")
+//!     .unwrap();
+//! ```
+
+use std::path::Path;
+
+use crate::graph::{Graph, Node, NodeAddress, ParameterChange, ScheduleError, ToNodeError};
+use crate::recorder::WavWriter;
+use crate::scheduling::{Beats, MusicalTimeMap};
+use crate::{sample_to_f32, sample_to_f64, Resources, Sample};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RenderError {
+    #[error("Unable to create a Node from the Graph: {0}")]
+    Node(ToNodeError),
+    #[error(transparent)]
+    Schedule(#[from] ScheduleError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// A time on a [`Timeline`], either in seconds from the start of the render
+/// or in beats according to the tempo of the Timeline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimelineTime {
+    Seconds(f64),
+    Beats(Beats),
+}
+
+impl From<f64> for TimelineTime {
+    fn from(seconds: f64) -> Self {
+        TimelineTime::Seconds(seconds)
+    }
+}
+
+impl From<Beats> for TimelineTime {
+    fn from(beats: Beats) -> Self {
+        TimelineTime::Beats(beats)
+    }
+}
+
+/// How an [`Automation`] gets to a point from the point before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutomationCurve {
+    /// Jump to the value at the time of the point
+    Step,
+    Linear,
+    /// Linear in the logarithm of the value, which sounds even for
+    /// frequencies and gains. Falls back to linear if the values are zero
+    /// or have different signs.
+    Exponential,
+}
+
+/// A curve of values over time for an input, made from points added in
+/// order of time.
+#[derive(Clone, Debug, Default)]
+pub struct Automation {
+    points: Vec<(TimelineTime, Sample, AutomationCurve)>,
+}
+
+impl Automation {
+    pub fn new() -> Self {
+        Self { points: vec![] }
+    }
+    /// Jump to `value` at `time`
+    pub fn set(mut self, time: impl Into<TimelineTime>, value: Sample) -> Self {
+        self.points
+            .push((time.into(), value, AutomationCurve::Step));
+        self
+    }
+    /// Ramp linearly from the previous point to `value` at `time`
+    pub fn linear(mut self, time: impl Into<TimelineTime>, value: Sample) -> Self {
+        self.points
+            .push((time.into(), value, AutomationCurve::Linear));
+        self
+    }
+    /// Ramp exponentially from the previous point to `value` at `time`
+    pub fn exponential(mut self, time: impl Into<TimelineTime>, value: Sample) -> Self {
+        self.points
+            .push((time.into(), value, AutomationCurve::Exponential));
+        self
+    }
+}
+
+type Callback = Box<dyn FnMut(&mut Graph) + Send>;
+
+enum TimelineEvent {
+    Automation {
+        node: NodeAddress,
+        input: &'static str,
+        automation: Automation,
+    },
+    Note {
+        node: NodeAddress,
+        start: TimelineTime,
+        end: TimelineTime,
+        freq: Sample,
+        velocity: Sample,
+    },
+    Callback {
+        time: TimelineTime,
+        callback: Callback,
+    },
+}
+
+/// A change of an input at a sample
+struct SampleChange {
+    sample: u64,
+    node: NodeAddress,
+    input: &'static str,
+    value: Sample,
+}
+
+/// An arrangement of events to render, see the
+/// [module documentation](self).
+pub struct Timeline {
+    events: Vec<TimelineEvent>,
+    tempo_map: MusicalTimeMap,
+    automation_interval: usize,
+}
+
+impl Timeline {
+    /// An empty Timeline at the default tempo of 60 bpm
+    pub fn new() -> Self {
+        Self {
+            events: vec![],
+            tempo_map: MusicalTimeMap::new(),
+            automation_interval: 32,
+        }
+    }
+    /// Set the tempo changes used to convert beats to seconds
+    pub fn tempo_map(mut self, tempo_map: MusicalTimeMap) -> Self {
+        self.tempo_map = tempo_map;
+        self
+    }
+    /// Set the number of samples between the values sent for a ramp in an
+    /// [`Automation`]. Default: 32
+    pub fn automation_interval(mut self, samples: usize) -> Self {
+        self.automation_interval = samples.max(1);
+        self
+    }
+    /// Automate the input of `node` with the label `input`
+    pub fn automate(&mut self, node: NodeAddress, input: &'static str, automation: Automation) {
+        self.events.push(TimelineEvent::Automation {
+            node,
+            input,
+            automation,
+        });
+    }
+    /// Set the input of `node` with the label `input` to `value` at `time`
+    pub fn change(
+        &mut self,
+        time: impl Into<TimelineTime>,
+        node: NodeAddress,
+        input: &'static str,
+        value: Sample,
+    ) {
+        self.automate(node, input, Automation::new().set(time, value));
+    }
+    /// Play a note on `node` between `start` and `end` using the same inputs
+    /// as a [`VoiceAllocator`](crate::voice::VoiceAllocator): "freq" and
+    /// "velocity" are set at the start and "gate" is 1 until the end. Inputs
+    /// the node doesn't have are skipped.
+    pub fn note(
+        &mut self,
+        node: NodeAddress,
+        start: impl Into<TimelineTime>,
+        end: impl Into<TimelineTime>,
+        freq: Sample,
+        velocity: Sample,
+    ) {
+        self.events.push(TimelineEvent::Note {
+            node,
+            start: start.into(),
+            end: end.into(),
+            freq,
+            velocity,
+        });
+    }
+    /// Call `callback` with the Graph before the block containing `time`
+    /// is processed. Changes to the Graph are committed before the block.
+    pub fn at(
+        &mut self,
+        time: impl Into<TimelineTime>,
+        callback: impl FnMut(&mut Graph) + Send + 'static,
+    ) {
+        self.events.push(TimelineEvent::Callback {
+            time: time.into(),
+            callback: Box::new(callback),
+        });
+    }
+    /// The time in seconds from the start of the render
+    pub fn seconds(&self, time: impl Into<TimelineTime>) -> f64 {
+        match time.into() {
+            TimelineTime::Seconds(seconds) => seconds,
+            TimelineTime::Beats(beats) => self.tempo_map.beats_to_seconds(beats),
+        }
+    }
+    fn sample(&self, time: TimelineTime, sample_rate: f64) -> u64 {
+        (self.seconds(time).max(0.0) * sample_rate).round() as u64
+    }
+    /// The changes of all automation and notes, sorted by time
+    ///
+    /// *Allocates memory*
+    fn sample_changes(&self, sample_rate: f64) -> Vec<SampleChange> {
+        let mut changes = vec![];
+        for event in &self.events {
+            match event {
+                TimelineEvent::Automation {
+                    node,
+                    input,
+                    automation,
+                } => {
+                    let mut previous: Option<(u64, Sample)> = None;
+                    for &(time, value, curve) in &automation.points {
+                        let sample = self.sample(time, sample_rate);
+                        if let Some((start, start_value)) = previous {
+                            let length = sample.saturating_sub(start);
+                            let exponential =
+                                curve == AutomationCurve::Exponential && start_value * value > 0.0;
+                            let mut offset = self.automation_interval as u64;
+                            while curve != AutomationCurve::Step && offset < length {
+                                let t = offset as f64 / length as f64;
+                                let ramp_value = if exponential {
+                                    sample_to_f64(start_value)
+                                        * (sample_to_f64(value) / sample_to_f64(start_value))
+                                            .powf(t)
+                                } else {
+                                    sample_to_f64(start_value)
+                                        + sample_to_f64(value - start_value) * t
+                                };
+                                changes.push(SampleChange {
+                                    sample: start + offset,
+                                    node: *node,
+                                    input,
+                                    value: ramp_value as Sample,
+                                });
+                                offset += self.automation_interval as u64;
+                            }
+                        }
+                        changes.push(SampleChange {
+                            sample,
+                            node: *node,
+                            input,
+                            value,
+                        });
+                        previous = Some((sample, value));
+                    }
+                }
+                TimelineEvent::Note {
+                    node,
+                    start,
+                    end,
+                    freq,
+                    velocity,
+                } => {
+                    let start = self.sample(*start, sample_rate);
+                    for (input, value) in [("freq", *freq), ("velocity", *velocity), ("gate", 1.0)]
+                    {
+                        changes.push(SampleChange {
+                            sample: start,
+                            node: *node,
+                            input,
+                            value,
+                        });
+                    }
+                    changes.push(SampleChange {
+                        sample: self.sample(*end, sample_rate).max(start + 1),
+                        node: *node,
+                        input: "gate",
+                        value: 0.0,
+                    });
+                }
+                TimelineEvent::Callback { .. } => (),
+            }
+        }
+        changes.sort_by_key(|change| change.sample);
+        changes
+    }
+    /// Render `duration` of `graph` with the events of the Timeline, calling
+    /// `on_block` with the output buffers and the number of frames in them
+    /// to use for every block. The Graph must not have been turned into a
+    /// Node yet, and keeps its state after the render.
+    ///
+    /// *Allocates memory*
+    pub fn render(
+        &mut self,
+        graph: &mut Graph,
+        resources: &mut Resources,
+        duration: impl Into<TimelineTime>,
+        mut on_block: impl FnMut(&[Box<[Sample]>], usize),
+    ) -> Result<(), RenderError> {
+        let sample_rate = sample_to_f64(graph.sample_rate());
+        let block_size = graph.block_size();
+        let length = self.sample(duration.into(), sample_rate);
+        let mut node: Node = graph.to_node().map_err(RenderError::Node)?;
+        graph.commit_changes();
+        let changes = self.sample_changes(sample_rate);
+        let callback_samples: Vec<u64> = self
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                TimelineEvent::Callback { time, .. } => Some(self.sample(*time, sample_rate)),
+                _ => None,
+            })
+            .collect();
+        let mut callbacks: Vec<(u64, &mut Callback)> = callback_samples
+            .into_iter()
+            .zip(self.events.iter_mut().filter_map(|event| match event {
+                TimelineEvent::Callback { callback, .. } => Some(callback),
+                _ => None,
+            }))
+            .collect();
+        callbacks.sort_by_key(|(sample, _)| *sample);
+        let inputs = vec![vec![0.0; block_size].into_boxed_slice(); graph.num_inputs()];
+        let mut next_change = 0;
+        let mut next_callback = 0;
+        let mut sample = 0;
+        while sample < length {
+            let block_end = sample + block_size as u64;
+            let first_callback = next_callback;
+            while next_callback < callbacks.len() && callbacks[next_callback].0 < block_end {
+                (callbacks[next_callback].1)(graph);
+                next_callback += 1;
+            }
+            if next_callback > first_callback {
+                graph.commit_changes();
+            }
+            while next_change < changes.len() && changes[next_change].sample < block_end {
+                let change = &changes[next_change];
+                let parameter_change =
+                    ParameterChange::absolute_samples(change.node, change.value, change.sample)
+                        .l(change.input);
+                match graph.schedule_change(parameter_change) {
+                    Ok(()) | Err(ScheduleError::InputLabelNotFound(_)) => (),
+                    Err(e) => return Err(e.into()),
+                }
+                next_change += 1;
+            }
+            graph.update();
+            node.process(&inputs, resources);
+            on_block(
+                node.output_buffers(),
+                (length - sample).min(block_size as u64) as usize,
+            );
+            sample = block_end;
+        }
+        Ok(())
+    }
+    /// Render `duration` of `graph` to a 32 bit float WAV file with one
+    /// channel per output of the Graph, see [`Timeline::render`].
+    pub fn render_to_file(
+        &mut self,
+        graph: &mut Graph,
+        resources: &mut Resources,
+        duration: impl Into<TimelineTime>,
+        path: impl AsRef<Path>,
+    ) -> Result<(), RenderError> {
+        let num_channels = graph.num_outputs();
+        let mut writer =
+            WavWriter::create(path.as_ref(), num_channels, graph.sample_rate() as u32)?;
+        let mut interleaved = vec![0.0; graph.block_size() * num_channels];
+        let mut result = Ok(());
+        self.render(graph, resources, duration, |outputs, frames| {
+            for (channel, output) in outputs.iter().enumerate() {
+                for (frame, sample) in output[..frames].iter().enumerate() {
+                    interleaved[frame * num_channels + channel] = sample_to_f32(*sample);
+                }
+            }
+            if result.is_ok() {
+                result = writer.write_samples(&interleaved[..frames * num_channels]);
+            }
+        })?;
+        result?;
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{gen, Connection, GenState, GraphSettings};
+    use crate::ResourcesSettings;
+
+    /// Outputs the sum of its "value" and "gate" inputs
+    fn value_graph() -> (Graph, NodeAddress) {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 16,
+            sample_rate: 1000.0,
+            ..Default::default()
+        });
+        let node = graph.push_gen(
+            gen(|inputs, outputs, _resources| {
+                for ((out, value), gate) in outputs[0].iter_mut().zip(&*inputs[0]).zip(&*inputs[1])
+                {
+                    *out = value + gate;
+                }
+                GenState::Continue
+            })
+            .input("value")
+            .input("gate")
+            .output("out"),
+        );
+        graph.connect(Connection::graph_output(node)).unwrap();
+        (graph, node)
+    }
+
+    fn render(timeline: &mut Timeline, graph: &mut Graph, duration: f64) -> Vec<Sample> {
+        let mut resources = Resources::new_with_seed(ResourcesSettings::default(), 1);
+        let mut output = vec![];
+        timeline
+            .render(graph, &mut resources, duration, |outputs, frames| {
+                output.extend_from_slice(&outputs[0][..frames]);
+            })
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn automation_is_sample_accurate() {
+        let (mut graph, node) = value_graph();
+        let mut timeline = Timeline::new().automation_interval(1);
+        timeline.automate(
+            node,
+            "value",
+            Automation::new()
+                .set(0.005, 1.0)
+                .linear(0.009, 3.0)
+                .set(Beats::from_fraction(1, 50), -1.0),
+        );
+        // There are no "freq" and "velocity" inputs so only the gate is set
+        timeline.note(node, 0.03, 0.04, 440.0, 1.0);
+        let output = render(&mut timeline, &mut graph, 0.05);
+        assert_eq!(output.len(), 50);
+        assert_eq!(output[4], 0.0);
+        assert_eq!(output[5..10], [1.0, 1.5, 2.0, 2.5, 3.0]);
+        assert_eq!(output[19], 3.0);
+        assert_eq!(output[20], -1.0);
+        assert_eq!(output[29], -1.0);
+        assert_eq!(output[30..40], [0.0; 10]);
+        assert_eq!(output[40..], [-1.0; 10]);
+    }
+
+    #[test]
+    fn callbacks_change_the_graph() {
+        let (mut graph, node) = value_graph();
+        let mut timeline = Timeline::new();
+        timeline.at(0.02, |graph| {
+            let ones = graph.push_gen(
+                gen(|_inputs, outputs, _resources| {
+                    outputs[0].fill(1.0);
+                    GenState::Continue
+                })
+                .output("out"),
+            );
+            graph.connect(Connection::graph_output(ones)).unwrap();
+        });
+        timeline.change(0.0, node, "value", 2.0);
+        let output = render(&mut timeline, &mut graph, 0.04);
+        assert_eq!(output.len(), 40);
+        // The callback runs before the block starting at sample 16
+        assert_eq!(output[..16], [2.0; 16]);
+        assert_eq!(output[16..], [3.0; 24]);
+    }
+}
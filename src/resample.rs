@@ -0,0 +1,407 @@
+//! Sample rate conversion using a windowed sinc kernel.
+//!
+//! - [`resample`] converts interleaved audio in one go, e.g. a sound file
+//!   that was recorded at a different rate than the one the Graph runs at.
+//!   [`Buffer::resampled`](crate::buffer::Buffer::resampled) uses it to
+//!   convert a [`Buffer`](crate::buffer::Buffer) before it is inserted.
+//! - [`Resampler`] converts a stream frame by frame without allocating, for
+//!   use on the audio thread.
+//! - [`StreamResampler`] is a node outputting the frames of an
+//!   [`AudioReceiver`] that were produced at a different sample rate than the
+//!   one of the Graph.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::buffer::Buffer;
+//! # use knyst::resample::ResampleQuality;
+//! let buffer = Buffer::from_vec(vec![0.0; 44100], 44100.0);
+//! let buffer = buffer.resampled(48000.0, ResampleQuality::High);
+//! assert_eq!(buffer.size(), 48000.0);
+//! ```
+
+use std::f64::consts::PI;
+
+use crate::audio_channel::AudioReceiver;
+use crate::graph::{Gen, GenState};
+use crate::{sample_to_f64, Resources, Sample};
+
+const OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
+
+/// The number of points in the kernel table per zero crossing. Values in
+/// between are linearly interpolated.
+const TABLE_OVERSAMPLING: usize = 128;
+
+/// The tradeoff between quality and CPU use of a sample rate conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResampleQuality {
+    /// 8 zero crossings on either side of the kernel
+    Low,
+    /// 16 zero crossings on either side of the kernel
+    #[default]
+    Medium,
+    /// 32 zero crossings on either side of the kernel
+    High,
+}
+
+impl ResampleQuality {
+    fn half_width(self) -> usize {
+        match self {
+            ResampleQuality::Low => 8,
+            ResampleQuality::Medium => 16,
+            ResampleQuality::High => 32,
+        }
+    }
+}
+
+/// A Blackman windowed sinc lowpass kernel, tabulated for one side
+#[derive(Debug, Clone)]
+struct SincKernel {
+    half_width: usize,
+    table: Vec<f64>,
+}
+
+impl SincKernel {
+    /// `ratio` is the output sample rate divided by the input sample rate.
+    /// When downsampling the cutoff is lowered to below the new Nyquist
+    /// frequency.
+    fn new(quality: ResampleQuality, ratio: f64) -> Self {
+        let half_width = quality.half_width();
+        // Leave room for the transition band
+        let cutoff = ratio.min(1.0) * 0.95;
+        let len = half_width * TABLE_OVERSAMPLING;
+        let mut table: Vec<f64> = (0..len)
+            .map(|i| {
+                let d = i as f64 / TABLE_OVERSAMPLING as f64;
+                let x = d / half_width as f64;
+                let window = 0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos();
+                let sinc = if d == 0.0 {
+                    1.0
+                } else {
+                    (PI * cutoff * d).sin() / (PI * cutoff * d)
+                };
+                cutoff * sinc * window
+            })
+            .collect();
+        // The kernel is zero at the edge, and the extra point saves a bounds
+        // check when interpolating
+        table.push(0.0);
+        table.push(0.0);
+        Self { half_width, table }
+    }
+    #[inline]
+    fn value(&self, d: f64) -> f64 {
+        let x = d.abs() * TABLE_OVERSAMPLING as f64;
+        let i = x as usize;
+        if i >= self.half_width * TABLE_OVERSAMPLING {
+            return 0.0;
+        }
+        let frac = x - i as f64;
+        self.table[i] + (self.table[i + 1] - self.table[i]) * frac
+    }
+}
+
+/// *Allocates memory*
+/// Convert interleaved audio from `from_rate` to `to_rate`. The output
+/// contains `ceil(frames * to_rate / from_rate)` frames and starts at the
+/// same point in time as the input.
+pub fn resample(
+    interleaved: &[Sample],
+    num_channels: usize,
+    from_rate: f64,
+    to_rate: f64,
+    quality: ResampleQuality,
+) -> Vec<Sample> {
+    let num_channels = num_channels.max(1);
+    let input_frames = interleaved.len() / num_channels;
+    if from_rate == to_rate || from_rate <= 0.0 || to_rate <= 0.0 {
+        return interleaved[..input_frames * num_channels].to_vec();
+    }
+    let ratio = to_rate / from_rate;
+    let kernel = SincKernel::new(quality, ratio);
+    let half_width = kernel.half_width as i64;
+    let output_frames = (input_frames as f64 * to_rate / from_rate).ceil() as usize;
+    let mut output = vec![0.0; output_frames * num_channels];
+    let mut sums = vec![0.0; num_channels];
+    for (frame, out) in output.chunks_exact_mut(num_channels).enumerate() {
+        let t = frame as f64 / ratio;
+        let center = t.floor() as i64;
+        let first = (center - half_width + 1).max(0);
+        let last = (center + half_width).min(input_frames as i64 - 1);
+        sums.fill(0.0);
+        let mut weight_sum = 0.0;
+        for k in first..=last {
+            let weight = kernel.value(t - k as f64);
+            weight_sum += weight;
+            let input = &interleaved[k as usize * num_channels..(k as usize + 1) * num_channels];
+            for (sum, sample) in sums.iter_mut().zip(input) {
+                *sum += sample_to_f64(*sample) * weight;
+            }
+        }
+        // Normalising keeps the gain at DC exact, also close to the edges
+        if weight_sum.abs() > 1e-9 {
+            for (out, sum) in out.iter_mut().zip(&sums) {
+                *out = (sum / weight_sum) as Sample;
+            }
+        }
+    }
+    output
+}
+
+/// Converts a stream of frames from one sample rate to another, one frame at
+/// a time, without allocating after it has been created.
+///
+/// Push input frames while [`Resampler::needs_input`] returns true, then take
+/// an output frame with [`Resampler::next_frame`]. Enough input has to be
+/// pushed to fill half the kernel before the first output frame, see
+/// [`Resampler::latency`].
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    num_channels: usize,
+    kernel: SincKernel,
+    /// Input frames per output frame
+    step: f64,
+    /// The last `2 * half_width` input frames of every channel, stored twice
+    /// after each other so that the window is always contiguous
+    history: Vec<Sample>,
+    write_index: usize,
+    /// The number of input frames to push before the next output frame, plus
+    /// the fractional position of the output between two input frames
+    position: f64,
+    weights: Vec<f64>,
+}
+
+impl Resampler {
+    /// *Allocates memory*
+    pub fn new(
+        num_channels: usize,
+        from_rate: f64,
+        to_rate: f64,
+        quality: ResampleQuality,
+    ) -> Self {
+        let num_channels = num_channels.max(1);
+        let ratio = to_rate / from_rate;
+        let kernel = SincKernel::new(quality, ratio);
+        let window = 2 * kernel.half_width;
+        Self {
+            num_channels,
+            step: 1.0 / ratio,
+            history: vec![0.0; num_channels * window * 2],
+            write_index: 0,
+            // Line up the first output frame with the first input frame
+            position: kernel.half_width as f64 + 1.0,
+            weights: vec![0.0; window],
+            kernel,
+        }
+    }
+    /// Change the number of input frames consumed per output frame, e.g. to
+    /// compensate for drifting clocks. The cutoff of the kernel stays the
+    /// same so only small adjustments should be made this way.
+    pub fn set_step(&mut self, step: f64) {
+        self.step = step.max(0.0);
+    }
+    pub fn step(&self) -> f64 {
+        self.step
+    }
+    pub fn num_channels(&self) -> usize {
+        self.num_channels
+    }
+    /// The delay of the output in input frames
+    pub fn latency(&self) -> usize {
+        self.kernel.half_width + 1
+    }
+    /// True if an input frame has to be pushed before the next output frame
+    /// can be taken
+    pub fn needs_input(&self) -> bool {
+        self.position >= 1.0
+    }
+    /// Push one frame with a sample for every channel. Missing channels are
+    /// set to 0.
+    pub fn push_frame(&mut self, frame: &[Sample]) {
+        let window = self.weights.len();
+        for channel in 0..self.num_channels {
+            let sample = frame.get(channel).copied().unwrap_or(0.0);
+            let offset = channel * window * 2;
+            self.history[offset + self.write_index] = sample;
+            self.history[offset + self.write_index + window] = sample;
+        }
+        self.write_index = (self.write_index + 1) % window;
+        self.position -= 1.0;
+    }
+    /// Write the next output frame to `frame`. If more input was needed the
+    /// output is computed from the frames that have been pushed anyway.
+    pub fn next_frame(&mut self, frame: &mut [Sample]) {
+        let window = self.weights.len();
+        let frac = self.position.clamp(0.0, 1.0);
+        let center = (self.kernel.half_width - 1) as f64 + frac;
+        let mut weight_sum = 0.0;
+        for (m, weight) in self.weights.iter_mut().enumerate() {
+            *weight = self.kernel.value(center - m as f64);
+            weight_sum += *weight;
+        }
+        let gain = if weight_sum.abs() > 1e-9 {
+            1.0 / weight_sum
+        } else {
+            0.0
+        };
+        for (channel, out) in frame.iter_mut().enumerate().take(self.num_channels) {
+            let offset = channel * window * 2 + self.write_index;
+            let history = &self.history[offset..offset + window];
+            let sum: f64 = history
+                .iter()
+                .zip(&self.weights)
+                .map(|(sample, weight)| sample_to_f64(*sample) * weight)
+                .sum();
+            *out = (sum * gain) as Sample;
+        }
+        self.position += self.step;
+    }
+    /// Forget all input, as if the Resampler was just created
+    pub fn reset(&mut self) {
+        self.history.fill(0.0);
+        self.write_index = 0;
+        self.position = self.kernel.half_width as f64 + 1.0;
+    }
+}
+
+/// Outputs the frames of an [`AudioReceiver`] that are sent at a different
+/// sample rate, converted to the sample rate of the Graph.
+///
+/// The `rate` input scales the number of input frames consumed per output
+/// frame, which can be used to compensate for the clocks of the sender and
+/// the Graph drifting apart. Frames that haven't arrived yet are replaced by
+/// silence and counted as underruns of the channel.
+pub struct StreamResampler {
+    receiver: AudioReceiver,
+    source_rate: f64,
+    quality: ResampleQuality,
+    resampler: Option<Resampler>,
+    base_step: f64,
+    frame: Vec<Sample>,
+}
+
+impl StreamResampler {
+    /// *Allocates memory*
+    /// `source_rate` is the sample rate of the frames sent to `receiver`
+    pub fn new(receiver: AudioReceiver, source_rate: f64) -> Self {
+        let num_channels = receiver.num_channels();
+        Self {
+            receiver,
+            source_rate,
+            quality: ResampleQuality::default(),
+            resampler: None,
+            base_step: 1.0,
+            frame: vec![0.0; num_channels],
+        }
+    }
+    pub fn quality(mut self, quality: ResampleQuality) -> Self {
+        self.quality = quality;
+        self
+    }
+}
+
+impl Gen for StreamResampler {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let Some(resampler) = &mut self.resampler else {
+            for output in outputs.iter_mut() {
+                output.fill(0.0);
+            }
+            return GenState::Continue;
+        };
+        let rate = &inputs[0];
+        let mut underruns = 0;
+        for i in 0..rate.len() {
+            resampler.set_step(self.base_step * sample_to_f64(rate[i]));
+            while resampler.needs_input() {
+                if self.receiver.receive(&mut self.frame) == 0 {
+                    self.frame.fill(0.0);
+                    underruns += 1;
+                }
+                resampler.push_frame(&self.frame);
+            }
+            resampler.next_frame(&mut self.frame);
+            for (output, sample) in outputs.iter_mut().zip(&self.frame) {
+                output[i] = *sample;
+            }
+        }
+        if underruns > 0 {
+            self.receiver.count_underruns(underruns);
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        self.receiver.num_channels()
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "rate",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        OUTPUT_NAMES.get(output).copied().unwrap_or("")
+    }
+    fn default_input(&self, _input: usize) -> Sample {
+        1.0
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        let sample_rate = sample_to_f64(sample_rate);
+        self.base_step = self.source_rate / sample_rate;
+        self.resampler = Some(Resampler::new(
+            self.receiver.num_channels(),
+            self.source_rate,
+            sample_rate,
+            self.quality,
+        ));
+    }
+    fn name(&self) -> &'static str {
+        "StreamResampler"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: f64, frames: usize) -> Vec<Sample> {
+        (0..frames)
+            .map(|i| (2.0 * PI * freq * i as f64 / rate).sin() as Sample)
+            .collect()
+    }
+
+    #[test]
+    fn offline_and_streaming_agree() {
+        let input = sine(1000.0, 44100.0, 441);
+        let output = resample(&input, 1, 44100.0, 48000.0, ResampleQuality::Medium);
+        assert_eq!(output.len(), 480);
+        let expected = sine(1000.0, 48000.0, 480);
+        // Away from the edges the result is very close to a sine generated
+        // at the new rate
+        for (out, expected) in output[40..440].iter().zip(&expected[40..440]) {
+            assert!((out - expected).abs() < 1e-3, "{out} != {expected}");
+        }
+
+        let mut resampler = Resampler::new(1, 44100.0, 48000.0, ResampleQuality::Medium);
+        let mut input = input.iter().chain(std::iter::repeat(&0.0));
+        let mut frame = [0.0];
+        for (i, expected) in output[..400].iter().enumerate() {
+            while resampler.needs_input() {
+                resampler.push_frame(&[*input.next().unwrap()]);
+            }
+            resampler.next_frame(&mut frame);
+            // The first frames differ since the stream is padded with zeros
+            if i >= 20 {
+                assert!((frame[0] - expected).abs() < 1e-4);
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Realtime safety audit for debugging, enabled with the `rt-audit` feature.
+//!
+//! Install [`AuditAllocator`] as the global allocator in your binary. While
+//! the feature is enabled every call to [`Node::process`] marks the current
+//! thread as an audio thread and any allocation or deallocation made on it is
+//! reported. Locks and other syscalls can't be detected this way, only
+//! allocations.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: knyst::rt_audit::AuditAllocator = knyst::rt_audit::AuditAllocator;
+//! ```
+//!
+//! By default violations are printed to stderr and counted, see
+//! [`violations`]. Use [`set_abort_on_violation`] to abort the process instead,
+//! which is useful in tests. A global allocator is not allowed to panic.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[allow(unused)]
+use crate::graph::Node;
+
+thread_local! {
+    /// How many audio thread scopes the current thread is in. Nodes are
+    /// nested when a Graph is run as a node in another Graph.
+    static AUDIO_THREAD_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+static ABORT_ON_VIOLATION: AtomicBool = AtomicBool::new(false);
+
+/// The number of allocations and deallocations that have happened on an
+/// audio thread since the program started.
+pub fn violations() -> usize {
+    VIOLATIONS.load(Ordering::SeqCst)
+}
+
+/// Abort the process when an allocation happens on an audio thread instead of
+/// printing a warning.
+pub fn set_abort_on_violation(abort: bool) {
+    ABORT_ON_VIOLATION.store(abort, Ordering::SeqCst);
+}
+
+/// Marks the current thread as an audio thread until dropped.
+pub struct AudioThreadGuard {
+    _private: (),
+}
+
+impl AudioThreadGuard {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        AUDIO_THREAD_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Self { _private: () }
+    }
+}
+
+impl Drop for AudioThreadGuard {
+    fn drop(&mut self) {
+        AUDIO_THREAD_DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+    }
+}
+
+/// Run `f` with allocations allowed even if this is an audio thread, e.g.
+/// for an allocation you know about and accept.
+pub fn allow_allocations<R>(f: impl FnOnce() -> R) -> R {
+    let previous_depth = AUDIO_THREAD_DEPTH.with(|depth| depth.replace(0));
+    let result = f();
+    AUDIO_THREAD_DEPTH.with(|depth| depth.set(previous_depth));
+    result
+}
+
+fn is_audio_thread() -> bool {
+    // `try_with` because the allocator can be called while thread locals are
+    // being destroyed.
+    AUDIO_THREAD_DEPTH
+        .try_with(|depth| depth.get() > 0)
+        .unwrap_or(false)
+}
+
+fn report_violation(kind: &str, layout: Layout) {
+    VIOLATIONS.fetch_add(1, Ordering::SeqCst);
+    // Printing may allocate, so leave the audio thread state while doing it.
+    allow_allocations(|| {
+        eprintln!(
+            "Realtime safety violation: {kind} of {} bytes on an audio thread",
+            layout.size()
+        );
+    });
+    if ABORT_ON_VIOLATION.load(Ordering::SeqCst) {
+        std::process::abort();
+    }
+}
+
+/// A global allocator reporting allocations on audio threads. It forwards all
+/// allocations to the system allocator.
+pub struct AuditAllocator;
+
+unsafe impl GlobalAlloc for AuditAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_audio_thread() {
+            report_violation("allocation", layout);
+        }
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if is_audio_thread() {
+            report_violation("deallocation", layout);
+        }
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if is_audio_thread() {
+            report_violation("allocation", layout);
+        }
+        System.alloc_zeroed(layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if is_audio_thread() {
+            report_violation("reallocation", layout);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{gen, Graph, GraphSettings};
+    use crate::graph::{Connection, GenState};
+    use crate::{Resources, ResourcesSettings};
+
+    #[global_allocator]
+    static ALLOCATOR: AuditAllocator = AuditAllocator;
+
+    #[test]
+    fn detects_allocation_in_process() {
+        let mut graph = Graph::new(GraphSettings::default());
+        let node = graph.push_gen(
+            gen(|_inputs, _outputs, _resources| {
+                let v = vec![0.0; 16];
+                std::hint::black_box(v);
+                GenState::Continue
+            })
+            .output("out"),
+        );
+        graph.connect(Connection::graph_output(node)).unwrap();
+        graph.commit_changes();
+        let mut graph_node = graph.to_node().unwrap();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let violations_before = violations();
+        graph_node.process(&[], &mut resources);
+        // The allocation and the deallocation of the Vec
+        assert!(violations() >= violations_before + 2);
+    }
+}
@@ -0,0 +1,480 @@
+//! Musical time and transport.
+//!
+//! Events can be scheduled in [`Beats`] instead of samples. The mapping from
+//! beats to real time is stored in a [`MusicalTimeMap`], a list of tempo
+//! changes. The transport (play/pause/stop) of the top level [`Graph`] decides
+//! where beat 0 is in sample time.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::scheduling::*;
+//! let mut map = MusicalTimeMap::new();
+//! map.insert(TempoChange::NewTempo { bpm: 120.0 }, Beats::from_beats(4)).unwrap();
+//! // The default tempo is 60 bpm so 4 beats take 4 seconds, the next 4 beats take 2 seconds.
+//! assert_eq!(map.beats_to_seconds(Beats::from_beats(8)), 6.0);
+//! ```
+
+#[allow(unused)]
+use crate::graph::Graph;
+
+/// A point or duration in musical time. Internally stored as a number of beats
+/// in an f64.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Default)]
+pub struct Beats(f64);
+
+impl Beats {
+    pub const ZERO: Beats = Beats(0.0);
+    pub fn from_beats(beats: u32) -> Self {
+        Self(beats as f64)
+    }
+    pub fn from_beats_f64(beats: f64) -> Self {
+        Self(beats)
+    }
+    /// Create a time in beats from a fraction, e.g. `from_fraction(1, 4)` is a
+    /// quarter of a beat.
+    pub fn from_fraction(numerator: u32, denominator: u32) -> Self {
+        Self(numerator as f64 / denominator as f64)
+    }
+    pub fn as_beats_f64(&self) -> f64 {
+        self.0
+    }
+}
+impl std::ops::Add for Beats {
+    type Output = Beats;
+    fn add(self, rhs: Self) -> Self::Output {
+        Beats(self.0 + rhs.0)
+    }
+}
+impl std::ops::AddAssign for Beats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.0 += rhs.0;
+    }
+}
+impl std::ops::Sub for Beats {
+    type Output = Beats;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Beats(self.0 - rhs.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TempoChange {
+    NewTempo { bpm: f64 },
+}
+
+impl TempoChange {
+    /// The duration of one beat in seconds after this change.
+    fn beat_duration(&self) -> f64 {
+        match self {
+            TempoChange::NewTempo { bpm } => 60.0 / bpm,
+        }
+    }
+    pub fn bpm(&self) -> f64 {
+        match self {
+            TempoChange::NewTempo { bpm } => *bpm,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum MusicalTimeMapError {
+    #[error("The MusicalTimeMap is full. Create it with a larger capacity to avoid allocating on the audio thread.")]
+    Full,
+    #[error("There is no tempo change at index {0}.")]
+    IndexOutOfBounds(usize),
+    #[error("The first tempo change at beat 0 cannot be removed.")]
+    CannotRemoveFirst,
+}
+
+/// A list of tempo changes, always starting with one at beat 0. Changes are
+/// kept sorted by their position in beats.
+///
+/// The map is mirrored on the audio thread and changes are made within the
+/// preallocated capacity so that they never allocate there.
+#[derive(Clone, Debug)]
+pub struct MusicalTimeMap {
+    tempo_changes: Vec<(TempoChange, Beats)>,
+}
+
+impl Default for MusicalTimeMap {
+    fn default() -> Self {
+        Self::with_capacity(64)
+    }
+}
+
+impl MusicalTimeMap {
+    /// A map at 60 bpm with room for 64 tempo changes
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut tempo_changes = Vec::with_capacity(capacity.max(1));
+        tempo_changes.push((TempoChange::NewTempo { bpm: 60.0 }, Beats::ZERO));
+        Self { tempo_changes }
+    }
+    /// Insert a tempo change at a point in time. If there already is a change
+    /// at exactly that time it will be replaced.
+    pub fn insert(
+        &mut self,
+        tempo_change: TempoChange,
+        time_stamp: Beats,
+    ) -> Result<(), MusicalTimeMapError> {
+        let time_stamp = if time_stamp < Beats::ZERO {
+            Beats::ZERO
+        } else {
+            time_stamp
+        };
+        let mut index = self.tempo_changes.len();
+        for (i, (_, beats)) in self.tempo_changes.iter().enumerate() {
+            if *beats == time_stamp {
+                self.tempo_changes[i].0 = tempo_change;
+                return Ok(());
+            } else if *beats > time_stamp {
+                index = i;
+                break;
+            }
+        }
+        if self.tempo_changes.len() == self.tempo_changes.capacity() {
+            return Err(MusicalTimeMapError::Full);
+        }
+        self.tempo_changes.insert(index, (tempo_change, time_stamp));
+        Ok(())
+    }
+    /// Replace the tempo change at `index`, keeping its position in time.
+    pub fn replace(
+        &mut self,
+        index: usize,
+        tempo_change: TempoChange,
+    ) -> Result<(), MusicalTimeMapError> {
+        match self.tempo_changes.get_mut(index) {
+            Some(change) => {
+                change.0 = tempo_change;
+                Ok(())
+            }
+            None => Err(MusicalTimeMapError::IndexOutOfBounds(index)),
+        }
+    }
+    pub fn remove(&mut self, index: usize) -> Result<(), MusicalTimeMapError> {
+        if index == 0 {
+            return Err(MusicalTimeMapError::CannotRemoveFirst);
+        }
+        if index >= self.tempo_changes.len() {
+            return Err(MusicalTimeMapError::IndexOutOfBounds(index));
+        }
+        self.tempo_changes.remove(index);
+        Ok(())
+    }
+    pub fn len(&self) -> usize {
+        self.tempo_changes.len()
+    }
+    /// Always false since there is always a tempo change at beat 0.
+    pub fn is_empty(&self) -> bool {
+        self.tempo_changes.is_empty()
+    }
+    /// The tempo in bpm at the given time
+    pub fn tempo_at(&self, time: Beats) -> f64 {
+        let mut bpm = self.tempo_changes[0].0.bpm();
+        for (change, beats) in &self.tempo_changes {
+            if *beats <= time {
+                bpm = change.bpm();
+            } else {
+                break;
+            }
+        }
+        bpm
+    }
+    /// Converts a position in beats to the number of seconds from beat 0.
+    pub fn beats_to_seconds(&self, time: Beats) -> f64 {
+        let mut seconds = 0.0;
+        let mut i = 0;
+        while i < self.tempo_changes.len() {
+            let (change, start) = self.tempo_changes[i];
+            let end = self.tempo_changes.get(i + 1).map(|(_, b)| *b);
+            match end {
+                Some(end) if end < time => {
+                    seconds += (end - start).as_beats_f64() * change.beat_duration();
+                }
+                _ => {
+                    seconds += (time - start).as_beats_f64() * change.beat_duration();
+                    break;
+                }
+            }
+            i += 1;
+        }
+        seconds
+    }
+    /// Converts a number of seconds from beat 0 to a position in beats.
+    pub fn seconds_to_beats(&self, seconds: f64) -> Beats {
+        let mut seconds_left = seconds;
+        let mut i = 0;
+        loop {
+            let (change, start) = self.tempo_changes[i];
+            if let Some((_, end)) = self.tempo_changes.get(i + 1) {
+                let segment_seconds = (*end - start).as_beats_f64() * change.beat_duration();
+                if segment_seconds < seconds_left {
+                    seconds_left -= segment_seconds;
+                    i += 1;
+                    continue;
+                }
+            }
+            return start + Beats::from_beats_f64(seconds_left / change.beat_duration());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportState {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// The state of the transport at the start of the current block. It is stored
+/// in [`crate::Resources`] so that any Gen can read the current musical time.
+#[derive(Clone, Copy, Debug)]
+pub struct TransportSnapshot {
+    pub state: TransportState,
+    /// The number of samples since processing started at the first sample
+    /// of the block, the same clock as [`crate::midi::MidiEvent`] timestamps
+    pub sample: u64,
+    /// The position at the first sample of the block
+    pub beats: Beats,
+    /// The tempo at the first sample of the block
+    pub bpm: f64,
+    /// How far the transport moves every sample. Tempo changes within a
+    /// block are not taken into account.
+    pub beats_per_sample: f64,
+}
+impl Default for TransportSnapshot {
+    fn default() -> Self {
+        Self {
+            state: TransportState::Stopped,
+            sample: 0,
+            beats: Beats::ZERO,
+            bpm: 60.0,
+            beats_per_sample: 0.0,
+        }
+    }
+}
+impl TransportSnapshot {
+    /// Returns the position in beats for a sample within the current block.
+    #[inline]
+    pub fn beats_at_sample(&self, sample_in_block: usize) -> Beats {
+        if self.state == TransportState::Playing {
+            Beats(self.beats.0 + self.beats_per_sample * sample_in_block as f64)
+        } else {
+            self.beats
+        }
+    }
+}
+
+/// Changes to the transport with the absolute sample at which they take effect.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TransportCommand {
+    pub at_sample: u64,
+    pub kind: TransportCommandKind,
+}
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum TransportCommandKind {
+    Play,
+    Pause,
+    Stop,
+    Seek(Beats),
+    InsertTempoChange(TempoChange, Beats),
+    ReplaceTempoChange(usize, TempoChange),
+    RemoveTempoChange(usize),
+}
+
+/// Maps between samples and beats based on a [`MusicalTimeMap`] and the
+/// transport state. The same timeline is kept on the Graph side and in the
+/// GraphGen; the same commands are applied to both, at the sample the
+/// GraphGen applied them at, so they always agree.
+#[derive(Clone, Debug)]
+pub(crate) struct TransportTimeline {
+    state: TransportState,
+    /// The sample at which the transport was last (re)started or the map changed
+    origin_sample: u64,
+    /// The position in beats at `origin_sample`
+    origin_beats: Beats,
+    map: MusicalTimeMap,
+    sample_rate: f64,
+}
+
+impl TransportTimeline {
+    pub fn new(map: MusicalTimeMap, sample_rate: f64) -> Self {
+        Self {
+            state: TransportState::Stopped,
+            origin_sample: 0,
+            origin_beats: Beats::ZERO,
+            map,
+            sample_rate,
+        }
+    }
+    pub fn state(&self) -> TransportState {
+        self.state
+    }
+    /// Continue from `at_sample` at a new sample rate without changing the
+    /// position in beats at that sample.
+    pub fn set_sample_rate(&mut self, sample_rate: f64, at_sample: u64) {
+        if self.state == TransportState::Playing {
+            self.origin_beats = self.beats_at(at_sample);
+            self.origin_sample = at_sample;
+        }
+        self.sample_rate = sample_rate;
+    }
+    pub fn map(&self) -> &MusicalTimeMap {
+        &self.map
+    }
+    /// Returns the position in beats at the absolute sample.
+    pub fn beats_at(&self, sample: u64) -> Beats {
+        match self.state {
+            TransportState::Playing => {
+                let elapsed = sample.saturating_sub(self.origin_sample) as f64 / self.sample_rate;
+                self.map
+                    .seconds_to_beats(self.map.beats_to_seconds(self.origin_beats) + elapsed)
+            }
+            TransportState::Paused | TransportState::Stopped => self.origin_beats,
+        }
+    }
+    /// Returns the absolute sample at which the transport will reach `beats`,
+    /// or None if the transport isn't playing.
+    pub fn sample_of(&self, beats: Beats) -> Option<u64> {
+        if self.state != TransportState::Playing {
+            return None;
+        }
+        let seconds =
+            self.map.beats_to_seconds(beats) - self.map.beats_to_seconds(self.origin_beats);
+        let samples = (seconds * self.sample_rate).round();
+        if samples < 0.0 {
+            Some(self.origin_sample.saturating_sub((-samples) as u64))
+        } else {
+            Some(self.origin_sample + samples as u64)
+        }
+    }
+    /// Move the origin to `sample` without changing the current position
+    fn rebase(&mut self, sample: u64) {
+        self.origin_beats = self.beats_at(sample);
+        self.origin_sample = sample;
+    }
+    pub fn apply(&mut self, command: TransportCommand) -> Result<(), MusicalTimeMapError> {
+        let at = command.at_sample;
+        match command.kind {
+            TransportCommandKind::Play => {
+                if self.state != TransportState::Playing {
+                    self.origin_sample = at;
+                    self.state = TransportState::Playing;
+                }
+            }
+            TransportCommandKind::Pause => {
+                self.rebase(at);
+                self.state = TransportState::Paused;
+            }
+            TransportCommandKind::Stop => {
+                self.origin_beats = Beats::ZERO;
+                self.origin_sample = at;
+                self.state = TransportState::Stopped;
+            }
+            TransportCommandKind::Seek(beats) => {
+                self.origin_beats = if beats < Beats::ZERO {
+                    Beats::ZERO
+                } else {
+                    beats
+                };
+                self.origin_sample = at;
+            }
+            TransportCommandKind::InsertTempoChange(change, beats) => {
+                self.rebase(at);
+                self.map.insert(change, beats)?;
+            }
+            TransportCommandKind::ReplaceTempoChange(index, change) => {
+                self.rebase(at);
+                self.map.replace(index, change)?;
+            }
+            TransportCommandKind::RemoveTempoChange(index) => {
+                self.rebase(at);
+                self.map.remove(index)?;
+            }
+        }
+        Ok(())
+    }
+    pub fn snapshot(&self, sample: u64) -> TransportSnapshot {
+        let beats = self.beats_at(sample);
+        let bpm = self.map.tempo_at(beats);
+        let beats_per_sample = if self.state == TransportState::Playing {
+            bpm / (60.0 * self.sample_rate)
+        } else {
+            0.0
+        };
+        TransportSnapshot {
+            state: self.state,
+            sample,
+            beats,
+            bpm,
+            beats_per_sample,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn tempo_changes() {
+        let mut map = MusicalTimeMap::new();
+        assert_eq!(map.beats_to_seconds(Beats::from_beats(3)), 3.0);
+        map.insert(TempoChange::NewTempo { bpm: 120.0 }, Beats::from_beats(2))
+            .unwrap();
+        map.insert(TempoChange::NewTempo { bpm: 30.0 }, Beats::from_beats(4))
+            .unwrap();
+        assert_eq!(map.beats_to_seconds(Beats::from_beats(3)), 2.5);
+        assert_eq!(map.beats_to_seconds(Beats::from_beats(5)), 5.0);
+        assert_eq!(map.seconds_to_beats(5.0), Beats::from_beats(5));
+        assert_eq!(map.seconds_to_beats(2.5), Beats::from_beats(3));
+        assert_eq!(map.tempo_at(Beats::from_fraction(7, 2)), 120.0);
+        assert_eq!(map.remove(0), Err(MusicalTimeMapError::CannotRemoveFirst));
+    }
+    #[test]
+    fn transport_pause_and_resume() {
+        let mut timeline = TransportTimeline::new(MusicalTimeMap::new(), 100.0);
+        assert_eq!(timeline.sample_of(Beats::from_beats(1)), None);
+        timeline
+            .apply(TransportCommand {
+                at_sample: 50,
+                kind: TransportCommandKind::Play,
+            })
+            .unwrap();
+        assert_eq!(timeline.sample_of(Beats::from_beats(2)), Some(250));
+        timeline
+            .apply(TransportCommand {
+                at_sample: 150,
+                kind: TransportCommandKind::Pause,
+            })
+            .unwrap();
+        assert_eq!(timeline.beats_at(1000), Beats::from_beats(1));
+        timeline
+            .apply(TransportCommand {
+                at_sample: 1000,
+                kind: TransportCommandKind::Play,
+            })
+            .unwrap();
+        assert_eq!(timeline.sample_of(Beats::from_beats(2)), Some(1100));
+    }
+    #[test]
+    fn transport_seek() {
+        let mut timeline = TransportTimeline::new(MusicalTimeMap::new(), 100.0);
+        timeline
+            .apply(TransportCommand {
+                at_sample: 0,
+                kind: TransportCommandKind::Play,
+            })
+            .unwrap();
+        timeline
+            .apply(TransportCommand {
+                at_sample: 100,
+                kind: TransportCommandKind::Seek(Beats::from_beats(8)),
+            })
+            .unwrap();
+        assert_eq!(timeline.beats_at(150), Beats::from_fraction(17, 2));
+        assert_eq!(timeline.state(), TransportState::Playing);
+    }
+}
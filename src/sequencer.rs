@@ -0,0 +1,381 @@
+//! Patterns and a sequencer for triggering events in musical time.
+//!
+//! A [`Pattern`] is a list of steps, each with a duration in [`Beats`] and an
+//! optional value. A [`Sequencer`] plays any number of patterns against the
+//! transport of a top level [`Graph`], calling a callback for every step a
+//! little ahead of time. The callback gets the exact time of the step in beats
+//! and can use it to schedule changes with [`ParameterChange::beats`] or push
+//! new nodes.
+//!
+//! The sequencer runs on the control thread: call [`Sequencer::update`]
+//! regularly, e.g. in the same loop as [`Graph::update`].
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::sequencer::*;
+//! # use std::time::Duration;
+//! let mut graph = Graph::new(GraphSettings::default());
+//! let node = graph.push_gen(Ramp::new());
+//! graph.connect(Connection::graph_output(node)).unwrap();
+//! graph.commit_changes();
+//! let mut graph_node = graph.to_node().unwrap();
+//!
+//! let mut sequencer = Sequencer::new(Duration::from_millis(100));
+//! // An arpeggio of quarter notes repeating forever
+//! let arpeggio = Pattern::from_values(Beats::from_fraction(1, 4), [220.0, 330.0, 440.0]);
+//! sequencer.add(arpeggio, Beats::ZERO, move |graph, event| {
+//!     graph
+//!         .schedule_change(ParameterChange::beats(node, event.value, event.beats).i(0))
+//!         .unwrap();
+//! });
+//! graph.transport_play().unwrap();
+//! // In a real program this would be a loop on the control thread
+//! sequencer.update(&mut graph);
+//! graph.update();
+//! ```
+
+use std::time::Duration;
+
+use crate::graph::Graph;
+#[allow(unused)]
+use crate::graph::ParameterChange;
+use crate::scheduling::{Beats, TransportState};
+use crate::tuning::Tuning;
+use crate::Sample;
+
+/// One step in a [`Pattern`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Step {
+    /// The time until the next step
+    pub duration: Beats,
+    /// The value of the step, or None if the step is a rest
+    pub value: Option<Sample>,
+}
+
+/// A list of steps that are played in order, optionally repeating.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pattern {
+    steps: Vec<Step>,
+    /// The number of times to play the pattern, None means forever
+    repeats: Option<usize>,
+}
+
+impl Pattern {
+    /// Create an empty pattern that repeats forever.
+    pub fn new() -> Self {
+        Self {
+            steps: vec![],
+            repeats: None,
+        }
+    }
+    /// Create a pattern where every value gets the same duration.
+    pub fn from_values(step_duration: Beats, values: impl IntoIterator<Item = Sample>) -> Self {
+        let mut pattern = Self::new();
+        for value in values {
+            pattern = pattern.step(step_duration, value);
+        }
+        pattern
+    }
+    /// Create a pattern of the frequencies of MIDI notes under `tuning`,
+    /// where every note gets the same duration. Notes that aren't mapped by
+    /// the tuning become rests.
+    pub fn from_notes(
+        step_duration: Beats,
+        notes: impl IntoIterator<Item = u8>,
+        tuning: &Tuning,
+    ) -> Self {
+        let mut pattern = Self::new();
+        for note in notes {
+            pattern = match tuning.note_to_freq(note) {
+                Some(freq) => pattern.step(step_duration, freq),
+                None => pattern.rest(step_duration),
+            };
+        }
+        pattern
+    }
+    /// Create a pattern of triggers where `true` is a hit and `false` is a
+    /// rest, e.g. for a drum machine. Hits have the value 1.0.
+    pub fn from_triggers(step_duration: Beats, triggers: impl IntoIterator<Item = bool>) -> Self {
+        let mut pattern = Self::new();
+        for trigger in triggers {
+            pattern = if trigger {
+                pattern.step(step_duration, 1.0)
+            } else {
+                pattern.rest(step_duration)
+            };
+        }
+        pattern
+    }
+    /// Add a step with a value
+    pub fn step(mut self, duration: Beats, value: Sample) -> Self {
+        self.steps.push(Step {
+            duration,
+            value: Some(value),
+        });
+        self
+    }
+    /// Add a step without a value
+    pub fn rest(mut self, duration: Beats) -> Self {
+        self.steps.push(Step {
+            duration,
+            value: None,
+        });
+        self
+    }
+    /// Set the number of times the pattern should be played. By default it
+    /// repeats forever.
+    pub fn repeats(mut self, times: usize) -> Self {
+        self.repeats = Some(times);
+        self
+    }
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+    /// The total duration of one repetition of the pattern
+    pub fn duration(&self) -> Beats {
+        self.steps
+            .iter()
+            .fold(Beats::ZERO, |acc, step| acc + step.duration)
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An event sent to the callback of a pattern for every step that isn't a rest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PatternEvent {
+    /// The time of the event in beats. Use this when scheduling changes to get
+    /// sample accurate timing.
+    pub beats: Beats,
+    /// The value of the step
+    pub value: Sample,
+    /// The duration of the step
+    pub duration: Beats,
+    /// The index of the step in the pattern
+    pub step: usize,
+    /// How many times the pattern has been played before this repetition
+    pub repetition: usize,
+}
+
+/// Identifies a pattern added to a [`Sequencer`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TrackId(usize);
+
+type PatternCallback = Box<dyn FnMut(&mut Graph, PatternEvent) + Send>;
+
+struct Track {
+    id: TrackId,
+    pattern: Pattern,
+    callback: PatternCallback,
+    start: Beats,
+    next_step: usize,
+    next_beats: Beats,
+    repetition: usize,
+}
+
+impl Track {
+    fn reset(&mut self) {
+        self.next_step = 0;
+        self.next_beats = self.start;
+        self.repetition = 0;
+    }
+    fn finished(&self) -> bool {
+        self.pattern.steps.is_empty()
+            || self
+                .pattern
+                .repeats
+                .map(|repeats| self.repetition >= repeats)
+                .unwrap_or(false)
+    }
+    fn advance(&mut self) {
+        self.next_beats += self.pattern.steps[self.next_step].duration;
+        self.next_step += 1;
+        if self.next_step >= self.pattern.steps.len() {
+            self.next_step = 0;
+            self.repetition += 1;
+        }
+    }
+}
+
+/// Plays [`Pattern`]s by calling a callback for each step ahead of time.
+///
+/// The sequencer follows the transport of the top level [`Graph`] it is
+/// updated with. When the transport is stopped all patterns go back to the
+/// beginning.
+pub struct Sequencer {
+    tracks: Vec<Track>,
+    lookahead: Duration,
+    next_id: usize,
+}
+
+impl Sequencer {
+    /// Create a new Sequencer. `lookahead` is how far ahead of the current
+    /// time events are sent to their callbacks. It needs to be longer than the
+    /// time between calls to [`Sequencer::update`].
+    pub fn new(lookahead: Duration) -> Self {
+        Self {
+            tracks: vec![],
+            lookahead,
+            next_id: 0,
+        }
+    }
+    /// Add a pattern starting at `start`. Steps that are already in the past
+    /// when the sequencer is updated are skipped.
+    pub fn add(
+        &mut self,
+        pattern: Pattern,
+        start: Beats,
+        callback: impl FnMut(&mut Graph, PatternEvent) + Send + 'static,
+    ) -> TrackId {
+        let id = TrackId(self.next_id);
+        self.next_id += 1;
+        self.tracks.push(Track {
+            id,
+            pattern,
+            callback: Box::new(callback),
+            start,
+            next_step: 0,
+            next_beats: start,
+            repetition: 0,
+        });
+        id
+    }
+    /// Remove a pattern. Events that have already been sent to the callback
+    /// are not affected.
+    pub fn remove(&mut self, id: TrackId) {
+        self.tracks.retain(|track| track.id != id);
+    }
+    /// Replace the pattern of a track. The new pattern starts from its first
+    /// step at the time the next step of the old pattern would have played.
+    pub fn replace_pattern(&mut self, id: TrackId, pattern: Pattern) {
+        if let Some(track) = self.tracks.iter_mut().find(|track| track.id == id) {
+            track.pattern = pattern;
+            track.next_step = 0;
+            track.repetition = 0;
+        }
+    }
+    /// The number of patterns that haven't finished playing
+    pub fn num_active(&self) -> usize {
+        self.tracks.iter().filter(|track| !track.finished()).count()
+    }
+    /// Move all patterns back to their start.
+    pub fn reset(&mut self) {
+        for track in &mut self.tracks {
+            track.reset();
+        }
+    }
+    /// Call the callbacks for all steps between now and now + lookahead. Needs
+    /// to be called regularly from the control thread.
+    ///
+    /// `graph` has to be a running top level Graph, otherwise nothing happens.
+    pub fn update(&mut self, graph: &mut Graph) {
+        let (now, end) = match (graph.current_beats(), graph.musical_time_map()) {
+            (Some(now), Some(map)) => {
+                let end =
+                    map.seconds_to_beats(map.beats_to_seconds(now) + self.lookahead.as_secs_f64());
+                (now, end)
+            }
+            _ => return,
+        };
+        match graph.transport_state() {
+            Some(TransportState::Playing) => (),
+            Some(TransportState::Stopped) => {
+                self.reset();
+                return;
+            }
+            _ => return,
+        }
+        for track in &mut self.tracks {
+            if track.pattern.duration() <= Beats::ZERO {
+                // A pattern without duration would loop forever
+                continue;
+            }
+            while !track.finished() && track.next_beats < end {
+                let step = track.pattern.steps[track.next_step];
+                if track.next_beats >= now {
+                    if let Some(value) = step.value {
+                        let event = PatternEvent {
+                            beats: track.next_beats,
+                            value,
+                            duration: step.duration,
+                            step: track.next_step,
+                            repetition: track.repetition,
+                        };
+                        (track.callback)(graph, event);
+                    }
+                }
+                track.advance();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{GraphSettings, Ramp};
+    use crate::scheduling::TempoChange;
+    use crate::{Resources, ResourcesSettings};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn sequencer_follows_transport() {
+        const BLOCK: usize = 16;
+        let mut graph: Graph = Graph::new(GraphSettings {
+            block_size: BLOCK,
+            sample_rate: 48000.,
+            latency: Duration::from_millis(0),
+            ..Default::default()
+        });
+        let mut graph_node = graph.to_node().unwrap();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let node = graph.push_gen(Ramp::new());
+        graph.commit_changes();
+        graph
+            .replace_tempo_change(0, TempoChange::NewTempo { bpm: 120.0 })
+            .unwrap();
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut sequencer = Sequencer::new(Duration::from_secs(1));
+        let e = events.clone();
+        sequencer.add(
+            Pattern::from_triggers(Beats::from_fraction(1, 2), [true, false, true]).repeats(2),
+            Beats::ZERO,
+            move |graph, event| {
+                graph
+                    .schedule_change(ParameterChange::beats(node, event.value, event.beats).i(0))
+                    .unwrap();
+                e.lock()
+                    .unwrap()
+                    .push((event.beats, event.step, event.repetition));
+            },
+        );
+        // Nothing happens while the transport is stopped
+        sequencer.update(&mut graph);
+        assert!(events.lock().unwrap().is_empty());
+        graph.transport_play().unwrap();
+        // A lookahead of 1 second is 2 beats at 120 bpm
+        sequencer.update(&mut graph);
+        graph.update();
+        graph_node.process(&[], &mut resources);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (Beats::ZERO, 0, 0),
+                (Beats::from_beats(1), 2, 0),
+                (Beats::from_fraction(3, 2), 0, 1),
+            ]
+        );
+        assert_eq!(sequencer.num_active(), 1);
+        // Stopping the transport resets the patterns
+        graph.transport_stop().unwrap();
+        sequencer.update(&mut graph);
+        graph.transport_play().unwrap();
+        sequencer.update(&mut graph);
+        assert_eq!(events.lock().unwrap().len(), 6);
+        assert_eq!(events.lock().unwrap()[3], (Beats::ZERO, 0, 0));
+    }
+}
@@ -0,0 +1,197 @@
+//! Block arithmetic for the hot inner loops that don't depend on the previous
+//! sample: the gain of the wavetable oscillators, [`Mult`](crate::graph::Mult),
+//! [`PanMonoToStereo`](crate::graph::PanMonoToStereo), the ambisonic decoder
+//! and summing node outputs into the outputs of a Graph. Loops with state
+//! carried from one sample to the next, like envelopes, filters and the phase
+//! of oscillators, stay scalar.
+//!
+//! With the `simd` feature enabled these use SSE on x86_64 and NEON on aarch64,
+//! both of which are always available on those targets. Otherwise, on other
+//! targets or with the `f64` feature, a scalar fallback is used. All functions process as many samples as
+//! the shortest of the slices given.
+
+use crate::Sample;
+
+#[cfg(all(feature = "simd", not(feature = "f64"), target_arch = "x86_64"))]
+mod arch {
+    use std::arch::x86_64::*;
+    pub const LANES: usize = 4;
+    pub type Vector = __m128;
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const f32) -> Vector {
+        _mm_loadu_ps(ptr)
+    }
+    #[inline(always)]
+    pub unsafe fn store(ptr: *mut f32, v: Vector) {
+        _mm_storeu_ps(ptr, v)
+    }
+    #[inline(always)]
+    pub unsafe fn splat(value: f32) -> Vector {
+        _mm_set1_ps(value)
+    }
+    #[inline(always)]
+    pub unsafe fn add(a: Vector, b: Vector) -> Vector {
+        _mm_add_ps(a, b)
+    }
+    #[inline(always)]
+    pub unsafe fn mul(a: Vector, b: Vector) -> Vector {
+        _mm_mul_ps(a, b)
+    }
+}
+
+#[cfg(all(feature = "simd", not(feature = "f64"), target_arch = "aarch64"))]
+mod arch {
+    use std::arch::aarch64::*;
+    pub const LANES: usize = 4;
+    pub type Vector = float32x4_t;
+    #[inline(always)]
+    pub unsafe fn load(ptr: *const f32) -> Vector {
+        vld1q_f32(ptr)
+    }
+    #[inline(always)]
+    pub unsafe fn store(ptr: *mut f32, v: Vector) {
+        vst1q_f32(ptr, v)
+    }
+    #[inline(always)]
+    pub unsafe fn splat(value: f32) -> Vector {
+        vdupq_n_f32(value)
+    }
+    #[inline(always)]
+    pub unsafe fn add(a: Vector, b: Vector) -> Vector {
+        vaddq_f32(a, b)
+    }
+    #[inline(always)]
+    pub unsafe fn mul(a: Vector, b: Vector) -> Vector {
+        vmulq_f32(a, b)
+    }
+}
+
+/// Runs `$vector` on chunks of `arch::LANES` samples starting at `$i` and
+/// `$scalar` for every remaining sample `$j`. `$len` is the number of samples
+/// to process.
+macro_rules! chunked {
+    ($len:expr, |$i:ident| $vector:block, |$j:ident| $scalar:expr) => {{
+        let len = $len;
+        #[allow(unused_mut)]
+        let mut $i = 0;
+        #[cfg(all(
+            feature = "simd",
+            not(feature = "f64"),
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        {
+            while $i + arch::LANES <= len {
+                // Safety: All pointers are offset by at most `len - LANES`
+                unsafe { $vector }
+                $i += arch::LANES;
+            }
+        }
+        for $j in $i..len {
+            $scalar;
+        }
+    }};
+}
+
+/// `out[i] = a[i] * b[i]`
+#[inline]
+pub fn mul(out: &mut [Sample], a: &[Sample], b: &[Sample]) {
+    let len = out.len().min(a.len()).min(b.len());
+    chunked!(
+        len,
+        |i| {
+            let v = arch::mul(arch::load(a.as_ptr().add(i)), arch::load(b.as_ptr().add(i)));
+            arch::store(out.as_mut_ptr().add(i), v);
+        },
+        |i| out[i] = a[i] * b[i]
+    );
+}
+
+/// `out[i] *= scalar`
+#[inline]
+pub fn scale(out: &mut [Sample], scalar: Sample) {
+    let len = out.len();
+    chunked!(
+        len,
+        |i| {
+            let ptr = out.as_mut_ptr().add(i);
+            arch::store(ptr, arch::mul(arch::load(ptr), arch::splat(scalar)));
+        },
+        |i| out[i] *= scalar
+    );
+}
+
+/// `out[i] *= a[i]`
+#[inline]
+pub fn mul_assign(out: &mut [Sample], a: &[Sample]) {
+    let len = out.len().min(a.len());
+    chunked!(
+        len,
+        |i| {
+            let ptr = out.as_mut_ptr().add(i);
+            arch::store(
+                ptr,
+                arch::mul(arch::load(ptr), arch::load(a.as_ptr().add(i))),
+            );
+        },
+        |i| out[i] *= a[i]
+    );
+}
+
+/// `out[i] += a[i]`
+#[inline]
+pub fn add_assign(out: &mut [Sample], a: &[Sample]) {
+    let len = out.len().min(a.len());
+    chunked!(
+        len,
+        |i| {
+            let ptr = out.as_mut_ptr().add(i);
+            arch::store(
+                ptr,
+                arch::add(arch::load(ptr), arch::load(a.as_ptr().add(i))),
+            );
+        },
+        |i| out[i] += a[i]
+    );
+}
+
+/// `out[i] += a[i] * gain`
+#[inline]
+pub fn mul_add_assign(out: &mut [Sample], a: &[Sample], gain: Sample) {
+    let len = out.len().min(a.len());
+    chunked!(
+        len,
+        |i| {
+            let ptr = out.as_mut_ptr().add(i);
+            let v = arch::mul(arch::load(a.as_ptr().add(i)), arch::splat(gain));
+            arch::store(ptr, arch::add(arch::load(ptr), v));
+        },
+        |i| out[i] += a[i] * gain
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn matches_scalar_including_remainder() {
+        // 11 samples to get both full chunks and a remainder
+        let a: Vec<Sample> = (0..11).map(|i| i as Sample).collect();
+        let b: Vec<Sample> = (0..11).map(|i| 0.5 * i as Sample - 1.0).collect();
+        let mut out = vec![0.0; 11];
+        mul(&mut out, &a, &b);
+        for i in 0..11 {
+            assert_eq!(out[i], a[i] * b[i]);
+        }
+        add_assign(&mut out, &a);
+        for i in 0..11 {
+            assert_eq!(out[i], a[i] * b[i] + a[i]);
+        }
+        out.copy_from_slice(&a);
+        scale(&mut out, 0.5);
+        mul_assign(&mut out, &b);
+        mul_add_assign(&mut out, &b, 2.0);
+        for i in 0..11 {
+            assert_eq!(out[i], a[i] * 0.5 * b[i] + b[i] * 2.0);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Finding the onsets in a recording and playing it back slice by slice.
+//!
+//! An [`OnsetDetector`] analyses a [`Buffer`] offline and returns the frames
+//! at which new events, e.g. drum hits or notes, start. These slice points
+//! can be given to a [`SlicePlayer`], which plays the slice chosen by its
+//! "slice" input every time it is triggered:
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::slicer::{OnsetDetector, SlicePlayer};
+//! # let mut resources = Resources::new(ResourcesSettings::default());
+//! # let mut graph = Graph::new(GraphSettings::default());
+//! # let buffer = Buffer::new(44100, 1, 44100.);
+//! let slices = OnsetDetector::new().threshold(2.0).detect(&buffer);
+//! let num_slices = slices.len();
+//! let key = resources.insert_buffer(buffer).unwrap();
+//! let player = graph.push_gen(SlicePlayer::new(key, slices));
+//! ```
+
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::buffer::{Buffer, BufferKey};
+use crate::graph::{Gen, GenState};
+use crate::patch::ResourceRef;
+use crate::spectral::hann_window;
+use crate::trig::is_trigger;
+use crate::{sample_to_f64, Resources, Sample};
+
+const OUTPUT_NAMES: [&str; 8] = [
+    "out0", "out1", "out2", "out3", "out4", "out5", "out6", "out7",
+];
+
+/// How the onset strength of a frame is measured
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OnsetMethod {
+    /// The increase in magnitude summed over all frequency bins. Finds onsets
+    /// which don't change the overall level much, e.g. a new note.
+    #[default]
+    SpectralFlux,
+    /// The increase in energy. Cheaper, and works well for percussive
+    /// material with silence between the hits.
+    Energy,
+}
+
+/// Detects onsets in a [`Buffer`] offline, see the
+/// [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct OnsetDetector {
+    method: OnsetMethod,
+    fft_size: usize,
+    hop_size: usize,
+    threshold: Sample,
+    min_gap: f64,
+}
+
+impl Default for OnsetDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The number of frames on each side that make up the local average of the
+/// onset strength
+const AVERAGE_FRAMES: usize = 8;
+
+impl OnsetDetector {
+    pub fn new() -> Self {
+        Self {
+            method: OnsetMethod::default(),
+            fft_size: 1024,
+            hop_size: 256,
+            threshold: 1.5,
+            min_gap: 0.05,
+        }
+    }
+    /// Default: [`OnsetMethod::SpectralFlux`]
+    pub fn method(mut self, method: OnsetMethod) -> Self {
+        self.method = method;
+        self
+    }
+    /// The analysis window in frames, rounded up to a power of two. Default:
+    /// 1024
+    pub fn fft_size(mut self, fft_size: usize) -> Self {
+        self.fft_size = fft_size.max(4).next_power_of_two();
+        self
+    }
+    /// The distance between analysis windows in frames. Smaller values give
+    /// more precise onsets at the cost of more analysis. Default: 256
+    pub fn hop_size(mut self, hop_size: usize) -> Self {
+        self.hop_size = hop_size.max(1);
+        self
+    }
+    /// How many times stronger than the local average an onset needs to be.
+    /// Lower values find more onsets. Default: 1.5
+    pub fn threshold(mut self, threshold: Sample) -> Self {
+        self.threshold = threshold;
+        self
+    }
+    /// The shortest time in seconds between two onsets. Default: 0.05
+    pub fn min_gap(mut self, min_gap: f64) -> Self {
+        self.min_gap = min_gap.max(0.0);
+        self
+    }
+    /// *Allocates memory*
+    /// The strength of the change at the centre of every analysis window,
+    /// one value per `hop_size` frames, from a mix of all channels.
+    pub fn onset_strength(&self, buffer: &Buffer) -> Vec<Sample> {
+        let signal = mono(buffer);
+        let fft_size = self.fft_size;
+        let window = hann_window(fft_size);
+        let fft: Arc<dyn Fft<Sample>> = FftPlanner::new().plan_fft_forward(fft_size);
+        let mut spectrum = vec![Complex::default(); fft_size];
+        let mut scratch = vec![Complex::default(); fft.get_inplace_scratch_len()];
+        let mut last_magnitudes = vec![0.0; fft_size / 2 + 1];
+        let mut last_energy = 0.0;
+        let num_windows = signal.len().div_ceil(self.hop_size);
+        let mut strength = Vec::with_capacity(num_windows);
+        for i in 0..num_windows {
+            // Windows are centred on `i * hop_size`
+            let start = (i * self.hop_size) as isize - (fft_size / 2) as isize;
+            let sample = |j: usize| {
+                let index = start + j as isize;
+                if index < 0 {
+                    0.0
+                } else {
+                    signal.get(index as usize).copied().unwrap_or(0.0)
+                }
+            };
+            let value = match self.method {
+                OnsetMethod::SpectralFlux => {
+                    for (j, (bin, w)) in spectrum.iter_mut().zip(&window).enumerate() {
+                        *bin = Complex::new(sample(j) * w, 0.0);
+                    }
+                    fft.process_with_scratch(&mut spectrum, &mut scratch);
+                    let mut flux = 0.0;
+                    for (bin, last) in spectrum.iter().zip(last_magnitudes.iter_mut()) {
+                        let magnitude = bin.norm();
+                        flux += (magnitude - *last).max(0.0);
+                        *last = magnitude;
+                    }
+                    flux
+                }
+                OnsetMethod::Energy => {
+                    let energy: Sample = window
+                        .iter()
+                        .enumerate()
+                        .map(|(j, w)| (sample(j) * w).powi(2))
+                        .sum();
+                    let rise = (energy - last_energy).max(0.0);
+                    last_energy = energy;
+                    rise
+                }
+            };
+            strength.push(value);
+        }
+        strength
+    }
+    /// *Allocates memory*
+    /// The frames at which slices start. The first slice always starts at 0.
+    pub fn detect(&self, buffer: &Buffer) -> Vec<usize> {
+        let strength = self.onset_strength(buffer);
+        let signal = mono(buffer);
+        let min_gap = (self.min_gap * buffer.sample_rate()) as usize;
+        // Ignore tiny changes, e.g. from dithering, in an otherwise silent buffer
+        let floor = strength.iter().fold(0.0 as Sample, |a, &b| a.max(b)) * 0.01;
+        let mut slices = vec![0];
+        for i in 0..strength.len() {
+            let value = strength[i];
+            let from = i.saturating_sub(AVERAGE_FRAMES);
+            let to = (i + AVERAGE_FRAMES + 1).min(strength.len());
+            let average = strength[from..to].iter().sum::<Sample>() / (to - from) as Sample;
+            let is_peak = (i == 0 || value > strength[i - 1])
+                && strength.get(i + 1).is_none_or(|&next| value >= next);
+            if !is_peak || value <= floor || value <= average * self.threshold {
+                continue;
+            }
+            let onset = self.refine(&signal, i * self.hop_size);
+            let last = *slices.last().unwrap();
+            if onset == 0 || onset < last + min_gap {
+                continue;
+            }
+            slices.push(onset);
+        }
+        slices
+    }
+    /// Move an onset found at the resolution of the hop size to where the
+    /// level rises, within half an analysis window
+    fn refine(&self, signal: &[Sample], onset: usize) -> usize {
+        let from = onset.saturating_sub(self.fft_size / 2);
+        let to = (onset + self.fft_size / 2).min(signal.len());
+        if from >= to {
+            return onset.min(signal.len());
+        }
+        let peak = signal[from..to]
+            .iter()
+            .fold(0.0 as Sample, |a, b| a.max(b.abs()));
+        signal[from..to]
+            .iter()
+            .position(|s| s.abs() >= peak * 0.5)
+            .map_or(onset, |i| from + i)
+    }
+}
+
+/// *Allocates memory*
+/// All channels of `buffer` mixed down to one
+fn mono(buffer: &Buffer) -> Vec<Sample> {
+    (0..buffer.num_frames())
+        .map(|frame| buffer.get_interleaved(frame).iter().sum())
+        .collect()
+}
+
+/// Plays one slice of a [`Buffer`] each time it is triggered, see the
+/// [module documentation](self).
+///
+/// Inputs are "trig", "slice", the index of the slice to play when
+/// triggered, and "rate", the playback speed. Slice indices wrap around so
+/// that a counter can step through all slices. Playback stops at the end of
+/// the slice.
+pub struct SlicePlayer {
+    buffer_key: BufferKey,
+    slices: Vec<usize>,
+    num_channels: usize,
+    /// The position in frames, None when not playing
+    position: Option<f64>,
+    /// The frame at which the current slice ends
+    end: f64,
+}
+
+impl SlicePlayer {
+    /// `slices` are the start frames of the slices in increasing order, e.g.
+    /// from [`OnsetDetector::detect`]. Each slice ends where the next one
+    /// starts and the last one at the end of the buffer.
+    pub fn new(buffer_key: BufferKey, slices: Vec<usize>) -> Self {
+        Self {
+            buffer_key,
+            slices,
+            num_channels: 1,
+            position: None,
+            end: 0.0,
+        }
+    }
+    /// The number of channels to output. Default: 1
+    pub fn channels(mut self, num_channels: usize) -> Self {
+        self.num_channels = num_channels.max(1);
+        self
+    }
+    pub fn num_slices(&self) -> usize {
+        self.slices.len()
+    }
+}
+
+impl Gen for SlicePlayer {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        for output in outputs.iter_mut() {
+            output.fill(0.0);
+        }
+        let Some(buffer) = resources.buffers.get(self.buffer_key) else {
+            self.position = None;
+            return GenState::Continue;
+        };
+        let num_frames = buffer.num_frames();
+        let base_rate = buffer.buf_rate_scale(resources.sample_rate);
+        let buffer_channels = buffer.num_channels();
+        for i in 0..outputs[0].len() {
+            if is_trigger(inputs[0][i]) && !self.slices.is_empty() {
+                let slice =
+                    (inputs[1][i].floor() as i64).rem_euclid(self.slices.len() as i64) as usize;
+                let start = self.slices[slice].min(num_frames);
+                let end = self
+                    .slices
+                    .get(slice + 1)
+                    .map_or(num_frames, |&end| end.min(num_frames));
+                self.position = Some(start as f64);
+                self.end = end as f64;
+            }
+            let Some(position) = self.position else {
+                continue;
+            };
+            if position >= self.end || position < 0.0 {
+                self.position = None;
+                continue;
+            }
+            let frame = position as usize;
+            let next = (frame + 1).min(self.end as usize - 1);
+            let mix = (position - frame as f64) as Sample;
+            let current = buffer.get_interleaved(frame);
+            let next = buffer.get_interleaved(next);
+            for (channel, output) in outputs.iter_mut().enumerate() {
+                let channel = channel % buffer_channels;
+                output[i] = current[channel] + (next[channel] - current[channel]) * mix;
+            }
+            self.position = Some(position + base_rate * sample_to_f64(inputs[2][i]));
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_channels
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "trig",
+            1 => "slice",
+            2 => "rate",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        OUTPUT_NAMES.get(output).copied().unwrap_or("")
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            2 => 1.0,
+            _ => 0.0,
+        }
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::Buffer(self.buffer_key)]
+    }
+    fn name(&self) -> &'static str {
+        "SlicePlayer"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    /// Decaying noise bursts starting at `onsets`
+    fn hits(onsets: &[usize], len: usize) -> Buffer {
+        let rng = fastrand::Rng::with_seed(4);
+        let mut signal = vec![0.0; len];
+        for &onset in onsets {
+            for (i, sample) in signal[onset..].iter_mut().take(4000).enumerate() {
+                *sample += (rng.f32() as Sample - 0.5) * (-(i as Sample) / 800.0).exp();
+            }
+        }
+        Buffer::from_vec(signal, 44100.)
+    }
+
+    #[test]
+    fn detect_and_play_slices() {
+        let onsets = [5000, 15000, 26000];
+        let buffer = hits(&onsets, 40000);
+        for method in [OnsetMethod::SpectralFlux, OnsetMethod::Energy] {
+            let slices = OnsetDetector::new().method(method).detect(&buffer);
+            assert_eq!(slices.len(), onsets.len() + 1, "{method:?}: {slices:?}");
+            for (&found, &onset) in slices[1..].iter().zip(&onsets) {
+                assert!(found.abs_diff(onset) < 64, "{method:?}: {slices:?}");
+            }
+        }
+
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let buffer = Buffer::from_vec((0..10).map(|i| i as Sample).collect(), 44100.);
+        let key = resources.insert_buffer(buffer).unwrap();
+        let mut player = SlicePlayer::new(key, vec![0, 4, 7]);
+        let mut trig = vec![0.0; 8];
+        trig[1] = 1.0;
+        let inputs = [
+            trig.into_boxed_slice(),
+            vec![-2.0; 8].into_boxed_slice(),
+            vec![1.0; 8].into_boxed_slice(),
+        ];
+        let mut outputs = [vec![0.0; 8].into_boxed_slice()];
+        player.process(&inputs, &mut outputs, &mut resources);
+        // Slice -2 wraps around to slice 1, which plays frames 4 to 6
+        assert_eq!(outputs[0][..], [0.0, 4.0, 5.0, 6.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
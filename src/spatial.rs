@@ -0,0 +1,539 @@
+//! Panning and spatialisation.
+//!
+//! - [`Pan2`] pans a mono signal between two channels
+//! - [`XFade`] crossfades between two signals
+//! - [`PanN`] pans a mono signal around a ring of speakers
+//! - [`AmbisonicEncoder`] and [`AmbisonicDecoder`] for first order ambisonics
+
+use std::f64::consts::TAU;
+
+use crate::graph::{Gen, GenState};
+use crate::{sample_to_f64, Resources, Sample};
+
+/// Equal power gains for a position between 0 and 1
+#[inline]
+pub(crate) fn equal_power_gains(position: Sample) -> (Sample, Sample) {
+    let radians = position.clamp(0.0, 1.0) * std::f64::consts::FRAC_PI_2 as Sample;
+    (radians.cos(), radians.sin())
+}
+
+/// Pan a mono signal to stereo with equal power. The "pan" input is between -1
+/// (left) and 1 (right) and can be modulated at audio rate.
+pub struct Pan2;
+
+impl Gen for Pan2 {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let (lefts, rights) = outputs.split_at_mut(1);
+        for (((&signal, &pan), left), right) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(lefts[0].iter_mut())
+            .zip(rights[0].iter_mut())
+        {
+            let (left_gain, right_gain) = equal_power_gains((pan + 1.0) * 0.5);
+            *left = signal * left_gain;
+            *right = signal * right_gain;
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        2
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "pan",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "left",
+            1 => "right",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Pan2"
+    }
+}
+
+/// Equal power crossfade between "a" and "b". "mix" is 0 for only "a" and 1
+/// for only "b".
+pub struct XFade;
+
+impl Gen for XFade {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (((&a, &b), &mix), out) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(inputs[2].iter())
+            .zip(outputs[0].iter_mut())
+        {
+            let (a_gain, b_gain) = equal_power_gains(mix);
+            *out = a * a_gain + b * b_gain;
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "a",
+            1 => "b",
+            2 => "mix",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "XFade"
+    }
+}
+
+/// Pans a mono signal around a ring of speakers using pair-wise constant power
+/// panning: the signal is always played by the two speakers on either side
+/// of the source.
+///
+/// Angles are in degrees, clockwise from the front. The outputs are in the
+/// same order as the speaker angles given to the constructor, which don't
+/// have to be sorted. Inputs are "signal" and "azimuth".
+pub struct PanN {
+    /// Speaker angles in radians between 0 and TAU, with their output index,
+    /// sorted by angle
+    speakers: Vec<(f64, usize)>,
+}
+
+impl PanN {
+    pub fn new(speaker_angles: &[Sample]) -> Self {
+        let mut speakers: Vec<(f64, usize)> = speaker_angles
+            .iter()
+            .enumerate()
+            .map(|(i, &angle)| (sample_to_f64(angle).to_radians().rem_euclid(TAU), i))
+            .collect();
+        speakers.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { speakers }
+    }
+    /// `num_speakers` speakers spread evenly around the ring, starting in the
+    /// front.
+    pub fn equidistant(num_speakers: usize) -> Self {
+        let angles: Vec<Sample> = (0..num_speakers)
+            .map(|i| (i as f64 * 360.0 / num_speakers as f64) as Sample)
+            .collect();
+        Self::new(&angles)
+    }
+    /// Returns the two speakers (as indices into `self.speakers`) surrounding
+    /// `azimuth` and how far between them it is.
+    fn speaker_pair(&self, azimuth: f64) -> (usize, usize, f64) {
+        let num = self.speakers.len();
+        for i in 0..num {
+            let start = self.speakers[i].0;
+            let (next, mut end) = if i + 1 < num {
+                (i + 1, self.speakers[i + 1].0)
+            } else {
+                (0, self.speakers[0].0 + TAU)
+            };
+            let mut azimuth = azimuth;
+            if azimuth < start {
+                azimuth += TAU;
+            }
+            if end <= start {
+                // All speakers at the same angle
+                end = start + TAU;
+            }
+            if azimuth >= start && azimuth < end {
+                return (i, next, (azimuth - start) / (end - start));
+            }
+        }
+        (0, 0, 0.0)
+    }
+}
+
+impl Gen for PanN {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for out in outputs.iter_mut() {
+            out.fill(0.0);
+        }
+        if self.speakers.is_empty() {
+            return GenState::Continue;
+        }
+        for (i, (&signal, &azimuth)) in inputs[0].iter().zip(inputs[1].iter()).enumerate() {
+            let azimuth = sample_to_f64(azimuth).to_radians().rem_euclid(TAU);
+            let (first, second, position) = self.speaker_pair(azimuth);
+            let (first_gain, second_gain) = equal_power_gains(position as Sample);
+            outputs[self.speakers[first].1][i] += signal * first_gain;
+            outputs[self.speakers[second].1][i] += signal * second_gain;
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        self.speakers.len()
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "azimuth",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        speaker_str(output)
+    }
+    fn name(&self) -> &'static str {
+        "PanN"
+    }
+}
+
+/// Encodes a mono signal into first order ambisonic B-format with the
+/// channels in W, X, Y, Z order and FuMa weighting (W is scaled by 1/sqrt(2)).
+///
+/// The inputs are "signal", "azimuth" and "elevation" in degrees. Azimuth is
+/// counter clockwise from the front, as is the convention for ambisonics, and
+/// elevation is positive upwards.
+pub struct AmbisonicEncoder;
+
+impl Gen for AmbisonicEncoder {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let (w, rest) = outputs.split_at_mut(1);
+        let (x, rest) = rest.split_at_mut(1);
+        let (y, z) = rest.split_at_mut(1);
+        for (i, ((&signal, &azimuth), &elevation)) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(inputs[2].iter())
+            .enumerate()
+        {
+            let [ux, uy, uz] = direction(azimuth, elevation);
+            w[0][i] = signal * std::f64::consts::FRAC_1_SQRT_2 as Sample;
+            x[0][i] = signal * ux;
+            y[0][i] = signal * uy;
+            z[0][i] = signal * uz;
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        4
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "azimuth",
+            2 => "elevation",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "w",
+            1 => "x",
+            2 => "y",
+            3 => "z",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "AmbisonicEncoder"
+    }
+}
+
+/// Unit vector pointing in a direction given in degrees
+fn direction(azimuth: Sample, elevation: Sample) -> [Sample; 3] {
+    let azimuth = sample_to_f64(azimuth).to_radians();
+    let elevation = sample_to_f64(elevation).to_radians();
+    [
+        (azimuth.cos() * elevation.cos()) as Sample,
+        (azimuth.sin() * elevation.cos()) as Sample,
+        elevation.sin() as Sample,
+    ]
+}
+
+/// Decodes first order B-format from an [`AmbisonicEncoder`] to any speaker
+/// layout.
+///
+/// The decoder is the pseudo inverse of the encoding of the speaker
+/// directions (mode matching), which works well for layouts where the
+/// speakers are spread out evenly. If all speakers are in the horizontal
+/// plane the Z channel is ignored. Inputs are "w", "x", "y" and "z" and there
+/// is one output per speaker.
+pub struct AmbisonicDecoder {
+    /// Gains for W, X, Y, Z per speaker
+    matrix: Vec<[Sample; 4]>,
+}
+
+impl AmbisonicDecoder {
+    /// Create a decoder for speakers at (azimuth, elevation) in degrees
+    pub fn new(speakers: &[(Sample, Sample)]) -> Self {
+        let horizontal = speakers.iter().all(|&(_, elevation)| elevation == 0.0);
+        let num_components = if horizontal { 3 } else { 4 };
+        // Re-encoding matrix C with one row per speaker
+        let c: Vec<[f64; 4]> = speakers
+            .iter()
+            .map(|&(azimuth, elevation)| {
+                let [x, y, z] = direction(azimuth, elevation);
+                [
+                    std::f64::consts::FRAC_1_SQRT_2,
+                    sample_to_f64(x),
+                    sample_to_f64(y),
+                    sample_to_f64(z),
+                ]
+            })
+            .collect();
+        // The decoder is C (C^T C)^-1
+        let mut ctc = [[0.0; 4]; 4];
+        for row in &c {
+            for i in 0..num_components {
+                for j in 0..num_components {
+                    ctc[i][j] += row[i] * row[j];
+                }
+            }
+        }
+        let matrix = match invert(ctc, num_components) {
+            Some(inverse) => c
+                .iter()
+                .map(|row| {
+                    let mut gains = [0.0; 4];
+                    for (j, gain) in gains.iter_mut().enumerate().take(num_components) {
+                        *gain = (0..num_components)
+                            .map(|k| row[k] * inverse[k][j])
+                            .sum::<f64>() as Sample;
+                    }
+                    gains
+                })
+                .collect(),
+            None => {
+                // Too few speakers for mode matching, fall back to projecting
+                // the sound field onto the speakers
+                let scale = 1.0 / speakers.len().max(1) as f64;
+                c.iter()
+                    .map(|row| {
+                        [
+                            (row[0] * 2.0 * scale) as Sample,
+                            (row[1] * scale) as Sample,
+                            (row[2] * scale) as Sample,
+                            (row[3] * scale) as Sample,
+                        ]
+                    })
+                    .collect()
+            }
+        };
+        Self { matrix }
+    }
+    /// `num_speakers` speakers spread evenly in the horizontal plane starting
+    /// in the front.
+    pub fn ring(num_speakers: usize) -> Self {
+        let speakers: Vec<(Sample, Sample)> = (0..num_speakers)
+            .map(|i| ((i as f64 * 360.0 / num_speakers as f64) as Sample, 0.0))
+            .collect();
+        Self::new(&speakers)
+    }
+}
+
+/// Gauss-Jordan inversion of the top left `n` by `n` part of `m`
+fn invert(mut m: [[f64; 4]; 4], n: usize) -> Option<[[f64; 4]; 4]> {
+    let mut inverse = [[0.0; 4]; 4];
+    for (i, row) in inverse.iter_mut().enumerate().take(n) {
+        row[i] = 1.0;
+    }
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&a, &b| m[a][col].abs().total_cmp(&m[b][col].abs()))?;
+        if m[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        m.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = 1.0 / m[col][col];
+        for j in 0..n {
+            m[col][j] *= scale;
+            inverse[col][j] *= scale;
+        }
+        for row in 0..n {
+            if row != col {
+                let factor = m[row][col];
+                for j in 0..n {
+                    m[row][j] -= factor * m[col][j];
+                    inverse[row][j] -= factor * inverse[col][j];
+                }
+            }
+        }
+    }
+    Some(inverse)
+}
+
+impl Gen for AmbisonicDecoder {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (gains, out) in self.matrix.iter().zip(outputs.iter_mut()) {
+            out.fill(0.0);
+            for (channel, &gain) in inputs.iter().zip(gains.iter()) {
+                if gain != 0.0 {
+                    crate::simd::mul_add_assign(out, channel, gain);
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        4
+    }
+    fn num_outputs(&self) -> usize {
+        self.matrix.len()
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "w",
+            1 => "x",
+            2 => "y",
+            3 => "z",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        speaker_str(output)
+    }
+    fn name(&self) -> &'static str {
+        "AmbisonicDecoder"
+    }
+}
+
+fn speaker_str(num: usize) -> &'static str {
+    match num {
+        0 => "speaker0",
+        1 => "speaker1",
+        2 => "speaker2",
+        3 => "speaker3",
+        4 => "speaker4",
+        5 => "speaker5",
+        6 => "speaker6",
+        7 => "speaker7",
+        8 => "speaker8",
+        9 => "speaker9",
+        10 => "speaker10",
+        11 => "speaker11",
+        12 => "speaker12",
+        13 => "speaker13",
+        14 => "speaker14",
+        15 => "speaker15",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    fn process(gen: &mut dyn Gen, inputs: &[&[Sample]]) -> Vec<Vec<Sample>> {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let len = inputs[0].len();
+        let inputs: Vec<Box<[Sample]>> = inputs.iter().map(|i| i.to_vec().into()).collect();
+        let mut outputs: Vec<Box<[Sample]>> = (0..gen.num_outputs())
+            .map(|_| vec![0.0; len].into_boxed_slice())
+            .collect();
+        gen.process(&inputs, &mut outputs, &mut resources);
+        outputs.into_iter().map(|o| o.to_vec()).collect()
+    }
+
+    #[test]
+    fn pan2_is_equal_power() {
+        let out = process(&mut Pan2, &[&[1.0; 3], &[-1.0, 0.0, 1.0]]);
+        let (left, right) = (&out[0], &out[1]);
+        assert!((left[0] - 1.0).abs() < 0.01 && right[0].abs() < 0.01);
+        assert!((left[2]).abs() < 0.01 && (right[2] - 1.0).abs() < 0.01);
+        for i in 0..3 {
+            assert!((left[i] * left[i] + right[i] * right[i] - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn xfade() {
+        let out = process(&mut XFade, &[&[1.0; 3], &[-1.0; 3], &[0.0, 0.5, 1.0]]);
+        assert!((out[0][0] - 1.0).abs() < 0.01);
+        assert!(out[0][1].abs() < 0.01);
+        assert!((out[0][2] + 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn pan_n_pairs() {
+        // The speaker order doesn't have to be sorted
+        let mut pan = PanN::new(&[0.0, 90.0, 270.0, 180.0]);
+        let out = process(&mut pan, &[&[1.0; 4], &[45.0, 90.0, 315.0, -45.0]]);
+        let half = std::f64::consts::FRAC_1_SQRT_2 as Sample;
+        assert!((out[0][0] - half).abs() < 0.01 && (out[1][0] - half).abs() < 0.01);
+        assert!((out[1][1] - 1.0).abs() < 0.01 && out[0][1].abs() < 0.01);
+        // Between the last and the first speaker
+        for i in [2, 3] {
+            assert!((out[2][i] - half).abs() < 0.01 && (out[0][i] - half).abs() < 0.01);
+            assert_eq!(out[1][i], 0.0);
+            assert_eq!(out[3][i], 0.0);
+        }
+        assert_eq!(PanN::equidistant(8).num_outputs(), 8);
+    }
+
+    #[test]
+    fn ambisonics_encode_decode() {
+        let encoded = process(&mut AmbisonicEncoder, &[&[1.0], &[0.0], &[0.0]]);
+        let encoded: Vec<&[Sample]> = encoded.iter().map(|c| c.as_slice()).collect();
+        let out = process(&mut AmbisonicDecoder::ring(4), &encoded);
+        // The basic decoder for a square
+        for (speaker, expected) in out.iter().zip([0.75, 0.25, -0.25, 0.25]) {
+            assert!((speaker[0] - expected).abs() < 1e-4, "{speaker:?}");
+        }
+        // Upwards is only in Z
+        let up = process(&mut AmbisonicEncoder, &[&[1.0], &[0.0], &[90.0]]);
+        assert!(up[1][0].abs() < 1e-6 && (up[3][0] - 1.0).abs() < 1e-6);
+        // An octahedron layout uses Z
+        let mut decoder = AmbisonicDecoder::new(&[
+            (0.0, 0.0),
+            (90.0, 0.0),
+            (180.0, 0.0),
+            (270.0, 0.0),
+            (0.0, 90.0),
+            (0.0, -90.0),
+        ]);
+        let up: Vec<&[Sample]> = up.iter().map(|c| c.as_slice()).collect();
+        let out = process(&mut decoder, &up);
+        assert!(out[4][0] > out[0][0] && out[0][0] > out[5][0]);
+    }
+}
@@ -0,0 +1,1350 @@
+//! Spectral processing using the short time Fourier transform (STFT).
+//!
+//! [`OverlapAdd`] does the windowing, FFTs and overlap-add resynthesis and
+//! [`Stft`] uses it to process a stream one sample at a time, calling a closure
+//! with the spectrum of every frame. [`PhaseVocoder`] turns frames into
+//! magnitudes and exact frequencies and back, which is what is needed to
+//! change pitch and time independently.
+//!
+//! Ready made Gens built on these are [`PitchShifter`] and
+//! [`TimeStretchPlayer`].
+//!
+//! Your own spectral effects can implement [`SpectralProcessor`], which gets
+//! the bins of every frame, and be run with [`Spectral`]. Frames can also be
+//! sent between nodes: [`StftAnalysis`] outputs the frames as a stream of bins
+//! that can be processed by [`SpectralProcess`] or any other Gen and turned
+//! back into sound by [`InverseStft`]. A few processors are included:
+//! [`Freeze`], [`Robotize`] and [`CrossSynthesis`].
+
+use std::f64::consts::TAU;
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::buffer::BufferKey;
+use crate::graph::{Gen, GenState, InputKind};
+use crate::patch::ResourceRef;
+use crate::xorrng::XOrShift32Rng;
+use crate::{sample_to_f64, Resources, Sample, StopAction};
+
+/// A periodic Hann window, which sums to a constant when overlapped by a
+/// quarter of its length or less.
+pub fn hann_window(size: usize) -> Vec<Sample> {
+    (0..size)
+        .map(|i| (0.5 - 0.5 * (TAU * i as f64 / size as f64).cos()) as Sample)
+        .collect()
+}
+
+/// A sine window, the square root of a Hann window. Its square sums to a
+/// constant when overlapped by half its length.
+pub fn sine_window(size: usize) -> Vec<Sample> {
+    (0..size)
+        .map(|i| (std::f64::consts::PI * i as f64 / size as f64).sin() as Sample)
+        .collect()
+}
+
+/// Windowed FFT analysis and inverse FFT overlap-add resynthesis.
+///
+/// Frames are `fft_size` samples long and start every `hop_size` samples. Both
+/// the analysis and the resynthesis use a Hann window, or a sine window for an
+/// overlap of 2, and the output is scaled so that resynthesizing unchanged
+/// frames gives back the input.
+pub struct OverlapAdd {
+    fft_size: usize,
+    hop_size: usize,
+    fft: Arc<dyn Fft<Sample>>,
+    ifft: Arc<dyn Fft<Sample>>,
+    scratch: Vec<Complex<Sample>>,
+    window: Vec<Sample>,
+    /// Output accumulator used as a ring buffer
+    output: Vec<Sample>,
+    output_pos: usize,
+    gain: Sample,
+}
+
+impl OverlapAdd {
+    /// `fft_size` is rounded up to a power of two. `overlap` is the number of
+    /// frames overlapping every sample, rounded up to a power of two and at
+    /// least 2.
+    pub fn new(fft_size: usize, overlap: usize) -> Self {
+        let fft_size = fft_size.max(4).next_power_of_two();
+        let overlap = overlap.max(2).next_power_of_two().min(fft_size);
+        let hop_size = fft_size / overlap;
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(fft_size);
+        let ifft = planner.plan_fft_inverse(fft_size);
+        let scratch_len = fft
+            .get_inplace_scratch_len()
+            .max(ifft.get_inplace_scratch_len());
+        let window = if overlap == 2 {
+            sine_window(fft_size)
+        } else {
+            hann_window(fft_size)
+        };
+        // The squared window summed over all overlapping frames, times the
+        // size because the inverse FFT isn't normalized
+        let window_sum: Sample = window.iter().map(|w| w * w).sum::<Sample>() / hop_size as Sample;
+        Self {
+            fft_size,
+            hop_size,
+            fft,
+            ifft,
+            scratch: vec![Complex::default(); scratch_len],
+            window,
+            output: vec![0.0; fft_size],
+            output_pos: 0,
+            gain: 1.0 / (window_sum * fft_size as Sample),
+        }
+    }
+    pub fn fft_size(&self) -> usize {
+        self.fft_size
+    }
+    pub fn hop_size(&self) -> usize {
+        self.hop_size
+    }
+    /// The number of bins from 0 Hz to the Nyquist frequency
+    pub fn num_bins(&self) -> usize {
+        self.fft_size / 2 + 1
+    }
+    /// Window a frame and transform it into `spectrum`, which needs to be
+    /// `fft_size` long. `read` is called with every index from 0 to
+    /// `fft_size` to get the samples of the frame.
+    pub fn analyze(
+        &mut self,
+        mut read: impl FnMut(usize) -> Sample,
+        spectrum: &mut [Complex<Sample>],
+    ) {
+        for (i, (bin, w)) in spectrum.iter_mut().zip(&self.window).enumerate() {
+            *bin = Complex::new(read(i) * w, 0.0);
+        }
+        self.fft.process_with_scratch(spectrum, &mut self.scratch);
+    }
+    /// Transform `spectrum` back, window it and add it to the output starting
+    /// at the next output sample. The spectrum is used as scratch space.
+    pub fn synthesize(&mut self, spectrum: &mut [Complex<Sample>]) {
+        self.ifft.process_with_scratch(spectrum, &mut self.scratch);
+        for (i, (bin, w)) in spectrum.iter().zip(&self.window).enumerate() {
+            let pos = (self.output_pos + i) % self.fft_size;
+            self.output[pos] += bin.re * w * self.gain;
+        }
+    }
+    /// Take the next sample of the resynthesized output.
+    #[inline]
+    pub fn next_output(&mut self) -> Sample {
+        let out = std::mem::replace(&mut self.output[self.output_pos], 0.0);
+        self.output_pos = (self.output_pos + 1) % self.fft_size;
+        out
+    }
+    pub fn reset(&mut self) {
+        self.output.fill(0.0);
+        self.output_pos = 0;
+    }
+}
+
+/// Streaming STFT processing. Every `hop_size` samples the spectrum of the
+/// latest `fft_size` samples is passed to a closure, which can change it
+/// before it is resynthesized. The output is delayed by `fft_size` samples.
+pub struct Stft {
+    overlap_add: OverlapAdd,
+    /// Input history used as a ring buffer
+    input: Vec<Sample>,
+    input_pos: usize,
+    hop_counter: usize,
+    spectrum: Vec<Complex<Sample>>,
+}
+
+impl Stft {
+    /// See [`OverlapAdd::new`] for how the sizes are rounded.
+    pub fn new(fft_size: usize, overlap: usize) -> Self {
+        let overlap_add = OverlapAdd::new(fft_size, overlap);
+        let fft_size = overlap_add.fft_size();
+        Self {
+            overlap_add,
+            input: vec![0.0; fft_size],
+            input_pos: 0,
+            hop_counter: 0,
+            spectrum: vec![Complex::default(); fft_size],
+        }
+    }
+    pub fn fft_size(&self) -> usize {
+        self.overlap_add.fft_size()
+    }
+    pub fn hop_size(&self) -> usize {
+        self.overlap_add.hop_size()
+    }
+    /// The delay of the output in samples
+    pub fn latency(&self) -> usize {
+        self.fft_size()
+    }
+    /// Process one sample. `process_frame` is called with the full spectrum
+    /// of a frame when one is ready.
+    #[inline]
+    pub fn process_sample(
+        &mut self,
+        input: Sample,
+        mut process_frame: impl FnMut(&mut [Complex<Sample>]),
+    ) -> Sample {
+        let fft_size = self.input.len();
+        self.input[self.input_pos] = input;
+        self.input_pos = (self.input_pos + 1) % fft_size;
+        let output = self.overlap_add.next_output();
+        self.hop_counter += 1;
+        if self.hop_counter >= self.overlap_add.hop_size() {
+            self.hop_counter = 0;
+            // The oldest sample is at `input_pos`
+            let input = &self.input;
+            let start = self.input_pos;
+            self.overlap_add
+                .analyze(|i| input[(start + i) % fft_size], &mut self.spectrum);
+            process_frame(&mut self.spectrum);
+            self.overlap_add.synthesize(&mut self.spectrum);
+        }
+        output
+    }
+    pub fn reset(&mut self) {
+        self.overlap_add.reset();
+        self.input.fill(0.0);
+        self.input_pos = 0;
+        self.hop_counter = 0;
+    }
+}
+
+/// Wrap a phase to the range -PI to PI
+#[inline]
+fn wrap_phase(phase: Sample) -> Sample {
+    let tau = TAU as Sample;
+    phase - tau * (phase / tau).round()
+}
+
+/// Converts STFT frames to magnitudes and frequencies and back, keeping the
+/// phases of the resynthesized frames coherent.
+///
+/// Frequencies are measured in bins and may be fractional.
+pub struct PhaseVocoder {
+    fft_size: usize,
+    last_phases: Vec<Sample>,
+    sum_phases: Vec<Sample>,
+    magnitudes: Vec<Sample>,
+    frequencies: Vec<Sample>,
+    shifted_magnitudes: Vec<Sample>,
+    shifted_frequencies: Vec<Sample>,
+    /// False until the first frame has been synthesized
+    started: bool,
+}
+
+impl PhaseVocoder {
+    pub fn new(fft_size: usize) -> Self {
+        let num_bins = fft_size / 2 + 1;
+        Self {
+            fft_size,
+            last_phases: vec![0.0; num_bins],
+            sum_phases: vec![0.0; num_bins],
+            magnitudes: vec![0.0; num_bins],
+            frequencies: vec![0.0; num_bins],
+            shifted_magnitudes: vec![0.0; num_bins],
+            shifted_frequencies: vec![0.0; num_bins],
+            started: false,
+        }
+    }
+    pub fn magnitudes(&self) -> &[Sample] {
+        &self.magnitudes
+    }
+    pub fn magnitudes_mut(&mut self) -> &mut [Sample] {
+        &mut self.magnitudes
+    }
+    pub fn frequencies(&self) -> &[Sample] {
+        &self.frequencies
+    }
+    pub fn frequencies_mut(&mut self) -> &mut [Sample] {
+        &mut self.frequencies
+    }
+    /// Analyze a frame that starts `hop_size` samples after the previous frame
+    /// given to this function.
+    pub fn analyze(&mut self, spectrum: &[Complex<Sample>], hop_size: usize) {
+        let num_bins = self.magnitudes.len();
+        for (k, bin) in spectrum.iter().take(num_bins).enumerate() {
+            let phase = bin.arg();
+            let difference = phase - self.last_phases[k];
+            self.last_phases[k] = phase;
+            self.magnitudes[k] = bin.norm();
+            self.frequencies[k] = self.bin_frequency(k, difference, hop_size);
+        }
+    }
+    /// Analyze a frame using a `previous` frame that starts `hop_size` samples
+    /// earlier. Use this when the frames aren't read at a steady pace, e.g.
+    /// when time stretching.
+    pub fn analyze_with_previous(
+        &mut self,
+        previous: &[Complex<Sample>],
+        spectrum: &[Complex<Sample>],
+        hop_size: usize,
+    ) {
+        let num_bins = self.magnitudes.len();
+        for (k, (bin, previous_bin)) in spectrum.iter().zip(previous).take(num_bins).enumerate() {
+            let phase = bin.arg();
+            let difference = phase - previous_bin.arg();
+            self.last_phases[k] = phase;
+            self.magnitudes[k] = bin.norm();
+            self.frequencies[k] = self.bin_frequency(k, difference, hop_size);
+        }
+    }
+    /// The exact frequency of bin `k` from the phase difference between two
+    /// frames
+    #[inline]
+    fn bin_frequency(&self, k: usize, phase_difference: Sample, hop_size: usize) -> Sample {
+        let expected = (TAU * k as f64 * hop_size as f64 / self.fft_size as f64) as Sample;
+        let deviation = wrap_phase(phase_difference - expected);
+        k as Sample + deviation * self.fft_size as Sample / (TAU as Sample * hop_size as Sample)
+    }
+    /// Move all partials up or down in frequency by `ratio`.
+    pub fn shift_pitch(&mut self, ratio: Sample) {
+        if ratio == 1.0 {
+            return;
+        }
+        let num_bins = self.magnitudes.len();
+        self.shifted_magnitudes.fill(0.0);
+        self.shifted_frequencies.fill(0.0);
+        for k in 0..num_bins {
+            let target = (k as Sample * ratio).round() as usize;
+            if target < num_bins {
+                self.shifted_magnitudes[target] += self.magnitudes[k];
+                self.shifted_frequencies[target] = self.frequencies[k] * ratio;
+            }
+        }
+        std::mem::swap(&mut self.magnitudes, &mut self.shifted_magnitudes);
+        std::mem::swap(&mut self.frequencies, &mut self.shifted_frequencies);
+    }
+    /// Write the current magnitudes and frequencies to `spectrum` for a frame
+    /// that starts `hop_size` samples after the previous synthesized frame.
+    pub fn synthesize(&mut self, spectrum: &mut [Complex<Sample>], hop_size: usize) {
+        let num_bins = self.magnitudes.len();
+        let phase_per_bin = (TAU * hop_size as f64 / self.fft_size as f64) as Sample;
+        if !self.started {
+            // Start from the analyzed phases so that the bins of a partial
+            // keep their phase relationship
+            self.sum_phases.copy_from_slice(&self.last_phases);
+            self.started = true;
+        } else {
+            for k in 0..num_bins {
+                self.sum_phases[k] =
+                    wrap_phase(self.sum_phases[k] + self.frequencies[k] * phase_per_bin);
+            }
+        }
+        for (bin, (&magnitude, &phase)) in spectrum
+            .iter_mut()
+            .zip(self.magnitudes.iter().zip(&self.sum_phases))
+        {
+            *bin = Complex::from_polar(magnitude, phase);
+        }
+        mirror_spectrum(spectrum);
+    }
+    pub fn reset(&mut self) {
+        self.last_phases.fill(0.0);
+        self.sum_phases.fill(0.0);
+        self.started = false;
+    }
+}
+
+/// Changes the pitch of the input without changing its speed.
+///
+/// Inputs are "signal" and "ratio", where a ratio of 2.0 is an octave up. The
+/// ratio is read once per frame. The output is delayed by `fft_size` samples.
+pub struct PitchShifter {
+    stft: Stft,
+    phase_vocoder: PhaseVocoder,
+}
+
+impl PitchShifter {
+    /// Larger FFT sizes work better for low sounds but smear transients more.
+    /// A good default is an `fft_size` of 2048 with an `overlap` of 4.
+    pub fn new(fft_size: usize, overlap: usize) -> Self {
+        let stft = Stft::new(fft_size, overlap);
+        let phase_vocoder = PhaseVocoder::new(stft.fft_size());
+        Self {
+            stft,
+            phase_vocoder,
+        }
+    }
+    /// The delay of the output in samples
+    pub fn latency(&self) -> usize {
+        self.stft.latency()
+    }
+}
+
+impl Gen for PitchShifter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let hop_size = self.stft.hop_size();
+        let phase_vocoder = &mut self.phase_vocoder;
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let ratio = inputs[1][i];
+            *out = self.stft.process_sample(inputs[0][i], |spectrum| {
+                phase_vocoder.analyze(spectrum, hop_size);
+                phase_vocoder.shift_pitch(ratio);
+                phase_vocoder.synthesize(spectrum, hop_size);
+            });
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "ratio",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn latency(&self) -> usize {
+        PitchShifter::latency(self)
+    }
+    fn name(&self) -> &'static str {
+        "PitchShifter"
+    }
+}
+
+/// Plays the first channel of a [`crate::buffer::Buffer`] with independent
+/// control over speed and pitch.
+///
+/// Inputs are "speed", where 0.5 is half speed, and "pitch", a ratio where
+/// 2.0 is an octave up. Both are read once per frame.
+pub struct TimeStretchPlayer {
+    buffer_key: BufferKey,
+    overlap_add: OverlapAdd,
+    phase_vocoder: PhaseVocoder,
+    previous: Vec<Complex<Sample>>,
+    spectrum: Vec<Complex<Sample>>,
+    read_pointer: f64,
+    hop_counter: usize,
+    /// The number of samples left of the output after reaching the end
+    tail: Option<usize>,
+    pub looping: bool,
+    stop_action: StopAction,
+}
+
+impl TimeStretchPlayer {
+    pub fn new(buffer_key: BufferKey, stop_action: StopAction) -> Self {
+        Self::with_fft_size(buffer_key, stop_action, 2048, 4)
+    }
+    pub fn with_fft_size(
+        buffer_key: BufferKey,
+        stop_action: StopAction,
+        fft_size: usize,
+        overlap: usize,
+    ) -> Self {
+        let overlap_add = OverlapAdd::new(fft_size, overlap);
+        let fft_size = overlap_add.fft_size();
+        Self {
+            buffer_key,
+            overlap_add,
+            phase_vocoder: PhaseVocoder::new(fft_size),
+            previous: vec![Complex::default(); fft_size],
+            spectrum: vec![Complex::default(); fft_size],
+            read_pointer: 0.0,
+            hop_counter: usize::MAX,
+            tail: None,
+            looping: false,
+            stop_action,
+        }
+    }
+    pub fn looping(mut self) -> Self {
+        self.looping = true;
+        self
+    }
+    pub fn reset(&mut self) {
+        self.jump_to(0.0);
+    }
+    /// Jump to a position in the buffer in samples of the buffer
+    pub fn jump_to(&mut self, new_pointer_pos: f64) {
+        self.read_pointer = new_pointer_pos;
+        self.tail = None;
+    }
+}
+
+impl Gen for TimeStretchPlayer {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let buffer = match resources.buffers.get(self.buffer_key) {
+            Some(buffer) => buffer,
+            None => {
+                outputs[0].fill(0.0);
+                return GenState::Continue;
+            }
+        };
+        let base_rate = buffer.buf_rate_scale(resources.sample_rate);
+        let size = buffer.size();
+        let hop_size = self.overlap_add.hop_size();
+        let looping = self.looping;
+        let read = |position: i64| -> Sample {
+            let position = if looping {
+                position.rem_euclid(size as i64)
+            } else {
+                position
+            };
+            if position >= 0 && (position as f64) < size {
+                buffer.get_interleaved(position as usize)[0]
+            } else {
+                0.0
+            }
+        };
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            if let Some(tail) = &mut self.tail {
+                if *tail == 0 {
+                    outputs[0][i..].fill(0.0);
+                    return self.stop_action.to_gen_state(i);
+                }
+                *tail -= 1;
+            }
+            *out = self.overlap_add.next_output();
+            self.hop_counter = self.hop_counter.saturating_add(1);
+            if self.hop_counter >= hop_size && self.tail.is_none() {
+                self.hop_counter = 0;
+                let start = self.read_pointer.floor() as i64;
+                self.overlap_add
+                    .analyze(|i| read(start + i as i64), &mut self.spectrum);
+                self.overlap_add.analyze(
+                    |i| read(start - hop_size as i64 + i as i64),
+                    &mut self.previous,
+                );
+                self.phase_vocoder
+                    .analyze_with_previous(&self.previous, &self.spectrum, hop_size);
+                self.phase_vocoder.shift_pitch(inputs[1][i]);
+                self.phase_vocoder.synthesize(&mut self.spectrum, hop_size);
+                self.overlap_add.synthesize(&mut self.spectrum);
+                self.read_pointer += hop_size as f64 * sample_to_f64(inputs[0][i]) * base_rate;
+                if looping {
+                    self.read_pointer = self.read_pointer.rem_euclid(size);
+                } else if self.read_pointer >= size || self.read_pointer < 0.0 {
+                    // Let the last frames fade out
+                    self.tail = Some(self.overlap_add.fft_size());
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "speed",
+            1 => "pitch",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::Buffer(self.buffer_key)]
+    }
+    fn name(&self) -> &'static str {
+        "TimeStretchPlayer"
+    }
+}
+
+/// Fill in the negative frequencies of a spectrum of a real signal from its
+/// positive frequencies.
+fn mirror_spectrum(spectrum: &mut [Complex<Sample>]) {
+    let fft_size = spectrum.len();
+    for k in fft_size / 2 + 1..fft_size {
+        spectrum[k] = spectrum[fft_size - k].conj();
+    }
+}
+
+/// Pack the bins from 0 Hz to Nyquist into the first `fft_size / 2` bins by
+/// storing the real Nyquist bin in the imaginary part of the 0 Hz bin.
+fn pack_frame(spectrum: &mut [Complex<Sample>]) {
+    spectrum[0].im = spectrum[spectrum.len() / 2].re;
+}
+
+/// The inverse of [`pack_frame`] for a full spectrum of `fft_size` bins
+fn unpack_frame(spectrum: &mut [Complex<Sample>]) {
+    let nyquist = spectrum[0].im;
+    spectrum[0].im = 0.0;
+    spectrum[spectrum.len() / 2] = Complex::new(nyquist, 0.0);
+    mirror_spectrum(spectrum);
+}
+
+/// A spectral effect processing one STFT frame at a time. Run it on a signal
+/// with [`Spectral`] or on a stream of bins with [`SpectralProcess`].
+///
+/// Frames contain the bins from 0 Hz up to and including the Nyquist
+/// frequency.
+pub trait SpectralProcessor: Send {
+    /// Allocate anything needed for frames of `num_bins` bins. Called on the
+    /// control thread before any frames are processed.
+    fn init(&mut self, _num_bins: usize) {}
+    /// Called before the first frame with a seed drawn from
+    /// [`Resources::rng`], for processors using random numbers. This makes
+    /// them reproducible with [`Resources::new_with_seed`].
+    fn seed(&mut self, _seed: u32) {}
+    /// Process a frame in place. `sidechain` is the frame of the sidechain if
+    /// [`SpectralProcessor::uses_sidechain`] is true, otherwise it is empty.
+    /// `parameters` are the values of the parameter inputs at the time of the
+    /// frame.
+    fn process_frame(
+        &mut self,
+        frame: &mut [Complex<Sample>],
+        sidechain: &[Complex<Sample>],
+        parameters: &[Sample],
+    );
+    /// True if the processor needs a second signal, e.g. for cross synthesis
+    fn uses_sidechain(&self) -> bool {
+        false
+    }
+    fn num_parameters(&self) -> usize {
+        0
+    }
+    fn parameter_desc(&self, _parameter: usize) -> &'static str {
+        ""
+    }
+    fn name(&self) -> &'static str {
+        "SpectralProcessor"
+    }
+}
+
+/// Runs a [`SpectralProcessor`] on a signal.
+///
+/// Inputs are "signal", "sidechain" if the processor uses one, and then the
+/// parameters of the processor. The output is delayed by `fft_size` samples.
+pub struct Spectral<P: SpectralProcessor> {
+    processor: P,
+    overlap_add: OverlapAdd,
+    input: Vec<Sample>,
+    sidechain_input: Vec<Sample>,
+    input_pos: usize,
+    hop_counter: usize,
+    spectrum: Vec<Complex<Sample>>,
+    sidechain_spectrum: Vec<Complex<Sample>>,
+    parameters: Vec<Sample>,
+    /// True once the processor has been given a seed
+    seeded: bool,
+}
+
+impl<P: SpectralProcessor> Spectral<P> {
+    /// See [`OverlapAdd::new`] for how the sizes are rounded.
+    pub fn new(mut processor: P, fft_size: usize, overlap: usize) -> Self {
+        let overlap_add = OverlapAdd::new(fft_size, overlap);
+        let fft_size = overlap_add.fft_size();
+        processor.init(overlap_add.num_bins());
+        let sidechain_size = if processor.uses_sidechain() {
+            fft_size
+        } else {
+            0
+        };
+        Self {
+            parameters: vec![0.0; processor.num_parameters()],
+            processor,
+            overlap_add,
+            input: vec![0.0; fft_size],
+            sidechain_input: vec![0.0; sidechain_size],
+            input_pos: 0,
+            hop_counter: 0,
+            spectrum: vec![Complex::default(); fft_size],
+            sidechain_spectrum: vec![Complex::default(); sidechain_size],
+            seeded: false,
+        }
+    }
+    pub fn processor(&self) -> &P {
+        &self.processor
+    }
+    pub fn processor_mut(&mut self) -> &mut P {
+        &mut self.processor
+    }
+    /// The delay of the output in samples
+    pub fn latency(&self) -> usize {
+        self.overlap_add.fft_size()
+    }
+}
+
+impl<P: SpectralProcessor> Gen for Spectral<P> {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        if !self.seeded {
+            self.processor.seed(resources.rng.u32(..));
+            self.seeded = true;
+        }
+        let uses_sidechain = !self.sidechain_input.is_empty();
+        let parameter_offset = if uses_sidechain { 2 } else { 1 };
+        let fft_size = self.input.len();
+        let num_bins = self.overlap_add.num_bins();
+        let sidechain_bins = if uses_sidechain { num_bins } else { 0 };
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            self.input[self.input_pos] = inputs[0][i];
+            if uses_sidechain {
+                self.sidechain_input[self.input_pos] = inputs[1][i];
+            }
+            self.input_pos = (self.input_pos + 1) % fft_size;
+            *out = self.overlap_add.next_output();
+            self.hop_counter += 1;
+            if self.hop_counter >= self.overlap_add.hop_size() {
+                self.hop_counter = 0;
+                for (parameter, value) in self.parameters.iter_mut().enumerate() {
+                    *value = inputs[parameter_offset + parameter][i];
+                }
+                // The oldest sample is at `input_pos`
+                let start = self.input_pos;
+                let input = &self.input;
+                self.overlap_add
+                    .analyze(|j| input[(start + j) % fft_size], &mut self.spectrum);
+                if uses_sidechain {
+                    let sidechain_input = &self.sidechain_input;
+                    self.overlap_add.analyze(
+                        |j| sidechain_input[(start + j) % fft_size],
+                        &mut self.sidechain_spectrum,
+                    );
+                }
+                self.processor.process_frame(
+                    &mut self.spectrum[..num_bins],
+                    &self.sidechain_spectrum[..sidechain_bins],
+                    &self.parameters,
+                );
+                mirror_spectrum(&mut self.spectrum);
+                self.overlap_add.synthesize(&mut self.spectrum);
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1 + self.processor.uses_sidechain() as usize + self.processor.num_parameters()
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        let uses_sidechain = self.processor.uses_sidechain();
+        match input {
+            0 => "signal",
+            1 if uses_sidechain => "sidechain",
+            _ => self
+                .processor
+                .parameter_desc(input - 1 - uses_sidechain as usize),
+        }
+    }
+    fn input_kind(&self, input: usize) -> InputKind {
+        match input {
+            1 if self.processor.uses_sidechain() => InputKind::Sidechain,
+            _ => InputKind::Main,
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn latency(&self) -> usize {
+        Spectral::latency(self)
+    }
+    fn name(&self) -> &'static str {
+        self.processor.name()
+    }
+}
+
+/// Outputs the STFT frames of the input as a stream of bins for processing
+/// in other nodes, e.g. [`SpectralProcess`], and resynthesis with
+/// [`InverseStft`].
+///
+/// Frames overlap by half. Every frame of `fft_size` samples is sent as
+/// `fft_size / 2` bins, one per sample, on the outputs "real", "imag" and
+/// "bin", where "bin" is the index of the bin. Bin 0 carries the 0 Hz bin in
+/// "real" and the Nyquist bin in "imag". A frame is sent during the
+/// `fft_size / 2` samples after it was analyzed.
+pub struct StftAnalysis {
+    overlap_add: OverlapAdd,
+    input: Vec<Sample>,
+    input_pos: usize,
+    spectrum: Vec<Complex<Sample>>,
+    bin: usize,
+}
+
+impl StftAnalysis {
+    /// `fft_size` is rounded up to a power of two.
+    pub fn new(fft_size: usize) -> Self {
+        let overlap_add = OverlapAdd::new(fft_size, 2);
+        let fft_size = overlap_add.fft_size();
+        Self {
+            overlap_add,
+            input: vec![0.0; fft_size],
+            input_pos: 0,
+            spectrum: vec![Complex::default(); fft_size],
+            bin: 0,
+        }
+    }
+    pub fn fft_size(&self) -> usize {
+        self.overlap_add.fft_size()
+    }
+    /// The delay from the input of this node to the output of an
+    /// [`InverseStft`] connected directly to it. Every [`SpectralProcess`] in
+    /// between adds another `fft_size / 2` samples.
+    pub fn latency(&self) -> usize {
+        self.fft_size() * 3 / 2
+    }
+}
+
+impl Gen for StftAnalysis {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let fft_size = self.input.len();
+        let hop_size = self.overlap_add.hop_size();
+        for i in 0..outputs[0].len() {
+            self.input[self.input_pos] = inputs[0][i];
+            self.input_pos = (self.input_pos + 1) % fft_size;
+            let bin = self.spectrum[self.bin];
+            outputs[0][i] = bin.re;
+            outputs[1][i] = bin.im;
+            outputs[2][i] = self.bin as Sample;
+            self.bin += 1;
+            if self.bin >= hop_size {
+                self.bin = 0;
+                let start = self.input_pos;
+                let input = &self.input;
+                self.overlap_add
+                    .analyze(|j| input[(start + j) % fft_size], &mut self.spectrum);
+                pack_frame(&mut self.spectrum);
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        3
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "real",
+            1 => "imag",
+            2 => "bin",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "StftAnalysis"
+    }
+}
+
+/// Resynthesizes a stream of bins from [`StftAnalysis`].
+///
+/// Inputs are "real", "imag" and "bin". A frame is resynthesized when its
+/// last bin arrives. The `fft_size` needs to be the same as for the
+/// [`StftAnalysis`].
+pub struct InverseStft {
+    overlap_add: OverlapAdd,
+    frame: Vec<Complex<Sample>>,
+}
+
+impl InverseStft {
+    /// `fft_size` is rounded up to a power of two.
+    pub fn new(fft_size: usize) -> Self {
+        let overlap_add = OverlapAdd::new(fft_size, 2);
+        let fft_size = overlap_add.fft_size();
+        Self {
+            overlap_add,
+            frame: vec![Complex::default(); fft_size],
+        }
+    }
+}
+
+impl Gen for InverseStft {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let hop_size = self.overlap_add.hop_size();
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            *out = self.overlap_add.next_output();
+            let bin = inputs[2][i].round();
+            if bin >= 0.0 && (bin as usize) < hop_size {
+                let bin = bin as usize;
+                self.frame[bin] = Complex::new(inputs[0][i], inputs[1][i]);
+                if bin == hop_size - 1 {
+                    unpack_frame(&mut self.frame);
+                    self.overlap_add.synthesize(&mut self.frame);
+                    self.frame.fill(Complex::default());
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "real",
+            1 => "imag",
+            2 => "bin",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "InverseStft"
+    }
+}
+
+/// Runs a [`SpectralProcessor`] on a stream of bins from [`StftAnalysis`].
+///
+/// Inputs are "real", "imag" and "bin", then "sidechain real" and "sidechain
+/// imag" if the processor uses a sidechain, followed by the parameters of the
+/// processor. The sidechain needs to come from an [`StftAnalysis`] with the
+/// same `fft_size` that was started at the same time. Outputs are "real",
+/// "imag" and "bin", delayed by one frame.
+pub struct SpectralProcess<P: SpectralProcessor> {
+    processor: P,
+    frame: Vec<Complex<Sample>>,
+    sidechain_frame: Vec<Complex<Sample>>,
+    /// The last processed frame in packed form
+    output_frame: Vec<Complex<Sample>>,
+    parameters: Vec<Sample>,
+    /// True once the processor has been given a seed
+    seeded: bool,
+}
+
+impl<P: SpectralProcessor> SpectralProcess<P> {
+    /// `fft_size` is rounded up to a power of two and needs to be the same as
+    /// for the [`StftAnalysis`].
+    pub fn new(mut processor: P, fft_size: usize) -> Self {
+        let num_bins = fft_size.max(4).next_power_of_two() / 2 + 1;
+        processor.init(num_bins);
+        let sidechain_bins = if processor.uses_sidechain() {
+            num_bins
+        } else {
+            0
+        };
+        Self {
+            parameters: vec![0.0; processor.num_parameters()],
+            processor,
+            frame: vec![Complex::default(); num_bins],
+            sidechain_frame: vec![Complex::default(); sidechain_bins],
+            output_frame: vec![Complex::default(); num_bins - 1],
+            seeded: false,
+        }
+    }
+    pub fn processor(&self) -> &P {
+        &self.processor
+    }
+    pub fn processor_mut(&mut self) -> &mut P {
+        &mut self.processor
+    }
+}
+
+impl<P: SpectralProcessor> Gen for SpectralProcess<P> {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        if !self.seeded {
+            self.processor.seed(resources.rng.u32(..));
+            self.seeded = true;
+        }
+        let uses_sidechain = !self.sidechain_frame.is_empty();
+        let parameter_offset = if uses_sidechain { 5 } else { 3 };
+        let hop_size = self.output_frame.len();
+        for i in 0..outputs[0].len() {
+            let bin = inputs[2][i].round();
+            outputs[2][i] = inputs[2][i];
+            if !(bin >= 0.0 && (bin as usize) < hop_size) {
+                outputs[0][i] = 0.0;
+                outputs[1][i] = 0.0;
+                continue;
+            }
+            let bin = bin as usize;
+            outputs[0][i] = self.output_frame[bin].re;
+            outputs[1][i] = self.output_frame[bin].im;
+            self.frame[bin] = Complex::new(inputs[0][i], inputs[1][i]);
+            if uses_sidechain {
+                self.sidechain_frame[bin] = Complex::new(inputs[3][i], inputs[4][i]);
+            }
+            if bin == hop_size - 1 {
+                for (parameter, value) in self.parameters.iter_mut().enumerate() {
+                    *value = inputs[parameter_offset + parameter][i];
+                }
+                for frame in [&mut self.frame, &mut self.sidechain_frame] {
+                    if let Some(first) = frame.first_mut() {
+                        let nyquist = std::mem::replace(&mut first.im, 0.0);
+                        frame[hop_size] = Complex::new(nyquist, 0.0);
+                    }
+                }
+                self.processor.process_frame(
+                    &mut self.frame,
+                    &self.sidechain_frame,
+                    &self.parameters,
+                );
+                self.output_frame.copy_from_slice(&self.frame[..hop_size]);
+                self.output_frame[0].im = self.frame[hop_size].re;
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        3 + self.processor.uses_sidechain() as usize * 2 + self.processor.num_parameters()
+    }
+    fn num_outputs(&self) -> usize {
+        3
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        let uses_sidechain = self.processor.uses_sidechain();
+        match input {
+            0 => "real",
+            1 => "imag",
+            2 => "bin",
+            3 if uses_sidechain => "sidechain real",
+            4 if uses_sidechain => "sidechain imag",
+            _ => self
+                .processor
+                .parameter_desc(input - 3 - uses_sidechain as usize * 2),
+        }
+    }
+    fn input_kind(&self, input: usize) -> InputKind {
+        match input {
+            3 | 4 if self.processor.uses_sidechain() => InputKind::Sidechain,
+            _ => InputKind::Main,
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "real",
+            1 => "imag",
+            2 => "bin",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        self.processor.name()
+    }
+}
+
+/// Holds the current spectrum while the "freeze" parameter is above 0.5.
+/// The phases of the frozen spectrum are randomized every frame for a
+/// smooth sustained sound.
+#[derive(Debug, Clone)]
+pub struct Freeze {
+    magnitudes: Vec<Sample>,
+    frozen: bool,
+    rng: Option<XOrShift32Rng>,
+}
+
+impl Freeze {
+    /// The seed is taken from [`Resources::rng`]
+    pub fn new() -> Self {
+        Self {
+            magnitudes: vec![],
+            frozen: false,
+            rng: None,
+        }
+    }
+    /// Create with a seed to get the same phases every time
+    pub fn with_seed(seed: u32) -> Self {
+        Self {
+            rng: Some(XOrShift32Rng::new(seed)),
+            ..Self::new()
+        }
+    }
+}
+
+impl Default for Freeze {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpectralProcessor for Freeze {
+    fn init(&mut self, num_bins: usize) {
+        self.magnitudes = vec![0.0; num_bins];
+    }
+    fn seed(&mut self, seed: u32) {
+        self.rng.get_or_insert(XOrShift32Rng::new(seed));
+    }
+    fn process_frame(
+        &mut self,
+        frame: &mut [Complex<Sample>],
+        _sidechain: &[Complex<Sample>],
+        parameters: &[Sample],
+    ) {
+        if parameters[0] <= 0.5 {
+            self.frozen = false;
+            return;
+        }
+        if !self.frozen {
+            for (magnitude, bin) in self.magnitudes.iter_mut().zip(frame.iter()) {
+                *magnitude = bin.norm();
+            }
+            self.frozen = true;
+        }
+        let rng = self.rng.get_or_insert_with(XOrShift32Rng::default);
+        for (bin, &magnitude) in frame.iter_mut().zip(&self.magnitudes) {
+            let phase = rng.gen_f64() * TAU;
+            *bin = Complex::from_polar(magnitude, phase as Sample);
+        }
+    }
+    fn num_parameters(&self) -> usize {
+        1
+    }
+    fn parameter_desc(&self, parameter: usize) -> &'static str {
+        match parameter {
+            0 => "freeze",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Freeze"
+    }
+}
+
+/// Sets all phases to zero, which gives a robotic voice with a pitch at the
+/// frame rate.
+#[derive(Debug, Clone, Default)]
+pub struct Robotize;
+
+impl SpectralProcessor for Robotize {
+    fn process_frame(
+        &mut self,
+        frame: &mut [Complex<Sample>],
+        _sidechain: &[Complex<Sample>],
+        _parameters: &[Sample],
+    ) {
+        for bin in frame {
+            *bin = Complex::new(bin.norm(), 0.0);
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Robotize"
+    }
+}
+
+/// Gives the signal the magnitudes of the sidechain, keeping the phases of
+/// the signal. Works best with a harmonically rich signal and a sidechain
+/// with a clear spectral envelope, e.g. a voice.
+#[derive(Debug, Clone, Default)]
+pub struct CrossSynthesis;
+
+impl SpectralProcessor for CrossSynthesis {
+    fn process_frame(
+        &mut self,
+        frame: &mut [Complex<Sample>],
+        sidechain: &[Complex<Sample>],
+        _parameters: &[Sample],
+    ) {
+        for (bin, sidechain_bin) in frame.iter_mut().zip(sidechain) {
+            *bin = Complex::from_polar(sidechain_bin.norm(), bin.arg());
+        }
+    }
+    fn uses_sidechain(&self) -> bool {
+        true
+    }
+    fn name(&self) -> &'static str {
+        "CrossSynthesis"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::Buffer;
+    use crate::{sample_to_f64, ResourcesSettings};
+
+    fn sine(freq: f64, len: usize) -> Vec<Sample> {
+        (0..len)
+            .map(|i| (TAU * freq * i as f64 / 48000.0).sin() as Sample)
+            .collect()
+    }
+
+    /// The magnitude of one frequency in the signal
+    fn goertzel(signal: &[Sample], freq: f64) -> f64 {
+        let coefficient = 2.0 * (TAU * freq / 48000.0).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for &x in signal {
+            let s = sample_to_f64(x) + coefficient * s1 - s2;
+            s2 = s1;
+            s1 = s;
+        }
+        (s1 * s1 + s2 * s2 - coefficient * s1 * s2).sqrt()
+    }
+
+    #[test]
+    fn stft_reconstructs_the_input() {
+        let mut stft = Stft::new(256, 4);
+        let input = sine(440.0, 2048);
+        let output: Vec<Sample> = input
+            .iter()
+            .map(|&x| stft.process_sample(x, |_| {}))
+            .collect();
+        let latency = stft.latency();
+        // Skip the first frames that don't overlap with enough other frames
+        for i in latency * 2..input.len() {
+            assert!((output[i] - input[i - latency]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn pitch_shifter_moves_the_frequency() {
+        let mut resources = Resources::new(ResourcesSettings {
+            sample_rate: 48000.,
+            ..Default::default()
+        });
+        let mut shifter = PitchShifter::new(1024, 4);
+        let len = 9600;
+        let inputs = [
+            sine(750.0, len).into_boxed_slice(),
+            vec![2.0; len].into_boxed_slice(),
+        ];
+        let mut outputs = [vec![0.0; len].into_boxed_slice()];
+        shifter.process(&inputs, &mut outputs, &mut resources);
+        let settled = &outputs[0][shifter.latency() * 2..];
+        assert!(goertzel(settled, 1500.0) > goertzel(settled, 750.0) * 10.0);
+    }
+
+    #[test]
+    fn time_stretch_keeps_the_pitch() {
+        let mut resources = Resources::new(ResourcesSettings {
+            sample_rate: 48000.,
+            ..Default::default()
+        });
+        let len = 4800;
+        let key = resources
+            .insert_buffer(Buffer::from_vec(sine(750.0, len), 48000.))
+            .unwrap();
+        let mut player = TimeStretchPlayer::with_fft_size(key, StopAction::FreeSelf, 1024, 4);
+        let block = 256;
+        let inputs = [
+            vec![0.5; block].into_boxed_slice(),
+            vec![1.0; block].into_boxed_slice(),
+        ];
+        let mut output = vec![];
+        let mut outputs = [vec![0.0; block].into_boxed_slice()];
+        for _ in 0..100 {
+            let state = player.process(&inputs, &mut outputs, &mut resources);
+            output.extend_from_slice(&outputs[0]);
+            if matches!(state, GenState::FreeSelf) {
+                break;
+            }
+        }
+        // Half speed takes twice as long, plus the latency
+        assert!(output.len() >= len * 2);
+        assert!(output.len() < len * 2 + 1024 + block * 2);
+        let middle = &output[2048..len * 2 - 1024];
+        assert!(goertzel(middle, 750.0) > goertzel(middle, 375.0) * 10.0);
+        assert!(goertzel(middle, 750.0) > middle.len() as f64 * 0.4);
+    }
+
+    /// Halves the level
+    struct Half;
+    impl SpectralProcessor for Half {
+        fn process_frame(
+            &mut self,
+            frame: &mut [Complex<Sample>],
+            _sidechain: &[Complex<Sample>],
+            _parameters: &[Sample],
+        ) {
+            for bin in frame {
+                *bin *= 0.5;
+            }
+        }
+    }
+
+    #[test]
+    fn bin_streams_between_nodes() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut analysis = StftAnalysis::new(256);
+        let mut process = SpectralProcess::new(Half, 256);
+        let mut inverse = InverseStft::new(256);
+        let len = 4096;
+        let input = sine(440.0, len);
+        let mut bins = [0, 1, 2].map(|_| vec![0.0; len].into_boxed_slice());
+        analysis.process(
+            &[input.clone().into_boxed_slice()],
+            &mut bins,
+            &mut resources,
+        );
+        let mut processed = [0, 1, 2].map(|_| vec![0.0; len].into_boxed_slice());
+        process.process(&bins, &mut processed, &mut resources);
+        let mut outputs = [vec![0.0; len].into_boxed_slice()];
+        inverse.process(&processed, &mut outputs, &mut resources);
+        let latency = analysis.latency() + 128;
+        for i in latency * 2..len {
+            assert!((outputs[0][i] - input[i - latency] * 0.5).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn spectral_processors() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let len = 4096;
+        // Silence in the sidechain silences the signal
+        let mut cross = Spectral::new(CrossSynthesis, 512, 4);
+        assert_eq!(cross.num_inputs(), 2);
+        let inputs = [
+            sine(440.0, len).into_boxed_slice(),
+            vec![0.0; len].into_boxed_slice(),
+        ];
+        let mut outputs = [vec![1.0; len].into_boxed_slice()];
+        cross.process(&inputs, &mut outputs, &mut resources);
+        assert!(outputs[0].iter().all(|s| s.abs() < 1e-6));
+        // A frozen sound keeps playing after the input stops
+        let mut freeze = Spectral::new(Freeze::new(), 512, 4);
+        assert_eq!(freeze.input_desc(1), "freeze");
+        let mut signal = sine(440.0, len);
+        signal[len / 2..].fill(0.0);
+        let mut freeze_input = vec![0.0; len];
+        freeze_input[len / 2 - 100..].fill(1.0);
+        let inputs = [signal.into_boxed_slice(), freeze_input.into_boxed_slice()];
+        freeze.process(&inputs, &mut outputs, &mut resources);
+        let tail = &outputs[0][len - 1024..];
+        assert!(tail.iter().map(|s| s * s).sum::<Sample>() / 1024.0 > 0.01);
+    }
+}
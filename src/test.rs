@@ -0,0 +1,594 @@
+//! Helpers for testing Gens and Graphs by rendering them offline and comparing
+//! the output to stored reference data.
+//!
+//! A [`Harness`] runs a single Gen or a whole Graph for a number of blocks.
+//! Signals can be fed to the inputs and changes can be scripted to happen at
+//! the start of a block. The result is a [`Rendered`], which can be compared
+//! to another [`Rendered`] within a tolerance, or to a golden file with
+//! [`Rendered::assert_golden`].
+//!
+//! Golden files are 32 bit float WAV files. A missing golden file is written
+//! from the current output, and all of them are rewritten when the
+//! `KNYST_BLESS` environment variable is set. When the output doesn't match,
+//! the output and the difference are written next to the golden file as
+//! `<name>.actual.wav` and `<name>.diff.wav` so that they can be inspected in
+//! an audio editor.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::test::Harness;
+//! # use knyst::wavetable::{Wavetable, WavetableOscillatorOwned};
+//! let rendered = Harness::gen(WavetableOscillatorOwned::new(Wavetable::sine()))
+//!     .block_size(64)
+//!     .sample_rate(44100.)
+//!     .set(0, "freq", 440.)
+//!     .set(4, "freq", 880.)
+//!     .run(8)
+//!     .unwrap();
+//! assert_eq!(rendered.num_frames(), 512);
+//! // rendered.assert_golden("tests/golden/oscillator.wav", 1e-6);
+//! ```
+
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::graph::{
+    constant, Connection, ConnectionError, Gen, Graph, GraphSettings, NodeAddress, ToNodeError,
+};
+use crate::recorder::WavWriter;
+use crate::{sample_to_f32, Resources, ResourcesSettings, Sample};
+
+/// The environment variable which makes [`Rendered::assert_golden`] rewrite
+/// golden files instead of comparing to them
+pub const BLESS_VAR: &str = "KNYST_BLESS";
+
+#[derive(thiserror::Error, Debug)]
+pub enum HarnessError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    ConnectionError(#[from] ConnectionError),
+    #[error("Unable to create a node from the Graph: {0}")]
+    CouldNotCreateNode(ToNodeError),
+    #[error("The input {input} doesn't exist, there are only {num_inputs} inputs.")]
+    InputOutOfBounds { input: usize, num_inputs: usize },
+    #[error("A change to a Gen was scripted, but the Harness is running a Graph.")]
+    NoGen,
+    #[error("Not a supported WAV file: {0}")]
+    InvalidWav(String),
+}
+
+/// What is being tested
+enum Source {
+    Gen {
+        push: Box<dyn FnOnce(&mut Graph) -> NodeAddress>,
+        num_inputs: usize,
+        num_outputs: usize,
+    },
+    Graph(Box<Graph>),
+}
+
+/// A change to the input of a node at the start of a block
+struct ScriptedChange {
+    block: usize,
+    /// None for the Gen under test
+    node: Option<NodeAddress>,
+    label: &'static str,
+    value: Sample,
+}
+
+/// Runs a Gen or a Graph offline, see the [module documentation](self).
+pub struct Harness {
+    source: Source,
+    block_size: usize,
+    sample_rate: Sample,
+    resources: Option<Resources>,
+    inputs: Vec<(usize, Vec<Sample>)>,
+    changes: Vec<ScriptedChange>,
+}
+
+impl Harness {
+    /// Test a single Gen. Its inputs and outputs become the inputs and
+    /// outputs of the Graph it is run in.
+    pub fn gen<G: Gen + Send + 'static>(gen: G) -> Self {
+        let num_inputs = gen.num_inputs();
+        let num_outputs = gen.num_outputs();
+        Self::new(Source::Gen {
+            push: Box::new(move |graph| graph.push_gen(gen)),
+            num_inputs,
+            num_outputs,
+        })
+    }
+    /// Test a Graph. The block size and sample rate of the Graph are used,
+    /// [`Harness::block_size`] and [`Harness::sample_rate`] have no effect.
+    pub fn graph(graph: Graph) -> Self {
+        let mut harness = Self::new(Source::Graph(Box::new(graph)));
+        if let Source::Graph(graph) = &harness.source {
+            harness.block_size = graph.block_size();
+            harness.sample_rate = graph.sample_rate();
+        }
+        harness
+    }
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            block_size: 64,
+            sample_rate: 44100.,
+            resources: None,
+            inputs: vec![],
+            changes: vec![],
+        }
+    }
+    /// Default: 64
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        if matches!(self.source, Source::Gen { .. }) {
+            self.block_size = block_size;
+        }
+        self
+    }
+    /// Default: 44100
+    pub fn sample_rate(mut self, sample_rate: Sample) -> Self {
+        if matches!(self.source, Source::Gen { .. }) {
+            self.sample_rate = sample_rate;
+        }
+        self
+    }
+    /// The Resources to run with, e.g. to provide buffers. Default: Resources
+    /// created with [`Resources::new_with_seed`] and the seed 0, so that
+    /// Gens using random numbers produce the same output every run.
+    pub fn resources(mut self, resources: Resources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+    /// Feed `signal` to an input of the Gen or Graph, starting at the first
+    /// frame. The input is silent after the end of the signal.
+    pub fn input(mut self, input: usize, signal: impl Into<Vec<Sample>>) -> Self {
+        self.inputs.push((input, signal.into()));
+        self
+    }
+    /// Set the input `label` of the Gen under test to `value` at the start of
+    /// `block`. Inputs which are fed a signal can't be changed.
+    pub fn set(mut self, block: usize, label: &'static str, value: Sample) -> Self {
+        self.changes.push(ScriptedChange {
+            block,
+            node: None,
+            label,
+            value,
+        });
+        self
+    }
+    /// Set the input `label` of a node in the Graph under test to `value` at
+    /// the start of `block`
+    pub fn set_node(
+        mut self,
+        block: usize,
+        node: NodeAddress,
+        label: &'static str,
+        value: Sample,
+    ) -> Self {
+        self.changes.push(ScriptedChange {
+            block,
+            node: Some(node),
+            label,
+            value,
+        });
+        self
+    }
+    /// *Allocates memory*
+    /// Run for `num_blocks` blocks and return the output.
+    pub fn run(self, num_blocks: usize) -> Result<Rendered, HarnessError> {
+        let Harness {
+            source,
+            block_size,
+            sample_rate,
+            resources,
+            inputs,
+            mut changes,
+        } = self;
+        let (mut graph, gen_node) = match source {
+            Source::Gen {
+                push,
+                num_inputs,
+                num_outputs,
+            } => {
+                let mut graph = Graph::new(GraphSettings {
+                    num_inputs,
+                    num_outputs,
+                    max_node_inputs: num_inputs.max(1),
+                    block_size,
+                    sample_rate,
+                    ..Default::default()
+                });
+                let node = push(&mut graph);
+                for &(input, _) in &inputs {
+                    if input < num_inputs {
+                        graph.connect(
+                            Connection::graph_input(node)
+                                .from_index(input)
+                                .to_index(input),
+                        )?;
+                    }
+                }
+                if num_outputs > 0 {
+                    graph.connect(Connection::graph_output(node).channels(num_outputs))?;
+                }
+                (graph, Some(node))
+            }
+            Source::Graph(graph) => (*graph, None),
+        };
+        let num_inputs = graph.num_inputs();
+        if let Some(&(input, _)) = inputs.iter().find(|(input, _)| *input >= num_inputs) {
+            return Err(HarnessError::InputOutOfBounds { input, num_inputs });
+        }
+        let mut resources = resources.unwrap_or_else(|| {
+            Resources::new_with_seed(
+                ResourcesSettings {
+                    sample_rate,
+                    ..Default::default()
+                },
+                0,
+            )
+        });
+        let mut node = graph.to_node().map_err(HarnessError::CouldNotCreateNode)?;
+        changes.sort_by_key(|change| change.block);
+        let mut changes = changes.into_iter().peekable();
+        let mut input_buffers = vec![vec![0.0; block_size].into_boxed_slice(); num_inputs];
+        let mut channels = vec![Vec::with_capacity(num_blocks * block_size); node.num_outputs()];
+        for block in 0..num_blocks {
+            while let Some(change) = changes.next_if(|change| change.block <= block) {
+                let target = change.node.or(gen_node).ok_or(HarnessError::NoGen)?;
+                graph.connect(constant(change.value).to(target).to_label(change.label))?;
+            }
+            graph.commit_changes();
+            graph.update();
+            for buffer in input_buffers.iter_mut() {
+                buffer.fill(0.0);
+            }
+            let start = block * block_size;
+            for (input, signal) in &inputs {
+                let end = (start + block_size).min(signal.len()).max(start);
+                if let Some(signal) = signal.get(start..end) {
+                    input_buffers[*input][..signal.len()].copy_from_slice(signal);
+                }
+            }
+            node.process(&input_buffers, &mut resources);
+            for (channel, output) in channels.iter_mut().zip(node.output_buffers()) {
+                channel.extend_from_slice(output);
+            }
+        }
+        Ok(Rendered {
+            sample_rate,
+            channels,
+        })
+    }
+}
+
+/// Rendered audio, one `Vec` per channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rendered {
+    pub sample_rate: Sample,
+    pub channels: Vec<Vec<Sample>>,
+}
+
+/// How a [`Rendered`] differs from the expected one
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    #[error("Expected {expected_channels} channels of {expected_frames} frames, got {actual_channels} channels of {actual_frames} frames.")]
+    Shape {
+        expected_channels: usize,
+        expected_frames: usize,
+        actual_channels: usize,
+        actual_frames: usize,
+    },
+    #[error("{num_differing} samples differ by more than the tolerance, the first in channel {channel} at frame {frame}: expected {expected}, got {actual}. The largest difference is {max_difference}.")]
+    Samples {
+        channel: usize,
+        frame: usize,
+        expected: Sample,
+        actual: Sample,
+        max_difference: Sample,
+        num_differing: usize,
+    },
+}
+
+impl Rendered {
+    pub fn num_channels(&self) -> usize {
+        self.channels.len()
+    }
+    /// The number of frames in the longest channel
+    pub fn num_frames(&self) -> usize {
+        self.channels.iter().map(Vec::len).max().unwrap_or(0)
+    }
+    pub fn channel(&self, channel: usize) -> &[Sample] {
+        &self.channels[channel]
+    }
+    /// Compare to `expected`, allowing every sample to differ by at most
+    /// `tolerance`
+    pub fn compare(&self, expected: &Rendered, tolerance: Sample) -> Result<(), Mismatch> {
+        let same_shape = self.num_channels() == expected.num_channels()
+            && self
+                .channels
+                .iter()
+                .zip(&expected.channels)
+                .all(|(a, b)| a.len() == b.len());
+        if !same_shape {
+            return Err(Mismatch::Shape {
+                expected_channels: expected.num_channels(),
+                expected_frames: expected.num_frames(),
+                actual_channels: self.num_channels(),
+                actual_frames: self.num_frames(),
+            });
+        }
+        let mut first = None;
+        let mut max_difference: Sample = 0.0;
+        let mut num_differing = 0;
+        for (channel, (actual, expected)) in
+            self.channels.iter().zip(&expected.channels).enumerate()
+        {
+            for (frame, (&a, &e)) in actual.iter().zip(expected).enumerate() {
+                let difference = (a - e).abs();
+                // NaN never compares, treat it as differing unless both are NaN
+                if difference > tolerance || (a.is_nan() != e.is_nan()) {
+                    num_differing += 1;
+                    max_difference = max_difference.max(difference);
+                    first.get_or_insert((channel, frame, e, a));
+                }
+            }
+        }
+        match first {
+            None => Ok(()),
+            Some((channel, frame, expected, actual)) => Err(Mismatch::Samples {
+                channel,
+                frame,
+                expected,
+                actual,
+                max_difference,
+                num_differing,
+            }),
+        }
+    }
+    /// *Allocates memory*
+    /// The sample by sample difference `self - other`. Missing channels and
+    /// frames are treated as silence.
+    pub fn diff(&self, other: &Rendered) -> Rendered {
+        let num_channels = self.num_channels().max(other.num_channels());
+        let num_frames = self.num_frames().max(other.num_frames());
+        let sample = |rendered: &Rendered, channel: usize, frame: usize| {
+            rendered
+                .channels
+                .get(channel)
+                .and_then(|c| c.get(frame))
+                .copied()
+                .unwrap_or(0.0)
+        };
+        let channels = (0..num_channels)
+            .map(|channel| {
+                (0..num_frames)
+                    .map(|frame| sample(self, channel, frame) - sample(other, channel, frame))
+                    .collect()
+            })
+            .collect();
+        Rendered {
+            sample_rate: self.sample_rate,
+            channels,
+        }
+    }
+    /// Write to a 32 bit float WAV file
+    pub fn save_wav(&self, path: impl AsRef<Path>) -> Result<(), HarnessError> {
+        let mut writer =
+            WavWriter::create(path.as_ref(), self.num_channels(), self.sample_rate as u32)?;
+        let num_frames = self.num_frames();
+        let mut frame = vec![0.0_f32; self.num_channels()];
+        for i in 0..num_frames {
+            for (sample, channel) in frame.iter_mut().zip(&self.channels) {
+                *sample = sample_to_f32(channel.get(i).copied().unwrap_or(0.0));
+            }
+            writer.write_samples(&frame)?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+    /// Read a 32 bit float WAV file, e.g. one written by [`Rendered::save_wav`]
+    pub fn load_wav(path: impl AsRef<Path>) -> Result<Self, HarnessError> {
+        let mut bytes = vec![];
+        std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+        let invalid = |reason: &str| HarnessError::InvalidWav(reason.to_string());
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("missing RIFF/WAVE header"));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let mut format = None;
+        let mut data = None;
+        let mut pos = 12;
+        while pos + 8 <= bytes.len() {
+            let size = u32_at(pos + 4) as usize;
+            let body = pos + 8;
+            let end = (body + size).min(bytes.len());
+            match &bytes[pos..pos + 4] {
+                b"fmt " if size >= 16 && end - body >= 16 => {
+                    // (format tag, channels, sample rate, bits per sample)
+                    format = Some((
+                        u16_at(body),
+                        u16_at(body + 2) as usize,
+                        u32_at(body + 4),
+                        u16_at(body + 14),
+                    ));
+                }
+                b"data" => data = Some(&bytes[body..end]),
+                _ => (),
+            }
+            // Chunks are padded to an even size
+            pos = body + size + size % 2;
+        }
+        let (format_tag, num_channels, sample_rate, bits) =
+            format.ok_or_else(|| invalid("missing fmt chunk"))?;
+        let data = data.ok_or_else(|| invalid("missing data chunk"))?;
+        if format_tag != 3 || bits != 32 {
+            return Err(invalid("only 32 bit float samples are supported"));
+        }
+        if num_channels == 0 {
+            return Err(invalid("no channels"));
+        }
+        let mut channels = vec![vec![]; num_channels];
+        for (i, sample) in data.chunks_exact(4).enumerate() {
+            let sample = f32::from_le_bytes(sample.try_into().unwrap());
+            channels[i % num_channels].push(sample as Sample);
+        }
+        let num_frames = data.len() / 4 / num_channels;
+        for channel in channels.iter_mut() {
+            channel.truncate(num_frames);
+        }
+        Ok(Self {
+            sample_rate: sample_rate as Sample,
+            channels,
+        })
+    }
+    /// Compare to the golden file at `path`, panicking with a report if they
+    /// differ by more than `tolerance`. See the
+    /// [module documentation](self) for how golden files are written.
+    ///
+    /// Golden files are stored with 32 bit precision, so the tolerance should
+    /// not be smaller than that when `Sample` is `f64`.
+    #[track_caller]
+    pub fn assert_golden(&self, path: impl AsRef<Path>, tolerance: Sample) {
+        let path = path.as_ref();
+        if std::env::var_os(BLESS_VAR).is_some() || !path.exists() {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).unwrap();
+            }
+            if let Err(e) = self.save_wav(path) {
+                panic!("Unable to write golden file {}: {e}", path.display());
+            }
+            return;
+        }
+        let expected = match Self::load_wav(path) {
+            Ok(expected) => expected,
+            Err(e) => panic!("Unable to read golden file {}: {e}", path.display()),
+        };
+        if let Err(mismatch) = self.compare(&expected, tolerance) {
+            let actual_path = sibling(path, "actual");
+            let diff_path = sibling(path, "diff");
+            // Best effort, the mismatch is reported either way
+            let _ = self.save_wav(&actual_path);
+            let _ = self.diff(&expected).save_wav(&diff_path);
+            panic!(
+                "{}",
+                GoldenMismatch {
+                    path,
+                    actual_path: &actual_path,
+                    diff_path: &diff_path,
+                    mismatch,
+                }
+            );
+        }
+    }
+}
+
+/// `name.wav` -> `name.<suffix>.wav`
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}.{suffix}.wav"))
+}
+
+struct GoldenMismatch<'a> {
+    path: &'a Path,
+    actual_path: &'a Path,
+    diff_path: &'a Path,
+    mismatch: Mismatch,
+}
+
+impl fmt::Display for GoldenMismatch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Output doesn't match the golden file {}",
+            self.path.display()
+        )?;
+        writeln!(f, "{}", self.mismatch)?;
+        writeln!(
+            f,
+            "The output was written to {} and the difference to {}.",
+            self.actual_path.display(),
+            self.diff_path.display()
+        )?;
+        write!(f, "Set {BLESS_VAR}=1 to accept the new output.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{GenState, Rate};
+
+    /// Multiplies its input by the "gain" input
+    struct Gain;
+    impl Gen for Gain {
+        fn process(
+            &mut self,
+            inputs: &[Box<[Sample]>],
+            outputs: &mut [Box<[Sample]>],
+            _resources: &mut Resources,
+        ) -> GenState {
+            for (out, sample) in outputs[0].iter_mut().zip(inputs[0].iter()) {
+                *out = sample * inputs[1][0];
+            }
+            GenState::Continue
+        }
+        fn num_inputs(&self) -> usize {
+            2
+        }
+        fn num_outputs(&self) -> usize {
+            1
+        }
+        fn input_desc(&self, input: usize) -> &'static str {
+            ["in", "gain"][input]
+        }
+        fn default_input(&self, input: usize) -> Sample {
+            input as Sample
+        }
+        fn input_rate(&self, input: usize) -> Rate {
+            [Rate::Audio, Rate::Control][input]
+        }
+        fn name(&self) -> &'static str {
+            "Gain"
+        }
+    }
+
+    #[test]
+    fn scripted_changes_and_golden_files() {
+        let rendered = Harness::gen(Gain)
+            .block_size(4)
+            .input(0, vec![1.0; 10])
+            .set(2, "gain", 0.5)
+            .run(3)
+            .unwrap();
+        assert_eq!(
+            rendered.channel(0),
+            [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.5, 0.5, 0.0, 0.0]
+        );
+
+        let path = std::env::temp_dir().join(format!("knyst_golden_{}.wav", std::process::id()));
+        rendered.save_wav(&path).unwrap();
+        let loaded = Rendered::load_wav(&path).unwrap();
+        assert_eq!(loaded, rendered);
+        rendered.assert_golden(&path, 0.0);
+
+        let mut changed = rendered.clone();
+        changed.channels[0][9] = 0.6;
+        assert!(rendered.compare(&changed, 0.2).is_ok());
+        assert_eq!(
+            rendered.compare(&changed, 0.01),
+            Err(Mismatch::Samples {
+                channel: 0,
+                frame: 9,
+                expected: 0.6,
+                actual: 0.5,
+                max_difference: rendered.diff(&changed).channel(0)[9].abs(),
+                num_differing: 1,
+            })
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
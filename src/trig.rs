@@ -0,0 +1,493 @@
+//! Triggers and Gens working with them.
+//!
+//! A trigger is a single sample impulse: a Gen sending a trigger outputs 1
+//! for one sample and 0 otherwise. A Gen receiving triggers treats every
+//! sample above 0 as a trigger, see [`is_trigger`]. A gate is different, it
+//! is open for as long as it is above 0, e.g. the "gate" input of a voice.
+//!
+//! - [`Metro`] sends triggers at a tempo
+//! - [`TrigDelay`] delays triggers
+//! - [`TrigDivider`] only lets every nth trigger through
+//! - [`TrigToGate`] opens a gate for a duration after every trigger
+//! - [`Latch`] samples and holds a signal on every trigger
+//! - [`Counter`] counts triggers
+
+use crate::graph::{Gen, GenState};
+use crate::{sample_to_f64, Resources, Sample};
+
+/// True if `sample` is a trigger
+#[inline]
+pub fn is_trigger(sample: Sample) -> bool {
+    sample > 0.0
+}
+
+/// Sends a trigger every beat at the tempo of the "bpm" input. The first
+/// trigger is sent on the first sample.
+pub struct Metro {
+    /// The position within the current beat from 0 to 1
+    phase: f64,
+    sample_rate: f64,
+}
+
+impl Metro {
+    pub fn new() -> Self {
+        Self {
+            phase: 1.0,
+            sample_rate: 0.0,
+        }
+    }
+}
+
+impl Default for Metro {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for Metro {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (&bpm, out) in inputs[0].iter().zip(outputs[0].iter_mut()) {
+            if self.phase >= 1.0 {
+                self.phase -= self.phase.floor();
+                *out = 1.0;
+            } else {
+                *out = 0.0;
+            }
+            self.phase += sample_to_f64(bpm).max(0.0) / (60.0 * self.sample_rate);
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_to_f64(sample_rate);
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "bpm",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "trig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Metro"
+    }
+}
+
+/// Delays every trigger by "delay_time" seconds, read when the trigger
+/// arrives. At most `max_pending` triggers can be waiting at once, further
+/// triggers are dropped.
+pub struct TrigDelay {
+    /// Samples left until each pending trigger is sent
+    pending: Vec<u64>,
+    max_pending: usize,
+    sample_rate: Sample,
+}
+
+impl TrigDelay {
+    pub fn new(max_pending: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            max_pending,
+            sample_rate: 0.0,
+        }
+    }
+}
+
+impl Default for TrigDelay {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+impl Gen for TrigDelay {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for ((&trig, &delay_time), out) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(outputs[0].iter_mut())
+        {
+            if is_trigger(trig) && self.pending.len() < self.max_pending {
+                let delay = (delay_time.max(0.0) * self.sample_rate) as u64;
+                self.pending.push(delay);
+            }
+            *out = 0.0;
+            let mut i = 0;
+            while i < self.pending.len() {
+                if self.pending[i] == 0 {
+                    *out = 1.0;
+                    self.pending.swap_remove(i);
+                } else {
+                    self.pending[i] -= 1;
+                    i += 1;
+                }
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        self.pending = Vec::with_capacity(self.max_pending);
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "trig",
+            1 => "delay_time",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "trig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "TrigDelay"
+    }
+}
+
+/// Lets the first of every "division" triggers through
+pub struct TrigDivider {
+    /// Triggers since the last one that was let through
+    count: u64,
+}
+
+impl TrigDivider {
+    pub fn new() -> Self {
+        Self { count: 0 }
+    }
+}
+
+impl Default for TrigDivider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for TrigDivider {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for ((&trig, &division), out) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(outputs[0].iter_mut())
+        {
+            *out = 0.0;
+            if is_trigger(trig) {
+                let division = (division.round() as u64).max(1);
+                if self.count == 0 {
+                    *out = 1.0;
+                }
+                self.count = (self.count + 1) % division;
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "trig",
+            1 => "division",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "trig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "TrigDivider"
+    }
+}
+
+/// Opens a gate for "duration" seconds after every trigger. A trigger while
+/// the gate is open keeps it open for "duration" from the new trigger.
+pub struct TrigToGate {
+    samples_left: u64,
+    sample_rate: Sample,
+}
+
+impl TrigToGate {
+    pub fn new() -> Self {
+        Self {
+            samples_left: 0,
+            sample_rate: 0.0,
+        }
+    }
+}
+
+impl Default for TrigToGate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for TrigToGate {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for ((&trig, &duration), out) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(outputs[0].iter_mut())
+        {
+            if is_trigger(trig) {
+                self.samples_left = (duration.max(0.0) * self.sample_rate) as u64;
+            }
+            if self.samples_left > 0 {
+                *out = 1.0;
+                self.samples_left -= 1;
+            } else {
+                *out = 0.0;
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "trig",
+            1 => "duration",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "gate",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "TrigToGate"
+    }
+}
+
+/// Outputs the value of "signal" at the last trigger, sample and hold
+pub struct Latch {
+    value: Sample,
+}
+
+impl Latch {
+    pub fn new() -> Self {
+        Self { value: 0.0 }
+    }
+}
+
+impl Default for Latch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for Latch {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for ((&signal, &trig), out) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(outputs[0].iter_mut())
+        {
+            if is_trigger(trig) {
+                self.value = signal;
+            }
+            *out = self.value;
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "trig",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Latch"
+    }
+}
+
+/// Counts the triggers on "trig", starting at 0. A trigger on "reset" sets
+/// the count to 0 before any trigger on "trig" in the same sample is counted.
+/// If "max" is above 0 the count wraps around to 0 when it reaches "max".
+pub struct Counter {
+    count: u64,
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Self { count: 0 }
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gen for Counter {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (((&trig, &reset), &max), out) in inputs[0]
+            .iter()
+            .zip(inputs[1].iter())
+            .zip(inputs[2].iter())
+            .zip(outputs[0].iter_mut())
+        {
+            if is_trigger(reset) {
+                self.count = 0;
+            }
+            if is_trigger(trig) {
+                self.count += 1;
+            }
+            let max = max.round() as u64;
+            if max > 0 {
+                self.count %= max;
+            }
+            *out = self.count as Sample;
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "trig",
+            1 => "reset",
+            2 => "max",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "count",
+            _ => "",
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Counter"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    /// Runs `gen` over `inputs`, one Vec per input, returning the first output
+    fn run(gen: &mut dyn Gen, inputs: &[Vec<Sample>]) -> Vec<Sample> {
+        gen.init(10.0);
+        let len = inputs[0].len();
+        let inputs: Vec<Box<[Sample]>> = inputs.iter().map(|i| i.clone().into()).collect();
+        let mut outputs = vec![vec![0.0; len].into_boxed_slice()];
+        let mut resources = Resources::new(ResourcesSettings::default());
+        gen.process(&inputs, &mut outputs, &mut resources);
+        outputs[0].to_vec()
+    }
+
+    #[test]
+    fn trigger_gens() {
+        // 150 bpm at a sample rate of 10 is a beat every 4 samples
+        assert_eq!(
+            run(&mut Metro::new(), &[vec![150.0; 9]]),
+            vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]
+        );
+        let trigs = vec![1.0, 0.0, 1.0, 0.0, 1.0, 0.0, 0.0];
+        assert_eq!(
+            run(&mut TrigDelay::default(), &[trigs.clone(), vec![0.2; 7]]),
+            vec![0.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0]
+        );
+        assert_eq!(
+            run(&mut TrigDivider::new(), &[trigs.clone(), vec![2.0; 7]]),
+            vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            run(&mut TrigToGate::new(), &[trigs.clone(), vec![0.2; 7]]),
+            vec![1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 0.0]
+        );
+        let ramp: Vec<Sample> = (0..7).map(|i| i as Sample).collect();
+        assert_eq!(
+            run(&mut Latch::new(), &[ramp, trigs.clone()]),
+            vec![0.0, 0.0, 2.0, 2.0, 4.0, 4.0, 4.0]
+        );
+        assert_eq!(
+            run(
+                &mut Counter::new(),
+                &[trigs, vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0], vec![0.0; 7]]
+            ),
+            vec![1.0, 1.0, 2.0, 0.0, 1.0, 1.0, 1.0]
+        );
+    }
+}
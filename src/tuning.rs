@@ -0,0 +1,440 @@
+//! Tunings other than 12 tone equal temperament.
+//!
+//! A [`Tuning`] maps MIDI note numbers to frequencies. It combines a
+//! [`Scale`], the intervals of the scale degrees, with a [`KeyboardMapping`],
+//! which decides what note plays which degree and the reference frequency.
+//! Both can be loaded from the [Scala](https://www.huygens-fokker.org/scala/)
+//! `.scl` and `.kbm` formats or built in code. Without a keyboard mapping the
+//! scale is mapped linearly with degree 0 on note 60 and note 69 at 440 Hz.
+//!
+//! The [`VoiceAllocator`](crate::voice::VoiceAllocator) and
+//! [`Pattern::from_notes`](crate::sequencer::Pattern::from_notes) take a
+//! tuning for turning notes into frequencies.
+//!
+//! ```
+//! # use knyst::tuning::*;
+//! // 19 tone equal temperament
+//! let tuning = Tuning::new(Scale::equal_temperament(19, 1200.0));
+//! assert!((tuning.note_to_freq(69).unwrap() - 440.0).abs() < 1e-3);
+//! assert!((tuning.note_to_freq(60 + 19).unwrap() / tuning.note_to_freq(60).unwrap() - 2.0).abs() < 1e-3);
+//! // Just intonation from a Scala file
+//! let scale = Scale::from_scl("! just.scl
+//! 5-limit major
+//! 7
+//! 9/8
+//! 5/4
+//! 4/3
+//! 3/2
+//! 5/3
+//! 15/8
+//! 2/1
+//! ").unwrap();
+//! let tuning = Tuning::new(scale);
+//! ```
+
+use std::path::Path;
+
+use crate::Sample;
+
+#[derive(thiserror::Error, Debug)]
+pub enum TuningError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Parse error on line {line}: {message}")]
+    Parse { line: usize, message: String },
+    #[error("A scale needs at least one degree.")]
+    EmptyScale,
+}
+
+/// The frequency ratio of an interval in cents
+pub fn cents_to_ratio(cents: f64) -> f64 {
+    (2.0_f64).powf(cents / 1200.0)
+}
+
+/// The interval of a frequency ratio in cents
+pub fn ratio_to_cents(ratio: f64) -> f64 {
+    1200.0 * ratio.log2()
+}
+
+/// The fractional MIDI note number of a frequency in equal temperament with
+/// A4 at 440 Hz, the inverse of [`crate::voice::midi_to_freq`]
+pub fn freq_to_midi(freq: Sample) -> Sample {
+    69.0 + 12.0 * (freq / 440.0).log2()
+}
+
+/// The intervals of a scale in cents above degree 0. The last interval is
+/// the period of the scale, usually an octave.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    description: String,
+    cents: Vec<f64>,
+}
+
+impl Scale {
+    /// A scale from the intervals of degree 1 and up in cents, the last one
+    /// being the period
+    pub fn from_cents(cents: Vec<f64>) -> Result<Self, TuningError> {
+        if cents.is_empty() {
+            return Err(TuningError::EmptyScale);
+        }
+        Ok(Self {
+            description: String::new(),
+            cents,
+        })
+    }
+    /// A scale from the frequency ratios of degree 1 and up, the last one
+    /// being the period
+    pub fn from_ratios(ratios: impl IntoIterator<Item = f64>) -> Result<Self, TuningError> {
+        Self::from_cents(ratios.into_iter().map(ratio_to_cents).collect())
+    }
+    /// `divisions` equal steps per `period` cents
+    pub fn equal_temperament(divisions: usize, period: f64) -> Self {
+        let divisions = divisions.max(1);
+        Self {
+            description: format!("{divisions} equal divisions of {period} cents"),
+            cents: (1..=divisions)
+                .map(|i| period * i as f64 / divisions as f64)
+                .collect(),
+        }
+    }
+    /// Parse the contents of a Scala `.scl` file
+    pub fn from_scl(scl: &str) -> Result<Self, TuningError> {
+        let mut lines = non_comment_lines(scl);
+        let (_, description) = lines.next().ok_or(TuningError::Parse {
+            line: 0,
+            message: "missing description".to_string(),
+        })?;
+        let (line, count) = lines.next().ok_or(TuningError::Parse {
+            line: 0,
+            message: "missing number of notes".to_string(),
+        })?;
+        let count: usize = parse_value(line, first_word(count))?;
+        let cents = lines
+            .take(count)
+            .map(|(line, pitch)| parse_pitch(line, first_word(pitch)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if cents.len() != count {
+            return Err(TuningError::Parse {
+                line,
+                message: format!("expected {count} notes, found {}", cents.len()),
+            });
+        }
+        let mut scale = Self::from_cents(cents)?;
+        scale.description = description.trim().to_string();
+        Ok(scale)
+    }
+    /// Load a Scala `.scl` file
+    pub fn load_scl(path: impl AsRef<Path>) -> Result<Self, TuningError> {
+        Self::from_scl(&std::fs::read_to_string(path)?)
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+    /// The number of degrees in one period
+    pub fn len(&self) -> usize {
+        self.cents.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cents.is_empty()
+    }
+    /// The interval in cents of any degree above or below degree 0
+    pub fn degree_cents(&self, degree: i64) -> f64 {
+        let len = self.cents.len() as i64;
+        let period = degree.div_euclid(len);
+        let index = degree.rem_euclid(len);
+        let cents = if index == 0 {
+            0.0
+        } else {
+            self.cents[index as usize - 1]
+        };
+        period as f64 * self.cents[len as usize - 1] + cents
+    }
+}
+
+/// Decides which note plays which scale degree and the reference frequency,
+/// like a Scala `.kbm` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    /// The scale degree of every key in a repeating pattern starting at
+    /// `middle_note`, None for keys that aren't mapped. Empty for a linear
+    /// mapping.
+    pub mapping: Vec<Option<i64>>,
+    /// The lowest note that is mapped
+    pub first_note: u8,
+    /// The highest note that is mapped
+    pub last_note: u8,
+    /// The note playing degree 0
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_freq: f64,
+    /// The degree that the pattern of the mapping repeats at, 0 for the
+    /// period of the scale
+    pub octave_degree: i64,
+}
+
+impl Default for KeyboardMapping {
+    fn default() -> Self {
+        Self::linear(60, 69, 440.0)
+    }
+}
+
+impl KeyboardMapping {
+    /// Every key plays the next degree of the scale
+    pub fn linear(middle_note: u8, reference_note: u8, reference_freq: f64) -> Self {
+        Self {
+            mapping: vec![],
+            first_note: 0,
+            last_note: 127,
+            middle_note,
+            reference_note,
+            reference_freq,
+            octave_degree: 0,
+        }
+    }
+    /// Parse the contents of a Scala `.kbm` file
+    pub fn from_kbm(kbm: &str) -> Result<Self, TuningError> {
+        let mut lines = non_comment_lines(kbm).map(|(line, text)| (line, first_word(text)));
+        let mut next = |name: &str| {
+            lines.next().ok_or_else(|| TuningError::Parse {
+                line: 0,
+                message: format!("missing {name}"),
+            })
+        };
+        let (line, map_size) = next("map size")?;
+        let map_size: usize = parse_value(line, map_size)?;
+        let (line, first_note) = next("first note")?;
+        let first_note = parse_value(line, first_note)?;
+        let (line, last_note) = next("last note")?;
+        let last_note = parse_value(line, last_note)?;
+        let (line, middle_note) = next("middle note")?;
+        let middle_note = parse_value(line, middle_note)?;
+        let (line, reference_note) = next("reference note")?;
+        let reference_note = parse_value(line, reference_note)?;
+        let (line, reference_freq) = next("reference frequency")?;
+        let reference_freq = parse_value(line, reference_freq)?;
+        let (line, octave_degree) = next("octave degree")?;
+        let octave_degree = parse_value(line, octave_degree)?;
+        let mut mapping = Vec::with_capacity(map_size);
+        for _ in 0..map_size {
+            // Trailing keys may be left out and are then unmapped
+            match lines.next() {
+                Some((_, "x")) | None => mapping.push(None),
+                Some((line, degree)) => mapping.push(Some(parse_value(line, degree)?)),
+            }
+        }
+        Ok(Self {
+            mapping,
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_freq,
+            octave_degree,
+        })
+    }
+    /// Load a Scala `.kbm` file
+    pub fn load_kbm(path: impl AsRef<Path>) -> Result<Self, TuningError> {
+        Self::from_kbm(&std::fs::read_to_string(path)?)
+    }
+    /// The scale degree played by `note`, or None if it isn't mapped
+    pub fn degree(&self, note: u8, scale: &Scale) -> Option<i64> {
+        if note < self.first_note || note > self.last_note {
+            return None;
+        }
+        let offset = note as i64 - self.middle_note as i64;
+        if self.mapping.is_empty() {
+            return Some(offset);
+        }
+        let size = self.mapping.len() as i64;
+        let octave_degree = if self.octave_degree == 0 {
+            scale.len() as i64
+        } else {
+            self.octave_degree
+        };
+        self.mapping[offset.rem_euclid(size) as usize]
+            .map(|degree| offset.div_euclid(size) * octave_degree + degree)
+    }
+}
+
+/// Maps notes to frequencies, see the [module documentation](self)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    scale: Scale,
+    mapping: KeyboardMapping,
+}
+
+impl Default for Tuning {
+    /// 12 tone equal temperament with A4 at 440 Hz
+    fn default() -> Self {
+        Self::new(Scale::equal_temperament(12, 1200.0))
+    }
+}
+
+impl Tuning {
+    /// Map `scale` linearly with degree 0 on note 60 and note 69 at 440 Hz
+    pub fn new(scale: Scale) -> Self {
+        Self {
+            scale,
+            mapping: KeyboardMapping::default(),
+        }
+    }
+    /// Set the keyboard mapping
+    pub fn mapping(mut self, mapping: KeyboardMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+    /// Load a tuning from Scala files, using the default mapping if `kbm`
+    /// is None
+    pub fn load_scala(
+        scl: impl AsRef<Path>,
+        kbm: Option<impl AsRef<Path>>,
+    ) -> Result<Self, TuningError> {
+        let tuning = Self::new(Scale::load_scl(scl)?);
+        Ok(match kbm {
+            Some(kbm) => tuning.mapping(KeyboardMapping::load_kbm(kbm)?),
+            None => tuning,
+        })
+    }
+    pub fn scale(&self) -> &Scale {
+        &self.scale
+    }
+    pub fn keyboard_mapping(&self) -> &KeyboardMapping {
+        &self.mapping
+    }
+    /// The frequency of `note`, or None if the note isn't mapped
+    pub fn note_to_freq(&self, note: u8) -> Option<Sample> {
+        let cents = self
+            .scale
+            .degree_cents(self.mapping.degree(note, &self.scale)?);
+        // An unmapped reference note is treated as if the mapping was linear
+        let reference_degree = self.mapping.reference_note as i64 - self.mapping.middle_note as i64;
+        let reference_cents = match self
+            .mapping
+            .degree(self.mapping.reference_note, &self.scale)
+        {
+            Some(degree) => self.scale.degree_cents(degree),
+            None => self.scale.degree_cents(reference_degree),
+        };
+        Some((self.mapping.reference_freq * cents_to_ratio(cents - reference_cents)) as Sample)
+    }
+}
+
+/// Lines that aren't Scala comments, with their line numbers
+fn non_comment_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.starts_with('!'))
+}
+
+/// Anything after the value on a line is ignored
+fn first_word(text: &str) -> &str {
+    text.split_whitespace().next().unwrap_or("")
+}
+
+fn parse_value<T: std::str::FromStr>(line: usize, value: &str) -> Result<T, TuningError> {
+    value.parse().map_err(|_| TuningError::Parse {
+        line,
+        message: format!("invalid value \"{value}\""),
+    })
+}
+
+/// A pitch in cents if it contains a period, otherwise a ratio
+fn parse_pitch(line: usize, pitch: &str) -> Result<f64, TuningError> {
+    if pitch.contains('.') {
+        return parse_value(line, pitch);
+    }
+    let ratio = match pitch.split_once('/') {
+        Some((numerator, denominator)) => {
+            parse_value::<f64>(line, numerator)? / parse_value::<f64>(line, denominator)?
+        }
+        None => parse_value(line, pitch)?,
+    };
+    if ratio <= 0.0 {
+        return Err(TuningError::Parse {
+            line,
+            message: format!("invalid ratio \"{pitch}\""),
+        });
+    }
+    Ok(ratio_to_cents(ratio))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduling::Beats;
+    use crate::sequencer::Pattern;
+    use crate::voice::midi_to_freq;
+
+    fn assert_close(a: Sample, b: Sample) {
+        assert!((a - b).abs() < 1e-2, "{a} != {b}");
+    }
+
+    #[test]
+    fn scala_scale_and_mapping() {
+        let scale = Scale::from_scl(
+            "! meantone.scl
+!
+ Pentatonic with a cents value and ratios
+ 5
+ 200.0
+ 5/4 major third
+ 3/2
+ 900.0 cents
+ 2
+",
+        )
+        .unwrap();
+        assert_eq!(
+            scale.description(),
+            "Pentatonic with a cents value and ratios"
+        );
+        assert_eq!(scale.len(), 5);
+        // White keys only, A at 432 Hz
+        let mapping = KeyboardMapping::from_kbm(
+            "! white.kbm
+12
+0
+127
+60
+69
+432.0
+5
+0
+x
+1
+x
+2
+x
+x
+3
+x
+4
+x
+x
+",
+        )
+        .unwrap();
+        let tuning = Tuning::new(scale).mapping(mapping);
+        assert_eq!(tuning.note_to_freq(61), None);
+        assert_close(tuning.note_to_freq(69).unwrap(), 432.0);
+        let c = tuning.note_to_freq(60).unwrap();
+        assert_close(c, 432.0 / cents_to_ratio(900.0) as Sample);
+        assert_close(tuning.note_to_freq(64).unwrap(), c * 1.25);
+        assert_close(tuning.note_to_freq(72).unwrap(), c * 2.0);
+        assert_close(tuning.note_to_freq(67).unwrap(), c * 1.5);
+
+        let pattern = Pattern::from_notes(Beats::from_beats(1), [60, 61], &tuning);
+        assert_eq!(pattern.steps()[0].value, Some(c));
+        assert_eq!(pattern.steps()[1].value, None);
+
+        let tuning = Tuning::default();
+        for note in [0, 60, 69, 127] {
+            assert_close(
+                tuning.note_to_freq(note).unwrap(),
+                midi_to_freq(note as Sample),
+            );
+        }
+        assert_close(freq_to_midi(440.0), 69.0);
+    }
+}
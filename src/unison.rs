@@ -0,0 +1,293 @@
+//! Stacks of detuned voices, e.g. for supersaw sounds.
+//!
+//! [`Unison::push`] adds a number of copies of a voice to a Graph, detuned
+//! and spread out in the stereo field, and mixes them in a stereo
+//! [`Mixer`]. The copies are controlled by a [`UnisonControl`] node with the
+//! inputs:
+//!
+//! - "freq": the frequency in Hz of the center of the stack
+//! - "detune": how far the outermost copies are detuned, in cents
+//! - "spread": how far the outermost copies are panned, 0 (center) to 1
+//!   (hard left and right)
+//! - "blend": the level of the other copies relative to the center copy or
+//!   copies, 0 to 1. The total power stays the same.
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::unison::Unison;
+//! # use knyst::wavetable::WavetableOscillatorOwned;
+//! let mut graph = Graph::new(GraphSettings {
+//!     num_outputs: 2,
+//!     max_node_inputs: 21,
+//!     ..Default::default()
+//! });
+//! let unison = Unison::push(&mut graph, 7, |graph: &mut Graph| {
+//!     Ok(graph.push_gen(WavetableOscillatorOwned::new(Wavetable::sine())))
+//! })
+//! .unwrap();
+//! graph.connect(Connection::graph_output(unison.mixer()).channels(2)).unwrap();
+//! graph.connect(constant(110.0).to(unison.control()).to_label("freq")).unwrap();
+//! graph.connect(constant(30.0).to(unison.control()).to_label("detune")).unwrap();
+//! ```
+
+use std::sync::OnceLock;
+
+use crate::graph::{ConnectionError, Gen, GenState, Graph, NodeAddress};
+use crate::mixer::Mixer;
+use crate::{Resources, Sample};
+
+/// Copies above this number have outputs without names
+pub const MAX_NAMED_COPIES: usize = 64;
+
+/// The names of the outputs of every copy: freq, gain and pan
+fn copy_names(copy: usize) -> [&'static str; 3] {
+    static NAMES: OnceLock<Vec<[&'static str; 3]>> = OnceLock::new();
+    let names = NAMES.get_or_init(|| {
+        (0..MAX_NAMED_COPIES)
+            .map(|copy| {
+                [
+                    &*Box::leak(format!("freq{copy}").into_boxed_str()),
+                    &*Box::leak(format!("gain{copy}").into_boxed_str()),
+                    &*Box::leak(format!("pan{copy}").into_boxed_str()),
+                ]
+            })
+            .collect()
+    });
+    names.get(copy).copied().unwrap_or(["", "", ""])
+}
+
+/// Calculates the frequency, gain and pan of every copy in a unison stack,
+/// see the [module documentation](self).
+///
+/// The outputs of copy `n` are "freq{n}", "gain{n}" and "pan{n}", at the
+/// indices `3n`, `3n + 1` and `3n + 2`.
+pub struct UnisonControl {
+    num_copies: usize,
+}
+
+impl UnisonControl {
+    pub fn new(num_copies: usize) -> Self {
+        Self {
+            num_copies: num_copies.max(1),
+        }
+    }
+    /// The position of `copy` in the stack from -1 to 1
+    fn position(&self, copy: usize) -> Sample {
+        if self.num_copies == 1 {
+            0.0
+        } else {
+            copy as Sample * 2.0 / (self.num_copies - 1) as Sample - 1.0
+        }
+    }
+    /// True for the one or two copies in the middle of the stack
+    fn is_center(&self, copy: usize) -> bool {
+        let n = self.num_copies;
+        copy == n / 2 || copy == (n - 1) / 2
+    }
+}
+
+impl Gen for UnisonControl {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        let num_centers = 2 - self.num_copies % 2;
+        for i in 0..outputs[0].len() {
+            let freq = inputs[0][i];
+            let detune = inputs[1][i];
+            let spread = inputs[2][i].clamp(0.0, 1.0);
+            let blend = inputs[3][i].clamp(0.0, 1.0);
+            let others = (self.num_copies - num_centers) as Sample;
+            let norm = 1.0 / (num_centers as Sample + others * blend * blend).sqrt();
+            for copy in 0..self.num_copies {
+                let position = self.position(copy);
+                let gain = if self.is_center(copy) { 1.0 } else { blend };
+                outputs[copy * 3][i] = freq * (2.0 as Sample).powf(detune * position / 1200.0);
+                outputs[copy * 3 + 1][i] = gain * norm;
+                outputs[copy * 3 + 2][i] = spread * position;
+            }
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        4
+    }
+    fn num_outputs(&self) -> usize {
+        self.num_copies * 3
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "freq",
+            1 => "detune",
+            2 => "spread",
+            3 => "blend",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        copy_names(output / 3)[output % 3]
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            0 => 220.0,
+            1 => 20.0,
+            2 | 3 => 1.0,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "UnisonControl"
+    }
+}
+
+/// The nodes of a unison stack added to a Graph, see the
+/// [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Unison {
+    control: NodeAddress,
+    mixer: NodeAddress,
+    copies: Vec<NodeAddress>,
+}
+
+impl Unison {
+    /// Add `num_copies` copies made by `make_copy` to `graph`. Every copy
+    /// needs a "freq" input and its first output is mixed. `make_copy` can
+    /// push a single Gen or a whole Graph and should return its address
+    /// without connecting its output.
+    ///
+    /// The Mixer has `3 * num_copies` inputs, so
+    /// [`GraphSettings::max_node_inputs`](crate::graph::GraphSettings::max_node_inputs)
+    /// has to be at least that.
+    pub fn push(
+        graph: &mut Graph,
+        num_copies: usize,
+        mut make_copy: impl FnMut(&mut Graph) -> Result<NodeAddress, ConnectionError>,
+    ) -> Result<Self, ConnectionError> {
+        let num_copies = num_copies.max(1);
+        let control = graph.push_gen(UnisonControl::new(num_copies));
+        let mixer_gen = Mixer::new(num_copies);
+        let indices: Vec<_> = (0..num_copies)
+            .map(|copy| {
+                (
+                    mixer_gen.signal_index(copy),
+                    mixer_gen.gain_index(copy),
+                    mixer_gen.pan_index(copy).unwrap_or(0),
+                )
+            })
+            .collect();
+        let mixer = graph.push_gen(mixer_gen);
+        let mut copies = Vec::with_capacity(num_copies);
+        for (copy, (signal, gain, pan)) in indices.into_iter().enumerate() {
+            let node = make_copy(graph)?;
+            graph.connect(control.to(node).from_index(copy * 3).to_label("freq"))?;
+            graph.connect(node.to(mixer).to_index(signal))?;
+            graph.connect(control.to(mixer).from_index(copy * 3 + 1).to_index(gain))?;
+            graph.connect(control.to(mixer).from_index(copy * 3 + 2).to_index(pan))?;
+            copies.push(node);
+        }
+        Ok(Self {
+            control,
+            mixer,
+            copies,
+        })
+    }
+    /// The node with the "freq", "detune", "spread" and "blend" inputs
+    pub fn control(&self) -> NodeAddress {
+        self.control
+    }
+    /// The stereo Mixer with the output of the stack
+    pub fn mixer(&self) -> NodeAddress {
+        self.mixer
+    }
+    pub fn copies(&self) -> &[NodeAddress] {
+        &self.copies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{constant, Connection, GraphSettings};
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn control_detunes_pans_and_blends() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut control = UnisonControl::new(3);
+        let inputs: Vec<Box<[Sample]>> = [100.0, 1200.0, 0.5, 0.0]
+            .iter()
+            .map(|&value| vec![value; 1].into_boxed_slice())
+            .collect();
+        let mut outputs = vec![vec![0.0; 1].into_boxed_slice(); 9];
+        control.process(&inputs, &mut outputs, &mut resources);
+        let values: Vec<Sample> = outputs.iter().map(|o| o[0]).collect();
+        assert_eq!(values, [50.0, 0.0, -0.5, 100.0, 1.0, 0.0, 200.0, 0.0, 0.5]);
+        assert_eq!(control.output_desc(4), "gain1");
+    }
+
+    /// Outputs its "freq" input
+    struct FreqOut;
+
+    impl Gen for FreqOut {
+        fn process(
+            &mut self,
+            inputs: &[Box<[Sample]>],
+            outputs: &mut [Box<[Sample]>],
+            _resources: &mut Resources,
+        ) -> GenState {
+            outputs[0].copy_from_slice(&inputs[0]);
+            GenState::Continue
+        }
+        fn num_inputs(&self) -> usize {
+            1
+        }
+        fn num_outputs(&self) -> usize {
+            1
+        }
+        fn input_desc(&self, input: usize) -> &'static str {
+            match input {
+                0 => "freq",
+                _ => "",
+            }
+        }
+    }
+
+    #[test]
+    fn push_mixes_the_copies() {
+        let mut graph = Graph::new(GraphSettings {
+            block_size: 1,
+            num_outputs: 2,
+            max_node_inputs: 9,
+            ..Default::default()
+        });
+        let mut graph_node = graph.to_node().unwrap();
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let unison = Unison::push(&mut graph, 3, |graph: &mut Graph| {
+            Ok(graph.push_gen(FreqOut))
+        })
+        .unwrap();
+        assert_eq!(unison.copies().len(), 3);
+        graph
+            .connect(Connection::graph_output(unison.mixer()).channels(2))
+            .unwrap();
+        graph
+            .connect(constant(100.0).to(unison.control()).to_label("freq"))
+            .unwrap();
+        graph
+            .connect(constant(1200.0).to(unison.control()).to_label("detune"))
+            .unwrap();
+        graph
+            .connect(constant(0.0).to(unison.control()).to_label("spread"))
+            .unwrap();
+        graph.commit_changes();
+        graph.update();
+        graph_node.process(&[], &mut resources);
+        let outputs = graph_node.output_buffers();
+        // Equal gains of 1/sqrt(3), panned to the center
+        let expected = 350.0 / (3.0 as Sample).sqrt() * std::f32::consts::FRAC_1_SQRT_2 as Sample;
+        assert!((outputs[0][0] - expected).abs() < 1.0, "{}", outputs[0][0]);
+        assert!((outputs[1][0] - expected).abs() < 1.0, "{}", outputs[1][0]);
+    }
+}
@@ -0,0 +1,205 @@
+//! A channel vocoder.
+//!
+//! The [`Vocoder`] splits the "modulator", typically a voice, into frequency
+//! bands with a bank of band pass filters and follows the level of every
+//! band. The "carrier", typically a harmonically rich synth, is split into
+//! the same bands and every band is multiplied by the level of the
+//! corresponding modulator band before they are summed.
+
+use crate::dynamics::time_coefficient;
+use crate::filter::{Biquad, BiquadKind};
+use crate::graph::{Gen, GenState, InputKind};
+use crate::{Resources, Sample};
+
+/// The analysis and synthesis filters of one band
+#[derive(Debug, Clone, Copy)]
+struct Band {
+    freq: Sample,
+    /// Two filters in series for steeper slopes
+    analysis: [Biquad; 2],
+    synthesis: [Biquad; 2],
+    envelope: Sample,
+}
+
+/// A channel vocoder, see the [module documentation](self).
+///
+/// Inputs are "modulator", "carrier", and "attack" and "release" in seconds
+/// for the envelope followers of the bands.
+#[derive(Debug, Clone)]
+pub struct Vocoder {
+    bands: Vec<Band>,
+    low: Sample,
+    high: Sample,
+    sample_rate: Sample,
+}
+
+impl Vocoder {
+    /// A vocoder with `num_bands` bands, at least 1
+    pub fn new(num_bands: usize) -> Self {
+        let band = Band {
+            freq: 0.0,
+            analysis: [Biquad::new(); 2],
+            synthesis: [Biquad::new(); 2],
+            envelope: 0.0,
+        };
+        Self {
+            bands: vec![band; num_bands.max(1)],
+            low: 100.0,
+            high: 8000.0,
+            sample_rate: 44100.0,
+        }
+    }
+    /// Set the center frequencies of the lowest and highest bands. The bands
+    /// in between are spaced evenly in pitch. Default: 100 Hz to 8 kHz
+    pub fn range(mut self, low: Sample, high: Sample) -> Self {
+        self.low = low.max(1.0);
+        self.high = high.max(self.low);
+        self
+    }
+    /// The center frequencies of the bands
+    pub fn band_freqs(&self) -> impl Iterator<Item = Sample> + '_ {
+        self.bands.iter().map(|band| band.freq)
+    }
+}
+
+impl Gen for Vocoder {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        _resources: &mut Resources,
+    ) -> GenState {
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let modulator = inputs[0][i];
+            let carrier = inputs[1][i];
+            let attack = time_coefficient(inputs[2][i], self.sample_rate);
+            let release = time_coefficient(inputs[3][i], self.sample_rate);
+            let mut sum = 0.0;
+            for band in &mut self.bands {
+                let level = band
+                    .analysis
+                    .iter_mut()
+                    .fold(modulator, |value, filter| filter.process(value))
+                    .abs();
+                let coefficient = if level > band.envelope {
+                    attack
+                } else {
+                    release
+                };
+                band.envelope = level + coefficient * (band.envelope - level);
+                let filtered = band
+                    .synthesis
+                    .iter_mut()
+                    .fold(carrier, |value, filter| filter.process(value));
+                sum += filtered * band.envelope;
+            }
+            *out = sum;
+        }
+        GenState::Continue
+    }
+    fn init(&mut self, sample_rate: Sample) {
+        self.sample_rate = sample_rate;
+        let num_bands = self.bands.len();
+        let ratio = if num_bands > 1 {
+            (self.high / self.low).powf(1.0 / (num_bands - 1) as Sample)
+        } else {
+            2.0
+        };
+        // Neighbouring bands cross around their -3 dB points
+        let q = (ratio.sqrt() / (ratio - 1.0)).max(0.5);
+        for (i, band) in self.bands.iter_mut().enumerate() {
+            band.freq = self.low * ratio.powi(i as i32);
+            for filter in band.analysis.iter_mut().chain(band.synthesis.iter_mut()) {
+                *filter = Biquad::new();
+                filter.set(BiquadKind::BandPass, band.freq, q, 0.0, sample_rate);
+            }
+            band.envelope = 0.0;
+        }
+    }
+    fn num_inputs(&self) -> usize {
+        4
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "modulator",
+            1 => "carrier",
+            2 => "attack",
+            3 => "release",
+            _ => "",
+        }
+    }
+    fn input_required(&self, input: usize) -> bool {
+        input < 2
+    }
+    fn input_kind(&self, input: usize) -> InputKind {
+        match input {
+            0 => InputKind::Sidechain,
+            _ => InputKind::Main,
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn default_input(&self, input: usize) -> Sample {
+        match input {
+            2 => 0.005,
+            3 => 0.02,
+            _ => 0.0,
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Vocoder"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    /// The RMS of the output for sines as modulator and carrier
+    fn vocode(modulator_freq: Sample, carrier_freq: Sample) -> Sample {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let mut vocoder = Vocoder::new(16);
+        vocoder.init(48000.0);
+        let frames = 9600;
+        let sine = |freq: Sample| -> Box<[Sample]> {
+            (0..frames)
+                .map(|i| (std::f64::consts::TAU as Sample * freq * i as Sample / 48000.0).sin())
+                .collect()
+        };
+        let inputs = vec![
+            sine(modulator_freq),
+            sine(carrier_freq),
+            vec![0.005; frames].into_boxed_slice(),
+            vec![0.02; frames].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; frames].into_boxed_slice()];
+        vocoder.process(&inputs, &mut outputs, &mut resources);
+        let tail = &outputs[0][frames / 2..];
+        (tail.iter().map(|v| v * v).sum::<Sample>() / tail.len() as Sample).sqrt()
+    }
+
+    #[test]
+    fn carrier_follows_the_modulator_spectrum() {
+        let vocoder = {
+            let mut vocoder = Vocoder::new(16).range(100.0, 8000.0);
+            vocoder.init(48000.0);
+            vocoder
+        };
+        let freqs: Vec<Sample> = vocoder.band_freqs().collect();
+        assert!((freqs[0] - 100.0).abs() < 1e-2);
+        assert!((freqs[15] - 8000.0).abs() < 1.0);
+        let matching = vocode(1000.0, 1000.0);
+        let mismatched = vocode(1000.0, 5000.0);
+        assert!(matching > 0.1, "{matching}");
+        assert!(mismatched < matching * 0.1, "{matching} {mismatched}");
+        assert!(vocode(0.0, 1000.0) < 1e-6);
+    }
+}
@@ -0,0 +1,342 @@
+//! Polyphonic voice allocation.
+//!
+//! A [`VoiceAllocator`] turns note on and note off events into changes of
+//! the inputs of voice nodes. A voice is any node, often a Graph, with some of
+//! the inputs "freq", "velocity" and "gate". Inputs a voice doesn't have are
+//! skipped. On note on "freq" is set to the frequency of the note, "velocity"
+//! to the velocity between 0 and 1 and "gate" to 1. On note off "gate" is set
+//! to 0.
+//!
+//! There are two kinds of allocators:
+//!
+//! - [`VoiceAllocator::pool`] reuses a fixed set of voices that are never
+//!   freed, picking them round-robin.
+//! - [`VoiceAllocator::spawn`] creates a new voice for every note. The voice
+//!   should free itself when it is done, e.g. using an envelope with
+//!   [`StopAction::FreeGraph`](crate::StopAction::FreeGraph), after which the
+//!   allocator no longer counts it.
+//!
+//! In both cases a note that arrives when all voices are in use steals the
+//! oldest voice, preferring voices that have already been released, unless
+//! stealing is turned off.
+//!
+//! Notes are turned into frequencies using 12 tone equal temperament unless
+//! another [`Tuning`] is set with [`VoiceAllocator::tuning`].
+//!
+//! ```
+//! # use knyst::prelude::*;
+//! # use knyst::voice::VoiceAllocator;
+//! # let mut graph = Graph::new(GraphSettings::default());
+//! # let _node = graph.to_node().unwrap();
+//! let mut voices = VoiceAllocator::spawn(8, |graph: &mut Graph| {
+//!     let voice = graph.push_gen(knyst::fm::PmOperator::new());
+//!     graph.connect(constant(1.0).to(voice).to_label("ratio"))?;
+//!     graph.connect(Connection::graph_output(voice))?;
+//!     Ok(voice)
+//! });
+//! voices.note_on(&mut graph, 60, 0.8).unwrap();
+//! voices.note_off(&mut graph, 60).unwrap();
+//! ```
+
+use crate::graph::{
+    constant, ConnectionError, FreeError, Graph, GroupAddress, GroupError, GroupPosition,
+    NodeAddress, ParameterChange, ScheduleError,
+};
+use crate::tuning::Tuning;
+use crate::Sample;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum VoiceError {
+    #[error("All voices are in use and voice stealing is turned off.")]
+    NoFreeVoice,
+    #[error("Note {0} is not mapped by the tuning.")]
+    UnmappedNote(u8),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error(transparent)]
+    Schedule(#[from] ScheduleError),
+    #[error(transparent)]
+    Free(#[from] FreeError),
+    #[error(transparent)]
+    Group(#[from] GroupError),
+}
+
+/// The frequency of a MIDI note number in equal temperament with A4 at 440 Hz
+pub fn midi_to_freq(note: Sample) -> Sample {
+    440.0 * (2.0 as Sample).powf((note - 69.0) / 12.0)
+}
+
+type MakeVoice = Box<dyn FnMut(&mut Graph) -> Result<NodeAddress, ConnectionError> + Send>;
+
+enum Voices {
+    Pool {
+        voices: Vec<NodeAddress>,
+        /// The index in `voices` to start looking for a free voice
+        next: usize,
+    },
+    Spawn {
+        max_voices: usize,
+        make_voice: MakeVoice,
+    },
+}
+
+struct ActiveVoice {
+    node: NodeAddress,
+    /// The note the voice is playing, or None if it has been released
+    note: Option<u8>,
+    /// Increases for every note on, used to find the oldest voice
+    started: u64,
+}
+
+/// Allocates voices for notes, see the [module documentation](self).
+pub struct VoiceAllocator {
+    voices: Voices,
+    active: Vec<ActiveVoice>,
+    steal: bool,
+    note_counter: u64,
+    tuning: Option<Tuning>,
+    group: Option<GroupAddress>,
+}
+
+impl VoiceAllocator {
+    /// Allocate notes to the given voices round-robin
+    pub fn pool(voices: Vec<NodeAddress>) -> Self {
+        Self::new(Voices::Pool { voices, next: 0 })
+    }
+    /// Create a new voice for every note using `make_voice`, with at most
+    /// `max_voices` voices at once. `make_voice` should add the voice to the
+    /// Graph, connect its output and return its address.
+    pub fn spawn(
+        max_voices: usize,
+        make_voice: impl FnMut(&mut Graph) -> Result<NodeAddress, ConnectionError> + Send + 'static,
+    ) -> Self {
+        Self::new(Voices::Spawn {
+            max_voices,
+            make_voice: Box::new(make_voice),
+        })
+    }
+    fn new(voices: Voices) -> Self {
+        Self {
+            voices,
+            active: vec![],
+            steal: true,
+            note_counter: 0,
+            tuning: None,
+            group: None,
+        }
+    }
+    /// Set whether the oldest voice is stolen when all voices are in use.
+    /// Default: true
+    pub fn steal(mut self, steal: bool) -> Self {
+        self.steal = steal;
+        self
+    }
+    /// Set the tuning used to turn notes into frequencies
+    pub fn tuning(mut self, tuning: Tuning) -> Self {
+        self.tuning = Some(tuning);
+        self
+    }
+    /// Add spawned voices to the tail of `group`, e.g. to run them before
+    /// the effects in a later group
+    pub fn group(mut self, group: GroupAddress) -> Self {
+        self.group = Some(group);
+        self
+    }
+    /// The number of voices currently playing a note or releasing
+    pub fn num_active(&mut self, graph: &Graph) -> usize {
+        self.remove_finished(graph);
+        self.active.len()
+    }
+    /// Start playing `note` with a `velocity` between 0 and 1. Returns the
+    /// voice playing it.
+    pub fn note_on(
+        &mut self,
+        graph: &mut Graph,
+        note: u8,
+        velocity: Sample,
+    ) -> Result<NodeAddress, VoiceError> {
+        self.remove_finished(graph);
+        let freq = match &self.tuning {
+            Some(tuning) => tuning
+                .note_to_freq(note)
+                .ok_or(VoiceError::UnmappedNote(note))?,
+            None => midi_to_freq(note as Sample),
+        };
+        let node = match &mut self.voices {
+            Voices::Pool { voices, next } => {
+                let free = (0..voices.len())
+                    .map(|i| (*next + i) % voices.len())
+                    .find(|&i| {
+                        !self
+                            .active
+                            .iter()
+                            .any(|active| active.node == voices[i] && active.note.is_some())
+                    });
+                let node = match free {
+                    Some(i) => {
+                        *next = (i + 1) % voices.len();
+                        voices[i]
+                    }
+                    None if self.steal && !voices.is_empty() => Self::oldest(&self.active).node,
+                    None => return Err(VoiceError::NoFreeVoice),
+                };
+                self.active.retain(|active| active.node != node);
+                Self::schedule(graph, node, "freq", freq)?;
+                Self::schedule(graph, node, "velocity", velocity)?;
+                Self::schedule(graph, node, "gate", 1.0)?;
+                node
+            }
+            Voices::Spawn {
+                max_voices,
+                make_voice,
+            } => {
+                if self.active.len() >= *max_voices {
+                    if !self.steal || self.active.is_empty() {
+                        return Err(VoiceError::NoFreeVoice);
+                    }
+                    let oldest = Self::oldest(&self.active).node;
+                    graph.free_node(oldest)?;
+                    self.active.retain(|active| active.node != oldest);
+                }
+                let node = make_voice(graph)?;
+                if let Some(group) = self.group {
+                    graph.move_node(node, group, GroupPosition::Tail)?;
+                }
+                Self::set_constant(graph, node, "freq", freq)?;
+                Self::set_constant(graph, node, "velocity", velocity)?;
+                Self::set_constant(graph, node, "gate", 1.0)?;
+                node
+            }
+        };
+        self.active.push(ActiveVoice {
+            node,
+            note: Some(note),
+            started: self.note_counter,
+        });
+        self.note_counter += 1;
+        Ok(node)
+    }
+    /// Release all voices playing `note`
+    pub fn note_off(&mut self, graph: &mut Graph, note: u8) -> Result<(), VoiceError> {
+        for active in &mut self.active {
+            if active.note == Some(note) {
+                active.note = None;
+                Self::schedule(graph, active.node, "gate", 0.0)?;
+            }
+        }
+        Ok(())
+    }
+    /// Release all voices
+    pub fn release_all(&mut self, graph: &mut Graph) -> Result<(), VoiceError> {
+        for active in &mut self.active {
+            if active.note.take().is_some() {
+                Self::schedule(graph, active.node, "gate", 0.0)?;
+            }
+        }
+        Ok(())
+    }
+    /// Forget voices that have freed themselves
+    fn remove_finished(&mut self, graph: &Graph) {
+        self.active
+            .retain(|active| graph.contains_node(active.node));
+    }
+    /// The oldest released voice, or the oldest voice if none are released.
+    /// `active` must not be empty.
+    fn oldest(active: &[ActiveVoice]) -> &ActiveVoice {
+        active
+            .iter()
+            .min_by_key(|active| (active.note.is_some(), active.started))
+            .unwrap()
+    }
+    fn schedule(
+        graph: &mut Graph,
+        node: NodeAddress,
+        label: &'static str,
+        value: Sample,
+    ) -> Result<(), VoiceError> {
+        match graph.schedule_change(ParameterChange::now(node, value).l(label)) {
+            Ok(()) | Err(ScheduleError::InputLabelNotFound(_)) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn set_constant(
+        graph: &mut Graph,
+        node: NodeAddress,
+        label: &'static str,
+        value: Sample,
+    ) -> Result<(), VoiceError> {
+        match graph.connect(constant(value).to(node).to_label(label)) {
+            Ok(()) | Err(ConnectionError::InvalidInputLabel(_)) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::{gen, Connection, GenState, GraphSettings};
+
+    #[test]
+    fn spawned_voices() {
+        let mut graph = Graph::new(GraphSettings::default());
+        let _node = graph.to_node().unwrap();
+        let mut voices = VoiceAllocator::spawn(2, |graph: &mut Graph| {
+            let voice = graph.push_gen(
+                gen(|inputs, outputs, _| {
+                    outputs[0].copy_from_slice(&inputs[0]);
+                    GenState::Continue
+                })
+                .input("freq")
+                .input("gate")
+                .output("out"),
+            );
+            graph.connect(Connection::graph_output(voice))?;
+            Ok(voice)
+        });
+        let first = voices.note_on(&mut graph, 69, 1.0).unwrap();
+        let second = voices.note_on(&mut graph, 72, 1.0).unwrap();
+        voices.note_off(&mut graph, 72).unwrap();
+        // The released voice is stolen before the older held one
+        let third = voices.note_on(&mut graph, 76, 1.0).unwrap();
+        assert!(graph.contains_node(first));
+        assert!(!graph.contains_node(second));
+        assert!(graph.contains_node(third));
+        assert_eq!(voices.num_active(&graph), 2);
+        let mut voices = voices.steal(false);
+        assert_eq!(
+            voices.note_on(&mut graph, 60, 1.0),
+            Err(VoiceError::NoFreeVoice)
+        );
+    }
+
+    #[test]
+    fn pooled_voices() {
+        let mut graph = Graph::new(GraphSettings::default());
+        let _node = graph.to_node().unwrap();
+        let pool: Vec<_> = (0..3)
+            .map(|_| graph.push_gen(gen(|_, _, _| GenState::Continue).input("gate")))
+            .collect();
+        let mut voices = VoiceAllocator::pool(pool.clone());
+        assert_eq!(voices.note_on(&mut graph, 60, 1.0).unwrap(), pool[0]);
+        assert_eq!(voices.note_on(&mut graph, 62, 1.0).unwrap(), pool[1]);
+        voices.note_off(&mut graph, 60).unwrap();
+        assert_eq!(voices.note_on(&mut graph, 64, 1.0).unwrap(), pool[2]);
+        // Round-robin wraps around to the released first voice
+        assert_eq!(voices.note_on(&mut graph, 65, 1.0).unwrap(), pool[0]);
+        // All voices are held so the oldest, playing 62, is stolen
+        assert_eq!(voices.note_on(&mut graph, 67, 1.0).unwrap(), pool[1]);
+        assert_eq!(voices.num_active(&graph), 3);
+        assert!((midi_to_freq(69.0) - 440.0).abs() < 1e-3);
+        let mut voices = VoiceAllocator::pool(pool).tuning(Tuning::default().mapping(
+            crate::tuning::KeyboardMapping {
+                first_note: 21,
+                ..Default::default()
+            },
+        ));
+        assert_eq!(
+            voices.note_on(&mut graph, 20, 1.0),
+            Err(VoiceError::UnmappedNote(20))
+        );
+    }
+}
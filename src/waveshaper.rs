@@ -0,0 +1,318 @@
+//! Waveshaping distortion.
+//!
+//! A [`Waveshaper`] maps every sample through a transfer curve. The built in
+//! curves are soft saturation, hard clipping and foldback. Any other curve can
+//! be stored as a [`LookupTable`] in [`Resources::lookup_tables`] and used with
+//! [`TransferFunction::Table`].
+//!
+//! Waveshaping adds harmonics which can alias when they go above the Nyquist
+//! frequency. Enabling oversampling runs the transfer curve at twice the
+//! sample rate to reduce aliasing.
+
+use slotmap::new_key_type;
+
+use crate::graph::{Gen, GenState};
+use crate::oversample::{halfband_taps, HALFBAND_TAPS};
+use crate::patch::ResourceRef;
+use crate::{Resources, Sample};
+
+new_key_type! {
+    pub struct LookupTableKey;
+}
+
+/// A transfer curve mapping the input range -1 to 1 to an output value.
+/// Inputs outside of the range are clamped and values in between the points
+/// of the table are linearly interpolated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookupTable {
+    values: Vec<Sample>,
+}
+
+impl LookupTable {
+    /// Create a table from values evenly spaced from input -1 to input 1.
+    /// At least two values are needed, an empty table is padded with zeros.
+    pub fn new(mut values: Vec<Sample>) -> Self {
+        while values.len() < 2 {
+            values.push(0.0);
+        }
+        Self { values }
+    }
+    /// Create a table with `size` points by sampling `f` between -1 and 1.
+    pub fn from_fn(size: usize, f: impl Fn(Sample) -> Sample) -> Self {
+        let size = size.max(2);
+        let values = (0..size)
+            .map(|i| f(i as Sample / (size - 1) as Sample * 2.0 - 1.0))
+            .collect();
+        Self { values }
+    }
+    pub fn values(&self) -> &[Sample] {
+        &self.values
+    }
+    /// Look up the output value for an input value
+    #[inline]
+    pub fn get(&self, input: Sample) -> Sample {
+        let last = self.values.len() - 1;
+        let position = (input.clamp(-1.0, 1.0) + 1.0) * 0.5 * last as Sample;
+        let index = (position as usize).min(last - 1);
+        let mix = position - index as Sample;
+        self.values[index] + (self.values[index + 1] - self.values[index]) * mix
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFunction {
+    /// Soft saturation using tanh
+    Tanh,
+    /// Clip everything outside of -1 to 1
+    HardClip,
+    /// Fold everything outside of -1 to 1 back into the range
+    Foldback,
+    /// A [`LookupTable`] in [`Resources::lookup_tables`]. The output is
+    /// silent if the table doesn't exist.
+    Table(LookupTableKey),
+}
+
+impl TransferFunction {
+    /// Apply the transfer function. `table` is only used by
+    /// [`TransferFunction::Table`].
+    #[inline]
+    fn apply(self, x: Sample, table: Option<&LookupTable>) -> Sample {
+        match self {
+            TransferFunction::Tanh => x.tanh(),
+            TransferFunction::HardClip => x.clamp(-1.0, 1.0),
+            TransferFunction::Foldback => ((x - 1.0).rem_euclid(4.0) - 2.0).abs() - 1.0,
+            TransferFunction::Table(_) => table.map(|table| table.get(x)).unwrap_or(0.0),
+        }
+    }
+}
+
+/// 2x oversampling using a windowed sinc halfband filter for both
+/// interpolation and decimation.
+#[derive(Debug, Clone)]
+struct Oversampler {
+    taps: [Sample; HALFBAND_TAPS],
+    up: [Sample; HALFBAND_TAPS],
+    down: [Sample; HALFBAND_TAPS],
+    pos: usize,
+}
+
+impl Oversampler {
+    fn new() -> Self {
+        Self {
+            taps: halfband_taps(),
+            up: [0.0; HALFBAND_TAPS],
+            down: [0.0; HALFBAND_TAPS],
+            pos: 0,
+        }
+    }
+    /// The delay in samples at the original sample rate
+    fn latency(&self) -> usize {
+        (HALFBAND_TAPS - 1) / 2
+    }
+    fn filter(
+        taps: &[Sample; HALFBAND_TAPS],
+        history: &[Sample; HALFBAND_TAPS],
+        pos: usize,
+    ) -> Sample {
+        // `pos` is the index of the newest sample
+        let mut sum = 0.0;
+        for (i, tap) in taps.iter().enumerate() {
+            sum += tap * history[(pos + HALFBAND_TAPS - i) % HALFBAND_TAPS];
+        }
+        sum
+    }
+    #[inline]
+    fn process(&mut self, input: Sample, mut shape: impl FnMut(Sample) -> Sample) -> Sample {
+        let mut output = 0.0;
+        // Zero stuffing halves the level, which is made up for by the 2.0
+        for (sub_sample, value) in [input * 2.0, 0.0].into_iter().enumerate() {
+            self.pos = (self.pos + 1) % HALFBAND_TAPS;
+            self.up[self.pos] = value;
+            let upsampled = Self::filter(&self.taps, &self.up, self.pos);
+            self.down[self.pos] = shape(upsampled);
+            if sub_sample == 1 {
+                output = Self::filter(&self.taps, &self.down, self.pos);
+            }
+        }
+        output
+    }
+}
+
+/// Distortion by mapping the signal through a [`TransferFunction`].
+///
+/// Inputs are "signal", "drive" and "gain". The signal is multiplied by the
+/// drive before the transfer function and the result is multiplied by the
+/// gain.
+#[derive(Debug, Clone)]
+pub struct Waveshaper {
+    transfer: TransferFunction,
+    oversampler: Option<Oversampler>,
+}
+
+impl Waveshaper {
+    pub fn new(transfer: TransferFunction) -> Self {
+        Self {
+            transfer,
+            oversampler: None,
+        }
+    }
+    pub fn tanh() -> Self {
+        Self::new(TransferFunction::Tanh)
+    }
+    pub fn hard_clip() -> Self {
+        Self::new(TransferFunction::HardClip)
+    }
+    pub fn foldback() -> Self {
+        Self::new(TransferFunction::Foldback)
+    }
+    pub fn table(key: LookupTableKey) -> Self {
+        Self::new(TransferFunction::Table(key))
+    }
+    /// Run the transfer function at twice the sample rate to reduce aliasing.
+    /// This delays the output by [`Waveshaper::latency`] samples.
+    pub fn oversample(mut self) -> Self {
+        self.oversampler = Some(Oversampler::new());
+        self
+    }
+    /// The delay of the output in samples
+    pub fn latency(&self) -> usize {
+        self.oversampler
+            .as_ref()
+            .map(|oversampler| oversampler.latency())
+            .unwrap_or(0)
+    }
+}
+
+impl Gen for Waveshaper {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let table = match self.transfer {
+            TransferFunction::Table(key) => match resources.lookup_tables.get(key) {
+                Some(table) => Some(table),
+                None => {
+                    outputs[0].fill(0.0);
+                    return GenState::Continue;
+                }
+            },
+            _ => None,
+        };
+        let transfer = self.transfer;
+        for (i, out) in outputs[0].iter_mut().enumerate() {
+            let driven = inputs[0][i] * inputs[1][i];
+            let shaped = match &mut self.oversampler {
+                Some(oversampler) => oversampler.process(driven, |x| transfer.apply(x, table)),
+                None => transfer.apply(driven, table),
+            };
+            *out = shaped * inputs[2][i];
+        }
+        GenState::Continue
+    }
+    fn num_inputs(&self) -> usize {
+        3
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "signal",
+            1 => "drive",
+            2 => "gain",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        match self.transfer {
+            TransferFunction::Table(key) => vec![ResourceRef::LookupTable(key)],
+            _ => vec![],
+        }
+    }
+    fn name(&self) -> &'static str {
+        "Waveshaper"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sample_to_f64, ResourcesSettings};
+
+    fn run(
+        waveshaper: &mut Waveshaper,
+        signal: &[Sample],
+        drive: Sample,
+        resources: &mut Resources,
+    ) -> Vec<Sample> {
+        let inputs = [
+            signal.to_vec().into_boxed_slice(),
+            vec![drive; signal.len()].into_boxed_slice(),
+            vec![1.0; signal.len()].into_boxed_slice(),
+        ];
+        let mut outputs = [vec![0.0; signal.len()].into_boxed_slice()];
+        waveshaper.process(&inputs, &mut outputs, resources);
+        outputs[0].to_vec()
+    }
+
+    #[test]
+    fn transfer_functions() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let signal = [-3.0, -0.5, 0.0, 0.5, 1.5];
+        let clipped = run(&mut Waveshaper::hard_clip(), &signal, 1.0, &mut resources);
+        assert_eq!(clipped, vec![-1.0, -0.5, 0.0, 0.5, 1.0]);
+        let folded = run(&mut Waveshaper::foldback(), &signal, 1.0, &mut resources);
+        assert_eq!(folded, vec![1.0, -0.5, 0.0, 0.5, 0.5]);
+        // A table that inverts the signal
+        let key = resources
+            .insert_lookup_table(LookupTable::from_fn(65, |x| -x))
+            .unwrap();
+        let inverted = run(&mut Waveshaper::table(key), &signal, 1.0, &mut resources);
+        for (out, input) in inverted.iter().zip(clipped.iter()) {
+            assert!((out + input).abs() < 1e-6);
+        }
+        resources.remove_lookup_table(key).unwrap();
+        let silent = run(&mut Waveshaper::table(key), &signal, 1.0, &mut resources);
+        assert!(silent.iter().all(|s| *s == 0.0));
+    }
+
+    /// The magnitude of one frequency in the signal
+    fn goertzel(signal: &[Sample], freq: f64, sample_rate: f64) -> f64 {
+        let coefficient = 2.0 * (std::f64::consts::TAU * freq / sample_rate).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for &x in signal {
+            let s = sample_to_f64(x) + coefficient * s1 - s2;
+            s2 = s1;
+            s1 = s;
+        }
+        (s1 * s1 + s2 * s2 - coefficient * s1 * s2).sqrt()
+    }
+
+    #[test]
+    fn oversampling_reduces_aliasing() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let sample_rate = 48000.0;
+        let signal: Vec<Sample> = (0..4800)
+            .map(|i| (std::f64::consts::TAU * 7000.0 * i as f64 / sample_rate).sin() as Sample)
+            .collect();
+        let plain = run(&mut Waveshaper::hard_clip(), &signal, 4.0, &mut resources);
+        let mut oversampled_shaper = Waveshaper::hard_clip().oversample();
+        let oversampled = run(&mut oversampled_shaper, &signal, 4.0, &mut resources);
+        // The 5th harmonic at 35kHz aliases to 13kHz
+        let plain_alias = goertzel(&plain, 13000.0, sample_rate);
+        let oversampled_alias = goertzel(&oversampled, 13000.0, sample_rate);
+        assert!(oversampled_alias * 10.0 < plain_alias);
+        // The fundamental is kept
+        let plain_fundamental = goertzel(&plain, 7000.0, sample_rate);
+        let oversampled_fundamental = goertzel(&oversampled, 7000.0, sample_rate);
+        assert!((oversampled_fundamental / plain_fundamental - 1.0).abs() < 0.1);
+    }
+}
@@ -1,13 +1,20 @@
 //! Wavetable synthesis
 
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
 use slotmap::new_key_type;
+use symphonia::core::errors::Error as SymphoniaError;
 
-use crate::{Resources, Sample};
+use crate::buffer::Buffer;
+use crate::{sample_to_f64, Resources, ResourcesError, Sample};
 
 use crate::graph::{Gen, GenState};
+use crate::patch::ResourceRef;
+use crate::waveshaper::{LookupTable, LookupTableKey};
 // use std::f64::consts::PI;
 use crate::xorrng::XOrShift32Rng;
-use std::f32::consts::PI;
+const PI: Sample = std::f64::consts::PI as Sample;
 
 /// Decides the number of samples per [`Wavetable`] buffer, and therefore also
 /// the number of high bits used for the phase indexing into the wavetable. With
@@ -21,6 +28,9 @@ pub const TABLE_HIGH_MASK: u32 = TABLE_SIZE as u32 - 1;
 /// Max number of the fractional part of a integer phase. Currently, 16 bits are used for the fractional part.
 pub const FRACTIONAL_PART: u32 = 65536;
 
+/// The increase of a [`Phase`] for one full cycle of a wavetable
+const PHASE_CYCLE: u32 = TABLE_SIZE as u32 * FRACTIONAL_PART;
+
 // We could later turn WavetableIndex into a generational index if we'd want
 pub type WavetableIndex = usize;
 
@@ -29,6 +39,14 @@ new_key_type! {
     pub struct WavetableKey;
 }
 
+/// A sine [`Wavetable`] shared by all Gens that need one. It is created the
+/// first time this is called so call it outside of the audio thread, e.g. in
+/// the constructor of a Gen.
+pub fn shared_sine() -> &'static Wavetable {
+    static SINE: OnceLock<Wavetable> = OnceLock::new();
+    SINE.get_or_init(Wavetable::sine)
+}
+
 /// Wavetable is a standardised wavetable with a buffer of samples, as well as a
 /// separate buffer with the difference between the current sample and the next.
 /// The wavetable is of size [`TABLE_SIZE`] and can be indexed using a [`Phase`].
@@ -54,7 +72,7 @@ impl Wavetable {
         Self::default()
     }
     pub fn update_diff_buffer(&mut self) {
-        let diff_buffer: Vec<f32> = self
+        let diff_buffer: Vec<Sample> = self
             .buffer
             .iter()
             .zip(self.buffer.iter().skip(1).cycle())
@@ -63,7 +81,7 @@ impl Wavetable {
         self.diff_buffer = diff_buffer;
     }
     pub fn from_buffer(buffer: Vec<Sample>) -> Self {
-        let diff_buffer: Vec<f32> = buffer
+        let diff_buffer: Vec<Sample> = buffer
             .iter()
             .zip(buffer.iter().skip(1).cycle())
             .map(|(&a, &b)| b - a)
@@ -78,7 +96,7 @@ impl Wavetable {
         let mut wt = Wavetable::new();
         // Fill buffer with a sine
         for i in 0..wavetable_size {
-            wt.buffer[i] = ((i as Sample / TABLE_SIZE as f32) * PI * 2.0).sin();
+            wt.buffer[i] = ((i as Sample / TABLE_SIZE as Sample) * PI * 2.0).sin();
         }
         wt.update_diff_buffer();
         wt
@@ -97,7 +115,7 @@ impl Wavetable {
         let mut xorrng = XOrShift32Rng::new(seed);
         wt.fill_sine(16, 1.0);
         for _ in 0..(xorrng.gen_u32() % 3 + 1) {
-            wt.fill_sine(16, (xorrng.gen_f32() * 32.0).floor());
+            wt.fill_sine(16, (xorrng.gen_f32() as Sample * 32.0).floor());
         }
         wt.add_noise(1.0 - xorrng.gen_f64() * 0.05, seed + wavetable_size as u32);
         wt.normalize();
@@ -137,7 +155,7 @@ impl Wavetable {
         self.update_diff_buffer();
     }
     pub fn add_sine(&mut self, freq: Sample, amplitude: Sample, phase: Sample) {
-        let step = (freq * PI * 2.0) / TABLE_SIZE as f32;
+        let step = (freq * PI * 2.0) / TABLE_SIZE as Sample;
         let mut phase = phase;
         for sample in &mut self.buffer {
             *sample += phase.sin() * amplitude;
@@ -154,7 +172,7 @@ impl Wavetable {
             };
             for i in 0..TABLE_SIZE {
                 self.buffer[i] +=
-                    ((i as Sample / TABLE_SIZE as f32) * PI * 2.0 * freq * (n + 1) as Sample
+                    ((i as Sample / TABLE_SIZE as Sample) * PI * 2.0 * freq * (n + 1) as Sample
                         + start_phase)
                         .sin()
                         * harmonic_amp;
@@ -202,7 +220,7 @@ impl Wavetable {
         let mut xorrng = XOrShift32Rng::new(seed);
         for sample in &mut self.buffer {
             if xorrng.gen_f64() > probability {
-                *sample += xorrng.gen_f32() - 0.5;
+                *sample += xorrng.gen_f32() as Sample - 0.5;
                 if *sample > 1.0 {
                     *sample -= 1.0;
                 }
@@ -241,7 +259,7 @@ impl Wavetable {
     pub fn get_linear_interp(&self, phase: Phase) -> Sample {
         let index = phase.integer_component();
         let mix = phase.fractional_component_f32();
-        self.buffer[index] + self.diff_buffer[index] * mix
+        self.buffer[index] + self.diff_buffer[index] * mix as Sample
     }
 
     /// Get the closest sample with no interpolation
@@ -310,11 +328,11 @@ impl WavetableOscillatorOwned {
     pub fn from_freq(wavetable: Wavetable, sample_rate: Sample, freq: Sample, amp: Sample) -> Self {
         let mut osc = Self::new(wavetable);
         osc.amp = amp;
-        osc.step = ((freq / sample_rate) * TABLE_SIZE as f32) as u32;
+        osc.step = ((freq / sample_rate) * TABLE_SIZE as Sample) as u32;
         osc
     }
     pub fn set_freq(&mut self, freq: Sample, resources: &mut Resources) {
-        self.step = (freq as f64 * resources.freq_to_phase_inc) as u32;
+        self.step = (sample_to_f64(freq) * resources.freq_to_phase_inc) as u32;
     }
     pub fn set_amp(&mut self, amp: Sample) {
         self.amp = amp;
@@ -344,8 +362,10 @@ impl Gen for WavetableOscillatorOwned {
         let freq_buf = &inputs[0];
         for (&freq, o) in freq_buf.iter().zip(output.iter_mut()) {
             self.set_freq(freq, resources);
-            *o = self.next_sample();
+            *o = self.wavetable.get(self.phase);
+            self.phase.increase(self.step);
         }
+        crate::simd::scale(output, self.amp);
         GenState::Continue
     }
     fn input_desc(&self, input: usize) -> &'static str {
@@ -394,6 +414,17 @@ impl Phase {
     pub fn increase(&mut self, add: u32) {
         self.0 = self.0.wrapping_add(add);
     }
+    /// The phase at `fraction` of a cycle, where 0.0 is the start and 1.0 the
+    /// end of the wavetable.
+    #[inline]
+    pub fn from_fraction(fraction: f64) -> Self {
+        Phase((fraction.rem_euclid(1.0) * PHASE_CYCLE as f64) as u32)
+    }
+    /// How far into the cycle the phase is, from 0.0 up to but not including 1.0
+    #[inline]
+    pub fn fraction(&self) -> f64 {
+        (self.0 & (PHASE_CYCLE - 1)) as f64 / PHASE_CYCLE as f64
+    }
 }
 
 /// Don't use! This is the same as Phase, but stored in an f32. Last time I
@@ -444,12 +475,12 @@ impl Oscillator {
     ) -> Self {
         let mut osc = Oscillator::new(wavetable);
         osc.amp = amp;
-        osc.step = ((freq / sample_rate) * TABLE_SIZE as f32) as u32;
+        osc.step = ((freq / sample_rate) * TABLE_SIZE as Sample) as u32;
         osc
     }
     #[inline]
     pub fn set_freq(&mut self, freq: Sample, resources: &mut Resources) {
-        self.step = (freq as f64 * resources.freq_to_phase_inc) as u32;
+        self.step = (sample_to_f64(freq) * resources.freq_to_phase_inc) as u32;
     }
     #[inline]
     pub fn set_amp(&mut self, amp: Sample) {
@@ -459,21 +490,99 @@ impl Oscillator {
     pub fn reset_phase(&mut self) {
         self.phase.0 = 0;
     }
-    #[inline]
-    fn next(&mut self, resources: &mut Resources) -> Sample {
-        // Use the phase to index into the wavetable
-        let sample = match resources.wavetables.get(self.wavetable) {
-            Some(wt) => wt.get(self.phase) * self.amp,
+}
+impl Gen for Oscillator {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let output = &mut outputs[0];
+        let freq_buf = &inputs[0];
+        let freq_to_phase_inc = resources.freq_to_phase_inc;
+        // Look up the wavetable once per block instead of once per sample
+        let wt = match resources.wavetables.get(self.wavetable) {
+            Some(wt) => wt,
             None => {
                 eprintln!("Wavetable doesn't exist: {:?}", self.wavetable);
-                0.0
+                output.fill(0.0);
+                return GenState::Continue;
             }
         };
-        self.phase.increase(self.step);
-        sample
+        for (&freq, o) in freq_buf.iter().zip(output.iter_mut()) {
+            self.step = (sample_to_f64(freq) * freq_to_phase_inc) as u32;
+            *o = wt.get(self.phase);
+            self.phase.increase(self.step);
+        }
+        crate::simd::scale(output, self.amp);
+        GenState::Continue
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "freq",
+            _ => "",
+        }
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn num_inputs(&self) -> usize {
+        1
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::Wavetable(self.wavetable)]
+    }
+    fn name(&self) -> &'static str {
+        "Oscillator"
     }
 }
-impl Gen for Oscillator {
+
+/// Hard sync oscillator pair playing a wavetable. The slave oscillator at the
+/// "freq" input is reset to the start of the wavetable every time the master
+/// oscillator at the "sync_freq" input starts a new cycle. A master frequency
+/// of 0 turns sync off.
+///
+/// The jump in the output at a reset is corrected with a polyBLEP spread over
+/// the samples on either side of it, so the reset doesn't alias the way naive
+/// sync does. The wavetable itself should be band limited. The output is
+/// delayed by one sample to make room for the correction.
+#[derive(Debug, Clone)]
+pub struct SyncOscillator {
+    /// Master phase from 0.0 to 1.0
+    master_phase: f64,
+    /// Slave phase from 0.0 to 1.0
+    slave_phase: f64,
+    wavetable: WavetableKey,
+    amp: Sample,
+    /// The sample waiting to be output
+    delayed: Sample,
+    /// Correction for the sample after a reset
+    correction: Sample,
+}
+
+impl SyncOscillator {
+    pub fn new(wavetable: WavetableKey) -> Self {
+        Self {
+            master_phase: 0.0,
+            slave_phase: 0.0,
+            wavetable,
+            amp: 1.0,
+            delayed: 0.0,
+            correction: 0.0,
+        }
+    }
+    pub fn amp(mut self, amp: Sample) -> Self {
+        self.amp = amp;
+        self
+    }
+    pub fn reset_phase(&mut self) {
+        self.master_phase = 0.0;
+        self.slave_phase = 0.0;
+    }
+}
+
+impl Gen for SyncOscillator {
     fn process(
         &mut self,
         inputs: &[Box<[Sample]>],
@@ -482,15 +591,58 @@ impl Gen for Oscillator {
     ) -> GenState {
         let output = &mut outputs[0];
         let freq_buf = &inputs[0];
-        for (&freq, o) in freq_buf.iter().zip(output.iter_mut()) {
-            self.set_freq(freq, resources);
-            *o = self.next(resources);
+        let sync_freq_buf = &inputs[1];
+        let sample_rate = sample_to_f64(resources.sample_rate);
+        let wt = match resources.wavetables.get(self.wavetable) {
+            Some(wt) => wt,
+            None => {
+                eprintln!("Wavetable doesn't exist: {:?}", self.wavetable);
+                output.fill(0.0);
+                return GenState::Continue;
+            }
+        };
+        let reset_value = wt.get_linear_interp(Phase(0));
+        for ((&freq, &sync_freq), o) in freq_buf
+            .iter()
+            .zip(sync_freq_buf.iter())
+            .zip(output.iter_mut())
+        {
+            let mut value =
+                wt.get_linear_interp(Phase::from_fraction(self.slave_phase)) + self.correction;
+            self.correction = 0.0;
+            let slave_step = sample_to_f64(freq) / sample_rate;
+            let master_step = (sample_to_f64(sync_freq) / sample_rate).max(0.0);
+            self.master_phase += master_step;
+            if self.master_phase >= 1.0 {
+                self.master_phase = self.master_phase.fract();
+                // How far into the step the reset happened, as a fraction
+                // of a sample counted back from the next sample
+                let after = (self.master_phase / master_step).min(1.0);
+                let at_reset = (self.slave_phase + slave_step * (1.0 - after)).rem_euclid(1.0);
+                let jump = reset_value - wt.get_linear_interp(Phase::from_fraction(at_reset));
+                value += jump * (after * after * 0.5) as Sample;
+                self.correction = -jump * ((1.0 - after) * (1.0 - after) * 0.5) as Sample;
+                self.slave_phase = slave_step * after;
+            } else {
+                self.slave_phase += slave_step;
+            }
+            self.slave_phase = self.slave_phase.rem_euclid(1.0);
+            *o = self.delayed;
+            self.delayed = value;
         }
+        crate::simd::scale(output, self.amp);
         GenState::Continue
     }
     fn input_desc(&self, input: usize) -> &'static str {
         match input {
             0 => "freq",
+            1 => "sync_freq",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
             _ => "",
         }
     }
@@ -498,6 +650,728 @@ impl Gen for Oscillator {
         1
     }
     fn num_inputs(&self) -> usize {
+        2
+    }
+    fn latency(&self) -> usize {
+        1
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::Wavetable(self.wavetable)]
+    }
+    fn name(&self) -> &'static str {
+        "SyncOscillator"
+    }
+}
+
+/// A curve warping the phase of a [`PhaseDistortion`] oscillator. The
+/// "amount" input goes from 0.0, leaving the phase unchanged, to 1.0 for the
+/// strongest distortion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseCurve {
+    /// Rush through the first half of the wavetable and slow down through
+    /// the second. Turns a sine into a saw like wave.
+    Bend,
+    /// Rush to the middle of each half of the wavetable and stay there.
+    /// Turns a sine into a pulse like wave.
+    Pulse,
+    /// Play the wavetable up to 8 times per cycle, like a hard synced
+    /// oscillator.
+    Sync,
+    /// A [`LookupTable`] in [`Resources::lookup_tables`] mapping the phase
+    /// from -1 at the start to 1 at the end of the cycle to a phase from 0.0
+    /// to 1.0. The amount crossfades between the unchanged phase and the
+    /// table. The output is silent if the table doesn't exist.
+    Table(LookupTableKey),
+}
+
+impl PhaseCurve {
+    /// Warp a phase from 0.0 to 1.0. `table` is only used by
+    /// [`PhaseCurve::Table`].
+    #[inline]
+    fn warp(self, phase: f64, amount: f64, table: Option<&LookupTable>) -> f64 {
+        match self {
+            PhaseCurve::Bend => {
+                let knee = 0.5 - amount * 0.495;
+                if phase < knee {
+                    phase * 0.5 / knee
+                } else {
+                    0.5 + (phase - knee) * 0.5 / (1.0 - knee)
+                }
+            }
+            PhaseCurve::Pulse => {
+                let width = 1.0 - amount * 0.99;
+                let half = (phase * 2.0).floor();
+                let position = phase * 2.0 - half;
+                let warped = if position < width * 0.5 {
+                    position / width
+                } else if position > 1.0 - width * 0.5 {
+                    0.5 + (position - (1.0 - width * 0.5)) / width
+                } else {
+                    0.5
+                };
+                (half + warped) * 0.5
+            }
+            PhaseCurve::Sync => (phase * (1.0 + amount * 7.0)).fract(),
+            PhaseCurve::Table(_) => match table {
+                Some(table) => {
+                    let curve = sample_to_f64(table.get((phase * 2.0 - 1.0) as Sample));
+                    phase + (curve - phase) * amount
+                }
+                None => phase,
+            },
+        }
+    }
+}
+
+/// Phase distortion oscillator in the style of the Casio CZ synthesizers.
+/// The phase is warped by a [`PhaseCurve`] before looking up the value in the
+/// wavetable, which changes the timbre without filtering. Inputs are "freq"
+/// and "amount", from 0.0 for no distortion to 1.0.
+#[derive(Debug, Clone)]
+pub struct PhaseDistortion {
+    phase: Phase,
+    wavetable: WavetableKey,
+    curve: PhaseCurve,
+    amp: Sample,
+}
+
+impl PhaseDistortion {
+    pub fn new(wavetable: WavetableKey, curve: PhaseCurve) -> Self {
+        Self {
+            phase: Phase(0),
+            wavetable,
+            curve,
+            amp: 1.0,
+        }
+    }
+    pub fn amp(mut self, amp: Sample) -> Self {
+        self.amp = amp;
+        self
+    }
+    pub fn set_curve(&mut self, curve: PhaseCurve) {
+        self.curve = curve;
+    }
+    pub fn reset_phase(&mut self) {
+        self.phase.0 = 0;
+    }
+}
+
+impl Gen for PhaseDistortion {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let output = &mut outputs[0];
+        let freq_buf = &inputs[0];
+        let amount_buf = &inputs[1];
+        let freq_to_phase_inc = resources.freq_to_phase_inc;
+        let table = match self.curve {
+            PhaseCurve::Table(key) => match resources.lookup_tables.get(key) {
+                Some(table) => Some(table),
+                None => {
+                    output.fill(0.0);
+                    return GenState::Continue;
+                }
+            },
+            _ => None,
+        };
+        let wt = match resources.wavetables.get(self.wavetable) {
+            Some(wt) => wt,
+            None => {
+                eprintln!("Wavetable doesn't exist: {:?}", self.wavetable);
+                output.fill(0.0);
+                return GenState::Continue;
+            }
+        };
+        for ((&freq, &amount), o) in freq_buf
+            .iter()
+            .zip(amount_buf.iter())
+            .zip(output.iter_mut())
+        {
+            let amount = sample_to_f64(amount.clamp(0.0, 1.0));
+            let warped = self.curve.warp(self.phase.fraction(), amount, table);
+            *o = wt.get_linear_interp(Phase::from_fraction(warped));
+            self.phase
+                .increase((sample_to_f64(freq) * freq_to_phase_inc) as u32);
+        }
+        crate::simd::scale(output, self.amp);
+        GenState::Continue
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "freq",
+            1 => "amount",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn num_outputs(&self) -> usize {
+        1
+    }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        let mut refs = vec![ResourceRef::Wavetable(self.wavetable)];
+        if let PhaseCurve::Table(key) = self.curve {
+            refs.push(ResourceRef::LookupTable(key));
+        }
+        refs
+    }
+    fn name(&self) -> &'static str {
+        "PhaseDistortion"
+    }
+}
+
+/// One sine partial of an [`AdditiveWavetable`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Partial {
+    /// The harmonic number where 1 is the fundamental
+    pub harmonic: usize,
+    pub amplitude: Sample,
+    /// Start phase in radians
+    pub phase: Sample,
+}
+
+/// Builds band limited wavetables from a list of harmonic partials. The
+/// partials are kept so that amplitudes can be changed and the wavetable
+/// regenerated. Partials at or above the Nyquist frequency of the table are
+/// left out.
+///
+/// ```
+/// # use knyst::wavetable::*;
+/// // A square wave approximation using the first 4 odd harmonics
+/// let mut square = AdditiveWavetable::from_partials([
+///     (1, 1.0, 0.0),
+///     (3, 1.0 / 3.0, 0.0),
+///     (5, 1.0 / 5.0, 0.0),
+///     (7, 1.0 / 7.0, 0.0),
+/// ]);
+/// let wavetable = square.build();
+/// // Make it brighter
+/// square.set_amplitude(9, 1.0 / 9.0);
+/// let brighter = square.build();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AdditiveWavetable {
+    partials: Vec<Partial>,
+}
+
+impl AdditiveWavetable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Create from (harmonic number, amplitude, phase) tuples
+    pub fn from_partials(partials: impl IntoIterator<Item = (usize, Sample, Sample)>) -> Self {
+        let mut additive = Self::new();
+        for (harmonic, amplitude, phase) in partials {
+            additive = additive.partial(harmonic, amplitude, phase);
+        }
+        additive
+    }
+    /// Add a partial
+    pub fn partial(mut self, harmonic: usize, amplitude: Sample, phase: Sample) -> Self {
+        self.partials.push(Partial {
+            harmonic,
+            amplitude,
+            phase,
+        });
+        self
+    }
+    pub fn partials(&self) -> &[Partial] {
+        &self.partials
+    }
+    /// Set the amplitude of all partials with the given harmonic number, or
+    /// add a new partial with phase 0 if there is none.
+    pub fn set_amplitude(&mut self, harmonic: usize, amplitude: Sample) {
+        let mut found = false;
+        for partial in self.partials.iter_mut().filter(|p| p.harmonic == harmonic) {
+            partial.amplitude = amplitude;
+            found = true;
+        }
+        if !found {
+            self.partials.push(Partial {
+                harmonic,
+                amplitude,
+                phase: 0.0,
+            });
+        }
+    }
+    /// Set the amplitudes of harmonics 1, 2, 3 etc. from a list
+    pub fn set_amplitudes(&mut self, amplitudes: &[Sample]) {
+        for (i, &amplitude) in amplitudes.iter().enumerate() {
+            self.set_amplitude(i + 1, amplitude);
+        }
+    }
+    /// Build a new normalized wavetable from the partials
+    pub fn build(&self) -> Wavetable {
+        let mut wt = Wavetable::new();
+        self.regenerate(&mut wt);
+        wt
+    }
+    /// Overwrite `wavetable` with the current partials, e.g. after changing
+    /// the amplitudes.
+    pub fn regenerate(&self, wavetable: &mut Wavetable) {
+        wavetable.buffer.clear();
+        wavetable.buffer.resize(TABLE_SIZE, 0.0);
+        for partial in &self.partials {
+            if partial.harmonic == 0 || partial.harmonic >= TABLE_SIZE / 2 {
+                continue;
+            }
+            for (i, sample) in wavetable.buffer.iter_mut().enumerate() {
+                // Wrap the index to keep the precision for high harmonics
+                let index = (i * partial.harmonic) % TABLE_SIZE;
+                let phase = (index as f64 / TABLE_SIZE as f64) as Sample * PI * 2.0;
+                *sample += (phase + partial.phase).sin() * partial.amplitude;
+            }
+        }
+        if wavetable.buffer.iter().any(|&sample| sample != 0.0) {
+            wavetable.normalize();
+        } else {
+            wavetable.update_diff_buffer();
+        }
+    }
+}
+
+impl Wavetable {
+    /// Create a normalized band limited wavetable from (harmonic number,
+    /// amplitude, phase) partials. See [`AdditiveWavetable`] for a builder that
+    /// can be changed and regenerated.
+    pub fn from_partials(partials: impl IntoIterator<Item = (usize, Sample, Sample)>) -> Self {
+        AdditiveWavetable::from_partials(partials).build()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WavetableBankError {
+    #[error("Unable to load the sound file: {0}")]
+    SoundFile(#[from] SymphoniaError),
+    #[error("The sound file doesn't contain a full cycle")]
+    NoCycles,
+    #[error("The cycle length has to be larger than 0")]
+    ZeroCycleLength,
+}
+
+impl Wavetable {
+    /// Create a wavetable from a single cycle of a waveform of any length. The
+    /// cycle is resampled to [`TABLE_SIZE`] using cubic interpolation and
+    /// normalized.
+    pub fn from_single_cycle(cycle: &[Sample]) -> Self {
+        let mut buffer = vec![0.0; TABLE_SIZE];
+        if !cycle.is_empty() {
+            let len = cycle.len();
+            let step = len as f64 / TABLE_SIZE as f64;
+            for (i, sample) in buffer.iter_mut().enumerate() {
+                let position = i as f64 * step;
+                let index = position as usize;
+                let mix = (position - index as f64) as Sample;
+                // The cycle is periodic so wrap around in both directions
+                let y0 = cycle[(index + len - 1) % len];
+                let y1 = cycle[index % len];
+                let y2 = cycle[(index + 1) % len];
+                let y3 = cycle[(index + 2) % len];
+                *sample = hermite_interpolate(y0, y1, y2, y3, mix);
+            }
+        }
+        let mut wt = Self::from_buffer(buffer);
+        // A silent table can't be normalized
+        if wt.buffer.iter().any(|&sample| sample != 0.0) {
+            wt.normalize();
+        }
+        wt
+    }
+}
+
+/// 4-point, 3rd-order Hermite interpolation between `y1` and `y2`
+#[inline]
+pub(crate) fn hermite_interpolate(
+    y0: Sample,
+    y1: Sample,
+    y2: Sample,
+    y3: Sample,
+    x: Sample,
+) -> Sample {
+    let c0 = y1;
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * x + c2) * x + c1) * x + c0
+}
+
+/// An ordered collection of wavetables loaded from single cycle waveforms,
+/// e.g. a folder of AKWF files or a Serum style wavetable with all the cycles
+/// stored after each other in one file. Every cycle is resampled to
+/// [`TABLE_SIZE`] and normalized.
+///
+/// Insert the bank into [`Resources`] and play it with a [`BankOscillator`].
+#[derive(Debug, Clone, Default)]
+pub struct WavetableBank {
+    wavetables: Vec<Wavetable>,
+}
+
+impl WavetableBank {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Load one single cycle waveform per file, in order. Only the first
+    /// channel of every file is used.
+    pub fn from_sound_files(
+        paths: impl IntoIterator<Item = impl Into<PathBuf>>,
+    ) -> Result<Self, WavetableBankError> {
+        let mut bank = Self::new();
+        for path in paths {
+            let samples = first_channel(&Buffer::from_sound_file(path)?);
+            if samples.is_empty() {
+                return Err(WavetableBankError::NoCycles);
+            }
+            bank.add_single_cycle(&samples);
+        }
+        Ok(bank)
+    }
+    /// Load a file containing cycles of `cycle_length` samples stored after
+    /// each other. Serum wavetables use a cycle length of 2048. Only the first
+    /// channel of the file is used.
+    pub fn from_concatenated_sound_file(
+        path: impl Into<PathBuf>,
+        cycle_length: usize,
+    ) -> Result<Self, WavetableBankError> {
+        let samples = first_channel(&Buffer::from_sound_file(path)?);
+        let mut bank = Self::new();
+        bank.add_concatenated(&samples, cycle_length)?;
+        Ok(bank)
+    }
+    pub fn add_single_cycle(&mut self, cycle: &[Sample]) {
+        self.wavetables.push(Wavetable::from_single_cycle(cycle));
+    }
+    /// Add every full cycle of `cycle_length` samples in `samples`. Samples
+    /// left over at the end are ignored.
+    pub fn add_concatenated(
+        &mut self,
+        samples: &[Sample],
+        cycle_length: usize,
+    ) -> Result<(), WavetableBankError> {
+        if cycle_length == 0 {
+            return Err(WavetableBankError::ZeroCycleLength);
+        }
+        if samples.len() < cycle_length {
+            return Err(WavetableBankError::NoCycles);
+        }
+        for cycle in samples.chunks_exact(cycle_length) {
+            self.add_single_cycle(cycle);
+        }
+        Ok(())
+    }
+    pub fn len(&self) -> usize {
+        self.wavetables.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.wavetables.is_empty()
+    }
+    pub fn wavetables(&self) -> &[Wavetable] {
+        &self.wavetables
+    }
+    /// Returns the wavetables, e.g. for inserting them through a
+    /// [`ResourcesCommandSender`](crate::ResourcesCommandSender).
+    pub fn into_wavetables(self) -> Vec<Wavetable> {
+        self.wavetables
+    }
+    /// Insert all the wavetables into `resources` and return their keys in
+    /// order. If there isn't space for all of them, the ones that were
+    /// inserted are removed again.
+    pub fn insert_into(
+        self,
+        resources: &mut Resources,
+    ) -> Result<Vec<WavetableKey>, ResourcesError> {
+        let mut keys = Vec::with_capacity(self.wavetables.len());
+        for wavetable in self.wavetables {
+            match resources.insert_wavetable(wavetable) {
+                Ok(key) => keys.push(key),
+                Err(e) => {
+                    for key in keys {
+                        resources.remove_wavetable(key);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+pub(crate) fn first_channel(buffer: &Buffer) -> Vec<Sample> {
+    let num_channels = buffer.num_channels().max(1);
+    let num_frames = buffer.size() as usize / num_channels;
+    (0..num_frames)
+        .map(|i| buffer.get_interleaved(i)[0])
+        .collect()
+}
+
+/// Oscillator playing a bank of wavetables, e.g. from a [`WavetableBank`].
+/// The "position" input selects the wavetable, from 0.0 for the first to 1.0
+/// for the last, and crossfades between neighbouring wavetables.
+#[derive(Debug, Clone)]
+pub struct BankOscillator {
+    phase: Phase,
+    wavetables: Vec<WavetableKey>,
+    amp: Sample,
+}
+
+impl BankOscillator {
+    pub fn new(wavetables: Vec<WavetableKey>) -> Self {
+        Self {
+            phase: Phase(0),
+            wavetables,
+            amp: 1.0,
+        }
+    }
+    pub fn amp(mut self, amp: Sample) -> Self {
+        self.amp = amp;
+        self
+    }
+    pub fn reset_phase(&mut self) {
+        self.phase.0 = 0;
+    }
+}
+
+impl Gen for BankOscillator {
+    fn process(
+        &mut self,
+        inputs: &[Box<[Sample]>],
+        outputs: &mut [Box<[Sample]>],
+        resources: &mut Resources,
+    ) -> GenState {
+        let output = &mut outputs[0];
+        if self.wavetables.is_empty() {
+            output.fill(0.0);
+            return GenState::Continue;
+        }
+        let freq_buf = &inputs[0];
+        let position_buf = &inputs[1];
+        let freq_to_phase_inc = resources.freq_to_phase_inc;
+        let last_index = self.wavetables.len() - 1;
+        let mut missing_wavetable = false;
+        for ((&freq, &position), o) in freq_buf
+            .iter()
+            .zip(position_buf.iter())
+            .zip(output.iter_mut())
+        {
+            let position = position.clamp(0.0, 1.0) * last_index as Sample;
+            let index = (position as usize).min(last_index);
+            let mix = position - index as Sample;
+            let next_index = (index + 1).min(last_index);
+            let mut value = 0.0;
+            match resources.wavetables.get(self.wavetables[index]) {
+                Some(wt) => value += wt.get(self.phase) * (1.0 - mix),
+                None => missing_wavetable = true,
+            }
+            if mix > 0.0 {
+                match resources.wavetables.get(self.wavetables[next_index]) {
+                    Some(wt) => value += wt.get(self.phase) * mix,
+                    None => missing_wavetable = true,
+                }
+            }
+            *o = value;
+            self.phase
+                .increase((sample_to_f64(freq) * freq_to_phase_inc) as u32);
+        }
+        if missing_wavetable {
+            eprintln!("BankOscillator: one or more wavetables don't exist");
+        }
+        crate::simd::scale(output, self.amp);
+        GenState::Continue
+    }
+    fn input_desc(&self, input: usize) -> &'static str {
+        match input {
+            0 => "freq",
+            1 => "position",
+            _ => "",
+        }
+    }
+    fn output_desc(&self, output: usize) -> &'static str {
+        match output {
+            0 => "sig",
+            _ => "",
+        }
+    }
+    fn num_outputs(&self) -> usize {
         1
     }
+    fn num_inputs(&self) -> usize {
+        2
+    }
+    fn resource_refs(&self) -> Vec<ResourceRef> {
+        self.wavetables
+            .iter()
+            .map(|&key| ResourceRef::Wavetable(key))
+            .collect()
+    }
+    fn name(&self) -> &'static str {
+        "BankOscillator"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ResourcesSettings;
+
+    #[test]
+    fn single_cycles_are_resampled_and_normalized() {
+        let mut bank = WavetableBank::new();
+        // Two cycles of a four sample waveform and a partial cycle
+        bank.add_concatenated(&[0.0, 0.5, 0.0, -0.5, 0.0, 0.25, 0.0, -0.25, 0.1], 4)
+            .unwrap();
+        assert_eq!(bank.len(), 2);
+        for wt in bank.wavetables() {
+            assert_eq!(wt.buffer.len(), TABLE_SIZE);
+            let peak = wt
+                .buffer
+                .iter()
+                .fold(0.0 as Sample, |acc, s| acc.max(s.abs()));
+            assert!((peak - 1.0).abs() < 1e-6);
+            // The original samples are kept at their positions
+            assert_eq!(wt.buffer[TABLE_SIZE / 4], 1.0);
+        }
+        assert!(matches!(
+            bank.add_concatenated(&[0.0; 3], 4),
+            Err(WavetableBankError::NoCycles)
+        ));
+    }
+
+    #[test]
+    fn additive_partials() {
+        let mut additive = AdditiveWavetable::from_partials([(1, 0.5, 0.0)]);
+        let wt = additive.build();
+        let sine = Wavetable::sine();
+        for (a, b) in wt.buffer.iter().zip(sine.buffer.iter()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+        // Partials above the Nyquist frequency of the table are ignored
+        additive.set_amplitude(TABLE_SIZE / 2, 1.0);
+        additive.set_amplitude(1, 2.0);
+        assert_eq!(additive.partials().len(), 2);
+        let mut wt = Wavetable::new();
+        additive.regenerate(&mut wt);
+        for (a, b) in wt.buffer.iter().zip(sine.buffer.iter()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn bank_oscillator_crossfades() {
+        let mut resources = Resources::new(ResourcesSettings::default());
+        let keys = vec![
+            resources
+                .insert_wavetable(Wavetable::from_buffer(vec![0.25; TABLE_SIZE]))
+                .unwrap(),
+            resources
+                .insert_wavetable(Wavetable::from_buffer(vec![-0.75; TABLE_SIZE]))
+                .unwrap(),
+        ];
+        let mut osc = BankOscillator::new(keys);
+        let inputs = vec![
+            vec![440.0; 4].into_boxed_slice(),
+            vec![0.0, 0.5, 1.0, 2.0].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; 4].into_boxed_slice()];
+        osc.process(&inputs, &mut outputs, &mut resources);
+        assert_eq!(*outputs[0], [0.25, -0.25, -0.75, -0.75]);
+    }
+    #[test]
+    fn sync_oscillator_resets_with_polyblep() {
+        let mut resources = Resources::new(ResourcesSettings {
+            sample_rate: 100.0,
+            ..Default::default()
+        });
+        let ramp = (0..TABLE_SIZE)
+            .map(|i| i as Sample / TABLE_SIZE as Sample)
+            .collect();
+        let key = resources
+            .insert_wavetable(Wavetable::from_buffer(ramp))
+            .unwrap();
+        let mut osc = SyncOscillator::new(key);
+        let mut outputs = vec![vec![0.0; 6].into_boxed_slice()];
+        // Without sync it is a plain ramp delayed by one sample
+        let inputs = vec![
+            vec![10.0; 6].into_boxed_slice(),
+            vec![0.0; 6].into_boxed_slice(),
+        ];
+        osc.process(&inputs, &mut outputs, &mut resources);
+        for (o, expected) in outputs[0].iter().zip([0.0, 0.0, 0.1, 0.2, 0.3, 0.4]) {
+            assert!((o - expected).abs() < 1e-4, "{o} != {expected}");
+        }
+        // The master wraps two thirds of a sample before the fifth sample.
+        // The slave drops from 1/3 to 0 and the drop is spread over the
+        // samples around it.
+        let mut osc = SyncOscillator::new(key);
+        let inputs = vec![
+            vec![10.0; 6].into_boxed_slice(),
+            vec![30.0; 6].into_boxed_slice(),
+        ];
+        osc.process(&inputs, &mut outputs, &mut resources);
+        let jump: Sample = -1.0 / 3.0;
+        let expected = [
+            0.0,
+            0.0,
+            0.1,
+            0.2,
+            0.3 + jump * 2.0 / 9.0,
+            0.2 / 3.0 - jump / 18.0,
+        ];
+        for (o, expected) in outputs[0].iter().zip(expected) {
+            assert!((o - expected).abs() < 1e-4, "{o} != {expected}");
+        }
+    }
+    #[test]
+    fn phase_distortion_curves() {
+        // No distortion at amount 0 and the ends of the cycle stay in place
+        for curve in [PhaseCurve::Bend, PhaseCurve::Pulse, PhaseCurve::Sync] {
+            for phase in [0.0, 0.1, 0.3, 0.5, 0.8] {
+                assert!((curve.warp(phase, 0.0, None) - phase).abs() < 1e-9);
+            }
+            assert_eq!(curve.warp(0.0, 1.0, None), 0.0);
+        }
+        // The first half of the wavetable is played during the first 0.5%
+        assert!((PhaseCurve::Bend.warp(0.005, 1.0, None) - 0.5).abs() < 1e-9);
+        assert!((PhaseCurve::Bend.warp(0.5, 1.0, None) - 0.75).abs() < 1e-2);
+        // The pulse holds at the peak and the trough of a sine
+        assert_eq!(PhaseCurve::Pulse.warp(0.2, 0.9, None), 0.25);
+        assert_eq!(PhaseCurve::Pulse.warp(0.7, 0.9, None), 0.75);
+        assert!((PhaseCurve::Sync.warp(0.25, 1.0, None) - 0.0).abs() < 1e-9);
+
+        // A user curve reversing the phase
+        let mut resources = Resources::new(ResourcesSettings {
+            sample_rate: 100.0,
+            ..Default::default()
+        });
+        let ramp = (0..TABLE_SIZE)
+            .map(|i| i as Sample / TABLE_SIZE as Sample)
+            .collect();
+        let wavetable = resources
+            .insert_wavetable(Wavetable::from_buffer(ramp))
+            .unwrap();
+        let table = resources
+            .insert_lookup_table(LookupTable::from_fn(65, |x| (1.0 - x) * 0.5))
+            .unwrap();
+        let mut osc = PhaseDistortion::new(wavetable, PhaseCurve::Table(table));
+        let inputs = vec![
+            vec![10.0; 4].into_boxed_slice(),
+            vec![0.0, 0.0, 1.0, 1.0].into_boxed_slice(),
+        ];
+        let mut outputs = vec![vec![0.0; 4].into_boxed_slice()];
+        osc.process(&inputs, &mut outputs, &mut resources);
+        for (o, expected) in outputs[0].iter().zip([0.0, 0.1, 0.8, 0.7]) {
+            assert!((o - expected).abs() < 1e-3, "{o} != {expected}");
+        }
+        resources.remove_lookup_table(table).unwrap();
+        osc.process(&inputs, &mut outputs, &mut resources);
+        assert!(outputs[0].iter().all(|s| *s == 0.0));
+    }
 }
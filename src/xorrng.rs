@@ -1,7 +1,7 @@
 // license: Public Domain
 // https://github.com/BillyDM/Fast-DSP-Approximations/blob/main/rng_and_noise.md
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct XOrShift32Rng {
     fpd: u32,
 }